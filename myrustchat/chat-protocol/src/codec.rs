@@ -0,0 +1,198 @@
+use std::fmt::Display;
+use std::str::FromStr;
+
+use bytes::{BufMut, Bytes, BytesMut};
+
+use crate::{ChatProtocolError, Datagram};
+
+/// Trait implemented by serialization formats which can encode datagrams on the wire.
+pub trait Codec: Send + Sync {
+    /// Encodes a `Datagram`, appending it to a buffer, e.g. right behind the header of a frame
+    /// so that the payload isn't copied. After an error, the buffer may hold part of the datagram.
+    ///
+    /// # Arguments
+    ///
+    /// * `datagram` - The datagram to be encoded.
+    /// * `out` - The buffer the encoded datagram is appended to.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), ChatProtocolError>` - Returns an empty result if successful.
+    fn encode_into(&self, datagram: &Datagram, out: &mut BytesMut) -> Result<(), ChatProtocolError>;
+
+    /// Encodes a `Datagram` to bytes.
+    ///
+    /// # Arguments
+    ///
+    /// * `datagram` - The datagram to be encoded.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Bytes, ChatProtocolError>` - Returns the encoded datagram if successful.
+    fn encode(&self, datagram: &Datagram) -> Result<Bytes, ChatProtocolError> {
+        let mut out = BytesMut::new();
+        self.encode_into(datagram, &mut out)?;
+        Ok(out.freeze())
+    }
+
+    /// Decodes a `Datagram` from bytes.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The encoded datagram.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Datagram, ChatProtocolError>` - Returns the decoded datagram if successful.
+    fn decode(&self, data: &[u8]) -> Result<Datagram, ChatProtocolError>;
+
+    /// Whether frames larger than `COMPRESSION_THRESHOLD` are compressed when written.
+    /// Compressed frames are always accepted when read.
+    fn compresses(&self) -> bool {
+        false
+    }
+}
+
+/// Compact binary encoding using CBOR, the default wire format.
+pub struct CborCodec;
+
+impl Codec for CborCodec {
+    fn encode_into(&self, datagram: &Datagram, out: &mut BytesMut) -> Result<(), ChatProtocolError> {
+        serde_cbor::to_writer(out.writer(), datagram).map_err(|_| ChatProtocolError::MalformedMessage)
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<Datagram, ChatProtocolError> {
+        // The frame was read completely, so running out of data means the datagram is truncated, not the stream
+        serde_cbor::from_slice::<Datagram>(data).map_err(|_| ChatProtocolError::MalformedMessage)
+    }
+}
+
+/// Human readable encoding using JSON, useful for debugging and non-Rust clients.
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    fn encode_into(&self, datagram: &Datagram, out: &mut BytesMut) -> Result<(), ChatProtocolError> {
+        serde_json::to_writer(out.writer(), datagram).map_err(|_| ChatProtocolError::MalformedMessage)
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<Datagram, ChatProtocolError> {
+        serde_json::from_slice::<Datagram>(data).map_err(|_| ChatProtocolError::MalformedMessage)
+    }
+}
+
+/// Compact binary encoding using MessagePack with named fields.
+pub struct MessagePackCodec;
+
+impl Codec for MessagePackCodec {
+    fn encode_into(&self, datagram: &Datagram, out: &mut BytesMut) -> Result<(), ChatProtocolError> {
+        rmp_serde::encode::write_named(&mut out.writer(), datagram).map_err(|_| ChatProtocolError::MalformedMessage)
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<Datagram, ChatProtocolError> {
+        rmp_serde::from_slice::<Datagram>(data).map_err(|_| ChatProtocolError::MalformedMessage)
+    }
+}
+
+/// Enum selecting one of the built-in codecs, e.g. from a command line flag.
+/// Both sides of a connection must use the same codec.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum CodecKind {
+    #[default]
+    Cbor,
+    Json,
+    MessagePack,
+}
+
+impl Codec for CodecKind {
+    fn encode_into(&self, datagram: &Datagram, out: &mut BytesMut) -> Result<(), ChatProtocolError> {
+        match self {
+            Self::Cbor => CborCodec.encode_into(datagram, out),
+            Self::Json => JsonCodec.encode_into(datagram, out),
+            Self::MessagePack => MessagePackCodec.encode_into(datagram, out),
+        }
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<Datagram, ChatProtocolError> {
+        match self {
+            Self::Cbor => CborCodec.decode(data),
+            Self::Json => JsonCodec.decode(data),
+            Self::MessagePack => MessagePackCodec.decode(data),
+        }
+    }
+}
+
+/// Codec of a logged in connection: one of the built-in codecs, compressing large frames
+/// if both sides agreed on it during the login.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct SessionCodec {
+    pub kind: CodecKind,
+    pub compression: bool,
+}
+
+impl From<CodecKind> for SessionCodec {
+    fn from(kind: CodecKind) -> Self {
+        SessionCodec { kind, compression: false }
+    }
+}
+
+impl Codec for SessionCodec {
+    fn encode_into(&self, datagram: &Datagram, out: &mut BytesMut) -> Result<(), ChatProtocolError> {
+        self.kind.encode_into(datagram, out)
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<Datagram, ChatProtocolError> {
+        self.kind.decode(data)
+    }
+
+    fn compresses(&self) -> bool {
+        self.compression
+    }
+}
+
+impl FromStr for CodecKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "cbor" => Ok(Self::Cbor),
+            "json" => Ok(Self::Json),
+            "msgpack" | "messagepack" => Ok(Self::MessagePack),
+            _ => Err(format!("unknown codec {s}, expected one of: cbor, json, msgpack")),
+        }
+    }
+}
+
+impl Display for CodecKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Cbor => write!(f, "cbor"),
+            Self::Json => write!(f, "json"),
+            Self::MessagePack => write!(f, "msgpack"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    #[test]
+    fn test_codec_roundtrip() {
+        let datagram = Datagram::Message(ChatMessage::new(7, "Bob", ChatMessageContent::File("test.txt".to_string(), vec![1, 2, 3])));
+
+        for codec in [CodecKind::Cbor, CodecKind::Json, CodecKind::MessagePack] {
+            let data = codec.encode(&datagram).unwrap();
+            let decoded = codec.decode(&data).unwrap();
+            assert!(matches!(decoded, Datagram::Message(message)
+                if message.id == 7 && matches!(message.content, ChatMessageContent::File(ref name, ref data) if name == "test.txt" && data == &[1, 2, 3])));
+            assert!(codec.decode(&data[..data.len() - 1]).is_err());
+        }
+
+        // Messages of older peers don't have a nickname
+        let old = r#"{"Message":{"id":1,"sender":"Bob","timestamp":"2024-01-01T00:00:00Z","content":{"Text":"hi"}}}"#;
+        assert!(matches!(CodecKind::Json.decode(old.as_bytes()).unwrap(), Datagram::Message(message) if message.nickname.is_none()));
+
+        assert_eq!("json".parse::<CodecKind>(), Ok(CodecKind::Json));
+        assert!("xml".parse::<CodecKind>().is_err());
+    }
+}
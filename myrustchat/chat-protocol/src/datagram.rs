@@ -0,0 +1,917 @@
+use std::io::{Read, Write};
+use std::time::Duration;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use chrono::{DateTime, Utc};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use serde::{Serialize, Deserialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::Codec;
+
+/// Enum representing different types of datagrams exchanged in the chat protocol.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Datagram {
+    /// Represents a login datagram containing a username and password. With `compression` the client
+    /// offers to exchange compressed frames, older clients don't send it. A client logging in again after its
+    /// connection broke sends the ID of the last stored message it received as `last_id`, the server sends
+    /// the messages it missed like for `FetchSince` before any new message.
+    Login {
+        username: String,
+        password: String,
+        #[serde(default)]
+        compression: bool,
+        #[serde(default)]
+        last_id: Option<MessageSeq>,
+    },
+    /// Represents a server response datagram.
+    ServerResponse(ServerResponse),
+    /// Represents a chat message datagram.
+    Message(ChatMessage),
+    /// Represents a private chat message delivered only to the user `to`.
+    DirectMessage { to: String, message: ChatMessage },
+    /// Announces the start of a chunked file transfer of `size` bytes. The server answers an upload with
+    /// `ServerResponse::TransferAccepted` telling from which chunk the client sends the file.
+    FileBegin { transfer_id: TransferId, id: MessageId, sender: String, kind: AttachmentKind, size: u64 },
+    /// Carries the part number `seq` of the file data of the transfer `transfer_id`. All chunks except the last one
+    /// are `FILE_CHUNK_SIZE` bytes long, so the number tells where the data belongs in the file.
+    FileChunk { transfer_id: TransferId, seq: u64, data: Vec<u8> },
+    /// Marks the successful end of the transfer `transfer_id`.
+    FileEnd { transfer_id: TransferId },
+    /// Cancels the transfer `transfer_id`, partially received data should be discarded.
+    FileAbort { transfer_id: TransferId },
+    /// Represents a command which can be issued only by users with the admin role.
+    AdminCommand(AdminCommand),
+    /// Notifies clients that a user has connected to (`online`) or disconnected from the server.
+    Presence { username: String, online: bool },
+    /// Requests the list of users which are currently online.
+    ListUsers,
+    /// Checks that the peer is alive, it must reply with `Pong`.
+    Ping,
+    /// Reply to `Ping`.
+    Pong,
+    /// Changes the password of the authenticated user, the current password must be provided.
+    ChangePassword { old_password: String, new_password: String },
+    /// Sets the display name of the authenticated user, `None` removes it.
+    SetNickname(Option<String>),
+    /// Notifies clients that the user `username` changed their nickname, `None` if it was removed.
+    Renamed { username: String, nickname: Option<String> },
+    /// Announces an image sent by another user with a small preview as the content of `message`.
+    /// The full image can be requested with `FetchAttachment`.
+    Thumbnail { id: AttachmentId, message: ChatMessage },
+    /// Announces a file sent by the user `sender`. Only its name and size are sent, the file itself
+    /// can be requested with `FetchAttachment`.
+    FileOffer { id: AttachmentId, sender: String, nickname: Option<String>, timestamp: DateTime<Utc>, filename: String, size: u64 },
+    /// Requests a stored attachment, the server sends it back in a chunked file transfer starting with
+    /// the chunk `from_seq`. A download interrupted by a dropped connection is resumed with the number
+    /// of the first chunk which is missing.
+    FetchAttachment { id: AttachmentId, from_seq: u64 },
+    /// Reports that the user has read the messages which arrived at the server up to the time `up_to`.
+    MarkRead { up_to: DateTime<Utc> },
+    /// Asks when the user last read the chat, the server replies with `ServerResponse::LastRead`.
+    Seen(String),
+    /// Opens a relay link between two servers, sent instead of `Login` by the server `server_id`.
+    /// The servers must share the same `secret`.
+    PeerHello { server_id: String, secret: String },
+    /// Carries the TOTP code from the authenticator app of the user, sent after `Login` when the server
+    /// replies with `ServerResponse::TotpRequired`.
+    TotpCode(String),
+    /// A notice from the operator of the server to all users, sent from the admin console. It isn't stored,
+    /// unlike the chat messages posted to the announcement channel with `ChatMessage::announcement`.
+    Announcement(String),
+    /// Logs in without an account, sent instead of `Login` to servers allowing guests. The guest is known
+    /// as `nickname` with `GUEST_PREFIX` in front of it. `last_id` is sent when logging in again, like with `Login`.
+    GuestLogin {
+        nickname: String,
+        #[serde(default)]
+        last_id: Option<MessageSeq>,
+    },
+    /// Registers a new user, sent instead of `Login`. The server replies with `ServerResponse::VerificationRequired`
+    /// and creates the user once the client sends the code with `VerifyRegistration`.
+    Register { username: String, password: String },
+    /// Logs in with the client certificate presented on the TLS port instead of a password, sent instead of `Login`
+    /// by bots. The fingerprint of the certificate must be registered for `username` on the server.
+    /// `last_id` is sent when logging in again, like with `Login`.
+    CertificateLogin {
+        username: String,
+        #[serde(default)]
+        compression: bool,
+        #[serde(default)]
+        last_id: Option<MessageSeq>,
+    },
+    /// Carries the verification code of a registration, which the user got from the operator of the server.
+    VerifyRegistration { code: String },
+    /// Blocks a user, the server stops delivering their messages to the authenticated user.
+    /// The server replies with `ServerResponse::BlockList`.
+    Block(String),
+    /// Unblocks a user, the server replies with `ServerResponse::BlockList`.
+    Unblock(String),
+    /// Requests the list of users blocked by the authenticated user.
+    ListBlocks,
+    /// Requests the statistics of the server, it replies with `ServerResponse::Stats`.
+    Stats,
+    /// Requests the stored messages with an ID greater than `last_id`, oldest first. The server sends them like
+    /// when they were posted, as `Message`, `Thumbnail` and `FileOffer` datagrams, up to a limit,
+    /// followed by `ServerResponse::FetchComplete`.
+    FetchSince { last_id: MessageSeq },
+    /// Notifies clients that the message `id` of the user `sender`, sent with a `ttl`, expired and was deleted.
+    /// Clients should remove the message or mark it as expired. Added in 1.2.
+    /// IDs are only unique per connection, so clients find the messages they received by the sequence number `seq`,
+    /// and only the sender's own connection, which doesn't get the message, by its `id`. `seq` was added in 1.3,
+    /// it's `None` from older servers.
+    MessageExpired {
+        sender: String,
+        id: MessageId,
+        #[serde(default)]
+        seq: Option<MessageSeq>,
+    },
+    /// Changes the profile of the authenticated user. `None` leaves a field as it is, an empty status or avatar
+    /// removes it. The avatar is a PNG of at most `MAX_AVATAR_SIZE` bytes and `MAX_AVATAR_DIMENSION` pixels a side.
+    /// The server replies with `ServerResponse::ProfileSet` and tells the others with `ProfileUpdated`. Added in 1.3.
+    SetProfile { status: Option<String>, avatar_png: Option<Vec<u8>> },
+    /// Requests the profile of a user with the avatar, the server replies with `ServerResponse::Profile`. Added in 1.3.
+    FetchProfile(String),
+    /// Notifies clients that the user `username` changed their profile, clients holding it should fetch it again.
+    /// Added in 1.3.
+    ProfileUpdated { username: String },
+    /// Requests the users which are currently online with their status lines, but without their avatars.
+    /// The server replies with `ServerResponse::UserStatuses`. Added in 1.3, clients which also talk to older servers
+    /// can follow it with `Datagram::ListUsers`, which those servers still answer.
+    ListStatuses,
+}
+
+/// Enum representing commands available to administrators.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum AdminCommand {
+    /// Disconnects all connections of the user.
+    Kick(String),
+    /// Permanently bans the user and disconnects them.
+    Ban(String),
+    /// Lifts the ban of the user.
+    Unban(String),
+}
+
+/// Enum representing different types of server responses.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum ServerResponse {
+    /// Indicates a successful login.
+    LoginOk,
+    /// Indicates a successful login, the server accepted the offer to compress large frames.
+    LoginOkCompressed,
+    /// Indicates a failed login.
+    LoginFailed,
+    /// Indicates that a direct message could not be delivered because the recipient is offline.
+    UserOffline(String),
+    /// Confirms that the message with the given ID was processed by the server.
+    MessageAck(MessageId),
+    /// Indicates that an admin command was performed.
+    AdminCommandOk,
+    /// Indicates that an admin command failed, with the reason.
+    AdminCommandFailed(String),
+    /// Indicates that the user was kicked by an administrator, the connection is closed afterwards.
+    Kicked,
+    /// Indicates that the user is banned, the connection is closed afterwards.
+    Banned,
+    /// Contains the sorted list of users which are currently online.
+    UserList(Vec<String>),
+    /// Indicates that the password of the user was changed.
+    PasswordChanged,
+    /// Indicates that the password could not be changed, with the reason.
+    PasswordChangeFailed(String),
+    /// Indicates that the nickname of the user was set, or removed if `None`.
+    NicknameChanged(Option<String>),
+    /// Indicates that the nickname was rejected, with the reason.
+    NicknameRejected(String),
+    /// Indicates that the server has no room for the connection, which is closed afterwards.
+    /// Also sent to idle clients disconnected to make room for a new one.
+    ServerFull,
+    /// Indicates that the user was muted for flooding and their messages are dropped until the given time.
+    Muted { until: DateTime<Utc> },
+    /// Indicates that an image or file attachment was not accepted, with the reason.
+    AttachmentRejected { reason: String },
+    /// Indicates that the attachment requested by `FetchAttachment` doesn't exist.
+    AttachmentNotFound(AttachmentId),
+    /// Indicates that the message was not delivered because it was blocked by the content filter.
+    MessageRejected { reason: String },
+    /// Contains the time when the user last read the chat, `None` if they never did.
+    LastRead { username: String, read_at: Option<DateTime<Utc>> },
+    /// Accepts a relay link opened with `PeerHello`, carrying the ID of the accepting server.
+    PeerOk { server_id: String },
+    /// Indicates that the password is correct but the user enabled two-factor authentication,
+    /// the client must send `Datagram::TotpCode` to complete the login.
+    TotpRequired,
+    /// Indicates that the login was refused without checking the password, because of too many failed logins
+    /// of the user or from the address of the client. Logins are accepted again after `until`.
+    LoginLocked { until: DateTime<Utc> },
+    /// Accepts an upload announced by `Datagram::FileBegin`, the client sends its chunks starting with `next_seq`.
+    /// It's greater than zero when an upload interrupted by a dropped connection is resumed with the same transfer ID.
+    TransferAccepted { transfer_id: TransferId, next_seq: u64 },
+    /// Indicates that the user is already logged in from another connection. Refuses a new login,
+    /// or is sent to an older connection which is closed afterwards because the user logged in again.
+    AlreadyLoggedIn,
+    /// Indicates that the registration was accepted so far, the client must send the verification code
+    /// with `Datagram::VerifyRegistration`.
+    VerificationRequired,
+    /// Indicates that the user was registered, the connection is closed afterwards and the user can log in.
+    Registered,
+    /// Indicates that the registration was refused, with the reason. The connection is closed afterwards.
+    RegistrationFailed { reason: String },
+    /// Contains the users blocked by the user, sent after every change of the block list.
+    BlockList(Vec<String>),
+    /// Indicates that a user could not be blocked or unblocked, with the reason.
+    BlockFailed(String),
+    /// Contains the statistics of the server.
+    Stats(ServerStatistics),
+    /// Ends the messages sent for `Datagram::FetchSince`. `last_id` is the ID of the last message looked at,
+    /// if `more` is true the newer messages are fetched with another `FetchSince` starting there.
+    FetchComplete { last_id: MessageSeq, more: bool },
+    /// Indicates that the profile of the user was changed. Added in 1.3.
+    ProfileSet,
+    /// Indicates that the profile was not changed, with the reason. Added in 1.3.
+    ProfileRejected(String),
+    /// Contains the profile requested with `Datagram::FetchProfile`, empty for users without one. Added in 1.3.
+    Profile(UserProfile),
+    /// Contains the sorted list of users which are currently online with their status lines, requested
+    /// with `Datagram::ListStatuses`. Added in 1.3.
+    UserStatuses(Vec<UserStatus>),
+}
+
+/// The status line and avatar a user shows to the others. Added in 1.3.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct UserProfile {
+    pub username: String,
+    /// A short line like `on vacation until Monday`
+    pub status: Option<String>,
+    /// A small picture encoded as PNG
+    pub avatar_png: Option<Vec<u8>>,
+}
+
+/// An online user with the status line of their profile, listed without the avatar. Added in 1.3.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct UserStatus {
+    pub username: String,
+    /// The status line, `None` for users without one
+    pub status: Option<String>,
+}
+
+/// Statistics of a running server, for a quick health check from a client.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ServerStatistics {
+    /// Time since the server was started
+    pub uptime: std::time::Duration,
+    /// Number of distinct users online
+    pub users_online: u64,
+    /// Number of chat messages stored in the database
+    pub messages: u64,
+    /// Size of the database in bytes, without the attachment files
+    pub database_size: u64,
+}
+
+/// Identifier of a chat message, generated by the sending client.
+pub type MessageId = u64;
+
+/// Identifier of a chunked file transfer, unique within a single connection. Clients give the same ID
+/// to the upload of the same file, so that an interrupted upload can be resumed.
+pub type TransferId = u64;
+
+/// Identifier of an attachment stored by the server, assigned by the server.
+pub type AttachmentId = u64;
+
+/// Sequence number given by the server to a stored message. Sequence numbers increase in the order
+/// the messages are delivered and are never reused.
+pub type MessageSeq = u64;
+
+/// Maximum number of bytes carried by a single `Datagram::FileChunk`.
+pub const FILE_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Maximum size of the audio of a voice note. Voice notes are sent and delivered in a single message,
+/// so they have to fit in a frame.
+pub const MAX_VOICE_NOTE_SIZE: usize = 512 * 1024;
+
+/// Maximum size of the PNG of an avatar.
+pub const MAX_AVATAR_SIZE: usize = 64 * 1024;
+
+/// Maximum width and height of an avatar in pixels.
+pub const MAX_AVATAR_DIMENSION: u32 = 128;
+
+/// Prefix of the usernames of guests, which registered users can't have.
+pub const GUEST_PREFIX: &str = "guest-";
+
+/// Returns whether a username belongs to a guest logged in without an account.
+///
+/// # Arguments
+///
+/// * `username` - The username.
+///
+/// # Returns
+///
+/// * `bool` - Returns `true` for usernames starting with `GUEST_PREFIX`.
+pub fn is_guest(username: &str) -> bool {
+    username.starts_with(GUEST_PREFIX)
+}
+
+/// Default maximum size of a single encoded datagram accepted from the network.
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 1024 * 1024;
+
+/// Default time a peer has to take a datagram off the network before it's considered stalled.
+pub const DEFAULT_WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Encoded datagrams larger than this many bytes are compressed if the codec allows it.
+pub const COMPRESSION_THRESHOLD: usize = 1024;
+
+/// Bit of the frame length set when the payload is compressed with gzip. Frames without
+/// it are the same as those of peers which don't know about compression.
+const COMPRESSED_FRAME: u32 = 1 << 31;
+
+/// Initial capacity of the buffer a frame is read into, it grows further only as the payload arrives.
+const FRAME_READ_CAPACITY: usize = 64 * 1024;
+
+/// Size of the frame header, the little-endian length of the payload with the compression flag.
+const FRAME_HEADER: usize = size_of::<u32>();
+
+/// Represents the type of a file sent in a chunked transfer.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum AttachmentKind {
+    /// Image encoded as PNG.
+    Image,
+    /// File with a filename.
+    File(String),
+    /// Voice note in the given format.
+    Audio(AudioFormat),
+}
+
+/// Represents the format of a voice note.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum AudioFormat {
+    /// Opus in an Ogg container
+    Opus,
+    /// Vorbis in an Ogg container
+    Ogg,
+    Mp3,
+    /// AAC in an MPEG-4 container
+    M4a,
+    Wav,
+}
+
+impl AudioFormat {
+    /// All supported formats.
+    pub const ALL: [AudioFormat; 5] = [AudioFormat::Opus, AudioFormat::Ogg, AudioFormat::Mp3, AudioFormat::M4a, AudioFormat::Wav];
+
+    /// Finds the format of an audio file by its extension, ignoring the case.
+    ///
+    /// # Arguments
+    ///
+    /// * `extension` - The extension of the file without the dot, e.g. `opus`.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<AudioFormat>` - Returns the format, `None` if it isn't supported.
+    pub fn from_extension(extension: &str) -> Option<AudioFormat> {
+        let extension = extension.to_lowercase();
+        AudioFormat::ALL.into_iter().find(|format| format.extension() == extension)
+    }
+
+    /// Returns the usual extension of files in the format, without the dot.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            AudioFormat::Opus => "opus",
+            AudioFormat::Ogg => "ogg",
+            AudioFormat::Mp3 => "mp3",
+            AudioFormat::M4a => "m4a",
+            AudioFormat::Wav => "wav",
+        }
+    }
+}
+
+/// Represents a chat message which consists of a client-generated ID, a sender nickname,
+/// the time of arrival at the server and content.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChatMessage {
+    pub id: MessageId,
+    pub sender: String,
+    /// Set by the server when the message arrives, the value sent by the client is ignored.
+    pub timestamp: DateTime<Utc>,
+    pub content: ChatMessageContent,
+    /// Display name of the sender. Set by the server when the message arrives, the value sent by the client is ignored.
+    #[serde(default)]
+    pub nickname: Option<String>,
+    /// ID of the server where the message was posted, set when it's relayed to another server.
+    /// `None` for messages of local users.
+    #[serde(default)]
+    pub origin: Option<String>,
+    /// The message this one replies to, `None` if it isn't a reply.
+    #[serde(default)]
+    pub reply_to: Option<ReplyTo>,
+    /// ID given by the server when the message is stored, the value sent by the client is ignored.
+    /// IDs increase in the order the messages are delivered, so a client can notice missed messages
+    /// and fetch them with `Datagram::FetchSince`. `None` for messages which aren't stored.
+    #[serde(default)]
+    pub seq: Option<MessageSeq>,
+    /// Whether the message was posted to the announcement channel, where only admins may post and everyone reads.
+    /// Clients show announcements apart from the chat, the server rejects them from other users and rejects
+    /// announcements which aren't text. Unlike `Datagram::Announcement` of the operator, they're stored.
+    /// Added in 1.1.
+    #[serde(default)]
+    pub announcement: bool,
+    /// Number of seconds after its arrival at the server when the message expires, `None` if it stays.
+    /// The server deletes an expired message and sends `Datagram::MessageExpired` to everyone.
+    /// Only text messages in the chat can expire, the server clears it for others. Added in 1.2.
+    #[serde(default)]
+    pub ttl: Option<u32>,
+}
+
+impl ChatMessage {
+    /// Creates a message to everyone, with the current time and without any of the optional fields.
+    /// Other fields are set with the struct update syntax, e.g. `ChatMessage { ttl: Some(60), ..ChatMessage::new(id, sender, content) }`.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The ID of the message chosen by the sender.
+    /// * `sender` - The username of the sender.
+    /// * `content` - The content of the message.
+    ///
+    /// # Returns
+    ///
+    /// * `ChatMessage` - Returns the new message.
+    pub fn new(id: MessageId, sender: &str, content: ChatMessageContent) -> ChatMessage {
+        ChatMessage {
+            id,
+            sender: sender.to_string(),
+            timestamp: Utc::now(),
+            content,
+            nickname: None,
+            origin: None,
+            reply_to: None,
+            seq: None,
+            announcement: false,
+            ttl: None,
+        }
+    }
+}
+
+/// Refers to the message a reply answers. Message IDs are generated by the clients,
+/// so a message is identified by its sender together with its ID.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ReplyTo {
+    pub sender: String,
+    pub id: MessageId,
+}
+
+/// Represents the content of a chat message which can be plaintext, image (encoded as PNG), a file (with a filename)
+/// or a voice note.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum ChatMessageContent {
+    /// Plaintext message content.
+    Text(String),
+    /// Image message content encoded as PNG.
+    Image(Vec<u8>),
+    /// File message content with a filename and its content as bytes.
+    File(String, Vec<u8>),
+    /// Preview of a link in a text message, made by the server and sent to everyone after the message,
+    /// with the sender and ID of the message. Clients can't send it.
+    LinkPreview { url: String, title: String, description: Option<String> },
+    /// Voice note with its audio data of at most `MAX_VOICE_NOTE_SIZE` bytes.
+    Audio { format: AudioFormat, data: Vec<u8> },
+}
+
+/// Enum representing errors that can occur in the chat protocol.
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum ChatProtocolError {
+    #[error("Socket error")]
+    IOError,
+    #[error("Malformed message")]
+    MalformedMessage,
+    #[error("Frame of {0} bytes exceeds the maximum frame size")]
+    FrameTooLarge(usize),
+    /// The peer didn't take the datagram in time. Part of it may have been written, so the stream is unusable.
+    #[error("Write timed out")]
+    Timeout,
+}
+
+impl Datagram {
+    /// Reads a `Datagram` from the provided stream, accepting frames up to `DEFAULT_MAX_FRAME_SIZE` bytes.
+    ///
+    /// # Arguments
+    ///
+    /// * `read_half` - The readable half of the stream, TCP or Unix socket.
+    /// * `codec` - The codec used to decode the datagram.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Datagram, ChatProtocolError>` - Returns a result containing the `Datagram` if successful.
+    pub async fn read_from_stream<R: AsyncRead + Unpin + ?Sized>(read_half: &mut R, codec: &dyn Codec) -> Result<Datagram, ChatProtocolError> {
+        Self::read_from_stream_limited(read_half, DEFAULT_MAX_FRAME_SIZE, codec).await
+    }
+
+    /// Reads a `Datagram` from the provided stream. Frames larger than `max_frame_size` are rejected
+    /// before their payload is read, the stream should be closed afterwards. Payloads which can't be decoded
+    /// are reported as `MalformedMessage`, the stream stays usable as their length was known.
+    ///
+    /// # Arguments
+    ///
+    /// * `read_half` - The readable half of the stream, TCP or Unix socket.
+    /// * `max_frame_size` - The maximum accepted size of the encoded datagram in bytes.
+    /// * `codec` - The codec used to decode the datagram.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Datagram, ChatProtocolError>` - Returns a result containing the `Datagram` if successful.
+    pub async fn read_from_stream_limited<R: AsyncRead + Unpin + ?Sized>(read_half: &mut R, max_frame_size: usize, codec: &dyn Codec) -> Result<Datagram, ChatProtocolError> {
+        let mut msg_len = [0u8; FRAME_HEADER];
+        
+        if read_half.read_exact(&mut msg_len).await.is_err() {
+            return Err(ChatProtocolError::IOError);
+        }
+
+        let header = u32::from_le_bytes(msg_len);
+        let msg_len = (header & !COMPRESSED_FRAME) as usize;
+        if msg_len > max_frame_size {
+            return Err(ChatProtocolError::FrameTooLarge(msg_len));
+        }
+
+        // The buffer grows as the payload arrives, so announcing a large frame without sending it costs the peer as much as us
+        let mut buf: Vec<u8> = Vec::with_capacity(msg_len.min(FRAME_READ_CAPACITY));
+        match (&mut *read_half).take(msg_len as u64).read_to_end(&mut buf).await {
+            Ok(read) if read == msg_len => {},
+            _ => return Err(ChatProtocolError::IOError),
+        }
+
+        if header & COMPRESSED_FRAME != 0 {
+            buf = decompress(&buf, max_frame_size)?;
+        }
+        codec.decode(&buf)
+    }
+
+    /// Writes a `Datagram` to the provided stream. The header and the payload are written together,
+    /// so that an unbuffered stream doesn't send them in separate packets.
+    ///
+    /// # Arguments
+    ///
+    /// * `stream` - The writable half of the stream, TCP or Unix socket.
+    /// * `codec` - The codec used to encode the datagram.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), ChatProtocolError>` - Returns an empty result if successful.
+    pub async fn write_to_stream<W: AsyncWrite + Unpin + ?Sized>(&self, stream: &mut W, codec: &dyn Codec) -> Result<(), ChatProtocolError> {
+        let frame = self.encode_frame(codec)?;
+        stream.write_all(&frame).await.map_err(|_| ChatProtocolError::IOError)
+    }
+
+    /// Encodes a `Datagram` to a complete frame, the header followed by the payload, e.g. to be written
+    /// together with other frames. The payload is encoded right behind the header, so an uncompressed one
+    /// isn't copied, and the frame can be shared without copying it either.
+    ///
+    /// # Arguments
+    ///
+    /// * `codec` - The codec used to encode the datagram.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Bytes, ChatProtocolError>` - Returns the frame if successful.
+    pub fn encode_frame(&self, codec: &dyn Codec) -> Result<Bytes, ChatProtocolError> {
+        let mut frame = BytesMut::new();
+        frame.put_u32_le(0);
+        codec.encode_into(self, &mut frame)?;
+        let mut flag = 0;
+        if codec.compresses() && frame.len() - FRAME_HEADER > COMPRESSION_THRESHOLD {
+            // Already compressed content, like images, is sent as is
+            if let Some(compressed) = compress(&frame[FRAME_HEADER..]).filter(|compressed| compressed.len() < frame.len() - FRAME_HEADER) {
+                frame.truncate(FRAME_HEADER);
+                frame.extend_from_slice(&compressed);
+                flag = COMPRESSED_FRAME;
+            }
+        }
+        let len = frame.len() - FRAME_HEADER;
+        // Longer frames would overflow into the compression flag
+        if len >= COMPRESSED_FRAME as usize {
+            return Err(ChatProtocolError::FrameTooLarge(len));
+        }
+        frame[..FRAME_HEADER].copy_from_slice(&(len as u32 | flag).to_le_bytes());
+        Ok(frame.freeze())
+    }
+
+    /// Writes a `Datagram` to the provided stream, giving up if the peer doesn't take it in time.
+    /// The stream should be closed after a timeout, the peer may have received part of the frame.
+    ///
+    /// # Arguments
+    ///
+    /// * `stream` - The writable half of the stream, TCP or Unix socket.
+    /// * `codec` - The codec used to encode the datagram.
+    /// * `timeout` - How long the write may take, `None` waits forever.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), ChatProtocolError>` - Returns an empty result if successful.
+    pub async fn write_to_stream_timeout<W: AsyncWrite + Unpin + ?Sized>(&self, stream: &mut W, codec: &dyn Codec, timeout: Option<Duration>) -> Result<(), ChatProtocolError> {
+        match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, self.write_to_stream(stream, codec)).await
+                .unwrap_or(Err(ChatProtocolError::Timeout)),
+            None => self.write_to_stream(stream, codec).await,
+        }
+    }
+}
+
+/// Compresses the payload of a frame with gzip.
+///
+/// # Arguments
+///
+/// * `data` - The encoded datagram.
+///
+/// # Returns
+///
+/// * `Option<Vec<u8>>` - Returns the compressed payload, `None` if the compression failed.
+fn compress(data: &[u8]) -> Option<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::fast());
+    encoder.write_all(data).ok()?;
+    encoder.finish().ok()
+}
+
+/// Decompresses the payload of a compressed frame. The decompressed datagram is subject to the
+/// same size limit as an uncompressed one.
+///
+/// # Arguments
+///
+/// * `data` - The compressed payload.
+/// * `max_frame_size` - The maximum accepted size of the decompressed datagram in bytes.
+///
+/// # Returns
+///
+/// * `Result<Vec<u8>, ChatProtocolError>` - Returns the encoded datagram if successful.
+fn decompress(data: &[u8], max_frame_size: usize) -> Result<Vec<u8>, ChatProtocolError> {
+    let mut decompressed = Vec::new();
+    GzDecoder::new(data)
+        .take(max_frame_size as u64 + 1)
+        .read_to_end(&mut decompressed)
+        .map_err(|_| ChatProtocolError::MalformedMessage)?;
+    if decompressed.len() > max_frame_size {
+        return Err(ChatProtocolError::FrameTooLarge(decompressed.len()));
+    }
+    Ok(decompressed)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Utc};
+    use proptest::collection::vec;
+    use proptest::option;
+    use proptest::prelude::*;
+
+    use crate::*;
+    use crate::datagram::COMPRESSED_FRAME;
+
+    fn text(text: &str) -> Datagram {
+        Datagram::Message(ChatMessage::new(1, "Bob", ChatMessageContent::Text(text.to_string())))
+    }
+
+    #[tokio::test]
+    async fn test_compressed_frames() {
+        let long_text = "hello ".repeat(10_000);
+        let plain = SessionCodec { kind: CodecKind::Cbor, compression: false };
+        let compressed = SessionCodec { kind: CodecKind::Cbor, compression: true };
+
+        let mut plain_frame = Vec::new();
+        text(&long_text).write_to_stream(&mut plain_frame, &plain).await.unwrap();
+        let mut compressed_frame = Vec::new();
+        text(&long_text).write_to_stream(&mut compressed_frame, &compressed).await.unwrap();
+        assert!(compressed_frame.len() < plain_frame.len() / 10);
+
+        // Compressed frames are read by any codec
+        for frame in [&plain_frame, &compressed_frame] {
+            let datagram = Datagram::read_from_stream(&mut frame.as_slice(), &CodecKind::Cbor).await.unwrap();
+            assert!(matches!(datagram, Datagram::Message(message) if matches!(message.content, ChatMessageContent::Text(ref text) if *text == long_text)));
+        }
+
+        // Small frames are left alone, so they look the same to peers without compression
+        let mut small_plain = Vec::new();
+        text("hi").write_to_stream(&mut small_plain, &plain).await.unwrap();
+        let mut small_compressed = Vec::new();
+        text("hi").write_to_stream(&mut small_compressed, &compressed).await.unwrap();
+        assert_eq!(small_plain.len(), small_compressed.len());
+
+        // The size limit applies to the decompressed datagram
+        let result = Datagram::read_from_stream_limited(&mut compressed_frame.as_slice(), 10_000, &CodecKind::Cbor).await;
+        assert!(matches!(result, Err(ChatProtocolError::FrameTooLarge(_))));
+    }
+
+    /// Records the writes it gets, accepting at most `max_write` bytes of each.
+    struct RecordingWriter {
+        writes: Vec<Vec<u8>>,
+        max_write: usize,
+    }
+
+    impl tokio::io::AsyncWrite for RecordingWriter {
+        fn poll_write(mut self: std::pin::Pin<&mut Self>, _: &mut std::task::Context<'_>, buf: &[u8]) -> std::task::Poll<std::io::Result<usize>> {
+            let len = buf.len().min(self.max_write);
+            self.writes.push(buf[..len].to_vec());
+            std::task::Poll::Ready(Ok(len))
+        }
+
+        fn poll_flush(self: std::pin::Pin<&mut Self>, _: &mut std::task::Context<'_>) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: std::pin::Pin<&mut Self>, _: &mut std::task::Context<'_>) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_single_write() {
+        let codec = CodecKind::Cbor;
+        let datagram = text("hello");
+        let frame = datagram.encode_frame(&codec).unwrap();
+
+        // The header and the payload go out in one write
+        let mut writer = RecordingWriter { writes: Vec::new(), max_write: usize::MAX };
+        datagram.write_to_stream(&mut writer, &codec).await.unwrap();
+        assert_eq!(writer.writes, vec![frame.clone()]);
+
+        // Short writes are continued until the whole frame is written, the header included
+        let mut writer = RecordingWriter { writes: Vec::new(), max_write: 3 };
+        datagram.write_to_stream(&mut writer, &codec).await.unwrap();
+        assert!(writer.writes.len() > 1);
+        assert_eq!(writer.writes.concat(), frame);
+        let read = Datagram::read_from_stream(&mut &frame[..], &codec).await.unwrap();
+        assert!(matches!(read, Datagram::Message(message) if matches!(message.content, ChatMessageContent::Text(ref text) if text == "hello")));
+    }
+
+    #[tokio::test]
+    async fn test_write_timeout() {
+        let timeout = Some(std::time::Duration::from_millis(100));
+        let (mut stream, mut peer) = tokio::io::duplex(256);
+        text("hi").write_to_stream_timeout(&mut stream, &CodecKind::Cbor, timeout).await.unwrap();
+        let datagram = Datagram::read_from_stream(&mut peer, &CodecKind::Cbor).await.unwrap();
+        assert!(matches!(datagram, Datagram::Message(_)));
+
+        // A peer which doesn't read can't hang the writer
+        let result = text(&"hello ".repeat(100)).write_to_stream_timeout(&mut stream, &CodecKind::Cbor, timeout).await;
+        assert!(matches!(result, Err(ChatProtocolError::Timeout)));
+    }
+
+    #[test]
+    fn test_maximum_frame_size() {
+        let codec = CodecKind::Cbor;
+        let mut frame = Vec::new();
+        futures::executor::block_on(text("hi").write_to_stream(&mut frame, &codec)).unwrap();
+        let size = frame.len() - 4;
+
+        let read = |max_frame_size| futures::executor::block_on(Datagram::read_from_stream_limited(&mut frame.as_slice(), max_frame_size, &codec));
+        assert!(read(size).is_ok());
+        assert!(matches!(read(size - 1), Err(ChatProtocolError::FrameTooLarge(len)) if len == size));
+
+        // A frame is rejected by its announced size, before anything is read or allocated
+        let header = (COMPRESSED_FRAME - 1).to_le_bytes();
+        let result = futures::executor::block_on(Datagram::read_from_stream(&mut header.as_slice(), &codec));
+        assert!(matches!(result, Err(ChatProtocolError::FrameTooLarge(len)) if len == (COMPRESSED_FRAME - 1) as usize));
+
+        // Collections announcing more elements than the payload holds don't allocate them up front
+        let huge_collections: [(CodecKind, &[u8]); 3] = [
+            (CodecKind::Cbor, &[0xa1, 0x69, b'F', b'i', b'l', b'e', b'C', b'h', b'u', b'n', b'k', 0x9b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]),
+            (CodecKind::Cbor, &[0x5b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]),
+            (CodecKind::MessagePack, &[0x81, 0xa8, b'U', b's', b'e', b'r', b'L', b'i', b's', b't', 0xdd, 0xff, 0xff, 0xff, 0xff]),
+        ];
+        for (codec, payload) in huge_collections {
+            assert!(matches!(codec.decode(payload), Err(ChatProtocolError::MalformedMessage)));
+        }
+    }
+
+    fn codec() -> impl Strategy<Value = SessionCodec> {
+        (prop::sample::select(vec![CodecKind::Cbor, CodecKind::Json, CodecKind::MessagePack]), any::<bool>())
+            .prop_map(|(kind, compression)| SessionCodec { kind, compression })
+    }
+
+    fn timestamp() -> impl Strategy<Value = DateTime<Utc>> {
+        (0..4_102_444_800i64, 0..1_000_000_000u32).prop_map(|(secs, nanos)| DateTime::from_timestamp(secs, nanos).unwrap())
+    }
+
+    fn content() -> impl Strategy<Value = ChatMessageContent> {
+        prop_oneof![
+            any::<String>().prop_map(ChatMessageContent::Text),
+            // Repeated bytes compress well, so compressed frames are covered too
+            vec(0..4u8, 0..4096).prop_map(ChatMessageContent::Image),
+            (any::<String>(), vec(any::<u8>(), 0..2048)).prop_map(|(name, data)| ChatMessageContent::File(name, data)),
+            (any::<String>(), any::<String>(), option::of(any::<String>()))
+                .prop_map(|(url, title, description)| ChatMessageContent::LinkPreview { url, title, description }),
+            (prop::sample::select(AudioFormat::ALL.to_vec()), vec(any::<u8>(), 0..2048))
+                .prop_map(|(format, data)| ChatMessageContent::Audio { format, data }),
+        ]
+    }
+
+    fn message() -> impl Strategy<Value = ChatMessage> {
+        let reply_to = (any::<String>(), any::<MessageId>()).prop_map(|(sender, id)| ReplyTo { sender, id });
+        (any::<MessageId>(), any::<String>(), timestamp(), content(), option::of(any::<String>()), option::of(any::<String>()),
+         option::of(reply_to), any::<Option<MessageSeq>>(), any::<bool>(), any::<Option<u32>>())
+            .prop_map(|(id, sender, timestamp, content, nickname, origin, reply_to, seq, announcement, ttl)| ChatMessage {
+                id, sender, timestamp, content, nickname, origin, reply_to, seq, announcement, ttl,
+            })
+    }
+
+    fn response() -> impl Strategy<Value = ServerResponse> {
+        prop_oneof![
+            Just(ServerResponse::LoginOk),
+            any::<MessageId>().prop_map(ServerResponse::MessageAck),
+            vec(any::<String>(), 0..8).prop_map(ServerResponse::UserList),
+            timestamp().prop_map(|until| ServerResponse::Muted { until }),
+            (any::<String>(), option::of(timestamp())).prop_map(|(username, read_at)| ServerResponse::LastRead { username, read_at }),
+            (any::<u64>(), 0..1_000_000_000u32, any::<u64>(), any::<u64>(), any::<u64>())
+                .prop_map(|(secs, nanos, users_online, messages, database_size)| ServerResponse::Stats(ServerStatistics {
+                    uptime: std::time::Duration::new(secs, nanos), users_online, messages, database_size,
+                })),
+            (any::<MessageSeq>(), any::<bool>()).prop_map(|(last_id, more)| ServerResponse::FetchComplete { last_id, more }),
+            (any::<String>(), option::of(any::<String>()), option::of(vec(any::<u8>(), 0..2048)))
+                .prop_map(|(username, status, avatar_png)| ServerResponse::Profile(UserProfile { username, status, avatar_png })),
+            vec((any::<String>(), option::of(any::<String>())), 0..8)
+                .prop_map(|users| ServerResponse::UserStatuses(users.into_iter().map(|(username, status)| UserStatus { username, status }).collect())),
+        ]
+    }
+
+    fn datagram() -> impl Strategy<Value = Datagram> {
+        prop_oneof![
+            (any::<String>(), any::<String>(), any::<bool>(), any::<Option<MessageSeq>>())
+                .prop_map(|(username, password, compression, last_id)| Datagram::Login { username, password, compression, last_id }),
+            response().prop_map(Datagram::ServerResponse),
+            message().prop_map(Datagram::Message),
+            (any::<String>(), message()).prop_map(|(to, message)| Datagram::DirectMessage { to, message }),
+            (any::<TransferId>(), any::<MessageId>(), any::<String>(), any::<String>(), any::<u64>())
+                .prop_map(|(transfer_id, id, sender, filename, size)| Datagram::FileBegin { transfer_id, id, sender, kind: AttachmentKind::File(filename), size }),
+            (any::<TransferId>(), any::<u64>(), vec(any::<u8>(), 0..4096))
+                .prop_map(|(transfer_id, seq, data)| Datagram::FileChunk { transfer_id, seq, data }),
+            any::<String>().prop_map(|user| Datagram::AdminCommand(AdminCommand::Ban(user))),
+            (any::<String>(), any::<bool>()).prop_map(|(username, online)| Datagram::Presence { username, online }),
+            prop::sample::select(vec![Datagram::ListUsers, Datagram::Ping, Datagram::Pong, Datagram::Stats, Datagram::ListStatuses]),
+            option::of(any::<String>()).prop_map(Datagram::SetNickname),
+            (any::<AttachmentId>(), any::<String>(), option::of(any::<String>()), timestamp(), any::<String>(), any::<u64>())
+                .prop_map(|(id, sender, nickname, timestamp, filename, size)| Datagram::FileOffer { id, sender, nickname, timestamp, filename, size }),
+            timestamp().prop_map(|up_to| Datagram::MarkRead { up_to }),
+            (any::<String>(), any::<bool>(), any::<Option<MessageSeq>>())
+                .prop_map(|(username, compression, last_id)| Datagram::CertificateLogin { username, compression, last_id }),
+            any::<MessageSeq>().prop_map(|last_id| Datagram::FetchSince { last_id }),
+            (any::<String>(), any::<MessageId>(), any::<Option<MessageSeq>>())
+                .prop_map(|(sender, id, seq)| Datagram::MessageExpired { sender, id, seq }),
+            (option::of(any::<String>()), option::of(vec(any::<u8>(), 0..2048)))
+                .prop_map(|(status, avatar_png)| Datagram::SetProfile { status, avatar_png }),
+        ]
+    }
+
+    /// Writes a datagram into a frame like on the network.
+    fn frame(datagram: &Datagram, codec: &SessionCodec) -> Vec<u8> {
+        let mut frame = Vec::new();
+        futures::executor::block_on(datagram.write_to_stream(&mut frame, codec)).unwrap();
+        frame
+    }
+
+    /// Reads a frame, with a limit small enough that random headers can't make the test allocate much.
+    fn read(frame: &[u8]) -> Result<Datagram, ChatProtocolError> {
+        futures::executor::block_on(Datagram::read_from_stream_limited(&mut &frame[..], 64 * 1024, &CodecKind::Cbor))
+    }
+
+    proptest! {
+        #[test]
+        fn test_frame_roundtrip(datagram in datagram(), codec in codec()) {
+            // Datagrams can't be compared, so their encodings are
+            let frame = frame(&datagram, &codec);
+            let decoded = futures::executor::block_on(Datagram::read_from_stream(&mut frame.as_slice(), &codec.kind)).unwrap();
+            prop_assert_eq!(codec.kind.encode(&decoded).unwrap(), codec.kind.encode(&datagram).unwrap());
+        }
+
+        #[test]
+        fn test_truncated_frames(datagram in datagram(), codec in codec(), cut in any::<prop::sample::Index>()) {
+            let frame = frame(&datagram, &codec);
+            // A stream ending in the middle of a frame is broken
+            let end = cut.index(frame.len());
+            prop_assert!(matches!(read(&frame[..end]), Err(ChatProtocolError::IOError)));
+
+            // A frame announcing only part of the datagram is malformed, whatever the codec
+            let payload = &frame[4..];
+            let end = cut.index(payload.len());
+            let header = u32::from_le_bytes(frame[..4].try_into().unwrap()) & COMPRESSED_FRAME | end as u32;
+            let truncated = [&header.to_le_bytes()[..], &payload[..end]].concat();
+            let result = futures::executor::block_on(Datagram::read_from_stream(&mut truncated.as_slice(), &codec.kind));
+            prop_assert!(matches!(result, Err(ChatProtocolError::MalformedMessage)));
+        }
+
+        #[test]
+        fn test_random_payloads(payload in vec(any::<u8>(), 0..1024)) {
+            for codec in [CodecKind::Cbor, CodecKind::Json, CodecKind::MessagePack] {
+                prop_assert!(!matches!(codec.decode(&payload), Err(ChatProtocolError::IOError | ChatProtocolError::FrameTooLarge(_) | ChatProtocolError::Timeout)));
+            }
+        }
+
+        #[test]
+        fn test_random_frames(frame in vec(any::<u8>(), 0..1024)) {
+            // Anything goes as long as it doesn't panic
+            let _ = read(&frame);
+        }
+
+        #[test]
+        fn test_corrupted_frames(datagram in datagram(), codec in codec(), position in any::<prop::sample::Index>(), byte in any::<u8>()) {
+            let mut frame = frame(&datagram, &codec);
+            let position = position.index(frame.len() - 4) + 4;
+            frame[position] = byte;
+            let result = futures::executor::block_on(Datagram::read_from_stream(&mut frame.as_slice(), &codec.kind));
+            prop_assert!(matches!(result, Ok(_) | Err(ChatProtocolError::MalformedMessage)));
+        }
+    }
+}
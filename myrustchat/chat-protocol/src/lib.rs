@@ -0,0 +1,17 @@
+//! Wire protocol of myrustchat, for clients, bots and servers: the datagrams exchanged by the peers,
+//! the codecs encoding them and the framing which puts them on a stream.
+//!
+//! The version of the crate follows semantic versioning for the wire format. A minor version only adds
+//! to the protocol, e.g. new datagrams, responses or optional fields, which peers of older minor versions
+//! must be ready to receive: an unknown datagram is decoded as `ChatProtocolError::MalformedMessage`
+//! and the stream stays usable. Changes which an older peer can't handle, like removing or renaming a datagram
+//! or a field, changing the framing or a codec, come only with a new major version.
+//!
+//! The promise doesn't extend to the Rust API. `Datagram`, `ServerResponse`, `ChatMessage` and the other types
+//! mirror the wire format and are exhaustive, so the variants and fields added by a minor version break
+//! exhaustive `match`es and struct literals. Code using them should depend on a tilde requirement like `~1.0`,
+//! which allows only patch versions, and move to a new minor version on purpose.
+pub mod datagram;
+pub use datagram::*;
+pub mod codec;
+pub use codec::*;
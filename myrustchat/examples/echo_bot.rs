@@ -0,0 +1,28 @@
+//! Bot repeating every text message sent to it directly.
+//!
+//! ```sh
+//! server register -u echo -p secret
+//! cargo run --example echo_bot -- echo secret
+//! ```
+
+use chat::client::ChatClient;
+use chat::{ChatMessageContent, CodecKind};
+use futures::StreamExt;
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let mut args = std::env::args().skip(1);
+    let (Some(username), Some(password)) = (args.next(), args.next()) else {
+        anyhow::bail!("Usage: echo_bot <username> <password>");
+    };
+
+    let mut client = ChatClient::connect("127.0.0.1", 11111, &username, &password, CodecKind::Cbor).await?;
+    let sender = client.sender();
+    let mut messages = client.messages();
+    while let Some(incoming) = messages.next().await {
+        if let (true, ChatMessageContent::Text(text)) = (incoming.direct, incoming.message.content) {
+            sender.send_direct(&incoming.message.sender, &text).await?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,328 @@
+# Zprávy klienta a serveru v češtině. ID zpráv jsou stejná jako v en.ftl.
+# Tvary sloves jsou v přítomném čase nebo neosobní, aby nezáležely na rodu uživatele.
+
+## Common
+
+error = Chyba: { $error }
+ok = Ok.
+connected = Ok, připojeno k serveru.
+bye = Ok, na shledanou.
+your-name = Vaše jméno je { $identity }
+unix-socket = unixový socket
+
+## Login
+
+waiting-for-login = Čekání na přihlášení...
+login-successful = Přihlášení proběhlo úspěšně.
+registering = Registrace uživatele { $username }...
+registered = Registrace uživatele { $username } je hotová.
+verification-code-prompt = Ověřovací kód:
+authentication-code-prompt = Autentizační kód:
+password-prompt = Heslo pro { $username }:
+could-not-read-code = Chyba: Kód nelze načíst: { $error }
+could-not-read-password = Chyba: Heslo nelze načíst: { $error }
+guest-name-taken = Jméno hosta je obsazené, zvolte jinou přezdívku.
+logging-in-to = Přihlašování k účtu { $name }.
+joining = Připojování k serveru { $name }.
+username-required = Chyba: Je potřeba uživatelské jméno, zadejte ho přes -u nebo v profilu.
+several-profiles-flags = Chyba: S více profily se server a přihlašovací údaje berou jen z profilů.
+profile-given-twice = Chyba: Profil { $name } je zadaný dvakrát.
+no-such-profile = Profil { $name } neexistuje.
+script-single-account = Režimy --script a --oneshot se přihlašují jen k jednomu účtu.
+no-default-control-socket = Chyba: Řídicí socket tu nemá výchozí umístění, zadejte ho přes --control-socket.
+
+## Keyring
+
+keyring-unavailable = Varování: Klíčenka není dostupná: { $error }
+keyring-read-failed = Varování: Klíčenku nelze přečíst: { $error }
+keyring-password-saved = Heslo bylo uloženo do klíčenky.
+keyring-save-failed = Varování: Heslo nelze uložit do klíčenky: { $error }
+keyring-password-removed = Uložené heslo bylo odstraněno z klíčenky.
+keyring-remove-failed = Varování: Heslo nelze odstranit z klíčenky: { $error }
+keyring-password-rejected = Uložené heslo bylo odmítnuto a odstraněno z klíčenky.
+keyring-remove-rejected-failed = Varování: Odmítnuté heslo nelze odstranit z klíčenky: { $error }
+password-file-readable = Varování: { $path } obsahuje heslo, ale mohou ho číst i jiní uživatelé.
+
+## Connection
+
+connection-broken = Chyba: Spojení se serverem bylo přerušeno.
+server-stalled = Chyba: Server přestal přijímat zprávy.
+frame-too-large = Chyba: Server poslal rámec o { $size } bajtech, který překračuje limit.
+reconnecting = { $reason } Opětovné připojování...
+could-not-log-in-again = Chyba: Nelze se znovu přihlásit: { $error }
+reconnected = { $count ->
+    [0] Znovu připojeno.
+    [one] Znovu připojeno, odeslána 1 čekající zpráva.
+    [few] Znovu připojeno, odeslány { $count } čekající zprávy.
+   *[other] Znovu připojeno, odesláno { $count } čekajících zpráv.
+}
+messages-pending = { $count ->
+    [one] 1 čekající zpráva
+    [few] { $count } čekající zprávy
+   *[other] { $count } čekajících zpráv
+}
+not-connected-pending = Nepřipojeno, { $pending }.
+not-connected = Klient není připojen k serveru, do opětovného připojení se uchovávají jen zprávy.
+stream-broken = Spojení je přerušené
+file-operation-failed = Operace se souborem selhala.
+kicked = Administrátor vás vyhodil ze serveru.
+banned = Administrátor vám zakázal přístup na server.
+server-full = Kvůli nečinnosti jste byli odpojeni, aby se uvolnilo místo na plném serveru.
+logged-in-elsewhere = Byli jste odpojeni, protože jste se přihlásili z jiného spojení.
+unexpected-datagram = Chyba: neočekávaný datagram
+malformed-message = Chyba: Přijata poškozená zpráva.
+
+## Received messages
+
+recipient-you = vám
+sending-image = posílá obrázek
+sending-file = posílá soubor
+sent-voice-note = posílá hlasovou zprávu ({ $size })
+sent-image-thumbnail = posílá obrázek, stáhnete ho příkazem .get { $id }
+sent-file-offer = posílá soubor { $filename } ({ $size }), stáhnete ho příkazem .get { $id }
+earlier-message = (starší zpráva)
+notification-mention = { $sender } vás zmiňuje
+notification-direct = Zpráva od { $sender }
+notification-failed = Chyba: Oznámení nelze zobrazit: { $error }
+unsupported-direct-content = Chyba: nepodporovaný obsah soukromé zprávy od { $sender }
+user-joined = *** { $username } se připojuje do chatu.
+user-left = *** { $username } opouští chat.
+announcement = *** Server: { $text }
+user-renamed = *** { $username } teď vystupuje jako { $nickname }.
+user-nickname-removed = *** { $username } už nepoužívá přezdívku.
+message-expired = *** Zpráva #{ $number } od { $sender } vypršela.
+earlier-message-expired = *** Zpráva od { $sender } vypršela.
+history-image = posílá obrázek
+history-file = posílá soubor { $filename }
+history-voice-note = posílá hlasovou zprávu
+marker-mention = [zmínka]
+marker-announcement = [oznámení]
+marker-ephemeral = [zmizí za { $ttl }]
+marker-image = [obrázek, { $size }]
+marker-file = [soubor { $filename }, { $size }]
+marker-voice-note = [hlasová zpráva, { $size }]
+marker-thumbnail = [náhled obrázku, stáhnete ho příkazem .get { $id }]
+marker-file-offer = [soubor { $filename }, { $size }, stáhnete ho příkazem .get { $id }]
+marker-link = [odkaz { $url }: { $title }]
+
+## Received files
+
+image-saved = Obrázek uložen do { $path }
+file-saved = Soubor uložen do { $path }
+voice-note-saved = Hlasová zpráva uložena do { $path }
+thumbnail-saved = Náhled uložen do { $path }
+file-skipped = Přeskočeno, soubor se stejným jménem už existuje.
+incoming-file-failed = Přijatý soubor nelze uložit.
+label-an-image = obrázek
+label-a-voice-note = hlasovou zprávu
+downloading = Stahování: { $label } ({ $size })
+receiving = Příjem: { $label }
+download-interrupted = Stahování bylo přerušeno, pokračujte příkazem .get { $id }.
+resuming-download = Stahování pokračuje od { $offset }.
+could-not-write = Chyba: Nelze zapisovat do { $path }: { $error }
+could-not-write-file = { $path } nelze zapsat.
+could-not-write-received = Chyba: Nelze zapisovat do { $path }
+could-not-open-path = Chyba: { $path } nelze otevřít
+could-not-truncate = Chyba: { $path } nelze zkrátit
+could-not-create-directory = Chyba: Adresář nelze vytvořit: { $path }
+could-not-create-path = Chyba: { $path } nelze vytvořit
+could-not-remove = Chyba: { $path } nelze odstranit
+could-not-move = Chyba: { $from } nelze přesunout do { $to }
+
+## Responses of the server
+
+user-offline = Chyba: uživatel { $username } není online, zpráva nebyla doručena.
+attachment-not-found = Chyba: příloha { $id } neexistuje.
+attachment-rejected = Chyba: server přílohu odmítl: { $reason }
+message-rejected = Chyba: zpráva nebyla doručena: { $reason }
+muted-for-flooding = Kvůli zahlcování jste ztlumeni do { $until }, zpráva nebyla doručena.
+online-users = Uživatelé online ({ $count }):
+password-changed = Heslo bylo změněno.
+password-change-failed = Chyba: heslo nelze změnit: { $reason }
+nickname-changed = Teď vystupujete jako { $nickname }.
+nickname-removed = Přezdívka byla odstraněna.
+nickname-rejected = Chyba: přezdívku nelze změnit: { $reason }
+no-blocked-users = Nikoho neblokujete.
+blocked-users = Blokovaní uživatelé ({ $count }): { $users }
+server-stats = Server běží { $uptime }, uživatelé online: { $users }, uložené zprávy: { $messages }, databáze { $size }.
+last-read = { $username } naposledy četl(a) chat { $time }.
+never-read = { $username } chat ještě nečetl(a).
+profile-set = Profil změněn.
+profile-rejected = Chyba: profil nelze změnit: { $reason }
+profile-status = { $username }: { $status }
+no-profile = { $username } nemá status ani avatar.
+avatar-saved = Avatar uživatele { $username } uložen jako { $filename }.
+message-not-acknowledged = Varování: server nepotvrdil zprávu { $id } do { $seconds } s.
+messages-not-acknowledged = { $count ->
+    [one] { $count } zpráva nebyla serverem potvrzena do { $seconds } s.
+    [few] { $count } zprávy nebyly serverem potvrzeny do { $seconds } s.
+   *[other] { $count } zpráv nebylo serverem potvrzeno do { $seconds } s.
+}
+server-reported-errors = Server ohlásil chyby.
+
+## Commands
+
+no-recent-message = Chyba: nedávná zpráva #{ $number } neexistuje.
+request-users-failed = Seznam uživatelů nelze vyžádat.
+request-seen-failed = Stav přečtení nelze vyžádat.
+block-failed = Uživatele nelze zablokovat.
+unblock-failed = Uživatele nelze odblokovat.
+request-blocks-failed = Seznam blokovaných nelze vyžádat.
+request-stats-failed = Statistiky serveru nelze vyžádat.
+admin-command-failed = Administrátorský příkaz nelze odeslat.
+password-request-failed = Žádost o změnu hesla nelze odeslat.
+request-attachment-failed = Přílohu nelze vyžádat.
+nickname-request-failed = Žádost o změnu přezdívky nelze odeslat.
+profile-request-failed = Žádost o změnu profilu nelze odeslat.
+request-profile-failed = Profil nelze vyžádat.
+no-muted-users = Žádní uživatelé nejsou ztlumení.
+muted-users = Ztlumení uživatelé: { $users }
+user-muted = { $username } je ztlumen(a), zprávy od něj/ní se nezobrazují.
+user-unmuted = { $username } už není ztlumen(a).
+user-not-muted = { $username } není ztlumen(a).
+dnd-state = Nerušit je { $state ->
+    [on] zapnuto
+   *[off] vypnuto
+}.
+dnd-scheduled = Nerušit je { $state ->
+    [on] zapnuto
+   *[off] vypnuto
+} a zapíná se každý den v { $schedule }.
+invalid-time-span = očekáván časový rozsah jako 22:00-07:00, zadáno { $value }
+accounts = Účty: { $accounts }
+account = { $name } ({ $username })
+account-active = { $name } ({ $username }, aktivní)
+switched-account = Teď píšete jako { $identity }.
+no-such-account = Chyba: účet { $name } neexistuje, seznam vypíše .switch.
+could-not-open-script = Skript { $path } nelze otevřít.
+could-not-read-script = Skript nelze přečíst.
+could-not-read-stdin = Chyba: Ze standardního vstupu nelze číst: { $error }
+could-not-write-input-history = Chyba: { $path } nelze zapsat: { $error }
+
+## Sent files
+
+image-converted = { $filename } převeden do PNG za { $seconds } s.
+image-sent = Obrázek odeslán.
+sticker-sent = Nálepka { $name } odeslána.
+no-stickers = Adresář nálepek neobsahuje žádné obrázky.
+stickers = Nálepky ({ $count }): { $names }
+no-sticker-dir = Adresář nálepek není nastaven, nastavte dir v tabulce [stickers] konfiguračního souboru.
+could-not-read-sticker-dir = Adresář nálepek { $path } nelze přečíst.
+no-such-sticker = Nálepka { $name } neexistuje, seznam vypíše .stickers.
+voice-note-sent = Hlasová zpráva odeslána.
+file-sent = Soubor { $filename } odeslán.
+sending-image-progress = Odesílání obrázku
+sending-file-progress = Odesílání { $filename }
+sending-voice-note-progress = Odesílání hlasové zprávy
+resuming-upload = Odesílání pokračuje od { $offset }.
+transfer-start-failed = Přenos souboru nelze zahájit.
+transfer-refused = Server přenos odmítl.
+transfer-cancel-failed = Přenos souboru nelze zrušit.
+transfer-finish-failed = Přenos souboru nelze dokončit.
+chunk-send-failed = Část souboru nelze odeslat.
+could-not-seek = V souboru nelze přejít na pozici.
+could-not-read-file = Soubor nelze přečíst.
+could-not-open-file = Soubor { $filename } nelze otevřít.
+could-not-read-named-file = Soubor { $filename } nelze přečíst.
+could-not-read-metadata = Metadata souboru { $filename } nelze přečíst.
+unknown-image-format = Formát obrázku { $filename } nelze rozpoznat.
+could-not-decode = { $filename } nelze dekódovat.
+could-not-encode = { $filename } nelze zakódovat
+avatar-too-large = Avatar je i po zmenšení větší než { $max }, zkuste jednodušší obrázek.
+voice-note-formats = Hlasové zprávy mohou být soubory { $formats }.
+voice-note-too-large = { $filename } má { $size }, hlasové zprávy mohou mít nejvýš { $limit }.
+
+## Configuration files
+
+could-not-read-config = Konfigurační soubor { $path } nelze přečíst.
+invalid-config = Neplatný konfigurační soubor { $path }.
+not-a-table = { $table } v { $path } není tabulka.
+could-not-create-config-directory = Adresář { $path } nelze vytvořit.
+could-not-write-config = Konfigurační soubor { $path } nelze zapsat.
+could-not-read-theme = Soubor motivu { $path } nelze přečíst.
+invalid-theme = Neplatný soubor motivu { $path }.
+empty-palette = Neplatný soubor motivu { $path }: paleta odesílatelů je prázdná.
+could-not-open-history = Soubor historie { $path } nelze otevřít.
+could-not-create-history-table = Tabulku history nelze vytvořit
+could-not-write-history = Do souboru historie nelze zapisovat.
+could-not-read-history = Soubor historie nelze přečíst.
+
+## Hooks
+
+hook-failed = Chyba: hook { $program } selhal: { $error }
+hook-exit-status = skončil se stavem { $status }
+
+## Daemon mode
+
+daemon-started = Klient běží jako démon, výstup se připojuje do { $log_file }. Připojíte se k němu příkazem: client --control-socket { $socket } attach
+daemon-greeting = Připojeno k démonu uživatele { $identity }, Ctrl-D odpojí a .quit démona ukončí.
+daemon-already-listening = Na { $path } už naslouchá jiný démon.
+daemon-stopped = Démon skončil.
+daemon-unsupported = Režim démona potřebuje unixové sockety, které tato platforma nepodporuje.
+not-a-socket = { $path } existuje a není to socket.
+no-daemon = Na { $path } nenaslouchá žádný démon.
+foreign-attach = Chyba: K démonu se pokusil připojit jiný uživatel.
+could-not-accept = Chyba: Připojujícího se klienta nelze přijmout: { $error }
+could-not-create = { $path } nelze vytvořit.
+could-not-listen = Na { $path } nelze naslouchat.
+could-not-open-log-file = Soubor logu { $path } nelze otevřít.
+could-not-handle-hangups = Zavěšení terminálu nelze obsloužit.
+could-not-read-control-socket = Řídicí socket nelze přečíst.
+could-not-read-daemon = Z démona nelze číst.
+lines-skipped = { $count ->
+    [one] *** { $count } řádek přeskočen, viz soubor logu.
+    [few] *** { $count } řádky přeskočeny, viz soubor logu.
+   *[other] *** { $count } řádků přeskočeno, viz soubor logu.
+}
+
+## Terminal user interface
+
+tui-messages = Zprávy
+tui-input = Vstup
+tui-users = Uživatelé
+tui-connected = připojeno
+tui-reconnecting = opětovné připojování
+tui-disconnected = odpojeno
+tui-scrolled = posunuto o { $count } řádků výš
+tui-keys = PgUp/PgDn: posun, Esc: konec
+tui-not-connected = Chyba: klient není připojen k serveru.
+could-not-draw = Terminál nelze vykreslit.
+could-not-read-terminal = Z terminálu nelze číst.
+
+## Server commands
+
+user-registered = Uživatel { $username } byl úspěšně zaregistrován.
+user-password-changed = Heslo uživatele { $username } bylo úspěšně změněno.
+user-deleted = Uživatel { $username } byl úspěšně smazán.
+admin-granted = Uživatel { $username } je teď administrátor.
+admin-revoked = Uživatel { $username } už není administrátor.
+two-factor-enabled = Dvoufázové ověření pro { $username } je zapnuté, přidejte tuto URI do autentizační aplikace:
+two-factor-disabled = Dvoufázové ověření pro { $username } je vypnuté.
+certificate-added = Certifikát { $fingerprint } byl zaregistrován pro { $username }.
+certificate-removed = Certifikát { $fingerprint } uživatele { $username } byl odstraněn.
+certificate-not-registered = Certifikát { $fingerprint } není zaregistrován.
+login-attempt = { $username } z { $address }: { $outcome }
+messages-exported = Exportováno zpráv do { $path }: { $count }.
+users-imported = Importováno uživatelů z { $path }: { $count }.
+import-invalid-rows = Žádní uživatelé nebyli importováni, neplatné řádky: { $count }.
+could-not-read-path = { $path } nelze přečíst.
+backup-saved = Databáze a přílohy ({ $count }) byly uloženy do { $path }.
+memory-database-run-only = Databázi v paměti může použít jen příkaz run.
+invite-needs-host = Server naslouchá na všech adresách, zadejte přes --host tu, ke které se klienti připojují.
+bench-start = Rozesílání { $messages } zpráv o { $size } bajtech od { $senders } odesílatelů { $clients } klientům...
+bench-elapsed = trvání:       { $seconds } s
+bench-throughput = propustnost:  { $messages } zpráv/s, { $deliveries } doručení/s
+bench-latency = latence:      p50 { $p50 } ms, p99 { $p99 } ms, max { $max } ms
+bench-lost = ztraceno:     { $lost } z { $total } doručení
+demo-started = Ukázkový server běží na { $address } a vše uchovává jen v paměti. Spusťte klienta každého ukázkového uživatele ve vlastním terminálu:
+demo-stop = Ukázku ukončíte stiskem Ctrl+C.
+demo-welcome = Vítejte v ukázce myrustchat! Vše na tomto serveru se po jeho zastavení zapomene.
+demo-chat = Spusťte ve dvou terminálech klienty uživatelů { $first } a { $second }, v jednom napište zprávu a stiskem Enter ji pošlete, ve druhém uvidíte, jak dorazí.
+demo-commands = Zkuste .who pro seznam připojených, .msg { $username } ahoj pro soukromou zprávu uživateli { $username }, .file <cesta> pro odeslání souboru a .quit pro odchod.
+storage-user = uživatel
+storage-attachments = přílohy
+storage-used = využito
+storage-quota = kvóta
+storage-share = z kvóty
+storage-unlimited = bez limitu
+storage-total = Celkem: { $attachments } příloh, { $used }
@@ -0,0 +1,318 @@
+# Messages of the client and the server in English, the fallback for messages missing in other languages.
+# The IDs are shared by all languages, `i18n::tests::test_languages_complete` checks that none is missing.
+
+## Common
+
+error = Error: { $error }
+ok = Ok.
+connected = Ok, connected to server.
+bye = Ok, bye.
+your-name = Your name is { $identity }
+unix-socket = unix socket
+
+## Login
+
+waiting-for-login = Waiting for login...
+login-successful = Login successful.
+registering = Registering { $username }...
+registered = Registered as { $username }.
+verification-code-prompt = Verification code:
+authentication-code-prompt = Authentication code:
+password-prompt = Password for { $username }:
+could-not-read-code = Error: Could not read the code: { $error }
+could-not-read-password = Error: Could not read the password: { $error }
+guest-name-taken = The guest name is taken, choose another nickname.
+logging-in-to = Logging in to { $name }.
+joining = Joining { $name }.
+username-required = Error: A username is required, give it with -u or in a profile.
+several-profiles-flags = Error: With several profiles, the server and the login are only taken from the profiles.
+profile-given-twice = Error: The profile { $name } is given twice.
+no-such-profile = There is no profile named { $name }.
+script-single-account = The --script and --oneshot modes log in to a single account.
+no-default-control-socket = Error: The control socket has no default location here, give it with --control-socket.
+
+## Keyring
+
+keyring-unavailable = Warning: The keyring is not available: { $error }
+keyring-read-failed = Warning: Could not read the keyring: { $error }
+keyring-password-saved = The password was saved in the keyring.
+keyring-save-failed = Warning: Could not save the password in the keyring: { $error }
+keyring-password-removed = The saved password was removed from the keyring.
+keyring-remove-failed = Warning: Could not remove the password from the keyring: { $error }
+keyring-password-rejected = The saved password was rejected and removed from the keyring.
+keyring-remove-rejected-failed = Warning: Could not remove the rejected password from the keyring: { $error }
+password-file-readable = Warning: { $path } contains a password but can be read by other users.
+
+## Connection
+
+connection-broken = Error: Connection with server broken.
+server-stalled = Error: The server stopped taking messages.
+frame-too-large = Error: Server sent a frame of { $size } bytes which exceeds the limit.
+reconnecting = { $reason } Reconnecting...
+could-not-log-in-again = Error: Could not log in again: { $error }
+reconnected = { $count ->
+    [0] Reconnected.
+    [one] Reconnected, sent 1 pending message.
+   *[other] Reconnected, sent { $count } pending messages.
+}
+messages-pending = { $count ->
+    [one] 1 message pending
+   *[other] { $count } messages pending
+}
+not-connected-pending = Not connected, { $pending }.
+not-connected = Not connected to the server, only messages are kept until the client reconnects.
+stream-broken = Stream is broken
+file-operation-failed = File operation failed.
+kicked = You were kicked from the server by an administrator.
+banned = You were banned from the server by an administrator.
+server-full = You were disconnected for inactivity to make room on the full server.
+logged-in-elsewhere = You were disconnected because you logged in from another connection.
+unexpected-datagram = Error: unexpected datagram
+malformed-message = Error: Malformed message received.
+
+## Received messages
+
+recipient-you = you
+sending-image = sending an image
+sending-file = sending a file
+sent-voice-note = sent a voice note ({ $size })
+sent-image-thumbnail = sent an image, type .get { $id } to download it
+sent-file-offer = sent the file { $filename } ({ $size }), type .get { $id } to download it
+earlier-message = (an earlier message)
+notification-mention = { $sender } mentioned you
+notification-direct = Message from { $sender }
+notification-failed = Error: Could not show a notification: { $error }
+unsupported-direct-content = Error: unsupported direct message content from { $sender }
+user-joined = *** { $username } joined the chat.
+user-left = *** { $username } left the chat.
+announcement = *** Server: { $text }
+user-renamed = *** { $username } is now known as { $nickname }.
+user-nickname-removed = *** { $username } removed their nickname.
+message-expired = *** Message #{ $number } of { $sender } expired.
+earlier-message-expired = *** A message of { $sender } expired.
+history-image = sent an image
+history-file = sent a file { $filename }
+history-voice-note = sent a voice note
+marker-mention = [mention]
+marker-announcement = [announcement]
+marker-ephemeral = [expires in { $ttl }]
+marker-image = [image, { $size }]
+marker-file = [file { $filename }, { $size }]
+marker-voice-note = [voice note, { $size }]
+marker-thumbnail = [image preview, download it with .get { $id }]
+marker-file-offer = [file { $filename }, { $size }, download it with .get { $id }]
+marker-link = [link { $url }: { $title }]
+
+## Received files
+
+image-saved = Image saved to { $path }
+file-saved = File saved to { $path }
+voice-note-saved = Voice note saved to { $path }
+thumbnail-saved = Thumbnail saved to { $path }
+file-skipped = Skipped, a file with the same name already exists.
+incoming-file-failed = Failed to save an incoming file.
+label-an-image = an image
+label-a-voice-note = a voice note
+downloading = Downloading { $label } ({ $size })
+receiving = Receiving { $label }
+download-interrupted = The download was interrupted, type .get { $id } to resume it.
+resuming-download = Resuming the download at { $offset }.
+could-not-write = Error: Could not write to { $path }: { $error }
+could-not-write-file = Could not write { $path }.
+could-not-write-received = Error: Could not write to { $path }
+could-not-open-path = Error: Could not open { $path }
+could-not-truncate = Error: Could not truncate { $path }
+could-not-create-directory = Error: Failed to create directory: { $path }
+could-not-create-path = Error: Could not create { $path }
+could-not-remove = Error: Could not remove { $path }
+could-not-move = Error: Could not move { $from } to { $to }
+
+## Responses of the server
+
+user-offline = Error: user { $username } is not online, message not delivered.
+attachment-not-found = Error: there is no attachment { $id }.
+attachment-rejected = Error: the attachment was rejected by the server: { $reason }
+message-rejected = Error: the message was not delivered: { $reason }
+muted-for-flooding = You are muted for flooding until { $until }, your message was not delivered.
+online-users = Online users ({ $count }):
+password-changed = Password changed.
+password-change-failed = Error: could not change the password: { $reason }
+nickname-changed = You are now known as { $nickname }.
+nickname-removed = Nickname removed.
+nickname-rejected = Error: could not change the nickname: { $reason }
+no-blocked-users = You don't block anyone.
+blocked-users = Blocked users ({ $count }): { $users }
+server-stats = Server up for { $uptime }, { $users } users online, { $messages } messages stored, database { $size }.
+last-read = { $username } last read the chat at { $time }.
+never-read = { $username } hasn't read the chat yet.
+profile-set = Profile updated.
+profile-rejected = Error: could not change the profile: { $reason }
+profile-status = { $username }: { $status }
+no-profile = { $username } has no status or avatar.
+avatar-saved = Avatar of { $username } saved as { $filename }.
+message-not-acknowledged = Warning: message { $id } was not acknowledged by the server within { $seconds }s.
+messages-not-acknowledged = { $count } messages were not acknowledged by the server within { $seconds }s.
+server-reported-errors = The server reported errors.
+
+## Commands
+
+no-recent-message = Error: there is no recent message #{ $number }.
+request-users-failed = Failed to request the list of users.
+request-seen-failed = Failed to request the read status.
+block-failed = Failed to block the user.
+unblock-failed = Failed to unblock the user.
+request-blocks-failed = Failed to request the block list.
+request-stats-failed = Failed to request the server statistics.
+admin-command-failed = Failed to send an admin command.
+password-request-failed = Failed to send a password change request.
+request-attachment-failed = Failed to request an attachment.
+nickname-request-failed = Failed to send a nickname change request.
+profile-request-failed = Failed to send a profile change request.
+request-profile-failed = Failed to request the profile.
+no-muted-users = No users are muted.
+muted-users = Muted users: { $users }
+user-muted = { $username } is muted, their messages are not shown.
+user-unmuted = { $username } is not muted anymore.
+user-not-muted = { $username } is not muted.
+dnd-state = Do not disturb is { $state ->
+    [on] on
+   *[off] off
+}.
+dnd-scheduled = Do not disturb is { $state ->
+    [on] on
+   *[off] off
+}, and on every day at { $schedule }.
+invalid-time-span = expected a time span like 22:00-07:00, got { $value }
+accounts = Accounts: { $accounts }
+account = { $name } ({ $username })
+account-active = { $name } ({ $username }, active)
+switched-account = Now chatting as { $identity }.
+no-such-account = Error: there is no account { $name }, type .switch to list them.
+could-not-open-script = Could not open script { $path }.
+could-not-read-script = Could not read the script.
+could-not-read-stdin = Error: Can't read from stdin: { $error }
+could-not-write-input-history = Error: Could not write { $path }: { $error }
+
+## Sent files
+
+image-converted = Converted { $filename } to PNG in { $seconds }s.
+image-sent = Image sent.
+sticker-sent = Sticker { $name } sent.
+no-stickers = The sticker directory has no images.
+stickers = Stickers ({ $count }): { $names }
+no-sticker-dir = No sticker directory is configured, set dir in the [stickers] table of the configuration file.
+could-not-read-sticker-dir = Could not read the sticker directory { $path }.
+no-such-sticker = There is no sticker { $name }, type .stickers to list them.
+voice-note-sent = Voice note sent.
+file-sent = File { $filename } sent.
+sending-image-progress = Sending image
+sending-file-progress = Sending { $filename }
+sending-voice-note-progress = Sending voice note
+resuming-upload = Resuming the upload at { $offset }.
+transfer-start-failed = Failed to start a file transfer.
+transfer-refused = The server refused the transfer.
+transfer-cancel-failed = Failed to cancel a file transfer.
+transfer-finish-failed = Failed to finish a file transfer.
+chunk-send-failed = Failed to send a file chunk.
+could-not-seek = Could not seek in the file.
+could-not-read-file = Could not read the file.
+could-not-open-file = Could not open file { $filename }.
+could-not-read-named-file = Could not read file { $filename }.
+could-not-read-metadata = Could not read metadata of { $filename }.
+unknown-image-format = Could not recognize the image format of { $filename }.
+could-not-decode = Could not decode { $filename }.
+could-not-encode = Could not encode { $filename }
+avatar-too-large = The avatar is larger than { $max } even when shrunk, try a simpler picture.
+voice-note-formats = Voice notes can be { $formats } files.
+voice-note-too-large = { $filename } has { $size }, voice notes can have at most { $limit }.
+
+## Configuration files
+
+could-not-read-config = Could not read configuration file { $path }.
+invalid-config = Invalid configuration file { $path }.
+not-a-table = { $table } in { $path } is not a table.
+could-not-create-config-directory = Could not create directory { $path }.
+could-not-write-config = Could not write configuration file { $path }.
+could-not-read-theme = Could not read theme file { $path }.
+invalid-theme = Invalid theme file { $path }.
+empty-palette = Invalid theme file { $path }: the senders palette is empty.
+could-not-open-history = Could not open history file { $path }.
+could-not-create-history-table = Failed to create table: history
+could-not-write-history = Could not write to the history file.
+could-not-read-history = Could not read the history file.
+
+## Hooks
+
+hook-failed = Error: hook { $program } failed: { $error }
+hook-exit-status = exited with { $status }
+
+## Daemon mode
+
+daemon-started = Running as a daemon, the output is appended to { $log_file }. Attach to it with: client --control-socket { $socket } attach
+daemon-greeting = Attached to the daemon of { $identity }, Ctrl-D detaches and .quit stops the daemon.
+daemon-already-listening = A daemon is already listening on { $path }.
+daemon-stopped = The daemon has stopped.
+daemon-unsupported = The daemon mode needs Unix sockets, which are not supported on this platform.
+not-a-socket = { $path } exists and is not a socket.
+no-daemon = No daemon is listening on { $path }.
+foreign-attach = Error: Another user tried to attach to the daemon.
+could-not-accept = Error: Could not accept an attached client: { $error }
+could-not-create = Could not create { $path }.
+could-not-listen = Could not listen on { $path }.
+could-not-open-log-file = Could not open the log file { $path }.
+could-not-handle-hangups = Could not handle hangups.
+could-not-read-control-socket = Could not read the control socket.
+could-not-read-daemon = Could not read from the daemon.
+lines-skipped = *** { $count } lines skipped, see the log file.
+
+## Terminal user interface
+
+tui-messages = Messages
+tui-input = Input
+tui-users = Users
+tui-connected = connected
+tui-reconnecting = reconnecting
+tui-disconnected = disconnected
+tui-scrolled = scrolled up { $count } lines
+tui-keys = PgUp/PgDn: scroll, Esc: quit
+tui-not-connected = Error: not connected to the server.
+could-not-draw = Could not draw the terminal.
+could-not-read-terminal = Could not read from the terminal.
+
+## Server commands
+
+user-registered = User { $username } registered successfully.
+user-password-changed = Password of { $username } changed successfully.
+user-deleted = User { $username } deleted successfully.
+admin-granted = User { $username } is now an admin.
+admin-revoked = User { $username } is no longer an admin.
+two-factor-enabled = Two-factor authentication enabled for { $username }, add this URI to an authenticator app:
+two-factor-disabled = Two-factor authentication disabled for { $username }.
+certificate-added = Certificate { $fingerprint } registered for { $username }.
+certificate-removed = Certificate { $fingerprint } of { $username } removed.
+certificate-not-registered = The certificate { $fingerprint } is not registered.
+login-attempt = { $username } from { $address }: { $outcome }
+messages-exported = Exported { $count } messages to { $path }.
+users-imported = Imported { $count } users from { $path }.
+import-invalid-rows = No users were imported, { $count } rows are invalid.
+could-not-read-path = Could not read { $path }.
+backup-saved = Saved the database and { $count } attachments to { $path }.
+memory-database-run-only = The in-memory database can only be used by the run command.
+invite-needs-host = The server listens on all addresses, give the one the clients connect to with --host.
+bench-start = Broadcasting { $messages } messages of { $size } bytes from { $senders } senders to { $clients } clients...
+bench-elapsed = elapsed:      { $seconds } s
+bench-throughput = throughput:   { $messages } messages/s, { $deliveries } deliveries/s
+bench-latency = latency:      p50 { $p50 } ms, p99 { $p99 } ms, max { $max } ms
+bench-lost = lost:         { $lost } of { $total } deliveries
+demo-started = The demo server is running on { $address }, with everything kept in memory. Start a client for each demo user in its own terminal:
+demo-stop = Press Ctrl+C to stop the demo.
+demo-welcome = Welcome to the myrustchat demo! Everything on this server is forgotten when it stops.
+demo-chat = Start the clients of { $first } and { $second } in two terminals, then type a message in one of them and press Enter to see it arrive in the other.
+demo-commands = Try .who to see who is online, .msg { $username } hi for a direct message to { $username }, .file <path> to send a file and .quit to leave.
+storage-user = user
+storage-attachments = attachments
+storage-used = used
+storage-quota = quota
+storage-share = of quota
+storage-unlimited = unlimited
+storage-total = Total: { $attachments } attachments, { $used }
@@ -0,0 +1,337 @@
+use std::collections::VecDeque;
+use std::io::IoSlice;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+use bytes::{Buf, Bytes};
+use tokio::io::AsyncWriteExt;
+
+use crate::client::WriteHalf;
+use crate::{ChatProtocolError, Codec, Datagram};
+
+/// Outcome of writing a batch, shared by all datagrams in it.
+type BatchResult = Arc<OnceLock<Result<(), ChatProtocolError>>>;
+
+/// Frames queued since the last write.
+#[derive(Default)]
+struct Batch {
+    frames: Frames,
+    result: BatchResult,
+}
+
+/// Frames written one after another with vectored writes, so that they aren't copied into a single buffer.
+#[derive(Default)]
+struct Frames {
+    frames: VecDeque<Bytes>,
+    /// Number of bytes not written yet
+    remaining: usize,
+}
+
+impl Frames {
+    fn push(&mut self, frame: Bytes) {
+        self.remaining += frame.len();
+        self.frames.push_back(frame);
+    }
+}
+
+impl Buf for Frames {
+    fn remaining(&self) -> usize {
+        self.remaining
+    }
+
+    fn chunk(&self) -> &[u8] {
+        self.frames.front().map(Bytes::as_ref).unwrap_or_default()
+    }
+
+    fn advance(&mut self, mut cnt: usize) {
+        self.remaining -= cnt;
+        while let Some(front) = self.frames.front_mut() {
+            if cnt < front.len() {
+                front.advance(cnt);
+                return;
+            }
+            cnt -= front.len();
+            self.frames.pop_front();
+        }
+    }
+
+    fn chunks_vectored<'a>(&'a self, dst: &mut [IoSlice<'a>]) -> usize {
+        let mut count = 0;
+        for (slice, frame) in dst.iter_mut().zip(&self.frames) {
+            *slice = IoSlice::new(frame);
+            count += 1;
+        }
+        count
+    }
+}
+
+/// The stream frames are written to.
+struct Connection {
+    /// `None` once a write failed or timed out, until the stream is replaced
+    stream: Option<WriteHalf>,
+    /// How long the peer may take to accept a batch, `None` waits forever
+    timeout: Option<Duration>,
+}
+
+/// Datagrams queued by `BatchWriter::queue` or `BatchWriter::queue_all`, to be handed to `BatchWriter::flush`.
+#[must_use = "the datagrams are only sent once they're flushed"]
+pub struct Queued {
+    result: BatchResult,
+}
+
+/// `BatchWriter` writes datagrams to a stream shared by several tasks, coalescing those queued while
+/// the stream is busy. A task which finds its datagram still queued writes it together with all the others
+/// queued in the meantime, in a single write followed by a flush, and they all get the outcome of that write.
+/// Datagrams are written in the order they were queued.
+///
+/// A failed or timed out write closes the stream, as the peer may have received part of a frame,
+/// and later writes fail right away until the stream is replaced.
+pub struct BatchWriter {
+    pending: Mutex<Batch>,
+    connection: tokio::sync::Mutex<Connection>,
+}
+
+impl BatchWriter {
+    /// Creates a new instance of `BatchWriter`.
+    ///
+    /// # Arguments
+    ///
+    /// * `stream` - The writable half of the connection.
+    /// * `timeout` - How long the peer may take to accept a batch, `None` waits forever.
+    ///
+    /// # Returns
+    ///
+    /// * `BatchWriter` - Returns a writer with nothing queued.
+    pub fn new(stream: WriteHalf, timeout: Option<Duration>) -> BatchWriter {
+        BatchWriter {
+            pending: Mutex::new(Batch::default()),
+            connection: tokio::sync::Mutex::new(Connection { stream: Some(stream), timeout }),
+        }
+    }
+
+    /// Encodes a datagram and queues it behind the ones queued before, without writing anything.
+    ///
+    /// # Arguments
+    ///
+    /// * `datagram` - The datagram to be sent.
+    /// * `codec` - The codec used to encode the datagram.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Queued, ChatProtocolError>` - Returns the queued datagram, or why it could not be encoded.
+    pub fn queue(&self, datagram: &Datagram, codec: &dyn Codec) -> Result<Queued, ChatProtocolError> {
+        self.queue_all(std::slice::from_ref(datagram), codec)
+    }
+
+    /// Encodes several datagrams and queues them one after another, with no datagram of another task in between.
+    /// If one of them can't be encoded, none are queued.
+    ///
+    /// # Arguments
+    ///
+    /// * `datagrams` - The datagrams to be sent.
+    /// * `codec` - The codec used to encode the datagrams.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Queued, ChatProtocolError>` - Returns the queued datagrams, flushed together, or why one could not be encoded.
+    pub fn queue_all(&self, datagrams: &[Datagram], codec: &dyn Codec) -> Result<Queued, ChatProtocolError> {
+        let frames = datagrams.iter().map(|datagram| datagram.encode_frame(codec)).collect::<Result<Vec<_>, _>>()?;
+        let mut pending = self.pending.lock().unwrap();
+        for frame in frames {
+            pending.frames.push(frame);
+        }
+        Ok(Queued { result: pending.result.clone() })
+    }
+
+    /// Waits until queued datagrams are written, writing them and everything queued after them if no other task
+    /// already did. Datagrams whose flush is cancelled stay queued and go out with the next ones.
+    ///
+    /// # Arguments
+    ///
+    /// * `queued` - The datagrams returned by `queue` or `queue_all`.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), ChatProtocolError>` - Returns an empty result if the batch with the datagrams was written.
+    pub async fn flush(&self, queued: Queued) -> Result<(), ChatProtocolError> {
+        let mut connection = self.connection.lock().await;
+        if let Some(result) = queued.result.get() {
+            return result.clone();
+        }
+        let batch = {
+            let mut pending = self.pending.lock().unwrap();
+            // The batch was taken by a write which was cancelled halfway
+            if !Arc::ptr_eq(&pending.result, &queued.result) {
+                connection.stream = None;
+                return Err(ChatProtocolError::IOError);
+            }
+            std::mem::take(&mut *pending)
+        };
+
+        let timeout = connection.timeout;
+        let mut frames = batch.frames;
+        let result = match connection.stream.as_mut() {
+            Some(stream) => match timeout {
+                Some(timeout) => tokio::time::timeout(timeout, write_batch(stream, &mut frames)).await
+                    .unwrap_or(Err(ChatProtocolError::Timeout)),
+                None => write_batch(stream, &mut frames).await,
+            },
+            None => Err(ChatProtocolError::IOError),
+        };
+        if result.is_err() {
+            connection.stream = None;
+        }
+        let _ = batch.result.set(result.clone());
+        result
+    }
+
+    /// Writes a datagram, together with the others queued while the stream is busy.
+    ///
+    /// # Arguments
+    ///
+    /// * `datagram` - The datagram to be sent.
+    /// * `codec` - The codec used to encode the datagram.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), ChatProtocolError>` - Returns an empty result if successful.
+    pub async fn write(&self, datagram: &Datagram, codec: &dyn Codec) -> Result<(), ChatProtocolError> {
+        let queued = self.queue(datagram, codec)?;
+        self.flush(queued).await
+    }
+
+    /// Switches to a new stream, e.g. after logging in again. Datagrams still queued are written to it.
+    ///
+    /// # Arguments
+    ///
+    /// * `stream` - The writable half of the new connection.
+    pub async fn replace(&self, stream: WriteHalf) {
+        self.connection.lock().await.stream = Some(stream);
+    }
+
+    /// Sets how long the peer may take to accept a batch before the stream is closed.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - The timeout, `None` waits forever.
+    pub async fn set_timeout(&self, timeout: Option<Duration>) {
+        self.connection.lock().await.timeout = timeout;
+    }
+}
+
+/// Writes the frames of a batch and flushes the stream, so that nothing waits in a buffer of the stream, e.g. TLS.
+///
+/// # Arguments
+///
+/// * `stream` - The writable half of the connection.
+/// * `frames` - The frames of the batch.
+///
+/// # Returns
+///
+/// * `Result<(), ChatProtocolError>` - Returns an empty result if successful.
+async fn write_batch(stream: &mut WriteHalf, frames: &mut Frames) -> Result<(), ChatProtocolError> {
+    stream.write_all_buf(frames).await.map_err(|_| ChatProtocolError::IOError)?;
+    stream.flush().await.map_err(|_| ChatProtocolError::IOError)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::task::{Context, Poll};
+
+    use bytes::{Buf, Bytes};
+    use tokio::io::{AsyncWrite, DuplexStream};
+
+    use crate::batch::{BatchWriter, Frames};
+    use crate::{ChatProtocolError, CodecKind, Datagram};
+
+    /// Counts the flushes, one per written batch.
+    struct CountingFlushes {
+        inner: DuplexStream,
+        flushes: Arc<AtomicUsize>,
+    }
+
+    impl AsyncWrite for CountingFlushes {
+        fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+            Pin::new(&mut self.inner).poll_write(cx, buf)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            self.flushes.fetch_add(1, Ordering::Relaxed);
+            Pin::new(&mut self.inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.inner).poll_shutdown(cx)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_batching() {
+        let codec = CodecKind::Cbor;
+        let flushes = Arc::new(AtomicUsize::new(0));
+        // Too small for a single frame, so the first write waits for the reader while the others are queued
+        let (stream, mut peer) = tokio::io::duplex(64);
+        let writer = BatchWriter::new(Box::new(CountingFlushes { inner: stream, flushes: flushes.clone() }), None);
+
+        let texts = (0..50).map(|i| format!("{i:03} {}", "x".repeat(100))).collect::<Vec<_>>();
+        let count = texts.len();
+        let reader = tokio::spawn(async move {
+            let mut received = Vec::new();
+            for _ in 0..count {
+                match Datagram::read_from_stream(&mut peer, &CodecKind::Cbor).await.unwrap() {
+                    Datagram::Announcement(text) => received.push(text),
+                    datagram => panic!("unexpected datagram {datagram:?}"),
+                }
+            }
+            received
+        });
+
+        let datagrams = texts.iter().map(|text| Datagram::Announcement(text.clone())).collect::<Vec<_>>();
+        let results = futures::future::join_all(datagrams.iter().map(|datagram| writer.write(datagram, &codec))).await;
+        assert!(results.iter().all(Result::is_ok));
+        assert_eq!(reader.await.unwrap(), texts);
+        // The first datagram alone, then all the others together
+        assert_eq!(flushes.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn test_frames() {
+        let mut frames = Frames::default();
+        for frame in ["abc", "de", "fghi"] {
+            frames.push(Bytes::from_static(frame.as_bytes()));
+        }
+        let mut slices = [std::io::IoSlice::new(&[]); 2];
+        assert_eq!(frames.chunks_vectored(&mut slices), 2);
+        assert_eq!((&*slices[0], &*slices[1]), (&b"abc"[..], &b"de"[..]));
+
+        // A partial write continues in the middle of a frame
+        frames.advance(4);
+        assert_eq!(frames.remaining(), 5);
+        assert_eq!(frames.chunk(), b"e");
+        assert_eq!(frames.copy_to_bytes(5), Bytes::from_static(b"efghi"));
+        assert!(!frames.has_remaining());
+    }
+
+    #[tokio::test]
+    async fn test_failed_batch() {
+        let codec = CodecKind::Cbor;
+        let (stream, peer) = tokio::io::duplex(64);
+        let writer = BatchWriter::new(Box::new(stream), None);
+        drop(peer);
+
+        let first = writer.queue(&Datagram::Announcement("a".to_string()), &codec).unwrap();
+        let second = writer.queue(&Datagram::Announcement("b".to_string()), &codec).unwrap();
+        assert!(matches!(writer.flush(second).await, Err(ChatProtocolError::IOError)));
+        assert!(matches!(writer.flush(first).await, Err(ChatProtocolError::IOError)));
+
+        // The stream stays closed until it's replaced
+        let (stream, mut peer) = tokio::io::duplex(1024);
+        assert!(writer.write(&Datagram::Ping, &codec).await.is_err());
+        writer.replace(Box::new(stream)).await;
+        writer.write(&Datagram::Ping, &codec).await.unwrap();
+        assert!(matches!(Datagram::read_from_stream(&mut peer, &codec).await.unwrap(), Datagram::Ping));
+    }
+}
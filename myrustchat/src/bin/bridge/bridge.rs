@@ -0,0 +1,601 @@
+//! Bot relaying the messages between the chat and an IRC channel.
+//!
+//! ```sh
+//! server register -u bridge -p secret
+//! bridge -u bridge -p secret --irc-server irc.libera.chat --irc-tls --irc-channel '#myrustchat'
+//! ```
+
+mod bridge_irc;
+
+use std::collections::HashMap;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+
+use anyhow::bail;
+use clap::Parser;
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncWriteExt;
+use tracing::{info, warn};
+
+use chat::client::ChatClient;
+use chat::{format_size, image_extension, AttachmentId, AttachmentKind, ChatMessage, ChatMessageContent, CodecKind, Datagram, ServerResponse, TransferId};
+
+use crate::bridge_irc::{IrcConnection, IrcEvent};
+
+/// Names of the users on both sides of the bridge, for users who go by different names in the chat and on IRC.
+#[derive(Debug, Default)]
+struct NickMap {
+    /// IRC nickname of chat users
+    to_irc: HashMap<String, String>,
+    /// Chat username of IRC users, compared without the case like IRC does
+    to_chat: HashMap<String, String>,
+}
+
+impl NickMap {
+    /// Creates the map from pairs of names.
+    ///
+    /// # Arguments
+    ///
+    /// * `pairs` - The chat username and IRC nickname of each user.
+    ///
+    /// # Returns
+    ///
+    /// * `NickMap` - Returns the map.
+    fn new(pairs: &[(String, String)]) -> NickMap {
+        let mut map = NickMap::default();
+        for (chat, irc) in pairs {
+            map.to_irc.insert(chat.clone(), irc.clone());
+            map.to_chat.insert(irc.to_lowercase(), chat.clone());
+        }
+        map
+    }
+
+    /// Returns the name shown on IRC for the sender of a chat message: the mapped nickname, else the display name, else the username.
+    fn irc_name(&self, username: &str, nickname: Option<&str>) -> String {
+        self.to_irc.get(username).map(String::as_str).or(nickname).unwrap_or(username).to_string()
+    }
+
+    /// Returns the name shown in the chat for an IRC user: the mapped username, else the IRC nickname.
+    fn chat_name(&self, nick: &str) -> String {
+        self.to_chat.get(&nick.to_lowercase()).cloned().unwrap_or_else(|| nick.to_string())
+    }
+}
+
+/// What the bridge does with a datagram from the chat server.
+#[derive(Debug, PartialEq)]
+enum Relayed {
+    /// Nothing, the datagram isn't relayed or is a part of a download
+    Nothing,
+    /// Post the text in the IRC channel
+    Say(String),
+    /// Download the stored attachment, it's posted once it arrived
+    Fetch(AttachmentId),
+}
+
+/// Where the attachments posted in the chat are published for the IRC users.
+#[derive(Debug)]
+struct AttachmentLinks {
+    /// Directory served by a web server
+    dir: PathBuf,
+    /// URL under which the web server serves `dir`
+    url: String,
+}
+
+/// Stored attachment being downloaded from the chat server into a partial file next to the published ones.
+struct Download {
+    /// ID of the attachment
+    id: AttachmentId,
+    file: tokio::fs::File,
+    path: PathBuf,
+    /// Hash of the data received so far, which names the published file
+    hasher: Sha256,
+    /// Extension of the published file, `None` for an image until its first chunk tells the format
+    extension: Option<String>,
+}
+
+impl AttachmentLinks {
+    /// Saves an attachment in the directory, named by the hash of its content so that it's saved only once.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The content of the attachment.
+    /// * `extension` - The extension of the file without the dot, empty for none.
+    ///
+    /// # Returns
+    ///
+    /// * `anyhow::Result<String>` - Returns the link to the saved file.
+    async fn publish(&self, data: &[u8], extension: &str) -> anyhow::Result<String> {
+        let name = published_name(Sha256::digest(data).as_slice(), extension);
+        let path = self.dir.join(&name);
+        if !tokio::fs::try_exists(&path).await? {
+            tokio::fs::write(&path, data).await?;
+        }
+        Ok(self.link(&name))
+    }
+
+    /// Starts saving a stored attachment the chat server is about to send.
+    ///
+    /// # Arguments
+    ///
+    /// * `transfer_id` - The ID of the transfer, which names the partial file.
+    /// * `id` - The ID of the attachment.
+    /// * `kind` - What the attachment is.
+    ///
+    /// # Returns
+    ///
+    /// * `anyhow::Result<Download>` - Returns the download if the partial file could be created.
+    async fn begin(&self, transfer_id: TransferId, id: AttachmentId, kind: &AttachmentKind) -> anyhow::Result<Download> {
+        let path = self.dir.join(format!(".{transfer_id}.part"));
+        let file = tokio::fs::File::create(&path).await?;
+        let extension = match kind {
+            AttachmentKind::Image => None,
+            AttachmentKind::File(filename) => Some(file_extension(filename)),
+            AttachmentKind::Audio(format) => Some(format.extension().to_string()),
+        };
+        Ok(Download { id, file, path, hasher: Sha256::new(), extension })
+    }
+
+    /// Publishes a completely downloaded attachment under the hash of its content.
+    ///
+    /// # Arguments
+    ///
+    /// * `download` - The download.
+    ///
+    /// # Returns
+    ///
+    /// * `anyhow::Result<String>` - Returns the link to the published file.
+    async fn finish(&self, mut download: Download) -> anyhow::Result<String> {
+        download.file.flush().await?;
+        let name = published_name(download.hasher.finalize().as_slice(), download.extension.as_deref().unwrap_or_default());
+        tokio::fs::rename(&download.path, self.dir.join(&name)).await?;
+        Ok(self.link(&name))
+    }
+
+    /// Returns the URL of a published file.
+    fn link(&self, name: &str) -> String {
+        format!("{}/{name}", self.url.trim_end_matches('/'))
+    }
+}
+
+impl Download {
+    /// Appends a chunk of the attachment to the partial file.
+    async fn write(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        if self.extension.is_none() {
+            self.extension = Some(image_extension(data).to_string());
+        }
+        self.hasher.update(data);
+        self.file.write_all(data).await?;
+        Ok(())
+    }
+}
+
+/// Translates the messages between the chat and IRC.
+struct Relay {
+    /// Username of the bridge in the chat, its own messages are not relayed back
+    username: String,
+    nicks: NickMap,
+    /// `None` if attachments are only described on IRC
+    links: Option<AttachmentLinks>,
+    /// Text announcing each attachment requested from the chat server, posted with the link once it's downloaded
+    pending: HashMap<AttachmentId, String>,
+    downloads: HashMap<TransferId, Download>,
+}
+
+impl Relay {
+    /// Creates the relay.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - The username of the bridge in the chat.
+    /// * `nicks` - The names of the users on both sides.
+    /// * `links` - Where attachments are published, `None` to only describe them.
+    ///
+    /// # Returns
+    ///
+    /// * `Relay` - Returns the relay.
+    fn new(username: &str, nicks: NickMap, links: Option<AttachmentLinks>) -> Relay {
+        Relay { username: username.to_string(), nicks, links, pending: HashMap::new(), downloads: HashMap::new() }
+    }
+
+    /// Decides what to do with a datagram from the chat server. Stored images and files are downloaded
+    /// to be published if the bridge has somewhere to put them, and announced on IRC with their link.
+    ///
+    /// # Arguments
+    ///
+    /// * `datagram` - The datagram.
+    ///
+    /// # Returns
+    ///
+    /// * `Relayed` - Returns what the bridge does next.
+    async fn chat_to_irc(&mut self, datagram: Datagram) -> Relayed {
+        match datagram {
+            // IRC can't take back a message, so ephemeral ones stay in the chat
+            Datagram::Message(message) if message.ttl.is_none() => match self.message_to_irc(message).await {
+                Some(text) => Relayed::Say(text),
+                None => Relayed::Nothing,
+            },
+            Datagram::Thumbnail { id, message } if message.sender != self.username => {
+                let name = self.nicks.irc_name(&message.sender, message.nickname.as_deref());
+                self.announce(id, format!("* {name} sent an image"))
+            },
+            Datagram::FileOffer { id, sender, nickname, filename, size, .. } if sender != self.username => {
+                let name = self.nicks.irc_name(&sender, nickname.as_deref());
+                self.announce(id, format!("* {name} sent the file {filename} ({})", format_size(size)))
+            },
+            Datagram::FileBegin { transfer_id, id, kind, .. } if self.pending.contains_key(&id) => {
+                let Some(links) = &self.links else {
+                    return Relayed::Nothing;
+                };
+                match links.begin(transfer_id, id, &kind).await {
+                    Ok(download) => {
+                        self.downloads.insert(transfer_id, download);
+                        Relayed::Nothing
+                    },
+                    Err(e) => {
+                        warn!("Could not save an attachment in {}: {e}", links.dir.display());
+                        self.unlinked(id)
+                    },
+                }
+            },
+            Datagram::FileChunk { transfer_id, data, .. } => {
+                let Some(download) = self.downloads.get_mut(&transfer_id) else {
+                    return Relayed::Nothing;
+                };
+                match download.write(&data).await {
+                    Ok(()) => Relayed::Nothing,
+                    Err(e) => {
+                        warn!("Could not save an attachment in {}: {e}", download.path.display());
+                        self.abort(transfer_id).await
+                    },
+                }
+            },
+            Datagram::FileEnd { transfer_id } => {
+                let (Some(download), Some(links)) = (self.downloads.remove(&transfer_id), &self.links) else {
+                    return Relayed::Nothing;
+                };
+                let id = download.id;
+                match links.finish(download).await {
+                    Ok(link) => Relayed::Say(format!("{}: {link}", self.pending.remove(&id).unwrap_or_default())),
+                    Err(e) => {
+                        warn!("Could not save an attachment in {}: {e}", links.dir.display());
+                        self.unlinked(id)
+                    },
+                }
+            },
+            Datagram::FileAbort { transfer_id } => self.abort(transfer_id).await,
+            Datagram::ServerResponse(ServerResponse::AttachmentNotFound(id)) => self.unlinked(id),
+            _ => Relayed::Nothing,
+        }
+    }
+
+    /// Announces a stored attachment, right away if it isn't published or else once it's downloaded.
+    fn announce(&mut self, id: AttachmentId, text: String) -> Relayed {
+        if self.links.is_none() {
+            return Relayed::Say(text);
+        }
+        self.pending.insert(id, text);
+        Relayed::Fetch(id)
+    }
+
+    /// Announces a requested attachment without a link, because it couldn't be downloaded.
+    fn unlinked(&mut self, id: AttachmentId) -> Relayed {
+        match self.pending.remove(&id) {
+            Some(text) => Relayed::Say(text),
+            None => Relayed::Nothing,
+        }
+    }
+
+    /// Drops an unfinished download with its partial file.
+    async fn abort(&mut self, transfer_id: TransferId) -> Relayed {
+        let Some(download) = self.downloads.remove(&transfer_id) else {
+            return Relayed::Nothing;
+        };
+        let _ = tokio::fs::remove_file(&download.path).await;
+        self.unlinked(download.id)
+    }
+
+    /// Turns a chat message into the text posted in the IRC channel.
+    ///
+    /// # Arguments
+    ///
+    /// * `message` - The message.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<String>` - Returns the text, `None` for the messages of the bridge and link previews.
+    async fn message_to_irc(&self, message: ChatMessage) -> Option<String> {
+        if message.sender == self.username {
+            return None;
+        }
+        let name = self.nicks.irc_name(&message.sender, message.nickname.as_deref());
+        let (description, data, extension) = match message.content {
+            ChatMessageContent::Text(text) => {
+                let lines: Vec<String> = text.lines().map(|line| format!("<{name}> {line}")).collect();
+                return Some(lines.join("\n"));
+            },
+            // IRC clients preview the links themselves
+            ChatMessageContent::LinkPreview { .. } => return None,
+            ChatMessageContent::Image(data) => {
+                let extension = image_extension(&data);
+                ("an image".to_string(), data, extension.to_string())
+            },
+            ChatMessageContent::File(filename, data) => {
+                let extension = file_extension(&filename);
+                (format!("the file {filename}"), data, extension)
+            },
+            ChatMessageContent::Audio { format, data } => ("a voice note".to_string(), data, format.extension().to_string()),
+        };
+        let size = format_size(data.len() as u64);
+        match self.link(&data, &extension).await {
+            Some(link) => Some(format!("* {name} sent {description} ({size}): {link}")),
+            None => Some(format!("* {name} sent {description} ({size})")),
+        }
+    }
+
+    /// Publishes an attachment sent inline if the bridge has somewhere to put it.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The content of the attachment.
+    /// * `extension` - The extension of the file without the dot.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<String>` - Returns the link, `None` if attachments aren't published or saving failed.
+    async fn link(&self, data: &[u8], extension: &str) -> Option<String> {
+        let links = self.links.as_ref()?;
+        match links.publish(data, extension).await {
+            Ok(link) => Some(link),
+            Err(e) => {
+                warn!("Could not save an attachment in {}: {e}", links.dir.display());
+                None
+            },
+        }
+    }
+
+    /// Turns a message in the IRC channel into the text posted in the chat.
+    ///
+    /// # Arguments
+    ///
+    /// * `nick` - The nickname of the IRC user.
+    /// * `text` - The text of the message.
+    /// * `action` - Whether it's a `/me` message.
+    ///
+    /// # Returns
+    ///
+    /// * `String` - Returns the text.
+    fn irc_to_chat(&self, nick: &str, text: &str, action: bool) -> String {
+        let name = self.nicks.chat_name(nick);
+        if action {
+            format!("* {name} {text}")
+        } else {
+            format!("<{name}> {text}")
+        }
+    }
+}
+
+/// Returns the name of a published file: the start of the hash of its content and its extension.
+fn published_name(hash: &[u8], extension: &str) -> String {
+    let mut name: String = hash[..8].iter().map(|byte| format!("{byte:02x}")).collect();
+    if !extension.is_empty() {
+        name = format!("{name}.{extension}");
+    }
+    name
+}
+
+/// Returns the extension of a file name if it's safe to put in a URL, else an empty string.
+fn file_extension(filename: &str) -> String {
+    Path::new(filename).extension()
+        .and_then(|extension| extension.to_str())
+        .filter(|extension| extension.chars().all(|c| c.is_ascii_alphanumeric()))
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// Parses a `--map` argument.
+///
+/// # Arguments
+///
+/// * `value` - The argument, `CHAT=IRC`.
+///
+/// # Returns
+///
+/// * `Result<(String, String), String>` - Returns the chat username and the IRC nickname.
+fn parse_mapping(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((chat, irc)) if !chat.is_empty() && !irc.is_empty() => Ok((chat.to_string(), irc.to_string())),
+        _ => Err(format!("expected CHAT=IRC, got {value}")),
+    }
+}
+
+#[derive(Parser, Debug)]
+#[command(version, about = "Relays the messages between a myrustchat server and an IRC channel")]
+struct Args {
+    /// Address of the chat server
+    #[arg(short, long, default_value = "127.0.0.1")]
+    address: String,
+    /// Port of the chat server
+    #[arg(short = 'P', long, default_value_t = 11111)]
+    port: u16,
+    /// Unix socket of the chat server, used instead of the address and port
+    #[arg(long)]
+    unix_socket: Option<PathBuf>,
+    /// Username of the bridge in the chat
+    #[arg(short)]
+    username: String,
+    /// Password of the bridge in the chat
+    #[arg(short = 'p')]
+    password: String,
+    /// Wire format of datagrams: cbor, json or msgpack, must match the server [default: cbor]
+    #[arg(long)]
+    codec: Option<CodecKind>,
+    /// Host name of the IRC server
+    #[arg(long)]
+    irc_server: String,
+    /// Port of the IRC server [default: 6667, 6697 with --irc-tls]
+    #[arg(long)]
+    irc_port: Option<u16>,
+    /// Connect to the IRC server with TLS
+    #[arg(long)]
+    irc_tls: bool,
+    /// Password of the IRC server
+    #[arg(long)]
+    irc_password: Option<String>,
+    /// Nickname of the bridge on IRC [default: the chat username]
+    #[arg(long)]
+    irc_nick: Option<String>,
+    /// IRC channel to relay, e.g. '#myrustchat'
+    #[arg(long)]
+    irc_channel: String,
+    /// Chat user known under another nickname on IRC, as CHAT=IRC, may be given several times
+    #[arg(long = "map", value_parser = parse_mapping)]
+    mappings: Vec<(String, String)>,
+    /// Directory where attachments posted in the chat are saved for the IRC users, served by a web server
+    #[arg(long, requires = "attachment_url")]
+    attachment_dir: Option<PathBuf>,
+    /// URL under which the web server serves --attachment-dir, e.g. https://example.com/chat
+    #[arg(long, requires = "attachment_dir")]
+    attachment_url: Option<String>,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    tracing_subscriber::fmt().with_ansi(std::io::stdout().is_terminal()).init();
+
+    let codec = args.codec.unwrap_or_default();
+    let mut client = match &args.unix_socket {
+        Some(path) => ChatClient::connect_unix(path, &args.username, &args.password, codec).await?,
+        None => ChatClient::connect(&args.address, args.port, &args.username, &args.password, codec).await?,
+    };
+    info!("Logged in to the chat as {}", client.username());
+    let sender = client.sender();
+
+    let irc_port = args.irc_port.unwrap_or(if args.irc_tls { 6697 } else { 6667 });
+    let irc_nick = args.irc_nick.as_deref().unwrap_or(&args.username);
+    let mut irc = IrcConnection::connect(&args.irc_server, irc_port, args.irc_tls, irc_nick, args.irc_password.as_deref(), &args.irc_channel).await?;
+
+    let links = match (args.attachment_dir, args.attachment_url) {
+        (Some(dir), Some(url)) => {
+            tokio::fs::create_dir_all(&dir).await?;
+            Some(AttachmentLinks { dir, url })
+        },
+        _ => None,
+    };
+    let mut relay = Relay::new(&args.username, NickMap::new(&args.mappings), links);
+
+    // the chat is read only once the channel is joined, meanwhile the messages wait in the queue of the client
+    let mut joined = false;
+    loop {
+        tokio::select! {
+            datagram = client.recv(), if joined => {
+                let Some(datagram) = datagram else {
+                    bail!("the chat server closed the connection");
+                };
+                match relay.chat_to_irc(datagram).await {
+                    Relayed::Say(text) => irc.say(&text).await?,
+                    Relayed::Fetch(id) => sender.send(&Datagram::FetchAttachment { id, from_seq: 0 }).await?,
+                    Relayed::Nothing => {},
+                }
+            },
+            event = irc.next_event() => match event? {
+                IrcEvent::Joined => {
+                    info!("Joined {} on {} as {}", args.irc_channel, args.irc_server, irc.nick());
+                    joined = true;
+                },
+                IrcEvent::Message { nick, text, action } => {
+                    sender.send_text(&relay.irc_to_chat(&nick, &text, action)).await?;
+                },
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn message(sender: &str, content: ChatMessageContent) -> ChatMessage {
+        ChatMessage::new(1, sender, content)
+    }
+
+    fn offer(id: AttachmentId) -> Datagram {
+        Datagram::FileOffer { id, sender: "bob".to_string(), nickname: None, timestamp: Utc::now(), filename: "big.zip".to_string(), size: 5 << 20 }
+    }
+
+    fn relay(links: Option<AttachmentLinks>) -> Relay {
+        let nicks = NickMap::new(&[("alice".to_string(), "Alice_".to_string())]);
+        Relay::new("bridge", nicks, links)
+    }
+
+    #[test]
+    fn test_nick_map() {
+        let nicks = NickMap::new(&[("alice".to_string(), "Alice_".to_string())]);
+        assert_eq!(nicks.irc_name("alice", Some("Alice Smith")), "Alice_");
+        assert_eq!(nicks.irc_name("bob", Some("Bobby")), "Bobby");
+        assert_eq!(nicks.irc_name("bob", None), "bob");
+        assert_eq!(nicks.chat_name("alice_"), "alice");
+        assert_eq!(nicks.chat_name("carol"), "carol");
+        assert_eq!(parse_mapping("bob=bobby"), Ok(("bob".to_string(), "bobby".to_string())));
+        assert!(parse_mapping("bob").is_err());
+        assert!(parse_mapping("=bobby").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_chat_to_irc() {
+        let mut relay = relay(None);
+        let say = |text: &str| Relayed::Say(text.to_string());
+        let text = Datagram::Message(message("alice", ChatMessageContent::Text("hi\nall".to_string())));
+        assert_eq!(relay.chat_to_irc(text).await, say("<Alice_> hi\n<Alice_> all"));
+        let own = Datagram::Message(message("bridge", ChatMessageContent::Text("<carol> hi".to_string())));
+        assert_eq!(relay.chat_to_irc(own).await, Relayed::Nothing);
+        let ephemeral = Datagram::Message(ChatMessage { ttl: Some(60), ..message("alice", ChatMessageContent::Text("secret".to_string())) });
+        assert_eq!(relay.chat_to_irc(ephemeral).await, Relayed::Nothing);
+        let file = Datagram::Message(message("bob", ChatMessageContent::File("notes.txt".to_string(), vec![0; 2048])));
+        assert_eq!(relay.chat_to_irc(file).await, say("* bob sent the file notes.txt (2.0 KB)"));
+        assert_eq!(relay.chat_to_irc(offer(7)).await, say("* bob sent the file big.zip (5.0 MB)"));
+        assert_eq!(relay.chat_to_irc(Datagram::Presence { username: "bob".to_string(), online: true }).await, Relayed::Nothing);
+    }
+
+    #[tokio::test]
+    async fn test_attachment_links() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut relay = relay(Some(AttachmentLinks { dir: dir.path().to_path_buf(), url: "https://example.com/chat/".to_string() }));
+        let file = Datagram::Message(message("bob", ChatMessageContent::File("notes.txt".to_string(), b"hello".to_vec())));
+        let Relayed::Say(text) = relay.chat_to_irc(file).await else {
+            panic!("the file wasn't relayed");
+        };
+        let link = text.strip_prefix("* bob sent the file notes.txt (5 B): https://example.com/chat/").unwrap();
+        assert!(link.ends_with(".txt"));
+        assert_eq!(std::fs::read(dir.path().join(link)).unwrap(), b"hello");
+
+        // stored files are downloaded first
+        assert_eq!(relay.chat_to_irc(offer(7)).await, Relayed::Fetch(7));
+        let begin = Datagram::FileBegin { transfer_id: 3, id: 7, sender: "bob".to_string(), kind: AttachmentKind::File("big.zip".to_string()), size: 11 };
+        assert_eq!(relay.chat_to_irc(begin).await, Relayed::Nothing);
+        for (seq, data) in ["hello ", "world"].into_iter().enumerate() {
+            let chunk = Datagram::FileChunk { transfer_id: 3, seq: seq as u64, data: data.as_bytes().to_vec() };
+            assert_eq!(relay.chat_to_irc(chunk).await, Relayed::Nothing);
+        }
+        let Relayed::Say(text) = relay.chat_to_irc(Datagram::FileEnd { transfer_id: 3 }).await else {
+            panic!("the file wasn't relayed");
+        };
+        let link = text.strip_prefix("* bob sent the file big.zip (5.0 MB): https://example.com/chat/").unwrap();
+        assert!(link.ends_with(".zip"));
+        assert_eq!(std::fs::read(dir.path().join(link)).unwrap(), b"hello world");
+
+        // and announced without a link if they can't be
+        assert_eq!(relay.chat_to_irc(offer(8)).await, Relayed::Fetch(8));
+        let missing = Datagram::ServerResponse(ServerResponse::AttachmentNotFound(8));
+        assert_eq!(relay.chat_to_irc(missing).await, Relayed::Say("* bob sent the file big.zip (5.0 MB)".to_string()));
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn test_irc_to_chat() {
+        let relay = relay(None);
+        assert_eq!(relay.irc_to_chat("Alice_", "hello", false), "<alice> hello");
+        assert_eq!(relay.irc_to_chat("carol", "waves", true), "* carol waves");
+    }
+}
@@ -0,0 +1,403 @@
+//! Minimal IRC client: the lines of the protocol, registration and the messages of one channel.
+
+use std::fmt;
+
+use anyhow::bail;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+use chat::client::{ReadHalf, TlsOptions, WriteHalf};
+
+/// Longest line the IRC server accepts, including the final `\r\n`.
+const MAX_LINE_LENGTH: usize = 512;
+
+/// Room left in a line for the prefix `:nick!user@host ` the IRC server adds when it relays a message.
+const PREFIX_RESERVE: usize = 100;
+
+/// One line of the IRC protocol, e.g. `:alice!a@host PRIVMSG #rust :hello`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IrcMessage {
+    /// Origin of the message, `nick!user@host` or the name of a server, `None` in lines sent by a client.
+    pub prefix: Option<String>,
+    /// Command or three-digit numeric reply, e.g. `PRIVMSG` or `001`.
+    pub command: String,
+    pub params: Vec<String>,
+}
+
+impl IrcMessage {
+    /// Creates a message sent by a client.
+    ///
+    /// # Arguments
+    ///
+    /// * `command` - The command, e.g. `JOIN`.
+    /// * `params` - The parameters, only the last one may contain spaces.
+    ///
+    /// # Returns
+    ///
+    /// * `IrcMessage` - Returns the message without a prefix.
+    pub fn new(command: &str, params: &[&str]) -> IrcMessage {
+        IrcMessage { prefix: None, command: command.to_string(), params: params.iter().map(|param| param.to_string()).collect() }
+    }
+
+    /// Parses a line received from the IRC server, ignoring IRCv3 tags.
+    ///
+    /// # Arguments
+    ///
+    /// * `line` - The line with or without the final `\r\n`.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<IrcMessage>` - Returns the message, `None` if the line has no command.
+    pub fn parse(line: &str) -> Option<IrcMessage> {
+        let mut rest = line.trim_end_matches(['\r', '\n']);
+        if rest.starts_with('@') {
+            rest = rest.split_once(' ')?.1.trim_start_matches(' ');
+        }
+        let mut prefix = None;
+        if let Some(stripped) = rest.strip_prefix(':') {
+            let (origin, after) = stripped.split_once(' ')?;
+            prefix = Some(origin.to_string());
+            rest = after.trim_start_matches(' ');
+        }
+        let (middle, trailing) = match rest.split_once(" :") {
+            Some((middle, trailing)) => (middle, Some(trailing)),
+            None => (rest, None),
+        };
+        let mut words = middle.split(' ').filter(|word| !word.is_empty());
+        let command = words.next()?.to_uppercase();
+        let mut params: Vec<String> = words.map(str::to_string).collect();
+        params.extend(trailing.map(str::to_string));
+        Some(IrcMessage { prefix, command, params })
+    }
+
+    /// Returns the nickname in the prefix of the message, `None` if it was sent by a server.
+    pub fn nick(&self) -> Option<&str> {
+        let prefix = self.prefix.as_deref()?;
+        match prefix.split_once('!') {
+            Some((nick, _)) => Some(nick),
+            None if prefix.contains('.') => None,
+            None => Some(prefix),
+        }
+    }
+}
+
+impl fmt::Display for IrcMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(prefix) = &self.prefix {
+            write!(f, ":{prefix} ")?;
+        }
+        write!(f, "{}", self.command)?;
+        if let Some((last, middle)) = self.params.split_last() {
+            for param in middle {
+                write!(f, " {param}")?;
+            }
+            if last.is_empty() || last.contains(' ') || last.starts_with(':') {
+                write!(f, " :{last}")?;
+            } else {
+                write!(f, " {last}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What happened in the channel of the bridge.
+#[derive(Clone, Debug, PartialEq)]
+pub enum IrcEvent {
+    /// The bridge joined the channel, after every connection.
+    Joined,
+    /// Someone wrote in the channel; `action` is set for `/me` messages.
+    Message { nick: String, text: String, action: bool },
+}
+
+/// Connection of the bridge to an IRC server, registered under a nickname and joined to one channel.
+pub struct IrcConnection {
+    reader: BufReader<ReadHalf>,
+    writer: WriteHalf,
+    /// Bytes of a line not read completely yet, kept so that `next_event` may be cancelled.
+    buffer: Vec<u8>,
+    nick: String,
+    channel: String,
+}
+
+impl IrcConnection {
+    /// Connects to an IRC server and sends the registration, the channel is joined once the server welcomes the bridge.
+    ///
+    /// # Arguments
+    ///
+    /// * `server` - The host name of the IRC server.
+    /// * `port` - The port of the IRC server.
+    /// * `tls` - Whether to connect with TLS, checking the certificate against the usual web authorities.
+    /// * `nick` - The nickname of the bridge, `_` is appended while it's taken.
+    /// * `password` - The password of the IRC server, if it has one.
+    /// * `channel` - The channel to join, e.g. `#rust`.
+    ///
+    /// # Returns
+    ///
+    /// * `anyhow::Result<IrcConnection>` - Returns the connection if the registration could be sent.
+    pub async fn connect(server: &str, port: u16, tls: bool, nick: &str, password: Option<&str>, channel: &str) -> anyhow::Result<IrcConnection> {
+        let (read_half, write_half) = if tls {
+            chat::client::open_tls(server, port, &TlsOptions::default()).await?
+        } else {
+            chat::client::open_tcp(server, port).await?
+        };
+        let mut connection = IrcConnection {
+            reader: BufReader::new(read_half),
+            writer: write_half,
+            buffer: Vec::new(),
+            nick: nick.to_string(),
+            channel: channel.to_string(),
+        };
+        if let Some(password) = password {
+            connection.send(&IrcMessage::new("PASS", &[password])).await?;
+        }
+        connection.send(&IrcMessage::new("NICK", &[nick])).await?;
+        connection.send(&IrcMessage::new("USER", &[nick, "0", "*", "myrustchat bridge"])).await?;
+        Ok(connection)
+    }
+
+    /// Returns the nickname the IRC server accepted, or the one being tried.
+    pub fn nick(&self) -> &str {
+        &self.nick
+    }
+
+    /// Sends one line to the IRC server.
+    ///
+    /// # Arguments
+    ///
+    /// * `message` - The message, its parameters must not contain line breaks.
+    ///
+    /// # Returns
+    ///
+    /// * `anyhow::Result<()>` - Returns an error if the connection is broken.
+    pub async fn send(&mut self, message: &IrcMessage) -> anyhow::Result<()> {
+        let line = format!("{message}\r\n");
+        self.writer.write_all(line.as_bytes()).await?;
+        self.writer.flush().await?;
+        Ok(())
+    }
+
+    /// Posts a text in the channel, split into several messages if it has several lines or is too long for one.
+    ///
+    /// # Arguments
+    ///
+    /// * `text` - The text to post.
+    ///
+    /// # Returns
+    ///
+    /// * `anyhow::Result<()>` - Returns an error if the connection is broken.
+    pub async fn say(&mut self, text: &str) -> anyhow::Result<()> {
+        let room = MAX_LINE_LENGTH - PREFIX_RESERVE - "PRIVMSG  :\r\n".len() - self.channel.len();
+        for line in split_text(text, room) {
+            let message = IrcMessage::new("PRIVMSG", &[&self.channel, &line]);
+            self.send(&message).await?;
+        }
+        Ok(())
+    }
+
+    /// Waits for the next event in the channel, answering pings and finishing the registration on the way.
+    /// Cancelling the returned future loses nothing, so it may be used in `tokio::select!`.
+    ///
+    /// # Returns
+    ///
+    /// * `anyhow::Result<IrcEvent>` - Returns the event, an error if the connection closed or the IRC server refused the bridge.
+    pub async fn next_event(&mut self) -> anyhow::Result<IrcEvent> {
+        loop {
+            if self.reader.read_until(b'\n', &mut self.buffer).await? == 0 {
+                bail!("the IRC server closed the connection");
+            }
+            if self.buffer.last() != Some(&b'\n') {
+                continue;
+            }
+            let line = String::from_utf8_lossy(&std::mem::take(&mut self.buffer)).into_owned();
+            let Some(message) = IrcMessage::parse(&line) else {
+                continue;
+            };
+            if let Some(event) = self.handle(message).await? {
+                return Ok(event);
+            }
+        }
+    }
+
+    /// Reacts to one message from the IRC server.
+    ///
+    /// # Arguments
+    ///
+    /// * `message` - The message.
+    ///
+    /// # Returns
+    ///
+    /// * `anyhow::Result<Option<IrcEvent>>` - Returns the event for the bridge, `None` if the message concerned only the connection.
+    async fn handle(&mut self, message: IrcMessage) -> anyhow::Result<Option<IrcEvent>> {
+        match message.command.as_str() {
+            "PING" => {
+                let params: Vec<&str> = message.params.iter().map(String::as_str).collect();
+                self.send(&IrcMessage::new("PONG", &params)).await?;
+            },
+            // RPL_WELCOME, the registration succeeded
+            "001" => {
+                if let Some(nick) = message.params.first() {
+                    self.nick = nick.clone();
+                }
+                let channel = self.channel.clone();
+                self.send(&IrcMessage::new("JOIN", &[&channel])).await?;
+            },
+            // ERR_NICKNAMEINUSE
+            "433" => {
+                self.nick.push('_');
+                let nick = self.nick.clone();
+                self.send(&IrcMessage::new("NICK", &[&nick])).await?;
+            },
+            "NICK" if message.nick() == Some(&self.nick) => {
+                if let Some(nick) = message.params.first() {
+                    self.nick = nick.clone();
+                }
+            },
+            "JOIN" if message.nick() == Some(&self.nick) => return Ok(Some(IrcEvent::Joined)),
+            // ERR_BANNEDFROMCHAN, ERR_INVITEONLYCHAN, ERR_BADCHANNELKEY...
+            "471" | "473" | "474" | "475" => bail!("could not join {}: {}", self.channel, message.params.last().map_or("", String::as_str)),
+            "ERROR" => bail!("the IRC server closed the connection: {}", message.params.last().map_or("", String::as_str)),
+            "PRIVMSG" => {
+                let (Some(nick), [target, text]) = (message.nick(), message.params.as_slice()) else {
+                    return Ok(None);
+                };
+                if !target.eq_ignore_ascii_case(&self.channel) || nick == self.nick {
+                    return Ok(None);
+                }
+                let (text, action) = match text.strip_prefix("\u{1}ACTION ") {
+                    Some(action) => (action.trim_end_matches('\u{1}'), true),
+                    // other CTCP requests, e.g. VERSION
+                    None if text.starts_with('\u{1}') => return Ok(None),
+                    None => (text.as_str(), false),
+                };
+                return Ok(Some(IrcEvent::Message { nick: nick.to_string(), text: strip_formatting(text), action }));
+            },
+            _ => {},
+        }
+        Ok(None)
+    }
+}
+
+/// Splits a text into lines which may be sent in IRC messages, dropping the control characters which would break the protocol.
+///
+/// # Arguments
+///
+/// * `text` - The text, possibly with several lines.
+/// * `max_bytes` - The longest line allowed, in bytes of UTF-8.
+///
+/// # Returns
+///
+/// * `Vec<String>` - Returns the non-empty lines, long ones cut between words where possible.
+pub fn split_text(text: &str, max_bytes: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    for line in text.lines() {
+        let mut rest: String = line.chars().filter(|c| *c == '\t' || !c.is_control()).collect();
+        while rest.len() > max_bytes {
+            let mut end = max_bytes;
+            while !rest.is_char_boundary(end) {
+                end -= 1;
+            }
+            if end == 0 {
+                end = rest.chars().next().map_or(1, char::len_utf8);
+            }
+            let cut = match rest[..end].rfind(' ') {
+                Some(space) if space > 0 => space,
+                _ => end,
+            };
+            lines.push(rest[..cut].to_string());
+            rest = rest[cut..].trim_start().to_string();
+        }
+        if !rest.trim().is_empty() {
+            lines.push(rest);
+        }
+    }
+    lines
+}
+
+/// Removes the mIRC formatting codes (bold, colors, italics...) from a text.
+///
+/// # Arguments
+///
+/// * `text` - The text of an IRC message.
+///
+/// # Returns
+///
+/// * `String` - Returns the plain text.
+pub fn strip_formatting(text: &str) -> String {
+    let mut plain = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            // color, followed by up to two digits for the foreground and optionally a comma and two for the background
+            '\u{3}' => {
+                for _ in 0..2 {
+                    chars.next_if(char::is_ascii_digit);
+                }
+                if chars.peek() == Some(&',') {
+                    let mut lookahead = chars.clone();
+                    lookahead.next();
+                    if lookahead.peek().is_some_and(char::is_ascii_digit) {
+                        chars.next();
+                        for _ in 0..2 {
+                            chars.next_if(char::is_ascii_digit);
+                        }
+                    }
+                }
+            },
+            '\u{2}' | '\u{f}' | '\u{11}' | '\u{16}' | '\u{1d}' | '\u{1e}' | '\u{1f}' => {},
+            c => plain.push(c),
+        }
+    }
+    plain
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let message = IrcMessage::parse(":alice!a@example.com PRIVMSG #rust :hello: world\r\n").unwrap();
+        assert_eq!(message.prefix.as_deref(), Some("alice!a@example.com"));
+        assert_eq!(message.command, "PRIVMSG");
+        assert_eq!(message.params, ["#rust", "hello: world"]);
+        assert_eq!(message.nick(), Some("alice"));
+
+        let message = IrcMessage::parse("@time=2024-01-01T00:00:00Z :irc.example.com 001 bridge :Welcome").unwrap();
+        assert_eq!(message.command, "001");
+        assert_eq!(message.params, ["bridge", "Welcome"]);
+        assert_eq!(message.nick(), None);
+
+        let message = IrcMessage::parse("ping token").unwrap();
+        assert_eq!(message.command, "PING");
+        assert_eq!(message.params, ["token"]);
+        assert_eq!(IrcMessage::parse(":alice"), None);
+        assert_eq!(IrcMessage::parse(""), None);
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(IrcMessage::new("PRIVMSG", &["#rust", "hello world"]).to_string(), "PRIVMSG #rust :hello world");
+        assert_eq!(IrcMessage::new("PRIVMSG", &["#rust", ":)"]).to_string(), "PRIVMSG #rust ::)");
+        assert_eq!(IrcMessage::new("NICK", &["bridge"]).to_string(), "NICK bridge");
+        assert_eq!(IrcMessage::new("USER", &["bridge", "0", "*", "myrustchat bridge"]).to_string(), "USER bridge 0 * :myrustchat bridge");
+        let line = ":alice!a@host PRIVMSG #rust :hi there";
+        assert_eq!(IrcMessage::parse(line).unwrap().to_string(), line);
+    }
+
+    #[test]
+    fn test_split_text() {
+        assert_eq!(split_text("one\r\ntwo\n\nthree", 400), ["one", "two", "three"]);
+        assert_eq!(split_text("a\0b\u{1}c", 400), ["abc"]);
+        assert_eq!(split_text("hello big world", 10), ["hello big", "world"]);
+        assert_eq!(split_text("abcdefghij", 4), ["abcd", "efgh", "ij"]);
+        assert_eq!(split_text("ééé", 3), ["é", "é", "é"]);
+        assert!(split_text("  \n", 400).is_empty());
+    }
+
+    #[test]
+    fn test_strip_formatting() {
+        assert_eq!(strip_formatting("\u{2}bold\u{2} \u{3}4,12red\u{3} \u{3}3green \u{1d}it\u{f}"), "bold red green it");
+        assert_eq!(strip_formatting("\u{3}12,x"), ",x");
+        assert_eq!(strip_formatting("plain, text"), "plain, text");
+    }
+}
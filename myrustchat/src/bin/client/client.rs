@@ -1,26 +1,163 @@
 use std::ffi::OsStr;
-use std::io::{Cursor, Read, Write};
-use std::path::Path;
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
 use std::process::exit;
 use std::fs::File;
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
-use tokio::net::TcpStream;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt};
+use tokio::sync::oneshot;
 
-use clap::Parser;
-use image::io::Reader as ImageReader;
-use anyhow::{Context, Error, Result};
+use clap::{Parser, Subcommand};
+use image::ImageFormat;
+use sha2::{Digest, Sha256};
+use anyhow::{anyhow, Context, Error, Result};
 
-use chat::{ChatMessage, ChatMessageContent, Datagram, EmptyResult, ServerResponse};
+mod client_console;
+use client_console::{format_uptime, status, Attachment, Console, OutputFormat, TransferProgress};
+mod client_daemon;
+use client_daemon::{ControlSocket, DaemonConfig};
+mod client_downloads;
+use client_downloads::{Downloads, OverwritePolicy};
+mod client_filters;
+use client_filters::{DndSchedule, NotificationFilters, SharedFilters};
+mod client_hooks;
+use client_hooks::{Hook, HookContent, HookEvent, Hooks};
+mod client_emoji;
+use client_emoji::expand_shortcodes;
+mod client_history;
+use client_history::History;
+mod client_input;
+mod client_markdown;
+mod client_keyring;
+use client_keyring::{KeyringUse, SavedPassword};
+mod client_outbox;
+use client_outbox::SharedOutbox;
+mod client_profiles;
+use client_profiles::{Profile, ProfileFile};
+mod client_replies;
+use client_replies::SharedRecent;
+mod client_stickers;
+use client_stickers::{StickerConfig, Stickers};
+mod client_theme;
+use client_theme::{ColorMode, MessageLine, Theme};
+mod client_tui;
+mod client_writer;
+use client_writer::{ServerWriter, SharedWriteHalf};
+
+use chat::client::{LoginError, ReadHalf, WriteHalf};
+use chat::i18n::Lang;
+use chat::invite::Invite;
+use chat::{format_size, image_extension, t};
+use chat::{AdminCommand, AttachmentId, AttachmentKind, AudioFormat, CodecKind, ChatMessage, ChatMessageContent, Datagram, EmptyResult, MessageId, MessageSeq, ReplyTo, ServerResponse, SessionCodec, TransferId, UserProfile, UserStatus, FILE_CHUNK_SIZE, MAX_AVATAR_DIMENSION, MAX_AVATAR_SIZE, MAX_VOICE_NOTE_SIZE};
 
 /// Enum representing different types of client errors.
 #[derive(Debug, thiserror::Error)]
 pub enum ClientError {
-    #[error("File operation failed.")]
+    #[error("{}", t!("file-operation-failed"))]
     FileOperationFailed(#[from] Error),
-    #[error("Stream is broken")]
+    #[error("{}", t!("stream-broken"))]
     BrokenStream,
-    #[error("Login failed")]
-    LoginFailed
+    #[error("{}", t!("not-connected"))]
+    NotConnected,
+}
+
+/// Messages sent by this client which were not acknowledged by the server yet, keyed by message ID.
+type PendingAcks = Arc<Mutex<HashMap<MessageId, Instant>>>;
+
+/// Uploads waiting for the server to accept them, keyed by transfer ID. The server tells from which chunk
+/// the upload continues, the sender is dropped if the upload is refused.
+type PendingUploads = Arc<Mutex<HashMap<TransferId, oneshot::Sender<u64>>>>;
+
+/// Usernames learned from the user list, presence notifications and messages, used by the tab completion.
+type KnownUsers = Arc<Mutex<BTreeSet<String>>>;
+
+/// Server timestamp of the newest chat message shown to the user, reported to the server as read.
+type LastSeen = Arc<Mutex<Option<chrono::DateTime<chrono::Utc>>>>;
+
+/// ID of the newest stored message received, sent when logging in again so the server replays the missed ones.
+type LastReceived = Arc<Mutex<Option<MessageSeq>>>;
+
+/// How often the client reports the messages read by the user.
+const READ_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Image formats sent as they are with `--keep-image-format`, others are converted to PNG.
+const KEPT_IMAGE_FORMATS: &[ImageFormat] = &[ImageFormat::Jpeg, ImageFormat::WebP];
+
+/// Address of the server when neither a flag nor the profile gives one.
+const DEFAULT_ADDRESS: &str = "127.0.0.1";
+
+/// Port of the server when neither a flag nor the profile gives one.
+const DEFAULT_PORT: u16 = 11111;
+
+/// State used by the incoming loop to display, store and save what it receives.
+struct IncomingContext {
+    /// Username of the logged in user, used to recognize mentions
+    username: String,
+    /// Whether to show desktop notifications for mentions and direct messages
+    notify: bool,
+    /// Where the received messages are displayed
+    console: Console,
+    /// Where the received messages are stored
+    history: History,
+    /// Usernames seen by the client, updated from the received datagrams
+    known_users: KnownUsers,
+    /// Where the received attachments are saved
+    downloads: Downloads,
+    /// Uploads waiting to be accepted by the server
+    pending_uploads: PendingUploads,
+    /// Newest chat message received, reported to the server as read
+    last_seen: LastSeen,
+    /// Newest stored message received, kept across reconnections
+    last_received: LastReceived,
+    /// Recent text messages, numbered for replies and quoted above them
+    recent: SharedRecent,
+    /// Muted senders and do not disturb, changed by the .mute and .dnd commands
+    filters: SharedFilters,
+    /// Commands run for the received messages
+    hooks: Hooks,
+}
+
+/// Represents a file which is being received in chunks. The data goes to a partial file
+/// which is moved to its place when the transfer ends.
+struct IncomingFile {
+    file: File,
+    /// Path of the partial file
+    path: PathBuf,
+    /// ID of the attachment, used to resume the download
+    id: AttachmentId,
+    /// Username of the user who sent the attachment
+    sender: String,
+    kind: AttachmentKind,
+    progress: TransferProgress,
+    /// Number of bytes in the partial file
+    written: u64,
+}
+
+impl IncomingFile {
+    /// Writes a chunk to its place given by the sequence number, so that a resumed download continues
+    /// after the chunks received earlier.
+    ///
+    /// # Arguments
+    ///
+    /// * `seq` - The sequence number of the chunk.
+    /// * `data` - The chunk data.
+    ///
+    /// # Returns
+    ///
+    /// * `std::io::Result<Option<String>>` - Returns the progress line if the percentage changed.
+    fn write_chunk(&mut self, seq: u64, data: &[u8]) -> std::io::Result<Option<String>> {
+        let offset = seq * FILE_CHUNK_SIZE as u64;
+        if offset != self.written {
+            self.file.set_len(offset)?;
+            self.file.seek(SeekFrom::Start(offset))?;
+            self.progress.advance(offset.saturating_sub(self.written));
+        }
+        self.file.write_all(data)?;
+        self.written = offset + data.len() as u64;
+        Ok(self.progress.advance(data.len() as u64))
+    }
 }
 
 /// Listens to the TCP socket and processes incoming messages.
@@ -28,43 +165,729 @@ pub enum ClientError {
 /// # Arguments
 ///
 /// * `read_half` - The readable half of the TCP stream.
-async fn incoming_loop(mut read_half: OwnedReadHalf) {
+/// * `write_half` - The writable half of the TCP stream, used to reply to pings.
+/// * `pending_acks` - Messages waiting for an acknowledgement from the server.
+/// * `codec` - The codec used to encode and decode datagrams.
+/// * `context` - Where the received messages are displayed, stored and saved.
+///
+/// # Returns
+///
+/// * `Disconnect` - Returns why the connection ended.
+async fn incoming_loop(mut read_half: ReadHalf, write_half: &SharedWriteHalf, pending_acks: &PendingAcks, codec: SessionCodec, context: &IncomingContext) -> Disconnect {
+    let IncomingContext { username, notify, console, history, known_users, downloads, pending_uploads, last_seen, last_received, recent, filters, hooks } = context;
+    let notify = *notify;
+    let muted = |sender: &str| filters.lock().unwrap().is_muted(sender);
+    let may_notify = || notify && !filters.lock().unwrap().dnd_at(chrono::Local::now().time());
+    let mut incoming_files = HashMap::<TransferId, IncomingFile>::new();
+    // Set when the server ends the session on purpose, the client doesn't log in again then
+    let mut closed_by_server = false;
+    // Set when the statuses of the online users were printed, the plain list following them is then skipped
+    let mut statuses_listed = false;
+    // Number of `ListStatuses` sent to refresh the user list of the TUI, their answers aren't printed
+    let mut refreshes = 0;
     loop {
-        match Datagram::read_from_stream(&mut read_half).await {
+        let datagram = tokio::select! {
+            datagram = Datagram::read_from_stream(&mut read_half, &codec) => datagram,
+            _ = write_half.stalled() => Err(chat::ChatProtocolError::Timeout),
+        };
+        match datagram {
             Ok(Datagram::Message(message)) => {
-                let sender = message.sender;
+                if let Err(e) = history.record(&message, None).await {
+                    console.error(t!("error", error = e.to_string()));
+                }
+                known_users.lock().unwrap().insert(message.sender.clone());
+                mark_seen(last_seen, message.timestamp);
+                if let Some(seq) = message.seq {
+                    mark_received(last_received, seq);
+                }
+                if muted(&message.sender) {
+                    continue;
+                }
+                let sender = display_name(&message.sender, message.nickname.as_deref());
+                let time = format_time(&message.timestamp);
+                let hook = |content, text: Option<&str>, file: Option<&str>| {
+                    hooks.run(&HookEvent { content, sender: &message.sender, timestamp: message.timestamp, direct: false, text, file: file.map(Path::new) });
+                };
+                let line = |text: String, mention: bool| MessageLine {
+                    time: time.clone(),
+                    timestamp: message.timestamp,
+                    username: message.sender.clone(),
+                    sender: sender.clone(),
+                    text,
+                    mention,
+                    announcement: message.announcement,
+                    seq: message.seq,
+                    ttl: message.ttl,
+                    ..MessageLine::default()
+                };
+                let attached = |attachment: Attachment| MessageLine { text: console.attachment(&attachment), attachment: Some(attachment), ..line(String::new(), false) };
                 match message.content {
                     ChatMessageContent::Text(text) => {
-                        println!("[{sender}] {text}");
+                        let mention = mentions(&text, username);
+                        if mention && may_notify() {
+                            show_notification(console, t!("notification-mention", sender = sender.as_str()), text.clone());
+                        }
+                        let number = {
+                            let mut recent = recent.lock().unwrap();
+                            if let Some(reply_to) = &message.reply_to {
+                                console.print(recent.quote(reply_to));
+                            }
+                            recent.add(&message.sender, message.id, message.seq, message.ttl.is_some(), &text)
+                        };
+                        hook(HookContent::Text, Some(&text), None);
+                        console.message(MessageLine { number: Some(number), ..line(text, mention) });
                     },
                     ChatMessageContent::Image(data) => {
-                        println!("[{sender}] sending an image");
-                        if let Some(file) = handle_incoming_file("images", data, None) {
-                            println!("Image saved to {}", file);
+                        console.message(attached(Attachment::Image { size: data.len() as u64 }));
+                        let filename = generate_timestamp(image_extension(&data));
+                        if let Some(file) = handle_incoming_file(console, downloads, "images", data, Some(filename)) {
+                            hook(HookContent::Image, None, Some(&file));
+                            console.print(t!("image-saved", path = file));
                         }
                     },
                     ChatMessageContent::File(filename, data) => {
-                        println!("[{sender}] sending a file");
-                        if let Some(file) = handle_incoming_file("files", data, Some(filename)) {
-                            println!("File saved to {}", file);
+                        console.message(attached(Attachment::File { filename: filename.clone(), size: data.len() as u64 }));
+                        if let Some(file) = handle_incoming_file(console, downloads, "files", data, Some(filename)) {
+                            hook(HookContent::File, None, Some(&file));
+                            console.print(t!("file-saved", path = file));
+                        }
+                    },
+                    ChatMessageContent::Audio { format, data } => {
+                        console.message(attached(Attachment::VoiceNote { size: data.len() as u64 }));
+                        let filename = generate_timestamp(format.extension());
+                        if let Some(file) = handle_incoming_file(console, downloads, "voice", data, Some(filename)) {
+                            hook(HookContent::Audio, None, Some(&file));
+                            console.print(t!("voice-note-saved", path = file));
                         }
+                    },
+                    ChatMessageContent::LinkPreview { url, title, description } => {
+                        hook(HookContent::Link, Some(&url), None);
+                        console.link_preview(&url, &title, description.as_deref());
+                    },
+                }
+            },
+            Ok(Datagram::Thumbnail { id, message }) => {
+                if let Err(e) = history.record(&message, None).await {
+                    console.error(t!("error", error = e.to_string()));
+                }
+                known_users.lock().unwrap().insert(message.sender.clone());
+                mark_seen(last_seen, message.timestamp);
+                mark_received(last_received, id);
+                if muted(&message.sender) {
+                    continue;
+                }
+                let sender = display_name(&message.sender, message.nickname.as_deref());
+                let time = format_time(&message.timestamp);
+                let attachment = Attachment::Thumbnail { id };
+                let text = console.attachment(&attachment);
+                let username = message.sender.clone();
+                console.message(MessageLine { time, timestamp: message.timestamp, username, sender, text, attachment: Some(attachment), ..MessageLine::default() });
+                if let ChatMessageContent::Image(data) = message.content {
+                    if let Some(file) = handle_incoming_file(console, downloads, "thumbnails", data, None) {
+                        console.print(t!("thumbnail-saved", path = file));
                     }
                 }
             },
+            Ok(Datagram::FileOffer { id, sender, nickname, timestamp, filename, size }) => {
+                let kind = AttachmentKind::File(filename.clone());
+                if let Err(e) = history.record_attachment(&sender, &kind).await {
+                    console.error(t!("error", error = e.to_string()));
+                }
+                known_users.lock().unwrap().insert(sender.clone());
+                mark_seen(last_seen, timestamp);
+                mark_received(last_received, id);
+                if muted(&sender) {
+                    continue;
+                }
+                let attachment = Attachment::FileOffer { filename, size, id };
+                let text = console.attachment(&attachment);
+                let shown = display_name(&sender, nickname.as_deref());
+                console.message(MessageLine { time: format_time(&timestamp), timestamp, username: sender, sender: shown, text, attachment: Some(attachment), ..MessageLine::default() });
+            },
+            Ok(Datagram::DirectMessage { to, message }) => {
+                if let Err(e) = history.record(&message, Some(&to)).await {
+                    console.error(t!("error", error = e.to_string()));
+                }
+                if muted(&message.sender) {
+                    continue;
+                }
+                let sender = display_name(&message.sender, message.nickname.as_deref());
+                let time = format_time(&message.timestamp);
+                match message.content {
+                    ChatMessageContent::Text(text) => {
+                        if may_notify() {
+                            show_notification(console, t!("notification-direct", sender = sender.as_str()), text.clone());
+                        }
+                        let event = HookEvent { content: HookContent::Text, sender: &message.sender, timestamp: message.timestamp, direct: true, text: Some(&text), file: None };
+                        hooks.run(&event);
+                        let username = message.sender.clone();
+                        console.message(MessageLine { time, timestamp: message.timestamp, username, sender, recipient: Some(t!("recipient-you")), text, ..MessageLine::default() });
+                    },
+                    _ => {
+                        console.error(t!("unsupported-direct-content", sender = sender.as_str()));
+                    }
+                }
+            },
+            // Transfers are started by the server only when the user asks for an attachment with .get
+            Ok(Datagram::FileBegin { transfer_id, id, sender, kind, size }) => {
+                let label = match &kind {
+                    AttachmentKind::Image => t!("label-an-image"),
+                    AttachmentKind::File(filename) => basename(filename),
+                    AttachmentKind::Audio(_) => t!("label-a-voice-note"),
+                };
+                if matches!(&kind, AttachmentKind::File(filename) if downloads.skips("files", &basename(filename))) {
+                    // The chunks of a skipped transfer are ignored as its ID is unknown
+                    console.print(t!("file-skipped"));
+                    continue;
+                }
+
+                match downloads.open_partial(id) {
+                    Ok((file, path)) => {
+                        console.print(t!("downloading", label = label.as_str(), size = format_size(size)));
+                        let progress = TransferProgress::new(t!("receiving", label = label.as_str()), size);
+                        incoming_files.insert(transfer_id, IncomingFile { file, path, id, sender, kind, progress, written: 0 });
+                    },
+                    Err(e) => {
+                        console.error(t!("incoming-file-failed"));
+                        console.error(format!("{e}"));
+                    }
+                }
+            },
+            Ok(Datagram::FileChunk { transfer_id, seq, data }) => {
+                if let Some(incoming) = incoming_files.get_mut(&transfer_id) {
+                    match incoming.write_chunk(seq, &data) {
+                        Ok(Some(line)) => console.progress(Some(line)),
+                        Ok(None) => {},
+                        Err(e) => {
+                            console.progress(None);
+                            console.error(t!("incoming-file-failed"));
+                            console.error(t!("could-not-write", path = incoming.path.display().to_string(), error = e.to_string()));
+                            let _ = std::fs::remove_file(&incoming.path);
+                            incoming_files.remove(&transfer_id);
+                        }
+                    }
+                }
+            },
+            Ok(Datagram::FileEnd { transfer_id }) => {
+                if let Some(incoming) = incoming_files.remove(&transfer_id) {
+                    console.progress(None);
+                    let (subdir, filename) = match &incoming.kind {
+                        AttachmentKind::Image => {
+                            let mut head = Vec::new();
+                            let _ = File::open(&incoming.path).and_then(|file| file.take(16).read_to_end(&mut head));
+                            ("images", generate_timestamp(image_extension(&head)))
+                        },
+                        AttachmentKind::File(filename) => ("files", basename(filename)),
+                        AttachmentKind::Audio(format) => ("voice", generate_timestamp(format.extension())),
+                    };
+                    drop(incoming.file);
+                    match downloads.complete(&incoming.path, subdir, &filename) {
+                        Ok(Some(path)) => {
+                            let shown = path.display().to_string();
+                            let (content, saved) = match incoming.kind {
+                                AttachmentKind::Image => (HookContent::Image, t!("image-saved", path = shown)),
+                                AttachmentKind::File(_) => (HookContent::File, t!("file-saved", path = shown)),
+                                AttachmentKind::Audio(_) => (HookContent::Audio, t!("voice-note-saved", path = shown)),
+                            };
+                            // The time of the message isn't sent with the transfer, so the download time is given
+                            hooks.run(&HookEvent { content, sender: &incoming.sender, timestamp: chrono::Utc::now(), direct: false, text: None, file: Some(&path) });
+                            console.print(saved);
+                        },
+                        Ok(None) => console.print(t!("file-skipped")),
+                        Err(e) => {
+                            console.error(t!("incoming-file-failed"));
+                            console.error(format!("{e}"));
+                        }
+                    }
+                }
+            },
+            Ok(Datagram::FileAbort { transfer_id }) => {
+                if let Some(incoming) = incoming_files.remove(&transfer_id) {
+                    // The partial file is kept, so the download can be resumed
+                    console.progress(None);
+                    console.print(t!("download-interrupted", id = incoming.id.to_string()));
+                } else {
+                    // Dropping the sender tells the waiting upload that the server refused it
+                    pending_uploads.lock().unwrap().remove(&transfer_id);
+                }
+            },
+            Ok(Datagram::ServerResponse(ServerResponse::TransferAccepted { transfer_id, next_seq })) => {
+                if let Some(accepted) = pending_uploads.lock().unwrap().remove(&transfer_id) {
+                    let _ = accepted.send(next_seq);
+                }
+            },
+            Ok(Datagram::Ping) => {
+                if write_half.write(&Datagram::Pong, &codec).await.is_err() {
+                    return Disconnect::Broken(t!("connection-broken"));
+                }
+            },
+            Ok(Datagram::Pong) => {},
+            // More than the server sends at once were missed, the rest is asked for until all arrived
+            Ok(Datagram::ServerResponse(ServerResponse::FetchComplete { last_id, more: true })) => {
+                let fetch = Datagram::FetchSince { last_id };
+                if write_half.write(&fetch, &codec).await.is_err() {
+                    return Disconnect::Broken(t!("connection-broken"));
+                }
+            },
+            Ok(Datagram::Presence { username, online }) => {
+                known_users.lock().unwrap().insert(username.clone());
+                console.presence(&username, online);
+                // The user list of the TUI shows the status line the user may have set earlier
+                if online && console.lists_users() {
+                    if write_half.write(&Datagram::ListStatuses, &codec).await.is_err() {
+                        return Disconnect::Broken(t!("connection-broken"));
+                    }
+                    refreshes += 1;
+                }
+            },
+            Ok(Datagram::Announcement(text)) => {
+                console.print(t!("announcement", text = text.as_str()));
+            },
+            // Only messages shown in this session are reported, others are already gone
+            Ok(Datagram::MessageExpired { sender, id, seq }) => {
+                let expired = recent.lock().unwrap().expire(username, &sender, id, seq);
+                if let Some(number) = expired {
+                    console.expired(&sender, seq, number);
+                }
+            },
+            Ok(Datagram::Renamed { username, nickname }) => {
+                match nickname {
+                    Some(nickname) => console.print(t!("user-renamed", username = username.as_str(), nickname = nickname.as_str())),
+                    None => console.print(t!("user-nickname-removed", username = username.as_str())),
+                }
+            },
+            Ok(Datagram::ServerResponse(ServerResponse::MessageAck(id))) => {
+                pending_acks.lock().unwrap().remove(&id);
+            },
+            Ok(Datagram::ServerResponse(ServerResponse::UserOffline(username))) => {
+                console.error(t!("user-offline", username = username.as_str()));
+            },
+            Ok(Datagram::ServerResponse(ServerResponse::AdminCommandOk)) => {
+                console.print(t!("ok"));
+            },
+            Ok(Datagram::ServerResponse(ServerResponse::AdminCommandFailed(reason))) => {
+                console.error(t!("error", error = reason.as_str()));
+            },
+            Ok(Datagram::ServerResponse(ServerResponse::Kicked)) => {
+                console.error(t!("kicked"));
+                closed_by_server = true;
+            },
+            Ok(Datagram::ServerResponse(ServerResponse::Banned)) => {
+                console.error(t!("banned"));
+                closed_by_server = true;
+            },
+            Ok(Datagram::ServerResponse(ServerResponse::ServerFull)) => {
+                console.error(t!("server-full"));
+                closed_by_server = true;
+            },
+            Ok(Datagram::ServerResponse(ServerResponse::AlreadyLoggedIn)) => {
+                console.error(t!("logged-in-elsewhere"));
+                closed_by_server = true;
+            },
+            Ok(Datagram::ServerResponse(ServerResponse::AttachmentNotFound(id))) => {
+                console.error(t!("attachment-not-found", id = id.to_string()));
+            },
+            Ok(Datagram::ServerResponse(ServerResponse::AttachmentRejected { reason })) => {
+                console.error(t!("attachment-rejected", reason = reason.as_str()));
+            },
+            Ok(Datagram::ServerResponse(ServerResponse::MessageRejected { reason })) => {
+                console.error(t!("message-rejected", reason = reason.as_str()));
+            },
+            Ok(Datagram::ServerResponse(ServerResponse::Muted { until })) => {
+                let until = until.with_timezone(&chrono::Local).format("%H:%M:%S");
+                console.error(t!("muted-for-flooding", until = until.to_string()));
+            },
+            // The users are asked for with `ListStatuses` followed by `ListUsers`. Servers before protocol 1.3
+            // skip the first one, so the plain list is printed only if the statuses didn't come before it.
+            Ok(Datagram::ServerResponse(ServerResponse::UserList(users))) => {
+                known_users.lock().unwrap().extend(users.iter().cloned());
+                if !std::mem::take(&mut statuses_listed) {
+                    console.users(&users.into_iter().map(|username| UserStatus { username, status: None }).collect::<Vec<_>>());
+                }
+            },
+            Ok(Datagram::ServerResponse(ServerResponse::UserStatuses(users))) => {
+                known_users.lock().unwrap().extend(users.iter().map(|user| user.username.clone()));
+                if refreshes > 0 {
+                    refreshes -= 1;
+                    console.refresh_users(&users);
+                } else {
+                    console.users(&users);
+                    statuses_listed = true;
+                }
+            },
+            Ok(Datagram::ServerResponse(ServerResponse::Profile(profile))) => {
+                show_profile(console, downloads, profile);
+            },
+            // Profiles are fetched with every .profile, only the statuses in the user list of the TUI are kept
+            Ok(Datagram::ProfileUpdated { .. }) => {
+                if console.lists_users() {
+                    if write_half.write(&Datagram::ListStatuses, &codec).await.is_err() {
+                        return Disconnect::Broken(t!("connection-broken"));
+                    }
+                    refreshes += 1;
+                }
+            },
+            Ok(Datagram::ServerResponse(ServerResponse::ProfileSet)) => {
+                console.print(t!("profile-set"));
+            },
+            Ok(Datagram::ServerResponse(ServerResponse::ProfileRejected(reason))) => {
+                console.error(t!("profile-rejected", reason = reason.as_str()));
+            },
+            Ok(Datagram::ServerResponse(ServerResponse::PasswordChanged)) => {
+                console.print(t!("password-changed"));
+            },
+            Ok(Datagram::ServerResponse(ServerResponse::PasswordChangeFailed(reason))) => {
+                console.error(t!("password-change-failed", reason = reason.as_str()));
+            },
+            Ok(Datagram::ServerResponse(ServerResponse::NicknameChanged(nickname))) => {
+                match nickname {
+                    Some(nickname) => console.print(t!("nickname-changed", nickname = nickname.as_str())),
+                    None => console.print(t!("nickname-removed")),
+                }
+            },
+            Ok(Datagram::ServerResponse(ServerResponse::NicknameRejected(reason))) => {
+                console.error(t!("nickname-rejected", reason = reason.as_str()));
+            },
+            Ok(Datagram::ServerResponse(ServerResponse::BlockList(users))) => {
+                if users.is_empty() {
+                    console.print(t!("no-blocked-users"));
+                } else {
+                    console.print(t!("blocked-users", count = users.len(), users = users.join(", ")));
+                }
+            },
+            Ok(Datagram::ServerResponse(ServerResponse::BlockFailed(reason))) => {
+                console.error(t!("error", error = reason.as_str()));
+            },
+            Ok(Datagram::ServerResponse(ServerResponse::Stats(stats))) => {
+                console.print(t!("server-stats", uptime = format_uptime(stats.uptime), users = stats.users_online,
+                    messages = stats.messages, size = format_size(stats.database_size)));
+            },
+            Ok(Datagram::ServerResponse(ServerResponse::LastRead { username, read_at })) => {
+                match read_at {
+                    Some(read_at) => {
+                        let read_at = read_at.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M");
+                        console.print(t!("last-read", username = username.as_str(), time = read_at.to_string()));
+                    },
+                    None => console.print(t!("never-read", username = username.as_str())),
+                }
+            },
             Ok(Datagram::ServerResponse(_)) => {
-                // We don't handle any server responses here
+                // We don't handle any other server responses here
             },
             Ok(_) => {
-                eprintln!("Error: unexpected datagram");
+                console.error(t!("unexpected-datagram"));
             }
             Err(chat::ChatProtocolError::MalformedMessage) => {
-                eprintln!("Error: Malformed message received."); 
+                console.error(t!("malformed-message"));
+            },
+            Err(chat::ChatProtocolError::Timeout) => {
+                return Disconnect::Broken(t!("server-stalled"));
             },
             Err(chat::ChatProtocolError::IOError) => {
-                eprintln!("Error: Connection with server broken.");
-                exit(1);
+                let reason = t!("connection-broken");
+                return match closed_by_server {
+                    true => Disconnect::Closed(reason),
+                    false => Disconnect::Broken(reason),
+                };
+            },
+            Err(chat::ChatProtocolError::FrameTooLarge(len)) => {
+                return Disconnect::Closed(t!("frame-too-large", size = len.to_string()));
+            }
+        };
+    }
+}
+
+/// Prints a profile asked for with `.profile` and saves its avatar to the `avatars` subdirectory of the downloads.
+///
+/// # Arguments
+///
+/// * `console` - Where the profile is printed.
+/// * `downloads` - Where the avatar is saved.
+/// * `profile` - The profile sent by the server.
+fn show_profile(console: &Console, downloads: &Downloads, profile: UserProfile) {
+    let UserProfile { username, status, avatar_png } = profile;
+    if status.is_none() && avatar_png.is_none() {
+        console.print(t!("no-profile", username = username.as_str()));
+        return;
+    }
+    if let Some(status) = status {
+        console.print(t!("profile-status", username = username.as_str(), status = status.as_str()));
+    }
+    if let Some(avatar) = avatar_png {
+        if let Some(filename) = handle_incoming_file(console, downloads, "avatars", avatar, Some(format!("{username}.png"))) {
+            console.print(t!("avatar-saved", username = username.as_str(), filename = filename.as_str()));
+        }
+    }
+}
+
+/// Why the incoming loop ended.
+enum Disconnect {
+    /// The connection broke, the client can log in again
+    Broken(String),
+    /// The server ended the session on purpose, e.g. the user was kicked, or it can't go on
+    Closed(String),
+}
+
+/// How the client logs in.
+enum Credentials {
+    /// A registered user
+    User { username: String, password: String },
+    /// A guest without an account, known by a nickname
+    Guest { nickname: String },
+}
+
+impl Credentials {
+    /// Returns the username the client is logged in as, guests get theirs from the nickname.
+    fn username(&self) -> String {
+        match self {
+            Credentials::User { username, .. } => username.clone(),
+            Credentials::Guest { nickname } => format!("{}{nickname}", chat::GUEST_PREFIX),
+        }
+    }
+
+    /// Returns the password of a registered user, `None` for a guest.
+    fn password(&self) -> Option<&str> {
+        match self {
+            Credentials::User { password, .. } => Some(password),
+            Credentials::Guest { .. } => None,
+        }
+    }
+
+    /// Logs in on an open connection.
+    ///
+    /// # Arguments
+    ///
+    /// * `read_half` - The readable half of the connection.
+    /// * `write_half` - The writable half of the connection.
+    /// * `totp_code` - Called for the TOTP code if the user enabled two-factor authentication, `None` gives up the login.
+    /// * `codec` - The codec used by the server.
+    /// * `compression` - Whether to offer the server to compress large frames, guests don't.
+    /// * `last_id` - The ID of the newest stored message received before the connection broke, `None` for a new session.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<SessionCodec, LoginError>` - Returns the codec of the session if the server accepted the login.
+    async fn log_in(&self, read_half: &mut ReadHalf, write_half: &mut WriteHalf, totp_code: impl FnOnce() -> Option<String>,
+                    codec: CodecKind, compression: bool, last_id: Option<MessageSeq>) -> Result<SessionCodec, LoginError> {
+        match self {
+            Credentials::User { username, password } => {
+                chat::client::login(read_half, write_half, username, password, totp_code, codec, compression, last_id).await
+            },
+            Credentials::Guest { nickname } => chat::client::guest_login(read_half, write_half, nickname, codec, last_id).await,
+        }
+    }
+}
+
+/// What the client needs to log in again after the connection broke.
+struct LoginDetails {
+    address: String,
+    port: u16,
+    unix_socket: Option<PathBuf>,
+    credentials: Credentials,
+    codec: CodecKind,
+    compression: bool,
+}
+
+/// Delay before the first attempt to log in again, doubled after every failed attempt up to `MAX_RECONNECT_DELAY`.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Longest delay between the attempts to log in again.
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// Receives from the server until the session ends. When the connection breaks, the client logs in again
+/// and sends the messages typed in the meantime, unless it has no login details.
+///
+/// # Arguments
+///
+/// * `read_half` - The readable half of the first connection.
+/// * `write_half` - The writable half shared with the command loop, replaced after logging in again.
+/// * `pending_acks` - Messages waiting for an acknowledgement from the server.
+/// * `codec` - The codec used to encode and decode datagrams.
+/// * `context` - Where the received messages are displayed, stored and saved.
+/// * `outbox` - Messages typed while the client is offline.
+/// * `login` - What is needed to log in again, `None` if the client ends with the connection.
+async fn connection_loop(mut read_half: ReadHalf, write_half: SharedWriteHalf, pending_acks: PendingAcks, codec: SessionCodec,
+                         context: IncomingContext, outbox: SharedOutbox, login: Option<LoginDetails>) {
+    loop {
+        let (reason, login) = match (incoming_loop(read_half, &write_half, &pending_acks, codec, &context).await, &login) {
+            (Disconnect::Broken(reason), Some(login)) => (reason, login),
+            (Disconnect::Broken(reason) | Disconnect::Closed(reason), _) => return context.console.disconnected(reason),
+        };
+
+        outbox.lock().unwrap().go_offline();
+        // The server forgets the transfers of a closed connection, waiting uploads are refused
+        context.pending_uploads.lock().unwrap().clear();
+        context.console.progress(None);
+        context.console.reconnecting(t!("reconnecting", reason = reason));
+
+        let last_received = *context.last_received.lock().unwrap();
+        read_half = match reconnect(login, last_received).await {
+            Ok((new_read_half, new_write_half)) => {
+                write_half.replace(new_write_half).await;
+                new_read_half
+            },
+            Err(e) => return context.console.disconnected(t!("could-not-log-in-again", error = e.to_string())),
+        };
+        let sent = flush_outbox(&write_half, &pending_acks, codec, &outbox).await;
+        context.console.pending(outbox.lock().unwrap().pending());
+        context.console.reconnected(t!("reconnected", count = sent));
+        // Users came and went while the client was away, the user list of the TUI is listed again
+        if context.console.lists_users() {
+            for datagram in [Datagram::ListStatuses, Datagram::ListUsers] {
+                let _ = write_half.write(&datagram, &codec).await;
             }
+        }
+    }
+}
+
+/// Logs in again, retrying with a growing delay while the server can't be reached or is full. The server may also
+/// refuse the login until it notices that the broken connection is gone, if it doesn't allow several logins of a user.
+/// Two-factor authentication codes aren't asked for again, such accounts have to start the client again.
+///
+/// # Arguments
+///
+/// * `login` - What is needed to log in.
+/// * `last_id` - The ID of the newest stored message received, the server sends the newer ones first.
+///
+/// # Returns
+///
+/// * `Result<(ReadHalf, WriteHalf), LoginError>` - Returns both halves of the new connection, or why the login was refused.
+async fn reconnect(login: &LoginDetails, last_id: Option<MessageSeq>) -> Result<(ReadHalf, WriteHalf), LoginError> {
+    let mut delay = RECONNECT_DELAY;
+    loop {
+        tokio::time::sleep(delay).await;
+        let attempt = async {
+            let (mut read_half, mut write_half) = match &login.unix_socket {
+                Some(path) => chat::client::open_unix(path).await?,
+                None => chat::client::open_tcp(&login.address, login.port).await?,
+            };
+            // The server is the same, so the negotiated codec is too
+            login.credentials.log_in(&mut read_half, &mut write_half, || None, login.codec, login.compression, last_id).await?;
+            Ok((read_half, write_half))
         };
+        match attempt.await {
+            Ok(halves) => return Ok(halves),
+            Err(LoginError::Connect(..) | LoginError::Protocol(_) | LoginError::ServerFull | LoginError::AlreadyLoggedIn) => {},
+            Err(e) => return Err(e),
+        }
+        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+    }
+}
+
+/// Sends the messages typed while the client was offline, in the typed order and written together.
+/// If the connection breaks again, they stay queued until the next login.
+///
+/// # Arguments
+///
+/// * `write_half` - The writable half of the new connection.
+/// * `pending_acks` - Messages waiting for an acknowledgement from the server.
+/// * `codec` - The codec used to encode datagrams.
+/// * `outbox` - The queued messages.
+///
+/// # Returns
+///
+/// * `usize` - Returns the number of sent messages.
+async fn flush_outbox(write_half: &SharedWriteHalf, pending_acks: &PendingAcks, codec: SessionCodec, outbox: &SharedOutbox) -> usize {
+    let (datagrams, queued) = {
+        let mut outbox = outbox.lock().unwrap();
+        let datagrams = outbox.take_all();
+        if datagrams.is_empty() {
+            return 0;
+        }
+        let queued = write_half.queue_all(&datagrams, &codec);
+        (datagrams, queued)
+    };
+    let ids = datagrams.iter().filter_map(client_outbox::message_id).collect::<Vec<_>>();
+    let now = Instant::now();
+    pending_acks.lock().unwrap().extend(ids.iter().map(|id| (*id, now)));
+
+    let sent = match queued {
+        Ok(queued) => write_half.flush(queued).await,
+        Err(e) => Err(e),
+    };
+    if sent.is_err() {
+        let mut pending_acks = pending_acks.lock().unwrap();
+        for id in &ids {
+            pending_acks.remove(id);
+        }
+        outbox.lock().unwrap().put_back(datagrams);
+        return 0;
+    }
+    datagrams.len()
+}
+
+/// Periodically checks for messages which were not acknowledged by the server in time and warns the user.
+///
+/// # Arguments
+///
+/// * `pending_acks` - Messages waiting for an acknowledgement from the server.
+/// * `timeout` - How long to wait for an acknowledgement.
+/// * `console` - Where the warnings are displayed.
+async fn ack_watchdog(pending_acks: PendingAcks, timeout: Duration, console: Console) {
+    let mut interval = tokio::time::interval(Duration::from_millis(500));
+    loop {
+        interval.tick().await;
+        let mut pending = pending_acks.lock().unwrap();
+        pending.retain(|id, sent_at| {
+            if sent_at.elapsed() < timeout {
+                true
+            } else {
+                console.error(t!("message-not-acknowledged", id = id.to_string(), seconds = timeout.as_secs()));
+                false
+            }
+        });
+    }
+}
+
+/// Remembers the timestamp of a received chat message if it is the newest one so far.
+///
+/// # Arguments
+///
+/// * `last_seen` - The newest timestamp seen so far.
+/// * `timestamp` - The server timestamp of the received message.
+fn mark_seen(last_seen: &LastSeen, timestamp: chrono::DateTime<chrono::Utc>) {
+    let mut last_seen = last_seen.lock().unwrap();
+    if last_seen.is_none_or(|seen| timestamp > seen) {
+        *last_seen = Some(timestamp);
+    }
+}
+
+/// Remembers the ID of a received stored message if it is the newest one so far.
+///
+/// # Arguments
+///
+/// * `last_received` - The newest ID received so far.
+/// * `id` - The sequence number given by the server to the received message.
+fn mark_received(last_received: &LastReceived, id: MessageSeq) {
+    let mut last_received = last_received.lock().unwrap();
+    if last_received.is_none_or(|received| id > received) {
+        *last_received = Some(id);
+    }
+}
+
+/// Periodically tells the server which messages the user has read. Nothing is sent
+/// until a new message arrives.
+///
+/// # Arguments
+///
+/// * `write_half` - The writable half of the connection.
+/// * `last_seen` - The newest message shown to the user.
+/// * `codec` - The codec used to encode datagrams.
+async fn read_reporter(write_half: SharedWriteHalf, last_seen: LastSeen, codec: SessionCodec) {
+    let mut interval = tokio::time::interval(READ_REPORT_INTERVAL);
+    let mut reported = None;
+    loop {
+        interval.tick().await;
+        let seen = *last_seen.lock().unwrap();
+        let Some(up_to) = seen.filter(|_| seen != reported) else {
+            continue;
+        };
+        let datagram = Datagram::MarkRead { up_to };
+        if write_half.write(&datagram, &codec).await.is_err() {
+            // The connection task logs in again, the report is sent after that
+            continue;
+        }
+        reported = seen;
     }
 }
 
@@ -72,53 +895,69 @@ async fn incoming_loop(mut read_half: OwnedReadHalf) {
 ///
 /// # Arguments
 ///
-/// * `dir_path` - The directory path where the file will be saved.
+/// * `console` - Where errors are displayed.
+/// * `downloads` - Where received attachments are saved.
+/// * `dir_path` - The subdirectory of the download directory where the file will be saved.
 /// * `data` - The file data.
 /// * `filename` - The optional filename.
 ///
 /// # Returns
 ///
-/// * `Option<String>` - Returns the saved filename if successful.
-fn handle_incoming_file(dir_path: &str, data: Vec<u8>, filename: Option<String>) -> Option<String> {
-    match save_received_file(dir_path, data, filename) {
-        Ok(filename) => {
+/// * `Option<String>` - Returns the saved filename if successful, `None` if it failed or was skipped.
+fn handle_incoming_file(console: &Console, downloads: &Downloads, dir_path: &str, data: Vec<u8>, filename: Option<String>) -> Option<String> {
+    match save_received_file(downloads, dir_path, data, filename) {
+        Ok(Some(filename)) => {
             Some(filename)
         },
+        Ok(None) => {
+            console.print(t!("file-skipped"));
+            None
+        },
         Err(e) => {
-            eprintln!("Failed to save an incoming file.");
-            eprintln!("{e}");
+            console.error(t!("incoming-file-failed"));
+            console.error(format!("{e}"));
             None
         }
     }
 }
 
-/// Saves the received file to the specified directory.
+/// Saves the received file to the specified directory. A file with the same name is handled by the overwrite policy.
 ///
 /// # Arguments
 ///
-/// * `dir_path` - The directory path where the file will be saved.
+/// * `downloads` - Where received attachments are saved.
+/// * `dir_path` - The subdirectory of the download directory where the file will be saved.
 /// * `data` - The file data.
 /// * `filename` - The optional filename.
 ///
 /// # Returns
 ///
-/// * `Result<String>` - Returns the saved filename if successful.
-fn save_received_file(dir_path: &str, data: Vec<u8>, filename: Option<String>) -> Result<String> {
-    let dir_path = Path::new(dir_path);
-    if !dir_path.exists() {
-        std::fs::create_dir_all(dir_path)
-            .with_context(|| format!("Error: Failed to create directory: {:?}", dir_path))?;       
-    }
-    
-    let filename = basename(filename.unwrap_or(generate_timestamp("png")).as_str());
-    let filepath = Path::join(dir_path, filename);
-
-    let mut file = File::create(&filepath)
-        .with_context(|| format!("Error: Could not create {:?}", &filepath))?;
+/// * `Result<Option<String>>` - Returns the saved filename if successful, `None` if the file was skipped.
+fn save_received_file(downloads: &Downloads, dir_path: &str, data: Vec<u8>, filename: Option<String>) -> Result<Option<String>> {
+    let Some((mut file, filepath)) = create_received_file(downloads, dir_path, filename)? else {
+        return Ok(None);
+    };
     file.write_all(&data)
-        .with_context(|| format!("Error: Could not write to {:?}", &filepath))?;
+        .with_context(|| t!("could-not-write-received", path = format!("{filepath:?}")))?;
 
-    Ok(filepath.to_string_lossy().to_string())
+    Ok(Some(filepath))
+}
+
+/// Creates a file for received data in the specified directory.
+///
+/// # Arguments
+///
+/// * `downloads` - Where received attachments are saved.
+/// * `dir_path` - The subdirectory of the download directory where the file will be created.
+/// * `filename` - The optional filename.
+///
+/// # Returns
+///
+/// * `Result<Option<(File, String)>>` - Returns the opened file and its path if successful, `None` if the file was skipped.
+fn create_received_file(downloads: &Downloads, dir_path: &str, filename: Option<String>) -> Result<Option<(File, String)>> {
+    let filename = basename(filename.unwrap_or(generate_timestamp("png")).as_str());
+    let created = downloads.create(dir_path, &filename)?;
+    Ok(created.map(|(file, filepath)| (file, filepath.to_string_lossy().to_string())))
 }
 
 /// Generates a timestamped filename with the specified extension.
@@ -135,6 +974,76 @@ fn generate_timestamp(file_ext: &str) -> String {
     time.format("%Y-%m-%d-%H:%M:%S.").to_string() + file_ext
 }
 
+/// Checks whether a message mentions the user by `@username`. The mention must not continue
+/// with another letter, so `@Bob` doesn't match `@Bobby`.
+///
+/// # Arguments
+///
+/// * `text` - The text of the message.
+/// * `username` - The username of the logged in user.
+///
+/// # Returns
+///
+/// * `bool` - Returns `true` if the user is mentioned.
+fn mentions(text: &str, username: &str) -> bool {
+    let mention = format!("@{username}");
+    text.match_indices(&mention).any(|(i, _)| {
+        !text[i + mention.len()..].chars().next().is_some_and(|c| c.is_alphanumeric() || c == '_')
+    })
+}
+
+/// Returns the name under which a sender is shown. The username stays visible next to the nickname,
+/// as it's needed for direct messages and nicknames are chosen freely.
+///
+/// # Arguments
+///
+/// * `username` - The username of the sender.
+/// * `nickname` - The nickname of the sender, if any.
+///
+/// # Returns
+///
+/// * `String` - Returns the displayed name.
+fn display_name(username: &str, nickname: Option<&str>) -> String {
+    match nickname {
+        Some(nickname) => format!("{nickname} ({username})"),
+        None => username.to_string(),
+    }
+}
+
+/// Shows a desktop notification. Talking to the notification daemon blocks, so it runs on a blocking thread.
+///
+/// # Arguments
+///
+/// * `console` - Where a failure is reported.
+/// * `summary` - The title of the notification.
+/// * `body` - The text of the notification.
+fn show_notification(console: &Console, summary: String, body: String) {
+    let console = console.clone();
+    tokio::task::spawn_blocking(move || {
+        let result = notify_rust::Notification::new()
+            .appname("myrustchat")
+            .summary(&summary)
+            .body(&body)
+            .show();
+        if let Err(e) = result {
+            console.error(t!("notification-failed", error = e.to_string()));
+        }
+    });
+}
+
+/// Formats a message timestamp as local time.
+///
+/// # Arguments
+///
+/// * `timestamp` - The timestamp of the message.
+///
+/// # Returns
+///
+/// * `String` - Returns the time formatted as `HH:MM`.
+fn format_time(timestamp: &chrono::DateTime<chrono::Utc>) -> String {
+    timestamp.with_timezone(&chrono::Local).format("%H:%M").to_string()
+}
+
 /// Extracts the basename from the given filename.
 ///
 /// # Arguments
@@ -151,21 +1060,260 @@ fn basename(filename: &str) -> String {
                 .to_str().unwrap_or(default_fn).to_string()
 }
 
-/// Represents the chat context holding the writable half of the TCP stream and the username.
+/// Represents the chat context holding the writable half of the TCP stream, the username,
+/// the state needed to track message acknowledgements, the console used for output and the local message history.
 struct ChatContext {
-    write_half: OwnedWriteHalf,
+    /// Accounts the client is logged in to, by name
+    sessions: BTreeMap<String, Session>,
+    /// Name of the account the typed messages and commands go to, changed by .switch
+    active: String,
+    console: Console,
+    /// Usernames of all accounts' servers, offered by the tab completion
+    known_users: KnownUsers,
+    /// Whether JPEG and WebP images are sent without converting them to PNG
+    keep_image_format: bool,
+    /// Images of the sticker directory, sent by .sticker
+    stickers: Stickers,
+    /// Notification filters shared with the incoming loops
+    filters: SharedFilters,
+    /// Configuration file where changed filters are saved
+    config_file: Option<PathBuf>,
+}
+
+/// Connection of one account and what the client keeps about it.
+struct Session {
+    write_half: SharedWriteHalf,
     username: String,
+    next_message_id: MessageId,
+    pending_acks: PendingAcks,
+    codec: SessionCodec,
+    /// Where the output of the account goes, with the name of the account if there are several
+    console: Console,
+    history: History,
+    recent: SharedRecent,
+    downloads: Downloads,
+    pending_uploads: PendingUploads,
+    /// Messages typed while the connection is down
+    outbox: SharedOutbox,
+    /// Newest chat message received, reported to the server as read
+    last_seen: LastSeen,
+}
+
+impl ChatContext {
+    /// Returns the session of the active account.
+    fn session(&self) -> &Session {
+        &self.sessions[&self.active]
+    }
+
+    /// Returns the session of the active account for changing it.
+    fn session_mut(&mut self) -> &mut Session {
+        self.sessions.get_mut(&self.active).expect("The active account has a session.")
+    }
+
+    /// Describes who the user is chatting as, with the account when the client is logged in to several.
+    ///
+    /// # Returns
+    ///
+    /// * `String` - Returns the username, e.g. `alice` or `asmith (work)`.
+    fn identity(&self) -> String {
+        match self.sessions.len() {
+            1 => self.session().username.clone(),
+            _ => format!("{} ({})", self.session().username, self.active),
+        }
+    }
+
+    /// Returns the name of the active account as the output of its session shows it, `None` if there's only one.
+    fn account(&self) -> Option<String> {
+        (self.sessions.len() > 1).then(|| self.active.clone())
+    }
+
+    /// Changes the notification filters and saves them to the configuration file. The change applies even if
+    /// saving fails, but only until the client exits.
+    ///
+    /// # Arguments
+    ///
+    /// * `change` - The function changing the filters.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<T>` - Returns what the function returned, or `ClientError::FileOperationFailed` if the file could not be written.
+    fn change_filters<T>(&self, change: impl FnOnce(&mut NotificationFilters) -> T) -> Result<T> {
+        let (result, filters) = {
+            let mut filters = self.filters.lock().unwrap();
+            (change(&mut filters), filters.clone())
+        };
+        if let Some(path) = &self.config_file {
+            filters.save(path).map_err(ClientError::FileOperationFailed)?;
+        }
+        Ok(result)
+    }
+
+    /// Allocates a fresh message ID.
+    ///
+    /// # Returns
+    ///
+    /// * `MessageId` - Returns the allocated ID.
+    fn allocate_message_id(&mut self) -> MessageId {
+        let session = self.session_mut();
+        let id = session.next_message_id;
+        session.next_message_id += 1;
+        id
+    }
+
+    /// Registers a message as waiting for an acknowledgement from the server.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The ID of the message.
+    fn expect_ack(&self, id: MessageId) {
+        self.session().pending_acks.lock().unwrap().insert(id, Instant::now());
+    }
+
+    /// Sends a datagram other than a chat message. A broken connection makes the client offline until it
+    /// has logged in again, the command fails with `ClientError::NotConnected` but the client keeps running.
+    ///
+    /// # Arguments
+    ///
+    /// * `datagram` - The datagram to be sent.
+    ///
+    /// # Returns
+    ///
+    /// * `EmptyResult` - Returns an empty result if successful.
+    async fn send(&self, datagram: &Datagram) -> EmptyResult {
+        let session = self.session();
+        if session.write_half.write(datagram, &session.codec).await.is_err() {
+            session.outbox.lock().unwrap().go_offline();
+            Err(ClientError::NotConnected)?
+        }
+        Ok(())
+    }
+
+    /// Sends a chat message and registers it as waiting for an acknowledgement. While the client is offline,
+    /// or if the connection breaks while sending, the message is queued and sent after the client has logged in again.
+    ///
+    /// # Arguments
+    ///
+    /// * `datagram` - The message, a `Datagram::Message` or a `Datagram::DirectMessage`.
+    async fn deliver(&self, datagram: Datagram) {
+        let session = self.session();
+        if session.outbox.lock().unwrap().is_online() {
+            if let Some(id) = client_outbox::message_id(&datagram) {
+                self.expect_ack(id);
+            }
+            if session.write_half.write(&datagram, &session.codec).await.is_ok() {
+                return;
+            }
+            if let Some(id) = client_outbox::message_id(&datagram) {
+                session.pending_acks.lock().unwrap().remove(&id);
+            }
+        }
+
+        let pending = {
+            let mut outbox = session.outbox.lock().unwrap();
+            outbox.go_offline();
+            outbox.push(datagram)
+        };
+        session.console.pending(pending);
+    }
+
+    /// Tells whether the command loop goes on after a failed command. File errors are only reported,
+    /// and so are the commands which couldn't be sent while the client is offline.
+    ///
+    /// # Arguments
+    ///
+    /// * `e` - The error of the command.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - Returns `true` if the error was only reported.
+    fn is_recoverable(&self, e: &Error) -> bool {
+        matches!(e.downcast_ref::<ClientError>(), Some(ClientError::FileOperationFailed(_) | ClientError::NotConnected))
+            || !self.session().outbox.lock().unwrap().is_online()
+    }
+
+    /// Stores a sent message in the local history. A failure is only reported, as the message was already sent.
+    ///
+    /// # Arguments
+    ///
+    /// * `message` - The sent message.
+    /// * `recipient` - The recipient of a direct message, `None` for messages sent to everyone.
+    async fn remember(&self, message: &ChatMessage, recipient: Option<&str>) {
+        if let Err(e) = self.session().history.record(message, recipient).await {
+            self.console.error(t!("error", error = e.to_string()));
+        }
+    }
+
+    /// Creates a new chat message with a fresh ID.
+    ///
+    /// # Arguments
+    ///
+    /// * `content` - The content of the chat message.
+    ///
+    /// # Returns
+    ///
+    /// * `ChatMessage` - Returns the message ready to be sent.
+    fn new_message(&mut self, content: ChatMessageContent) -> ChatMessage {
+        let id = self.allocate_message_id();
+        ChatMessage::new(id, &self.session().username, content)
+    }
 }
 
+/// Number of messages printed by `.history` without an argument.
+const DEFAULT_HISTORY_COUNT: usize = 20;
+
 /// Enum representing different user commands.
 #[derive(PartialEq)]
 enum UserCommand {
     Text(String),
+    Reply(u64, String),
+    Direct(String, String),
+    /// Posts to the announcement channel, only admins may
+    Announce(String),
+    /// Sends a message which expires after the given number of seconds
+    Ephemeral(u32, String),
+    Admin(AdminCommand),
+    ChangePassword(String, String),
+    Nick(Option<String>),
+    /// Sets the status line of the profile, `None` removes it
+    Status(Option<String>),
+    /// Sets the avatar of the profile from an image file, `None` removes it
+    Avatar(Option<String>),
+    /// Shows the profile of a user
+    Profile(String),
+    Get(AttachmentId),
     File(String),
     Image(String),
+    /// Sends an image of the sticker directory
+    Sticker(String),
+    /// Lists the stickers
+    Stickers,
+    Voice(String),
+    Who,
+    Seen(String),
+    Block(String),
+    Unblock(String),
+    Blocks,
+    /// Mutes a sender, `None` lists the muted ones
+    Mute(Option<String>),
+    Unmute(String),
+    /// Changes do not disturb, `None` shows its state
+    Dnd(Option<DndChange>),
+    Stats,
+    History(usize),
+    /// Makes another account the active one, `None` lists the accounts
+    Switch(Option<String>),
     Quit,
 }
 
+/// Change of the do not disturb mode asked for with `.dnd`.
+#[derive(PartialEq)]
+enum DndChange {
+    On,
+    /// Turns it off and removes the schedule
+    Off,
+    Schedule(DndSchedule),
+}
+
 impl UserCommand {
     /// Parses a command string into a `UserCommand`.
     ///
@@ -181,12 +1329,92 @@ impl UserCommand {
         let command = line_sep.split_once(' ');
         match command {
             Some((".quit", "")) => Self::Quit,
+            Some((".who", "")) => Self::Who,
+            Some((".seen", username)) if !username.trim().is_empty() && !username.trim().contains(' ') => Self::Seen(username.trim().to_string()),
+            Some((".block", username)) if !username.trim().is_empty() && !username.trim().contains(' ') => Self::Block(username.trim().to_string()),
+            Some((".unblock", username)) if !username.trim().is_empty() && !username.trim().contains(' ') => Self::Unblock(username.trim().to_string()),
+            Some((".blocks", "")) => Self::Blocks,
+            Some((".mute", username)) => match username.trim() {
+                "" => Self::Mute(None),
+                username if !username.contains(' ') => Self::Mute(Some(username.to_string())),
+                _ => Self::Text(line.to_string()),
+            },
+            Some((".unmute", username)) if !username.trim().is_empty() && !username.trim().contains(' ') => Self::Unmute(username.trim().to_string()),
+            Some((".dnd", setting)) => match setting.trim() {
+                "" => Self::Dnd(None),
+                "on" => Self::Dnd(Some(DndChange::On)),
+                "off" => Self::Dnd(Some(DndChange::Off)),
+                schedule => schedule.parse().map(|schedule| Self::Dnd(Some(DndChange::Schedule(schedule)))).unwrap_or(Self::Text(line.to_string())),
+            },
+            Some((".stats", "")) => Self::Stats,
+            Some((".switch", name)) => match name.trim() {
+                "" => Self::Switch(None),
+                name if !name.contains(' ') => Self::Switch(Some(name.to_string())),
+                _ => Self::Text(line.to_string()),
+            },
+            Some((".history", count)) => match count.trim() {
+                "" => Self::History(DEFAULT_HISTORY_COUNT),
+                count => count.parse().map(Self::History).unwrap_or(Self::Text(line.to_string())),
+            },
             Some((".file", filename)) => Self::File(filename.trim().to_string()),
             Some((".image", filename)) => Self::Image(filename.trim().to_string()),
+            Some((".sticker", name)) if !name.trim().is_empty() => Self::Sticker(name.trim().to_string()),
+            Some((".stickers", "")) => Self::Stickers,
+            Some((".voice", filename)) => Self::Voice(filename.trim().to_string()),
+            Some((".kick", username)) if !username.trim().is_empty() => Self::Admin(AdminCommand::Kick(username.trim().to_string())),
+            Some((".ban", username)) if !username.trim().is_empty() => Self::Admin(AdminCommand::Ban(username.trim().to_string())),
+            Some((".unban", username)) if !username.trim().is_empty() => Self::Admin(AdminCommand::Unban(username.trim().to_string())),
+            Some((".announce", text)) if !text.trim().is_empty() => Self::Announce(text.trim().to_string()),
+            Some((".passwd", rest)) => match rest.trim().split_once(' ') {
+                Some((old, new)) if !new.trim().is_empty() && !new.trim().contains(' ') => Self::ChangePassword(old.to_string(), new.trim().to_string()),
+                _ => Self::Text(line.to_string())
+            },
+            // .fetch is the former name of .get
+            Some((".get" | ".fetch", id)) => match id.trim().parse() {
+                Ok(id) => Self::Get(id),
+                Err(_) => Self::Text(line.to_string()),
+            },
+            Some((".nick", nickname)) => match nickname.trim() {
+                "" => Self::Nick(None),
+                nickname => Self::Nick(Some(nickname.to_string())),
+            },
+            Some((".status", status)) => match status.trim() {
+                "" => Self::Status(None),
+                status => Self::Status(Some(status.to_string())),
+            },
+            Some((".avatar", filename)) => match filename.trim() {
+                "" => Self::Avatar(None),
+                filename => Self::Avatar(Some(filename.to_string())),
+            },
+            Some((".profile", username)) if !username.trim().is_empty() && !username.trim().contains(' ') => Self::Profile(username.trim().to_string()),
+            Some((".reply", rest)) => match rest.trim().split_once(' ') {
+                Some((number, text)) if !text.trim().is_empty() => match number.trim_start_matches('#').parse() {
+                    Ok(number) => Self::Reply(number, text.trim().to_string()),
+                    Err(_) => Self::Text(line.to_string()),
+                },
+                _ => Self::Text(line.to_string())
+            },
+            Some((".ephemeral", rest)) => match rest.trim().split_once(' ') {
+                Some((ttl, text)) if !text.trim().is_empty() => match ttl.parse() {
+                    Ok(ttl) if ttl > 0 => Self::Ephemeral(ttl, text.trim().to_string()),
+                    _ => Self::Text(line.to_string()),
+                },
+                _ => Self::Text(line.to_string())
+            },
+            Some((".msg", rest)) => match rest.trim().split_once(' ') {
+                Some((to, text)) if !text.trim().is_empty() => Self::Direct(to.to_string(), text.trim().to_string()),
+                _ => Self::Text(line.to_string())
+            },
             _ => Self::Text(line.to_string())
         }
     }
 
+    /// Tells whether the command can be used while the client is reconnecting. Messages are queued
+    /// until the client is back online, other requests to the server fail.
+    fn works_offline(&self) -> bool {
+        matches!(self, Self::Text(_) | Self::Reply(..) | Self::Direct(..) | Self::Announce(_) | Self::Ephemeral(..) | Self::Voice(_) | Self::Stickers | Self::History(_) | Self::Mute(_) | Self::Unmute(_) | Self::Dnd(_) | Self::Switch(_) | Self::Quit)
+    }
+
     /// Performs a user command.
     ///
     /// # Arguments
@@ -197,29 +1425,241 @@ impl UserCommand {
     ///
     /// * `Result<bool>` - Returns `true` if the command indicates to quit, otherwise `false`.
     async fn perform(&self, context: &mut ChatContext) -> Result<bool> {
+        if !self.works_offline() && !context.session().outbox.lock().unwrap().is_online() {
+            Err(ClientError::NotConnected)?
+        }
+
         match &self {
             Self::Text(text) => {
-                send_message(context, ChatMessageContent::Text(text.clone())).await?;
+                send_message(context, ChatMessageContent::Text(expand_shortcodes(text)), None).await?;
+                Ok(false)
+            },
+            Self::Reply(number, text) => {
+                let reply_to = context.session().recent.lock().unwrap().reply_to(*number);
+                match reply_to {
+                    Some(reply_to) => send_message(context, ChatMessageContent::Text(expand_shortcodes(text)), Some(reply_to)).await?,
+                    None => context.console.error(t!("no-recent-message", number = number.to_string())),
+                }
+                Ok(false)
+            },
+            Self::Direct(to, text) => {
+                send_direct_message(context, to, ChatMessageContent::Text(expand_shortcodes(text))).await?;
+                Ok(false)
+            },
+            Self::Announce(text) => {
+                let message = ChatMessage { announcement: true, ..context.new_message(ChatMessageContent::Text(expand_shortcodes(text))) };
+                context.deliver(Datagram::Message(message.clone())).await;
+                context.remember(&message, None).await;
+                Ok(false)
+            },
+            // Not kept in the history, it would outlive the message
+            Self::Ephemeral(ttl, text) => {
+                let text = expand_shortcodes(text);
+                let message = ChatMessage { ttl: Some(*ttl), ..context.new_message(ChatMessageContent::Text(text.clone())) };
+                context.session().recent.lock().unwrap().add_own(&message.sender, message.id, true, &text);
+                context.deliver(Datagram::Message(message)).await;
+                Ok(false)
+            },
+            Self::Who => {
+                for datagram in [Datagram::ListStatuses, Datagram::ListUsers] {
+                    context.send(&datagram).await
+                        .context(t!("request-users-failed"))?;
+                }
+                Ok(false)
+            },
+            Self::Seen(username) => {
+                context.send(&Datagram::Seen(username.clone())).await
+                    .context(t!("request-seen-failed"))?;
+                Ok(false)
+            },
+            Self::Status(status) => {
+                let status = status.clone().unwrap_or_default();
+                context.send(&Datagram::SetProfile { status: Some(status), avatar_png: None }).await
+                    .context(t!("profile-request-failed"))?;
+                Ok(false)
+            },
+            Self::Avatar(filename) => {
+                let avatar = match filename {
+                    Some(filename) => read_avatar(filename).await.map_err(ClientError::FileOperationFailed)?,
+                    None => Vec::new(),
+                };
+                context.send(&Datagram::SetProfile { status: None, avatar_png: Some(avatar) }).await
+                    .context(t!("profile-request-failed"))?;
+                Ok(false)
+            },
+            Self::Profile(username) => {
+                context.send(&Datagram::FetchProfile(username.clone())).await
+                    .context(t!("request-profile-failed"))?;
+                Ok(false)
+            },
+            Self::Block(username) => {
+                context.send(&Datagram::Block(username.clone())).await
+                    .context(t!("block-failed"))?;
+                Ok(false)
+            },
+            Self::Unblock(username) => {
+                context.send(&Datagram::Unblock(username.clone())).await
+                    .context(t!("unblock-failed"))?;
+                Ok(false)
+            },
+            Self::Blocks => {
+                context.send(&Datagram::ListBlocks).await
+                    .context(t!("request-blocks-failed"))?;
+                Ok(false)
+            },
+            Self::Mute(None) => {
+                let muted = context.filters.lock().unwrap().muted.iter().cloned().collect::<Vec<_>>();
+                match muted.is_empty() {
+                    true => context.console.print(t!("no-muted-users")),
+                    false => context.console.print(t!("muted-users", users = muted.join(", "))),
+                }
+                Ok(false)
+            },
+            Self::Mute(Some(username)) => {
+                context.change_filters(|filters| filters.muted.insert(username.clone()))?;
+                context.console.print(t!("user-muted", username = username.as_str()));
+                Ok(false)
+            },
+            Self::Unmute(username) => {
+                match context.change_filters(|filters| filters.muted.remove(username))? {
+                    true => context.console.print(t!("user-unmuted", username = username.as_str())),
+                    false => context.console.print(t!("user-not-muted", username = username.as_str())),
+                }
+                Ok(false)
+            },
+            Self::Dnd(change) => {
+                let filters = match change {
+                    None => context.filters.lock().unwrap().clone(),
+                    Some(change) => context.change_filters(|filters| {
+                        match change {
+                            DndChange::On => filters.dnd = true,
+                            DndChange::Off => (filters.dnd, filters.dnd_schedule) = (false, None),
+                            DndChange::Schedule(schedule) => filters.dnd_schedule = Some(*schedule),
+                        }
+                        filters.clone()
+                    })?,
+                };
+                let state = if filters.dnd { "on" } else { "off" };
+                match filters.dnd_schedule {
+                    Some(schedule) => context.console.print(t!("dnd-scheduled", state = state, schedule = schedule.to_string())),
+                    None => context.console.print(t!("dnd-state", state = state)),
+                }
+                Ok(false)
+            },
+            Self::Stats => {
+                context.send(&Datagram::Stats).await
+                    .context(t!("request-stats-failed"))?;
+                Ok(false)
+            },
+            Self::History(count) => {
+                let entries = context.session().history.last(*count).await
+                    .map_err(ClientError::FileOperationFailed)?;
+                for entry in entries {
+                    context.console.message(entry.to_message_line());
+                }
+                Ok(false)
+            },
+            Self::Admin(command) => {
+                context.send(&Datagram::AdminCommand(command.clone())).await
+                    .context(t!("admin-command-failed"))?;
+                Ok(false)
+            },
+            Self::ChangePassword(old_password, new_password) => {
+                let datagram = Datagram::ChangePassword { old_password: old_password.clone(), new_password: new_password.clone() };
+                context.send(&datagram).await
+                    .context(t!("password-request-failed"))?;
+                Ok(false)
+            },
+            Self::Get(id) => {
+                let from_seq = context.session().downloads.resume_point(*id)
+                    .map_err(ClientError::FileOperationFailed)?;
+                if from_seq > 0 {
+                    context.console.print(t!("resuming-download", offset = format_size(from_seq * FILE_CHUNK_SIZE as u64)));
+                }
+                context.send(&Datagram::FetchAttachment { id: *id, from_seq }).await
+                    .context(t!("request-attachment-failed"))?;
+                Ok(false)
+            },
+            Self::Nick(nickname) => {
+                context.send(&Datagram::SetNickname(nickname.clone())).await
+                    .context(t!("nickname-request-failed"))?;
+                Ok(false)
+            },
+            Self::Image(filename) => {
+                let (data, conversion) = read_image_data(filename, context.keep_image_format).await
+                    .map_err(ClientError::FileOperationFailed)?;
+                if let Some(conversion) = conversion {
+                    context.console.print(t!("image-converted", filename = basename(filename), seconds = format!("{:.2}", conversion.as_secs_f64())));
+                }
+                let transfer_id = upload_id(&[b"image", &data]);
+                send_attachment(context, AttachmentKind::Image, data.len() as u64, transfer_id, Cursor::new(data)).await?;
+                context.console.print(t!("image-sent"));
+                Ok(false)
+            },
+            Self::Sticker(name) => {
+                let data = context.stickers.load(name).await
+                    .map_err(ClientError::FileOperationFailed)?;
+                let transfer_id = upload_id(&[b"image", &data]);
+                send_attachment(context, AttachmentKind::Image, data.len() as u64, transfer_id, Cursor::new(data)).await?;
+                context.console.print(t!("sticker-sent", name = name.as_str()));
+                Ok(false)
+            },
+            Self::Stickers => {
+                let names = context.stickers.names()
+                    .map_err(ClientError::FileOperationFailed)?;
+                match names.is_empty() {
+                    true => context.console.print(t!("no-stickers")),
+                    false => context.console.print(t!("stickers", count = names.len(), names = names.join(", "))),
+                }
                 Ok(false)
             },
-            Self::Image(filename) => {
-                let data = read_image_data(filename)
-                    .map_err(|e| ClientError::FileOperationFailed(e))?;
-                let content = ChatMessageContent::Image(data);
-                send_message(context, content).await?;
-                println!("Image sent.");
+            Self::Voice(filename) => {
+                let (format, data) = read_voice_note(filename).await
+                    .map_err(ClientError::FileOperationFailed)?;
+                send_message(context, ChatMessageContent::Audio { format, data }, None).await?;
+                context.console.print(t!("voice-note-sent"));
                 Ok(false)
             },
             Self::File(filename) => {
-                let data = read_file_data(filename)
-                    .map_err(|e| ClientError::FileOperationFailed(e))?;
-                let content = ChatMessageContent::File(basename(filename), data);
-                send_message(context, content).await?;
-                println!("File {} sent.", basename(filename));
+                let file = tokio::fs::File::open(filename).await
+                    .with_context(|| t!("could-not-open-file", filename = filename.as_str()))
+                    .map_err(ClientError::FileOperationFailed)?;
+                let metadata = file.metadata().await
+                    .with_context(|| t!("could-not-read-metadata", filename = filename.as_str()))
+                    .map_err(ClientError::FileOperationFailed)?;
+                let size = metadata.len();
+                // The same unchanged file gets the same ID, so an interrupted upload continues where it stopped
+                let path = std::fs::canonicalize(filename).unwrap_or_else(|_| PathBuf::from(filename));
+                let modified = metadata.modified().ok()
+                    .and_then(|modified| modified.duration_since(std::time::UNIX_EPOCH).ok())
+                    .unwrap_or_default();
+                let transfer_id = upload_id(&[path.as_os_str().as_encoded_bytes(), &size.to_le_bytes(), &modified.as_nanos().to_le_bytes()]);
+                send_attachment(context, AttachmentKind::File(basename(filename)), size, transfer_id, file).await?;
+                context.console.print(t!("file-sent", filename = basename(filename)));
+                Ok(false)
+            },
+            Self::Switch(None) => {
+                let accounts = context.sessions.iter()
+                    .map(|(name, session)| match *name == context.active {
+                        true => t!("account-active", name = name.as_str(), username = session.username.as_str()),
+                        false => t!("account", name = name.as_str(), username = session.username.as_str()),
+                    })
+                    .collect::<Vec<_>>();
+                context.console.print(t!("accounts", accounts = accounts.join(", ")));
+                Ok(false)
+            },
+            Self::Switch(Some(name)) => {
+                match context.sessions.contains_key(name) {
+                    true => {
+                        context.active = name.clone();
+                        context.console.print(t!("switched-account", identity = context.identity()));
+                    },
+                    false => context.console.error(t!("no-such-account", name = name.as_str())),
+                }
                 Ok(false)
             },
             Self::Quit => {
-                println!("Ok, bye.");
+                context.console.print(t!("bye"));
                 Ok(true)
             }
         }
@@ -236,22 +1676,21 @@ impl UserCommand {
 ///
 /// * `EmptyResult` - Returns an empty result if successful.
 async fn keyboard_loop(context: &mut ChatContext) -> EmptyResult {
-    println!("Ok, connected to server.");
-    println!("Your name is {}", context.username);
+    context.console.print(t!("connected"));
+    context.console.print(t!("your-name", identity = context.identity()));
+    let mut lines = client_input::spawn_line_reader(context.console.clone(), context.known_users.clone());
     loop {
-        let mut buf = String::new();
-        let len = std::io::stdin().read_line(&mut buf)
-            .context("Can't read from stdin.")?;
-        
-        if len > 0 {
-            let cmd = UserCommand::from_str(buf.trim());
+        if let Some(line) = lines.recv().await {
+            let cmd = UserCommand::from_str(line.trim());
 
             match cmd.perform(context).await {
                 Err(e) => {
-                    // If there was a problem with file handling, print it, otherwise terminate the loop
-                    if matches!(e.downcast_ref::<ClientError>(), Some(ClientError::FileOperationFailed(_))) {
-                        eprintln!("Error: {e}"); 
-                        eprint!("{}", e.root_cause());
+                    // If there was a problem with file handling or the client is offline, print it, otherwise terminate the loop
+                    if context.is_recoverable(&e) {
+                        context.console.error(t!("error", error = e.to_string()));
+                        if e.chain().count() > 1 {
+                            context.console.error(format!("{}", e.root_cause()));
+                        }
                     } else {
                         return Err(e); 
                     }
@@ -265,107 +1704,575 @@ async fn keyboard_loop(context: &mut ChatContext) -> EmptyResult {
     }
 }
 
+/// Runs the commands of the headless mode, one per line, and waits until the server acknowledges all sent messages.
+/// Any failure, including the errors reported by the server, makes the client exit with a non-zero status.
+///
+/// # Arguments
+///
+/// * `context` - The chat context.
+/// * `script` - Where the commands come from.
+/// * `ack_timeout` - How long to wait for the acknowledgement of the last message.
+///
+/// # Returns
+///
+/// * `EmptyResult` - Returns an empty result if all commands succeeded.
+async fn run_script(context: &mut ChatContext, script: Script, ack_timeout: Duration) -> EmptyResult {
+    let reader: Box<dyn AsyncRead + Send + Unpin> = match script {
+        Script::Oneshot(line) => Box::new(Cursor::new(line.into_bytes())),
+        Script::File(path) if path.as_os_str() == "-" => Box::new(tokio::io::stdin()),
+        Script::File(path) => Box::new(tokio::fs::File::open(&path).await
+            .with_context(|| t!("could-not-open-script", path = path.display().to_string()))?),
+    };
+
+    let mut lines = tokio::io::BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await.context(t!("could-not-read-script"))? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match UserCommand::from_str(line.trim()).perform(context).await {
+            Ok(true) => break,
+            Ok(false) => {},
+            Err(e) => {
+                if matches!(e.downcast_ref::<ClientError>(), Some(ClientError::FileOperationFailed(_))) {
+                    context.console.error(format!("{}", e.root_cause()));
+                }
+                return Err(e);
+            }
+        }
+    }
+
+    let unacknowledged = wait_for_acks(&context.session().pending_acks, ack_timeout).await;
+    if unacknowledged > 0 {
+        Err(anyhow!(t!("messages-not-acknowledged", count = unacknowledged, seconds = ack_timeout.as_secs())))?
+    }
+    if context.console.error_count() > 0 {
+        Err(anyhow!(t!("server-reported-errors")))?
+    }
+    Ok(())
+}
+
+/// Waits until the server acknowledges all sent messages, or until the oldest pending one times out.
+///
+/// # Arguments
+///
+/// * `pending_acks` - Messages waiting for an acknowledgement from the server.
+/// * `timeout` - How long to wait for an acknowledgement.
+///
+/// # Returns
+///
+/// * `usize` - Returns the number of messages which were not acknowledged in time.
+async fn wait_for_acks(pending_acks: &PendingAcks, timeout: Duration) -> usize {
+    let mut interval = tokio::time::interval(Duration::from_millis(50));
+    loop {
+        interval.tick().await;
+        let pending = pending_acks.lock().unwrap();
+        if pending.values().all(|sent_at| sent_at.elapsed() >= timeout) {
+            return pending.len();
+        }
+    }
+}
+
 /// Sends a chat message.
 ///
 /// # Arguments
 ///
 /// * `context` - The chat context.
 /// * `content` - The content of the chat message.
+/// * `reply_to` - The message this one replies to, `None` if it isn't a reply.
+///
+/// # Returns
+///
+/// * `EmptyResult` - Returns an empty result if successful.
+async fn send_message(context: &mut ChatContext, content: ChatMessageContent, reply_to: Option<ReplyTo>) -> EmptyResult {
+    let message = ChatMessage { reply_to, ..context.new_message(content) };
+    if let ChatMessageContent::Text(text) = &message.content {
+        context.session().recent.lock().unwrap().add_own(&message.sender, message.id, false, text);
+    }
+
+    context.deliver(Datagram::Message(message.clone())).await;
+    context.remember(&message, None).await;
+    Ok(())
+}
+
+/// Derives the transfer ID of an upload from what identifies the uploaded data.
+///
+/// # Arguments
+///
+/// * `parts` - The parts identifying the data, e.g. the path, size and modification time of a file.
+///
+/// # Returns
+///
+/// * `TransferId` - Returns the transfer ID.
+fn upload_id(parts: &[&[u8]]) -> TransferId {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update((part.len() as u64).to_le_bytes());
+        hasher.update(part);
+    }
+    let digest = hasher.finalize();
+    TransferId::from_le_bytes(digest[..8].try_into().unwrap())
+}
+
+/// Streams an attachment to the server in chunks of `FILE_CHUNK_SIZE` bytes, only the last one may be shorter.
+/// Every chunk is written before the next one is read, so memory usage stays bounded
+/// and a slow connection throttles reading of the file. If the server has a part of the attachment
+/// from an interrupted upload with the same transfer ID, only the rest is sent.
+///
+/// # Arguments
+///
+/// * `context` - The chat context.
+/// * `kind` - The type of the attachment.
+/// * `size` - The size of the attachment in bytes.
+/// * `transfer_id` - ID of the transfer, the same for every upload of the same data.
+/// * `reader` - The source of the attachment data.
 ///
 /// # Returns
 ///
 /// * `EmptyResult` - Returns an empty result if successful.
-async fn send_message(context: &mut ChatContext, content: ChatMessageContent) -> EmptyResult {
-    let nickname = context.username.to_string();
-    let message = ChatMessage {
-        sender: nickname,
-        content,
+async fn send_attachment<R: AsyncRead + AsyncSeek + Unpin>(context: &mut ChatContext, kind: AttachmentKind, size: u64, transfer_id: TransferId, mut reader: R) -> EmptyResult {
+    let id = context.allocate_message_id();
+
+    let (accepted, next_seq) = oneshot::channel();
+    context.session().pending_uploads.lock().unwrap().insert(transfer_id, accepted);
+    let begin = Datagram::FileBegin { transfer_id, id, sender: context.session().username.to_string(), kind: kind.clone(), size };
+    context.send(&begin).await
+        .context(t!("transfer-start-failed"))?;
+    let Ok(next_seq) = next_seq.await else {
+        Err(ClientError::FileOperationFailed(anyhow!(t!("transfer-refused"))))?
+    };
+
+    let label = match &kind {
+        AttachmentKind::Image => t!("sending-image-progress"),
+        AttachmentKind::File(filename) => t!("sending-file-progress", filename = filename.as_str()),
+        AttachmentKind::Audio(_) => t!("sending-voice-note-progress"),
     };
+    let mut progress = TransferProgress::new(label, size);
+    let console = context.console.clone();
+
+    let mut seq = next_seq;
+    if seq > 0 {
+        let offset = seq * FILE_CHUNK_SIZE as u64;
+        reader.seek(SeekFrom::Start(offset)).await
+            .context(t!("could-not-seek"))
+            .map_err(ClientError::FileOperationFailed)?;
+        progress.advance(offset);
+        console.print(t!("resuming-upload", offset = format_size(offset)));
+    }
+    loop {
+        let mut data = Vec::with_capacity(FILE_CHUNK_SIZE);
+        let len = match (&mut reader).take(FILE_CHUNK_SIZE as u64).read_to_end(&mut data).await {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(e) => {
+                console.progress(None);
+                context.send(&Datagram::FileAbort { transfer_id }).await
+                    .context(t!("transfer-cancel-failed"))?;
+                return Err(ClientError::FileOperationFailed(Error::new(e).context(t!("could-not-read-file"))))?;
+            }
+        };
+
+        context.send(&Datagram::FileChunk { transfer_id, seq, data }).await
+            .inspect_err(|_| console.progress(None))
+            .context(t!("chunk-send-failed"))?;
+        seq += 1;
+        if let Some(line) = progress.advance(len as u64) {
+            console.progress(Some(line));
+        }
+    }
+    console.progress(None);
+
+    context.expect_ack(id);
+    context.send(&Datagram::FileEnd { transfer_id }).await
+        .context(t!("transfer-finish-failed"))?;
+
+    let session = context.session();
+    if let Err(e) = session.history.record_attachment(&session.username, &kind).await {
+        context.console.error(t!("error", error = e.to_string()));
+    }
+    Ok(())
+}
+
+/// Sends a private chat message to a single user.
+///
+/// # Arguments
+///
+/// * `context` - The chat context.
+/// * `to` - The username of the recipient.
+/// * `content` - The content of the chat message.
+///
+/// # Returns
+///
+/// * `EmptyResult` - Returns an empty result if successful.
+async fn send_direct_message(context: &mut ChatContext, to: &str, content: ChatMessageContent) -> EmptyResult {
+    let message = context.new_message(content);
 
-    Datagram::Message(message).write_to_stream(&mut context.write_half).await
-        .context("Failed to send a message.")?;
+    context.deliver(Datagram::DirectMessage { to: to.to_string(), message: message.clone() }).await;
+    context.remember(&message, Some(to)).await;
     Ok(())
 }
 
-/// Reads image data from a file. The file is converted to PNG if needed.
+/// Reads image data from a file. The image is converted to PNG unless it already is one, or it's a JPEG or WebP
+/// and the original format should be kept. The conversion runs on a blocking thread, so that the client
+/// keeps receiving messages while a large picture is being converted.
 ///
 /// # Arguments
 ///
 /// * `filename` - The name of the file.
+/// * `keep_format` - Whether JPEG and WebP images are sent as they are.
 ///
 /// # Returns
 ///
-/// * `Result<Vec<u8>>` - Returns the image data if successful.
-fn read_image_data(filename: &str) -> Result<Vec<u8>> {
-    let extension = Path::new(filename).extension().and_then(OsStr::to_str);
-    match extension {
-        Some("png") | Some("PNG") => {
-            read_file_data(filename)
-        },
-        _ => {
-            let img = ImageReader::open(filename)
-                .with_context(|| format!("Could not open {filename} for image conversion."))
-                ?.decode()
-                .with_context(|| format!("Could not decode {filename}."))?;
-            let mut data = Vec::<u8>::new();
-            img.write_to(&mut Cursor::new(&mut data), image::ImageFormat::Png)
-                .with_context(|| format!("Could not encode {filename}"))?;
-            Ok(data)
+/// * `Result<(Vec<u8>, Option<Duration>)>` - Returns the image data and how long the conversion took, `None` if the image wasn't converted.
+async fn read_image_data(filename: &str, keep_format: bool) -> Result<(Vec<u8>, Option<Duration>)> {
+    let data = tokio::fs::read(filename).await
+        .with_context(|| t!("could-not-open-file", filename = filename))?;
+    let format = image::guess_format(&data)
+        .with_context(|| t!("unknown-image-format", filename = filename))?;
+    if format == ImageFormat::Png || (keep_format && KEPT_IMAGE_FORMATS.contains(&format)) {
+        return Ok((data, None));
+    }
+
+    let started = Instant::now();
+    let filename = filename.to_string();
+    let png = tokio::task::spawn_blocking(move || -> Result<Vec<u8>> {
+        let img = image::load_from_memory_with_format(&data, format)
+            .with_context(|| t!("could-not-decode", filename = filename.as_str()))?;
+        let mut png = Vec::<u8>::new();
+        img.write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .with_context(|| t!("could-not-encode", filename = filename.as_str()))?;
+        Ok(png)
+    }).await??;
+    Ok((png, Some(started.elapsed())))
+}
+
+/// Reads an avatar from an image file. The image is shrunk to fit `MAX_AVATAR_DIMENSION` pixels a side
+/// and encoded as PNG on a blocking thread.
+///
+/// # Arguments
+///
+/// * `filename` - The path to the image file.
+///
+/// # Returns
+///
+/// * `Result<Vec<u8>>` - Returns the PNG data, or an error if it isn't an image or still too large.
+async fn read_avatar(filename: &str) -> Result<Vec<u8>> {
+    let data = tokio::fs::read(filename).await
+        .with_context(|| t!("could-not-open-file", filename = filename))?;
+    let filename = filename.to_string();
+    let png = tokio::task::spawn_blocking(move || -> Result<Vec<u8>> {
+        let mut img = image::load_from_memory(&data)
+            .with_context(|| t!("could-not-decode", filename = filename.as_str()))?;
+        if img.width() > MAX_AVATAR_DIMENSION || img.height() > MAX_AVATAR_DIMENSION {
+            img = img.thumbnail(MAX_AVATAR_DIMENSION, MAX_AVATAR_DIMENSION);
         }
+        let mut png = Vec::<u8>::new();
+        img.write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .with_context(|| t!("could-not-encode", filename = filename.as_str()))?;
+        Ok(png)
+    }).await??;
+    if png.len() > MAX_AVATAR_SIZE {
+        Err(anyhow!(t!("avatar-too-large", max = format_size(MAX_AVATAR_SIZE as u64))))?
     }
+    Ok(png)
 }
 
-/// Reads file data to a vector.
+/// Reads a voice note from an audio file. The format is told by the extension of the file, the server checks
+/// that the content matches it.
 ///
 /// # Arguments
 ///
-/// * `filename` - The name of the file.
+/// * `filename` - The path to the audio file.
 ///
 /// # Returns
 ///
-/// * `Result<Vec<u8>>` - Returns the file data if successful.
-fn read_file_data(filename: &str) -> Result<Vec<u8>> {
-    let mut file = File::open(filename)
-        .with_context(|| format!("Could not open file {filename}."))?;
-    let mut buf = Vec::<u8>::new();
-    file.read_to_end(&mut buf)
-        .with_context(|| format!("Could not read file {filename}."))?;
-    Ok(buf)
+/// * `Result<(AudioFormat, Vec<u8>)>` - Returns the format and the audio data.
+async fn read_voice_note(filename: &str) -> Result<(AudioFormat, Vec<u8>)> {
+    let extension = Path::new(filename).extension().unwrap_or_default().to_string_lossy();
+    let Some(format) = AudioFormat::from_extension(&extension) else {
+        let supported: Vec<String> = AudioFormat::ALL.iter().map(|format| format!(".{}", format.extension())).collect();
+        Err(anyhow!(t!("voice-note-formats", formats = supported.join(", "))))?
+    };
+    let data = tokio::fs::read(filename).await
+        .with_context(|| t!("could-not-read-named-file", filename = filename))?;
+    if data.len() > MAX_VOICE_NOTE_SIZE {
+        Err(anyhow!(t!("voice-note-too-large", filename = basename(filename), size = format_size(data.len() as u64), limit = format_size(MAX_VOICE_NOTE_SIZE as u64))))?
+    }
+    Ok((format, data))
+}
+
+/// Settings of the client which don't identify the server or the user.
+struct ClientConfig {
+    /// How long to wait for the server to acknowledge a message
+    ack_timeout: Duration,
+    /// How long the server may take to accept a datagram before the client logs in again, `None` waits forever
+    write_timeout: Option<Duration>,
+    /// Wire format of datagrams, must match the server
+    codec: CodecKind,
+    /// Whether to offer the server to compress large frames
+    compression: bool,
+    /// Whether to run the terminal user interface instead of the plain line mode
+    tui: bool,
+    /// SQLite file where the sent and received messages are stored
+    history_file: String,
+    /// Directory where received images and files are saved
+    download_dir: PathBuf,
+    /// What to do when a received file has the same name as an existing one
+    overwrite: OverwritePolicy,
+    /// Whether to show desktop notifications for mentions and direct messages
+    notify: bool,
+    /// When to use colors in the output
+    color: ColorMode,
+    /// Colors of the output
+    theme: Theme,
+    /// Whether to render the Markdown of received messages
+    markdown: bool,
+    /// Whether to print one line per message without colors or progress, for screen readers and log scrapers
+    plain_output: bool,
+    /// Format of the output of the line mode
+    output: OutputFormat,
+    /// Whether JPEG and WebP images are sent without converting them to PNG
+    keep_image_format: bool,
+    /// Sticker directory, read from the configuration file
+    stickers: StickerConfig,
+    /// Muted senders and do not disturb, read from the configuration file
+    filters: NotificationFilters,
+    /// Commands run for the received messages, read from the configuration file
+    hooks: Vec<Hook>,
+    /// Configuration file where the .mute and .dnd commands save the filters
+    config_file: Option<PathBuf>,
+    /// Commands of the headless mode, `None` for the interactive modes
+    script: Option<Script>,
+    /// Where the daemon mode writes its output and listens for attached clients, `None` for the other modes
+    daemon: Option<DaemonConfig>,
 }
 
-/// Main function of the client. Connects to the server and starts the keyboard loop
-/// which reads text commands.
+/// Commands run by the headless mode instead of the ones typed by the user.
+enum Script {
+    /// A single line given on the command line
+    Oneshot(String),
+    /// Lines read from a file, `-` for stdin
+    File(PathBuf),
+}
+
+/// Connects to the server, through a Unix socket if its path is given, over TCP otherwise.
 ///
 /// # Arguments
 ///
 /// * `address` - The address of the server.
 /// * `port` - The port of the server.
-/// * `username` - The username of the client.
-/// * `password` - The password of the client.
+/// * `unix_socket` - The path of the Unix socket of the server.
 ///
 /// # Returns
 ///
-/// * `EmptyResult` - Returns an empty result if successful.
-async fn start_client(address: &str, port: u16, username: String, password: String) -> EmptyResult {
-    let stream = TcpStream::connect((address, port)).await
-        .with_context(|| format!("Could not connect to {address}:{port}"))?;
-    let (mut read_half, mut write_half) = stream.into_split();
+/// * `Result<(ReadHalf, WriteHalf)>` - Returns both halves of the connection if successful.
+async fn connect(address: &str, port: u16, unix_socket: Option<&Path>) -> Result<(ReadHalf, WriteHalf)> {
+    Ok(match unix_socket {
+        Some(path) => chat::client::open_unix(path).await?,
+        None => chat::client::open_tcp(address, port).await?,
+    })
+}
+
+/// Registers a new user on the server, asking for the verification code which the operator of the server passes on.
+///
+/// # Arguments
+///
+/// * `address` - The address of the server.
+/// * `port` - The port of the server.
+/// * `unix_socket` - The path of the Unix socket of the server, used instead of the address and port.
+/// * `username` - The username of the new user.
+/// * `password` - The password of the new user.
+/// * `codec` - The codec used by the server.
+///
+/// # Returns
+///
+/// * `EmptyResult` - Returns an empty result if the user was registered.
+async fn register(address: &str, port: u16, unix_socket: Option<&Path>, username: &str, password: &str, codec: CodecKind) -> EmptyResult {
+    let (mut read_half, mut write_half) = connect(address, port, unix_socket).await?;
+    status(t!("registering", username = username));
+    let code = || rpassword::prompt_password(format!("{} ", t!("verification-code-prompt"))).inspect_err(|e| eprintln!("{}", t!("could-not-read-code", error = e.to_string()))).ok();
+    chat::client::register(&mut read_half, &mut write_half, username, password, code, codec).await?;
+    status(t!("registered", username = username));
+    Ok(())
+}
+
+/// How to reach and log in to one account.
+struct Account {
+    /// Name of the profile, shown before the output of the account when the client is logged in to several
+    name: String,
+    address: String,
+    port: u16,
+    /// Unix socket of the server, used instead of the address and port
+    unix_socket: Option<PathBuf>,
+    credentials: Credentials,
+    /// Whether the password is saved in the keyring or removed from it, depending on the login
+    keyring: KeyringUse,
+}
+
+/// Logs in to an account and starts receiving from its server.
+///
+/// # Arguments
+///
+/// * `account` - The account to log in to.
+/// * `config` - Other settings of the client.
+/// * `console` - Where the output of the account goes.
+/// * `known_users` - Usernames offered by the tab completion, filled in from the received datagrams.
+/// * `filters` - Notification filters shared by all accounts.
+///
+/// # Returns
+///
+/// * `Result<Session>` - Returns the session of the account if the login succeeded.
+async fn open_session(account: Account, config: &ClientConfig, console: Console, known_users: KnownUsers, filters: SharedFilters) -> Result<Session> {
+    let Account { address, port, unix_socket, credentials, keyring, .. } = account;
+    let (mut read_half, mut write_half) = connect(&address, port, unix_socket.as_deref()).await?;
 
     // Authenticate
-    println!("Waiting for login...");
-    let login_datagram = Datagram::Login { username: username.clone(), password };
-    login_datagram.write_to_stream(&mut write_half).await?;
+    status(t!("waiting-for-login"));
+    // The code is read from the terminal even when a script comes from stdin
+    let totp_code = || rpassword::prompt_password(format!("{} ", t!("authentication-code-prompt"))).inspect_err(|e| eprintln!("{}", t!("could-not-read-code", error = e.to_string()))).ok();
+    let login = credentials.log_in(&mut read_half, &mut write_half, totp_code, config.codec, config.compression, None).await;
+    let codec = match (login, credentials.password()) {
+        (Err(LoginError::LoginFailed), Some(password)) => {
+            keyring.update(password, false);
+            Err(LoginError::LoginFailed)?
+        },
+        (Err(LoginError::AlreadyLoggedIn), None) => anyhow::bail!(t!("guest-name-taken")),
+        (login, _) => login?,
+    };
+
+    status(t!("login-successful"));
+    if let Some(password) = credentials.password() {
+        keyring.update(password, true);
+    }
+    let username = credentials.username();
+    let history = History::open(&config.history_file, &username).await?;
+    let pending_acks = PendingAcks::default();
+    let write_half = ServerWriter::new(write_half, config.write_timeout);
+    let recent = SharedRecent::default();
+    // Partial downloads are kept per server, as each one numbers its attachments
+    let server = match &unix_socket {
+        Some(path) => path.display().to_string(),
+        None => format!("{address}:{port}"),
+    };
+    let downloads = Downloads::new(&config.download_dir, config.overwrite, &server);
+    let pending_uploads = PendingUploads::default();
+    let incoming_context = IncomingContext {
+        username: username.clone(),
+        notify: config.notify,
+        console: console.clone(),
+        history: history.clone(),
+        known_users,
+        downloads: downloads.clone(),
+        pending_uploads: pending_uploads.clone(),
+        last_seen: LastSeen::default(),
+        last_received: LastReceived::default(),
+        recent: recent.clone(),
+        filters,
+        hooks: Hooks::new(config.hooks.clone(), console.clone()),
+    };
+    let last_seen = incoming_context.last_seen.clone();
+    // The headless mode fails when the connection breaks, the interactive modes log in again
+    let login = config.script.is_none().then_some(LoginDetails {
+        address,
+        port,
+        unix_socket,
+        credentials,
+        codec: config.codec,
+        compression: config.compression,
+    });
+    let outbox = SharedOutbox::default();
+    let incoming_outbox = outbox.clone();
+    let incoming_write_half = write_half.clone();
+    let incoming_acks = pending_acks.clone();
+    tokio::spawn(async move {
+        connection_loop(read_half, incoming_write_half, incoming_acks, codec, incoming_context, incoming_outbox, login).await
+    });
+
+    Ok(Session { write_half, username, next_message_id: 1, pending_acks, codec, console, history, recent, downloads, pending_uploads, outbox, last_seen })
+}
+
+/// Main function of the client. Logs in to the accounts and starts the keyboard loop
+/// which reads text commands, the terminal user interface or the daemon mode.
+///
+/// # Arguments
+///
+/// * `accounts` - The accounts to log in to, the first one is active.
+/// * `config` - Other settings of the client.
+///
+/// # Returns
+///
+/// * `EmptyResult` - Returns an empty result if successful.
+async fn start_client(accounts: Vec<Account>, config: ClientConfig) -> EmptyResult {
+    if accounts.len() > 1 && config.script.is_some() {
+        anyhow::bail!(t!("script-single-account"));
+    }
+    // A daemon which is already running is found before the user logs in
+    let daemon = match &config.daemon {
+        Some(daemon) => Some((ControlSocket::bind(&daemon.socket).await?, daemon.log_file.clone())),
+        None => None,
+    };
+    let (console, console_events) = if config.tui || daemon.is_some() {
+        let (console, events) = Console::channel(config.theme.clone(), config.color, config.markdown);
+        (console, Some(events))
+    } else {
+        let console = Console::plain(config.theme.clone(), config.color, config.markdown)
+            .with_plain_output(config.plain_output)
+            .with_output(config.output);
+        (console, None)
+    };
+
+    let known_users = KnownUsers::default();
+    let filters = SharedFilters::new(Mutex::new(config.filters.clone()));
+    let several = accounts.len() > 1;
+    let active = accounts[0].name.clone();
+    let mut sessions = BTreeMap::new();
+    for account in accounts {
+        let session_console = match several {
+            true => {
+                status(t!("logging-in-to", name = account.name.as_str()));
+                console.for_account(&account.name)
+            },
+            false => console.clone(),
+        };
+        let name = account.name.clone();
+        let session = open_session(account, &config, session_console, known_users.clone(), filters.clone()).await?;
+        sessions.insert(name, session);
+    }
+    if let Some((control, log_file)) = &daemon {
+        println!("{}", t!("daemon-started", log_file = log_file.display().to_string(), socket = control.path().display().to_string()));
+    }
+
+    let mut context = ChatContext { sessions, active, console, known_users, keep_image_format: config.keep_image_format,
+                                   stickers: Stickers::new(&config.stickers), filters, config_file: config.config_file };
+    if let Some(script) = config.script {
+        // The headless mode waits for the acknowledgements itself
+        return run_script(&mut context, script, config.ack_timeout).await;
+    }
+
+    for session in context.sessions.values() {
+        // The list of online users fills in the usernames offered by the tab completion, see `.who`
+        for datagram in [Datagram::ListStatuses, Datagram::ListUsers] {
+            session.write_half.write(&datagram, &session.codec).await
+                .context(t!("request-users-failed"))?;
+        }
 
-    if let Datagram::ServerResponse(ServerResponse::LoginOk) = Datagram::read_from_stream(&mut read_half).await? {
-        println!("Login successful.");
+        let watchdog_acks = session.pending_acks.clone();
+        let watchdog_console = session.console.clone();
         tokio::spawn(async move {
-            incoming_loop(read_half).await
+            ack_watchdog(watchdog_acks, config.ack_timeout, watchdog_console).await
         });
-    
-        let mut context = ChatContext { write_half, username };
-        return keyboard_loop(&mut context).await;
-    } else {
-        Err(ClientError::LoginFailed)?
+
+        // Scripts don't show messages, so only the interactive modes report them as read
+        let reporter_write_half = session.write_half.clone();
+        let (last_seen, codec) = (session.last_seen.clone(), session.codec);
+        tokio::spawn(async move {
+            read_reporter(reporter_write_half, last_seen, codec).await
+        });
+    }
+
+    match (console_events, daemon) {
+        (Some(events), Some((control, log_file))) => client_daemon::run(&mut context, events, control, &log_file).await,
+        (Some(events), None) => client_tui::run(&mut context, events).await,
+        (None, _) => keyboard_loop(&mut context).await,
     }
 }
 
@@ -373,26 +2280,314 @@ async fn start_client(address: &str, port: u16, username: String, password: Stri
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
-    /// Address of the server
-    #[arg(short, long, default_value = "127.0.0.1")]
-    address: String,
-    /// Port of the server
-    #[arg(short = 'P', long, default_value_t = 11111)]
-    port: u16,
+    /// Invite link printed by `server invite`, like chat://example.com:11111?name=Team, giving the server instead of a profile
+    #[arg(conflicts_with_all = ["profile", "address", "port", "unix_socket"])]
+    invite: Option<Invite>,
+    /// Configuration file with connection profiles [default: ~/.config/myrustchat/config.toml]
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+    /// Connection profile from the configuration file providing the server and username, given several times the client logs in to all of them
+    #[arg(long)]
+    profile: Vec<String>,
+    /// Address of the server [default: 127.0.0.1]
+    #[arg(short, long)]
+    address: Option<String>,
+    /// Port of the server [default: 11111]
+    #[arg(short = 'P', long)]
+    port: Option<u16>,
+    /// Unix socket of the server, used instead of the address and port
+    #[arg(long)]
+    unix_socket: Option<PathBuf>,
     /// Your username
     #[arg(short)]
-    username: String,
-    /// Your password
+    username: Option<String>,
+    /// Register the user on the server first, with a verification code from the operator of the server, if the server allows it
+    #[arg(long, conflicts_with = "guest")]
+    register: bool,
+    /// Log in as a guest with this nickname, if the server allows guests, instead of with a username and password
+    #[arg(long, conflicts_with_all = ["username", "password", "save_password", "forget_password"])]
+    guest: Option<String>,
+    /// Your password, asked for if it's neither given nor saved in the profile or the keyring
     #[arg(short = 'p')]
-    password: String,
+    password: Option<String>,
+    /// Save the password in the keyring of the operating system once the login succeeds, later logins read it from there
+    #[arg(long)]
+    save_password: bool,
+    /// Remove the password saved with --save-password from the keyring before logging in
+    #[arg(long, conflicts_with = "save_password")]
+    forget_password: bool,
+    /// Seconds to wait for the server to acknowledge a message
+    #[arg(long, default_value_t = 5)]
+    ack_timeout: u64,
+    /// Seconds the server may take to accept a message before the connection is dropped, 0 waits forever
+    #[arg(long, default_value_t = chat::DEFAULT_WRITE_TIMEOUT.as_secs())]
+    write_timeout: u64,
+    /// Wire format of datagrams: cbor, json or msgpack, must match the server [default: cbor, or the one of the invite link]
+    #[arg(long)]
+    codec: Option<CodecKind>,
+    /// Never compress frames, even if the server supports it
+    #[arg(long)]
+    no_compression: bool,
+    /// Run the full-screen terminal user interface
+    #[arg(long)]
+    tui: bool,
+    /// SQLite file where the sent and received messages are stored
+    #[arg(long, default_value = "history.db")]
+    history_file: String,
+    /// Directory where received images and files are saved, in the images and files subdirectories
+    #[arg(long, default_value = ".")]
+    download_dir: PathBuf,
+    /// What to do when a received file has the same name as an existing one
+    #[arg(long, value_enum, default_value_t = OverwritePolicy::Rename)]
+    overwrite: OverwritePolicy,
+    /// Show a desktop notification when someone mentions you with @username or sends you a direct message
+    #[arg(long)]
+    notify: bool,
+    /// When to color the output
+    #[arg(long, value_enum, default_value_t = ColorMode::Auto)]
+    color: ColorMode,
+    /// Language of the messages: en or cs [default: from LANG]
+    #[arg(long, global = true)]
+    lang: Option<Lang>,
+    /// TOML file with the colors of the output
+    #[arg(long)]
+    theme: Option<PathBuf>,
+    /// Show messages as they were typed instead of rendering their Markdown
+    #[arg(long)]
+    plain: bool,
+    /// Output for screen readers and log scrapers: no colors or progress, one line per message and markers like [image] for attachments
+    #[arg(long, conflicts_with_all = ["tui", "daemon"])]
+    plain_output: bool,
+    /// Format of the output: text, or json for one JSON object per line for every message, presence change and error
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text, conflicts_with_all = ["tui", "daemon", "plain_output"])]
+    output: OutputFormat,
+    /// Send JPEG and WebP images as they are instead of converting them to PNG
+    #[arg(long)]
+    keep_image_format: bool,
+    /// Run the commands from this file, `-` for stdin, one per line, wait for the server to acknowledge them and exit
+    #[arg(long, conflicts_with_all = ["tui", "oneshot"])]
+    script: Option<PathBuf>,
+    /// Send this message or command, wait for the server to acknowledge it and exit
+    #[arg(long, conflicts_with = "tui")]
+    oneshot: Option<String>,
+    /// Stay connected in the background after logging in, even when the terminal is closed, use `client attach` to chat
+    #[arg(long, conflicts_with_all = ["tui", "script", "oneshot"])]
+    daemon: bool,
+    /// File where the daemon mode appends its output
+    #[arg(long, default_value = "client.log", requires = "daemon")]
+    log_file: PathBuf,
+    /// Control socket of the daemon mode [default: $XDG_RUNTIME_DIR/myrustchat/client.sock]
+    #[arg(long, global = true)]
+    control_socket: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Option<ClientCommand>,
+}
+
+#[derive(Subcommand, Debug)]
+enum ClientCommand {
+    /// Attach to a client running with --daemon to chat through it, Ctrl-D detaches
+    Attach,
 }
 
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
+    chat::i18n::set_lang(args.lang.unwrap_or_else(Lang::from_env));
+    client_console::set_output(args.output);
+    let control_socket = args.control_socket.clone().or_else(client_daemon::default_socket_path);
+    if (args.daemon || args.command.is_some()) && control_socket.is_none() {
+        eprintln!("{}", t!("no-default-control-socket"));
+        exit(1);
+    }
+    if let (Some(ClientCommand::Attach), Some(socket)) = (&args.command, &control_socket) {
+        exit_with(client_daemon::attach(socket).await);
+    }
+    let theme = match &args.theme {
+        Some(path) => Theme::load(path).unwrap_or_else(|e| {
+            eprintln!("{}", t!("error", error = format!("{e:#}")));
+            exit(1);
+        }),
+        None => Theme::default(),
+    };
+
+    let profile_path = args.config.clone().or_else(|| ProfileFile::default_path().filter(|path| path.exists()));
+    let profiles = match &profile_path {
+        Some(path) => ProfileFile::load(path).unwrap_or_else(|e| {
+            eprintln!("{}", t!("error", error = format!("{e:#}")));
+            exit(1);
+        }),
+        None => ProfileFile::default(),
+    };
+    // Several profiles log in to several accounts at once, the connection flags apply to a single one
+    let several = args.profile.len() > 1;
+    if several && (args.address.is_some() || args.port.is_some() || args.unix_socket.is_some() || args.username.is_some()
+                   || args.password.is_some() || args.guest.is_some() || args.register) {
+        eprintln!("{}", t!("several-profiles-flags"));
+        exit(1);
+    }
+    if let Some(name) = args.profile.iter().enumerate().find_map(|(i, name)| args.profile[..i].contains(name).then_some(name)) {
+        eprintln!("{}", t!("profile-given-twice", name = name.as_str()));
+        exit(1);
+    }
+    let names = match args.profile.is_empty() {
+        true => vec![None],
+        false => args.profile.iter().map(|name| Some(name.as_str())).collect(),
+    };
+    let mut accounts = Vec::new();
+    for name in names {
+        let profile = match &args.invite {
+            // The default profile may be for another server, its password must not be sent to this one
+            Some(invite) => Profile { address: Some(invite.address.clone()), port: invite.port, username: invite.username.clone(), ..Profile::default() },
+            None => profiles.profile(name).unwrap_or_else(|e| {
+                eprintln!("{}", t!("error", error = e.to_string()));
+                exit(1);
+            }),
+        };
+        // Command line flags override the values from the profile
+        let profile = Profile {
+            address: args.address.clone().or(profile.address),
+            port: args.port.or(profile.port),
+            unix_socket: args.unix_socket.clone().or(profile.unix_socket),
+            username: args.username.clone().or(profile.username),
+            password: profile.password,
+        };
+        let name = args.invite.as_ref().and_then(|invite| invite.name.as_deref()).or(name).unwrap_or("default").to_string();
+        accounts.push(resolve_account(name, profile, args.password.clone(), args.guest.clone(), args.save_password, args.forget_password, profile_path.as_deref()));
+    }
+
+    let config = ClientConfig {
+        ack_timeout: Duration::from_secs(args.ack_timeout),
+        write_timeout: (args.write_timeout > 0).then(|| Duration::from_secs(args.write_timeout)),
+        codec: args.codec.or(args.invite.as_ref().and_then(|invite| invite.codec)).unwrap_or_default(),
+        compression: !args.no_compression,
+        tui: args.tui,
+        history_file: args.history_file,
+        download_dir: args.download_dir,
+        overwrite: args.overwrite,
+        notify: args.notify,
+        // The plain output has neither colors nor rendered Markdown
+        color: if args.plain_output { ColorMode::Never } else { args.color },
+        theme,
+        markdown: !args.plain && !args.plain_output,
+        plain_output: args.plain_output,
+        output: args.output,
+        keep_image_format: args.keep_image_format,
+        stickers: profiles.stickers().clone(),
+        filters: profiles.notifications().clone(),
+        hooks: profiles.hooks().to_vec(),
+        // The filters are saved even if there is no configuration file yet
+        config_file: args.config.clone().or_else(ProfileFile::default_path),
+        script: args.oneshot.map(Script::Oneshot).or(args.script.map(Script::File)),
+        daemon: control_socket.filter(|_| args.daemon).map(|socket| DaemonConfig { socket, log_file: args.log_file }),
+    };
+
+    if let (true, [account]) = (args.register, accounts.as_slice()) {
+        if let Credentials::User { username, password } = &account.credentials {
+            if let Err(e) = register(&account.address, account.port, account.unix_socket.as_deref(), username, password, config.codec).await {
+                eprintln!("{}", t!("error", error = e.to_string()));
+                exit(1);
+            }
+        }
+    }
 
-    if let Err(e) = start_client(&args.address, args.port, args.username, args.password).await {
-        eprintln!("Error: {e}");
+    if let (Some(name), None) = (args.invite.as_ref().and_then(|invite| invite.name.as_ref()), &config.script) {
+        status(t!("joining", name = name.as_str()));
+    }
+    exit_with(start_client(accounts, config).await);
+}
+
+/// Works out how to log in to an account, asking for the password if it's neither given nor saved.
+/// The client exits if the account can't be used.
+///
+/// # Arguments
+///
+/// * `name` - The name of the account.
+/// * `profile` - The profile of the account, with the command line flags applied except for the password.
+/// * `password` - The password given on the command line.
+/// * `guest` - The nickname to log in with as a guest, instead of the username and password.
+/// * `save_password` - Whether to save the password in the keyring once the login succeeds.
+/// * `forget_password` - Whether to remove the saved password from the keyring first.
+/// * `profile_path` - The configuration file the profile comes from.
+///
+/// # Returns
+///
+/// * `Account` - Returns the account.
+fn resolve_account(name: String, profile: Profile, password: Option<String>, guest: Option<String>, save_password: bool, forget_password: bool, profile_path: Option<&Path>) -> Account {
+    let address = profile.address.unwrap_or_else(|| DEFAULT_ADDRESS.to_string());
+    let port = profile.port.unwrap_or(DEFAULT_PORT);
+    let unix_socket = profile.unix_socket;
+
+    // Guests have no password to look up
+    if let Some(nickname) = guest {
+        return Account { name, address, port, unix_socket, credentials: Credentials::Guest { nickname }, keyring: KeyringUse::None };
+    }
+
+    let Some(username) = profile.username else {
+        eprintln!("{}", t!("username-required"));
+        exit(1);
+    };
+
+    // The keyring is opened only when it's needed, as it may ask the user to unlock it
+    let server = unix_socket.as_ref().map_or_else(|| format!("{address}:{port}"), |path| path.display().to_string());
+    let use_keyring = save_password || forget_password || (password.is_none() && profile.password.is_none());
+    let saved = match use_keyring.then(|| SavedPassword::new(&username, &server)) {
+        Some(Ok(saved)) => Some(saved),
+        Some(Err(e)) => {
+            // Without the flags the keyring is only tried, a missing one isn't worth a warning
+            if save_password || forget_password {
+                eprintln!("{}", t!("keyring-unavailable", error = e.to_string()));
+            }
+            None
+        },
+        None => None,
+    };
+    if let (true, Some(saved)) = (forget_password, &saved) {
+        match saved.delete() {
+            Ok(()) => status(t!("keyring-password-removed")),
+            Err(e) => eprintln!("{}", t!("keyring-remove-failed", error = e.to_string())),
+        }
+    }
+
+    let mut from_keyring = false;
+    let password = match (password, profile.password) {
+        (Some(password), _) => password,
+        (None, Some(password)) => {
+            if let Some(path) = profile_path.filter(|path| client_profiles::readable_by_others(path)) {
+                eprintln!("{}", t!("password-file-readable", path = path.display().to_string()));
+            }
+            password
+        },
+        (None, None) => match saved.as_ref().and_then(|saved| saved.get().unwrap_or_else(|e| {
+            eprintln!("{}", t!("keyring-read-failed", error = e.to_string()));
+            None
+        })) {
+            Some(password) => {
+                from_keyring = true;
+                password
+            },
+            None => rpassword::prompt_password(format!("{} ", t!("password-prompt", username = username.as_str()))).unwrap_or_else(|e| {
+                eprintln!("{}", t!("could-not-read-password", error = e.to_string()));
+                exit(1);
+            }),
+        },
+    };
+    let keyring = match saved {
+        Some(saved) if from_keyring => KeyringUse::Loaded(saved),
+        Some(saved) if save_password => KeyringUse::Save(saved),
+        _ => KeyringUse::None,
+    };
+
+    Account { name, address, port, unix_socket, credentials: Credentials::User { username, password }, keyring }
+}
+
+/// Exits the client once it has ended, with an error status if it failed.
+///
+/// # Arguments
+///
+/// * `result` - The result of `start_client`.
+fn exit_with(result: EmptyResult) -> ! {
+    if let Err(e) = result {
+        eprintln!("{}", t!("error", error = e.to_string()));
         exit(1);
     } else {
         exit(0);
@@ -402,7 +2597,11 @@ async fn main() {
 #[cfg(test)]
 mod tests {
 
-    use crate::{basename, UserCommand};
+    use std::time::{Duration, Instant};
+
+    use chat::{image_extension, AdminCommand, AudioFormat, MAX_VOICE_NOTE_SIZE};
+
+    use crate::{basename, display_name, mentions, read_image_data, read_voice_note, wait_for_acks, DndChange, PendingAcks, UserCommand};
 
     #[test]
     fn test_basename() {
@@ -410,6 +2609,42 @@ mod tests {
         assert_eq!(z, "c.txt");
     }
 
+    #[test]
+    fn test_mentions() {
+        assert!(mentions("@Bob hi", "Bob"));
+        assert!(mentions("hi @Bob, how are you", "Bob"));
+        assert!(mentions("@Bobby and @Bob", "Bob"));
+        assert!(!mentions("hi @Bobby", "Bob"));
+        assert!(!mentions("hi Bob", "Bob"));
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_acks() {
+        let timeout = Duration::from_millis(200);
+        let pending_acks = PendingAcks::default();
+        assert_eq!(wait_for_acks(&pending_acks, timeout).await, 0);
+
+        pending_acks.lock().unwrap().insert(1, Instant::now());
+        pending_acks.lock().unwrap().insert(2, Instant::now());
+        let acks = pending_acks.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            acks.lock().unwrap().remove(&1);
+        });
+        let start = Instant::now();
+        assert_eq!(wait_for_acks(&pending_acks, timeout).await, 1);
+        assert!(start.elapsed() >= Duration::from_millis(150));
+
+        pending_acks.lock().unwrap().clear();
+        assert_eq!(wait_for_acks(&pending_acks, timeout).await, 0);
+    }
+
+    #[test]
+    fn test_display_name() {
+        assert_eq!(display_name("Bob", None), "Bob");
+        assert_eq!(display_name("Bob", Some("Bobby")), "Bobby (Bob)");
+    }
+
     #[test]
     fn test_user_command_from_str() {
         assert!(matches!(UserCommand::from_str("this is a text"), UserCommand::Text(_)));
@@ -419,10 +2654,107 @@ mod tests {
 
         let image_command = UserCommand::Image("test.jpg".to_string());
         assert!(UserCommand::from_str(".image test.jpg")==image_command);
+        assert!(UserCommand::from_str(".voice note.opus")==UserCommand::Voice("note.opus".to_string()));
+        assert!(UserCommand::from_str(".sticker thumbsup")==UserCommand::Sticker("thumbsup".to_string()));
+        assert!(UserCommand::from_str(".announce  Server restart at 18:00 ")==UserCommand::Announce("Server restart at 18:00".to_string()));
+        assert!(UserCommand::from_str(".ephemeral 60 the code is 1234")==UserCommand::Ephemeral(60, "the code is 1234".to_string()));
+        assert!(matches!(UserCommand::from_str(".ephemeral 0 hi"), UserCommand::Text(_)));
+        assert!(matches!(UserCommand::from_str(".ephemeral soon hi"), UserCommand::Text(_)));
+        assert!(UserCommand::from_str(".announce")==UserCommand::Text(".announce".to_string()));
+        assert!(UserCommand::from_str(".stickers")==UserCommand::Stickers);
+        assert!(UserCommand::from_str(".status  at lunch ")==UserCommand::Status(Some("at lunch".to_string())));
+        assert!(UserCommand::from_str(".status")==UserCommand::Status(None));
+        assert!(UserCommand::from_str(".avatar me.jpg")==UserCommand::Avatar(Some("me.jpg".to_string())));
+        assert!(UserCommand::from_str(".avatar")==UserCommand::Avatar(None));
+        assert!(UserCommand::from_str(".profile Bob")==UserCommand::Profile("Bob".to_string()));
+        assert!(matches!(UserCommand::from_str(".profile"), UserCommand::Text(_)));
+        assert!(matches!(UserCommand::from_str(".sticker"), UserCommand::Text(_)));
 
         assert!(matches!(UserCommand::from_str(".quit  "), UserCommand::Text(_)));
         
         assert!(matches!(UserCommand::from_str(".quit"), UserCommand::Quit));
+        assert!(matches!(UserCommand::from_str(".who"), UserCommand::Who));
+        assert!(UserCommand::from_str(".seen Bob")==UserCommand::Seen("Bob".to_string()));
+        assert!(matches!(UserCommand::from_str(".seen"), UserCommand::Text(_)));
+        assert!(UserCommand::from_str(".block Bob")==UserCommand::Block("Bob".to_string()));
+        assert!(UserCommand::from_str(".unblock Bob ")==UserCommand::Unblock("Bob".to_string()));
+        assert!(matches!(UserCommand::from_str(".block"), UserCommand::Text(_)));
+        assert!(matches!(UserCommand::from_str(".blocks"), UserCommand::Blocks));
+        assert!(matches!(UserCommand::from_str(".stats"), UserCommand::Stats));
+        assert!(UserCommand::from_str(".switch")==UserCommand::Switch(None));
+        assert!(UserCommand::from_str(".switch work ")==UserCommand::Switch(Some("work".to_string())));
+        assert!(matches!(UserCommand::from_str(".switch to work"), UserCommand::Text(_)));
+        assert!(UserCommand::from_str(".mute")==UserCommand::Mute(None));
+        assert!(UserCommand::from_str(".mute Bob")==UserCommand::Mute(Some("Bob".to_string())));
+        assert!(UserCommand::from_str(".unmute Bob")==UserCommand::Unmute("Bob".to_string()));
+        assert!(matches!(UserCommand::from_str(".unmute"), UserCommand::Text(_)));
+        assert!(UserCommand::from_str(".dnd on")==UserCommand::Dnd(Some(DndChange::On)));
+        assert!(matches!(UserCommand::from_str(".dnd 22:00-07:00"), UserCommand::Dnd(Some(DndChange::Schedule(_)))));
+        assert!(matches!(UserCommand::from_str(".dnd tonight"), UserCommand::Text(_)));
+
+        let direct_command = UserCommand::Direct("Bob".to_string(), "hello there".to_string());
+        assert!(UserCommand::from_str(".msg Bob hello there")==direct_command);
+        assert!(matches!(UserCommand::from_str(".msg Bob"), UserCommand::Text(_)));
+
+        assert!(UserCommand::from_str(".reply 3 me too")==UserCommand::Reply(3, "me too".to_string()));
+        assert!(UserCommand::from_str(".reply #3 me too")==UserCommand::Reply(3, "me too".to_string()));
+        assert!(matches!(UserCommand::from_str(".reply 3"), UserCommand::Text(_)));
+        assert!(matches!(UserCommand::from_str(".reply Bob hi"), UserCommand::Text(_)));
+
+        let kick_command = UserCommand::Admin(AdminCommand::Kick("Bob".to_string()));
+        assert!(UserCommand::from_str(".kick Bob")==kick_command);
+        assert!(matches!(UserCommand::from_str(".ban"), UserCommand::Text(_)));
+
+        let passwd_command = UserCommand::ChangePassword("old".to_string(), "new".to_string());
+        assert!(UserCommand::from_str(".passwd old new")==passwd_command);
+        assert!(matches!(UserCommand::from_str(".passwd old"), UserCommand::Text(_)));
+
+        assert!(matches!(UserCommand::from_str(".history"), UserCommand::History(20)));
+        assert!(matches!(UserCommand::from_str(".history 5"), UserCommand::History(5)));
+        assert!(matches!(UserCommand::from_str(".history five"), UserCommand::Text(_)));
+
+        assert!(UserCommand::from_str(".nick Bobby")==UserCommand::Nick(Some("Bobby".to_string())));
+        assert!(UserCommand::from_str(".nick")==UserCommand::Nick(None));
+
+        assert!(UserCommand::from_str(".get 12")==UserCommand::Get(12));
+        assert!(UserCommand::from_str(".fetch 12")==UserCommand::Get(12));
+        assert!(matches!(UserCommand::from_str(".get"), UserCommand::Text(_)));
+    }
+
+    #[tokio::test]
+    async fn test_read_image_data() {
+        let dir = tempfile::tempdir().unwrap();
+        let jpeg = dir.path().join("photo.jpg");
+        image::RgbImage::new(20, 10).save(&jpeg).unwrap();
+        let jpeg = jpeg.to_str().unwrap();
+
+        let (png, conversion) = read_image_data(jpeg, false).await.unwrap();
+        assert_eq!(image_extension(&png), "png");
+        assert!(conversion.is_some());
+        let (kept, conversion) = read_image_data(jpeg, true).await.unwrap();
+        assert_eq!(image_extension(&kept), "jpg");
+        assert_eq!(kept, std::fs::read(jpeg).unwrap());
+        assert!(conversion.is_none());
+
+        // A PNG is never converted, whatever its name
+        let renamed = dir.path().join("image.dat");
+        std::fs::write(&renamed, &png).unwrap();
+        assert_eq!(read_image_data(renamed.to_str().unwrap(), false).await.unwrap(), (png, None));
+        assert!(read_image_data(dir.path().join("missing.png").to_str().unwrap(), false).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_voice_note() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+        std::fs::write(path("note.OPUS"), b"OggS").unwrap();
+        std::fs::write(path("note.flac"), b"fLaC").unwrap();
+        std::fs::write(path("long.mp3"), vec![0u8; MAX_VOICE_NOTE_SIZE + 1]).unwrap();
+
+        assert_eq!(read_voice_note(&path("note.OPUS")).await.unwrap(), (AudioFormat::Opus, b"OggS".to_vec()));
+        assert!(read_voice_note(&path("note.flac")).await.is_err());
+        assert!(read_voice_note(&path("long.mp3")).await.is_err());
+        assert!(read_voice_note(&path("missing.wav")).await.is_err());
     }
 }
 
@@ -0,0 +1,763 @@
+use std::io::{IsTerminal, Write};
+use std::process::exit;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+
+use chat::{format_size, t, AttachmentId, MessageSeq, UserStatus};
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use serde::Serialize;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use crate::client_theme::{self, ColorMode, MessageLine, Theme};
+
+/// Enum representing output produced by the client, displayed either on stdout or in the TUI.
+pub enum ConsoleEvent {
+    /// A regular line, e.g. a chat message.
+    Line(String),
+    /// A chat message.
+    Message(MessageLine),
+    /// An error or warning.
+    Error(String),
+    /// The connection with the server was lost, with the reason.
+    Disconnected(String),
+    /// The connection with the server was lost and the client is logging in again, with the reason.
+    Reconnecting(String),
+    /// The client has logged in again.
+    Reconnected(String),
+    /// Number of messages waiting to be sent until the client is reconnected.
+    Pending(usize),
+    /// Progress of a file transfer, `None` when the transfer is over.
+    Progress(Option<String>),
+    /// The message `seq` expired, received by `account` when the client is logged in to several.
+    /// The TUI strikes the message through, `notice` is shown where the message can't be found.
+    /// `seq` is `None` from servers older than protocol 1.3.
+    Expired { account: Option<String>, seq: Option<MessageSeq>, notice: String },
+    /// The online users with their status lines, listed by `account` when the client is logged in to several.
+    /// The TUI keeps them in its user list and shows the `heading` and the users in the message pane if there is
+    /// a heading, which is `None` when the list is only refreshed after a user changed their status.
+    Users { account: Option<String>, heading: Option<String>, users: Vec<UserStatus> },
+    /// The user `username` went online or offline, reported by `account` when the client is logged in to several.
+    /// The TUI shows the `notice` and updates its user list.
+    Presence { account: Option<String>, username: String, online: bool, notice: String },
+}
+
+/// An attachment of a received message, described in words or, in the plain output mode, by a marker.
+/// The JSON output shows it as an object with its `kind`.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Attachment {
+    /// An image, with its size in bytes
+    Image { size: u64 },
+    /// A file, with its name and size in bytes
+    File { filename: String, size: u64 },
+    /// A voice note, with its size in bytes
+    VoiceNote { size: u64 },
+    /// A preview of an image which can be downloaded with `.get`
+    Thumbnail { id: AttachmentId },
+    /// A file which can be downloaded with `.get`
+    FileOffer { filename: String, size: u64, id: AttachmentId },
+}
+
+/// Format of the output of the line mode.
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum OutputFormat {
+    /// lines for humans
+    #[default]
+    Text,
+    /// one JSON object per line for every event, for scripts and log collectors
+    Json,
+}
+
+/// An event of the JSON output, printed as one object per line with its `type`.
+/// The `account` is only given when the client is logged in to several.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum JsonEvent<'a> {
+    /// A chat message, `text` is `null` for attachments
+    Message {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        account: Option<&'a str>,
+        timestamp: DateTime<Utc>,
+        sender: &'a str,
+        display_name: &'a str,
+        direct: bool,
+        number: Option<u64>,
+        text: Option<&'a str>,
+        mention: bool,
+        announcement: bool,
+        ttl: Option<u32>,
+        attachment: Option<&'a Attachment>,
+    },
+    /// An ephemeral message expired, `number` is `null` for messages of the user
+    Expired {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        account: Option<&'a str>,
+        sender: &'a str,
+        number: Option<u64>,
+    },
+    /// The preview of a link in the previous message
+    LinkPreview {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        account: Option<&'a str>,
+        url: &'a str,
+        title: &'a str,
+        description: Option<&'a str>,
+    },
+    /// A user went online or offline
+    Presence {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        account: Option<&'a str>,
+        username: &'a str,
+        online: bool,
+    },
+    /// Any other line of output, e.g. a response to a command
+    Info {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        account: Option<&'a str>,
+        text: &'a str,
+    },
+    /// An error or warning
+    Error {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        account: Option<&'a str>,
+        text: &'a str,
+    },
+    /// Progress of the login, before the session starts
+    Status { text: &'a str },
+    /// The connection was lost and the client exits
+    Disconnected {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        account: Option<&'a str>,
+        reason: &'a str,
+    },
+    /// The connection was lost and the client logs in again
+    Reconnecting {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        account: Option<&'a str>,
+        reason: &'a str,
+    },
+    /// The client has logged in again
+    Reconnected {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        account: Option<&'a str>,
+        text: &'a str,
+    },
+    /// Number of messages waiting for the client to reconnect
+    Pending {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        account: Option<&'a str>,
+        count: usize,
+    },
+}
+
+impl JsonEvent<'_> {
+    /// Prints the event as a line of JSON on stdout.
+    fn print(&self) {
+        match serde_json::to_string(self) {
+            Ok(line) => println!("{line}"),
+            Err(e) => eprintln!("{}", t!("error", error = e.to_string())),
+        }
+    }
+}
+
+/// Format of the output of the process, chosen once at startup for the lines printed before a console exists.
+static OUTPUT: OnceLock<OutputFormat> = OnceLock::new();
+
+/// Chooses the format of the output, once at startup.
+///
+/// # Arguments
+///
+/// * `output` - The format of the output.
+pub fn set_output(output: OutputFormat) {
+    let _ = OUTPUT.set(output);
+}
+
+/// Prints the progress of the login, which comes before the console of the session exists.
+/// The JSON output prints it as a `status` event.
+///
+/// # Arguments
+///
+/// * `line` - The line to be printed.
+pub fn status(line: impl Into<String>) {
+    let line = line.into();
+    match OUTPUT.get() {
+        Some(OutputFormat::Json) => JsonEvent::Status { text: &line }.print(),
+        _ => println!("{line}"),
+    }
+}
+
+/// `Console` is the single place where the client writes its output.
+/// In the plain mode the lines are printed to stdout and stderr, in the TUI mode they are sent to the UI task.
+#[derive(Clone, Default)]
+pub struct Console {
+    events: Option<UnboundedSender<ConsoleEvent>>,
+    theme: Arc<Theme>,
+    color: ColorMode,
+    /// Whether the Markdown of messages is rendered
+    markdown: bool,
+    /// Number of errors printed so far, shared by all clones
+    errors: Arc<AtomicUsize>,
+    /// Account shown before the output, when the client is logged in to several
+    account: Option<Arc<str>>,
+    /// Whether the output is meant for screen readers and log scrapers, one line per message with markers for attachments
+    plain_output: bool,
+    /// Format of the output printed to stdout
+    output: OutputFormat,
+}
+
+impl Console {
+    /// Creates a console which prints the output to stdout and stderr.
+    ///
+    /// # Arguments
+    ///
+    /// * `theme` - Colors of the output.
+    /// * `color` - When to use colors.
+    /// * `markdown` - Whether to render the Markdown of messages.
+    ///
+    /// # Returns
+    ///
+    /// * `Console` - Returns the console.
+    pub fn plain(theme: Theme, color: ColorMode, markdown: bool) -> Console {
+        Console { events: None, theme: Arc::new(theme), color, markdown, errors: Arc::default(), account: None, plain_output: false, output: OutputFormat::Text }
+    }
+
+    /// Creates a console which forwards the output to a channel instead of printing it.
+    ///
+    /// # Arguments
+    ///
+    /// * `theme` - Colors of the output.
+    /// * `color` - When to use colors.
+    /// * `markdown` - Whether to render the Markdown of messages.
+    ///
+    /// # Returns
+    ///
+    /// * `(Console, UnboundedReceiver<ConsoleEvent>)` - Returns the console and the receiving end of the channel.
+    pub fn channel(theme: Theme, color: ColorMode, markdown: bool) -> (Console, UnboundedReceiver<ConsoleEvent>) {
+        let (events, events_rx) = mpsc::unbounded_channel();
+        (Console { events: Some(events), theme: Arc::new(theme), color, markdown, errors: Arc::default(), account: None, plain_output: false, output: OutputFormat::Text }, events_rx)
+    }
+
+    /// Creates a console for the output of one of several accounts, which shows the name of the account
+    /// before every line. The output goes to the same place as the one of this console.
+    ///
+    /// # Arguments
+    ///
+    /// * `account` - The name of the account.
+    ///
+    /// # Returns
+    ///
+    /// * `Console` - Returns the console of the account.
+    pub fn for_account(&self, account: &str) -> Console {
+        Console { account: Some(account.into()), ..self.clone() }
+    }
+
+    /// Switches the console to the plain output mode for screen readers and log scrapers. Every message is printed
+    /// on a single line, attachments and mentions are marked in words and the progress of transfers isn't shown.
+    /// Colors and Markdown are turned off by the caller.
+    ///
+    /// # Arguments
+    ///
+    /// * `plain_output` - Whether to use the plain output mode.
+    ///
+    /// # Returns
+    ///
+    /// * `Console` - Returns the console.
+    pub fn with_plain_output(self, plain_output: bool) -> Console {
+        Console { plain_output, ..self }
+    }
+
+    /// Switches the format of the output printed to stdout, the JSON output prints every event as a line of JSON.
+    ///
+    /// # Arguments
+    ///
+    /// * `output` - The format of the output.
+    ///
+    /// # Returns
+    ///
+    /// * `Console` - Returns the console.
+    pub fn with_output(self, output: OutputFormat) -> Console {
+        Console { output, ..self }
+    }
+
+    /// Returns whether the output is printed as JSON.
+    fn json(&self) -> bool {
+        self.output == OutputFormat::Json
+    }
+
+    /// Returns whether the console is in the plain output mode, the line reader doesn't use the line editor then.
+    pub fn plain_output(&self) -> bool {
+        self.plain_output
+    }
+
+    /// Puts the name of the account, if there is one, before a line of output.
+    fn prefixed(&self, line: impl Into<String>) -> String {
+        match &self.account {
+            Some(account) => format!("[{account}] {}", line.into()),
+            None => line.into(),
+        }
+    }
+
+    /// Returns whether the Markdown of messages is rendered, the UI task renders it when styling the lines.
+    pub fn markdown(&self) -> bool {
+        self.markdown
+    }
+
+    /// Returns the colors of the output, `None` if colors are disabled.
+    /// The UI task uses it to style the lines, the terminal is always a terminal there.
+    pub fn theme(&self) -> Option<&Theme> {
+        self.color.enabled(true).then_some(self.theme.as_ref())
+    }
+
+    /// Prints a line of output.
+    ///
+    /// # Arguments
+    ///
+    /// * `line` - The line to be printed.
+    pub fn print(&self, line: impl Into<String>) {
+        if self.events.is_none() && self.json() {
+            return JsonEvent::Info { account: self.account.as_deref(), text: &line.into() }.print();
+        }
+        let line = match self.plain_output {
+            true => self.prefixed(client_theme::one_line(&line.into())),
+            false => self.prefixed(line),
+        };
+        match &self.events {
+            Some(events) => { let _ = events.send(ConsoleEvent::Line(line)); },
+            None => println!("{line}"),
+        }
+    }
+
+    /// Prints a chat message.
+    ///
+    /// # Arguments
+    ///
+    /// * `message` - The message to be printed.
+    pub fn message(&self, mut message: MessageLine) {
+        message.account = self.account.as_deref().map(str::to_string);
+        match &self.events {
+            Some(events) => { let _ = events.send(ConsoleEvent::Message(message)); },
+            None if self.json() => JsonEvent::Message {
+                account: message.account.as_deref(),
+                timestamp: message.timestamp,
+                sender: &message.username,
+                display_name: &message.sender,
+                direct: message.recipient.is_some(),
+                number: message.number,
+                text: message.attachment.is_none().then_some(message.text.as_str()),
+                mention: message.mention,
+                announcement: message.announcement,
+                ttl: message.ttl,
+                attachment: message.attachment.as_ref(),
+            }.print(),
+            None if self.plain_output => println!("{}", client_theme::format_plain(&message)),
+            None => println!("{}", self.theme.format(&message, self.color.enabled_for_stdout(), self.markdown)),
+        }
+    }
+
+    /// Prints an error.
+    ///
+    /// # Arguments
+    ///
+    /// * `line` - The error message.
+    pub fn error(&self, line: impl Into<String>) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+        if self.events.is_none() && self.json() {
+            return JsonEvent::Error { account: self.account.as_deref(), text: &line.into() }.print();
+        }
+        let line = self.prefixed(line);
+        match &self.events {
+            Some(events) => { let _ = events.send(ConsoleEvent::Error(line)); },
+            None if self.color.enabled(std::io::stderr().is_terminal()) => eprintln!("{}", self.theme.format_error(&line)),
+            None => eprintln!("{line}"),
+        }
+    }
+
+    /// Returns the number of errors printed so far, the headless mode exits with a failure if there were any.
+    pub fn error_count(&self) -> usize {
+        self.errors.load(Ordering::Relaxed)
+    }
+
+    /// Shows the progress of a file transfer. In the plain mode a single line on stderr is rewritten,
+    /// and only if stderr is a terminal so that redirected output isn't cluttered. The plain output mode never shows it,
+    /// the start and the end of a transfer are printed as lines of their own.
+    ///
+    /// # Arguments
+    ///
+    /// * `progress` - The progress line, `None` to remove it when the transfer is over.
+    pub fn progress(&self, progress: Option<String>) {
+        match &self.events {
+            Some(events) => { let _ = events.send(ConsoleEvent::Progress(progress)); },
+            None if self.plain_output || self.json() => {},
+            None if std::io::stderr().is_terminal() => {
+                let mut stderr = std::io::stderr();
+                let _ = write!(stderr, "\r{}\x1b[K", progress.unwrap_or_default());
+                let _ = stderr.flush();
+            },
+            None => {},
+        }
+    }
+
+    /// Describes an attachment of a received message, the text of its message line.
+    ///
+    /// # Arguments
+    ///
+    /// * `attachment` - The attachment.
+    ///
+    /// # Returns
+    ///
+    /// * `String` - Returns the description, e.g. `sent a voice note (12.0 KB)`, or `[voice note, 12.0 KB]` in the plain output mode.
+    pub fn attachment(&self, attachment: &Attachment) -> String {
+        match (self.plain_output, attachment) {
+            (false, Attachment::Image { .. }) => t!("sending-image"),
+            (false, Attachment::File { .. }) => t!("sending-file"),
+            (false, Attachment::VoiceNote { size }) => t!("sent-voice-note", size = format_size(*size)),
+            (false, Attachment::Thumbnail { id }) => t!("sent-image-thumbnail", id = id.to_string()),
+            (false, Attachment::FileOffer { filename, size, id }) => t!("sent-file-offer", filename = filename.as_str(), size = format_size(*size), id = id.to_string()),
+            (true, Attachment::Image { size }) => t!("marker-image", size = format_size(*size)),
+            (true, Attachment::File { filename, size }) => t!("marker-file", filename = filename.as_str(), size = format_size(*size)),
+            (true, Attachment::VoiceNote { size }) => t!("marker-voice-note", size = format_size(*size)),
+            (true, Attachment::Thumbnail { id }) => t!("marker-thumbnail", id = id.to_string()),
+            (true, Attachment::FileOffer { filename, size, id }) => t!("marker-file-offer", filename = filename.as_str(), size = format_size(*size), id = id.to_string()),
+        }
+    }
+
+    /// Prints the preview of a link in a received message, indented below the message,
+    /// or as a single line with a marker in the plain output mode.
+    ///
+    /// # Arguments
+    ///
+    /// * `url` - The previewed link.
+    /// * `title` - The title of the linked page.
+    /// * `description` - The description of the linked page.
+    pub fn link_preview(&self, url: &str, title: &str, description: Option<&str>) {
+        if self.events.is_none() && self.json() {
+            return JsonEvent::LinkPreview { account: self.account.as_deref(), url, title, description }.print();
+        }
+        if self.plain_output {
+            let marker = t!("marker-link", url = url, title = title);
+            self.print(description.map(|description| format!("{marker} {description}")).unwrap_or(marker));
+            return;
+        }
+        self.print(format!("  ↳ {title} ({url})"));
+        if let Some(description) = description {
+            self.print(format!("    {description}"));
+        }
+    }
+
+    /// Reports that a user went online or offline.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - The username of the user.
+    /// * `online` - Whether the user is online now.
+    pub fn presence(&self, username: &str, online: bool) {
+        if self.events.is_none() && self.json() {
+            return JsonEvent::Presence { account: self.account.as_deref(), username, online }.print();
+        }
+        let notice = match online {
+            true => t!("user-joined", username = username),
+            false => t!("user-left", username = username),
+        };
+        match &self.events {
+            Some(events) => {
+                let account = self.account.as_deref().map(str::to_string);
+                let _ = events.send(ConsoleEvent::Presence { account, username: username.to_string(), online, notice: self.prefixed(notice) });
+            },
+            None => self.print(notice),
+        }
+    }
+
+    /// Prints the online users with their status lines. The TUI shows the usernames in their colors
+    /// and keeps them in its user list.
+    ///
+    /// # Arguments
+    ///
+    /// * `users` - The online users.
+    pub fn users(&self, users: &[UserStatus]) {
+        let heading = t!("online-users", count = users.len());
+        match &self.events {
+            Some(events) => {
+                let account = self.account.as_deref().map(str::to_string);
+                let _ = events.send(ConsoleEvent::Users { account, heading: Some(self.prefixed(heading)), users: users.to_vec() });
+            },
+            None => self.print(format!("{heading} {}", client_theme::format_users(users))),
+        }
+    }
+
+    /// Updates the user list of the TUI without printing anything, e.g. after a user changed their status.
+    ///
+    /// # Arguments
+    ///
+    /// * `users` - The online users.
+    pub fn refresh_users(&self, users: &[UserStatus]) {
+        if let Some(events) = &self.events {
+            let account = self.account.as_deref().map(str::to_string);
+            let _ = events.send(ConsoleEvent::Users { account, heading: None, users: users.to_vec() });
+        }
+    }
+
+    /// Tells whether the output is passed on as events, which keep the user list of the TUI, so that the client
+    /// has to keep the statuses in it up to date.
+    pub fn lists_users(&self) -> bool {
+        self.events.is_some()
+    }
+
+    /// Reports that an ephemeral message expired and was deleted by the server.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - The username of the sender.
+    /// * `seq` - The sequence number of the message, `None` from servers older than protocol 1.3.
+    /// * `number` - The number of the message for `.reply`, `None` for messages of the user.
+    pub fn expired(&self, username: &str, seq: Option<MessageSeq>, number: Option<u64>) {
+        if self.events.is_none() && self.json() {
+            return JsonEvent::Expired { account: self.account.as_deref(), sender: username, number }.print();
+        }
+        let notice = match number {
+            Some(number) => t!("message-expired", number = number.to_string(), sender = username),
+            None => t!("earlier-message-expired", sender = username),
+        };
+        match &self.events {
+            Some(events) => {
+                let account = self.account.as_deref().map(str::to_string);
+                let _ = events.send(ConsoleEvent::Expired { account, seq, notice: self.prefixed(notice) });
+            },
+            None => self.print(notice),
+        }
+    }
+
+    /// Reports that the connection with the server was lost. In the plain mode the client exits,
+    /// the TUI stays open so that the user can read the history.
+    ///
+    /// # Arguments
+    ///
+    /// * `reason` - The reason of the disconnection.
+    pub fn disconnected(&self, reason: impl Into<String>) {
+        match &self.events {
+            Some(events) => { let _ = events.send(ConsoleEvent::Disconnected(self.prefixed(reason))); },
+            None if self.json() => {
+                JsonEvent::Disconnected { account: self.account.as_deref(), reason: &reason.into() }.print();
+                exit(1);
+            },
+            None => {
+                self.error(reason);
+                exit(1);
+            }
+        }
+    }
+
+    /// Reports that the connection with the server was lost and the client is logging in again.
+    /// The TUI shows it in the status bar until the client has reconnected.
+    ///
+    /// # Arguments
+    ///
+    /// * `reason` - The reason of the disconnection.
+    pub fn reconnecting(&self, reason: impl Into<String>) {
+        match &self.events {
+            Some(events) => { let _ = events.send(ConsoleEvent::Reconnecting(self.prefixed(reason))); },
+            None if self.json() => JsonEvent::Reconnecting { account: self.account.as_deref(), reason: &reason.into() }.print(),
+            None => self.error(reason),
+        }
+    }
+
+    /// Reports that the client has logged in again.
+    ///
+    /// # Arguments
+    ///
+    /// * `line` - The line to be printed.
+    pub fn reconnected(&self, line: impl Into<String>) {
+        match &self.events {
+            Some(events) => { let _ = events.send(ConsoleEvent::Reconnected(self.prefixed(line))); },
+            None if self.json() => JsonEvent::Reconnected { account: self.account.as_deref(), text: &line.into() }.print(),
+            None => println!("{}", self.prefixed(line)),
+        }
+    }
+
+    /// Shows how many messages wait for the client to reconnect. The TUI keeps the number in the status bar,
+    /// the plain mode prints it whenever a message is queued.
+    ///
+    /// # Arguments
+    ///
+    /// * `count` - The number of pending messages.
+    pub fn pending(&self, count: usize) {
+        match &self.events {
+            Some(events) => { let _ = events.send(ConsoleEvent::Pending(count)); },
+            None if count > 0 && self.json() => JsonEvent::Pending { account: self.account.as_deref(), count }.print(),
+            None if count > 0 => println!("{}", self.prefixed(t!("not-connected-pending", pending = pending_messages(count)))),
+            None => {},
+        }
+    }
+}
+
+/// Describes the number of messages waiting for the client to reconnect.
+///
+/// # Arguments
+///
+/// * `count` - The number of pending messages.
+///
+/// # Returns
+///
+/// * `String` - Returns the description, e.g. `2 messages pending`.
+pub fn pending_messages(count: usize) -> String {
+    t!("messages-pending", count = count)
+}
+
+/// `TransferProgress` tracks how much of a file was transferred and produces a progress line
+/// whenever the percentage changes, so that the output isn't updated for every chunk.
+pub struct TransferProgress {
+    label: String,
+    total: u64,
+    done: u64,
+    last_percent: Option<u64>,
+}
+
+impl TransferProgress {
+    /// Creates a new instance of `TransferProgress`.
+    ///
+    /// # Arguments
+    ///
+    /// * `label` - Description of the transfer, e.g. `Sending notes.txt`.
+    /// * `total` - The size of the file in bytes.
+    ///
+    /// # Returns
+    ///
+    /// * `TransferProgress` - Returns the progress of a transfer which has just started.
+    pub fn new(label: impl Into<String>, total: u64) -> TransferProgress {
+        TransferProgress { label: label.into(), total, done: 0, last_percent: None }
+    }
+
+    /// Records transferred bytes.
+    ///
+    /// # Arguments
+    ///
+    /// * `len` - The number of bytes transferred since the last call.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<String>` - Returns the progress line if the percentage changed.
+    pub fn advance(&mut self, len: u64) -> Option<String> {
+        self.done += len;
+        let percent = (self.done * 100).checked_div(self.total).unwrap_or(100).min(100);
+        if self.last_percent == Some(percent) {
+            return None;
+        }
+        self.last_percent = Some(percent);
+        Some(format!("{}: {percent}% ({} / {})", self.label, format_size(self.done), format_size(self.total)))
+    }
+}
+
+/// Formats a long duration for humans, to the minute.
+///
+/// # Arguments
+///
+/// * `duration` - The duration.
+///
+/// # Returns
+///
+/// * `String` - Returns the duration, e.g. `2d 3h 4m`.
+pub fn format_uptime(duration: std::time::Duration) -> String {
+    let minutes = duration.as_secs() / 60;
+    match (minutes / 1440, minutes / 60 % 24, minutes % 60) {
+        (0, 0, minutes) => format!("{minutes}m"),
+        (0, hours, minutes) => format!("{hours}h {minutes}m"),
+        (days, hours, minutes) => format!("{days}d {hours}h {minutes}m"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chat::UserStatus;
+
+    use crate::client_console::{format_uptime, Attachment, Console, ConsoleEvent, JsonEvent, TransferProgress};
+    use crate::client_theme::{ColorMode, MessageLine, Theme};
+
+    #[test]
+    fn test_for_account() {
+        let (console, mut events) = Console::channel(Theme::default(), ColorMode::Never, false);
+        let work = console.for_account("work");
+        console.print("Ok.");
+        work.print("*** Bob joined the chat.");
+        work.error("Error: user Bob is not online, message not delivered.");
+        work.message(MessageLine { time: "12:00".into(), sender: "Bob".into(), recipient: None, number: None, text: "hi".into(), mention: false, account: None, ..MessageLine::default() });
+        work.users(&[UserStatus { username: "Bob".into(), status: Some("away".into()) }]);
+        work.presence("Carol", false);
+
+        assert!(matches!(events.try_recv(), Ok(ConsoleEvent::Line(line)) if line == "Ok."));
+        assert!(matches!(events.try_recv(), Ok(ConsoleEvent::Line(line)) if line == "[work] *** Bob joined the chat."));
+        assert!(matches!(events.try_recv(), Ok(ConsoleEvent::Error(line)) if line == "[work] Error: user Bob is not online, message not delivered."));
+        assert!(matches!(events.try_recv(), Ok(ConsoleEvent::Message(message)) if message.account.as_deref() == Some("work")));
+        assert!(matches!(events.try_recv(), Ok(ConsoleEvent::Users { account, heading, users })
+            if account.as_deref() == Some("work") && heading.as_deref() == Some("[work] Online users (1):") && users.len() == 1));
+        assert!(matches!(events.try_recv(), Ok(ConsoleEvent::Presence { account, username, online: false, notice })
+            if account.as_deref() == Some("work") && username == "Carol" && notice == "[work] *** Carol left the chat."));
+        // The accounts count the errors together, the headless mode fails on any of them
+        assert_eq!(console.error_count(), 1);
+    }
+
+    #[test]
+    fn test_plain_output() {
+        let (console, mut events) = Console::channel(Theme::default(), ColorMode::Never, false);
+        assert_eq!(console.attachment(&Attachment::VoiceNote { size: 2048 }), "sent a voice note (2.0 KB)");
+        let console = console.with_plain_output(true);
+        assert_eq!(console.attachment(&Attachment::VoiceNote { size: 2048 }), "[voice note, 2.0 KB]");
+        let offer = Attachment::FileOffer { filename: "notes.txt".into(), size: 10, id: 7 };
+        assert_eq!(console.attachment(&offer), "[file notes.txt, 10 B, download it with .get 7]");
+
+        // Every line of the output stands on its own
+        console.print("> Bob: first\nsecond");
+        console.link_preview("https://example.com", "Example", Some("An example"));
+        assert!(matches!(events.try_recv(), Ok(ConsoleEvent::Line(line)) if line == "> Bob: first\\nsecond"));
+        assert!(matches!(events.try_recv(), Ok(ConsoleEvent::Line(line)) if line == "[link https://example.com: Example] An example"));
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_json_event() {
+        let offer = Attachment::FileOffer { filename: "notes.txt".into(), size: 10, id: 7 };
+        let event = JsonEvent::Message {
+            account: None,
+            timestamp: "2024-05-01T12:00:00Z".parse().unwrap(),
+            sender: "bob",
+            display_name: "Bobby (bob)",
+            direct: false,
+            number: None,
+            text: None,
+            mention: false,
+            announcement: false,
+            ttl: None,
+            attachment: Some(&offer),
+        };
+        assert_eq!(serde_json::to_string(&event).unwrap(), concat!(
+            r#"{"type":"message","timestamp":"2024-05-01T12:00:00Z","sender":"bob","display_name":"Bobby (bob)","direct":false,"#,
+            r#""number":null,"text":null,"mention":false,"announcement":false,"ttl":null,"attachment":{"kind":"file_offer","filename":"notes.txt","size":10,"id":7}}"#,
+        ));
+
+        let event = JsonEvent::Expired { account: None, sender: "bob", number: Some(4) };
+        assert_eq!(serde_json::to_string(&event).unwrap(), r#"{"type":"expired","sender":"bob","number":4}"#);
+
+        let event = JsonEvent::Presence { account: Some("work"), username: "alice", online: true };
+        assert_eq!(serde_json::to_string(&event).unwrap(), r#"{"type":"presence","account":"work","username":"alice","online":true}"#);
+    }
+
+
+    #[test]
+    fn test_format_uptime() {
+        assert_eq!(format_uptime(Duration::from_secs(59)), "0m");
+        assert_eq!(format_uptime(Duration::from_secs(3 * 3600 + 4 * 60)), "3h 4m");
+        assert_eq!(format_uptime(Duration::from_secs(2 * 86400 + 60)), "2d 0h 1m");
+    }
+
+    #[test]
+    fn test_transfer_progress() {
+        let mut progress = TransferProgress::new("Sending notes.txt", 1000);
+        assert_eq!(progress.advance(250).as_deref(), Some("Sending notes.txt: 25% (250 B / 1000 B)"));
+        assert_eq!(progress.advance(1), None);
+        assert_eq!(progress.advance(749).as_deref(), Some("Sending notes.txt: 100% (1000 B / 1000 B)"));
+
+        let mut empty = TransferProgress::new("Receiving empty.txt", 0);
+        assert_eq!(empty.advance(0).as_deref(), Some("Receiving empty.txt: 100% (0 B / 0 B)"));
+    }
+}
@@ -0,0 +1,352 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use chat::{t, EmptyResult};
+use tokio::sync::mpsc::UnboundedReceiver;
+
+use crate::client_console::ConsoleEvent;
+use crate::ChatContext;
+
+/// Number of output lines kept for an attached client which doesn't read them quickly enough.
+#[cfg(unix)]
+const OUTPUT_BACKLOG: usize = 1000;
+
+/// Where the daemon mode writes its output and listens for attached clients.
+pub struct DaemonConfig {
+    /// Control socket which `client attach` connects to
+    pub socket: PathBuf,
+    /// File where the output is appended
+    pub log_file: PathBuf,
+}
+
+/// Returns the default location of the control socket, in the runtime directory of the user on Linux.
+pub fn default_socket_path() -> Option<PathBuf> {
+    dirs::runtime_dir().or_else(dirs::cache_dir).map(|dir| dir.join("myrustchat").join("client.sock"))
+}
+
+/// The listening control socket of a daemon, the socket file is removed when it's dropped.
+pub struct ControlSocket {
+    #[cfg(unix)]
+    listener: tokio::net::UnixListener,
+    path: PathBuf,
+}
+
+impl ControlSocket {
+    /// Starts listening on the control socket. A socket left behind by a daemon which didn't stop cleanly is replaced,
+    /// the one of a running daemon isn't.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the socket.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<ControlSocket>` - Returns the listening socket if successful.
+    #[cfg(unix)]
+    pub async fn bind(path: &Path) -> Result<ControlSocket> {
+        use std::os::unix::fs::{DirBuilderExt, FileTypeExt};
+        use anyhow::Context;
+
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::DirBuilder::new().recursive(true).mode(0o700).create(dir)
+                .with_context(|| t!("could-not-create", path = dir.display().to_string()))?;
+        }
+        if tokio::net::UnixStream::connect(path).await.is_ok() {
+            anyhow::bail!(t!("daemon-already-listening", path = path.display().to_string()));
+        }
+        match std::fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.file_type().is_socket() => {
+                let _ = std::fs::remove_file(path);
+            },
+            Ok(_) => anyhow::bail!(t!("not-a-socket", path = path.display().to_string())),
+            Err(_) => {},
+        }
+        let listener = tokio::net::UnixListener::bind(path)
+            .with_context(|| t!("could-not-listen", path = path.display().to_string()))?;
+        Ok(ControlSocket { listener, path: path.to_path_buf() })
+    }
+
+    /// Starts listening on the control socket, which is not available on this platform.
+    #[cfg(not(unix))]
+    pub async fn bind(_path: &Path) -> Result<ControlSocket> {
+        Err(anyhow!(t!("daemon-unsupported")))
+    }
+
+    /// Returns the path of the socket.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Runs the daemon mode. The output of the client is appended to the log file and sent to the attached clients,
+/// the lines they send are performed as if they were typed. The daemon ignores hangups, so it stays connected
+/// when the terminal it was started from is closed.
+///
+/// # Arguments
+///
+/// * `context` - The chat context.
+/// * `events` - Output of the client.
+/// * `control` - The control socket the clients attach to.
+/// * `log_file` - The file where the output is appended.
+///
+/// # Returns
+///
+/// * `EmptyResult` - Returns an empty result when the daemon is stopped with `.quit`.
+#[cfg(unix)]
+pub async fn run(context: &mut ChatContext, events: UnboundedReceiver<ConsoleEvent>, control: ControlSocket, log_file: &Path) -> EmptyResult {
+    use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+    use anyhow::Context;
+    use tokio::signal::unix::{signal, SignalKind};
+    use tokio::sync::{broadcast, mpsc};
+
+    use crate::UserCommand;
+
+    // The log holds the received messages, so only the user can read it
+    let log = std::fs::OpenOptions::new().create(true).append(true).mode(0o600).open(log_file)
+        .with_context(|| t!("could-not-open-log-file", path = log_file.display().to_string()))?;
+    let (output, _) = broadcast::channel(OUTPUT_BACKLOG);
+    let mut disconnected = tokio::spawn(write_output(events, log, output.clone()));
+    let mut hangups = signal(SignalKind::hangup()).context(t!("could-not-handle-hangups"))?;
+    // Anyone who can connect could chat as the user, the socket is created by this process so it's owned by the user
+    let owner = std::fs::metadata(control.path()).context(t!("could-not-read-control-socket"))?.uid();
+    let (commands, mut commands_rx) = mpsc::unbounded_channel();
+    context.console.print(t!("connected"));
+
+    loop {
+        tokio::select! {
+            accepted = control.listener.accept() => match accepted {
+                Ok((stream, _)) if stream.peer_cred().is_ok_and(|peer| peer.uid() == owner) => {
+                    let greeting = t!("daemon-greeting", identity = context.identity());
+                    tokio::spawn(attached_session(stream, greeting, commands.clone(), output.subscribe()));
+                },
+                Ok(_) => context.console.error(t!("foreign-attach")),
+                Err(e) => context.console.error(t!("could-not-accept", error = e.to_string())),
+            },
+            Some(line) = commands_rx.recv() => {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match UserCommand::from_str(line.trim()).perform(context).await {
+                    Err(e) => {
+                        // Same as the plain mode
+                        if context.is_recoverable(&e) {
+                            context.console.error(t!("error", error = e.to_string()));
+                            if e.chain().count() > 1 {
+                                context.console.error(format!("{}", e.root_cause()));
+                            }
+                        } else {
+                            return Err(e);
+                        }
+                    },
+                    Ok(true) => return Ok(()),
+                    Ok(false) => {},
+                }
+            },
+            reason = &mut disconnected => return Err(anyhow!(reason.unwrap_or_default())),
+            _ = hangups.recv() => {},
+        }
+    }
+}
+
+/// Runs the daemon mode, which is not available on this platform.
+#[cfg(not(unix))]
+pub async fn run(_context: &mut ChatContext, _events: UnboundedReceiver<ConsoleEvent>, _control: ControlSocket, _log_file: &Path) -> EmptyResult {
+    Err(anyhow!(t!("daemon-unsupported")))
+}
+
+/// Formats the output of the client as lines of the log file and of the attached clients.
+///
+/// # Arguments
+///
+/// * `event` - The output of the client.
+///
+/// # Returns
+///
+/// * `Option<String>` - Returns the line, `None` for output which is only shown by the TUI.
+fn format_event(event: &ConsoleEvent) -> Option<String> {
+    match event {
+        ConsoleEvent::Line(line) | ConsoleEvent::Error(line) | ConsoleEvent::Disconnected(line) => Some(line.clone()),
+        ConsoleEvent::Reconnecting(line) | ConsoleEvent::Reconnected(line) => Some(line.clone()),
+        ConsoleEvent::Expired { notice, .. } | ConsoleEvent::Presence { notice, .. } => Some(notice.clone()),
+        ConsoleEvent::Users { heading: Some(heading), users, .. } => Some(format!("{heading} {}", crate::client_theme::format_users(users))),
+        ConsoleEvent::Users { heading: None, .. } => None,
+        ConsoleEvent::Message(message) => Some(crate::client_theme::Theme::default().format(message, false, false)),
+        ConsoleEvent::Pending(count) if *count > 0 => Some(t!("not-connected-pending", pending = crate::client_console::pending_messages(*count))),
+        ConsoleEvent::Pending(_) | ConsoleEvent::Progress(_) => None,
+    }
+}
+
+/// Writes the output of the client to the log file and to the attached clients until the session ends.
+///
+/// # Arguments
+///
+/// * `events` - Output of the client.
+/// * `log` - The log file.
+/// * `output` - Where the attached clients receive the lines.
+///
+/// # Returns
+///
+/// * `String` - Returns why the connection with the server ended.
+#[cfg(unix)]
+async fn write_output(mut events: UnboundedReceiver<ConsoleEvent>, mut log: std::fs::File, output: tokio::sync::broadcast::Sender<String>) -> String {
+    use std::io::Write;
+
+    while let Some(event) = events.recv().await {
+        if let Some(line) = format_event(&event) {
+            // A full disk doesn't stop the chat, the attached clients still get the output
+            let _ = writeln!(log, "{line}");
+            let _ = output.send(line.clone());
+        }
+        if let ConsoleEvent::Disconnected(reason) = event {
+            return reason;
+        }
+    }
+    String::new()
+}
+
+/// Passes the lines of an attached client to the daemon and the output of the daemon back until it detaches.
+///
+/// # Arguments
+///
+/// * `stream` - The connection of the attached client.
+/// * `greeting` - The first line sent to the client.
+/// * `commands` - Where the lines of the client go.
+/// * `output` - Output of the daemon.
+#[cfg(unix)]
+async fn attached_session(stream: tokio::net::UnixStream, greeting: String, commands: tokio::sync::mpsc::UnboundedSender<String>,
+                          mut output: tokio::sync::broadcast::Receiver<String>) {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::sync::broadcast::error::RecvError;
+
+    let (read_half, mut write_half) = stream.into_split();
+    let mut lines = BufReader::new(read_half).lines();
+    let mut line = Some(greeting);
+    loop {
+        if let Some(line) = line.take() {
+            if write_half.write_all(format!("{line}\n").as_bytes()).await.is_err() {
+                return;
+            }
+        }
+        line = tokio::select! {
+            typed = lines.next_line() => match typed {
+                Ok(Some(typed)) => match commands.send(typed) {
+                    Ok(()) => None,
+                    Err(_) => return,
+                },
+                _ => return,
+            },
+            received = output.recv() => match received {
+                Ok(received) => Some(received),
+                Err(RecvError::Lagged(skipped)) => Some(t!("lines-skipped", count = skipped)),
+                Err(RecvError::Closed) => return,
+            },
+        };
+    }
+}
+
+/// Attaches to a running daemon, sending the typed lines to it and printing its output until the user detaches
+/// with Ctrl-D or the daemon stops.
+///
+/// # Arguments
+///
+/// * `socket` - The control socket of the daemon.
+///
+/// # Returns
+///
+/// * `EmptyResult` - Returns an empty result if successful.
+#[cfg(unix)]
+pub async fn attach(socket: &Path) -> EmptyResult {
+    use anyhow::Context;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    use crate::client_console::Console;
+    use crate::client_theme::{ColorMode, Theme};
+
+    let stream = tokio::net::UnixStream::connect(socket).await
+        .with_context(|| t!("no-daemon", path = socket.display().to_string()))?;
+    let (read_half, mut write_half) = stream.into_split();
+    let mut output = BufReader::new(read_half).lines();
+    // The daemon formats its output, so it's printed as it comes
+    let console = Console::plain(Theme::default(), ColorMode::Never, false);
+    let mut typed = crate::client_input::spawn_line_reader(console.clone(), crate::KnownUsers::default());
+    loop {
+        tokio::select! {
+            line = typed.recv() => match line {
+                Some(line) => write_half.write_all(format!("{line}\n").as_bytes()).await
+                    .context(t!("daemon-stopped"))?,
+                None => return Ok(()),
+            },
+            line = output.next_line() => match line.context(t!("could-not-read-daemon"))? {
+                Some(line) => console.print(line),
+                None => {
+                    console.print(t!("daemon-stopped"));
+                    return Ok(());
+                },
+            },
+        }
+    }
+}
+
+/// Attaches to a running daemon, which is not available on this platform.
+#[cfg(not(unix))]
+pub async fn attach(_socket: &Path) -> EmptyResult {
+    Err(anyhow!(t!("daemon-unsupported")))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::UnixStream;
+    use tokio::sync::{broadcast, mpsc};
+
+    use crate::client_console::ConsoleEvent;
+    use crate::client_daemon::{attached_session, format_event, ControlSocket};
+    use crate::client_theme::MessageLine;
+
+    #[test]
+    fn test_format_event() {
+        let message = MessageLine { time: "12:00".into(), sender: "Bob".into(), recipient: None, number: Some(3), text: "**hi**".into(), mention: false, account: None, ..MessageLine::default() };
+        assert_eq!(format_event(&ConsoleEvent::Message(message)).as_deref(), Some("[12:00] #3 [Bob] **hi**"));
+        assert_eq!(format_event(&ConsoleEvent::Pending(2)).as_deref(), Some("Not connected, 2 messages pending."));
+        assert_eq!(format_event(&ConsoleEvent::Pending(0)), None);
+        assert_eq!(format_event(&ConsoleEvent::Progress(Some("Sending a.txt: 5%".into()))), None);
+    }
+
+    #[tokio::test]
+    async fn test_attached_session() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("daemon").join("client.sock");
+        let control = ControlSocket::bind(&path).await.unwrap();
+        let (commands, mut commands_rx) = mpsc::unbounded_channel();
+        let (output, _) = broadcast::channel(16);
+        let client = UnixStream::connect(&path).await.unwrap();
+        let (stream, _) = control.listener.accept().await.unwrap();
+        assert!(ControlSocket::bind(&path).await.is_err());
+        tokio::spawn(attached_session(stream, "Hello".into(), commands, output.subscribe()));
+
+        let (read_half, mut write_half) = client.into_split();
+        let mut lines = BufReader::new(read_half).lines();
+        assert_eq!(lines.next_line().await.unwrap().as_deref(), Some("Hello"));
+        write_half.write_all(b".who\n").await.unwrap();
+        assert_eq!(commands_rx.recv().await.as_deref(), Some(".who"));
+        output.send("[12:00] [Bob] hi".into()).unwrap();
+        assert_eq!(lines.next_line().await.unwrap().as_deref(), Some("[12:00] [Bob] hi"));
+
+        // Detaching ends the session, the daemon goes on
+        drop(write_half);
+        assert!(tokio::time::timeout(Duration::from_secs(5), commands_rx.recv()).await.unwrap().is_none());
+
+        // The socket file is removed, so the next daemon can listen there
+        drop(control);
+        assert!(!path.exists());
+        ControlSocket::bind(&path).await.unwrap();
+    }
+}
@@ -0,0 +1,265 @@
+use std::fs::File;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use chat::{t, AttachmentId, FILE_CHUNK_SIZE};
+use clap::ValueEnum;
+
+/// Subdirectory of the download directory holding unfinished downloads.
+const PARTIAL_DIR: &str = "partial";
+
+/// What to do when a received file has the same name as an existing one.
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum OverwritePolicy {
+    /// save under a unique name like `notes (1).txt`
+    #[default]
+    Rename,
+    /// replace the existing file
+    Overwrite,
+    /// keep the existing file and drop the received one
+    Skip,
+}
+
+/// `Downloads` decides where received images and files are saved.
+#[derive(Clone)]
+pub struct Downloads {
+    /// Images are saved to `images/` and files to `files/` in this directory
+    dir: PathBuf,
+    policy: OverwritePolicy,
+    /// Identifies the server in the names of partial files, as attachment IDs are assigned by each server
+    server: String,
+}
+
+impl Downloads {
+    /// Creates a new instance of `Downloads`.
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - The directory where received attachments are saved.
+    /// * `policy` - What to do when a file with the same name exists.
+    /// * `server` - The address of the server the attachments are downloaded from.
+    ///
+    /// # Returns
+    ///
+    /// * `Downloads` - Returns the download settings.
+    pub fn new(dir: &Path, policy: OverwritePolicy, server: &str) -> Downloads {
+        let server = server.chars().map(|c| if c.is_ascii_alphanumeric() || c == '.' { c } else { '_' }).collect();
+        Downloads { dir: dir.to_path_buf(), policy, server }
+    }
+
+    /// Returns the path of the partial file where an attachment is downloaded before it's complete.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The ID of the attachment.
+    ///
+    /// # Returns
+    ///
+    /// * `PathBuf` - Returns the path of the partial file.
+    pub fn partial_path(&self, id: AttachmentId) -> PathBuf {
+        self.dir.join(PARTIAL_DIR).join(format!("{}-{id}.part", self.server))
+    }
+
+    /// Finds where an interrupted download of an attachment continues. Only complete chunks
+    /// of the partial file are kept, the rest is cut off.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The ID of the attachment.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<u64>` - Returns the number of the first missing chunk, zero if nothing was downloaded yet.
+    pub fn resume_point(&self, id: AttachmentId) -> Result<u64> {
+        let path = self.partial_path(id);
+        let file = match File::options().write(true).open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(0),
+            Err(e) => Err(e).with_context(|| t!("could-not-open-path", path = format!("{path:?}")))?,
+        };
+        let seq = file.metadata()?.len() / FILE_CHUNK_SIZE as u64;
+        file.set_len(seq * FILE_CHUNK_SIZE as u64)
+            .with_context(|| t!("could-not-truncate", path = format!("{path:?}")))?;
+        Ok(seq)
+    }
+
+    /// Opens the partial file of an attachment, creating it if the download isn't resumed.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The ID of the attachment.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(File, PathBuf)>` - Returns the opened file and its path.
+    pub fn open_partial(&self, id: AttachmentId) -> Result<(File, PathBuf)> {
+        let path = self.partial_path(id);
+        let dir = self.dir.join(PARTIAL_DIR);
+        std::fs::create_dir_all(&dir)
+            .with_context(|| t!("could-not-create-directory", path = format!("{dir:?}")))?;
+        let file = File::options().create(true).truncate(false).write(true).open(&path)
+            .with_context(|| t!("could-not-create-path", path = format!("{path:?}")))?;
+        Ok((file, path))
+    }
+
+    /// Tells whether a received file would be dropped because a file with the same name exists
+    /// and the policy is to skip it.
+    ///
+    /// # Arguments
+    ///
+    /// * `subdir` - The subdirectory of the download directory, e.g. `files`.
+    /// * `filename` - The name of the file, without any directories.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - Returns `true` if the file would be skipped.
+    pub fn skips(&self, subdir: &str, filename: &str) -> bool {
+        self.policy == OverwritePolicy::Skip && self.dir.join(subdir).join(filename).exists()
+    }
+
+    /// Moves a completely downloaded partial file to its place, following the overwrite policy.
+    ///
+    /// # Arguments
+    ///
+    /// * `partial` - The path of the partial file.
+    /// * `subdir` - The subdirectory of the download directory, e.g. `files`.
+    /// * `filename` - The name of the file, without any directories.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<PathBuf>>` - Returns the path of the saved file, `None` if it was skipped.
+    pub fn complete(&self, partial: &Path, subdir: &str, filename: &str) -> Result<Option<PathBuf>> {
+        let Some((_, path)) = self.create(subdir, filename)? else {
+            std::fs::remove_file(partial)
+                .with_context(|| t!("could-not-remove", path = format!("{partial:?}")))?;
+            return Ok(None);
+        };
+        // The created file only reserves the name, it's replaced by the downloaded one
+        std::fs::rename(partial, &path)
+            .with_context(|| t!("could-not-move", from = format!("{partial:?}"), to = format!("{path:?}")))?;
+        Ok(Some(path))
+    }
+
+    /// Creates a file for received data, following the overwrite policy.
+    ///
+    /// # Arguments
+    ///
+    /// * `subdir` - The subdirectory of the download directory, e.g. `images`.
+    /// * `filename` - The name of the file, without any directories.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<(File, PathBuf)>>` - Returns the opened file and its path, `None` if the file exists and the policy is to skip it.
+    pub fn create(&self, subdir: &str, filename: &str) -> Result<Option<(File, PathBuf)>> {
+        let dir = self.dir.join(subdir);
+        std::fs::create_dir_all(&dir)
+            .with_context(|| t!("could-not-create-directory", path = format!("{dir:?}")))?;
+
+        let path = dir.join(filename);
+        if self.policy == OverwritePolicy::Overwrite {
+            let file = File::create(&path)
+                .with_context(|| t!("could-not-create-path", path = format!("{path:?}")))?;
+            return Ok(Some((file, path)));
+        }
+
+        // create_new fails if the file exists, so a file appearing in the meantime is never overwritten
+        let mut attempt = 0;
+        loop {
+            let candidate = if attempt == 0 { path.clone() } else { numbered_path(&path, attempt) };
+            match File::options().write(true).create_new(true).open(&candidate) {
+                Ok(file) => return Ok(Some((file, candidate))),
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                    if self.policy == OverwritePolicy::Skip {
+                        return Ok(None);
+                    }
+                    attempt += 1;
+                },
+                Err(e) => Err(e).with_context(|| t!("could-not-create-path", path = format!("{candidate:?}")))?,
+            }
+        }
+    }
+}
+
+/// Adds a number to the file name before its extension, e.g. `notes.txt` becomes `notes (2).txt`.
+///
+/// # Arguments
+///
+/// * `path` - The original path.
+/// * `number` - The number to be added.
+///
+/// # Returns
+///
+/// * `PathBuf` - Returns the numbered path.
+fn numbered_path(path: &Path, number: u32) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(extension) => format!("{stem} ({number}).{}", extension.to_string_lossy()),
+        None => format!("{stem} ({number})"),
+    };
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use std::io::Write;
+
+    use chat::FILE_CHUNK_SIZE;
+
+    use crate::client_downloads::{numbered_path, Downloads, OverwritePolicy};
+
+    #[test]
+    fn test_numbered_path() {
+        assert_eq!(numbered_path(Path::new("files/notes.txt"), 1), Path::new("files/notes (1).txt"));
+        assert_eq!(numbered_path(Path::new("files/archive.tar.gz"), 2), Path::new("files/archive.tar (2).gz"));
+        assert_eq!(numbered_path(Path::new("files/README"), 3), Path::new("files/README (3)"));
+    }
+
+    #[test]
+    fn test_overwrite_policy() {
+        let dir = tempfile::tempdir().unwrap();
+        let existing = dir.path().join("files").join("notes.txt");
+        std::fs::create_dir_all(existing.parent().unwrap()).unwrap();
+        std::fs::write(&existing, b"old").unwrap();
+
+        let (_, path) = Downloads::new(dir.path(), OverwritePolicy::Rename, "server").create("files", "notes.txt").unwrap().unwrap();
+        assert_eq!(path, dir.path().join("files").join("notes (1).txt"));
+        let (_, path) = Downloads::new(dir.path(), OverwritePolicy::Rename, "server").create("files", "notes.txt").unwrap().unwrap();
+        assert_eq!(path, dir.path().join("files").join("notes (2).txt"));
+
+        assert!(Downloads::new(dir.path(), OverwritePolicy::Skip, "server").create("files", "notes.txt").unwrap().is_none());
+        assert_eq!(std::fs::read(&existing).unwrap(), b"old");
+
+        let (_, path) = Downloads::new(dir.path(), OverwritePolicy::Overwrite, "server").create("files", "notes.txt").unwrap().unwrap();
+        assert_eq!(path, existing);
+        assert!(std::fs::read(&existing).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_partial_download() {
+        let dir = tempfile::tempdir().unwrap();
+        let downloads = Downloads::new(dir.path(), OverwritePolicy::Rename, "127.0.0.1:11111");
+        assert_eq!(downloads.partial_path(7), dir.path().join("partial").join("127.0.0.1_11111-7.part"));
+        assert_eq!(downloads.resume_point(7).unwrap(), 0);
+
+        // An incomplete chunk is downloaded again
+        let (mut file, partial) = downloads.open_partial(7).unwrap();
+        file.write_all(&vec![1; FILE_CHUNK_SIZE + 10]).unwrap();
+        assert_eq!(downloads.resume_point(7).unwrap(), 1);
+        assert_eq!(std::fs::metadata(&partial).unwrap().len(), FILE_CHUNK_SIZE as u64);
+
+        let path = downloads.complete(&partial, "files", "data.bin").unwrap().unwrap();
+        assert_eq!(path, dir.path().join("files").join("data.bin"));
+        assert_eq!(std::fs::read(&path).unwrap().len(), FILE_CHUNK_SIZE);
+        assert!(!partial.exists());
+
+        let skipping = Downloads::new(dir.path(), OverwritePolicy::Skip, "127.0.0.1:11111");
+        assert!(skipping.skips("files", "data.bin"));
+        assert!(!downloads.skips("files", "data.bin"));
+        let (_, partial) = skipping.open_partial(8).unwrap();
+        assert!(skipping.complete(&partial, "files", "data.bin").unwrap().is_none());
+        assert!(!partial.exists());
+    }
+}
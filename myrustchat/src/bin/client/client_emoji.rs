@@ -0,0 +1,56 @@
+/// Replaces emoji shortcodes like `:smile:` or `:+1:` with the emoji. Unknown shortcodes and other text
+/// between colons, like times or `a:b:c`, are kept as they are.
+///
+/// # Arguments
+///
+/// * `text` - The text typed by the user.
+///
+/// # Returns
+///
+/// * `String` - Returns the text with the shortcodes expanded.
+pub fn expand_shortcodes(text: &str) -> String {
+    let mut expanded = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find(':') {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let emoji = after.find(':')
+            .filter(|&end| end > 0 && after[..end].chars().all(is_shortcode_char))
+            .and_then(|end| Some((end, emojis::get_by_shortcode(&after[..end])?)));
+        match emoji {
+            Some((end, emoji)) => {
+                expanded.push_str(emoji.as_str());
+                rest = &after[end + 1..];
+            },
+            None => {
+                // The colon may still open a shortcode, like the second one in `at 10:30 :smile:`
+                expanded.push(':');
+                rest = after;
+            },
+        }
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+/// Tells whether a character may appear in a shortcode.
+fn is_shortcode_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '_' | '+' | '-')
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client_emoji::expand_shortcodes;
+
+    #[test]
+    fn test_expand_shortcodes() {
+        assert_eq!(expand_shortcodes("hi :smile:"), "hi 😄");
+        assert_eq!(expand_shortcodes(":+1::tada:!"), "👍🎉!");
+        assert_eq!(expand_shortcodes("at 10:30 :smile:"), "at 10:30 😄");
+        assert_eq!(expand_shortcodes("a :nosuchemoji: b"), "a :nosuchemoji: b");
+        assert_eq!(expand_shortcodes("::"), "::");
+        assert_eq!(expand_shortcodes(":smile"), ":smile");
+        assert_eq!(expand_shortcodes("žluťoučký :heart:"), "žluťoučký ❤️");
+    }
+}
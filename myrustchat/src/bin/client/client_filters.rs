@@ -0,0 +1,193 @@
+use std::collections::BTreeSet;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result};
+use chat::t;
+use chrono::NaiveTime;
+use serde::Deserialize;
+
+/// Notification filters shared by the incoming loop and the commands changing them.
+pub type SharedFilters = Arc<Mutex<NotificationFilters>>;
+
+/// Name of the table of the configuration file holding the filters.
+const TABLE: &str = "notifications";
+
+/// Daily time span in which notifications are held back, like `22:00-07:00`. A span ending before it starts
+/// goes on past midnight.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct DndSchedule {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl DndSchedule {
+    /// Checks whether a time of day is inside the schedule.
+    ///
+    /// # Arguments
+    ///
+    /// * `time` - The local time of day.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - Returns `true` if the time is at or after the start and before the end.
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        match self.start <= self.end {
+            true => self.start <= time && time < self.end,
+            false => time >= self.start || time < self.end,
+        }
+    }
+}
+
+impl FromStr for DndSchedule {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<DndSchedule, String> {
+        let invalid = || t!("invalid-time-span", value = value);
+        let (start, end) = value.split_once('-').ok_or_else(invalid)?;
+        let time = |time: &str| NaiveTime::parse_from_str(time.trim(), "%H:%M").map_err(|_| invalid());
+        Ok(DndSchedule { start: time(start)?, end: time(end)? })
+    }
+}
+
+impl TryFrom<String> for DndSchedule {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<DndSchedule, String> {
+        value.parse()
+    }
+}
+
+impl fmt::Display for DndSchedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.start.format("%H:%M"), self.end.format("%H:%M"))
+    }
+}
+
+/// Rules deciding which received messages are shown and notified, kept in the `[notifications]` table
+/// of the configuration file. There is a single chat room, so senders are all that can be muted.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NotificationFilters {
+    /// Users whose messages, images, files and private messages are not shown
+    #[serde(default)]
+    pub muted: BTreeSet<String>,
+    /// Do not disturb, no desktop notifications are shown while it's on
+    #[serde(default)]
+    pub dnd: bool,
+    /// Daily time span in which do not disturb is on by itself
+    pub dnd_schedule: Option<DndSchedule>,
+}
+
+impl NotificationFilters {
+    /// Checks whether the messages of a user are hidden.
+    ///
+    /// # Arguments
+    ///
+    /// * `sender` - The username of the sender.
+    pub fn is_muted(&self, sender: &str) -> bool {
+        self.muted.contains(sender)
+    }
+
+    /// Checks whether desktop notifications are held back at a time of day.
+    ///
+    /// # Arguments
+    ///
+    /// * `time` - The local time of day.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - Returns `true` if do not disturb is on or the time is inside its schedule.
+    pub fn dnd_at(&self, time: NaiveTime) -> bool {
+        self.dnd || self.dnd_schedule.is_some_and(|schedule| schedule.contains(time))
+    }
+
+    /// Writes the filters to the `[notifications]` table of the configuration file. The rest of the file,
+    /// including comments, is kept as it is, and a missing file is created.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path to the configuration file.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Returns an empty result if the file was written.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => Err(e).with_context(|| t!("could-not-read-config", path = path.display().to_string()))?,
+        };
+        let mut document: toml_edit::DocumentMut = text.parse()
+            .with_context(|| t!("invalid-config", path = path.display().to_string()))?;
+
+        let table = document.entry(TABLE).or_insert(toml_edit::table())
+            .as_table_mut()
+            .with_context(|| t!("not-a-table", table = TABLE, path = path.display().to_string()))?;
+        table["muted"] = toml_edit::value(self.muted.iter().collect::<toml_edit::Array>());
+        table["dnd"] = toml_edit::value(self.dnd);
+        match self.dnd_schedule {
+            Some(schedule) => table["dnd_schedule"] = toml_edit::value(schedule.to_string()),
+            None => {
+                table.remove("dnd_schedule");
+            },
+        }
+
+        if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent)
+                .with_context(|| t!("could-not-create-config-directory", path = parent.display().to_string()))?;
+        }
+        std::fs::write(path, document.to_string())
+            .with_context(|| t!("could-not-write-config", path = path.display().to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveTime;
+
+    use crate::client_filters::{DndSchedule, NotificationFilters};
+    use crate::client_profiles::ProfileFile;
+
+    #[test]
+    fn test_notification_filters() {
+        let time = |value: &str| NaiveTime::parse_from_str(value, "%H:%M").unwrap();
+        let night: DndSchedule = "22:00-07:00".parse().unwrap();
+        assert!(night.contains(time("23:30")) && night.contains(time("06:59")));
+        assert!(!night.contains(time("07:00")) && !night.contains(time("12:00")));
+        let lunch: DndSchedule = "12:00 - 13:00".parse().unwrap();
+        assert!(lunch.contains(time("12:30")) && !lunch.contains(time("13:30")));
+        assert_eq!(lunch.to_string(), "12:00-13:00");
+        assert!("22:00".parse::<DndSchedule>().is_err());
+        assert!("10pm-7am".parse::<DndSchedule>().is_err());
+
+        let mut filters = NotificationFilters { dnd_schedule: Some(night), ..NotificationFilters::default() };
+        filters.muted.insert("Bob".to_string());
+        assert!(filters.is_muted("Bob") && !filters.is_muted("Carol"));
+        assert!(filters.dnd_at(time("23:00")) && !filters.dnd_at(time("12:00")));
+        filters.dnd = true;
+        assert!(filters.dnd_at(time("12:00")));
+
+        // Saving keeps the profiles and the comments of the file
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(&path, "# my servers\ndefault = \"home\"\n\n[profiles.home]\naddress = \"10.0.0.1\"\n").unwrap();
+        filters.save(&path).unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert!(text.starts_with("# my servers\n"));
+        let file = ProfileFile::load(&path).unwrap();
+        assert_eq!(file.profile(None).unwrap().address.as_deref(), Some("10.0.0.1"));
+        assert_eq!(file.notifications(), &filters);
+
+        filters.dnd_schedule = None;
+        filters.save(&path).unwrap();
+        assert_eq!(ProfileFile::load(&path).unwrap().notifications(), &filters);
+
+        let new_file = dir.path().join("new").join("config.toml");
+        NotificationFilters::default().save(&new_file).unwrap();
+        assert_eq!(ProfileFile::load(&new_file).unwrap().notifications(), &NotificationFilters::default());
+    }
+}
@@ -0,0 +1,217 @@
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+use chat::{t, AttachmentKind, ChatMessage, ChatMessageContent, EmptyResult};
+use chrono::{DateTime, Utc};
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+use sqlx::SqlitePool;
+
+use crate::client_theme::MessageLine;
+
+/// A message loaded from the local history.
+pub struct HistoryEntry {
+    /// Time when the message was sent
+    pub timestamp: DateTime<Utc>,
+    /// Username of the sender
+    pub sender: String,
+    /// Username of the recipient of a direct message, `None` for messages sent to everyone
+    pub recipient: Option<String>,
+    /// Text of the message, or a description of the attachment
+    pub text: String,
+}
+
+impl HistoryEntry {
+    /// Prepares the entry for display. The date is shown too, as the history spans several days.
+    ///
+    /// # Returns
+    ///
+    /// * `MessageLine` - Returns the message ready to be printed.
+    pub fn to_message_line(&self) -> MessageLine {
+        MessageLine {
+            time: self.timestamp.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M").to_string(),
+            timestamp: self.timestamp,
+            username: self.sender.clone(),
+            sender: self.sender.clone(),
+            recipient: self.recipient.clone(),
+            text: self.text.clone(),
+            ..MessageLine::default()
+        }
+    }
+}
+
+/// `History` stores all messages sent and received by the client in a local SQLite database,
+/// so that they can be viewed even after the client is restarted.
+#[derive(Clone)]
+pub struct History {
+    db: SqlitePool,
+    /// Username of the logged in user, one database file can hold the history of several accounts
+    account: String,
+}
+
+impl History {
+    /// Opens the history database, creating it if needed.
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - The path to the database file.
+    /// * `account` - The username of the logged in user.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<History>` - Returns the opened history if successful.
+    pub async fn open(file: &str, account: &str) -> Result<History> {
+        let options = SqliteConnectOptions::from_str(format!("sqlite:{file}").as_str())?
+            .create_if_missing(true);
+        let db = SqlitePoolOptions::new()
+            .connect_with(options)
+            .await
+            .with_context(|| t!("could-not-open-history", path = file))?;
+
+        sqlx::query(
+            "
+            CREATE TABLE IF NOT EXISTS history (
+                history_id INTEGER PRIMARY KEY,
+                account TEXT NOT NULL,
+                timestamp TEXT NOT NULL,
+                sender TEXT NOT NULL,
+                recipient TEXT,
+                text TEXT NOT NULL
+            )
+            "
+        ).execute(&db).await
+        .context(t!("could-not-create-history-table"))?;
+
+        Ok(History { db, account: account.to_string() })
+    }
+
+    /// Stores a sent or received message, except ephemeral ones.
+    ///
+    /// # Arguments
+    ///
+    /// * `message` - The chat message.
+    /// * `recipient` - The recipient of a direct message, `None` for messages sent to everyone.
+    ///
+    /// # Returns
+    ///
+    /// * `EmptyResult` - Returns an empty result if successful.
+    pub async fn record(&self, message: &ChatMessage, recipient: Option<&str>) -> EmptyResult {
+        // Ephemeral messages aren't kept, the history would outlive them
+        if message.ttl.is_some() {
+            return Ok(());
+        }
+        let text = match &message.content {
+            ChatMessageContent::Text(text) => text.clone(),
+            ChatMessageContent::Image(_) => describe_attachment(&AttachmentKind::Image),
+            ChatMessageContent::File(filename, _) => describe_attachment(&AttachmentKind::File(filename.clone())),
+            ChatMessageContent::Audio { format, .. } => describe_attachment(&AttachmentKind::Audio(*format)),
+            // The link is already in the recorded message
+            ChatMessageContent::LinkPreview { .. } => return Ok(()),
+        };
+        self.record_text(message.timestamp, &message.sender, recipient, &text).await
+    }
+
+    /// Stores an attachment sent in chunks. Only its description is kept, not the data.
+    ///
+    /// # Arguments
+    ///
+    /// * `sender` - The username of the sender.
+    /// * `kind` - The type of the attachment.
+    ///
+    /// # Returns
+    ///
+    /// * `EmptyResult` - Returns an empty result if successful.
+    pub async fn record_attachment(&self, sender: &str, kind: &AttachmentKind) -> EmptyResult {
+        self.record_text(Utc::now(), sender, None, &describe_attachment(kind)).await
+    }
+
+    /// Stores a line of history.
+    ///
+    /// # Arguments
+    ///
+    /// * `timestamp` - Time when the message was sent.
+    /// * `sender` - The username of the sender.
+    /// * `recipient` - The recipient of a direct message, `None` for messages sent to everyone.
+    /// * `text` - Text of the message, or a description of the attachment.
+    ///
+    /// # Returns
+    ///
+    /// * `EmptyResult` - Returns an empty result if successful.
+    pub async fn record_text(&self, timestamp: DateTime<Utc>, sender: &str, recipient: Option<&str>, text: &str) -> EmptyResult {
+        sqlx::query("INSERT INTO history (account, timestamp, sender, recipient, text) VALUES ($1, $2, $3, $4, $5)")
+            .bind(&self.account).bind(timestamp).bind(sender).bind(recipient).bind(text)
+            .execute(&self.db).await
+            .context(t!("could-not-write-history"))?;
+        Ok(())
+    }
+
+    /// Loads the most recent messages.
+    ///
+    /// # Arguments
+    ///
+    /// * `count` - The maximum number of messages.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Vec<HistoryEntry>>` - Returns the messages from the oldest to the newest.
+    pub async fn last(&self, count: usize) -> Result<Vec<HistoryEntry>> {
+        let rows: Vec<(DateTime<Utc>, String, Option<String>, String)> = sqlx::query_as(
+            "
+            SELECT timestamp, sender, recipient, text FROM history
+            WHERE account=$1 ORDER BY history_id DESC LIMIT $2
+            "
+        ).bind(&self.account).bind(count as i64)
+        .fetch_all(&self.db).await
+        .context(t!("could-not-read-history"))?;
+
+        Ok(rows.into_iter().rev()
+            .map(|(timestamp, sender, recipient, text)| HistoryEntry { timestamp, sender, recipient, text })
+            .collect())
+    }
+}
+
+/// Describes an attachment for the history.
+///
+/// # Arguments
+///
+/// * `kind` - The type of the attachment.
+///
+/// # Returns
+///
+/// * `String` - Returns the description.
+fn describe_attachment(kind: &AttachmentKind) -> String {
+    match kind {
+        AttachmentKind::Image => t!("history-image"),
+        AttachmentKind::File(filename) => t!("history-file", filename = filename.as_str()),
+        AttachmentKind::Audio(_) => t!("history-voice-note"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chat::{ChatMessage, ChatMessageContent};
+
+    use crate::client_history::History;
+
+    #[tokio::test]
+    async fn test_history() {
+        let file = tempfile::tempdir().unwrap().keep().join("history.db");
+        let file = file.as_os_str().to_str().unwrap();
+
+        let history = History::open(file, "Alice").await.unwrap();
+        for (id, text) in ["one", "two", "three"].iter().enumerate() {
+            let message = ChatMessage::new(id as u64, "Bob", ChatMessageContent::Text(text.to_string()));
+            history.record(&message, None).await.unwrap();
+        }
+        history.record_text(chrono::Utc::now(), "Alice", Some("Bob"), "four").await.unwrap();
+
+        let entries = history.last(2).await.unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].text, "three");
+        assert_eq!(entries[1].text, "four");
+        assert_eq!(entries[1].recipient.as_deref(), Some("Bob"));
+
+        // Another account using the same file has its own history
+        let other = History::open(file, "Carol").await.unwrap();
+        assert!(other.last(10).await.unwrap().is_empty());
+    }
+}
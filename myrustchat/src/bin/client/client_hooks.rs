@@ -0,0 +1,207 @@
+use std::path::Path;
+use std::process::Stdio;
+use std::sync::Arc;
+
+use chat::t;
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use crate::client_console::Console;
+
+/// Kind of received content a hook is run for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HookContent {
+    Text,
+    Image,
+    File,
+    Audio,
+    Link,
+}
+
+impl HookContent {
+    /// Returns the name of the content type, as written in the configuration file.
+    pub fn name(&self) -> &'static str {
+        match self {
+            HookContent::Text => "text",
+            HookContent::Image => "image",
+            HookContent::File => "file",
+            HookContent::Audio => "audio",
+            HookContent::Link => "link",
+        }
+    }
+}
+
+/// An external command run for every received message of one content type, kept in the `[[hooks]]` array
+/// of the configuration file.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Hook {
+    /// Content type the command is run for
+    pub content: HookContent,
+    /// Program and its arguments, run without a shell
+    pub command: Vec<String>,
+}
+
+/// A received message handed to the hooks.
+pub struct HookEvent<'a> {
+    pub content: HookContent,
+    /// Username of the user who sent the message
+    pub sender: &'a str,
+    /// When the message was sent
+    pub timestamp: DateTime<Utc>,
+    /// Whether the message was sent only to the user
+    pub direct: bool,
+    /// Text of the message or the URL of a link, written to the standard input of the command
+    pub text: Option<&'a str>,
+    /// Where the received image, file or voice note was saved
+    pub file: Option<&'a Path>,
+}
+
+/// Runs the hooks of the configuration file for the received messages. The commands run in the background,
+/// so a slow one doesn't hold up the chat, and the lines they print are shown in it.
+#[derive(Clone)]
+pub struct Hooks {
+    hooks: Arc<Vec<Hook>>,
+    console: Console,
+}
+
+impl Hooks {
+    /// Creates the runner of the hooks.
+    ///
+    /// # Arguments
+    ///
+    /// * `hooks` - The hooks read from the configuration file.
+    /// * `console` - Where the output and the failures of the commands are shown.
+    ///
+    /// # Returns
+    ///
+    /// * `Hooks` - Returns the runner.
+    pub fn new(hooks: Vec<Hook>, console: Console) -> Hooks {
+        Hooks { hooks: Arc::new(hooks), console }
+    }
+
+    /// Starts the commands of the hooks for the content type of a received message. The details of the message
+    /// are given in the `MYRUSTCHAT_CONTENT`, `MYRUSTCHAT_SENDER`, `MYRUSTCHAT_TIME`, `MYRUSTCHAT_DIRECT` and
+    /// `MYRUSTCHAT_FILE` environment variables.
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The received message.
+    pub fn run(&self, event: &HookEvent) {
+        for hook in self.hooks.iter().filter(|hook| hook.content == event.content) {
+            let Some((program, args)) = hook.command.split_first() else {
+                continue;
+            };
+            let mut command = Command::new(program);
+            command.args(args)
+                .env("MYRUSTCHAT_CONTENT", event.content.name())
+                .env("MYRUSTCHAT_SENDER", event.sender)
+                .env("MYRUSTCHAT_TIME", event.timestamp.to_rfc3339())
+                .env("MYRUSTCHAT_DIRECT", if event.direct { "1" } else { "0" })
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null());
+            if let Some(file) = event.file {
+                command.env("MYRUSTCHAT_FILE", file);
+            }
+            let stdin = event.text.unwrap_or_default().to_string();
+            let program = program.clone();
+            let console = self.console.clone();
+            tokio::spawn(async move {
+                if let Err(e) = run_command(command, stdin, &program, &console).await {
+                    console.error(t!("hook-failed", program = program.as_str(), error = e.to_string()));
+                }
+            });
+        }
+    }
+}
+
+/// Runs a command of a hook to its end and shows what it printed.
+///
+/// # Arguments
+///
+/// * `command` - The command, with the details of the message set.
+/// * `stdin` - What is written to the standard input of the command.
+/// * `program` - The program of the command, shown before its output.
+/// * `console` - Where the output is shown.
+///
+/// # Returns
+///
+/// * `std::io::Result<()>` - Returns an empty result if the command ran successfully.
+async fn run_command(mut command: Command, stdin: String, program: &str, console: &Console) -> std::io::Result<()> {
+    let mut child = command.spawn()?;
+    if let Some(mut input) = child.stdin.take() {
+        // A command which doesn't read its input closes it early, which is fine
+        let _ = input.write_all(stdin.as_bytes()).await;
+    }
+    let output = child.wait_with_output().await?;
+    for line in String::from_utf8_lossy(&output.stdout).lines().filter(|line| !line.trim().is_empty()) {
+        console.print(format!("[{program}] {line}"));
+    }
+    match output.status.success() {
+        true => Ok(()),
+        false => Err(std::io::Error::other(t!("hook-exit-status", status = output.status.to_string()))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::time::Duration;
+
+    use chrono::Utc;
+
+    use crate::client_console::{Console, ConsoleEvent};
+    use crate::client_hooks::{Hook, HookContent, HookEvent, Hooks};
+    use crate::client_theme::{ColorMode, Theme};
+
+    #[test]
+    fn test_hook_config() {
+        #[derive(serde::Deserialize)]
+        struct File {
+            hooks: Vec<Hook>,
+        }
+        let file: File = toml::from_str(
+            r#"
+            [[hooks]]
+            content = "image"
+            command = ["ocr.sh", "--lang", "eng"]
+            "#
+        ).unwrap();
+        assert_eq!(file.hooks, vec![Hook { content: HookContent::Image, command: vec!["ocr.sh".into(), "--lang".into(), "eng".into()] }]);
+        assert!(toml::from_str::<File>("[[hooks]]\ncontent = \"video\"\ncommand = [\"x\"]").is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_run_hooks() {
+        let (console, mut events) = Console::channel(Theme::default(), ColorMode::Never, false);
+        let hook = |content, script: &str| Hook { content, command: vec!["sh".into(), "-c".into(), script.into()] };
+        let hooks = Hooks::new(vec![
+            hook(HookContent::Text, r#"echo "$MYRUSTCHAT_SENDER:$MYRUSTCHAT_DIRECT:$(cat)""#),
+            hook(HookContent::Image, r#"echo "$MYRUSTCHAT_FILE""#),
+            hook(HookContent::Text, "exit 3"),
+        ], console);
+
+        let event = |content, text, file| HookEvent { content, sender: "Bob", timestamp: Utc::now(), direct: false, text, file };
+        hooks.run(&event(HookContent::Text, Some("hello"), None));
+        let mut lines = Vec::new();
+        while lines.len() < 2 {
+            match tokio::time::timeout(Duration::from_secs(5), events.recv()).await.unwrap().unwrap() {
+                ConsoleEvent::Line(line) => lines.push(line),
+                ConsoleEvent::Error(line) => lines.push(line),
+                _ => {},
+            }
+        }
+        lines.sort();
+        assert_eq!(lines, ["Error: hook sh failed: exited with exit status: 3", "[sh] Bob:0:hello"]);
+
+        // Only the hooks of the content type run
+        hooks.run(&event(HookContent::Image, None, Some(Path::new("/tmp/image.png"))));
+        let line = tokio::time::timeout(Duration::from_secs(5), events.recv()).await.unwrap().unwrap();
+        assert!(matches!(line, ConsoleEvent::Line(line) if line == "[sh] /tmp/image.png"));
+    }
+}
@@ -0,0 +1,219 @@
+use std::io::{BufRead, IsTerminal};
+use std::path::PathBuf;
+
+use rustyline::completion::{Completer, FilenameCompleter, Pair};
+use rustyline::config::Config;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{CompletionType, Editor, Helper};
+use chat::t;
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+
+use crate::client_console::Console;
+use crate::KnownUsers;
+
+/// Name of the file in the home directory where the typed lines are kept between sessions.
+const HISTORY_FILE_NAME: &str = ".myrustchat_history";
+
+/// Maximum number of lines kept in the input history.
+const MAX_HISTORY_SIZE: usize = 1000;
+
+/// Commands offered by the tab completion.
+const COMMANDS: &[&str] = &[".msg", ".reply", ".ephemeral", ".file", ".image", ".sticker", ".stickers", ".voice", ".who", ".seen", ".block", ".unblock", ".blocks", ".mute", ".unmute", ".dnd", ".switch", ".stats", ".history", ".get", ".passwd", ".nick", ".status", ".avatar", ".profile", ".kick", ".ban", ".unban", ".announce", ".quit"];
+
+/// Commands whose argument is a username.
+const USER_COMMANDS: &[&str] = &[".msg", ".seen", ".profile", ".block", ".unblock", ".mute", ".unmute", ".kick", ".ban", ".unban"];
+
+/// Commands whose argument is a local file.
+const FILE_COMMANDS: &[&str] = &[".file", ".image", ".voice", ".avatar"];
+
+/// Completes the word before the cursor. The first word is completed as a command, the argument of
+/// `.file`, `.image`, `.voice` and `.avatar` as a local path, and the argument of the user commands or a word starting with `@`
+/// as one of the known usernames.
+///
+/// # Arguments
+///
+/// * `line` - The edited line.
+/// * `pos` - The byte position of the cursor.
+/// * `users` - Usernames known from the server.
+///
+/// # Returns
+///
+/// * `(usize, Vec<Pair>)` - Returns the start of the completed word and the candidates replacing it.
+pub fn complete(line: &str, pos: usize, users: &KnownUsers) -> (usize, Vec<Pair>) {
+    let before = &line[..pos];
+    let start = before.rfind(' ').map(|i| i + 1).unwrap_or(0);
+    let word = &before[start..];
+    let command = before.split_once(' ').map(|(command, _)| command);
+
+    let candidates: Vec<String> = match command {
+        None => COMMANDS.iter()
+            .filter(|c| c.starts_with(word))
+            .map(|c| c.to_string())
+            .collect(),
+        Some(command) if FILE_COMMANDS.contains(&command) => {
+            return FilenameCompleter::new().complete_path(line, pos).unwrap_or((start, Vec::new()));
+        },
+        Some(command) if USER_COMMANDS.contains(&command) && before[command.len()..].trim_start() == word => {
+            users.lock().unwrap().iter()
+                .filter(|u| u.starts_with(word))
+                .cloned()
+                .collect()
+        },
+        Some(_) => match word.strip_prefix('@') {
+            Some(prefix) => users.lock().unwrap().iter()
+                .filter(|u| u.starts_with(prefix))
+                .map(|u| format!("@{u}"))
+                .collect(),
+            None => Vec::new(),
+        },
+    };
+
+    // A completed word is followed by a space, like in a shell
+    let pairs = candidates.into_iter()
+        .map(|c| Pair { replacement: format!("{c} "), display: c })
+        .collect();
+    (start, pairs)
+}
+
+/// Line editor helper providing the tab completion.
+struct InputHelper {
+    users: KnownUsers,
+}
+
+impl Completer for InputHelper {
+    type Candidate = Pair;
+
+    fn complete(&self, line: &str, pos: usize, _ctx: &rustyline::Context<'_>) -> rustyline::Result<(usize, Vec<Pair>)> {
+        Ok(complete(line, pos, &self.users))
+    }
+}
+
+impl Hinter for InputHelper {
+    type Hint = String;
+}
+
+impl Highlighter for InputHelper {}
+
+impl Validator for InputHelper {}
+
+impl Helper for InputHelper {}
+
+/// Returns the path of the input history file, `None` if the home directory is unknown.
+fn history_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE_NAME))
+}
+
+/// Starts a thread reading lines typed by the user with a line editor supporting arrow-key history,
+/// Ctrl-R search, editing and tab completion. The editor blocks, so it can't run on the async runtime.
+/// The input history is persisted only when stdin is a terminal, so piped input doesn't end up in it.
+/// The plain output mode reads plain lines instead, as a screen reader would read out every redraw of the editor.
+///
+/// # Arguments
+///
+/// * `console` - Where errors of the editor are reported.
+/// * `users` - Usernames known from the server, offered by the tab completion.
+///
+/// # Returns
+///
+/// * `UnboundedReceiver<String>` - Returns the receiver of the entered lines. It's closed at the end of input or on Ctrl-C.
+pub fn spawn_line_reader(console: Console, users: KnownUsers) -> UnboundedReceiver<String> {
+    let (lines, lines_rx) = mpsc::unbounded_channel();
+    std::thread::spawn(move || {
+        if console.plain_output() {
+            for line in std::io::stdin().lock().lines() {
+                match line {
+                    Ok(line) => if lines.send(line).is_err() {
+                        return;
+                    },
+                    Err(e) => {
+                        console.error(t!("could-not-read-stdin", error = e.to_string()));
+                        return;
+                    }
+                }
+            }
+            return;
+        }
+
+        let config = Config::builder()
+            .max_history_size(MAX_HISTORY_SIZE).expect("Invalid history size.")
+            .auto_add_history(false)
+            .completion_type(CompletionType::List)
+            .build();
+        let mut editor = match Editor::<InputHelper, DefaultHistory>::with_config(config) {
+            Ok(editor) => editor,
+            Err(e) => {
+                console.error(t!("could-not-read-stdin", error = e.to_string()));
+                return;
+            }
+        };
+
+        editor.set_helper(Some(InputHelper { users }));
+
+        let history_file = history_path().filter(|_| std::io::stdin().is_terminal());
+        if let Some(path) = &history_file {
+            // The file doesn't exist before the first session
+            let _ = editor.load_history(path);
+        }
+
+        loop {
+            match editor.readline("") {
+                Ok(line) => {
+                    // Passwords must not be written to the history file
+                    if !line.trim().is_empty() && !line.starts_with(".passwd") {
+                        let _ = editor.add_history_entry(line.as_str());
+                        if let Some(path) = &history_file {
+                            if let Err(e) = editor.append_history(path) {
+                                console.error(t!("could-not-write-input-history", path = path.display().to_string(), error = e.to_string()));
+                            }
+                        }
+                    }
+                    if lines.send(line).is_err() {
+                        return;
+                    }
+                },
+                Err(ReadlineError::Eof | ReadlineError::Interrupted) => return,
+                Err(e) => {
+                    console.error(t!("could-not-read-stdin", error = e.to_string()));
+                    return;
+                }
+            }
+        }
+    });
+    lines_rx
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client_input::complete;
+    use crate::KnownUsers;
+
+    fn replacements(line: &str, users: &KnownUsers) -> (usize, Vec<String>) {
+        let (start, pairs) = complete(line, line.len(), users);
+        (start, pairs.into_iter().map(|p| p.display).collect())
+    }
+
+    #[test]
+    fn test_complete() {
+        let users = KnownUsers::default();
+        users.lock().unwrap().extend(["Alice".to_string(), "Bob".to_string(), "Bobby".to_string()]);
+
+        assert_eq!(replacements(".q", &users), (0, vec![".quit".to_string()]));
+        assert_eq!(complete(".q", 2, &users).1[0].replacement, ".quit ");
+        assert_eq!(replacements(".h", &users), (0, vec![".history".to_string()]));
+        assert_eq!(replacements(".msg B", &users), (5, vec!["Bob".to_string(), "Bobby".to_string()]));
+        assert_eq!(replacements(".msg Bob hi", &users), (9, vec![]));
+        assert_eq!(replacements("hello @A", &users), (6, vec!["@Alice".to_string()]));
+        assert_eq!(replacements("hello A", &users), (6, vec![]));
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("picture.png"), b"").unwrap();
+        let line = format!(".image {}/pic", dir.path().display());
+        let (_, candidates) = complete(&line, line.len(), &users);
+        assert_eq!(candidates.len(), 1);
+        assert_eq!(candidates[0].replacement, format!("{}/picture.png", dir.path().display()));
+    }
+}
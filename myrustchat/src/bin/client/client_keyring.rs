@@ -0,0 +1,97 @@
+use anyhow::Result;
+use chat::t;
+
+/// Name under which the passwords are stored in the keyring.
+const KEYRING_SERVICE: &str = "myrustchat";
+
+/// Password of a user of a server, stored in the keyring of the operating system: the Keychain on macOS,
+/// the Credential Manager on Windows and the kernel keyring on Linux, which keeps it until the user logs out.
+pub struct SavedPassword {
+    entry: keyring::Entry,
+}
+
+impl SavedPassword {
+    /// Opens the keyring entry of a user of a server, whether a password is stored in it or not.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - The username of the user.
+    /// * `server` - The address and port or the Unix socket of the server.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<SavedPassword>` - Returns the entry, or an error if the keyring is not available.
+    pub fn new(username: &str, server: &str) -> Result<SavedPassword> {
+        Ok(SavedPassword { entry: keyring::Entry::new(KEYRING_SERVICE, &format!("{username}@{server}"))? })
+    }
+
+    /// Reads the stored password.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<String>>` - Returns the password, `None` if none is stored.
+    pub fn get(&self) -> Result<Option<String>> {
+        match self.entry.get_password() {
+            Ok(password) => Ok(Some(password)),
+            Err(keyring::Error::NoEntry) => Ok(None),
+            Err(e) => Err(e)?,
+        }
+    }
+
+    /// Stores the password, replacing the stored one.
+    ///
+    /// # Arguments
+    ///
+    /// * `password` - The password to be stored.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Returns an empty result if successful.
+    pub fn set(&self, password: &str) -> Result<()> {
+        Ok(self.entry.set_password(password)?)
+    }
+
+    /// Removes the stored password, doing nothing if none is stored.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Returns an empty result if successful.
+    pub fn delete(&self) -> Result<()> {
+        match self.entry.delete_credential() {
+            Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+            Err(e) => Err(e)?,
+        }
+    }
+}
+
+/// What the client does with the keyring after logging in.
+pub enum KeyringUse {
+    /// The keyring isn't used
+    None,
+    /// The password was read from the keyring, it's removed if the server rejects it
+    Loaded(SavedPassword),
+    /// The password is stored in the keyring once the login succeeds
+    Save(SavedPassword),
+}
+
+impl KeyringUse {
+    /// Updates the keyring after a login attempt. A failure of the keyring doesn't fail the login, it's only reported.
+    ///
+    /// # Arguments
+    ///
+    /// * `password` - The password used to log in.
+    /// * `accepted` - Whether the server accepted the password.
+    pub fn update(&self, password: &str, accepted: bool) {
+        match (self, accepted) {
+            (KeyringUse::Save(saved), true) => match saved.set(password) {
+                Ok(()) => println!("{}", t!("keyring-password-saved")),
+                Err(e) => eprintln!("{}", t!("keyring-save-failed", error = e.to_string())),
+            },
+            (KeyringUse::Loaded(saved), false) => match saved.delete() {
+                Ok(()) => eprintln!("{}", t!("keyring-password-rejected")),
+                Err(e) => eprintln!("{}", t!("keyring-remove-rejected-failed", error = e.to_string())),
+            },
+            _ => {},
+        }
+    }
+}
@@ -0,0 +1,293 @@
+use std::sync::OnceLock;
+
+use syntect::easy::HighlightLines;
+use syntect::highlighting::{FontStyle, ThemeSet};
+use syntect::parsing::SyntaxSet;
+
+/// Color scheme of code blocks, one of the schemes bundled with syntect.
+const CODE_THEME: &str = "base16-ocean.dark";
+
+/// How a part of a rendered message is shown. The client theme turns it into ANSI codes or a TUI style.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TextStyle {
+    pub bold: bool,
+    pub italic: bool,
+    /// Inline code, shown in the code color of the theme
+    pub code: bool,
+    /// Color of a highlighted token in a code block
+    pub rgb: Option<(u8, u8, u8)>,
+}
+
+/// A line of a rendered message, made of differently styled parts.
+pub type StyledLine = Vec<(String, TextStyle)>;
+
+/// Inline markup found at the start of the remaining text of a line.
+enum Markup<'a> {
+    /// Text shown as it is, e.g. an escaped character or unmatched backticks
+    Text(&'a str),
+    Code(&'a str),
+    /// Text between emphasis markers with the style they give it
+    Emphasis(&'a str, TextStyle),
+}
+
+/// Renders the basic Markdown of a text message: `**bold**`, `*italics*`, `` `inline code` `` and fenced
+/// code blocks, which are highlighted if their language is known. Everything else is shown as typed.
+///
+/// # Arguments
+///
+/// * `text` - The text of the message.
+///
+/// # Returns
+///
+/// * `Vec<StyledLine>` - Returns the lines of the message, at least one.
+pub fn render(text: &str) -> Vec<StyledLine> {
+    let mut lines = Vec::new();
+    let mut code_block: Option<HighlightLines> = None;
+
+    for line in text.lines() {
+        // Like in CommonMark, the language of a backtick fence can't contain backticks,
+        // so a line like ```code``` is inline code
+        if let Some(language) = line.trim_start().strip_prefix("```").filter(|language| !language.contains('`')) {
+            code_block = match code_block {
+                Some(_) => None,
+                None => Some(highlighter(language.trim())),
+            };
+            continue;
+        }
+        match &mut code_block {
+            Some(highlighter) => lines.push(highlight(highlighter, line)),
+            None => {
+                let mut spans = Vec::new();
+                render_inline(line, TextStyle::default(), &mut spans);
+                lines.push(spans);
+            },
+        }
+    }
+
+    // A message made only of fences, like a lone ```, is shown as typed
+    if lines.is_empty() {
+        lines.push(vec![(text.to_string(), TextStyle::default())]);
+    }
+    lines
+}
+
+/// Creates the highlighter of a code block. The syntax definitions are loaded when they're needed the first time.
+///
+/// # Arguments
+///
+/// * `language` - The language given after the opening fence, e.g. `rust`, may be empty.
+///
+/// # Returns
+///
+/// * `HighlightLines<'static>` - Returns the highlighter, of plain text if the language isn't known.
+fn highlighter(language: &str) -> HighlightLines<'static> {
+    static THEMES: OnceLock<ThemeSet> = OnceLock::new();
+    let syntaxes = syntaxes();
+    let syntax = syntaxes.find_syntax_by_token(language)
+        .unwrap_or_else(|| syntaxes.find_syntax_plain_text());
+    let theme = &THEMES.get_or_init(ThemeSet::load_defaults).themes[CODE_THEME];
+    HighlightLines::new(syntax, theme)
+}
+
+/// Returns the syntax definitions bundled with syntect.
+fn syntaxes() -> &'static SyntaxSet {
+    static SYNTAXES: OnceLock<SyntaxSet> = OnceLock::new();
+    SYNTAXES.get_or_init(SyntaxSet::load_defaults_newlines)
+}
+
+/// Highlights a line of a code block.
+///
+/// # Arguments
+///
+/// * `highlighter` - The highlighter of the code block, it keeps the state between the lines.
+/// * `line` - The line of code.
+///
+/// # Returns
+///
+/// * `StyledLine` - Returns the highlighted parts of the line.
+fn highlight(highlighter: &mut HighlightLines, line: &str) -> StyledLine {
+    // The bundled syntaxes expect lines with their line ending
+    match highlighter.highlight_line(&format!("{line}\n"), syntaxes()) {
+        Ok(regions) => regions.into_iter()
+            .map(|(style, text)| (text.trim_end_matches('\n').to_string(), TextStyle {
+                bold: style.font_style.contains(FontStyle::BOLD),
+                italic: style.font_style.contains(FontStyle::ITALIC),
+                code: true,
+                rgb: Some((style.foreground.r, style.foreground.g, style.foreground.b)),
+            }))
+            .filter(|(text, _)| !text.is_empty())
+            .collect(),
+        // A broken syntax definition shouldn't hide the code
+        Err(_) => vec![(line.to_string(), TextStyle { code: true, ..TextStyle::default() })],
+    }
+}
+
+/// Renders the inline markup of a line.
+///
+/// # Arguments
+///
+/// * `text` - The text to be rendered.
+/// * `style` - The style of the text outside of any markup, emphasis can be nested.
+/// * `spans` - The parts of the line the rendered text is appended to.
+fn render_inline(text: &str, style: TextStyle, spans: &mut StyledLine) {
+    let mut plain = String::new();
+    let mut previous = None;
+    let mut rest = text;
+
+    while let Some(c) = rest.chars().next() {
+        let Some((markup, after)) = markup(rest, previous, style) else {
+            plain.push(c);
+            previous = Some(c);
+            rest = &rest[c.len_utf8()..];
+            continue;
+        };
+        match markup {
+            Markup::Text(text) => plain.push_str(text),
+            Markup::Code(code) => {
+                flush(&mut plain, style, spans);
+                spans.push((code.to_string(), TextStyle { code: true, ..style }));
+            },
+            Markup::Emphasis(inner, inner_style) => {
+                flush(&mut plain, style, spans);
+                render_inline(inner, inner_style, spans);
+            },
+        }
+        previous = rest[..rest.len() - after.len()].chars().last();
+        rest = after;
+    }
+    flush(&mut plain, style, spans);
+}
+
+/// Appends the collected plain text to the parts of the line.
+fn flush(plain: &mut String, style: TextStyle, spans: &mut StyledLine) {
+    if !plain.is_empty() {
+        spans.push((std::mem::take(plain), style));
+    }
+}
+
+/// Recognizes inline markup at the start of the text.
+///
+/// # Arguments
+///
+/// * `rest` - The remaining text of the line.
+/// * `previous` - The character before it, underscores inside words like snake_case aren't emphasis.
+/// * `style` - The style of the surrounding text.
+///
+/// # Returns
+///
+/// * `Option<(Markup, &str)>` - Returns the markup and the text after it, `None` if there's no markup.
+fn markup<'a>(rest: &'a str, previous: Option<char>, style: TextStyle) -> Option<(Markup<'a>, &'a str)> {
+    let marker = rest.chars().next()?;
+    match marker {
+        '\\' => {
+            let escaped = rest[1..].chars().next().filter(char::is_ascii_punctuation)?;
+            Some((Markup::Text(&rest[1..1 + escaped.len_utf8()]), &rest[1 + escaped.len_utf8()..]))
+        },
+        '`' => {
+            // The code ends with a run of as many backticks as it starts with
+            let fence = rest.chars().take_while(|&c| c == '`').count();
+            let after = &rest[fence..];
+            let mut offset = 0;
+            while let Some(start) = after[offset..].find('`').map(|start| offset + start) {
+                let run = after[start..].chars().take_while(|&c| c == '`').count();
+                if run == fence {
+                    return Some((Markup::Code(after[..start].trim()), &after[start + fence..]));
+                }
+                offset = start + run;
+            }
+            Some((Markup::Text(&rest[..fence]), after))
+        },
+        '*' | '_' => {
+            let double = rest[1..].starts_with(marker);
+            let delimiter = &rest[..if double { 2 } else { 1 }];
+            let after = &rest[delimiter.len()..];
+            if after.starts_with(char::is_whitespace) || (marker == '_' && previous.is_some_and(char::is_alphanumeric)) {
+                return None;
+            }
+
+            let mut offset = 0;
+            while let Some(end) = after[offset..].find(delimiter).map(|end| offset + end) {
+                let inner = &after[..end];
+                let next = after[end + delimiter.len()..].chars().next();
+                let inside_word = marker == '_' && next.is_some_and(char::is_alphanumeric);
+                let closes = !inner.is_empty()
+                    && !inner.ends_with(char::is_whitespace)
+                    // The closing delimiter is the last one of a run, like in **bold *both***
+                    && next != Some(marker)
+                    && (double || !inner.ends_with(marker))
+                    && !inside_word;
+                if closes {
+                    let inner_style = match double {
+                        true => TextStyle { bold: true, ..style },
+                        false => TextStyle { italic: true, ..style },
+                    };
+                    return Some((Markup::Emphasis(inner, inner_style), &after[end + delimiter.len()..]));
+                }
+                offset = end + 1;
+            }
+            None
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client_markdown::{render, TextStyle};
+
+    /// Renders a single line and returns its parts with their styles simplified to `b`, `i`, `c` and `bi`.
+    fn parts(text: &str) -> Vec<(String, &'static str)> {
+        let lines = render(text);
+        assert_eq!(lines.len(), 1);
+        lines[0].iter().map(|(text, style)| {
+            let kind = match (style.bold, style.italic, style.code) {
+                (false, false, false) => "",
+                (true, false, false) => "b",
+                (false, true, false) => "i",
+                (true, true, false) => "bi",
+                (_, _, true) => "c",
+            };
+            (text.clone(), kind)
+        }).collect()
+    }
+
+    fn owned(parts: &[(&str, &'static str)]) -> Vec<(String, &'static str)> {
+        parts.iter().map(|(text, kind)| (text.to_string(), *kind)).collect()
+    }
+
+    #[test]
+    fn test_render_inline() {
+        assert_eq!(parts("plain text"), owned(&[("plain text", "")]));
+        assert_eq!(parts("a **bold** and *italic* _word_"),
+                   owned(&[("a ", ""), ("bold", "b"), (" and ", ""), ("italic", "i"), (" ", ""), ("word", "i")]));
+        assert_eq!(parts("**bold *both***"), owned(&[("bold ", "b"), ("both", "bi")]));
+        assert_eq!(parts("run `cargo test` now"), owned(&[("run ", ""), ("cargo test", "c"), (" now", "")]));
+        assert_eq!(parts("``a ` b``"), owned(&[("a ` b", "c")]));
+        assert_eq!(parts("```let x = 1;```"), owned(&[("let x = 1;", "c")]));
+
+        // Things which only look like markup are kept as typed
+        assert_eq!(parts("2 * 3 * 4"), owned(&[("2 * 3 * 4", "")]));
+        assert_eq!(parts("snake_case_name"), owned(&[("snake_case_name", "")]));
+        assert_eq!(parts("unclosed `tick and **star"), owned(&[("unclosed `tick and **star", "")]));
+        assert_eq!(parts(r"\*not italic\*"), owned(&[("*not italic*", "")]));
+    }
+
+    #[test]
+    fn test_render_code_block() {
+        let lines = render("look:\n```rust\nfn main() {}\n```\ndone");
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], vec![("look:".to_string(), TextStyle::default())]);
+        let code: String = lines[1].iter().map(|(text, _)| text.as_str()).collect();
+        assert_eq!(code, "fn main() {}");
+        assert!(lines[1].iter().all(|(_, style)| style.code && style.rgb.is_some()));
+        // The keyword gets a different color than the name of the function
+        assert_ne!(lines[1].first().unwrap().1.rgb, lines[1].iter().find(|(text, _)| text == "main").unwrap().1.rgb);
+        assert_eq!(lines[2], vec![("done".to_string(), TextStyle::default())]);
+
+        // An unknown language and a missing closing fence still show the code
+        let lines = render("```nosuchlanguage\n*not markup*");
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0][0].0, "*not markup*");
+        assert_eq!(render("```"), vec![vec![("```".to_string(), TextStyle::default())]]);
+    }
+}
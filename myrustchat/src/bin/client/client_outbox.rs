@@ -0,0 +1,125 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use chat::{Datagram, MessageId};
+
+/// Outbox shared by the command loop, which queues messages while the client is offline,
+/// and the connection task, which sends them once the client has logged in again.
+pub type SharedOutbox = Arc<Mutex<Outbox>>;
+
+/// Chat messages typed while the connection with the server is down. They're sent in the typed order
+/// after the client has logged in again, instead of being lost or failing the command loop.
+#[derive(Default)]
+pub struct Outbox {
+    offline: bool,
+    queue: VecDeque<Datagram>,
+}
+
+impl Outbox {
+    /// Returns whether messages can be sent right away.
+    pub fn is_online(&self) -> bool {
+        !self.offline
+    }
+
+    /// Marks the client as offline, later messages are queued until the outbox is emptied by `take_next`.
+    pub fn go_offline(&mut self) {
+        self.offline = true;
+    }
+
+    /// Queues a message until the client is online again.
+    ///
+    /// # Arguments
+    ///
+    /// * `datagram` - The message, a `Datagram::Message` or a `Datagram::DirectMessage`.
+    ///
+    /// # Returns
+    ///
+    /// * `usize` - Returns the number of pending messages.
+    pub fn push(&mut self, datagram: Datagram) -> usize {
+        self.queue.push_back(datagram);
+        self.queue.len()
+    }
+
+    /// Puts back messages which couldn't be sent, they stay the first ones to be sent.
+    /// The client is offline again, as the connection broke.
+    ///
+    /// # Arguments
+    ///
+    /// * `datagrams` - The messages taken by `take_all`.
+    pub fn put_back(&mut self, datagrams: Vec<Datagram>) {
+        self.offline = true;
+        for datagram in datagrams.into_iter().rev() {
+            self.queue.push_front(datagram);
+        }
+    }
+
+    /// Takes all pending messages, to be sent together. The client is online again in the same step,
+    /// the caller queues them for sending before releasing the outbox so that no message typed
+    /// in the meantime overtakes them.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<Datagram>` - Returns the messages to be sent, oldest first.
+    pub fn take_all(&mut self) -> Vec<Datagram> {
+        self.offline = false;
+        self.queue.drain(..).collect()
+    }
+
+    /// Returns the number of pending messages.
+    pub fn pending(&self) -> usize {
+        self.queue.len()
+    }
+}
+
+/// Returns the ID of a queued message, which the server acknowledges once the message is sent.
+///
+/// # Arguments
+///
+/// * `datagram` - The queued message.
+///
+/// # Returns
+///
+/// * `Option<MessageId>` - Returns the ID, `None` for other datagrams.
+pub fn message_id(datagram: &Datagram) -> Option<MessageId> {
+    match datagram {
+        Datagram::Message(message) | Datagram::DirectMessage { message, .. } => Some(message.id),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chat::{ChatMessage, ChatMessageContent, Datagram};
+
+    use crate::client_outbox::{message_id, Outbox};
+
+    fn text(id: u64) -> Datagram {
+        Datagram::Message(ChatMessage::new(id, "Bob", ChatMessageContent::Text(format!("message {id}"))))
+    }
+
+    #[test]
+    fn test_outbox() {
+        let mut outbox = Outbox::default();
+        assert!(outbox.is_online());
+        assert!(outbox.take_all().is_empty());
+
+        outbox.go_offline();
+        assert_eq!(outbox.push(text(1)), 1);
+        assert_eq!(outbox.push(text(2)), 2);
+
+        // The messages come out in the typed order, failed ones are sent first again
+        let ids = |datagrams: &[Datagram]| datagrams.iter().map(message_id).collect::<Vec<_>>();
+        let taken = outbox.take_all();
+        assert_eq!(ids(&taken), vec![Some(1), Some(2)]);
+        assert!(outbox.is_online());
+        outbox.put_back(taken);
+        assert!(!outbox.is_online());
+        assert_eq!(outbox.push(text(3)), 3);
+        assert_eq!(ids(&outbox.take_all()), vec![Some(1), Some(2), Some(3)]);
+
+        // The client is online once the outbox is empty
+        assert!(outbox.is_online());
+        assert_eq!(outbox.pending(), 0);
+        assert_eq!(message_id(&Datagram::Ping), None);
+    }
+}
@@ -0,0 +1,155 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use chat::t;
+use serde::Deserialize;
+
+use crate::client_filters::NotificationFilters;
+use crate::client_hooks::Hook;
+use crate::client_stickers::StickerConfig;
+
+/// A named server connection in the client configuration file. Flags given on the command line take precedence.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    /// Address of the server
+    pub address: Option<String>,
+    /// Port of the server
+    pub port: Option<u16>,
+    /// Unix socket of the server, used instead of the address and port
+    pub unix_socket: Option<PathBuf>,
+    /// Username to log in with
+    pub username: Option<String>,
+    /// Saved password, the client asks for it when it's not set
+    pub password: Option<String>,
+}
+
+/// Content of the client configuration file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProfileFile {
+    /// Name of the profile used when none is chosen with `--profile`
+    default: Option<String>,
+    #[serde(default)]
+    profiles: HashMap<String, Profile>,
+    #[serde(default)]
+    notifications: NotificationFilters,
+    #[serde(default)]
+    hooks: Vec<Hook>,
+    #[serde(default)]
+    stickers: StickerConfig,
+}
+
+impl ProfileFile {
+    /// Returns the default location of the configuration file, `~/.config/myrustchat/config.toml` on Linux.
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("myrustchat").join("config.toml"))
+    }
+
+    /// Reads the profiles from a TOML file.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path to the configuration file.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<ProfileFile>` - Returns the parsed profiles if successful.
+    pub fn load(path: &Path) -> Result<ProfileFile> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| t!("could-not-read-config", path = path.display().to_string()))?;
+        toml::from_str(&text)
+            .with_context(|| t!("invalid-config", path = path.display().to_string()))
+    }
+
+    /// Selects a profile.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the profile, `None` for the default one.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Profile>` - Returns the profile, an empty one if no name is given and there is no default profile.
+    pub fn profile(&self, name: Option<&str>) -> Result<Profile> {
+        match name.or(self.default.as_deref()) {
+            Some(name) => self.profiles.get(name).cloned().ok_or_else(|| anyhow!(t!("no-such-profile", name = name))),
+            None => Ok(Profile::default()),
+        }
+    }
+
+    /// Returns the notification filters saved in the `[notifications]` table.
+    pub fn notifications(&self) -> &NotificationFilters {
+        &self.notifications
+    }
+
+    /// Returns the commands run for received messages, saved in the `[[hooks]]` array.
+    pub fn hooks(&self) -> &[Hook] {
+        &self.hooks
+    }
+
+    /// Returns the sticker directory, saved in the `[stickers]` table.
+    pub fn stickers(&self) -> &StickerConfig {
+        &self.stickers
+    }
+}
+
+/// Checks whether a file can be read by other users than its owner, which a file with a saved password shouldn't.
+///
+/// # Arguments
+///
+/// * `path` - The path to the file.
+///
+/// # Returns
+///
+/// * `bool` - Returns `true` if other users can read the file.
+#[cfg(unix)]
+pub fn readable_by_others(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::metadata(path).is_ok_and(|metadata| metadata.permissions().mode() & 0o044 != 0)
+}
+
+/// Checks whether a file can be read by other users than its owner, which isn't known on this platform.
+#[cfg(not(unix))]
+pub fn readable_by_others(_path: &Path) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client_profiles::{Profile, ProfileFile};
+
+    #[test]
+    fn test_profiles() {
+        let file: ProfileFile = toml::from_str(
+            r#"
+            default = "home"
+
+            [profiles.home]
+            address = "192.168.1.10"
+            username = "alice"
+
+            [profiles.work]
+            address = "chat.example.com"
+            port = 12345
+            username = "asmith"
+            password = "secret"
+            "#
+        ).unwrap();
+
+        let home = file.profile(None).unwrap();
+        assert_eq!(home.address.as_deref(), Some("192.168.1.10"));
+        assert_eq!(home.port, None);
+        assert_eq!(home.password, None);
+
+        let work = file.profile(Some("work")).unwrap();
+        assert_eq!(work.port, Some(12345));
+        assert_eq!(work.password.as_deref(), Some("secret"));
+
+        assert!(file.profile(Some("school")).is_err());
+        // Without a default, no profile is used unless one is chosen
+        assert_eq!(ProfileFile::default().profile(None).unwrap(), Profile::default());
+        assert!(toml::from_str::<ProfileFile>("[profiles.home]\nhost = \"x\"").is_err());
+    }
+}
@@ -0,0 +1,183 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use chat::{t, MessageId, MessageSeq, ReplyTo};
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+/// Number of recent messages which can be replied to and quoted.
+const RECENT_MESSAGES: usize = 1000;
+
+/// Maximum number of terminal columns of the original message quoted above a reply.
+const QUOTE_LENGTH: usize = 60;
+
+/// Recent text messages shared by the incoming loop and the command loop.
+pub type SharedRecent = Arc<Mutex<RecentMessages>>;
+
+/// A text message remembered for replies.
+struct RecentMessage {
+    /// Number shown next to the message and used by `.reply`, `None` for messages of the user
+    number: Option<u64>,
+    sender: String,
+    id: MessageId,
+    /// Sequence number of a received message, by which it expires, `None` for messages of the user
+    seq: Option<MessageSeq>,
+    /// Whether the message was sent with a `ttl`
+    expiring: bool,
+    text: String,
+}
+
+/// The most recent text messages of the session. Received messages are numbered, so that the user can reply
+/// to them with `.reply <number>`, as message IDs are only unique per sender. Messages sent by the user
+/// aren't numbered, but they're remembered to quote them above the replies of others.
+#[derive(Default)]
+pub struct RecentMessages {
+    messages: VecDeque<RecentMessage>,
+    last_number: u64,
+}
+
+impl RecentMessages {
+    /// Remembers a received text message.
+    ///
+    /// # Arguments
+    ///
+    /// * `sender` - The username of the sender.
+    /// * `id` - The ID of the message.
+    /// * `seq` - The sequence number of the message, `None` if it isn't stored.
+    /// * `expiring` - Whether the message was sent with a `ttl`.
+    /// * `text` - The text of the message.
+    ///
+    /// # Returns
+    ///
+    /// * `u64` - Returns the number of the message.
+    pub fn add(&mut self, sender: &str, id: MessageId, seq: Option<MessageSeq>, expiring: bool, text: &str) -> u64 {
+        self.last_number += 1;
+        self.push(RecentMessage { number: Some(self.last_number), sender: sender.to_string(), id, seq, expiring, text: text.to_string() });
+        self.last_number
+    }
+
+    /// Remembers a text message sent by the user.
+    ///
+    /// # Arguments
+    ///
+    /// * `sender` - The username of the user.
+    /// * `id` - The ID of the message.
+    /// * `expiring` - Whether the message was sent with a `ttl`.
+    /// * `text` - The text of the message.
+    pub fn add_own(&mut self, sender: &str, id: MessageId, expiring: bool, text: &str) {
+        self.push(RecentMessage { number: None, sender: sender.to_string(), id, seq: None, expiring, text: text.to_string() });
+    }
+
+    fn push(&mut self, message: RecentMessage) {
+        if self.messages.len() == RECENT_MESSAGES {
+            self.messages.pop_front();
+        }
+        self.messages.push_back(message);
+    }
+
+    /// Finds the message a reply to the message with the given number refers to.
+    ///
+    /// # Arguments
+    ///
+    /// * `number` - The number shown next to the message.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<ReplyTo>` - Returns the reference to the message, `None` if there is no such recent message.
+    pub fn reply_to(&self, number: u64) -> Option<ReplyTo> {
+        self.messages.iter()
+            .find(|message| message.number == Some(number))
+            .map(|message| ReplyTo { sender: message.sender.clone(), id: message.id })
+    }
+
+    /// Forgets an ephemeral message which expired, so that it can't be replied to or quoted anymore.
+    /// Message IDs are reused by reconnected clients, so received messages are found by their sequence number.
+    /// The user's own messages aren't sent back by the server, they're found by their ID.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - The username of the user.
+    /// * `sender` - The username of the sender.
+    /// * `id` - The ID of the message.
+    /// * `seq` - The sequence number of the message, `None` from servers older than protocol 1.3.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<Option<u64>>` - Returns the number of the message, `Some(None)` for a message of the user,
+    ///   or `None` if it isn't a recent ephemeral message.
+    pub fn expire(&mut self, username: &str, sender: &str, id: MessageId, seq: Option<MessageSeq>) -> Option<Option<u64>> {
+        let received = |message: &RecentMessage| seq.is_some() && message.seq == seq;
+        let own = |message: &RecentMessage| sender == username && message.number.is_none() && message.id == id;
+        let index = self.messages.iter().position(|message| message.expiring && received(message))
+            .or_else(|| self.messages.iter().rposition(|message| message.expiring && own(message)))?;
+        self.messages.remove(index).map(|message| message.number)
+    }
+
+    /// Formats the quote of the original message shown above a reply, like `> Alice: original text`.
+    ///
+    /// # Arguments
+    ///
+    /// * `reply_to` - The reference to the original message.
+    ///
+    /// # Returns
+    ///
+    /// * `String` - Returns the quote, without the text if the original message isn't known.
+    pub fn quote(&self, reply_to: &ReplyTo) -> String {
+        // The newest message wins if a sender reconnected and reused an ID
+        let original = self.messages.iter().rev()
+            .find(|message| message.sender == reply_to.sender && message.id == reply_to.id);
+        match original {
+            Some(original) if original.text.width() > QUOTE_LENGTH => {
+                let mut columns = 0;
+                let text: String = original.text.chars()
+                    .take_while(|c| {
+                        columns += c.width().unwrap_or(0);
+                        columns <= QUOTE_LENGTH
+                    })
+                    .collect();
+                format!("> {}: {}…", reply_to.sender, text.trim_end())
+            },
+            Some(original) => format!("> {}: {}", reply_to.sender, original.text),
+            None => format!("> {}: {}", reply_to.sender, t!("earlier-message")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chat::ReplyTo;
+
+    use crate::client_replies::RecentMessages;
+
+    #[test]
+    fn test_recent_messages() {
+        let mut recent = RecentMessages::default();
+        assert_eq!(recent.add("Alice", 1, Some(7), true, "hello"), 1);
+        recent.add_own("Bob", 1, true, "hi Alice");
+        assert_eq!(recent.add("Carol", 1, Some(8), false, &"long ".repeat(20)), 2);
+        assert_eq!(recent.add("Dave", 1, None, false, &"😄".repeat(40)), 3);
+
+        // Messages with the same ID are told apart by their senders
+        let alice = ReplyTo { sender: "Alice".to_string(), id: 1 };
+        assert_eq!(recent.reply_to(1), Some(alice.clone()));
+        assert_eq!(recent.reply_to(4), None);
+        assert_eq!(recent.quote(&alice), "> Alice: hello");
+        assert_eq!(recent.quote(&ReplyTo { sender: "Bob".to_string(), id: 1 }), "> Bob: hi Alice");
+        assert!(recent.quote(&recent.reply_to(2).unwrap()).ends_with("long…"));
+        // Emoji take two columns each
+        assert_eq!(recent.quote(&recent.reply_to(3).unwrap()), format!("> Dave: {}…", "😄".repeat(30)));
+        assert_eq!(recent.quote(&ReplyTo { sender: "Alice".to_string(), id: 9 }), "> Alice: (an earlier message)");
+
+        // Only ephemeral messages expire, a message which stays isn't mistaken for one with the same ID
+        assert_eq!(recent.add("Alice", 1, Some(9), false, "hello again"), 4);
+        assert_eq!(recent.expire("Bob", "Alice", 1, Some(7)), Some(Some(1)));
+        assert_eq!(recent.expire("Bob", "Alice", 1, Some(7)), None);
+        assert_eq!(recent.expire("Bob", "Carol", 1, Some(8)), None);
+        assert_eq!(recent.reply_to(1), None);
+        assert_eq!(recent.reply_to(4), Some(alice.clone()));
+        assert_eq!(recent.quote(&alice), "> Alice: hello again");
+
+        // The user's own messages are found by their ID
+        assert_eq!(recent.expire("Bob", "Bob", 1, Some(10)), Some(None));
+        assert_eq!(recent.quote(&ReplyTo { sender: "Bob".to_string(), id: 1 }), "> Bob: (an earlier message)");
+    }
+}
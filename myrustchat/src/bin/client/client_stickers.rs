@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use anyhow::{anyhow, Context, Result};
+use chat::t;
+use serde::Deserialize;
+
+/// Extensions of the image files offered as stickers.
+const STICKER_EXTENSIONS: &[&str] = &["png", "jpg", "jpeg", "gif", "webp", "bmp"];
+
+/// The `[stickers]` table of the configuration file.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StickerConfig {
+    /// Directory with the images, each sticker is named after its file without the extension
+    pub dir: Option<PathBuf>,
+}
+
+/// A sticker encoded as PNG, with the modification time of its file when it was encoded.
+struct CachedSticker {
+    modified: Option<SystemTime>,
+    png: Vec<u8>,
+}
+
+/// The stickers of the sticker directory. Every sticker is encoded as PNG once and kept for the later sends,
+/// until its file changes.
+pub struct Stickers {
+    dir: Option<PathBuf>,
+    cache: HashMap<String, CachedSticker>,
+}
+
+impl Stickers {
+    /// Creates the sticker pack of the configured directory, nothing is read yet.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The `[stickers]` table of the configuration file.
+    ///
+    /// # Returns
+    ///
+    /// * `Stickers` - Returns the sticker pack.
+    pub fn new(config: &StickerConfig) -> Stickers {
+        Stickers { dir: config.dir.clone(), cache: HashMap::new() }
+    }
+
+    /// Returns the sticker directory, or an error telling how to configure it.
+    fn dir(&self) -> Result<&Path> {
+        self.dir.as_deref().ok_or_else(|| anyhow!(t!("no-sticker-dir")))
+    }
+
+    /// Lists the stickers with the paths of their images, sorted by name. Of several images with the same name
+    /// but different extensions, the first in the order of `STICKER_EXTENSIONS` is used.
+    fn files(&self) -> Result<Vec<(String, PathBuf)>> {
+        let dir = self.dir()?;
+        let entries = std::fs::read_dir(dir)
+            .with_context(|| t!("could-not-read-sticker-dir", path = dir.display().to_string()))?;
+        let mut files: Vec<(String, usize, PathBuf)> = entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.is_file())
+            .filter_map(|path| {
+                let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+                let rank = STICKER_EXTENSIONS.iter().position(|known| *known == extension)?;
+                Some((path.file_stem()?.to_str()?.to_string(), rank, path))
+            })
+            .collect();
+        files.sort();
+        files.dedup_by(|later, first| later.0 == first.0);
+        Ok(files.into_iter().map(|(name, _, path)| (name, path)).collect())
+    }
+
+    /// Returns the names of the available stickers, sorted.
+    pub fn names(&self) -> Result<Vec<String>> {
+        Ok(self.files()?.into_iter().map(|(name, _)| name).collect())
+    }
+
+    /// Returns a sticker encoded as PNG, from the cache if its file didn't change since it was encoded.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the sticker.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Vec<u8>>` - Returns the PNG data, or an error if there is no such sticker or it isn't a valid image.
+    pub async fn load(&mut self, name: &str) -> Result<Vec<u8>> {
+        let path = self.files()?.into_iter()
+            .find_map(|(sticker, path)| (sticker == name).then_some(path))
+            .ok_or_else(|| anyhow!(t!("no-such-sticker", name = name)))?;
+        let modified = std::fs::metadata(&path).and_then(|metadata| metadata.modified()).ok();
+        if let Some(cached) = self.cache.get(name).filter(|cached| cached.modified.is_some() && cached.modified == modified) {
+            return Ok(cached.png.clone());
+        }
+        let (png, _) = crate::read_image_data(&path.to_string_lossy(), false).await?;
+        self.cache.insert(name.to_string(), CachedSticker { modified, png: png.clone() });
+        Ok(png)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::{ImageFormat, RgbImage};
+
+    use crate::client_stickers::{StickerConfig, Stickers};
+
+    fn write_image(path: &std::path::Path, format: ImageFormat) {
+        let mut data = Vec::new();
+        RgbImage::new(4, 4).write_to(&mut Cursor::new(&mut data), format).unwrap();
+        std::fs::write(path, data).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stickers() {
+        let dir = tempfile::tempdir().unwrap();
+        write_image(&dir.path().join("thumbsup.png"), ImageFormat::Png);
+        write_image(&dir.path().join("party.bmp"), ImageFormat::Bmp);
+        write_image(&dir.path().join("party.jpg"), ImageFormat::Jpeg);
+        std::fs::write(dir.path().join("notes.txt"), "not a sticker").unwrap();
+        std::fs::create_dir(dir.path().join("old.png")).unwrap();
+
+        let mut stickers = Stickers::new(&StickerConfig { dir: Some(dir.path().to_path_buf()) });
+        assert_eq!(stickers.names().unwrap(), vec!["party", "thumbsup"]);
+
+        // The JPEG is preferred to the BMP and converted to PNG, then kept
+        let party = stickers.load("party").await.unwrap();
+        assert_eq!(image::guess_format(&party).unwrap(), ImageFormat::Png);
+        assert!(stickers.cache.contains_key("party"));
+        assert_eq!(stickers.load("party").await.unwrap(), party);
+
+        assert!(stickers.load("notes").await.is_err());
+        assert!(stickers.load("../thumbsup").await.is_err());
+        assert!(Stickers::new(&StickerConfig::default()).names().is_err());
+    }
+}
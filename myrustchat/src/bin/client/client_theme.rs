@@ -0,0 +1,696 @@
+use std::io::IsTerminal;
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use chat::{t, MessageSeq, UserStatus};
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use ratatui::style::{Color, Modifier, Style};
+use serde::Deserialize;
+
+use crate::client_console::Attachment;
+use crate::client_markdown::{self, StyledLine, TextStyle};
+
+/// When to use colors in the output.
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum ColorMode {
+    /// colors if the output is a terminal and `NO_COLOR` isn't set
+    #[default]
+    Auto,
+    /// always use colors
+    Always,
+    /// never use colors
+    Never,
+}
+
+impl ColorMode {
+    /// Decides whether colors are used.
+    ///
+    /// # Arguments
+    ///
+    /// * `terminal` - Whether the output is a terminal.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - Returns `true` if the output should be colored.
+    pub fn enabled(&self, terminal: bool) -> bool {
+        match self {
+            ColorMode::Auto => terminal && std::env::var_os("NO_COLOR").is_none(),
+            ColorMode::Always => true,
+            ColorMode::Never => false,
+        }
+    }
+
+    /// Decides whether colors are used for stdout.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - Returns `true` if stdout should be colored.
+    pub fn enabled_for_stdout(&self) -> bool {
+        self.enabled(std::io::stdout().is_terminal())
+    }
+}
+
+/// A color usable in a theme, one of the 16 basic terminal colors.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ThemeColor {
+    Black,
+    Red,
+    Green,
+    Yellow,
+    Blue,
+    Magenta,
+    Cyan,
+    White,
+    Gray,
+    BrightRed,
+    BrightGreen,
+    BrightYellow,
+    BrightBlue,
+    BrightMagenta,
+    BrightCyan,
+    BrightWhite,
+}
+
+impl ThemeColor {
+    /// Returns the ANSI escape code setting this color as the foreground.
+    fn ansi_code(&self) -> u8 {
+        match self {
+            ThemeColor::Black => 30,
+            ThemeColor::Red => 31,
+            ThemeColor::Green => 32,
+            ThemeColor::Yellow => 33,
+            ThemeColor::Blue => 34,
+            ThemeColor::Magenta => 35,
+            ThemeColor::Cyan => 36,
+            ThemeColor::White => 37,
+            ThemeColor::Gray => 90,
+            ThemeColor::BrightRed => 91,
+            ThemeColor::BrightGreen => 92,
+            ThemeColor::BrightYellow => 93,
+            ThemeColor::BrightBlue => 94,
+            ThemeColor::BrightMagenta => 95,
+            ThemeColor::BrightCyan => 96,
+            ThemeColor::BrightWhite => 97,
+        }
+    }
+
+    /// Returns the matching color of the terminal user interface.
+    fn tui_color(&self) -> Color {
+        match self {
+            ThemeColor::Black => Color::Black,
+            ThemeColor::Red => Color::Red,
+            ThemeColor::Green => Color::Green,
+            ThemeColor::Yellow => Color::Yellow,
+            ThemeColor::Blue => Color::Blue,
+            ThemeColor::Magenta => Color::Magenta,
+            ThemeColor::Cyan => Color::Cyan,
+            ThemeColor::White => Color::Gray,
+            ThemeColor::Gray => Color::DarkGray,
+            ThemeColor::BrightRed => Color::LightRed,
+            ThemeColor::BrightGreen => Color::LightGreen,
+            ThemeColor::BrightYellow => Color::LightYellow,
+            ThemeColor::BrightBlue => Color::LightBlue,
+            ThemeColor::BrightMagenta => Color::LightMagenta,
+            ThemeColor::BrightCyan => Color::LightCyan,
+            ThemeColor::BrightWhite => Color::White,
+        }
+    }
+}
+
+/// A chat message prepared for display.
+#[derive(Default)]
+pub struct MessageLine {
+    /// Formatted time when the message was sent
+    pub time: String,
+    /// Time when the message was sent, for the JSON output
+    pub timestamp: DateTime<Utc>,
+    /// Username of the sender, `sender` may show the nickname too
+    pub username: String,
+    /// Shown name of the sender
+    pub sender: String,
+    /// Recipient of a direct message
+    pub recipient: Option<String>,
+    /// Number of the message for `.reply`, `None` for messages which can't be replied to
+    pub number: Option<u64>,
+    pub text: String,
+    /// Whether the message mentions the user
+    pub mention: bool,
+    /// Whether the message was posted to the announcement channel
+    pub announcement: bool,
+    /// Sequence number given by the server, to find an ephemeral message again when it expires
+    pub seq: Option<MessageSeq>,
+    /// Number of seconds after which the message expires, `None` if it stays
+    pub ttl: Option<u32>,
+    /// Account which received the message, shown when the client is logged in to several
+    pub account: Option<String>,
+    /// Attachment of the message, which the text describes
+    pub attachment: Option<Attachment>,
+}
+
+/// Colors of the client output, read from a TOML file. Every key is optional.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Theme {
+    /// Palette of sender names, every sender always gets the same color from it
+    pub senders: Vec<ThemeColor>,
+    /// Color of messages mentioning the user, which are also bold
+    pub mention: ThemeColor,
+    /// Color of announcements, which are also bold and marked
+    pub announcement: ThemeColor,
+    /// Color of errors
+    pub error: ThemeColor,
+    /// Color of inline code in messages
+    pub code: ThemeColor,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Theme {
+            senders: vec![
+                ThemeColor::Cyan,
+                ThemeColor::Green,
+                ThemeColor::Magenta,
+                ThemeColor::Blue,
+                ThemeColor::BrightCyan,
+                ThemeColor::BrightGreen,
+                ThemeColor::BrightMagenta,
+                ThemeColor::BrightBlue,
+            ],
+            mention: ThemeColor::Yellow,
+            announcement: ThemeColor::BrightRed,
+            error: ThemeColor::Red,
+            code: ThemeColor::BrightYellow,
+        }
+    }
+}
+
+impl Theme {
+    /// Reads the theme from a TOML file.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path to the theme file.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Theme>` - Returns the parsed theme if successful.
+    pub fn load(path: &Path) -> Result<Theme> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| t!("could-not-read-theme", path = path.display().to_string()))?;
+        let theme: Theme = toml::from_str(&text)
+            .with_context(|| t!("invalid-theme", path = path.display().to_string()))?;
+        if theme.senders.is_empty() {
+            return Err(anyhow!(t!("empty-palette", path = path.display().to_string())));
+        }
+        Ok(theme)
+    }
+
+    /// Picks the color of a sender. The color is derived from a hash of the name,
+    /// so it's the same in every session.
+    ///
+    /// # Arguments
+    ///
+    /// * `sender` - The username of the sender.
+    ///
+    /// # Returns
+    ///
+    /// * `ThemeColor` - Returns the color of the sender.
+    pub fn sender_color(&self, sender: &str) -> ThemeColor {
+        // FNV-1a, the hash of the standard library isn't guaranteed to be stable between releases
+        let hash = sender.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        });
+        self.senders[(hash % self.senders.len() as u64) as usize]
+    }
+
+    /// Picks the color which highlights the whole text of a message. Announcements stand out more than mentions.
+    ///
+    /// # Arguments
+    ///
+    /// * `message` - The message.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<ThemeColor>` - Returns the color, `None` for ordinary messages.
+    fn highlight(&self, message: &MessageLine) -> Option<ThemeColor> {
+        match (message.announcement, message.mention) {
+            (true, _) => Some(self.announcement),
+            (false, true) => Some(self.mention),
+            (false, false) => None,
+        }
+    }
+
+    /// Formats a message for the plain mode, with ANSI colors if `colored` is set.
+    /// Markdown is rendered only with colors, otherwise the text is shown as typed.
+    ///
+    /// # Arguments
+    ///
+    /// * `message` - The message to be formatted.
+    /// * `colored` - Whether to add ANSI colors.
+    /// * `markdown` - Whether to render the Markdown of the text.
+    ///
+    /// # Returns
+    ///
+    /// * `String` - Returns the formatted line.
+    pub fn format(&self, message: &MessageLine, colored: bool, markdown: bool) -> String {
+        let sender = match &message.recipient {
+            Some(recipient) => format!("{} -> {recipient}", message.sender),
+            None => message.sender.clone(),
+        };
+        let number = message.number.map(|number| format!("#{number} ")).unwrap_or_default();
+        let account = message.account.as_ref().map(|account| format!("[{account}] ")).unwrap_or_default();
+        if !colored {
+            return format!("{account}[{}] {number}[{sender}] {}{}{}", message.time, announcement_marker(message), ephemeral_marker(message), message.text);
+        }
+
+        let sender_code = self.sender_color(&message.sender).ansi_code();
+        let highlight = self.highlight(message);
+        let marker = match message.announcement {
+            true => format!("\x1b[1;{}m{}\x1b[0m ", self.announcement.ansi_code(), t!("marker-announcement")),
+            false => String::new(),
+        };
+        let ephemeral = match ephemeral_marker(message) {
+            marker if marker.is_empty() => marker,
+            marker => format!("\x1b[2m{}\x1b[0m ", marker.trim_end()),
+        };
+        let text = text_lines(message, markdown).iter()
+            .map(|line| line.iter().map(|(part, style)| match self.ansi_codes(*style, highlight) {
+                codes if codes.is_empty() => part.clone(),
+                codes => format!("\x1b[{codes}m{part}\x1b[0m"),
+            }).collect::<String>())
+            .collect::<Vec<_>>()
+            .join("\n");
+        format!("{account}\x1b[2m[{}]\x1b[0m {number}[\x1b[{sender_code}m{sender}\x1b[0m] {marker}{ephemeral}{text}", message.time)
+    }
+
+    /// Formats an error for the plain mode.
+    ///
+    /// # Arguments
+    ///
+    /// * `line` - The error message.
+    ///
+    /// # Returns
+    ///
+    /// * `String` - Returns the error in the error color.
+    pub fn format_error(&self, line: &str) -> String {
+        format!("\x1b[{}m{line}\x1b[0m", self.error.ansi_code())
+    }
+
+    /// Styles the parts of a message for the terminal user interface.
+    ///
+    /// # Arguments
+    ///
+    /// * `message` - The message to be styled.
+    /// * `markdown` - Whether to render the Markdown of the text.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<(String, Style)>` - Returns the parts of the message with their styles, lines of the text are separated by newlines.
+    pub fn styled(&self, message: &MessageLine, markdown: bool) -> Vec<(String, Style)> {
+        let sender = match &message.recipient {
+            Some(recipient) => format!("{} -> {recipient}", message.sender),
+            None => message.sender.clone(),
+        };
+        let number = message.number.map(|number| format!("#{number} ")).unwrap_or_default();
+        let account = message.account.as_ref().map(|account| format!("[{account}] ")).unwrap_or_default();
+        let mut parts = vec![
+            (account, Style::default()),
+            (format!("[{}]", message.time), Style::default().add_modifier(Modifier::DIM)),
+            (format!(" {number}["), Style::default()),
+            (sender, Style::default().fg(self.sender_color(&message.sender).tui_color())),
+            ("] ".to_string(), Style::default()),
+        ];
+        if message.announcement {
+            parts.push((announcement_marker(message), Style::default().fg(self.announcement.tui_color()).add_modifier(Modifier::BOLD)));
+        }
+        if message.ttl.is_some() {
+            parts.push((ephemeral_marker(message), Style::default().add_modifier(Modifier::DIM)));
+        }
+        let highlight = self.highlight(message);
+        for (i, line) in text_lines(message, markdown).into_iter().enumerate() {
+            if i > 0 {
+                parts.push(("\n".to_string(), Style::default()));
+            }
+            parts.extend(line.into_iter().map(|(part, style)| (part, self.tui_style(style, highlight))));
+        }
+        parts
+    }
+
+    /// Builds the ANSI codes of a part of a message text.
+    ///
+    /// # Arguments
+    ///
+    /// * `style` - The style of the part.
+    /// * `highlight` - The color highlighting the whole message, which also makes it bold.
+    ///
+    /// # Returns
+    ///
+    /// * `String` - Returns the codes separated by semicolons, empty for unstyled text.
+    fn ansi_codes(&self, style: TextStyle, highlight: Option<ThemeColor>) -> String {
+        let mut codes = Vec::new();
+        if style.bold || highlight.is_some() {
+            codes.push("1".to_string());
+        }
+        if style.italic {
+            codes.push("3".to_string());
+        }
+        match style.rgb {
+            Some((r, g, b)) => codes.push(format!("38;2;{r};{g};{b}")),
+            None if style.code => codes.push(self.code.ansi_code().to_string()),
+            None => codes.extend(highlight.map(|color| color.ansi_code().to_string())),
+        }
+        codes.join(";")
+    }
+
+    /// Converts the style of a part of a message text for the terminal user interface.
+    ///
+    /// # Arguments
+    ///
+    /// * `style` - The style of the part.
+    /// * `highlight` - The color highlighting the whole message, which also makes it bold.
+    ///
+    /// # Returns
+    ///
+    /// * `Style` - Returns the style of the part.
+    fn tui_style(&self, style: TextStyle, highlight: Option<ThemeColor>) -> Style {
+        let mut tui_style = Style::default();
+        if style.bold || highlight.is_some() {
+            tui_style = tui_style.add_modifier(Modifier::BOLD);
+        }
+        if style.italic {
+            tui_style = tui_style.add_modifier(Modifier::ITALIC);
+        }
+        match style.rgb {
+            Some((r, g, b)) => tui_style.fg(Color::Rgb(r, g, b)),
+            None if style.code => tui_style.fg(self.code.tui_color()),
+            None => match highlight {
+                Some(color) => tui_style.fg(color.tui_color()),
+                None => tui_style,
+            },
+        }
+    }
+
+    /// Returns the style of errors in the terminal user interface.
+    pub fn error_style(&self) -> Style {
+        Style::default().fg(self.error.tui_color())
+    }
+
+    /// Styles the list of online users for the terminal user interface, with the usernames in the colors
+    /// of their messages and the status lines dimmed.
+    ///
+    /// # Arguments
+    ///
+    /// * `heading` - The line in front of the users.
+    /// * `users` - The online users with their status lines.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<(String, Style)>` - Returns the parts of the list with their styles.
+    pub fn styled_users(&self, heading: &str, users: &[UserStatus]) -> Vec<(String, Style)> {
+        let mut parts = vec![(heading.to_string(), Style::default())];
+        for (i, user) in users.iter().enumerate() {
+            let separator = if i == 0 { " " } else { ", " };
+            parts.push((separator.to_string(), Style::default()));
+            parts.push((user.username.clone(), Style::default().fg(self.sender_color(&user.username).tui_color())));
+            if let Some(status) = &user.status {
+                parts.push((format!(" ({})", one_line(status)), Style::default().add_modifier(Modifier::DIM)));
+            }
+        }
+        parts
+    }
+
+    /// Styles an entry of the user list of the TUI, the username in its color followed by the dimmed status line.
+    ///
+    /// # Arguments
+    ///
+    /// * `user` - The online user with their status line.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<(String, Style)>` - Returns the parts of the entry with their styles.
+    pub fn styled_user(&self, user: &UserStatus) -> Vec<(String, Style)> {
+        let mut parts = vec![(user.username.clone(), Style::default().fg(self.sender_color(&user.username).tui_color()))];
+        if let Some(status) = &user.status {
+            parts.push((format!(" {}", one_line(status)), Style::default().add_modifier(Modifier::DIM)));
+        }
+        parts
+    }
+}
+
+/// Formats the online users with their status lines in parentheses, e.g. `alice (at lunch), bob`.
+///
+/// # Arguments
+///
+/// * `users` - The online users with their status lines.
+///
+/// # Returns
+///
+/// * `String` - Returns the formatted list.
+pub fn format_users(users: &[UserStatus]) -> String {
+    users.iter()
+        .map(|user| match &user.status {
+            Some(status) => format!("{} ({})", user.username, one_line(status)),
+            None => user.username.clone(),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Formats a message for the plain output mode of screen readers and log scrapers: always a single line
+/// without colors, with the text as typed and a marker in front if it mentions the user.
+///
+/// # Arguments
+///
+/// * `message` - The message to be formatted.
+///
+/// # Returns
+///
+/// * `String` - Returns the formatted line.
+pub fn format_plain(message: &MessageLine) -> String {
+    let sender = match &message.recipient {
+        Some(recipient) => format!("{} -> {recipient}", message.sender),
+        None => message.sender.clone(),
+    };
+    let number = message.number.map(|number| format!("#{number} ")).unwrap_or_default();
+    let account = message.account.as_ref().map(|account| format!("[{account}] ")).unwrap_or_default();
+    let mention = match message.mention {
+        true => format!("{} ", t!("marker-mention")),
+        false => String::new(),
+    };
+    format!("{account}[{}] {number}[{sender}] {}{}{mention}{}", message.time, announcement_marker(message), ephemeral_marker(message), one_line(&message.text))
+}
+
+/// Returns the marker put in front of an announcement, followed by a space, or nothing for other messages.
+fn announcement_marker(message: &MessageLine) -> String {
+    match message.announcement {
+        true => format!("{} ", t!("marker-announcement")),
+        false => String::new(),
+    }
+}
+
+/// Returns the marker telling when an ephemeral message expires, followed by a space, or nothing for other messages.
+fn ephemeral_marker(message: &MessageLine) -> String {
+    match message.ttl {
+        Some(ttl) => format!("{} ", t!("marker-ephemeral", ttl = format_ttl(ttl))),
+        None => String::new(),
+    }
+}
+
+/// Formats the time to live of a message in the largest unit which divides it, e.g. `90 s`, `5 min` or `2 h`.
+///
+/// # Arguments
+///
+/// * `ttl` - The time to live in seconds.
+///
+/// # Returns
+///
+/// * `String` - Returns the formatted time.
+pub fn format_ttl(ttl: u32) -> String {
+    match ttl {
+        ttl if ttl > 0 && ttl % 86400 == 0 => format!("{} d", ttl / 86400),
+        ttl if ttl > 0 && ttl % 3600 == 0 => format!("{} h", ttl / 3600),
+        ttl if ttl > 0 && ttl % 60 == 0 => format!("{} min", ttl / 60),
+        ttl => format!("{ttl} s"),
+    }
+}
+
+/// Puts a text on a single line, so that every line of the output stands on its own. Line breaks become `\n` and
+/// backslashes `\\`, so that the text can be restored. Other control characters are dropped, so that no one can
+/// sneak terminal escape codes into the output.
+///
+/// # Arguments
+///
+/// * `text` - The text.
+///
+/// # Returns
+///
+/// * `String` - Returns the text on one line.
+pub fn one_line(text: &str) -> String {
+    let mut line = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => line.push_str("\\\\"),
+            '\n' => line.push_str("\\n"),
+            '\t' => line.push(' '),
+            c if c.is_control() => {},
+            c => line.push(c),
+        }
+    }
+    line
+}
+
+/// Splits the text of a message into styled lines.
+///
+/// # Arguments
+///
+/// * `message` - The message.
+/// * `markdown` - Whether to render the Markdown of the text, otherwise it's a single unstyled line.
+///
+/// # Returns
+///
+/// * `Vec<StyledLine>` - Returns the lines of the text.
+fn text_lines(message: &MessageLine, markdown: bool) -> Vec<StyledLine> {
+    match markdown {
+        true => client_markdown::render(&message.text),
+        false => vec![vec![(message.text.clone(), TextStyle::default())]],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chat::UserStatus;
+    use ratatui::style::Modifier;
+
+    use crate::client_theme::{format_plain, format_ttl, format_users, one_line, MessageLine, Theme, ThemeColor};
+
+    #[test]
+    fn test_theme() {
+        let theme: Theme = toml::from_str(r#"senders = ["red", "bright_blue"]"#).unwrap();
+        assert_eq!(theme.senders, vec![ThemeColor::Red, ThemeColor::BrightBlue]);
+        assert_eq!(theme.mention, ThemeColor::Yellow);
+        assert!(toml::from_str::<Theme>(r#"mention = "pink""#).is_err());
+        assert!(toml::from_str::<Theme>(r#"colour = "red""#).is_err());
+
+        let theme = Theme::default();
+        assert_eq!(theme.sender_color("Alice"), theme.sender_color("Alice"));
+
+        let mut message = MessageLine {
+            time: "12:00".to_string(),
+            sender: "Alice".to_string(),
+            recipient: Some("you".to_string()),
+            number: None,
+            text: "hi @Bob".to_string(),
+            mention: false,
+            account: None,
+            ..MessageLine::default()
+        };
+        assert_eq!(theme.format(&message, false, true), "[12:00] [Alice -> you] hi @Bob");
+        message.mention = true;
+        let colored = theme.format(&message, true, true);
+        assert!(colored.starts_with("\x1b[2m[12:00]\x1b[0m"));
+        assert!(colored.ends_with("\x1b[1;33mhi @Bob\x1b[0m"));
+
+        message.number = Some(3);
+        message.mention = false;
+        assert_eq!(theme.format(&message, false, true), "[12:00] #3 [Alice -> you] hi @Bob");
+
+        // Markdown is rendered only with colors and unless it's turned off
+        message.text = "a **b**".to_string();
+        assert_eq!(theme.format(&message, false, true), "[12:00] #3 [Alice -> you] a **b**");
+        assert!(theme.format(&message, true, true).ends_with("] a \x1b[1mb\x1b[0m"));
+        assert!(theme.format(&message, true, false).ends_with("] a **b**"));
+        let parts = theme.styled(&message, true);
+        assert_eq!(parts.last().unwrap().0, "b");
+
+        // Messages of several accounts tell which one received them
+        message.account = Some("work".to_string());
+        assert_eq!(theme.format(&message, false, true), "[work] [12:00] #3 [Alice -> you] a **b**");
+        assert!(theme.format(&message, true, true).starts_with("[work] \x1b[2m[12:00]"));
+        assert_eq!(theme.styled(&message, true)[0].0, "[work] ");
+        assert!(parts.last().unwrap().1.add_modifier.contains(Modifier::BOLD));
+    }
+
+    #[test]
+    fn test_announcement() {
+        let theme = Theme::default();
+        let message = MessageLine {
+            time: "12:00".to_string(),
+            sender: "admin".to_string(),
+            text: "maintenance at 18:00".to_string(),
+            announcement: true,
+            ..MessageLine::default()
+        };
+        assert_eq!(theme.format(&message, false, true), "[12:00] [admin] [announcement] maintenance at 18:00");
+        assert_eq!(format_plain(&message), "[12:00] [admin] [announcement] maintenance at 18:00");
+        let colored = theme.format(&message, true, true);
+        assert!(colored.ends_with("\x1b[1;91m[announcement]\x1b[0m \x1b[1;91mmaintenance at 18:00\x1b[0m"), "{colored:?}");
+
+        // The marker has a part of its own in the terminal user interface
+        let parts = theme.styled(&message, true);
+        assert_eq!(parts[parts.len() - 2].0, "[announcement] ");
+        assert_eq!(parts.last().unwrap().1.fg, Some(ratatui::style::Color::LightRed));
+        assert_eq!(toml::from_str::<Theme>(r#"announcement = "magenta""#).unwrap().announcement, ThemeColor::Magenta);
+    }
+
+    #[test]
+    fn test_ephemeral() {
+        let theme = Theme::default();
+        let message = MessageLine {
+            time: "12:00".to_string(),
+            sender: "Alice".to_string(),
+            number: Some(2),
+            text: "the door code is 1234".to_string(),
+            ttl: Some(300),
+            ..MessageLine::default()
+        };
+        assert_eq!(theme.format(&message, false, true), "[12:00] #2 [Alice] [expires in 5 min] the door code is 1234");
+        assert_eq!(format_plain(&message), "[12:00] #2 [Alice] [expires in 5 min] the door code is 1234");
+        assert!(theme.format(&message, true, true).ends_with("\x1b[2m[expires in 5 min]\x1b[0m the door code is 1234"));
+        let parts = theme.styled(&message, true);
+        assert_eq!(parts[parts.len() - 2].0, "[expires in 5 min] ");
+        assert!(parts[parts.len() - 2].1.add_modifier.contains(Modifier::DIM));
+
+        assert_eq!(format_ttl(90), "90 s");
+        assert_eq!(format_ttl(7200), "2 h");
+        assert_eq!(format_ttl(7 * 86400), "7 d");
+    }
+
+    #[test]
+    fn test_format_plain() {
+        let mut message = MessageLine {
+            time: "12:00".to_string(),
+            sender: "Alice".to_string(),
+            recipient: None,
+            number: Some(3),
+            text: "**hi** @Bob\nsee C:\\temp\x1b[31m".to_string(),
+            mention: true,
+            account: Some("work".to_string()),
+            ..MessageLine::default()
+        };
+        assert_eq!(format_plain(&message), "[work] [12:00] #3 [Alice] [mention] **hi** @Bob\\nsee C:\\\\temp[31m");
+        message.mention = false;
+        message.text = "a\tb".to_string();
+        assert!(format_plain(&message).ends_with("[Alice] a b"));
+        assert_eq!(one_line("plain"), "plain");
+    }
+
+    #[test]
+    fn test_users() {
+        let users = [
+            UserStatus { username: "alice".to_string(), status: Some("at lunch".to_string()) },
+            UserStatus { username: "bob".to_string(), status: None },
+        ];
+        assert_eq!(format_users(&users), "alice (at lunch), bob");
+        assert_eq!(format_users(&[]), "");
+
+        let theme = Theme::default();
+        let parts = theme.styled_users("Online users (2):", &users);
+        let text: String = parts.iter().map(|(text, _)| text.as_str()).collect();
+        assert_eq!(text, "Online users (2): alice (at lunch), bob");
+        assert_eq!(parts[2].1.fg, Some(theme.sender_color("alice").tui_color()));
+        assert!(parts[3].1.add_modifier.contains(Modifier::DIM));
+        assert_eq!(parts[5].1.fg, Some(theme.sender_color("bob").tui_color()));
+    }
+}
@@ -0,0 +1,545 @@
+use std::collections::HashMap;
+
+use anyhow::Context;
+use chat::{t, EmptyResult, MessageSeq, UserStatus};
+use crossterm::event::{Event, EventStream, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use futures::StreamExt;
+use ratatui::layout::{Constraint, Layout, Position};
+use ratatui::style::{Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Borders, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+use tokio::sync::mpsc::UnboundedReceiver;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+use crate::client_console::{pending_messages, ConsoleEvent};
+use crate::client_input::complete;
+use crate::client_theme::{self, Theme};
+use crate::{ChatContext, KnownUsers, UserCommand};
+
+/// A line displayed in the message pane, made of differently styled parts.
+type PaneLine = Vec<(String, Style)>;
+
+/// Width of the user list next to the message pane, in terminal columns with the borders.
+const USER_LIST_WIDTH: u16 = 24;
+
+/// Narrowest terminal which shows the user list, narrower ones leave the whole width to the messages.
+const MIN_WIDTH_WITH_USER_LIST: u16 = 60;
+
+/// State of the terminal user interface.
+struct App {
+    username: String,
+    /// Name of the active account when the client is logged in to several, whose users are listed
+    account: Option<String>,
+    /// Usernames offered by the tab completion
+    users: KnownUsers,
+    /// Colors of the message pane, `None` if colors are disabled
+    theme: Option<Theme>,
+    /// Whether the Markdown of messages is rendered
+    markdown: bool,
+    lines: Vec<PaneLine>,
+    /// Lines of the ephemeral messages, by the account which received them and the sequence number of the message
+    ephemeral: HashMap<(Option<String>, MessageSeq), usize>,
+    /// Online users with their status lines, by the account which listed them
+    online: HashMap<Option<String>, Vec<UserStatus>>,
+    input: String,
+    /// Position of the cursor in the input, in characters
+    cursor: usize,
+    /// Number of rows the message pane is scrolled up from the bottom
+    scroll: usize,
+    /// Height of the message pane in the last drawn frame, used for paging
+    pane_height: usize,
+    /// Progress of the current file transfer, shown in the status bar
+    progress: Option<String>,
+    connection: Connection,
+    /// Number of messages waiting for the client to reconnect, shown in the status bar
+    pending: usize,
+    quit: bool,
+}
+
+/// State of the connection with the server, shown in the status bar.
+#[derive(PartialEq)]
+enum Connection {
+    Connected,
+    /// The connection broke and the client is logging in again, typed messages are queued meanwhile
+    Reconnecting,
+    /// The connection is closed for good
+    Disconnected,
+}
+
+impl App {
+    /// Creates the UI state for the given user.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - The name of the logged in user.
+    /// * `account` - The name of the active account when the client is logged in to several.
+    /// * `users` - Usernames offered by the tab completion.
+    /// * `theme` - Colors of the message pane, `None` if colors are disabled.
+    /// * `markdown` - Whether to render the Markdown of messages.
+    ///
+    /// # Returns
+    ///
+    /// * `App` - Returns the initial UI state.
+    fn new(username: &str, account: Option<String>, users: KnownUsers, theme: Option<Theme>, markdown: bool) -> App {
+        App {
+            username: username.to_string(),
+            account,
+            users,
+            theme,
+            markdown,
+            lines: Vec::new(),
+            ephemeral: HashMap::new(),
+            online: HashMap::new(),
+            input: String::new(),
+            cursor: 0,
+            scroll: 0,
+            pane_height: 0,
+            progress: None,
+            connection: Connection::Connected,
+            pending: 0,
+            quit: false,
+        }
+    }
+
+    /// Appends output of the client to the message pane. Transfer progress is shown in the status bar instead.
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The output to be displayed.
+    fn push(&mut self, event: ConsoleEvent) {
+        let error_style = self.theme.as_ref().map(Theme::error_style).unwrap_or_default();
+        let line = match event {
+            ConsoleEvent::Line(text) => vec![(text, Style::default())],
+            ConsoleEvent::Message(message) => {
+                if let (Some(seq), Some(_)) = (message.seq, message.ttl) {
+                    self.ephemeral.insert((message.account.clone(), seq), self.lines.len());
+                }
+                match &self.theme {
+                    Some(theme) => theme.styled(&message, self.markdown),
+                    None => vec![(Theme::default().format(&message, false, false), Style::default())],
+                }
+            },
+            ConsoleEvent::Users { account, heading, users } => {
+                let line = heading.map(|heading| match &self.theme {
+                    Some(theme) => theme.styled_users(&heading, &users),
+                    None => vec![(format!("{heading} {}", client_theme::format_users(&users)), Style::default())],
+                });
+                self.online.insert(account, users);
+                match line {
+                    Some(line) => line,
+                    None => return,
+                }
+            },
+            ConsoleEvent::Presence { account, username, online, notice } => {
+                let users = self.online.entry(account).or_default();
+                users.retain(|user| user.username != username);
+                if online {
+                    // The status line comes with the list the client asks for next
+                    users.push(UserStatus { username, status: None });
+                    users.sort_by(|a, b| a.username.cmp(&b.username));
+                }
+                vec![(notice, Style::default())]
+            },
+            ConsoleEvent::Error(text) => vec![(text, error_style)],
+            ConsoleEvent::Disconnected(text) => {
+                self.connection = Connection::Disconnected;
+                vec![(text, error_style)]
+            },
+            ConsoleEvent::Reconnecting(text) => {
+                self.connection = Connection::Reconnecting;
+                vec![(text, error_style)]
+            },
+            ConsoleEvent::Reconnected(text) => {
+                self.connection = Connection::Connected;
+                vec![(text, Style::default())]
+            },
+            ConsoleEvent::Pending(count) => {
+                self.pending = count;
+                return;
+            },
+            ConsoleEvent::Progress(progress) => {
+                self.progress = progress;
+                return;
+            },
+            ConsoleEvent::Expired { account, seq, notice } => {
+                match seq.and_then(|seq| self.ephemeral.remove(&(account, seq))).and_then(|index| self.lines.get_mut(index)) {
+                    Some(line) => line.iter_mut().for_each(|(_, style)| *style = style.add_modifier(Modifier::CROSSED_OUT)),
+                    None => self.lines.push(vec![(notice, Style::default())]),
+                }
+                return;
+            },
+        };
+        self.lines.push(line);
+    }
+
+    /// Processes a key press.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The pressed key.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<String>` - Returns the entered line when Enter was pressed.
+    fn handle_key(&mut self, key: KeyEvent) -> Option<String> {
+        let page = self.pane_height.saturating_sub(1).max(1);
+        match key.code {
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => self.quit = true,
+            KeyCode::Esc => self.quit = true,
+            KeyCode::Enter if !self.input.trim().is_empty() => {
+                self.cursor = 0;
+                self.scroll = 0;
+                return Some(std::mem::take(&mut self.input));
+            },
+            KeyCode::Char(c) => {
+                self.input.insert(self.byte_index(), c);
+                self.cursor += 1;
+            },
+            KeyCode::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.input.remove(self.byte_index());
+            },
+            KeyCode::Delete if self.cursor < self.input.chars().count() => {
+                self.input.remove(self.byte_index());
+            },
+            KeyCode::Left => self.cursor = self.cursor.saturating_sub(1),
+            KeyCode::Right => self.cursor = (self.cursor + 1).min(self.input.chars().count()),
+            KeyCode::Tab => self.complete(),
+            KeyCode::Home => self.cursor = 0,
+            KeyCode::End => self.cursor = self.input.chars().count(),
+            KeyCode::Up => self.scroll += 1,
+            KeyCode::Down => self.scroll = self.scroll.saturating_sub(1),
+            KeyCode::PageUp => self.scroll += page,
+            KeyCode::PageDown => self.scroll = self.scroll.saturating_sub(page),
+            _ => {}
+        }
+        None
+    }
+
+    /// Completes the word before the cursor. A single candidate is inserted, otherwise their common prefix
+    /// is inserted and the candidates are listed in the message pane.
+    fn complete(&mut self) {
+        let pos = self.byte_index();
+        let (start, candidates) = complete(&self.input, pos, &self.users);
+        let Some(first) = candidates.first() else { return };
+
+        let prefix = candidates.iter().fold(first.replacement.as_str(), |prefix, candidate| {
+            let len = prefix.chars().zip(candidate.replacement.chars())
+                .take_while(|(a, b)| a == b)
+                .map(|(a, _)| a.len_utf8())
+                .sum();
+            &prefix[..len]
+        }).to_string();
+        if candidates.len() > 1 {
+            let names: Vec<&str> = candidates.iter().map(|c| c.display.as_str()).collect();
+            self.lines.push(vec![(names.join("  "), Style::default())]);
+        }
+        if prefix.len() > pos - start {
+            self.input.replace_range(start..pos, &prefix);
+            self.cursor = self.input[..start + prefix.len()].chars().count();
+        }
+    }
+
+    /// Converts the cursor position to a byte index into the input.
+    fn byte_index(&self) -> usize {
+        self.input.char_indices().nth(self.cursor).map(|(i, _)| i).unwrap_or(self.input.len())
+    }
+
+    /// Returns the rows of the user list, one for each online user of the active account.
+    ///
+    /// # Arguments
+    ///
+    /// * `width` - The number of columns inside the borders, longer status lines are cut.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<Line<'static>>` - Returns the rows.
+    fn user_rows(&self, width: usize) -> Vec<Line<'static>> {
+        let users = self.online.get(&self.account).map(Vec::as_slice).unwrap_or_default();
+        users.iter()
+            .map(|user| match &self.theme {
+                Some(theme) => theme.styled_user(user),
+                None => vec![(client_theme::format_users(std::slice::from_ref(user)), Style::default())],
+            })
+            .filter_map(|entry| wrap_styled(&entry, width).into_iter().next())
+            .collect()
+    }
+
+    /// Draws the message pane, the user list, the input box and the status bar.
+    ///
+    /// # Arguments
+    ///
+    /// * `frame` - The frame to draw to.
+    fn draw(&mut self, frame: &mut Frame) {
+        let [main_area, input_area, status_area] = Layout::vertical([
+            Constraint::Min(3),
+            Constraint::Length(3),
+            Constraint::Length(1),
+        ]).areas(frame.area());
+        let pane_area = match main_area.width >= MIN_WIDTH_WITH_USER_LIST {
+            true => {
+                let [pane_area, users_area] = Layout::horizontal([
+                    Constraint::Min(1),
+                    Constraint::Length(USER_LIST_WIDTH),
+                ]).areas(main_area);
+                let users = Block::default().borders(Borders::ALL).title(t!("tui-users"));
+                let rows = self.user_rows(users.inner(users_area).width as usize);
+                frame.render_widget(Paragraph::new(rows).block(users), users_area);
+                pane_area
+            },
+            false => main_area,
+        };
+
+        let pane = Block::default().borders(Borders::ALL).title(t!("tui-messages"));
+        let inner = pane.inner(pane_area);
+        self.pane_height = inner.height as usize;
+
+        let rows: Vec<Line> = self.lines.iter()
+            .flat_map(|line| {
+                wrap_styled(line, inner.width as usize)
+            })
+            .collect();
+
+        // Keep the newest messages at the bottom, scrolling moves the window up
+        self.scroll = self.scroll.min(rows.len().saturating_sub(self.pane_height));
+        let end = rows.len() - self.scroll;
+        let start = end.saturating_sub(self.pane_height);
+        frame.render_widget(Paragraph::new(rows[start..end].to_vec()).block(pane), pane_area);
+
+        let input = Paragraph::new(self.input.as_str())
+            .block(Block::default().borders(Borders::ALL).title(t!("tui-input")));
+        frame.render_widget(input, input_area);
+        // Wide characters like emoji take two columns
+        let cursor_column = self.input[..self.byte_index()].width() as u16;
+        frame.set_cursor_position(Position::new(input_area.x + 1 + cursor_column, input_area.y + 1));
+
+        let state = match self.connection {
+            Connection::Connected => t!("tui-connected"),
+            Connection::Reconnecting => t!("tui-reconnecting"),
+            Connection::Disconnected => t!("tui-disconnected"),
+        };
+        let pending = if self.pending > 0 { format!(" | {}", pending_messages(self.pending)) } else { String::new() };
+        let scrolled = if self.scroll > 0 { format!(" | {}", t!("tui-scrolled", count = self.scroll)) } else { String::new() };
+        let progress = self.progress.as_ref().map(|progress| format!(" | {progress}")).unwrap_or_default();
+        let status = format!(" {} | {state}{pending}{scrolled}{progress} | {}", self.username, t!("tui-keys"));
+        let status_style = Style::default().add_modifier(Modifier::REVERSED);
+        frame.render_widget(Paragraph::new(status).style(status_style), status_area);
+    }
+}
+
+/// Splits a line of text into rows of at most `width` terminal columns. Wide characters like emoji
+/// take two columns and are never split between rows, zero width characters stay with the preceding one.
+/// Newlines in the text, e.g. in a message with a code block, always start a new row.
+///
+/// # Arguments
+///
+/// * `text` - The text to be wrapped.
+/// * `width` - The maximum number of columns in a row.
+///
+/// # Returns
+///
+/// * `Vec<String>` - Returns the rows, at least one.
+fn wrap(text: &str, width: usize) -> Vec<String> {
+    if text.is_empty() || width == 0 {
+        return vec![text.to_string()];
+    }
+    let mut rows = vec![String::new()];
+    let mut columns = 0;
+    for c in text.chars() {
+        if c == '\n' {
+            rows.push(String::new());
+            columns = 0;
+            continue;
+        }
+        let char_width = c.width().unwrap_or(0);
+        if columns + char_width > width && columns > 0 {
+            rows.push(String::new());
+            columns = 0;
+        }
+        rows.last_mut().unwrap().push(c);
+        columns += char_width;
+    }
+    rows
+}
+
+/// Splits a styled line into rows of at most `width` terminal columns, keeping the style of every character.
+///
+/// # Arguments
+///
+/// * `line` - The parts of the line with their styles.
+/// * `width` - The maximum number of columns in a row.
+///
+/// # Returns
+///
+/// * `Vec<Line<'static>>` - Returns the rows, at least one.
+fn wrap_styled(line: &PaneLine, width: usize) -> Vec<Line<'static>> {
+    let text: String = line.iter().map(|(part, _)| part.as_str()).collect();
+    // The rows contain all characters of the line except for the newlines
+    let mut styles = line.iter()
+        .flat_map(|(part, style)| part.chars().filter(|&c| c != '\n').map(move |_| *style));
+    wrap(&text, width).into_iter().map(|row| {
+        let mut spans: Vec<Span<'static>> = Vec::new();
+        for c in row.chars() {
+            let style = styles.next().unwrap_or_default();
+            match spans.last_mut() {
+                Some(span) if span.style == style => span.content.to_mut().push(c),
+                _ => spans.push(Span::styled(c.to_string(), style)),
+            }
+        }
+        Line::from(spans)
+    }).collect()
+}
+
+/// Runs the terminal user interface until the user quits.
+/// The terminal is restored even when the loop fails.
+///
+/// # Arguments
+///
+/// * `context` - The chat context.
+/// * `events` - Output of the client which is displayed in the message pane.
+///
+/// # Returns
+///
+/// * `EmptyResult` - Returns an empty result if successful.
+pub async fn run(context: &mut ChatContext, events: UnboundedReceiver<ConsoleEvent>) -> EmptyResult {
+    let mut terminal = ratatui::init();
+    let result = event_loop(&mut terminal, context, events).await;
+    ratatui::restore();
+    result
+}
+
+/// Redraws the interface and dispatches key presses and client output.
+///
+/// # Arguments
+///
+/// * `terminal` - The terminal to draw to.
+/// * `context` - The chat context.
+/// * `events` - Output of the client which is displayed in the message pane.
+///
+/// # Returns
+///
+/// * `EmptyResult` - Returns an empty result if successful.
+async fn event_loop(terminal: &mut DefaultTerminal, context: &mut ChatContext, mut events: UnboundedReceiver<ConsoleEvent>) -> EmptyResult {
+    let mut app = App::new(&context.identity(), context.account(), context.known_users.clone(), context.console.theme().cloned(), context.console.markdown());
+    let mut keys = EventStream::new();
+    context.console.print(t!("connected"));
+
+    while !app.quit {
+        terminal.draw(|frame| app.draw(frame))
+            .context(t!("could-not-draw"))?;
+
+        tokio::select! {
+            Some(event) = events.recv() => app.push(event),
+            key = keys.next() => match key {
+                Some(Ok(Event::Key(key))) if key.kind == KeyEventKind::Press => {
+                    let Some(line) = app.handle_key(key) else { continue };
+                    if app.connection == Connection::Disconnected {
+                        context.console.error(t!("tui-not-connected"));
+                        continue;
+                    }
+
+                    match UserCommand::from_str(line.trim()).perform(context).await {
+                        Err(e) => {
+                            // Same as the plain mode
+                            if context.is_recoverable(&e) {
+                                context.console.error(t!("error", error = e.to_string()));
+                                if e.chain().count() > 1 {
+                                    context.console.error(format!("{}", e.root_cause()));
+                                }
+                            } else {
+                                return Err(e);
+                            }
+                        },
+                        Ok(true) => app.quit = true,
+                        // .switch may have changed the account shown in the status bar and the user list
+                        Ok(false) => {
+                            app.username = context.identity();
+                            app.account = context.account();
+                        },
+                    }
+                },
+                Some(Ok(_)) => {},
+                Some(Err(e)) => return Err(e).context(t!("could-not-read-terminal")),
+                None => return Ok(()),
+            },
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chat::UserStatus;
+    use ratatui::style::{Color, Modifier, Style};
+
+    use crate::client_console::ConsoleEvent;
+    use crate::client_theme::{MessageLine, Theme};
+    use crate::client_tui::{wrap, wrap_styled, App};
+
+    #[test]
+    fn test_wrap() {
+        assert_eq!(wrap("", 5), vec![""]);
+        assert_eq!(wrap("abc", 5), vec!["abc"]);
+        assert_eq!(wrap("abcdefg", 3), vec!["abc", "def", "g"]);
+        assert_eq!(wrap("žluťoučký", 4), vec!["žluť", "oučk", "ý"]);
+        assert_eq!(wrap("a😄😄b", 4), vec!["a😄", "😄b"]);
+        assert_eq!(wrap("😄😄", 3), vec!["😄", "😄"]);
+        assert_eq!(wrap("漢字", 1), vec!["漢", "字"]);
+        assert_eq!(wrap("e\u{301}x", 1), vec!["e\u{301}", "x"]);
+        assert_eq!(wrap("ab\ncd", 5), vec!["ab", "cd"]);
+    }
+
+    #[test]
+    fn test_wrap_styled() {
+        let red = Style::default().fg(Color::Red);
+        let rows = wrap_styled(&vec![("ab".to_string(), Style::default()), ("cde".to_string(), red)], 4);
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].spans.len(), 2);
+        assert_eq!(rows[0].spans[1].content, "cd");
+        assert_eq!(rows[0].spans[1].style, red);
+        assert_eq!(rows[1].spans[0].content, "e");
+    }
+
+    #[test]
+    fn test_expired() {
+        let mut app = App::new("bob", None, Default::default(), Some(Theme::default()), false);
+        let message = |seq, ttl| MessageLine { username: "alice".into(), sender: "alice".into(), text: "secret".into(), seq: Some(seq), ttl, ..MessageLine::default() };
+        app.push(ConsoleEvent::Message(message(1, Some(60))));
+        app.push(ConsoleEvent::Message(message(2, None)));
+        let expired = |seq| ConsoleEvent::Expired { account: None, seq: Some(seq), notice: "*** Message #1 of alice expired.".into() };
+
+        // The message is struck through where it is, unknown ones get a notice
+        app.push(expired(1));
+        assert!(app.lines[0].iter().all(|(_, style)| style.add_modifier.contains(Modifier::CROSSED_OUT)));
+        assert!(!app.lines[1].iter().any(|(_, style)| style.add_modifier.contains(Modifier::CROSSED_OUT)));
+        app.push(expired(2));
+        assert_eq!(app.lines.len(), 3);
+        assert_eq!(app.lines[2][0].0, "*** Message #1 of alice expired.");
+    }
+
+    #[test]
+    fn test_user_list() {
+        let mut app = App::new("bob", None, Default::default(), None, false);
+        let user = |username: &str, status: Option<&str>| UserStatus { username: username.into(), status: status.map(str::to_string) };
+        let users = |account: Option<&str>, heading: Option<&str>, users| ConsoleEvent::Users { account: account.map(str::to_string), heading: heading.map(str::to_string), users };
+        let presence = |username: &str, online| ConsoleEvent::Presence { account: None, username: username.into(), online, notice: format!("*** {username}") };
+
+        // Listed users are printed, refreshed ones only update the list
+        app.push(users(None, Some("Online users (2):"), vec![user("alice", None), user("bob", Some("at lunch"))]));
+        assert_eq!(app.lines.len(), 1);
+        app.push(users(None, None, vec![user("alice", Some("busy")), user("bob", Some("at lunch"))]));
+        app.push(users(Some("work"), None, vec![user("dave", None)]));
+        assert_eq!(app.lines.len(), 1);
+        let rows: Vec<String> = app.user_rows(12).iter().map(ToString::to_string).collect();
+        assert_eq!(rows, ["alice (busy)", "bob (at lunc"]);
+
+        app.push(presence("carol", true));
+        app.push(presence("alice", false));
+        assert_eq!(app.lines.len(), 3);
+        assert_eq!(app.online[&None], [user("bob", Some("at lunch")), user("carol", None)]);
+
+        app.account = Some("work".into());
+        assert_eq!(app.user_rows(12).len(), 1);
+    }
+}
@@ -0,0 +1,128 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use chat::batch::{BatchWriter, Queued};
+use chat::client::WriteHalf;
+use chat::{ChatProtocolError, Datagram, SessionCodec};
+use tokio::sync::watch;
+
+/// Writable half of the connection shared by the keyboard loop and the incoming loop.
+pub type SharedWriteHalf = Arc<ServerWriter>;
+
+/// Writes datagrams to the server, those sent while the connection is busy are written together. It gives up
+/// on a server which doesn't take them in time. A timed out write may have left part of a frame on the connection,
+/// so the incoming loop is told to drop the connection.
+pub struct ServerWriter {
+    writer: BatchWriter,
+    /// Set when a write timed out, until the connection is replaced
+    stalled: watch::Sender<bool>,
+}
+
+impl ServerWriter {
+    /// Creates a writer for a logged in connection.
+    ///
+    /// # Arguments
+    ///
+    /// * `write_half` - The writable half of the connection.
+    /// * `timeout` - How long the server may take to accept a datagram, `None` waits forever.
+    ///
+    /// # Returns
+    ///
+    /// * `SharedWriteHalf` - Returns the writer, shared by the tasks of the client.
+    pub fn new(write_half: WriteHalf, timeout: Option<Duration>) -> SharedWriteHalf {
+        Arc::new(ServerWriter {
+            writer: BatchWriter::new(write_half, timeout),
+            stalled: watch::Sender::new(false),
+        })
+    }
+
+    /// Writes a datagram to the server.
+    ///
+    /// # Arguments
+    ///
+    /// * `datagram` - The datagram to be sent.
+    /// * `codec` - The codec of the session.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), ChatProtocolError>` - Returns an empty result if successful.
+    pub async fn write(&self, datagram: &Datagram, codec: &SessionCodec) -> Result<(), ChatProtocolError> {
+        let queued = self.writer.queue(datagram, codec)?;
+        self.flush(queued).await
+    }
+
+    /// Queues several datagrams to be written together, without writing anything yet.
+    ///
+    /// # Arguments
+    ///
+    /// * `datagrams` - The datagrams to be sent.
+    /// * `codec` - The codec of the session.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Queued, ChatProtocolError>` - Returns the queued datagrams, to be handed to `flush`.
+    pub fn queue_all(&self, datagrams: &[Datagram], codec: &SessionCodec) -> Result<Queued, ChatProtocolError> {
+        self.writer.queue_all(datagrams, codec)
+    }
+
+    /// Waits until queued datagrams are written to the server.
+    ///
+    /// # Arguments
+    ///
+    /// * `queued` - The datagrams returned by `queue_all`.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), ChatProtocolError>` - Returns an empty result if successful.
+    pub async fn flush(&self, queued: Queued) -> Result<(), ChatProtocolError> {
+        let result = self.writer.flush(queued).await;
+        if let Err(ChatProtocolError::Timeout) = result {
+            self.stalled.send_replace(true);
+        }
+        result
+    }
+
+    /// Switches to the connection of a new login.
+    ///
+    /// # Arguments
+    ///
+    /// * `write_half` - The writable half of the new connection.
+    pub async fn replace(&self, write_half: WriteHalf) {
+        self.writer.replace(write_half).await;
+        self.stalled.send_replace(false);
+    }
+
+    /// Waits until a write to the current connection times out.
+    pub async fn stalled(&self) {
+        let mut stalled = self.stalled.subscribe();
+        // The sender lives as long as `self`, so waiting can't fail
+        let _ = stalled.wait_for(|stalled| *stalled).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chat::{CodecKind, Datagram, SessionCodec};
+
+    use crate::client_writer::ServerWriter;
+
+    #[tokio::test]
+    async fn test_write_timeout() {
+        let codec = SessionCodec::from(CodecKind::Cbor);
+        let (client, _server) = tokio::io::duplex(16);
+        let writer = ServerWriter::new(Box::new(client), Some(Duration::from_millis(100)));
+
+        // The server doesn't read, so the stalled write wakes the incoming loop
+        let datagram = Datagram::Announcement("x".repeat(100));
+        let (result, _) = tokio::join!(writer.write(&datagram, &codec), writer.stalled());
+        assert!(matches!(result, Err(chat::ChatProtocolError::Timeout)));
+
+        // A new connection starts out fine
+        let (client, _server) = tokio::io::duplex(1024);
+        writer.replace(Box::new(client)).await;
+        writer.write(&datagram, &codec).await.unwrap();
+        assert!(tokio::time::timeout(Duration::from_millis(100), writer.stalled()).await.is_err());
+    }
+}
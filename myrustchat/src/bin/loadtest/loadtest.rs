@@ -0,0 +1,300 @@
+//! Load test of a running server: many simulated clients send messages to each other at a steady rate
+//! and measure how long the broadcasts take.
+//!
+//! ```sh
+//! loadtest --print-users --clients 300 > users.csv
+//! server import-users users.csv
+//! loadtest --clients 300 --rate 0.5 --duration 60
+//! ```
+
+mod loadtest_report;
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::bail;
+use clap::Parser;
+use tokio::task::JoinSet;
+use tokio::time::{Instant, Interval};
+
+use chat::client::ChatClient;
+use chat::{ChatMessageContent, CodecKind, Datagram};
+
+use crate::loadtest_report::{latency_histogram, ClientStats, Report};
+
+/// Start of the text of every load test message, followed by the ID of the run and the send time.
+const MARKER: &str = "loadtest";
+
+/// How one simulated client behaves.
+#[derive(Clone, Copy, Debug)]
+struct Plan {
+    /// ID of the run, messages of other runs sharing the server aren't counted
+    run: u32,
+    /// Time between two messages of the client, `None` if it only receives
+    interval: Option<Duration>,
+    /// Delay before the first message, spreading the messages of all clients evenly
+    offset: Duration,
+    /// Size of the text of a message in bytes
+    size: usize,
+    /// Start of the test, the send times in the messages are relative to it
+    start: Instant,
+    /// When the client stops sending
+    send_until: Instant,
+    /// When the client stops waiting for the messages of the others
+    receive_until: Instant,
+}
+
+/// Writes the text of a load test message.
+///
+/// # Arguments
+///
+/// * `run` - The ID of the run.
+/// * `sent_at` - The send time in microseconds since the start of the test.
+/// * `size` - The size of the text, it's longer if the marker, ID and time need more room.
+///
+/// # Returns
+///
+/// * `String` - Returns the text.
+fn message_text(run: u32, sent_at: u64, size: usize) -> String {
+    let text = format!("{MARKER} {run:08x} {sent_at} ");
+    let padding = size.saturating_sub(text.len());
+    text + &"x".repeat(padding)
+}
+
+/// Reads the send time out of the text of a load test message.
+///
+/// # Arguments
+///
+/// * `text` - The text of a message.
+/// * `run` - The ID of the run.
+///
+/// # Returns
+///
+/// * `Option<u64>` - Returns the send time in microseconds since the start of the test, `None` for other messages.
+fn sent_at(text: &str, run: u32) -> Option<u64> {
+    let mut words = text.strip_prefix(MARKER)?.split_whitespace();
+    if u32::from_str_radix(words.next()?, 16).ok()? != run {
+        return None;
+    }
+    words.next()?.parse().ok()
+}
+
+/// Waits for the next tick, forever if there is none.
+async fn next_tick(ticks: &mut Option<Interval>) {
+    match ticks {
+        Some(ticks) => {
+            ticks.tick().await;
+        },
+        None => std::future::pending().await,
+    }
+}
+
+/// Sends and receives the messages of one simulated client until the end of the test.
+///
+/// # Arguments
+///
+/// * `client` - The connection of the client.
+/// * `plan` - How the client behaves.
+///
+/// # Returns
+///
+/// * `ClientStats` - Returns what the client measured.
+async fn simulate(mut client: ChatClient, plan: Plan) -> ClientStats {
+    let sender = client.sender();
+    let mut stats = ClientStats { sent: 0, latencies: latency_histogram(), disconnected: false };
+    let mut ticks = plan.interval.map(|interval| tokio::time::interval_at(plan.start + plan.offset, interval));
+    loop {
+        let sending = Instant::now() < plan.send_until;
+        tokio::select! {
+            _ = next_tick(&mut ticks), if sending => {
+                let sent_at = plan.start.elapsed().as_micros() as u64;
+                if sender.send_text(&message_text(plan.run, sent_at, plan.size)).await.is_err() {
+                    stats.disconnected = true;
+                    break;
+                }
+                stats.sent += 1;
+            },
+            datagram = tokio::time::timeout_at(plan.receive_until, client.recv()) => match datagram {
+                Ok(Some(Datagram::Message(message))) => {
+                    let ChatMessageContent::Text(text) = message.content else {
+                        continue;
+                    };
+                    if let Some(sent_at) = sent_at(&text, plan.run) {
+                        let latency = (plan.start.elapsed().as_micros() as u64).saturating_sub(sent_at);
+                        stats.latencies.saturating_record(latency);
+                    }
+                },
+                Ok(Some(_)) => {},
+                Ok(None) => {
+                    stats.disconnected = true;
+                    break;
+                },
+                Err(_) => break,
+            },
+        }
+    }
+    stats
+}
+
+/// Logs in a simulated client.
+///
+/// # Arguments
+///
+/// * `args` - The flags of the load test.
+/// * `index` - The number of the client.
+///
+/// # Returns
+///
+/// * `anyhow::Result<ChatClient>` - Returns the connection.
+async fn connect(args: &Args, index: usize) -> anyhow::Result<ChatClient> {
+    let codec = args.codec.unwrap_or_default();
+    let name = format!("{}{index}", args.user_prefix);
+    let client = match (&args.unix_socket, args.guest) {
+        (Some(_), true) => bail!("guests can only log in over TCP"),
+        (Some(path), false) => ChatClient::connect_unix(path, &name, &args.password, codec).await?,
+        (None, true) => ChatClient::connect_guest(&args.address, args.port, &name, codec).await?,
+        (None, false) => ChatClient::connect(&args.address, args.port, &name, &args.password, codec).await?,
+    };
+    Ok(client)
+}
+
+#[derive(Parser, Clone, Debug)]
+#[command(version, about = "Measures the latency of a running server under the load of many simulated clients")]
+struct Args {
+    /// Address of the server
+    #[arg(short, long, default_value = "127.0.0.1")]
+    address: String,
+    /// Port of the server
+    #[arg(short = 'P', long, default_value_t = 11111)]
+    port: u16,
+    /// Unix socket of the server, used instead of the address and port
+    #[arg(long)]
+    unix_socket: Option<PathBuf>,
+    /// Wire format of datagrams: cbor, json or msgpack, must match the server [default: cbor]
+    #[arg(long)]
+    codec: Option<CodecKind>,
+    /// Number of simulated clients
+    #[arg(short, long, default_value_t = 100)]
+    clients: usize,
+    /// Messages per second sent by each client, 0 only receives
+    #[arg(short, long, default_value_t = 1.0)]
+    rate: f64,
+    /// Size of the text of a message in bytes
+    #[arg(short, long, default_value_t = 64)]
+    size: usize,
+    /// Seconds the clients send messages
+    #[arg(short, long, default_value_t = 30)]
+    duration: u64,
+    /// Seconds over which the clients log in, so that the server isn't hit by all logins at once
+    #[arg(long, default_value_t = 5)]
+    ramp_up: u64,
+    /// Seconds to wait for the last messages after the clients stopped sending
+    #[arg(long, default_value_t = 5)]
+    drain: u64,
+    /// Start of the usernames of the clients, followed by their number from 0
+    #[arg(long, default_value = "loadtest")]
+    user_prefix: String,
+    /// Password of all simulated users
+    #[arg(long, default_value = "loadtest")]
+    password: String,
+    /// Log in as guests instead of users, if the server allows guests. The flood protection limits guests more
+    #[arg(long)]
+    guest: bool,
+    /// Print the users as CSV for `server import-users` and exit
+    #[arg(long)]
+    print_users: bool,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    if args.print_users {
+        for index in 0..args.clients {
+            println!("{}{index},{}", args.user_prefix, args.password);
+        }
+        return Ok(());
+    }
+    if args.clients < 2 {
+        bail!("the load test needs at least two clients");
+    }
+    if !args.rate.is_finite() || args.rate < 0.0 {
+        bail!("the rate must be a positive number of messages per second, or 0");
+    }
+
+    let server = match &args.unix_socket {
+        Some(path) => path.display().to_string(),
+        None => format!("{}:{}", args.address, args.port),
+    };
+    println!("Logging in {} clients to {server} over {} s...", args.clients, args.ramp_up);
+    let ramp_up = Duration::from_secs(args.ramp_up);
+    let mut logins = JoinSet::new();
+    for index in 0..args.clients {
+        let args = args.clone();
+        let delay = ramp_up.mul_f64(index as f64 / args.clients as f64);
+        logins.spawn(async move {
+            tokio::time::sleep(delay).await;
+            connect(&args, index).await
+        });
+    }
+    let mut clients = Vec::with_capacity(args.clients);
+    let mut failed_logins = 0;
+    while let Some(login) = logins.join_next().await {
+        match login? {
+            Ok(client) => clients.push(client),
+            Err(e) => {
+                if failed_logins == 0 {
+                    eprintln!("A client could not log in: {e:#}");
+                }
+                failed_logins += 1;
+            },
+        }
+    }
+    if clients.len() < 2 {
+        bail!("only {} of {} clients could log in", clients.len(), args.clients);
+    }
+
+    let duration = Duration::from_secs(args.duration);
+    println!("Sending {} messages of {} bytes per second and client for {} s...", args.rate, args.size, args.duration);
+    let interval = (args.rate > 0.0).then(|| Duration::from_secs_f64(1.0 / args.rate));
+    let run = rand::random();
+    let start = Instant::now();
+    let count = clients.len();
+    let mut simulations = JoinSet::new();
+    for (index, client) in clients.into_iter().enumerate() {
+        let plan = Plan {
+            run,
+            interval,
+            offset: interval.unwrap_or_default().mul_f64(index as f64 / count as f64),
+            size: args.size,
+            start,
+            send_until: start + duration,
+            receive_until: start + duration + Duration::from_secs(args.drain),
+        };
+        simulations.spawn(simulate(client, plan));
+    }
+    let mut stats = Vec::with_capacity(count);
+    while let Some(client) = simulations.join_next().await {
+        stats.push(client?);
+    }
+
+    print!("{}", Report::new(&stats, failed_logins, duration));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message_text() {
+        let text = message_text(0xbeef, 1_234_567, 64);
+        assert_eq!(text.len(), 64);
+        assert!(text.starts_with("loadtest 0000beef 1234567 xx"));
+        assert_eq!(sent_at(&text, 0xbeef), Some(1_234_567));
+        // messages of another run or of people
+        assert_eq!(sent_at(&text, 0xdead), None);
+        assert_eq!(sent_at("hello", 0xbeef), None);
+        // too small for the padding
+        assert_eq!(message_text(1, 42, 4), "loadtest 00000001 42 ");
+    }
+}
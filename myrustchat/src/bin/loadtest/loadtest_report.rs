@@ -0,0 +1,168 @@
+//! Measurements of a load test and their summary.
+
+use std::fmt;
+use std::time::Duration;
+
+use hdrhistogram::Histogram;
+
+/// Highest latency recorded, in microseconds. Slower deliveries are recorded as this.
+const MAX_LATENCY_MICROS: u64 = 3_600_000_000;
+
+/// Percentiles of the latency printed in the summary.
+const PERCENTILES: [f64; 5] = [50.0, 90.0, 99.0, 99.9, 100.0];
+
+/// Creates an empty histogram of latencies in microseconds, precise to 3 significant digits.
+pub fn latency_histogram() -> Histogram<u64> {
+    Histogram::new_with_bounds(1, MAX_LATENCY_MICROS, 3).expect("the bounds of the histogram are valid")
+}
+
+/// What one simulated client measured.
+#[derive(Debug)]
+pub struct ClientStats {
+    /// Number of messages the client sent
+    pub sent: u64,
+    /// Latencies of the messages of the other clients it received, in microseconds
+    pub latencies: Histogram<u64>,
+    /// Whether the server closed the connection before the end of the test
+    pub disconnected: bool,
+}
+
+/// Measurements of all clients together.
+#[derive(Debug)]
+pub struct Report {
+    /// Number of clients which logged in
+    pub clients: usize,
+    /// Number of clients which could not log in
+    pub failed_logins: usize,
+    /// Number of clients the server disconnected during the test
+    pub disconnected: usize,
+    /// Number of messages sent by all clients together
+    pub sent: u64,
+    /// Time the clients were sending
+    pub duration: Duration,
+    /// Latencies of all deliveries, in microseconds
+    pub latencies: Histogram<u64>,
+}
+
+impl Report {
+    /// Adds up the measurements of the clients.
+    ///
+    /// # Arguments
+    ///
+    /// * `stats` - The measurements of the clients which logged in.
+    /// * `failed_logins` - The number of clients which could not log in.
+    /// * `duration` - The time the clients were sending.
+    ///
+    /// # Returns
+    ///
+    /// * `Report` - Returns the report.
+    pub fn new(stats: &[ClientStats], failed_logins: usize, duration: Duration) -> Report {
+        let mut latencies = latency_histogram();
+        for client in stats {
+            latencies.add(&client.latencies).expect("the histograms have the same bounds");
+        }
+        Report {
+            clients: stats.len(),
+            failed_logins,
+            disconnected: stats.iter().filter(|client| client.disconnected).count(),
+            sent: stats.iter().map(|client| client.sent).sum(),
+            duration,
+            latencies,
+        }
+    }
+
+    /// Returns the number of messages received by all clients together.
+    pub fn received(&self) -> u64 {
+        self.latencies.len()
+    }
+
+    /// Returns the number of deliveries which never arrived: every message should reach all clients but its sender.
+    pub fn lost(&self) -> u64 {
+        let expected = self.sent * self.clients.saturating_sub(1) as u64;
+        expected.saturating_sub(self.received())
+    }
+
+    /// Returns a percentile of the latency.
+    ///
+    /// # Arguments
+    ///
+    /// * `percentile` - The percentile between 0 and 100, e.g. 99 for the latency 99 % of the deliveries stay within.
+    ///
+    /// # Returns
+    ///
+    /// * `Duration` - Returns the latency, zero if nothing was delivered.
+    pub fn latency(&self, percentile: f64) -> Duration {
+        if self.latencies.is_empty() {
+            return Duration::ZERO;
+        }
+        Duration::from_micros(self.latencies.value_at_quantile(percentile / 100.0))
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let seconds = self.duration.as_secs_f64().max(f64::EPSILON);
+        let expected = self.received() + self.lost();
+        writeln!(f, "Clients: {} logged in, {} failed to log in, {} disconnected", self.clients, self.failed_logins, self.disconnected)?;
+        writeln!(f, "Sent: {} messages in {:.1} s, {:.1} per second", self.sent, seconds, self.sent as f64 / seconds)?;
+        writeln!(f, "Received: {} deliveries, {:.1} per second", self.received(), self.received() as f64 / seconds)?;
+        let share = if expected > 0 { self.lost() as f64 * 100.0 / expected as f64 } else { 0.0 };
+        writeln!(f, "Lost: {} of {} deliveries ({share:.2} %)", self.lost(), expected)?;
+        if self.latencies.is_empty() {
+            return writeln!(f, "Latency: nothing was delivered");
+        }
+        let millis = |duration: Duration| duration.as_secs_f64() * 1000.0;
+        write!(f, "Latency: min {:.2} ms, mean {:.2} ms", millis(Duration::from_micros(self.latencies.min())), self.latencies.mean() / 1000.0)?;
+        for percentile in PERCENTILES {
+            let name = if percentile == 100.0 { "max".to_string() } else { format!("p{percentile}") };
+            write!(f, ", {name} {:.2} ms", millis(self.latency(percentile)))?;
+        }
+        writeln!(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::loadtest_report::{latency_histogram, ClientStats, Report};
+
+    fn client(sent: u64, latencies: impl IntoIterator<Item = u64>, disconnected: bool) -> ClientStats {
+        let mut histogram = latency_histogram();
+        for latency in latencies {
+            histogram.saturating_record(latency);
+        }
+        ClientStats { sent, latencies: histogram, disconnected }
+    }
+
+    #[test]
+    fn test_report() {
+        let stats = [client(10, (1..=15).map(|millis| millis * 1000), false), client(5, (16..=20).map(|millis| millis * 1000), true)];
+        let report = Report::new(&stats, 1, Duration::from_secs(5));
+        assert_eq!(report.clients, 2);
+        assert_eq!(report.disconnected, 1);
+        assert_eq!(report.sent, 15);
+        assert_eq!(report.received(), 20);
+        assert_eq!(report.lost(), 0);
+        assert_eq!(report.latency(50.0).as_millis(), 10);
+        assert_eq!(report.latency(100.0).as_millis(), 20);
+
+        let summary = report.to_string();
+        assert!(summary.contains("Clients: 2 logged in, 1 failed to log in, 1 disconnected"), "{summary}");
+        assert!(summary.contains("Sent: 15 messages in 5.0 s, 3.0 per second"), "{summary}");
+        assert!(summary.contains("Lost: 0 of 20 deliveries (0.00 %)"), "{summary}");
+        assert!(summary.contains("min 1.00 ms, mean 10.50 ms, p50 10.01 ms, p90 18.02 ms"), "{summary}");
+        assert!(summary.contains("max 20.02 ms"), "{summary}");
+    }
+
+    #[test]
+    fn test_lost() {
+        let stats = [client(4, [500; 3], false), client(4, [500; 4], false), client(0, [], false)];
+        let report = Report::new(&stats, 0, Duration::from_secs(1));
+        // 8 messages for 2 other clients each
+        assert_eq!(report.lost(), 16 - 7);
+        let empty = Report::new(&[client(0, [], false)], 3, Duration::from_secs(1));
+        assert_eq!(empty.latency(99.0), Duration::ZERO);
+        assert!(empty.to_string().contains("Latency: nothing was delivered"));
+    }
+}
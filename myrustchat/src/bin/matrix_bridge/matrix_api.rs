@@ -0,0 +1,329 @@
+//! Account of the bridge on a Matrix homeserver, built on matrix-sdk: login, joining rooms, syncing and sending messages.
+//!
+//! The client keeps its state in memory and is built without the `e2e-encryption` feature, so the bridge refuses
+//! encrypted rooms, see `MatrixClient::is_encrypted`.
+
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use matrix_sdk::authentication::matrix::MatrixSession;
+use matrix_sdk::config::SyncSettings;
+use matrix_sdk::deserialized_responses::TimelineEvent;
+use matrix_sdk::ruma::api::client::filter::FilterDefinition;
+use matrix_sdk::ruma::api::client::sync::sync_events::v3::Filter;
+use matrix_sdk::ruma::events::room::message::{MessageType, Relation, SyncRoomMessageEvent};
+use matrix_sdk::ruma::events::room::MediaSource;
+use matrix_sdk::ruma::events::{AnySyncMessageLikeEvent, AnySyncTimelineEvent};
+use matrix_sdk::ruma::{device_id, user_id, OwnedDeviceId, OwnedUserId, RoomId, RoomOrAliasId, TransactionId, UInt};
+use matrix_sdk::{Client, SessionMeta, SessionTokens};
+use reqwest::Url;
+use serde_json::Value;
+
+/// How long the homeserver may hold a sync request open when nothing happens.
+pub const SYNC_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Device ID of a session the homeserver doesn't tell the device of, which only matters for encryption.
+const BRIDGE_DEVICE: &str = "MYRUSTCHAT";
+
+/// New messages of a sync.
+#[derive(Debug)]
+pub struct SyncResponse {
+    /// Position to continue from in the next sync
+    pub next_batch: String,
+    /// Messages in the order they were posted, room by room
+    pub messages: Vec<RoomMessage>,
+}
+
+/// A message posted in a Matrix room.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RoomMessage {
+    pub room_id: String,
+    pub event_id: String,
+    /// User ID of the sender, e.g. `@carol:example.org`
+    pub sender: String,
+    /// ID of the transaction of the sender, only known for messages sent by the bridge itself
+    pub transaction_id: Option<String>,
+    pub content: MessageContent,
+}
+
+/// What a Matrix message says.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MessageContent {
+    Text(String),
+    /// `/me` message
+    Emote(String),
+    /// Message of a bot, not meant to be answered
+    Notice(String),
+    /// Image, file, audio or video, with its name and `mxc://` URL
+    Media { body: String, url: String },
+}
+
+/// Reads an event of a room timeline.
+///
+/// # Arguments
+///
+/// * `room_id` - The ID of the room.
+/// * `event` - The event, as the sync returned it.
+///
+/// # Returns
+///
+/// * `Option<RoomMessage>` - Returns the message, `None` for other events, message types the bridge doesn't know and redacted messages.
+fn room_message(room_id: &RoomId, event: &TimelineEvent) -> Option<RoomMessage> {
+    let Ok(AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::RoomMessage(SyncRoomMessageEvent::Original(event)))) = event.raw().deserialize() else {
+        return None;
+    };
+    let body = event.content.body();
+    // replies quote the message they answer in lines starting with `> `, followed by an empty line
+    let body = match event.content.relates_to {
+        Some(Relation::Reply { .. }) if body.starts_with("> ") => body.split_once("\n\n").map_or(body, |(_, reply)| reply),
+        _ => body,
+    };
+    let media = |source: &MediaSource| match source {
+        MediaSource::Plain(url) => Some(MessageContent::Media { body: body.to_string(), url: url.to_string() }),
+        MediaSource::Encrypted(_) => None,
+    };
+    let content = match &event.content.msgtype {
+        MessageType::Text(_) => MessageContent::Text(body.to_string()),
+        MessageType::Emote(_) => MessageContent::Emote(body.to_string()),
+        MessageType::Notice(_) => MessageContent::Notice(body.to_string()),
+        MessageType::Image(image) => media(&image.source)?,
+        MessageType::File(file) => media(&file.source)?,
+        MessageType::Audio(audio) => media(&audio.source)?,
+        MessageType::Video(video) => media(&video.source)?,
+        _ => return None,
+    };
+    Some(RoomMessage {
+        room_id: room_id.to_string(),
+        event_id: event.event_id.to_string(),
+        sender: event.sender.to_string(),
+        transaction_id: event.unsigned.transaction_id.map(|id| id.to_string()),
+        content,
+    })
+}
+
+/// Creates a client of the homeserver, keeping its state in memory.
+async fn client(homeserver: &Url) -> Result<Client> {
+    Client::builder().homeserver_url(homeserver.as_str()).build().await.context("Could not create the Matrix client.")
+}
+
+/// Builds the session of an access token.
+fn session(user_id: OwnedUserId, device_id: OwnedDeviceId, access_token: &str) -> MatrixSession {
+    MatrixSession {
+        meta: SessionMeta { user_id, device_id },
+        tokens: SessionTokens { access_token: access_token.to_string(), refresh_token: None },
+    }
+}
+
+/// Logged in account on a Matrix homeserver.
+#[derive(Clone, Debug)]
+pub struct MatrixClient {
+    client: Client,
+    /// User ID of the account, e.g. `@bridge:example.org`
+    pub user_id: String,
+}
+
+impl MatrixClient {
+    /// Logs in with a password.
+    ///
+    /// # Arguments
+    ///
+    /// * `homeserver` - The URL of the homeserver, e.g. `https://matrix.example.org`.
+    /// * `user` - The user ID or its local part.
+    /// * `password` - The password.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<MatrixClient>` - Returns the logged in account.
+    pub async fn login(homeserver: Url, user: &str, password: &str) -> Result<MatrixClient> {
+        let client = client(&homeserver).await?;
+        let login = client.matrix_auth().login_username(user, password)
+            .initial_device_display_name("myrustchat bridge")
+            .send().await
+            .with_context(|| format!("Could not log in to {homeserver} as {user}."))?;
+        Ok(MatrixClient { client, user_id: login.user_id.to_string() })
+    }
+
+    /// Uses an access token obtained elsewhere, e.g. from the settings of a Matrix client.
+    ///
+    /// # Arguments
+    ///
+    /// * `homeserver` - The URL of the homeserver.
+    /// * `access_token` - The access token.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<MatrixClient>` - Returns the account if the homeserver accepts the token.
+    pub async fn with_token(homeserver: Url, access_token: &str) -> Result<MatrixClient> {
+        // matrix-sdk restores a session with its user and device, which the token alone doesn't tell,
+        // so a first client asks the homeserver whose token it is
+        let probe = client(&homeserver).await?;
+        probe.restore_session(session(user_id!("@bridge:localhost").to_owned(), device_id!(BRIDGE_DEVICE).to_owned(), access_token)).await?;
+        let whoami = probe.whoami().await.with_context(|| format!("{homeserver} refused the access token."))?;
+        let device_id = whoami.device_id.unwrap_or_else(|| device_id!(BRIDGE_DEVICE).to_owned());
+        let user_id = whoami.user_id.to_string();
+        let client = client(&homeserver).await?;
+        client.restore_session(session(whoami.user_id, device_id, access_token)).await?;
+        Ok(MatrixClient { client, user_id })
+    }
+
+    /// Joins a room, which the account must be allowed to. Joining a room again does nothing.
+    ///
+    /// # Arguments
+    ///
+    /// * `room` - The ID of the room, e.g. `!abc:example.org`, or an alias, e.g. `#chat:example.org`.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<String>` - Returns the ID of the room.
+    pub async fn join(&self, room: &str) -> Result<String> {
+        let alias = RoomOrAliasId::parse(room).with_context(|| format!("{room} isn't a room ID or alias."))?;
+        let joined = self.client.join_room_by_id_or_alias(&alias, &[]).await.with_context(|| format!("Could not join {room}."))?;
+        Ok(joined.room_id().to_string())
+    }
+
+    /// Tells whether end-to-end encryption is turned on in a room, which the bridge can't read.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The ID of the room, which the account joined.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<bool>` - Returns whether the room has an `m.room.encryption` state event.
+    pub async fn is_encrypted(&self, room_id: &str) -> Result<bool> {
+        let room = self.room(room_id)?;
+        let state = room.latest_encryption_state().await.with_context(|| format!("Could not read the state of {room_id}."))?;
+        Ok(state.is_encrypted())
+    }
+
+    /// Waits for new events.
+    ///
+    /// # Arguments
+    ///
+    /// * `since` - The `next_batch` of the previous sync, `None` for the first one, which returns no old messages.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<SyncResponse>` - Returns the new messages, after at most `SYNC_TIMEOUT` if there are none.
+    pub async fn sync(&self, since: Option<&str>) -> Result<SyncResponse> {
+        let settings = match since {
+            Some(since) => SyncSettings::default().token(since).timeout(SYNC_TIMEOUT),
+            None => {
+                let mut filter = FilterDefinition::default();
+                filter.room.timeline.limit = Some(UInt::MIN);
+                SyncSettings::default().filter(Filter::FilterDefinition(filter)).timeout(Duration::ZERO)
+            },
+        };
+        let sync = self.client.sync_once(settings).await?;
+        let mut messages = Vec::new();
+        for (room_id, room) in &sync.rooms.joined {
+            messages.extend(room.timeline.events.iter().filter_map(|event| room_message(room_id, event)));
+        }
+        Ok(SyncResponse { next_batch: sync.next_batch, messages })
+    }
+
+    /// Sends a message to a room. Sending again with the same transaction ID doesn't post the message twice.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The ID of the room.
+    /// * `transaction_id` - The ID of the transaction, unique for the access token.
+    /// * `content` - The content of the `m.room.message` event.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<String>` - Returns the ID of the event.
+    pub async fn send(&self, room_id: &str, transaction_id: &str, content: &Value) -> Result<String> {
+        let sent = self.room(room_id)?.send_raw("m.room.message", content)
+            .with_transaction_id(<&TransactionId>::from(transaction_id))
+            .await
+            .with_context(|| format!("Could not send a message to {room_id}."))?;
+        Ok(sent.response.event_id.to_string())
+    }
+
+    /// Uploads a file to the media repository of the homeserver.
+    ///
+    /// # Arguments
+    ///
+    /// * `filename` - The name of the file.
+    /// * `content_type` - The MIME type of the file.
+    /// * `data` - The content of the file.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<String>` - Returns the `mxc://` URL of the file.
+    pub async fn upload(&self, filename: &str, content_type: &str, data: Vec<u8>) -> Result<String> {
+        let content_type: mime::Mime = content_type.parse().with_context(|| format!("Invalid MIME type {content_type}."))?;
+        let uploaded = self.client.media().upload(&content_type, data, None).await.with_context(|| format!("Could not upload {filename}."))?;
+        Ok(uploaded.content_uri.to_string())
+    }
+
+    /// Returns the web address of a file in the media repository.
+    ///
+    /// # Arguments
+    ///
+    /// * `mxc` - The `mxc://server/id` URL of the file.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<String>` - Returns the URL to download the file from the homeserver of the bridge, `None` if `mxc` isn't an `mxc://` URL.
+    pub fn download_url(&self, mxc: &str) -> Option<String> {
+        let (server, id) = mxc.strip_prefix("mxc://")?.split_once('/')?;
+        let mut url = self.client.homeserver();
+        url.path_segments_mut().ok()?
+            .pop_if_empty()
+            .extend(["_matrix", "media", "v3", "download", server, id]);
+        Some(url.to_string())
+    }
+
+    /// Returns a joined room.
+    fn room(&self, room_id: &str) -> Result<matrix_sdk::Room> {
+        let room_id = RoomId::parse(room_id).with_context(|| format!("{room_id} isn't a room ID."))?;
+        self.client.get_room(&room_id).with_context(|| format!("{room_id} isn't a joined room."))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use matrix_sdk::ruma::room_id;
+    use matrix_sdk::ruma::serde::Raw;
+
+    use super::*;
+
+    #[test]
+    fn test_room_messages() {
+        let events = [
+            r#"{ "type": "m.room.member", "event_id": "$1", "sender": "@carol:example.org", "origin_server_ts": 1, "state_key": "@carol:example.org", "content": { "membership": "join" } }"#,
+            r#"{ "type": "m.room.message", "event_id": "$2", "sender": "@carol:example.org", "origin_server_ts": 2, "content": { "msgtype": "m.text", "body": "hello" } }"#,
+            r#"{ "type": "m.room.message", "event_id": "$3", "sender": "@carol:example.org", "origin_server_ts": 3, "content": { "msgtype": "m.emote", "body": "waves" } }"#,
+            r#"{ "type": "m.room.message", "event_id": "$4", "sender": "@carol:example.org", "origin_server_ts": 4,
+                "content": { "msgtype": "m.image", "body": "cat.png", "url": "mxc://example.org/xyz" } }"#,
+            r#"{ "type": "m.room.message", "event_id": "$5", "sender": "@bridge:example.org", "origin_server_ts": 5, "unsigned": { "transaction_id": "t1" },
+                "content": { "msgtype": "m.text", "body": "> <@dave:example.org> hi\n\nhi dave", "m.relates_to": { "m.in_reply_to": { "event_id": "$0" } } } }"#,
+            r#"{ "type": "m.room.message", "event_id": "$6", "sender": "@carol:example.org", "origin_server_ts": 6, "content": {} }"#,
+        ];
+        let room_id = room_id!("!abc:example.org");
+        let messages: Vec<RoomMessage> = events.iter()
+            .map(|event| TimelineEvent::from_plaintext(Raw::from_json_string(event.to_string()).unwrap()))
+            .filter_map(|event| room_message(room_id, &event))
+            .collect();
+        let contents: Vec<&MessageContent> = messages.iter().map(|message| &message.content).collect();
+        assert_eq!(contents, [
+            &MessageContent::Text("hello".to_string()),
+            &MessageContent::Emote("waves".to_string()),
+            &MessageContent::Media { body: "cat.png".to_string(), url: "mxc://example.org/xyz".to_string() },
+            &MessageContent::Text("hi dave".to_string()),
+        ]);
+        assert_eq!(messages[0].room_id, "!abc:example.org");
+        assert_eq!(messages[0].event_id, "$2");
+        assert_eq!(messages[0].sender, "@carol:example.org");
+        assert_eq!(messages[3].transaction_id.as_deref(), Some("t1"));
+    }
+
+    #[tokio::test]
+    async fn test_download_url() {
+        let client = MatrixClient { client: client(&Url::parse("https://matrix.example.org/").unwrap()).await.unwrap(), user_id: String::new() };
+        assert_eq!(client.download_url("mxc://example.org/xyz").as_deref(), Some("https://matrix.example.org/_matrix/media/v3/download/example.org/xyz"));
+        assert_eq!(client.download_url("https://example.org/xyz"), None);
+    }
+}
@@ -0,0 +1,453 @@
+//! Bot mirroring chat servers into Matrix rooms, both ways.
+//!
+//! ```sh
+//! server register -u bridge -p secret
+//! matrix-bridge -c matrix-bridge.toml
+//! ```
+
+mod matrix_api;
+mod matrix_config;
+mod matrix_dedup;
+
+use std::collections::HashMap;
+use std::io::IsTerminal;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use clap::Parser;
+use serde_json::{json, Value};
+use tokio::task::JoinSet;
+use tracing::{info, warn};
+
+use chat::client::ChatClient;
+use chat::{format_size, image_extension, AttachmentId, AttachmentKind, ChatMessage, ChatMessageContent, Datagram, MessageSeq, ServerResponse, TransferId};
+
+use crate::matrix_api::{MatrixClient, MessageContent, RoomMessage};
+use crate::matrix_config::BridgeConfig;
+use crate::matrix_dedup::{Dedup, DedupKey, DEDUP_CAPACITY};
+
+/// How long to wait before syncing again after the homeserver couldn't be reached.
+const SYNC_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Recently relayed messages, shared by the sync loop and the tasks of the chat servers.
+type SharedDedup = Arc<Mutex<Dedup>>;
+
+/// What the bridge posts in the Matrix room for a datagram from the chat server.
+#[derive(Debug, PartialEq)]
+enum ToMatrix {
+    /// Nothing, the datagram isn't relayed or is a part of a download
+    Nothing,
+    /// A text message
+    Text(String),
+    /// A text announcing an attachment, followed by the attachment uploaded to the homeserver
+    Attachment { text: String, filename: String, msgtype: &'static str, data: Vec<u8> },
+    /// Download the stored attachment, it's posted once it arrived
+    Fetch(AttachmentId),
+}
+
+/// Stored attachment being downloaded from the chat server.
+struct Download {
+    /// ID of the attachment
+    id: AttachmentId,
+    kind: AttachmentKind,
+    data: Vec<u8>,
+}
+
+/// Translates what one chat server sends into messages for its Matrix room.
+struct ChatRelay {
+    /// Username of the bridge on the chat server, its own messages are not relayed back
+    username: String,
+    room_id: String,
+    dedup: SharedDedup,
+    /// Text and file name announcing each attachment requested from the chat server
+    pending: HashMap<AttachmentId, (String, String)>,
+    downloads: HashMap<TransferId, Download>,
+}
+
+impl ChatRelay {
+    /// Creates the relay of a chat server.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - The username of the bridge on the chat server.
+    /// * `room_id` - The ID of the Matrix room.
+    /// * `dedup` - The recently relayed messages.
+    ///
+    /// # Returns
+    ///
+    /// * `ChatRelay` - Returns the relay.
+    fn new(username: &str, room_id: &str, dedup: SharedDedup) -> ChatRelay {
+        ChatRelay { username: username.to_string(), room_id: room_id.to_string(), dedup, pending: HashMap::new(), downloads: HashMap::new() }
+    }
+
+    /// Decides what to post in the Matrix room for a datagram from the chat server.
+    ///
+    /// # Arguments
+    ///
+    /// * `datagram` - The datagram.
+    ///
+    /// # Returns
+    ///
+    /// * `ToMatrix` - Returns what the bridge does next.
+    fn handle(&mut self, datagram: Datagram) -> ToMatrix {
+        match datagram {
+            // Ephemeral messages aren't bridged, the bridge doesn't redact them when they expire
+            Datagram::Message(message) if message.ttl.is_none() && self.is_new(&message.sender, message.seq) => self.message(message),
+            Datagram::Thumbnail { id, message } if self.is_new(&message.sender, Some(id)) => {
+                let name = message.nickname.as_deref().unwrap_or(&message.sender);
+                self.pending.insert(id, (format!("* {name} sent an image"), "image".to_string()));
+                ToMatrix::Fetch(id)
+            },
+            Datagram::FileOffer { id, sender, nickname, filename, size, .. } if self.is_new(&sender, Some(id)) => {
+                let name = nickname.as_deref().unwrap_or(&sender);
+                self.pending.insert(id, (format!("* {name} sent the file {filename} ({})", format_size(size)), filename));
+                ToMatrix::Fetch(id)
+            },
+            Datagram::FileBegin { transfer_id, id, kind, .. } if self.pending.contains_key(&id) => {
+                self.downloads.insert(transfer_id, Download { id, kind, data: Vec::new() });
+                ToMatrix::Nothing
+            },
+            Datagram::FileChunk { transfer_id, data, .. } => {
+                if let Some(download) = self.downloads.get_mut(&transfer_id) {
+                    download.data.extend_from_slice(&data);
+                }
+                ToMatrix::Nothing
+            },
+            Datagram::FileEnd { transfer_id } => {
+                let Some(download) = self.downloads.remove(&transfer_id) else {
+                    return ToMatrix::Nothing;
+                };
+                let Some((text, mut filename)) = self.pending.remove(&download.id) else {
+                    return ToMatrix::Nothing;
+                };
+                let msgtype = match &download.kind {
+                    AttachmentKind::Image => {
+                        filename = format!("{filename}.{}", image_extension(&download.data));
+                        "m.image"
+                    },
+                    AttachmentKind::File(_) => "m.file",
+                    AttachmentKind::Audio(_) => "m.audio",
+                };
+                ToMatrix::Attachment { text, filename, msgtype, data: download.data }
+            },
+            Datagram::FileAbort { transfer_id } => match self.downloads.remove(&transfer_id) {
+                Some(download) => self.unavailable(download.id),
+                None => ToMatrix::Nothing,
+            },
+            Datagram::ServerResponse(ServerResponse::AttachmentNotFound(id)) => self.unavailable(id),
+            _ => ToMatrix::Nothing,
+        }
+    }
+
+    /// Tells whether a datagram from the chat server should be relayed: it's not from the bridge
+    /// and, for the stored ones, the server didn't send it before.
+    ///
+    /// # Arguments
+    ///
+    /// * `sender` - The sender of the message.
+    /// * `seq` - The sequence number given by the server to stored messages.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - Returns whether to relay it.
+    fn is_new(&self, sender: &str, seq: Option<MessageSeq>) -> bool {
+        if sender == self.username {
+            return false;
+        }
+        match seq {
+            Some(seq) => self.dedup.lock().unwrap().insert(DedupKey::Chat { room_id: self.room_id.clone(), seq }),
+            None => true,
+        }
+    }
+
+    /// Translates a chat message, attachments sent inline are uploaded to the homeserver.
+    fn message(&self, message: ChatMessage) -> ToMatrix {
+        let name = message.nickname.as_deref().unwrap_or(&message.sender);
+        let size = |data: &[u8]| format_size(data.len() as u64);
+        match message.content {
+            ChatMessageContent::Text(text) => ToMatrix::Text(format!("<{name}> {text}")),
+            // Matrix clients preview the links themselves
+            ChatMessageContent::LinkPreview { .. } => ToMatrix::Nothing,
+            ChatMessageContent::Image(data) => ToMatrix::Attachment {
+                text: format!("* {name} sent an image ({})", size(&data)),
+                filename: format!("image.{}", image_extension(&data)),
+                msgtype: "m.image",
+                data,
+            },
+            ChatMessageContent::File(filename, data) => ToMatrix::Attachment {
+                text: format!("* {name} sent the file {filename} ({})", size(&data)),
+                filename,
+                msgtype: "m.file",
+                data,
+            },
+            ChatMessageContent::Audio { format, data } => ToMatrix::Attachment {
+                text: format!("* {name} sent a voice note ({})", size(&data)),
+                filename: format!("voice-note.{}", format.extension()),
+                msgtype: "m.audio",
+                data,
+            },
+        }
+    }
+
+    /// Announces a requested attachment without the attachment, because it couldn't be downloaded.
+    fn unavailable(&mut self, id: AttachmentId) -> ToMatrix {
+        match self.pending.remove(&id) {
+            Some((text, _)) => ToMatrix::Text(text),
+            None => ToMatrix::Nothing,
+        }
+    }
+}
+
+/// Turns a Matrix message into the text posted in the chat.
+///
+/// # Arguments
+///
+/// * `message` - The message.
+/// * `media_link` - The web address of the attached file, for images and files.
+///
+/// # Returns
+///
+/// * `Option<String>` - Returns the text, `None` for notices, which bots send and other bots don't relay.
+fn matrix_to_chat(message: &RoomMessage, media_link: Option<String>) -> Option<String> {
+    // @carol:example.org
+    let name = message.sender.trim_start_matches('@').split(':').next().unwrap_or(&message.sender);
+    match &message.content {
+        MessageContent::Text(text) => Some(format!("<{name}> {text}")),
+        MessageContent::Emote(text) => Some(format!("* {name} {text}")),
+        MessageContent::Notice(_) => None,
+        MessageContent::Media { body, .. } => match media_link {
+            Some(link) => Some(format!("* {name} sent {body}: {link}")),
+            None => Some(format!("* {name} sent {body}")),
+        },
+    }
+}
+
+/// Posts a message in a Matrix room, remembering it so that it isn't relayed back when the sync returns it.
+///
+/// # Arguments
+///
+/// * `matrix` - The Matrix account.
+/// * `room_id` - The ID of the room.
+/// * `dedup` - The recently relayed messages.
+/// * `content` - The content of the `m.room.message` event.
+///
+/// # Returns
+///
+/// * `Result<()>` - Returns an error if the homeserver refused the message.
+async fn post(matrix: &MatrixClient, room_id: &str, dedup: &SharedDedup, content: Value) -> Result<()> {
+    static NEXT_TRANSACTION: AtomicU64 = AtomicU64::new(0);
+    let transaction_id = format!("myrustchat-{}-{}", std::process::id(), NEXT_TRANSACTION.fetch_add(1, Ordering::Relaxed));
+    dedup.lock().unwrap().insert(DedupKey::Transaction(transaction_id.clone()));
+    let event_id = matrix.send(room_id, &transaction_id, &content).await?;
+    dedup.lock().unwrap().insert(DedupKey::Event(event_id));
+    Ok(())
+}
+
+/// Posts an attachment in a Matrix room: a text announcing it, then the attachment uploaded to the homeserver.
+///
+/// # Arguments
+///
+/// * `matrix` - The Matrix account.
+/// * `room_id` - The ID of the room.
+/// * `dedup` - The recently relayed messages.
+/// * `text` - The text announcing the attachment.
+/// * `filename` - The name of the file.
+/// * `msgtype` - The type of the message, e.g. `m.image`.
+/// * `data` - The content of the file.
+///
+/// # Returns
+///
+/// * `Result<()>` - Returns an error if the homeserver refused the text or the attachment.
+async fn post_attachment(matrix: &MatrixClient, room_id: &str, dedup: &SharedDedup, text: &str, filename: &str, msgtype: &str, data: Vec<u8>) -> Result<()> {
+    post(matrix, room_id, dedup, json!({ "msgtype": "m.text", "body": text })).await?;
+    let size = data.len();
+    let mimetype = infer::get(&data).map_or("application/octet-stream", |kind| kind.mime_type());
+    let url = matrix.upload(filename, mimetype, data).await?;
+    let content = json!({ "msgtype": msgtype, "body": filename, "url": url, "info": { "size": size, "mimetype": mimetype } });
+    post(matrix, room_id, dedup, content).await
+}
+
+/// Relays everything one chat server sends to its Matrix room until the connection closes.
+/// Messages the homeserver refuses are logged and dropped.
+///
+/// # Arguments
+///
+/// * `client` - The connection to the chat server.
+/// * `matrix` - The Matrix account.
+/// * `relay` - The relay of the chat server.
+/// * `server` - Where the chat server is, for the log.
+///
+/// # Returns
+///
+/// * `Result<()>` - Returns an error once the connection closed.
+async fn relay_chat(mut client: ChatClient, matrix: MatrixClient, mut relay: ChatRelay, server: String) -> Result<()> {
+    let sender = client.sender();
+    while let Some(datagram) = client.recv().await {
+        let room_id = relay.room_id.clone();
+        let result = match relay.handle(datagram) {
+            ToMatrix::Nothing => Ok(()),
+            ToMatrix::Text(text) => post(&matrix, &room_id, &relay.dedup, json!({ "msgtype": "m.text", "body": text })).await,
+            ToMatrix::Attachment { text, filename, msgtype, data } => {
+                post_attachment(&matrix, &room_id, &relay.dedup, &text, &filename, msgtype, data).await
+            },
+            ToMatrix::Fetch(id) => {
+                sender.send(&Datagram::FetchAttachment { id, from_seq: 0 }).await?;
+                Ok(())
+            },
+        };
+        if let Err(e) = result {
+            warn!("Could not relay a message from {server} to {room_id}: {e:#}");
+        }
+    }
+    bail!("the chat server {server} closed the connection")
+}
+
+#[derive(Parser, Debug)]
+#[command(version, about = "Mirrors myrustchat servers into Matrix rooms, both ways")]
+struct Args {
+    /// TOML configuration file with the Matrix account and the rooms bridged to chat servers
+    #[arg(short, long)]
+    config: PathBuf,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    tracing_subscriber::fmt().with_ansi(std::io::stdout().is_terminal()).init();
+
+    let config = BridgeConfig::load(&args.config)?;
+    let matrix = config.matrix.connect().await?;
+    info!("Logged in to Matrix as {}", matrix.user_id);
+
+    let dedup: SharedDedup = Arc::new(Mutex::new(Dedup::new(DEDUP_CAPACITY)));
+    let mut senders = HashMap::new();
+    let mut tasks = JoinSet::new();
+    for room in &config.rooms {
+        let room_id = matrix.join(&room.matrix_room).await?;
+        if matrix.is_encrypted(&room_id).await? {
+            bail!("{} is end-to-end encrypted, which the bridge doesn't support.", room.matrix_room);
+        }
+        let client = room.connect().await?;
+        info!("Bridging {} to {}", room.matrix_room, room.server());
+        senders.insert(room_id.clone(), client.sender());
+        let relay = ChatRelay::new(client.username(), &room_id, dedup.clone());
+        tasks.spawn(relay_chat(client, matrix.clone(), relay, room.server()));
+    }
+
+    // the first sync only tells where the new messages start, the history of the rooms isn't relayed
+    let mut since = matrix.sync(None).await?.next_batch;
+    loop {
+        tokio::select! {
+            Some(result) = tasks.join_next() => result??,
+            sync = matrix.sync(Some(&since)) => {
+                let sync = match sync {
+                    Ok(sync) => sync,
+                    Err(e) => {
+                        warn!("Could not sync with the homeserver: {e:#}");
+                        tokio::time::sleep(SYNC_RETRY_DELAY).await;
+                        continue;
+                    },
+                };
+                for message in sync.messages {
+                    let own = message.transaction_id.as_ref().is_some_and(|id| dedup.lock().unwrap().contains(&DedupKey::Transaction(id.clone())));
+                    if own || !dedup.lock().unwrap().insert(DedupKey::Event(message.event_id.clone())) {
+                        continue;
+                    }
+                    let Some(sender) = senders.get(&message.room_id) else {
+                        continue;
+                    };
+                    let link = match &message.content {
+                        MessageContent::Media { url, .. } => matrix.download_url(url),
+                        _ => None,
+                    };
+                    if let Some(text) = matrix_to_chat(&message, link) {
+                        sender.send_text(&text).await?;
+                    }
+                }
+                since = sync.next_batch;
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn message(sender: &str, seq: Option<u64>, content: ChatMessageContent) -> Datagram {
+        Datagram::Message(ChatMessage { seq, ..ChatMessage::new(1, sender, content) })
+    }
+
+    fn text(text: &str) -> ToMatrix {
+        ToMatrix::Text(text.to_string())
+    }
+
+    #[test]
+    fn test_chat_relay() {
+        let dedup = Arc::new(Mutex::new(Dedup::new(DEDUP_CAPACITY)));
+        let mut relay = ChatRelay::new("bridge", "!abc:example.org", dedup.clone());
+        let hello = || message("alice", Some(5), ChatMessageContent::Text("hello".to_string()));
+        assert_eq!(relay.handle(hello()), text("<alice> hello"));
+        // sent again by the server, e.g. after `FetchSince`
+        assert_eq!(relay.handle(hello()), ToMatrix::Nothing);
+        assert_eq!(relay.handle(message("bridge", Some(6), ChatMessageContent::Text("<carol> hi".to_string()))), ToMatrix::Nothing);
+        assert_eq!(relay.handle(message("alice", None, ChatMessageContent::Text("not stored".to_string()))), text("<alice> not stored"));
+
+        // the same server bridged to another room relays it there too
+        let mut other = ChatRelay::new("bridge", "!def:example.org", dedup);
+        assert_eq!(other.handle(hello()), text("<alice> hello"));
+
+        let file = message("alice", Some(7), ChatMessageContent::File("notes.txt".to_string(), b"hello".to_vec()));
+        let attachment = ToMatrix::Attachment {
+            text: "* alice sent the file notes.txt (5 B)".to_string(),
+            filename: "notes.txt".to_string(),
+            msgtype: "m.file",
+            data: b"hello".to_vec(),
+        };
+        assert_eq!(relay.handle(file), attachment);
+    }
+
+    #[test]
+    fn test_chat_relay_download() {
+        let mut relay = ChatRelay::new("bridge", "!abc:example.org", Arc::new(Mutex::new(Dedup::new(DEDUP_CAPACITY))));
+        let offer = |id| Datagram::FileOffer { id, sender: "bob".to_string(), nickname: None, timestamp: Utc::now(), filename: "big.zip".to_string(), size: 11 };
+        assert_eq!(relay.handle(offer(8)), ToMatrix::Fetch(8));
+        let begin = Datagram::FileBegin { transfer_id: 3, id: 8, sender: "bob".to_string(), kind: AttachmentKind::File("big.zip".to_string()), size: 11 };
+        assert_eq!(relay.handle(begin), ToMatrix::Nothing);
+        for (seq, data) in ["hello ", "world"].into_iter().enumerate() {
+            assert_eq!(relay.handle(Datagram::FileChunk { transfer_id: 3, seq: seq as u64, data: data.as_bytes().to_vec() }), ToMatrix::Nothing);
+        }
+        let attachment = ToMatrix::Attachment {
+            text: "* bob sent the file big.zip (11 B)".to_string(),
+            filename: "big.zip".to_string(),
+            msgtype: "m.file",
+            data: b"hello world".to_vec(),
+        };
+        assert_eq!(relay.handle(Datagram::FileEnd { transfer_id: 3 }), attachment);
+
+        assert_eq!(relay.handle(offer(9)), ToMatrix::Fetch(9));
+        assert_eq!(relay.handle(Datagram::ServerResponse(ServerResponse::AttachmentNotFound(9))), text("* bob sent the file big.zip (11 B)"));
+        assert_eq!(relay.handle(Datagram::FileEnd { transfer_id: 3 }), ToMatrix::Nothing);
+    }
+
+    #[test]
+    fn test_matrix_to_chat() {
+        let message = |content| RoomMessage {
+            room_id: "!abc:example.org".to_string(),
+            event_id: "$1".to_string(),
+            sender: "@carol:example.org".to_string(),
+            transaction_id: None,
+            content,
+        };
+        assert_eq!(matrix_to_chat(&message(MessageContent::Text("hi".to_string())), None).as_deref(), Some("<carol> hi"));
+        assert_eq!(matrix_to_chat(&message(MessageContent::Emote("waves".to_string())), None).as_deref(), Some("* carol waves"));
+        assert_eq!(matrix_to_chat(&message(MessageContent::Notice("build passed".to_string())), None), None);
+        let media = message(MessageContent::Media { body: "cat.png".to_string(), url: "mxc://example.org/xyz".to_string() });
+        let link = "https://example.org/_matrix/media/v3/download/example.org/xyz".to_string();
+        assert_eq!(matrix_to_chat(&media, Some(link)).as_deref(), Some("* carol sent cat.png: https://example.org/_matrix/media/v3/download/example.org/xyz"));
+    }
+}
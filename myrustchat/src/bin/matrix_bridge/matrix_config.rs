@@ -0,0 +1,200 @@
+//! Configuration file of the Matrix bridge: the Matrix account and which room is bridged to which chat server.
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use reqwest::Url;
+use serde::Deserialize;
+
+use chat::client::ChatClient;
+use chat::CodecKind;
+
+use crate::matrix_api::MatrixClient;
+
+/// The Matrix account of the bridge, logging in with a password or with an access token.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MatrixAccount {
+    /// URL of the homeserver, e.g. `https://matrix.example.org`
+    pub homeserver: String,
+    /// User ID or its local part, needed with `password`
+    pub user: Option<String>,
+    pub password: Option<String>,
+    /// Access token used instead of the user and password
+    pub access_token: Option<String>,
+}
+
+/// A Matrix room and the chat server it's bridged to, with the account of the bridge on the server.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RoomMapping {
+    /// ID of the room, e.g. `!abc:example.org`, or an alias, e.g. `#chat:example.org`
+    pub matrix_room: String,
+    /// Address of the chat server [default: 127.0.0.1]
+    pub address: Option<String>,
+    /// Port of the chat server [default: 11111]
+    pub port: Option<u16>,
+    /// Unix socket of the chat server, used instead of the address and port
+    pub unix_socket: Option<PathBuf>,
+    pub username: String,
+    pub password: String,
+    /// Wire format of datagrams: cbor, json or msgpack [default: cbor]
+    pub codec: Option<String>,
+}
+
+/// Content of the configuration file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BridgeConfig {
+    pub matrix: MatrixAccount,
+    #[serde(default)]
+    pub rooms: Vec<RoomMapping>,
+}
+
+impl BridgeConfig {
+    /// Reads and checks the configuration file.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path to the TOML file.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<BridgeConfig>` - Returns the configuration if it's valid.
+    pub fn load(path: &Path) -> Result<BridgeConfig> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read {}.", path.display()))?;
+        BridgeConfig::parse(&text)
+            .with_context(|| format!("Invalid configuration file {}.", path.display()))
+    }
+
+    /// Parses and checks a configuration.
+    ///
+    /// # Arguments
+    ///
+    /// * `text` - The TOML text.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<BridgeConfig>` - Returns the configuration if it's valid.
+    pub fn parse(text: &str) -> Result<BridgeConfig> {
+        let config: BridgeConfig = toml::from_str(text)?;
+        config.matrix.homeserver()?;
+        let account = &config.matrix;
+        if account.access_token.is_none() && (account.user.is_none() || account.password.is_none()) {
+            bail!("the Matrix account needs either an access_token or a user and a password");
+        }
+        if config.rooms.is_empty() {
+            bail!("no rooms to bridge");
+        }
+        let mut rooms = HashSet::new();
+        for room in &config.rooms {
+            if !rooms.insert(room.matrix_room.as_str()) {
+                bail!("the room {} is bridged twice", room.matrix_room);
+            }
+            room.codec()?;
+        }
+        Ok(config)
+    }
+}
+
+impl MatrixAccount {
+    /// Returns the parsed URL of the homeserver.
+    fn homeserver(&self) -> Result<Url> {
+        let url = Url::parse(&self.homeserver).with_context(|| format!("invalid homeserver URL {}", self.homeserver))?;
+        if !matches!(url.scheme(), "http" | "https") {
+            bail!("the homeserver URL {} isn't an http(s) URL", self.homeserver);
+        }
+        Ok(url)
+    }
+
+    /// Logs in to the homeserver.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<MatrixClient>` - Returns the logged in account.
+    pub async fn connect(&self) -> Result<MatrixClient> {
+        let homeserver = self.homeserver()?;
+        match (&self.access_token, &self.user, &self.password) {
+            (Some(token), _, _) => MatrixClient::with_token(homeserver, token).await,
+            (None, Some(user), Some(password)) => MatrixClient::login(homeserver, user, password).await,
+            _ => bail!("the Matrix account needs either an access_token or a user and a password"),
+        }
+    }
+}
+
+impl RoomMapping {
+    /// Returns the codec of the chat server.
+    fn codec(&self) -> Result<CodecKind> {
+        match &self.codec {
+            Some(codec) => codec.parse().map_err(|e: String| anyhow::anyhow!(e)),
+            None => Ok(CodecKind::default()),
+        }
+    }
+
+    /// Returns where the chat server is, for the log.
+    pub fn server(&self) -> String {
+        match &self.unix_socket {
+            Some(path) => path.display().to_string(),
+            None => format!("{}:{}", self.address.as_deref().unwrap_or("127.0.0.1"), self.port.unwrap_or(11111)),
+        }
+    }
+
+    /// Logs in to the chat server.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<ChatClient>` - Returns the connection.
+    pub async fn connect(&self) -> Result<ChatClient> {
+        let codec = self.codec()?;
+        let client = match &self.unix_socket {
+            Some(path) => ChatClient::connect_unix(path, &self.username, &self.password, codec).await,
+            None => {
+                let address = self.address.as_deref().unwrap_or("127.0.0.1");
+                ChatClient::connect(address, self.port.unwrap_or(11111), &self.username, &self.password, codec).await
+            },
+        };
+        client.with_context(|| format!("Could not log in to {} as {}.", self.server(), self.username))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::matrix_config::BridgeConfig;
+
+    #[test]
+    fn test_parse() {
+        let config = BridgeConfig::parse(r##"
+            [matrix]
+            homeserver = "https://matrix.example.org"
+            user = "bridge"
+            password = "secret"
+
+            [[rooms]]
+            matrix_room = "#chat:example.org"
+            username = "bridge"
+            password = "secret"
+
+            [[rooms]]
+            matrix_room = "!abc:example.org"
+            unix_socket = "/run/myrustchat/chat.sock"
+            username = "bridge"
+            password = "secret"
+            codec = "json"
+        "##).unwrap();
+        assert_eq!(config.rooms.len(), 2);
+        assert_eq!(config.rooms[0].server(), "127.0.0.1:11111");
+        assert_eq!(config.rooms[1].server(), "/run/myrustchat/chat.sock");
+
+        let room = "[[rooms]]\nmatrix_room = \"!abc:example.org\"\nusername = \"bridge\"\npassword = \"secret\"\n";
+        let token = "[matrix]\nhomeserver = \"https://matrix.example.org\"\naccess_token = \"syt_x\"\n";
+        assert!(BridgeConfig::parse(&format!("{token}{room}")).is_ok());
+        // no rooms, no credentials, a room twice, an unknown codec, a bad homeserver
+        assert!(BridgeConfig::parse(token).is_err());
+        assert!(BridgeConfig::parse(&format!("[matrix]\nhomeserver = \"https://matrix.example.org\"\nuser = \"bridge\"\n{room}")).is_err());
+        assert!(BridgeConfig::parse(&format!("{token}{room}{room}")).is_err());
+        assert!(BridgeConfig::parse(&format!("{token}{room}codec = \"xml\"\n")).is_err());
+        assert!(BridgeConfig::parse(&format!("[matrix]\nhomeserver = \"matrix.example.org\"\naccess_token = \"syt_x\"\n{room}")).is_err());
+    }
+}
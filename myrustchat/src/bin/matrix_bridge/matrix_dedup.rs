@@ -0,0 +1,90 @@
+//! Memory of the recently relayed messages, which keeps the bridge from relaying a message twice or echoing its own.
+
+use std::collections::{HashSet, VecDeque};
+
+/// How many keys are remembered, older ones are forgotten first.
+pub const DEDUP_CAPACITY: usize = 4096;
+
+/// Why a message is remembered. The kinds are kept apart so that IDs from both sides can't collide.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum DedupKey {
+    /// Matrix event relayed to the chat, or posted by the bridge
+    Event(String),
+    /// Transaction of the bridge on Matrix, its events come back in the sync with this ID
+    Transaction(String),
+    /// Stored chat message or attachment relayed to a Matrix room, by the ID the chat server gave it
+    Chat { room_id: String, seq: u64 },
+}
+
+/// Bounded set of the keys of recent messages.
+#[derive(Debug)]
+pub struct Dedup {
+    keys: HashSet<DedupKey>,
+    /// Keys in the order they were added
+    order: VecDeque<DedupKey>,
+    capacity: usize,
+}
+
+impl Dedup {
+    /// Creates an empty set.
+    ///
+    /// # Arguments
+    ///
+    /// * `capacity` - How many keys are remembered.
+    ///
+    /// # Returns
+    ///
+    /// * `Dedup` - Returns the set.
+    pub fn new(capacity: usize) -> Dedup {
+        Dedup { keys: HashSet::new(), order: VecDeque::new(), capacity }
+    }
+
+    /// Remembers a key.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - Returns whether the key is new, `false` if the message was already seen.
+    pub fn insert(&mut self, key: DedupKey) -> bool {
+        if self.keys.contains(&key) {
+            return false;
+        }
+        if self.order.len() == self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.keys.remove(&oldest);
+            }
+        }
+        self.keys.insert(key.clone());
+        self.order.push_back(key);
+        true
+    }
+
+    /// Returns whether a key is remembered.
+    pub fn contains(&self, key: &DedupKey) -> bool {
+        self.keys.contains(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::matrix_dedup::{Dedup, DedupKey};
+
+    #[test]
+    fn test_dedup() {
+        let mut dedup = Dedup::new(2);
+        let event = |id: &str| DedupKey::Event(id.to_string());
+        assert!(dedup.insert(event("$1")));
+        assert!(!dedup.insert(event("$1")));
+        assert!(dedup.insert(DedupKey::Transaction("$1".to_string())));
+        assert!(dedup.contains(&event("$1")));
+
+        // the oldest key is forgotten
+        assert!(dedup.insert(event("$2")));
+        assert!(!dedup.contains(&event("$1")));
+        assert!(dedup.contains(&event("$2")));
+        assert!(dedup.insert(event("$1")));
+    }
+}
@@ -1,25 +1,86 @@
 use anyhow::{Result, Context};
-use chat::{Datagram, ServerResponse};
-use tokio::net::tcp::OwnedWriteHalf;
-use tokio::try_join;
-use std::collections::HashMap;
+use chat::{AdminCommand, AttachmentId, AttachmentKind, ChatMessageContent, CodecKind, Datagram, MessageId, MessageSeq, ServerResponse, ServerStatistics, SessionCodec, TransferId};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use std::collections::{HashMap, HashSet};
 
-use tokio::net::{TcpStream, TcpListener};
-use std::net::SocketAddr;
+use tokio::net::TcpListener;
+use std::net::IpAddr;
 
 use std::process::exit;
 
-use clap::{Parser, Subcommand};
-use log;
+use clap::{Parser, Subcommand, ValueEnum};
+use serde::Deserialize;
+use tracing::level_filters::LevelFilter;
+use tracing::Instrument;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
 
 use chat::ChatMessage;
 use chat::EmptyResult;
-use tokio;
-use tokio::sync::{Mutex, RwLock};
-use std::sync::Arc;
+use chat::i18n::Lang;
+use chat::{format_size, t};
+use tokio::sync::{Notify, RwLock};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::path::{Path, PathBuf};
+use std::io::IsTerminal;
+use std::future::Future;
+use std::time::Duration;
+use tokio::time::Instant;
 
+mod server_admin;
+mod server_api;
+mod server_attachments;
+use server_attachments::make_thumbnail;
+mod server_audit;
+use server_audit::{AuditEvent, COMMAND_LINE_ACTOR};
+mod server_auth;
+use server_auth::{AuthBackend, Authenticator, DatabaseAuthenticator, HtpasswdAuthenticator};
+mod server_backup;
+mod server_bench;
+use server_bench::BenchArgs;
+mod server_blocks;
+use server_blocks::{blockable_sender, BlockLists, MAX_BLOCKED_USERS};
+mod server_config;
+use server_config::{FileConfig, LogFormat, Storage};
 mod server_db;
-use server_db::ServerDatabase;
+mod server_demo;
+use server_demo::DemoArgs;
+mod server_export;
+use server_export::ExportFormat;
+mod server_federation;
+use server_federation::RelayCache;
+mod server_import;
+mod server_invite;
+mod server_filter;
+use server_filter::{ContentFilter, FilterAction};
+mod server_flood;
+use server_flood::{FloodConfig, FloodGuard, FloodVerdict};
+mod server_limits;
+use server_limits::{AttachmentLimits, PendingStorage, Reservation, StorageQuotas};
+mod server_lockout;
+use server_lockout::{LockoutConfig, LoginOutcome};
+#[cfg(test)]
+mod server_integration;
+mod server_migrations;
+mod server_preview;
+use server_preview::{LinkPreviewer, PreviewConfig};
+mod server_queue;
+mod server_registration;
+use server_registration::RegistrationConfig;
+mod server_reload;
+use server_reload::{LogHandle, Reloader};
+use server_queue::{Offer, Outgoing, QueuePolicy, QueueStats, SendQueue};
+mod server_router;
+use server_router::{MessageRouter, Route};
+use server_db::{FilteredRecord, LoginRecord, MessageRecord, NewPassword, ServerDatabase, StorageUsage, StoredAttachment, MEMORY_DATABASE};
+mod server_tls;
+mod server_totp;
+mod server_transfer;
+use server_transfer::IncomingTransfer;
+mod server_transport;
+use server_transport::{Listener, PeerAddr, ReadHalf, WriteHalf};
+use tokio_rustls::TlsAcceptor;
 
 /// Enum representing various server-related errors.
 #[derive(Debug, thiserror::Error)]
@@ -32,14 +93,311 @@ pub enum ServerError {
     LoginError,
     #[error("Message spoofing detected")]
     SpoofingError,
+    #[error("File transfer failed: {0}")]
+    TransferFailed(String),
+}
+
+/// What happens when a user logs in while already logged in from another connection.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DuplicateLogin {
+    /// the new login is refused while the user is logged in
+    RejectNew,
+    /// the older connections of the user are closed
+    KickOld,
+    /// all connections stay logged in and share the messages sent to the user
+    #[default]
+    AllowMulti,
+}
+
+/// Struct holding the configurable limits of the server.
+#[derive(Clone, Debug)]
+struct ServerConfig {
+    /// Maximum size of a single datagram accepted from a client
+    max_message_size: usize,
+    /// Wire format used to encode datagrams
+    codec: CodecKind,
+    /// Clients which don't send anything for this long are disconnected, idle clients are pinged halfway through
+    idle_timeout: Option<Duration>,
+    /// Clients and linked servers which don't take a datagram within this time are disconnected, `None` waits forever
+    write_timeout: Option<Duration>,
+    /// Directory where image and file attachments are stored
+    attachment_dir: PathBuf,
+    /// Messages older than this are periodically deleted, `None` keeps them forever
+    retention: Option<Duration>,
+    /// Maximum number of open connections, `None` for no limit
+    max_clients: Option<usize>,
+    /// Maximum number of open connections from a single IP address, `None` for no limit
+    max_clients_per_ip: Option<usize>,
+    /// When the server is full, the client idle for the longest time is disconnected to make room
+    /// if it has been idle for at least this long, `None` never disconnects idle clients
+    evict_idle_after: Option<Duration>,
+    /// Thresholds of the flood protection
+    flood: FloodConfig,
+    /// Thresholds of the lockout after failed logins
+    lockout: LockoutConfig,
+    /// Limits of image and file attachments
+    attachments: AttachmentLimits,
+    /// Address and port of the HTTP API, `None` disables the API
+    api_address: Option<String>,
+    /// Loopback address and port of the admin console
+    admin_address: Option<String>,
+    /// Unix socket of the admin console
+    admin_socket: Option<PathBuf>,
+    /// Rules checked against every text message, `None` disables the filter
+    content_filter: Option<ContentFilter>,
+    /// Whether large frames are compressed for clients offering it
+    compression: bool,
+    /// Maximum number of datagrams waiting to be written to a single client
+    send_queue_size: usize,
+    /// What happens when a client doesn't read fast enough and its send queue is full
+    send_queue_policy: QueuePolicy,
+    /// What happens when a user logs in from a second connection
+    duplicate_login: DuplicateLogin,
+    /// Whether clients may log in as guests without an account
+    allow_guests: bool,
+    /// Settings of the registration of new users by clients, `None` disables it
+    registration: Option<RegistrationConfig>,
+    /// ID of this server among linked servers, shown after the names of its users on the other servers
+    server_id: String,
+    /// Addresses of the servers this server links to and relays messages with
+    peers: Vec<String>,
+    /// Secret shared by linked servers, `None` refuses links from other servers
+    peer_secret: Option<String>,
+    /// Settings of the link previews, `None` disables them
+    link_previews: Option<PreviewConfig>,
+    /// htpasswd file the passwords are checked against, `None` checks them against the database
+    htpasswd_file: Option<PathBuf>,
+}
+
+impl ServerConfig {
+    /// Takes the settings which can change while the server runs from a reloaded configuration.
+    /// Addresses, storage, the wire format, the API, the admin console and linked servers are kept until a restart.
+    ///
+    /// # Arguments
+    ///
+    /// * `new` - The reloaded configuration.
+    ///
+    /// # Returns
+    ///
+    /// * `ServerConfig` - Returns the configuration to be used from now on.
+    fn reloaded(&self, new: ServerConfig) -> ServerConfig {
+        ServerConfig {
+            max_message_size: new.max_message_size,
+            idle_timeout: new.idle_timeout,
+            write_timeout: new.write_timeout,
+            max_clients: new.max_clients,
+            max_clients_per_ip: new.max_clients_per_ip,
+            evict_idle_after: new.evict_idle_after,
+            flood: new.flood,
+            lockout: new.lockout,
+            attachments: new.attachments,
+            content_filter: new.content_filter,
+            compression: new.compression,
+            send_queue_size: new.send_queue_size,
+            send_queue_policy: new.send_queue_policy,
+            duplicate_login: new.duplicate_login,
+            allow_guests: new.allow_guests,
+            registration: new.registration,
+            link_previews: new.link_previews,
+            ..self.clone()
+        }
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            max_message_size: chat::DEFAULT_MAX_FRAME_SIZE,
+            codec: CodecKind::default(),
+            idle_timeout: Some(Duration::from_secs(DEFAULT_IDLE_TIMEOUT)),
+            write_timeout: Some(chat::DEFAULT_WRITE_TIMEOUT),
+            attachment_dir: PathBuf::from(DEFAULT_ATTACHMENT_DIR),
+            retention: None,
+            max_clients: Some(DEFAULT_MAX_CLIENTS),
+            max_clients_per_ip: None,
+            evict_idle_after: None,
+            flood: FloodConfig::default(),
+            lockout: LockoutConfig::default(),
+            attachments: AttachmentLimits::default(),
+            api_address: None,
+            admin_address: None,
+            admin_socket: None,
+            content_filter: None,
+            compression: true,
+            send_queue_size: server_queue::DEFAULT_SEND_QUEUE_SIZE,
+            send_queue_policy: QueuePolicy::default(),
+            duplicate_login: DuplicateLogin::default(),
+            allow_guests: false,
+            registration: None,
+            server_id: server_federation::random_server_id(),
+            peers: Vec::new(),
+            peer_secret: None,
+            link_previews: None,
+            htpasswd_file: None,
+        }
+    }
+}
+
+/// Default address the server binds to.
+const DEFAULT_ADDRESS: &str = "127.0.0.1";
+
+/// Default port the server binds to.
+const DEFAULT_PORT: u16 = 11111;
+
+/// Default SQLite database file, relative to the working directory.
+const DEFAULT_DB_FILE: &str = "server.db";
+
+/// Default directory where attachments are stored, relative to the working directory.
+const DEFAULT_ATTACHMENT_DIR: &str = "attachments";
+
+/// Default number of seconds of inactivity after which a client is disconnected.
+const DEFAULT_IDLE_TIMEOUT: u64 = 60;
+
+/// Default maximum number of open connections.
+const DEFAULT_MAX_CLIENTS: usize = 1000;
+
+/// How long a rejected connection may take to send its login datagram and receive the response.
+const REJECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a user asked for a TOTP code has to type it in.
+const TOTP_CODE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// How often old messages are pruned when a retention period is configured.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How often the ephemeral messages which expired are deleted.
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Longest time an ephemeral message is kept, in seconds. Longer TTLs are shortened to it.
+const MAX_MESSAGE_TTL: u32 = 7 * 24 * 60 * 60;
+
+/// How long the partial file of an interrupted upload is kept for the client to resume the upload.
+const PARTIAL_UPLOAD_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Maximum number of characters of a nickname.
+const MAX_NICKNAME_LENGTH: usize = 32;
+
+/// Maximum number of characters of the status line of a profile.
+const MAX_STATUS_LENGTH: usize = 80;
+
+/// Reason given to guests trying to send images, files or voice notes.
+const GUEST_ATTACHMENT_REASON: &str = "guests can't send attachments";
+
+/// Reason given to users who aren't admins trying to post to the announcement channel.
+const ANNOUNCEMENT_REASON: &str = "only admins can post announcements";
+
+/// Reason given to admins posting an image, a file or a voice note to the announcement channel.
+const ANNOUNCEMENT_TEXT_REASON: &str = "announcements can only be text";
+
+/// Maximum number of stored messages sent for a single `FetchSince`.
+const MAX_FETCHED_MESSAGES: u32 = 200;
+
+/// Struct representing a connected and authenticated client.
+struct ClientHandle {
+    /// Username of the client
+    username: String,
+    /// Display name of the client, shown next to the username
+    nickname: Option<String>,
+    /// Queue of datagrams to be written to the client by its writer task
+    queue: Arc<SendQueue>,
+    /// Notified when the client should be disconnected
+    disconnect: Arc<Notify>,
+    /// Time of the last datagram received from the client, pongs don't count
+    last_active: Arc<Mutex<Instant>>,
+    /// Set when the client is being disconnected to make room for a new one
+    evicted: bool,
+}
+
+/// A connected client as listed by the admin console.
+struct ClientInfo {
+    addr: PeerAddr,
+    username: String,
+    /// Time since the last datagram received from the client
+    idle: Duration,
+    queue: QueueStats,
+}
+
+/// Counters of the running server shown by the admin console.
+struct ServerStats {
+    uptime: Duration,
+    /// Open connections, including those which are not authenticated yet
+    connections: usize,
+    /// Authenticated connections
+    clients: usize,
+    /// Distinct users online
+    users: usize,
+    /// Linked peer servers
+    peers: usize,
+    /// Datagrams waiting in the send queues of all clients
+    queued: usize,
+    /// Datagrams dropped from the send queues of all connected clients
+    dropped: u64,
+}
+
+/// Struct representing a linked peer server.
+struct PeerHandle {
+    /// ID of the peer server
+    server_id: String,
+    /// Queue of datagrams to be written to the peer by its writer task
+    queue: Arc<SendQueue>,
+    /// Notified when the link should be closed
+    disconnect: Arc<Notify>,
+}
+
+/// Numbers of open connections, including those which are not authenticated yet.
+#[derive(Default)]
+struct ConnectionCounts {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+/// A place taken by an open connection in the connection limits, released when dropped.
+struct ConnectionSlot {
+    counts: Arc<Mutex<ConnectionCounts>>,
+    /// IP address of a TCP connection, Unix socket connections count only towards the total
+    ip: Option<IpAddr>,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut counts = self.counts.lock().unwrap();
+        counts.total -= 1;
+        if let Some(ip) = self.ip {
+            if let Some(count) = counts.per_ip.get_mut(&ip) {
+                *count -= 1;
+                if *count == 0 {
+                    counts.per_ip.remove(&ip);
+                }
+            }
+        }
+    }
 }
 
 /// Struct representing the server context, holding shared data among asynchronous tasks.
 #[derive(Clone)]
 struct ServerContext {
-    socket_table: Arc<Mutex<HashMap<SocketAddr, OwnedWriteHalf>>>,
-    username_table: Arc<RwLock<HashMap<SocketAddr, String>>>,
-    database: Arc<Mutex<ServerDatabase>>
+    /// Replaced as a whole when the configuration is reloaded
+    config: Arc<std::sync::RwLock<Arc<ServerConfig>>>,
+    /// Rebuilds the configuration from the configuration file, `None` if it can't be reloaded
+    reloader: Option<Arc<Reloader>>,
+    client_table: Arc<RwLock<HashMap<PeerAddr, ClientHandle>>>,
+    database: Arc<ServerDatabase>,
+    /// Checks the passwords of users logging in, chosen when the server starts
+    authenticator: Arc<dyn Authenticator>,
+    next_transfer_id: Arc<AtomicU64>,
+    connections: Arc<Mutex<ConnectionCounts>>,
+    flood: Arc<Mutex<FloodGuard>>,
+    router: MessageRouter,
+    peers: Arc<RwLock<HashMap<PeerAddr, PeerHandle>>>,
+    relayed: Arc<Mutex<RelayCache>>,
+    previews: Arc<LinkPreviewer>,
+    blocks: Arc<Mutex<BlockLists>>,
+    /// Held while a message is stored and queued, so messages are delivered in the order of their IDs
+    publishing: Arc<tokio::sync::Mutex<()>>,
+    /// Attachments accepted against the storage quotas but not stored yet
+    pending_storage: PendingStorage,
+    started: Instant,
 }
 
 impl ServerContext {
@@ -48,46 +406,376 @@ impl ServerContext {
     /// # Arguments
     ///
     /// * `file` - A string slice that holds the path to the database file.
+    /// * `config` - The server configuration.
     ///
     /// # Returns
     ///
     /// * `Result<ServerContext>` - Returns a result containing a `ServerContext` instance if successful.
-    pub async fn new(file: &str) -> Result<ServerContext> {
+    pub async fn new(file: &str, config: ServerConfig) -> Result<ServerContext> {
+        let database = ServerDatabase::new(file, &config.attachment_dir).await?;
+        let blocks = BlockLists::new(database.blocks().await?);
+        let database = Arc::new(database);
+        let authenticator: Arc<dyn Authenticator> = match &config.htpasswd_file {
+            Some(path) => Arc::new(HtpasswdAuthenticator::load(path)?),
+            None => Arc::new(DatabaseAuthenticator::new(database.clone())),
+        };
         Ok(ServerContext {
-            socket_table: Arc::new(Mutex::new(HashMap::<SocketAddr, OwnedWriteHalf>::new())),
-            username_table: Arc::new(RwLock::new(HashMap::<SocketAddr, String>::new())),
-            database: Arc::new(Mutex::new(ServerDatabase::new(file).await?))
+            client_table: Arc::new(RwLock::new(HashMap::<PeerAddr, ClientHandle>::new())),
+            database,
+            authenticator,
+            next_transfer_id: Arc::new(AtomicU64::new(1)),
+            connections: Arc::new(Mutex::new(ConnectionCounts::default())),
+            flood: Arc::new(Mutex::new(FloodGuard::new(config.flood.clone()))),
+            router: MessageRouter,
+            peers: Arc::new(RwLock::new(HashMap::new())),
+            relayed: Arc::new(Mutex::new(RelayCache::default())),
+            previews: Arc::new(LinkPreviewer::new(&config.link_previews.clone().unwrap_or_default())?),
+            blocks: Arc::new(Mutex::new(blocks)),
+            publishing: Arc::new(tokio::sync::Mutex::new(())),
+            pending_storage: PendingStorage::default(),
+            started: Instant::now(),
+            config: Arc::new(std::sync::RwLock::new(Arc::new(config))),
+            reloader: None,
         })
     }
 
-    /// Adds a new client to the server context.
+    /// Returns the current configuration. It's replaced when the configuration is reloaded,
+    /// so long running tasks should read it again instead of keeping it.
+    pub fn config(&self) -> Arc<ServerConfig> {
+        self.config.read().unwrap().clone()
+    }
+
+    /// Applies the settings of a reloaded configuration which can change while the server runs.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The reloaded configuration.
+    pub fn apply_config(&self, config: ServerConfig) {
+        self.flood.lock().unwrap().set_config(config.flood.clone());
+        if let Some(previews) = &config.link_previews {
+            self.previews.set_config(previews);
+        }
+        let mut current = self.config.write().unwrap();
+        *current = Arc::new(current.reloaded(config));
+    }
+
+    /// Reloads the configuration file, without dropping any connections, and records it in the audit log.
+    ///
+    /// # Arguments
+    ///
+    /// * `actor` - Who asked for the reload, `console` or `SIGHUP`.
+    /// * `ip` - The IP address of the actor, `None` for Unix socket connections and signals.
+    ///
+    /// # Returns
+    ///
+    /// * `EmptyResult` - Returns an empty result if the configuration was reloaded.
+    pub async fn reload(&self, actor: &str, ip: Option<IpAddr>) -> EmptyResult {
+        let reloaded = match &self.reloader {
+            Some(reloader) => reloader.reload(self),
+            None => Err(anyhow::anyhow!("The configuration of this server can't be reloaded.")),
+        };
+        let detail = match &reloaded {
+            Ok(()) => "ok".to_string(),
+            Err(e) => format!("failed: {e:#}"),
+        };
+        self.audit(AuditEvent::Reload, Some(actor), None, ip, Some(&detail)).await?;
+        reloaded
+    }
+
+    /// Allocates a server-wide unique ID for relaying a file transfer.
+    ///
+    /// # Returns
+    ///
+    /// * `TransferId` - Returns a fresh transfer ID.
+    pub fn next_transfer_id(&self) -> TransferId {
+        self.next_transfer_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Takes a place for a new connection if the connection limits allow it. When the server is full,
+    /// an idle client may be disconnected to make room, according to `evict_idle_after`.
+    ///
+    /// # Arguments
+    ///
+    /// * `ip` - The IP address of the new connection, `None` for Unix socket connections.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<ConnectionSlot, &'static str>` - Returns the place of the connection, or the reason why it's rejected.
+    pub async fn admit(&self, ip: Option<IpAddr>) -> Result<ConnectionSlot, &'static str> {
+        let (total, from_ip) = {
+            let counts = self.connections.lock().unwrap();
+            (counts.total, ip.and_then(|ip| counts.per_ip.get(&ip).copied()).unwrap_or(0))
+        };
+
+        if self.config().max_clients_per_ip.is_some_and(|max| from_ip >= max) {
+            return Err("too many connections from the address");
+        }
+        if self.config().max_clients.is_some_and(|max| total >= max) && !self.evict_idle_client().await {
+            return Err("the server is full");
+        }
+
+        // The evicted client releases its place only when its task ends, so the total may exceed the limit for a moment
+        let mut counts = self.connections.lock().unwrap();
+        counts.total += 1;
+        if let Some(ip) = ip {
+            *counts.per_ip.entry(ip).or_default() += 1;
+        }
+        Ok(ConnectionSlot { counts: self.connections.clone(), ip })
+    }
+
+    /// Disconnects the client which is idle for the longest time, if it has been idle for at least `evict_idle_after`.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - Returns `true` if a client was disconnected.
+    async fn evict_idle_client(&self) -> bool {
+        let Some(evict_idle_after) = self.config().evict_idle_after else {
+            return false;
+        };
+
+        let mut clients = self.client_table.write().await;
+        let idlest = clients.iter_mut()
+            .filter(|(_, client)| !client.evicted)
+            .map(|(addr, client)| {
+                let last_active = *client.last_active.lock().unwrap();
+                (*addr, last_active, client)
+            })
+            .filter(|(_, last_active, _)| last_active.elapsed() >= evict_idle_after)
+            .min_by_key(|(_, last_active, _)| *last_active);
+
+        match idlest {
+            Some((addr, _, client)) => {
+                tracing::info!("Disconnecting idle client {addr} of user {} to make room.", client.username);
+                // The notice is best effort, the client is disconnected even if its queue is full
+                client.queue.try_offer(Outgoing::new(Datagram::ServerResponse(ServerResponse::ServerFull)));
+                client.disconnect.notify_one();
+                client.evicted = true;
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Adds a new client to the server context and spawns the task writing datagrams to it, confirming the login.
+    /// If the user is already logged in, the duplicate login policy decides whether the new client is refused,
+    /// the older connections are closed or all of them stay. Both the refused client and the closed ones
+    /// are told with `ServerResponse::AlreadyLoggedIn`.
     ///
     /// # Arguments
     ///
-    /// * `addr` - The socket address of the client.
+    /// * `addr` - The address of the client.
     /// * `username` - The username of the client.
-    /// * `write_half` - The writable half of the TCP stream.
-    pub async fn add_client(&self, addr: SocketAddr, username: &str, write_half: OwnedWriteHalf) {
-        let mut clients = self.socket_table.lock().await;
-        let mut usernames = self.username_table.write().await;
-        clients.insert(addr, write_half); 
-        usernames.insert(addr, username.to_string());
+    /// * `nickname` - The nickname of the client.
+    /// * `last_active` - Time of the last activity of the client, updated by its session.
+    /// * `write_half` - The writable half of the connection.
+    /// * `codec` - The codec of the session, compressing large frames if the client agreed to it.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(Arc<Notify>, bool), ServerError>` - Returns a handle which is notified when the client should be disconnected
+    ///   and whether the user was already logged in, or an error if the login was refused.
+    pub async fn add_client(&self, addr: PeerAddr, username: &str, nickname: Option<String>, last_active: Arc<Mutex<Instant>>, mut write_half: WriteHalf,
+                            codec: SessionCodec) -> Result<(Arc<Notify>, bool), ServerError> {
+        let config = self.config();
+        // The table stays locked from the check until the client is added, so that two logins can't both pass it
+        let mut clients = self.client_table.write().await;
+        let logged_in = clients.values().any(|client| client.username == username);
+        // A guest name belongs to whoever took it first
+        let policy = if chat::is_guest(username) { DuplicateLogin::RejectNew } else { config.duplicate_login };
+        match policy {
+            DuplicateLogin::RejectNew if logged_in => {
+                drop(clients);
+                // The login is refused anyway, even if the client can't be told
+                let _ = send_response(&mut write_half, &config, ServerResponse::AlreadyLoggedIn).await;
+                return Err(ServerError::LoginError);
+            },
+            DuplicateLogin::KickOld => {
+                let notice = Outgoing::new(Datagram::ServerResponse(ServerResponse::AlreadyLoggedIn));
+                for client in clients.values().filter(|client| client.username == username) {
+                    // The notice is best effort, the client is disconnected even if its queue is full
+                    client.queue.try_offer(notice.clone());
+                    client.disconnect.notify_one();
+                }
+            },
+            _ => {},
+        }
+
+        let queue = SendQueue::new(config.send_queue_size, config.send_queue_policy);
+        let disconnect = Arc::new(Notify::new());
+        // The login is confirmed by the first datagram of the queue, before anything broadcast to the client
+        let response = if codec.compression { ServerResponse::LoginOkCompressed } else { ServerResponse::LoginOk };
+        queue.try_offer(Outgoing::new(Datagram::ServerResponse(response)));
+
+        let writer_queue = queue.clone();
+        let writer_disconnect = disconnect.clone();
+        let write_timeout = config.write_timeout;
+        tokio::spawn(async move {
+            send_datagrams(addr, write_half, writer_queue, codec, write_timeout, writer_disconnect).await
+        }.in_current_span());
+
+        clients.insert(addr, ClientHandle {
+            username: username.to_string(),
+            nickname,
+            queue,
+            disconnect: disconnect.clone(),
+            last_active,
+            evicted: false,
+        });
+
+        tracing::info!("Client {addr} connected.");
+        Ok((disconnect, logged_in))
+    }
+
+    /// Adds a linked peer server and spawns the task writing datagrams to it.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - The address of the peer.
+    /// * `server_id` - The ID of the peer.
+    /// * `write_half` - The writable half of the link.
+    ///
+    /// # Returns
+    ///
+    /// * `(Arc<SendQueue>, Arc<Notify>)` - Returns the send queue of the link and a handle notified when it should be closed.
+    pub async fn add_peer(&self, addr: PeerAddr, server_id: &str, write_half: WriteHalf) -> (Arc<SendQueue>, Arc<Notify>) {
+        let queue = SendQueue::new(self.config().send_queue_size, self.config().send_queue_policy);
+        let disconnect = Arc::new(Notify::new());
+
+        let writer_queue = queue.clone();
+        let writer_disconnect = disconnect.clone();
+        let codec = SessionCodec::from(self.config().codec);
+        let write_timeout = self.config().write_timeout;
+        tokio::spawn(async move {
+            send_datagrams(addr, write_half, writer_queue, codec, write_timeout, writer_disconnect).await
+        }.in_current_span());
+
+        let mut peers = self.peers.write().await;
+        peers.insert(addr, PeerHandle { server_id: server_id.to_string(), queue: queue.clone(), disconnect: disconnect.clone() });
+        (queue, disconnect)
+    }
+
+    /// Removes a linked peer server. Its writer task terminates once the queued datagrams are written.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - The address of the peer.
+    pub async fn remove_peer(&self, addr: PeerAddr) {
+        let mut peers = self.peers.write().await;
+        if let Some(peer) = peers.remove(&addr) {
+            peer.queue.close();
+        }
+    }
+
+    /// Removes a client from the server context. Its writer task terminates once the queued datagrams are written.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - The address of the client.
+    pub async fn remove_client(&self, addr: PeerAddr) {
+        let mut clients = self.client_table.write().await;
+        if let Some(client) = clients.remove(&addr) {
+            client.queue.close();
+        }
+        tracing::info!("Client {addr} disconnected.");
+    }
+
+    /// Counts the connections of a user.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - The username of the user.
+    ///
+    /// # Returns
+    ///
+    /// * `usize` - Returns the number of connections authenticated as the user.
+    pub async fn count_connections(&self, username: &str) -> usize {
+        let clients = self.client_table.read().await;
+        clients.values().filter(|client| client.username == username).count()
+    }
+
+    /// Lists the users which are currently connected.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<String>` - Returns the sorted usernames, each listed once.
+    pub async fn online_users(&self) -> Vec<String> {
+        let clients = self.client_table.read().await;
+        let mut users: Vec<String> = clients.values().map(|client| client.username.clone()).collect();
+        users.sort();
+        users.dedup();
+        users
+    }
+
+    /// Returns the nickname of a connected client.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - The address of the client.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<String>` - Returns the nickname, `None` if the client has none.
+    pub async fn nickname_of(&self, addr: PeerAddr) -> Option<String> {
+        let clients = self.client_table.read().await;
+        clients.get(&addr).and_then(|client| client.nickname.clone())
+    }
+
+    /// Sets or removes the nickname of a user and announces the change to all other clients.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - The address of the client requesting the change.
+    /// * `username` - A string slice that holds the username.
+    /// * `nickname` - The new nickname, `None` removes it.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<ServerResponse>` - Returns the response to be sent to the user.
+    pub async fn set_nickname(&self, addr: PeerAddr, username: &str, nickname: Option<String>) -> Result<ServerResponse> {
+        if let Some(nickname) = &nickname {
+            if let Err(reason) = validate_nickname(nickname) {
+                return Ok(ServerResponse::NicknameRejected(reason.to_string()));
+            }
+        }
+
+        if !self.database.set_nickname(username, nickname.as_deref()).await? {
+            return Ok(ServerResponse::NicknameRejected("The nickname is already taken.".to_string()));
+        }
+
+        {
+            let mut clients = self.client_table.write().await;
+            for client in clients.values_mut().filter(|client| client.username == username) {
+                client.nickname = nickname.clone();
+            }
+        }
 
-        log::info!("Client {addr} connected.");
+        tracing::info!("User {username} changed their nickname to {nickname:?}.");
+        self.broadcast_datagram(addr, Datagram::Renamed { username: username.to_string(), nickname: nickname.clone() }).await?;
+        Ok(ServerResponse::NicknameChanged(nickname))
     }
 
-    /// Removes a client from the server context.
+    /// Changes the profile of a user and tells all other clients, which fetch it again when they need it.
     ///
     /// # Arguments
     ///
-    /// * `addr` - The socket address of the client.
-    pub async fn remove_client(&self, addr: SocketAddr) {
-        let mut clients = self.socket_table.lock().await;
-        let mut usernames = self.username_table.write().await;
+    /// * `addr` - The address of the client requesting the change.
+    /// * `username` - A string slice that holds the username.
+    /// * `status` - The new status line, `None` keeps it and an empty one removes it.
+    /// * `avatar` - The new avatar encoded as PNG, `None` keeps it and an empty one removes it.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<ServerResponse>` - Returns the response to be sent to the user.
+    pub async fn set_profile(&self, addr: PeerAddr, username: &str, status: Option<String>, avatar: Option<Vec<u8>>) -> Result<ServerResponse> {
+        let status = status.map(|status| status.trim().to_string());
+        if let Err(reason) = status.as_deref().map_or(Ok(()), validate_status).and(avatar.as_deref().map_or(Ok(()), validate_avatar)) {
+            return Ok(ServerResponse::ProfileRejected(reason.to_string()));
+        }
 
-        clients.remove(&addr); 
-        usernames.remove(&addr);
-        log::info!("Client {addr} disconnected.");
+        self.database.set_profile(username, status.as_deref(), avatar.as_deref()).await?;
+        tracing::info!("User {username} changed their profile.");
+        self.broadcast_datagram(addr, Datagram::ProfileUpdated { username: username.to_string() }).await?;
+        Ok(ServerResponse::ProfileSet)
     }
 
     /// Stores a chat message in the database.
@@ -98,263 +786,2724 @@ impl ServerContext {
     ///
     /// # Returns
     ///
+    /// * `Result<MessageSeq>` - Returns the sequence number of the stored message, which also identifies its attachment.
+    pub async fn store_message(&self, message: &ChatMessage) -> Result<MessageSeq> {
+        self.database.store_message(message).await
+    }
+
+    /// Stores a chat message and broadcasts it to all connected clients except the author, with the ID given
+    /// by the database. Storing and queueing don't overlap with other messages, so every client receives
+    /// the messages in the order of their IDs.
+    ///
+    /// # Arguments
+    ///
+    /// * `author` - The address of the author of the message.
+    /// * `message` - The `ChatMessage` to be published, its `seq` is set to the ID of the stored message.
+    ///
+    /// # Returns
+    ///
     /// * `EmptyResult` - Returns an empty result if successful.
-    pub async fn store_message(&self, message: &ChatMessage) -> EmptyResult {
-        let mut db = self.database.lock().await;
-        db.store_message(message).await
+    pub async fn publish_message(&self, author: PeerAddr, message: &mut ChatMessage) -> EmptyResult {
+        let _publishing = self.publishing.lock().await;
+        message.seq = Some(self.store_message(message).await?);
+        self.broadcast_message(author, message).await
     }
 
-    /// Verifies that the sender of a message is the authenticated user.
+    /// Stores an image and broadcasts its thumbnail to all connected clients except the author,
+    /// who gets an acknowledgement. The full image is sent only to clients asking for it with `FetchAttachment`.
+    /// Images which can't be decoded are rejected.
     ///
     /// # Arguments
     ///
-    /// * `verified_username` - The username of the authenticated user.
-    /// * `message` - A reference to the `ChatMessage` to be verified.
+    /// * `author` - The address of the author of the message.
+    /// * `message` - A reference to the `ChatMessage` carrying the image.
+    /// * `image` - The encoded image.
     ///
     /// # Returns
     ///
     /// * `EmptyResult` - Returns an empty result if successful.
-    pub fn verify_message_sender(&self, verified_username: &str, message: &ChatMessage) -> EmptyResult {
-        if message.sender == verified_username {
-            Ok(())
-        } else {
-            Err(ServerError::SpoofingError)?
+    pub async fn publish_image(&self, author: PeerAddr, message: &ChatMessage, image: &[u8]) -> EmptyResult {
+        let thumbnail = match make_thumbnail(image.to_vec()).await {
+            Ok(thumbnail) => thumbnail,
+            Err(e) => {
+                tracing::debug!("Could not make a thumbnail: {e}");
+                return self.reject_attachment(author, message.id, "the image could not be decoded".to_string()).await;
+            }
+        };
+
+        {
+            let _publishing = self.publishing.lock().await;
+            let id = self.store_message(message).await?;
+            let preview = ChatMessage {
+                timestamp: message.timestamp,
+                nickname: message.nickname.clone(),
+                seq: Some(id),
+                ..ChatMessage::new(message.id, &message.sender, ChatMessageContent::Image(thumbnail))
+            };
+            self.broadcast_datagram(author, Datagram::Thumbnail { id, message: preview }).await?;
         }
+        self.send_response_to(author, ServerResponse::MessageAck(message.id)).await
     }
 
-    /// Broadcasts a chat message to all connected clients except the author.
+    /// Stores a file and announces it to all connected clients except the author with its name and size.
+    /// The file itself is sent only to clients asking for it with `FetchAttachment`.
     ///
     /// # Arguments
     ///
-    /// * `author` - The socket address of the author of the message.
-    /// * `message` - A reference to the `ChatMessage` to be broadcasted.
+    /// * `author` - The address of the author of the message.
+    /// * `message` - A reference to the `ChatMessage` carrying the file.
+    /// * `filename` - The name of the file.
+    /// * `data` - The content of the file.
     ///
     /// # Returns
     ///
     /// * `EmptyResult` - Returns an empty result if successful.
-    pub async fn broadcast_message(&self, author: SocketAddr, message: &ChatMessage) -> EmptyResult {
-        let mut clients = self.socket_table.lock().await;
-        let mut to_remove = vec![];
-
-        let datagram = Datagram::Message(message.clone());
+    pub async fn publish_file(&self, author: PeerAddr, message: &ChatMessage, filename: &str, data: &[u8]) -> EmptyResult {
+        let _publishing = self.publishing.lock().await;
+        let id = self.store_message(message).await?;
+        let offer = Datagram::FileOffer {
+            id,
+            sender: message.sender.clone(),
+            nickname: message.nickname.clone(),
+            timestamp: message.timestamp,
+            filename: filename.to_string(),
+            size: data.len() as u64,
+        };
+        self.broadcast_datagram(author, offer).await
+    }
 
-        log::debug!("Broadcasting a message from {author}");
+    /// Sends a stored attachment to a client in a chunked file transfer. The transfer runs in its own task
+    /// and waits for room in the send queue of the client, so a large file doesn't overflow it.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - The address of the client.
+    /// * `id` - The ID of the attachment.
+    /// * `from_seq` - The first chunk to be sent, greater than zero when the client resumes a download.
+    ///
+    /// # Returns
+    ///
+    /// * `EmptyResult` - Returns an empty result if successful.
+    pub async fn fetch_attachment(&self, addr: PeerAddr, id: AttachmentId, from_seq: u64) -> EmptyResult {
+        let Some(attachment) = self.database.find_attachment(id).await? else {
+            return self.send_response_to(addr, ServerResponse::AttachmentNotFound(id)).await;
+        };
+        let Some(queue) = self.client_table.read().await.get(&addr).map(|client| client.queue.clone()) else {
+            return Ok(());
+        };
 
-        for (addr, write_half) in clients.iter_mut() {
-            if *addr == author {
-                continue;
+        let transfer_id = self.next_transfer_id();
+        tokio::spawn(async move {
+            if let Err(e) = send_stored_attachment(queue, transfer_id, id, attachment, from_seq).await {
+                tracing::warn!("Sending attachment {id} to {addr} failed: {e}");
             }
+        }.in_current_span());
+        Ok(())
+    }
 
-            log::debug!("Forwarding the message to {addr}.");
+    /// Sends the stored messages with an ID greater than `last_id` to a client, at most `MAX_FETCHED_MESSAGES`
+    /// of them, followed by `ServerResponse::FetchComplete`. Messages of users blocked by the user are left out.
+    /// Like attachments, the messages are sent by their own task, which waits for room in the send queue of the client.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - The address of the client.
+    /// * `username` - The username of the client.
+    /// * `last_id` - The ID of the last message the client received.
+    ///
+    /// # Returns
+    ///
+    /// * `EmptyResult` - Returns an empty result if successful.
+    pub async fn fetch_since(&self, addr: PeerAddr, username: &str, last_id: MessageSeq) -> EmptyResult {
+        let (records, up_to, more) = self.missed_messages(username, last_id).await?;
+        let Some(queue) = self.client_table.read().await.get(&addr).map(|client| client.queue.clone()) else {
+            return Ok(());
+        };
 
-            if let Err(_) = datagram.write_to_stream(write_half).await {
-                log::warn!("Write to client {addr} failed.");
-                to_remove.push(addr.clone());
+        let database = self.database.clone();
+        tokio::spawn(async move {
+            if let Err(e) = send_stored_messages(queue, &database, records, up_to, more).await {
+                tracing::warn!("Sending the messages after {last_id} to {addr} failed: {e}");
             }
+        }.in_current_span());
+        Ok(())
+    }
+
+    /// Queues the stored messages a client missed while it was disconnected, followed by `ServerResponse::FetchComplete`,
+    /// like `fetch_since`. It returns only once they are queued, so a caller holding `publishing` gets them
+    /// to the client ahead of any new message.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - The address of the client.
+    /// * `username` - The username of the client.
+    /// * `last_id` - The ID of the last message the client received before it was disconnected.
+    ///
+    /// # Returns
+    ///
+    /// * `EmptyResult` - Returns an empty result if the messages were queued.
+    pub async fn replay_missed(&self, addr: PeerAddr, username: &str, last_id: MessageSeq) -> EmptyResult {
+        let (records, up_to, more) = self.missed_messages(username, last_id).await?;
+        let Some(queue) = self.client_table.read().await.get(&addr).map(|client| client.queue.clone()) else {
+            return Ok(());
+        };
+        send_stored_messages(queue, &self.database, records, up_to, more).await
+    }
+
+    /// Loads the stored messages with an ID greater than `last_id` for a user, at most `MAX_FETCHED_MESSAGES`
+    /// of them. Messages of users blocked by the user are left out.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - The username of the user.
+    /// * `last_id` - The ID of the last message the user received.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(Vec<MessageRecord>, MessageSeq, bool)>` - Returns the messages, the ID of the last loaded message,
+    ///   even if it was left out, and whether there are newer messages.
+    async fn missed_messages(&self, username: &str, last_id: MessageSeq) -> Result<(Vec<MessageRecord>, MessageSeq, bool)> {
+        let mut records = self.database.messages_after(last_id, MAX_FETCHED_MESSAGES + 1).await?;
+        let more = records.len() > MAX_FETCHED_MESSAGES as usize;
+        records.truncate(MAX_FETCHED_MESSAGES as usize);
+        let up_to = records.last().map_or(last_id, |record| record.id);
+        let blocked = self.blocked_by(username);
+        records.retain(|record| !blocked.contains(&record.sender));
+        Ok((records, up_to, more))
+    }
+
+    /// Checks a message against the flood protection, muting the sender if it exceeds the thresholds.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - The username of the sender.
+    /// * `message` - A reference to the `ChatMessage` to be checked.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<chrono::DateTime<chrono::Utc>>` - Returns the end of the mute if the message must be dropped.
+    pub fn check_flood(&self, username: &str, message: &ChatMessage) -> Option<chrono::DateTime<chrono::Utc>> {
+        let now = Instant::now().into_std();
+        let verdict = self.flood.lock().unwrap().check(username, &message.content, now);
+        let until = match verdict {
+            FloodVerdict::Allowed => return None,
+            FloodVerdict::Muted(until) => {
+                tracing::warn!("User {username} is flooding, muted for {:?}.", until - now);
+                until
+            },
+            FloodVerdict::StillMuted(until) => until,
+        };
+        Some(chrono::Utc::now() + chrono::Duration::from_std(until - now).unwrap_or_default())
+    }
+
+    /// Runs a text message through the content filter. Every caught message is logged, a redacted message
+    /// gets the masked text and the author of a rejected message is told about it.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - The address of the author.
+    /// * `message` - The message to be checked, redacted in place.
+    /// * `recipient` - The recipient of a direct message, `None` for a message to everyone.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<bool>` - Returns `true` if the message may be delivered.
+    pub async fn filter_message(&self, addr: PeerAddr, message: &mut ChatMessage, recipient: Option<&str>) -> Result<bool> {
+        if self.check_content(message, recipient).await? {
+            return Ok(true);
         }
+        let reason = "the message was blocked by the content filter".to_string();
+        self.send_response_to(addr, ServerResponse::MessageRejected { reason }).await?;
+        self.send_response_to(addr, ServerResponse::MessageAck(message.id)).await?;
+        Ok(false)
+    }
+
+    /// Checks a text message against the content filter without telling its author. Every caught message
+    /// is logged and a redacted message gets the masked text.
+    ///
+    /// # Arguments
+    ///
+    /// * `message` - The message to be checked, redacted in place.
+    /// * `recipient` - The recipient of a direct message, `None` for a message to everyone.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<bool>` - Returns `true` if the message may be delivered, `false` if it's rejected.
+    async fn check_content(&self, message: &mut ChatMessage, recipient: Option<&str>) -> Result<bool> {
+        let config = self.config();
+        let (Some(filter), ChatMessageContent::Text(text)) = (&config.content_filter, &mut message.content) else {
+            return Ok(true);
+        };
+        let Some(verdict) = filter.check(text) else {
+            return Ok(true);
+        };
+
+        let rules = verdict.rules.join(", ");
+        tracing::warn!("Message {} from {} matched the content filter ({rules}), action {}.", message.id, message.sender, verdict.action);
+        self.database.log_filtered(&FilteredRecord {
+            sender: message.sender.clone(),
+            recipient: recipient.map(str::to_string),
+            timestamp: chrono::Utc::now(),
+            text: text.clone(),
+            action: verdict.action.to_string(),
+            rules,
+        }).await?;
+
+        match verdict.action {
+            FilterAction::Flag => Ok(true),
+            FilterAction::Redact => {
+                *text = verdict.text;
+                Ok(true)
+            },
+            FilterAction::Reject => Ok(false),
+        }
+    }
+
+    /// Tells a client that its attachment was rejected. The message is acknowledged, as it was processed.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - The address of the client.
+    /// * `id` - The ID of the message carrying the attachment.
+    /// * `reason` - Why the attachment was rejected.
+    ///
+    /// # Returns
+    ///
+    /// * `EmptyResult` - Returns an empty result if successful.
+    pub async fn reject_attachment(&self, addr: PeerAddr, id: MessageId, reason: String) -> EmptyResult {
+        tracing::warn!("Rejected an attachment from {addr}: {reason}.");
+        self.send_response_to(addr, ServerResponse::AttachmentRejected { reason }).await?;
+        self.send_response_to(addr, ServerResponse::MessageAck(id)).await
+    }
+
+    /// Checks whether a new attachment fits into the storage quota of its sender and reserves its size
+    /// until it's stored. Attachments which are still uploading count as used. The stored attachments
+    /// are only summed up if the sender has a quota.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - The username of the sender.
+    /// * `size` - The size of the attachment in bytes.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Result<Reservation, String>>` - Returns the reservation, to be dropped once the attachment
+    ///   is stored or given up, or the reason if the attachment would exceed the quota.
+    pub async fn check_quota(&self, username: &str, size: u64) -> Result<Result<Reservation, String>> {
+        let config = self.config();
+        if config.attachments.quotas.quota_of(username).is_none() {
+            return Ok(Ok(Reservation::default()));
+        }
+        let _checking = self.pending_storage.checking().await;
+        // Read before the stored attachments, an upload stored in between is counted twice rather than not at all
+        let pending = self.pending_storage.of(username);
+        let used = self.database.storage_used(username).await?;
+        Ok(config.attachments.quotas.check(username, used.saturating_add(pending), size)
+            .map(|()| self.pending_storage.reserve(username, size)))
+    }
+
+    /// Verifies that the sender of a message is the authenticated user. Link previews are made only
+    /// by the server, so a client sending one is spoofing too.
+    ///
+    /// # Arguments
+    ///
+    /// * `verified_username` - The username of the authenticated user.
+    /// * `message` - A reference to the `ChatMessage` to be verified.
+    ///
+    /// # Returns
+    ///
+    /// * `EmptyResult` - Returns an empty result if successful.
+    pub fn verify_message_sender(&self, verified_username: &str, message: &ChatMessage) -> EmptyResult {
+        if message.sender == verified_username && !matches!(message.content, ChatMessageContent::LinkPreview { .. }) {
+            Ok(())
+        } else {
+            Err(ServerError::SpoofingError)?
+        }
+    }
+
+    /// Broadcasts a chat message to all connected clients except the author.
+    ///
+    /// # Arguments
+    ///
+    /// * `author` - The address of the author of the message.
+    /// * `message` - A reference to the `ChatMessage` to be broadcasted.
+    ///
+    /// # Returns
+    ///
+    /// * `EmptyResult` - Returns an empty result if successful.
+    #[tracing::instrument(skip_all, fields(author = %author, sender = %message.sender, id = message.id))]
+    pub async fn broadcast_message(&self, author: PeerAddr, message: &ChatMessage) -> EmptyResult {
+        self.broadcast_datagram(author, Datagram::Message(message.clone())).await
+    }
+
+    /// Queues a datagram for all clients on a route. What happens to clients whose queue is full
+    /// depends on the queue policy, by default they can't keep up with the traffic and are disconnected
+    /// instead of slowing down the others. The client table is not locked while waiting for room.
+    /// Messages of a user are withheld from the users blocking them.
+    ///
+    /// # Arguments
+    ///
+    /// * `datagram` - The datagram to be delivered.
+    /// * `route` - The recipients of the datagram.
+    ///
+    /// # Returns
+    ///
+    /// * `usize` - Returns the number of clients the datagram was queued for.
+    async fn deliver(&self, datagram: Arc<Outgoing>, route: Route<'_>) -> usize {
+        let sender = blockable_sender(datagram.datagram());
+        let targets: Vec<(PeerAddr, Arc<SendQueue>, Arc<Notify>)> = {
+            let clients = self.client_table.read().await;
+            let blocks = self.blocks.lock().unwrap();
+            let connections = clients.iter()
+                .filter(|(_, client)| !sender.is_some_and(|sender| blocks.blocks(&client.username, sender)))
+                .map(|(addr, client)| (*addr, client.username.as_str()));
+            self.router.targets(route, connections)
+                .into_iter()
+                .filter_map(|addr| clients.get(&addr).map(|client| (addr, client.queue.clone(), client.disconnect.clone())))
+                .collect()
+        };
+        let mut delivered = 0;
+
+        for (addr, queue, disconnect) in targets {
+            match queue.offer(datagram.clone()).await {
+                Offer::Queued => delivered += 1,
+                Offer::DroppedOldest => {
+                    tracing::debug!("Send queue of client {addr} is full, dropped the oldest datagram.");
+                    delivered += 1;
+                },
+                Offer::Full => {
+                    tracing::warn!("Send queue of client {addr} is full, disconnecting.");
+                    disconnect.notify_one();
+                },
+                Offer::Closed => {
+                    // The writer task has failed and the client is being disconnected
+                }
+            }
+        }
+
+        delivered
+    }
+
+    /// Broadcasts a datagram to all connected clients except the author.
+    ///
+    /// # Arguments
+    ///
+    /// * `author` - The address of the author of the datagram.
+    /// * `datagram` - The `Datagram` to be broadcasted, shared by the queues of all recipients.
+    ///
+    /// # Returns
+    ///
+    /// * `EmptyResult` - Returns an empty result if successful.
+    pub async fn broadcast_datagram(&self, author: PeerAddr, datagram: Datagram) -> EmptyResult {
+        tracing::debug!("Broadcasting a datagram from {author}");
+        self.deliver(Outgoing::new(datagram), Route::Broadcast { author }).await;
+        Ok(())
+    }
+
+    /// Delivers a private chat message to all connections of the user `to`.
+    ///
+    /// # Arguments
+    ///
+    /// * `to` - The username of the recipient.
+    /// * `message` - A reference to the `ChatMessage` to be delivered.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<bool>` - Returns `false` if the recipient is not logged in.
+    pub async fn send_direct_message(&self, to: &str, message: &ChatMessage) -> Result<bool> {
+        tracing::debug!("Forwarding a direct message from {} to {to}.", message.sender);
+
+        let datagram = Outgoing::new(Datagram::DirectMessage { to: to.to_string(), message: message.clone() });
+        let delivered = self.deliver(datagram, Route::User(to)).await;
+        Ok(delivered > 0)
+    }
+
+    /// Sends a server response to an already authenticated client.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - The address of the client.
+    /// * `response` - The server response to be sent.
+    ///
+    /// # Returns
+    ///
+    /// * `EmptyResult` - Returns an empty result if successful.
+    pub async fn send_response_to(&self, addr: PeerAddr, response: ServerResponse) -> EmptyResult {
+        self.send_datagram_to(addr, &Datagram::ServerResponse(response)).await
+    }
+
+    /// Sends a datagram to an already authenticated client.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - The address of the client.
+    /// * `datagram` - The datagram to be sent.
+    ///
+    /// # Returns
+    ///
+    /// * `EmptyResult` - Returns an empty result if successful.
+    pub async fn send_datagram_to(&self, addr: PeerAddr, datagram: &Datagram) -> EmptyResult {
+        self.deliver(Outgoing::new(datagram.clone()), Route::Connection(addr)).await;
+        Ok(())
+    }
+
+    /// Sends a notice to all connections of a user and disconnects them.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - The username of the user.
+    /// * `notice` - The server response sent to the user before disconnecting.
+    ///
+    /// # Returns
+    ///
+    /// * `usize` - Returns the number of disconnected connections.
+    pub async fn kick_user(&self, username: &str, notice: ServerResponse) -> usize {
+        let clients = self.client_table.read().await;
+        let targets = self.router.targets(Route::User(username), clients.iter().map(|(addr, client)| (*addr, client.username.as_str())));
+        let notice = Outgoing::new(Datagram::ServerResponse(notice));
+
+        for client in targets.iter().filter_map(|addr| clients.get(addr)) {
+            // The notice is best effort, the client is disconnected even if its queue is full
+            client.queue.try_offer(notice.clone());
+            client.disconnect.notify_one();
+        }
+
+        targets.len()
+    }
+
+    /// Relays a public text message to the linked servers, except the one it came from and the one where it was posted.
+    /// Messages of local users are marked with the ID of this server as their origin.
+    ///
+    /// # Arguments
+    ///
+    /// * `from` - The address of the peer the message came from, `None` for messages of local users.
+    /// * `message` - A reference to the `ChatMessage` to be relayed.
+    pub async fn relay_message(&self, from: Option<PeerAddr>, message: &ChatMessage) {
+        if !server_federation::relayable(message) {
+            return;
+        }
+        let mut message = message.clone();
+        let origin = message.origin.get_or_insert_with(|| self.config().server_id.clone()).clone();
+        let datagram = Outgoing::new(Datagram::Message(message));
+
+        let peers = self.peers.read().await;
+        for (addr, peer) in peers.iter().filter(|(addr, peer)| Some(**addr) != from && peer.server_id != origin) {
+            if peer.queue.try_offer(datagram.clone()) == Offer::Full {
+                tracing::warn!("Send queue of peer {} at {addr} is full, closing the link.", peer.server_id);
+                peer.disconnect.notify_one();
+            }
+        }
+    }
+
+    /// Delivers a message relayed by a linked server to the local users and relays it further. Relayed messages
+    /// aren't stored, as their senders aren't users of this server. Messages which come back to their origin
+    /// or arrive again over another link are dropped, as are attachments and ephemeral messages, which a peer
+    /// shouldn't relay. The announcement flag is cleared, as the rights of the sender can't be checked here.
+    /// The local content filter applies as to messages of local users, its redactions are relayed further.
+    ///
+    /// # Arguments
+    ///
+    /// * `from` - The address of the peer the message came from.
+    /// * `message` - The relayed message.
+    ///
+    /// # Returns
+    ///
+    /// * `EmptyResult` - Returns an empty result if successful.
+    pub async fn receive_relayed(&self, from: PeerAddr, message: ChatMessage) -> EmptyResult {
+        let Some(origin) = message.origin.as_deref() else {
+            tracing::warn!("Dropped a relayed message without an origin from {from}.");
+            return Ok(());
+        };
+        if !server_federation::relayable(&message) {
+            tracing::warn!("Dropped a relayed attachment or ephemeral message from {from}.");
+            return Ok(());
+        }
+        if origin == self.config().server_id || !self.relayed.lock().unwrap().insert(origin, &message) {
+            return Ok(());
+        }
+
+        let mut local = server_federation::local_copy(&message, origin);
+        if !self.check_content(&mut local, None).await? {
+            tracing::info!("Dropped a relayed message of {} blocked by the content filter.", local.sender);
+            return Ok(());
+        }
+        self.broadcast_message(from, &local).await?;
+        self.preview_links(&local);
+        self.relay_message(Some(from), &ChatMessage { content: local.content.clone(), announcement: false, ..message }).await;
+        Ok(())
+    }
+
+    /// Sends previews of the links in a public text message to everyone, including its author, if link previews
+    /// are enabled. The pages are fetched in the background, so the message isn't delayed.
+    /// Previews are neither stored nor relayed, every linked server makes them for its own clients.
+    /// Messages which expire get no previews, they would keep showing the links after the message is gone.
+    ///
+    /// # Arguments
+    ///
+    /// * `message` - A reference to the `ChatMessage` which may contain links.
+    pub fn preview_links(&self, message: &ChatMessage) {
+        let config = self.config();
+        let (Some(preview_config), ChatMessageContent::Text(text)) = (&config.link_previews, &message.content) else {
+            return;
+        };
+        if message.ttl.is_some() {
+            return;
+        }
+        let urls: Vec<String> = server_preview::find_urls(text).into_iter().map(str::to_string).collect();
+        if urls.is_empty() {
+            return;
+        }
+
+        let context = self.clone();
+        let timeout = preview_config.timeout;
+        let (id, sender, nickname) = (message.id, message.sender.clone(), message.nickname.clone());
+        tokio::spawn(async move {
+            for url in urls {
+                let Some(preview) = context.previews.preview(&url, timeout).await else { continue };
+                let message = ChatMessage {
+                    nickname: nickname.clone(),
+                    ..ChatMessage::new(id, &sender, ChatMessageContent::LinkPreview { url, title: preview.title, description: preview.description })
+                };
+                context.deliver(Outgoing::new(Datagram::Message(message)), Route::Everyone).await;
+            }
+        }.in_current_span());
+    }
+
+    /// Returns the state of the send queues of all clients.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<(PeerAddr, String, QueueStats)>` - Returns the address, username and queue state of each client.
+    pub async fn queue_stats(&self) -> Vec<(PeerAddr, String, QueueStats)> {
+        let clients = self.client_table.read().await;
+        clients.iter().map(|(addr, client)| (*addr, client.username.clone(), client.queue.stats())).collect()
+    }
+
+    /// Lists the connected clients.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<ClientInfo>` - Returns the clients sorted by username.
+    pub async fn connected_clients(&self) -> Vec<ClientInfo> {
+        let clients = self.client_table.read().await;
+        let mut list: Vec<ClientInfo> = clients.iter().map(|(addr, client)| ClientInfo {
+            addr: *addr,
+            username: client.username.clone(),
+            idle: client.last_active.lock().unwrap().elapsed(),
+            queue: client.queue.stats(),
+        }).collect();
+        list.sort_by(|a, b| a.username.cmp(&b.username));
+        list
+    }
+
+    /// Collects the counters of the server.
+    ///
+    /// # Returns
+    ///
+    /// * `ServerStats` - Returns the current counters.
+    pub async fn stats(&self) -> ServerStats {
+        let queues: Vec<QueueStats> = self.queue_stats().await.into_iter().map(|(_, _, stats)| stats).collect();
+        let connections = self.connections.lock().unwrap().total;
+        ServerStats {
+            uptime: self.started.elapsed(),
+            connections,
+            clients: queues.len(),
+            users: self.online_users().await.len(),
+            peers: self.peers.read().await.len(),
+            queued: queues.iter().map(|stats| stats.depth).sum(),
+            dropped: queues.iter().map(|stats| stats.dropped).sum(),
+        }
+    }
+
+    /// Collects the statistics sent to clients asking for them with `Datagram::Stats`.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<ServerStatistics>` - Returns the statistics.
+    pub async fn statistics(&self) -> Result<ServerStatistics> {
+        Ok(ServerStatistics {
+            uptime: self.started.elapsed(),
+            users_online: self.online_users().await.len() as u64,
+            messages: self.database.message_count().await?,
+            database_size: self.database.size().await?,
+        })
+    }
+
+    /// Sends an announcement of the operator to all connected clients. Announcements are neither stored nor relayed.
+    ///
+    /// # Arguments
+    ///
+    /// * `text` - The text of the announcement.
+    ///
+    /// # Returns
+    ///
+    /// * `usize` - Returns the number of clients the announcement was queued for.
+    pub async fn announce(&self, text: &str) -> usize {
+        tracing::info!("Announcing to all clients: {text}");
+        self.deliver(Outgoing::new(Datagram::Announcement(text.to_string())), Route::Everyone).await
+    }
+
+    /// Disconnects all clients and linked servers, used when the server shuts down.
+    pub async fn disconnect_all(&self) {
+        let clients = self.client_table.read().await;
+        for client in clients.values() {
+            client.disconnect.notify_one();
+        }
+        let peers = self.peers.read().await;
+        for peer in peers.values() {
+            peer.disconnect.notify_one();
+        }
+    }
+
+    /// Performs an administrative command. The issuer must have the admin role.
+    /// Commands which were performed are recorded in the audit log.
+    ///
+    /// # Arguments
+    ///
+    /// * `issuer` - The username of the user issuing the command.
+    /// * `ip` - The IP address of the issuer, `None` for Unix socket connections.
+    /// * `command` - The command to be performed.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<ServerResponse>` - Returns a result containing the response for the issuer.
+    pub async fn perform_admin_command(&self, issuer: &str, ip: Option<IpAddr>, command: AdminCommand) -> Result<ServerResponse> {
+        let is_admin = self.database.is_admin(issuer).await?;
+        if !is_admin {
+            tracing::warn!("User {issuer} attempted an admin command without permission.");
+            return Ok(ServerResponse::AdminCommandFailed("Permission denied.".to_string()));
+        }
+
+        match command {
+            AdminCommand::Kick(username) => {
+                if self.kick_user(&username, ServerResponse::Kicked).await == 0 {
+                    return Ok(ServerResponse::AdminCommandFailed(format!("User {username} is not online.")));
+                }
+                tracing::info!("User {username} was kicked by {issuer}.");
+                self.audit(AuditEvent::Kick, Some(issuer), Some(&username), ip, None).await?;
+            },
+            AdminCommand::Ban(username) => {
+                let banned = self.database.ban_user(&username, issuer).await;
+                if let Err(e) = banned {
+                    return Ok(ServerResponse::AdminCommandFailed(format!("Could not ban {username}: {e}")));
+                }
+                self.kick_user(&username, ServerResponse::Banned).await;
+                tracing::info!("User {username} was banned by {issuer}.");
+                self.audit(AuditEvent::Ban, Some(issuer), Some(&username), ip, None).await?;
+            },
+            AdminCommand::Unban(username) => {
+                let unbanned = self.database.unban_user(&username).await?;
+                if !unbanned {
+                    return Ok(ServerResponse::AdminCommandFailed(format!("User {username} is not banned.")));
+                }
+                tracing::info!("User {username} was unbanned by {issuer}.");
+                self.audit(AuditEvent::Unban, Some(issuer), Some(&username), ip, None).await?;
+            }
+        }
+
+        Ok(ServerResponse::AdminCommandOk)
+    }
+
+    /// Checks whether a user is banned.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - A string slice that holds the username.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<bool>` - Returns a result containing `true` if the user is banned.
+    pub async fn is_banned(&self, username: &str) -> Result<bool> {
+        self.database.is_banned(username).await
+    }
+
+    /// Checks user authentication by verifying the password with the authentication backend.
+    /// Users checked by another backend than the database are added to it on their first login,
+    /// as their messages, nicknames and blocks are kept there. Their usernames must be valid for a registration,
+    /// other users of the backend are refused.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - A string slice that holds the username.
+    /// * `password` - A string slice that holds the password.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<bool>` - Returns a result containing a boolean indicating if authentication was successful.
+    pub async fn check_auth(&self, username: &str, password: &str) -> Result<bool> {
+        let authenticated = self.authenticator.check_auth(username, password).await?;
+        if authenticated && !self.authenticator.stores_passwords() && !self.database.user_exists(username).await? {
+            if let Err(reason) = server_registration::validate_username(username) {
+                tracing::warn!("Refused {username}, who was authenticated by another backend: {reason}.");
+                return Ok(false);
+            }
+            self.database.add_external_user(username).await?;
+        }
+        Ok(authenticated)
+    }
+
+    /// Checks whether logins of a user or from an address are refused after too many failed attempts.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - A string slice that holds the username.
+    /// * `ip` - The IP address of the client, `None` for Unix socket connections.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<chrono::DateTime<chrono::Utc>>>` - Returns the time until which logins are refused, `None` if they are allowed.
+    pub async fn locked_until(&self, username: &str, ip: Option<IpAddr>) -> Result<Option<chrono::DateTime<chrono::Utc>>> {
+        let lockout = self.config().lockout.clone();
+        if lockout.attempts.is_none() {
+            return Ok(None);
+        }
+        let now = chrono::Utc::now();
+        let since = now - chrono::Duration::from_std(lockout.duration)?;
+        let by_user = lockout.locked_until(&self.database.failed_logins_of(username, since).await?, now);
+        let by_address = match ip {
+            Some(ip) => lockout.locked_until(&self.database.failed_logins_from(&ip.to_string(), since).await?, now),
+            None => None,
+        };
+        Ok(by_user.max(by_address))
+    }
+
+    /// Records a login attempt in the audit log.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - A string slice that holds the username the client tried to log in as.
+    /// * `ip` - The IP address of the client, `None` for Unix socket connections.
+    /// * `outcome` - The outcome of the attempt.
+    ///
+    /// # Returns
+    ///
+    /// * `EmptyResult` - Returns an empty result if successful.
+    pub async fn audit_login(&self, username: &str, ip: Option<IpAddr>, outcome: LoginOutcome) -> EmptyResult {
+        self.database.log_login(&LoginRecord {
+            username: username.to_string(),
+            address: ip.map(|ip| ip.to_string()),
+            timestamp: chrono::Utc::now(),
+            outcome: outcome.to_string(),
+        }).await
+    }
+
+    /// Records a security-relevant event other than a login in the audit log.
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The kind of the event.
+    /// * `actor` - Who caused the event.
+    /// * `target` - The user the event is about.
+    /// * `ip` - The IP address of the actor, `None` for Unix socket connections and local events.
+    /// * `detail` - More about the event.
+    ///
+    /// # Returns
+    ///
+    /// * `EmptyResult` - Returns an empty result if successful.
+    pub async fn audit(&self, event: AuditEvent, actor: Option<&str>, target: Option<&str>, ip: Option<IpAddr>, detail: Option<&str>) -> EmptyResult {
+        let address = ip.map(|ip| ip.to_string());
+        self.database.log_event(event, actor, target, address.as_deref(), detail).await
+    }
+
+    /// Changes the password of an authenticated user after verifying the current one.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - A string slice that holds the username.
+    /// * `ip` - The IP address of the client, `None` for Unix socket connections.
+    /// * `old_password` - A string slice that holds the current password.
+    /// * `new_password` - A string slice that holds the new password.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<ServerResponse>` - Returns the response to be sent to the user.
+    pub async fn change_password(&self, username: &str, ip: Option<IpAddr>, old_password: &str, new_password: &str) -> Result<ServerResponse> {
+        if !self.authenticator.stores_passwords() {
+            return Ok(ServerResponse::PasswordChangeFailed("Passwords are managed outside of the chat server.".to_string()));
+        }
+        if new_password.is_empty() {
+            return Ok(ServerResponse::PasswordChangeFailed("The new password must not be empty.".to_string()));
+        }
+
+        if !self.database.check_auth(username, old_password).await? {
+            tracing::warn!("User {username} attempted to change the password with a wrong current password.");
+            return Ok(ServerResponse::PasswordChangeFailed("Wrong password.".to_string()));
+        }
+
+        self.database.change_password(username, new_password).await?;
+        tracing::info!("User {username} changed their password.");
+        self.audit(AuditEvent::PasswordChange, Some(username), Some(username), ip, None).await?;
+        Ok(ServerResponse::PasswordChanged)
+    }
+
+    /// Returns the users blocked by a user.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - A string slice that holds the username.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<String>` - Returns the blocked usernames, sorted.
+    pub fn blocked_by(&self, username: &str) -> Vec<String> {
+        self.blocks.lock().unwrap().blocked_by(username)
+    }
+
+    /// Blocks or unblocks a user on behalf of another user. Registered users and guests can be blocked.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - A string slice that holds the username of the blocking user.
+    /// * `other` - A string slice that holds the username of the user to be blocked or unblocked.
+    /// * `block` - `true` blocks the user, `false` unblocks them.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<ServerResponse>` - Returns the response to be sent to the user.
+    pub async fn set_blocked(&self, username: &str, other: &str, block: bool) -> Result<ServerResponse> {
+        if !block {
+            if !self.database.unblock_user(username, other).await? {
+                return Ok(ServerResponse::BlockFailed(format!("{other} is not blocked.")));
+            }
+            self.blocks.lock().unwrap().unblock(username, other);
+            tracing::info!("User {username} unblocked {other}.");
+            return Ok(ServerResponse::BlockList(self.blocked_by(username)));
+        }
+
+        if other == username {
+            return Ok(ServerResponse::BlockFailed("You can't block yourself.".to_string()));
+        }
+        if !chat::is_guest(other) && !self.database.user_exists(other).await? {
+            return Ok(ServerResponse::BlockFailed(format!("There is no user {other}.")));
+        }
+        if self.blocked_by(username).len() >= MAX_BLOCKED_USERS {
+            return Ok(ServerResponse::BlockFailed(format!("You can't block more than {MAX_BLOCKED_USERS} users.")));
+        }
+
+        self.database.block_user(username, other).await?;
+        self.blocks.lock().unwrap().block(username, other);
+        tracing::info!("User {username} blocked {other}.");
+        Ok(ServerResponse::BlockList(self.blocked_by(username)))
+    }
+}
+
+/// Checks that a nickname is non-empty, not too long and contains no whitespace or control characters.
+///
+/// # Arguments
+///
+/// * `nickname` - The requested nickname.
+///
+/// # Returns
+///
+/// * `Result<(), &'static str>` - Returns the reason if the nickname is not valid.
+fn validate_nickname(nickname: &str) -> Result<(), &'static str> {
+    if nickname.is_empty() {
+        return Err("The nickname must not be empty.");
+    }
+    if nickname.chars().count() > MAX_NICKNAME_LENGTH {
+        return Err("The nickname is too long.");
+    }
+    if nickname.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return Err("The nickname must not contain spaces.");
+    }
+    Ok(())
+}
+
+/// Checks that the status line of a profile is not too long and contains no control characters. An empty one is valid,
+/// it removes the status.
+///
+/// # Arguments
+///
+/// * `status` - The requested status line.
+///
+/// # Returns
+///
+/// * `Result<(), &'static str>` - Returns the reason if the status is not valid.
+fn validate_status(status: &str) -> Result<(), &'static str> {
+    if status.chars().count() > MAX_STATUS_LENGTH {
+        return Err("The status is too long.");
+    }
+    if status.chars().any(char::is_control) {
+        return Err("The status must fit on one line.");
+    }
+    Ok(())
+}
+
+/// Checks that an avatar is a PNG which isn't too large. Only the header is read, the picture isn't decoded.
+/// An empty one is valid, it removes the avatar.
+///
+/// # Arguments
+///
+/// * `avatar` - The requested avatar.
+///
+/// # Returns
+///
+/// * `Result<(), &'static str>` - Returns the reason if the avatar is not valid.
+fn validate_avatar(avatar: &[u8]) -> Result<(), &'static str> {
+    if avatar.is_empty() {
+        return Ok(());
+    }
+    if avatar.len() > chat::MAX_AVATAR_SIZE {
+        return Err("The avatar is too large.");
+    }
+    let reader = image::io::Reader::with_format(std::io::Cursor::new(avatar), image::ImageFormat::Png);
+    match reader.into_dimensions() {
+        Ok((width, height)) if width <= chat::MAX_AVATAR_DIMENSION && height <= chat::MAX_AVATAR_DIMENSION => Ok(()),
+        Ok(_) => Err("The avatar is too large."),
+        Err(_) => Err("The avatar must be a PNG image."),
+    }
+}
+
+/// Sends a server response to the client.
+///
+/// # Arguments
+///
+/// * `write_half` - The writable half of the connection.
+/// * `config` - The server configuration, with the codec and the write timeout.
+/// * `response` - The server response to be sent.
+///
+/// # Returns
+///
+/// * `EmptyResult` - Returns an empty result if successful.
+pub(crate) async fn send_response(write_half: &mut WriteHalf, config: &ServerConfig, response: ServerResponse) -> EmptyResult {
+    let datagram = Datagram::ServerResponse(response);
+    datagram.write_to_stream_timeout(write_half, &config.codec, config.write_timeout).await?;
+    Ok(())
+}
+
+/// Asks a user who enabled two-factor authentication for a TOTP code, after the password was verified.
+///
+/// # Arguments
+///
+/// * `context` - The server context.
+/// * `read_half` - The readable half of the connection.
+/// * `write_half` - The writable half of the connection.
+/// * `username` - The username of the user logging in.
+///
+/// # Returns
+///
+/// * `Result<bool>` - Returns `true` if the user doesn't use two-factor authentication or sent a valid code.
+async fn check_second_factor(context: &ServerContext, read_half: &mut ReadHalf, write_half: &mut WriteHalf, username: &str) -> Result<bool> {
+    let Some(secret) = context.database.totp_secret(username).await? else {
+        return Ok(true);
+    };
+
+    let config = context.config();
+    send_response(write_half, &config, ServerResponse::TotpRequired).await?;
+    let code = Datagram::read_from_stream_limited(read_half, config.max_message_size, &config.codec);
+    let code = tokio::time::timeout(TOTP_CODE_TIMEOUT, code).await.map_err(|_| ServerError::LoginError)??;
+    match code {
+        Datagram::TotpCode(code) => server_totp::verify(&secret, username, &code, chrono::Utc::now().timestamp() as u64),
+        _ => Ok(false),
+    }
+}
+
+/// Receives messages from a client and broadcasts them to other clients.
+///
+/// # Arguments
+///
+/// * `context` - The server context.
+/// * `read_half` - The readable half of the connection.
+/// * `write_half` - The writable half of the connection.
+/// * `addr` - The address of the client.
+/// * `certificate` - The fingerprint of the client certificate presented on the TLS port, `None` without one.
+///
+/// # Returns
+///
+/// * `EmptyResult` - Returns an empty result if successful.
+#[tracing::instrument(name = "session", skip_all, fields(username = tracing::field::Empty))]
+async fn receive_datagrams(context: ServerContext, mut read_half: ReadHalf, mut write_half: WriteHalf, addr: PeerAddr,
+                           certificate: Option<String>) -> EmptyResult {
+    
+    // Settings of the configuration at the time the client connects are kept for the whole session
+    let config = context.config();
+    let max_message_size = config.max_message_size;
+    let idle_timeout = config.idle_timeout;
+    let verified_username;
+    let codec;
+    let last_id;
+
+    // Expect login datagram
+    let login = Datagram::read_from_stream_limited(&mut read_half, max_message_size, &config.codec);
+    let login = match idle_timeout {
+        Some(timeout) => tokio::time::timeout(timeout, login).await.map_err(|_| {
+            tracing::warn!("Login datagram not received in time, closing connection with {addr}.");
+            ServerError::LoginError
+        })?,
+        None => login.await,
+    };
+
+    match login {
+        Err(e) => return Err(e)?,
+        Ok(Datagram::Login { username, password, compression, last_id: resume_after }) => {
+            // Locked logins are refused before the password is checked, so guessing can't go on
+            if let Some(until) = context.locked_until(&username, addr.ip()).await? {
+                tracing::warn!("Refused a login of {username} from {addr}, logins are locked until {until}.");
+                context.audit_login(&username, addr.ip(), LoginOutcome::Locked).await?;
+                send_response(&mut write_half, &context.config(), ServerResponse::LoginLocked { until }).await?;
+                return Err(ServerError::LoginError)?;
+            }
+
+            if context.check_auth(username.as_str(), password.as_str()).await? {
+                if context.is_banned(&username).await? {
+                    tracing::warn!("Banned user {username} attempted to log in from {addr}.");
+                    context.audit_login(&username, addr.ip(), LoginOutcome::Banned).await?;
+                    send_response(&mut write_half, &context.config(), ServerResponse::Banned).await?;
+                    return Err(ServerError::LoginError)?;
+                }
+
+                if !check_second_factor(&context, &mut read_half, &mut write_half, &username).await? {
+                    tracing::warn!("Invalid authentication code for {username} received from {addr}.");
+                    context.audit_login(&username, addr.ip(), LoginOutcome::InvalidCode).await?;
+                    send_response(&mut write_half, &context.config(), ServerResponse::LoginFailed).await?;
+                    return Err(ServerError::LoginError)?;
+                }
+
+                verified_username = username;
+                codec = SessionCodec { kind: context.config().codec, compression: compression && context.config().compression };
+                last_id = resume_after;
+
+            } else {
+                tracing::warn!("Invalid username or password received from {addr}.");
+                context.audit_login(&username, addr.ip(), LoginOutcome::WrongPassword).await?;
+                send_response(&mut write_half, &context.config(), ServerResponse::LoginFailed).await?;
+
+                return Err(ServerError::LoginError)?; 
+            }
+        },
+        Ok(Datagram::CertificateLogin { username, compression, last_id: resume_after }) => {
+            let owner = match &certificate {
+                Some(fingerprint) => context.database.certificate_owner(fingerprint).await?,
+                None => None,
+            };
+            if owner.as_deref() != Some(username.as_str()) {
+                match &certificate {
+                    Some(fingerprint) => tracing::warn!("Refused a certificate login of {username} from {addr}, {fingerprint} isn't registered for them."),
+                    None => tracing::warn!("Refused a certificate login of {username} from {addr} without a client certificate."),
+                }
+                context.audit_login(&username, addr.ip(), LoginOutcome::UnknownCertificate).await?;
+                send_response(&mut write_half, &context.config(), ServerResponse::LoginFailed).await?;
+                return Err(ServerError::LoginError)?;
+            }
+            if context.is_banned(&username).await? {
+                tracing::warn!("Banned user {username} attempted to log in from {addr}.");
+                context.audit_login(&username, addr.ip(), LoginOutcome::Banned).await?;
+                send_response(&mut write_half, &context.config(), ServerResponse::Banned).await?;
+                return Err(ServerError::LoginError)?;
+            }
+
+            // The certificate replaces both the password and the TOTP code
+            verified_username = username;
+            codec = SessionCodec { kind: context.config().codec, compression: compression && context.config().compression };
+            last_id = resume_after;
+        },
+        Ok(Datagram::GuestLogin { nickname, last_id: resume_after }) => {
+            if !context.config().allow_guests {
+                tracing::warn!("Refused a guest login from {addr}, guests aren't allowed.");
+                send_response(&mut write_half, &context.config(), ServerResponse::LoginFailed).await?;
+                return Err(ServerError::LoginError)?;
+            }
+            if let Err(reason) = validate_nickname(&nickname) {
+                tracing::warn!("Refused a guest login from {addr}: {reason}");
+                send_response(&mut write_half, &context.config(), ServerResponse::LoginFailed).await?;
+                return Err(ServerError::LoginError)?;
+            }
+
+            // Guests don't offer compression, their frames are sent as they are
+            verified_username = format!("{}{nickname}", chat::GUEST_PREFIX);
+            codec = SessionCodec::from(context.config().codec);
+            last_id = resume_after;
+        },
+        Ok(Datagram::Register { username, password }) => {
+            return server_registration::register_client(context, read_half, write_half, addr, username, password).await;
+        },
+        Ok(Datagram::PeerHello { server_id, secret }) => {
+            return server_federation::accept_peer(context, read_half, write_half, addr, server_id, secret).await;
+        },
+        Ok(_) => {
+            tracing::warn!("Login datagram not present, closing connection with {addr}.");
+            return Err(ServerError::LoginError)?;
+        }
+    }
+    
+    // We have authenticated the user
+    tracing::Span::current().record("username", tracing::field::display(&verified_username));
+    let nickname = context.database.nickname(&verified_username).await?;
+    let last_active = Arc::new(Mutex::new(Instant::now()));
+    // A client coming back gets the messages it missed first, new messages are published once they're queued
+    let publishing = match last_id {
+        Some(_) => Some(context.publishing.lock().await),
+        None => None,
+    };
+    let (disconnect, logged_in) = match context.add_client(addr, &verified_username, nickname, last_active.clone(), write_half, codec).await {
+        Ok(added) => added,
+        Err(e) => {
+            tracing::warn!("Refused a login of {verified_username} from {addr}, the user is already logged in.");
+            context.audit_login(&verified_username, addr.ip(), LoginOutcome::AlreadyLoggedIn).await?;
+            return Err(e)?;
+        }
+    };
+    tracing::info!("User {verified_username} logged in from {addr}.");
+    if let Some(last_id) = last_id {
+        if let Err(e) = context.replay_missed(addr, &verified_username, last_id).await {
+            tracing::warn!("Sending the messages after {last_id} to {addr} failed: {e}");
+        }
+        drop(publishing);
+    }
+    context.audit_login(&verified_username, addr.ip(), LoginOutcome::Success).await?;
+
+    // A kicked older connection may already be gone, so the user counts as online since the check in `add_client`
+    if !logged_in {
+        context.broadcast_datagram(addr, Datagram::Presence { username: verified_username.clone(), online: true }).await?;
+    }
+
+    let result = session_loop(&context, &mut read_half, addr, &verified_username, &disconnect, &last_active).await;
+    // The client is removed however the session ended, including errors like spoofing
+    disconnect_client(&context, addr, &verified_username).await?;
+    result
+}
+
+/// Processes the datagrams of a logged in client until the session ends. The client is disconnected by the caller.
+///
+/// # Arguments
+///
+/// * `context` - The server context.
+/// * `read_half` - The readable half of the connection.
+/// * `addr` - The address of the client.
+/// * `verified_username` - The username of the authenticated user.
+/// * `disconnect` - Notified when the client should be disconnected.
+/// * `last_active` - Time of the last activity of the client.
+///
+/// # Returns
+///
+/// * `EmptyResult` - Returns an empty result if the session ended normally.
+async fn session_loop(context: &ServerContext, read_half: &mut ReadHalf, addr: PeerAddr, verified_username: &str, disconnect: &Notify,
+                      last_active: &Mutex<Instant>) -> EmptyResult {
+    let config = context.config();
+    let max_message_size = config.max_message_size;
+    let idle_timeout = config.idle_timeout;
+    // Guests have no account, so they can't upload anything and nothing refers to them in the database
+    let guest = chat::is_guest(verified_username);
+    // File transfers in progress with the storage reserved for them, keyed by the transfer ID chosen
+    // by the client. When the session ends, their reservations are released but the partial files stay, so
+    // the uploads can be resumed, only `IncomingTransfer::discard` deletes them
+    let mut transfers = HashMap::<TransferId, (IncomingTransfer, Reservation)>::new();
+    // Rejected transfers whose remaining chunks are dropped
+    let mut rejected = HashSet::<TransferId>::new();
+
+    // Read incoming datagrams in a loop
+    loop {
+        // The read is kept alive while pinging, cancelling it in the middle of a frame would desynchronize the stream
+        let read = Datagram::read_from_stream_limited(read_half, max_message_size, &config.codec);
+        tokio::pin!(read);
+
+        let idle_since = Instant::now();
+        let ping_at = idle_since + idle_timeout.unwrap_or_default() / 2;
+        let expire_at = idle_since + idle_timeout.unwrap_or_default();
+        let mut pinged = false;
+
+        let datagram = loop {
+            tokio::select! {
+                datagram = &mut read => break datagram,
+                _ = disconnect.notified() => {
+                    tracing::info!("Disconnecting user {verified_username} at {addr}.");
+                    return Ok(());
+                }
+                _ = tokio::time::sleep_until(ping_at), if idle_timeout.is_some() && !pinged => {
+                    tracing::debug!("Client {addr} is idle, sending a ping.");
+                    context.send_datagram_to(addr, &Datagram::Ping).await?;
+                    pinged = true;
+                }
+                _ = tokio::time::sleep_until(expire_at), if idle_timeout.is_some() && pinged => {
+                    tracing::warn!("Client {addr} did not respond to a ping, closing connection.");
+                    return Ok(());
+                }
+            }
+        };
+
+        // Pongs are sent by the client program without the user doing anything
+        if !matches!(datagram, Ok(Datagram::Pong)) {
+            *last_active.lock().unwrap() = Instant::now();
+        }
+
+        match datagram {
+            Ok(Datagram::Message(mut message)) => { 
+                context.verify_message_sender(verified_username, &message)?;
+                if let Some(until) = context.check_flood(verified_username, &message) {
+                    context.send_response_to(addr, ServerResponse::Muted { until }).await?;
+                    context.send_response_to(addr, ServerResponse::MessageAck(message.id)).await?;
+                    continue;
+                }
+                if message.announcement && (guest || !context.database.is_admin(verified_username).await?) {
+                    tracing::warn!("User {verified_username} attempted to post an announcement without permission.");
+                    let reason = ANNOUNCEMENT_REASON.to_string();
+                    context.send_response_to(addr, ServerResponse::MessageRejected { reason }).await?;
+                    context.send_response_to(addr, ServerResponse::MessageAck(message.id)).await?;
+                    continue;
+                }
+                // Attachments are replayed as offers and thumbnails, which can't carry the announcement flag
+                if message.announcement && !matches!(message.content, ChatMessageContent::Text(_)) {
+                    let reason = ANNOUNCEMENT_TEXT_REASON.to_string();
+                    context.send_response_to(addr, ServerResponse::MessageRejected { reason }).await?;
+                    context.send_response_to(addr, ServerResponse::MessageAck(message.id)).await?;
+                    continue;
+                }
+                if guest && !matches!(message.content, ChatMessageContent::Text(_)) {
+                    context.reject_attachment(addr, message.id, GUEST_ATTACHMENT_REASON.to_string()).await?;
+                    continue;
+                }
+                if let Err(reason) = context.config().attachments.check_message(&message.content) {
+                    context.reject_attachment(addr, message.id, reason).await?;
+                    continue;
+                }
+                let size = server_limits::attachment_size(&message.content);
+                // Held until the attachment is stored
+                let _reservation = match size {
+                    0 => Reservation::default(),
+                    _ => match context.check_quota(verified_username, size).await? {
+                        Ok(reservation) => reservation,
+                        Err(reason) => {
+                            context.reject_attachment(addr, message.id, reason).await?;
+                            continue;
+                        }
+                    },
+                };
+                if !context.filter_message(addr, &mut message, None).await? {
+                    continue;
+                }
+                message.timestamp = chrono::Utc::now();
+                message.nickname = context.nickname_of(addr).await;
+                message.origin = None;
+                message.seq = None;
+                // Only stored messages can be deleted when they expire
+                let expiring = !guest && matches!(message.content, ChatMessageContent::Text(_));
+                message.ttl = message.ttl.filter(|_| expiring).map(|ttl| ttl.clamp(1, MAX_MESSAGE_TTL));
+                if let ChatMessageContent::Image(image) = &message.content {
+                    context.publish_image(addr, &message, image).await?;
+                    continue;
+                }
+                if let ChatMessageContent::File(filename, data) = &message.content {
+                    context.publish_file(addr, &message, filename, data).await?;
+                    context.send_response_to(addr, ServerResponse::MessageAck(message.id)).await?;
+                    continue;
+                }
+                // Messages of guests are delivered but not kept in the history
+                if guest {
+                    context.broadcast_message(addr, &message).await?;
+                } else {
+                    context.publish_message(addr, &mut message).await?;
+                }
+                context.relay_message(None, &message).await;
+                context.send_response_to(addr, ServerResponse::MessageAck(message.id)).await?;
+                context.preview_links(&message);
+            }
+            Ok(Datagram::DirectMessage { to, mut message }) => {
+                context.verify_message_sender(verified_username, &message)?;
+                if let Some(until) = context.check_flood(verified_username, &message) {
+                    context.send_response_to(addr, ServerResponse::Muted { until }).await?;
+                    context.send_response_to(addr, ServerResponse::MessageAck(message.id)).await?;
+                    continue;
+                }
+                if guest && !matches!(message.content, ChatMessageContent::Text(_)) {
+                    context.reject_attachment(addr, message.id, GUEST_ATTACHMENT_REASON.to_string()).await?;
+                    continue;
+                }
+                if let Err(reason) = context.config().attachments.check_message(&message.content) {
+                    context.reject_attachment(addr, message.id, reason).await?;
+                    continue;
+                }
+                if !context.filter_message(addr, &mut message, Some(&to)).await? {
+                    continue;
+                }
+                message.timestamp = chrono::Utc::now();
+                message.nickname = context.nickname_of(addr).await;
+                message.origin = None;
+                message.seq = None;
+                // Announcements are posted to everyone, a direct message is never one
+                message.announcement = false;
+                // Direct messages aren't stored, so they can't expire
+                message.ttl = None;
+                if !context.send_direct_message(&to, &message).await? {
+                    tracing::info!("Direct message from {verified_username} to offline user {to} dropped.");
+                    context.send_response_to(addr, ServerResponse::UserOffline(to)).await?;
+                }
+                context.send_response_to(addr, ServerResponse::MessageAck(message.id)).await?;
+            }
+            Ok(Datagram::FileBegin { transfer_id, id, sender, kind, size }) => {
+                if sender != verified_username {
+                    Err(ServerError::SpoofingError)?
+                }
+
+                let allowed = match guest {
+                    true => Err(GUEST_ATTACHMENT_REASON.to_string()),
+                    false => match context.config().attachments.check_size(&kind, size) {
+                        Ok(()) => context.check_quota(verified_username, size).await?,
+                        Err(reason) => Err(reason),
+                    },
+                };
+                let reservation = match allowed {
+                    Ok(reservation) => reservation,
+                    Err(reason) => {
+                        // The client waits for the upload to be accepted, the abort tells it not to send the chunks
+                        context.send_datagram_to(addr, &Datagram::FileAbort { transfer_id }).await?;
+                        context.reject_attachment(addr, id, reason).await?;
+                        continue;
+                    }
+                };
+
+                let partial_dir = context.database.attachments.partial_dir();
+                let transfer = IncomingTransfer::open(&partial_dir, transfer_id, id, sender, kind, size).await?;
+                let next_seq = transfer.next_seq();
+                match next_seq {
+                    0 => tracing::info!("User {verified_username} started transfer {transfer_id} of {size} bytes."),
+                    _ => tracing::info!("User {verified_username} resumed transfer {transfer_id} of {size} bytes at chunk {next_seq}."),
+                }
+                transfers.insert(transfer_id, (transfer, reservation));
+                context.send_response_to(addr, ServerResponse::TransferAccepted { transfer_id, next_seq }).await?;
+            }
+            Ok(Datagram::FileChunk { transfer_id, seq, data }) => {
+                if rejected.contains(&transfer_id) {
+                    continue;
+                }
+                let Some((transfer, _)) = transfers.get_mut(&transfer_id) else {
+                    tracing::warn!("Received a chunk of an unknown transfer {transfer_id} from {addr}.");
+                    continue;
+                };
+
+                if seq == 0 {
+                    if let Err(reason) = context.config().attachments.check_content(&transfer.kind, &data) {
+                        let message_id = transfer.message_id;
+                        transfer.discard().await;
+                        transfers.remove(&transfer_id);
+                        rejected.insert(transfer_id);
+                        context.reject_attachment(addr, message_id, reason).await?;
+                        continue;
+                    }
+                }
+                if let Err(e) = transfer.write_chunk(seq, &data).await {
+                    tracing::warn!("Aborting transfer {transfer_id} from {addr}: {e}");
+                    transfer.discard().await;
+                    transfers.remove(&transfer_id);
+                }
+            }
+            Ok(Datagram::FileEnd { transfer_id }) => {
+                if rejected.remove(&transfer_id) {
+                    continue;
+                }
+                // The reservation is released after the upload is stored
+                let Some((transfer, _reservation)) = transfers.remove(&transfer_id) else {
+                    tracing::warn!("Received the end of an unknown transfer {transfer_id} from {addr}.");
+                    continue;
+                };
+
+                match transfer.finish().await {
+                    Ok(mut message) => {
+                        tracing::info!("Transfer {transfer_id} from {addr} completed.");
+                        message.nickname = context.nickname_of(addr).await;
+                        match &message.content {
+                            ChatMessageContent::Image(image) => context.publish_image(addr, &message, image).await?,
+                            ChatMessageContent::File(filename, data) => {
+                                context.publish_file(addr, &message, filename, data).await?;
+                                context.send_response_to(addr, ServerResponse::MessageAck(message.id)).await?;
+                            },
+                            // Voice notes are small, they're delivered inline like text messages
+                            ChatMessageContent::Audio { .. } => {
+                                context.publish_message(addr, &mut message).await?;
+                                context.send_response_to(addr, ServerResponse::MessageAck(message.id)).await?;
+                            },
+                            _ => {},
+                        }
+                    },
+                    Err(e) => {
+                        tracing::warn!("Aborting transfer {transfer_id} from {addr}: {e}");
+                    }
+                }
+            }
+            Ok(Datagram::FileAbort { transfer_id }) => {
+                rejected.remove(&transfer_id);
+                if let Some((transfer, _)) = transfers.remove(&transfer_id) {
+                    tracing::info!("Transfer {transfer_id} from {addr} cancelled by the client.");
+                    transfer.discard().await;
+                }
+            }
+            Ok(Datagram::Ping) => {
+                context.send_datagram_to(addr, &Datagram::Pong).await?;
+            }
+            Ok(Datagram::Pong) => {
+                // Any datagram resets the idle timer, nothing else to do
+            }
+            Ok(Datagram::ListUsers) => {
+                let users = context.online_users().await;
+                context.send_response_to(addr, ServerResponse::UserList(users)).await?;
+            }
+            Ok(Datagram::AdminCommand(command)) => {
+                let response = context.perform_admin_command(verified_username, addr.ip(), command).await?;
+                context.send_response_to(addr, response).await?;
+            }
+            Ok(Datagram::ChangePassword { .. }) if guest => {
+                context.send_response_to(addr, ServerResponse::PasswordChangeFailed("Guests have no password.".to_string())).await?;
+            }
+            Ok(Datagram::ChangePassword { old_password, new_password }) => {
+                let response = context.change_password(verified_username, addr.ip(), &old_password, &new_password).await?;
+                context.send_response_to(addr, response).await?;
+            }
+            Ok(Datagram::FetchAttachment { id, from_seq }) => {
+                context.fetch_attachment(addr, id, from_seq).await?;
+            }
+            Ok(Datagram::FetchSince { last_id }) => {
+                context.fetch_since(addr, verified_username, last_id).await?;
+            }
+            Ok(Datagram::SetNickname(_)) if guest => {
+                context.send_response_to(addr, ServerResponse::NicknameRejected("Guests can't change their nickname.".to_string())).await?;
+            }
+            Ok(Datagram::SetNickname(nickname)) => {
+                let response = context.set_nickname(addr, verified_username, nickname).await?;
+                context.send_response_to(addr, response).await?;
+            }
+            Ok(Datagram::SetProfile { .. }) if guest => {
+                context.send_response_to(addr, ServerResponse::ProfileRejected("Guests can't set a profile.".to_string())).await?;
+            }
+            Ok(Datagram::SetProfile { status, avatar_png }) => {
+                let response = context.set_profile(addr, verified_username, status, avatar_png).await?;
+                context.send_response_to(addr, response).await?;
+            }
+            Ok(Datagram::FetchProfile(username)) => {
+                let profile = context.database.profile(&username).await?;
+                context.send_response_to(addr, ServerResponse::Profile(profile)).await?;
+            }
+            Ok(Datagram::ListStatuses) => {
+                let users = context.database.statuses(context.online_users().await).await?;
+                context.send_response_to(addr, ServerResponse::UserStatuses(users)).await?;
+            }
+            Ok(Datagram::MarkRead { .. }) if guest => {
+                // Read receipts are kept only for registered users
+            }
+            Ok(Datagram::MarkRead { up_to }) => {
+                context.database.mark_read(verified_username, up_to, chrono::Utc::now()).await?;
+            }
+            Ok(Datagram::Seen(username)) => {
+                let read_at = context.database.last_read(&username).await?.map(|(_, read_at)| read_at);
+                context.send_response_to(addr, ServerResponse::LastRead { username, read_at }).await?;
+            }
+            Ok(Datagram::Block(_) | Datagram::Unblock(_)) if guest => {
+                context.send_response_to(addr, ServerResponse::BlockFailed("Guests can't block users.".to_string())).await?;
+            }
+            Ok(Datagram::Block(other)) => {
+                let response = context.set_blocked(verified_username, &other, true).await?;
+                context.send_response_to(addr, response).await?;
+            }
+            Ok(Datagram::Unblock(other)) => {
+                let response = context.set_blocked(verified_username, &other, false).await?;
+                context.send_response_to(addr, response).await?;
+            }
+            Ok(Datagram::ListBlocks) => {
+                context.send_response_to(addr, ServerResponse::BlockList(context.blocked_by(verified_username))).await?;
+            }
+            Ok(Datagram::Stats) => {
+                let statistics = context.statistics().await?;
+                context.send_response_to(addr, ServerResponse::Stats(statistics)).await?;
+            }
+            Ok(_) => {
+                tracing::warn!("Received an unexpected datagram from {addr}."); 
+            },
+            Err(chat::ChatProtocolError::IOError | chat::ChatProtocolError::Timeout) => { 
+                Err(ServerError::BrokenStream)?
+            },
+            Err(chat::ChatProtocolError::MalformedMessage) => { 
+                tracing::warn!("Received a malformed datagram from {addr}."); 
+            }
+            Err(chat::ChatProtocolError::FrameTooLarge(len)) => {
+                tracing::warn!("Received a frame of {len} bytes from {addr}, closing connection.");
+                Err(ServerError::BrokenStream)?
+            }
+        }
+    }
+}
+
+/// Writes queued datagrams to a client until the queue is closed and empty or the connection fails.
+/// Datagrams queued for several clients are encoded by the first writer with the codec of the client.
+///
+/// # Arguments
+///
+/// * `addr` - The address of the client.
+/// * `write_half` - The writable half of the connection.
+/// * `queue` - The queue of datagrams to be written.
+/// * `codec` - The codec used to encode the datagrams.
+/// * `write_timeout` - How long the client may take to accept a datagram, `None` waits forever.
+/// * `disconnect` - Notified when the write fails or times out so that the client gets disconnected.
+async fn send_datagrams(addr: PeerAddr, mut write_half: WriteHalf, queue: Arc<SendQueue>, codec: SessionCodec, write_timeout: Option<Duration>,
+                        disconnect: Arc<Notify>) {
+    while let Some(datagram) = queue.pop().await {
+        tracing::debug!("Forwarding a datagram to {addr}.");
+        let written = match datagram.frame(&codec) {
+            Ok(frame) => write_frame(&mut write_half, &frame, write_timeout).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = written {
+            match e {
+                chat::ChatProtocolError::Timeout => tracing::warn!("Write to client {addr} timed out, disconnecting it."),
+                _ => tracing::warn!("Write to client {addr} failed."),
+            }
+            // Closing the queue also releases senders waiting for room under the block policy
+            queue.close();
+            disconnect.notify_one();
+            break;
+        }
+    }
+}
+
+/// Writes an encoded frame to a client.
+///
+/// # Arguments
+///
+/// * `write_half` - The writable half of the connection.
+/// * `frame` - The frame, header and payload.
+/// * `write_timeout` - How long the client may take to accept the frame, `None` waits forever.
+///
+/// # Returns
+///
+/// * `Result<(), ChatProtocolError>` - Returns an empty result if successful.
+async fn write_frame(write_half: &mut WriteHalf, frame: &[u8], write_timeout: Option<Duration>) -> Result<(), chat::ChatProtocolError> {
+    let write = async { write_half.write_all(frame).await.map_err(|_| chat::ChatProtocolError::IOError) };
+    match write_timeout {
+        Some(timeout) => tokio::time::timeout(timeout, write).await.unwrap_or(Err(chat::ChatProtocolError::Timeout)),
+        None => write.await,
+    }
+}
+
+/// Sends a stored attachment to a client in a chunked file transfer.
+///
+/// # Arguments
+///
+/// * `queue` - The send queue of the client.
+/// * `transfer_id` - The ID of the transfer.
+/// * `id` - The ID of the attachment.
+/// * `attachment` - The attachment to be sent.
+/// * `from_seq` - The first chunk to be sent, the whole attachment is sent if it's past its end.
+///
+/// # Returns
+///
+/// * `EmptyResult` - Returns an empty result if the whole attachment was queued.
+async fn send_stored_attachment(queue: Arc<SendQueue>, transfer_id: TransferId, id: AttachmentId, attachment: StoredAttachment, from_seq: u64) -> EmptyResult {
+    let mut file = tokio::fs::File::open(&attachment.path).await
+        .with_context(|| format!("Could not open {}.", attachment.path.display()))?;
+    let chunk_size = chat::FILE_CHUNK_SIZE as u64;
+    let mut seq = match from_seq.checked_mul(chunk_size) {
+        Some(offset) if offset <= attachment.size => from_seq,
+        _ => 0,
+    };
+    file.seek(std::io::SeekFrom::Start(seq * chunk_size)).await
+        .with_context(|| format!("Could not read {}.", attachment.path.display()))?;
+
+    let begin = Datagram::FileBegin { transfer_id, id, sender: attachment.sender, kind: attachment.kind, size: attachment.size };
+    anyhow::ensure!(queue.push(Outgoing::new(begin)).await, "The client disconnected.");
+
+    loop {
+        let mut data = Vec::with_capacity(chat::FILE_CHUNK_SIZE);
+        let read = (&mut file).take(chat::FILE_CHUNK_SIZE as u64).read_to_end(&mut data).await;
+        let datagram = match read {
+            Ok(0) => break,
+            Ok(_) => Datagram::FileChunk { transfer_id, seq, data },
+            Err(e) => {
+                queue.push(Outgoing::new(Datagram::FileAbort { transfer_id })).await;
+                return Err(e).with_context(|| format!("Could not read {}.", attachment.path.display()));
+            }
+        };
+        anyhow::ensure!(queue.push(Outgoing::new(datagram)).await, "The client disconnected.");
+        seq += 1;
+    }
+
+    anyhow::ensure!(queue.push(Outgoing::new(Datagram::FileEnd { transfer_id })).await, "The client disconnected.");
+    Ok(())
+}
+
+/// Sends stored messages to a client the way they were delivered when they were posted, followed by
+/// `ServerResponse::FetchComplete`. Images get their thumbnails made again, messages whose attachment
+/// was removed are left out. Nicknames are the current ones of the senders.
+///
+/// # Arguments
+///
+/// * `queue` - The send queue of the client.
+/// * `database` - The database.
+/// * `records` - The messages, oldest first.
+/// * `last_id` - The ID of the last loaded message, sent with `FetchComplete`.
+/// * `more` - Whether there are newer messages than the loaded ones.
+///
+/// # Returns
+///
+/// * `EmptyResult` - Returns an empty result if all messages were queued.
+async fn send_stored_messages(queue: Arc<SendQueue>, database: &ServerDatabase, records: Vec<MessageRecord>, last_id: MessageSeq, more: bool) -> EmptyResult {
+    let mut nicknames: HashMap<String, Option<String>> = HashMap::new();
+    let now = chrono::Utc::now();
+    for record in records {
+        // Expired messages may not have been deleted yet
+        if record.expires_at.is_some_and(|expires_at| expires_at <= now) {
+            continue;
+        }
+        let nickname = match nicknames.get(&record.sender) {
+            Some(nickname) => nickname.clone(),
+            None => {
+                let nickname = database.nickname(&record.sender).await?;
+                nicknames.insert(record.sender.clone(), nickname.clone());
+                nickname
+            }
+        };
+        let timestamp = record.timestamp.unwrap_or_default();
+        let mut message = ChatMessage {
+            timestamp,
+            nickname,
+            reply_to: record.reply_to,
+            seq: Some(record.id),
+            announcement: record.announcement,
+            ttl: record.expires_at.map(|expires_at| (expires_at - timestamp).num_seconds().max(0) as u32),
+            ..ChatMessage::new(record.client_id.unwrap_or_default(), &record.sender, ChatMessageContent::Text(record.text.unwrap_or_default()))
+        };
+
+        let datagram = match record.attachment {
+            None => Datagram::Message(message),
+            Some(AttachmentKind::File(filename)) => Datagram::FileOffer {
+                id: record.id,
+                sender: message.sender,
+                nickname: message.nickname,
+                timestamp,
+                filename,
+                size: record.size.unwrap_or_default(),
+            },
+            Some(kind) => {
+                let Some(attachment) = database.find_attachment(record.id).await? else {
+                    continue;
+                };
+                let data = tokio::fs::read(&attachment.path).await
+                    .with_context(|| format!("Could not read {}.", attachment.path.display()))?;
+                match kind {
+                    AttachmentKind::Audio(format) => {
+                        message.content = ChatMessageContent::Audio { format, data };
+                        Datagram::Message(message)
+                    },
+                    _ => {
+                        message.content = ChatMessageContent::Image(make_thumbnail(data).await?);
+                        Datagram::Thumbnail { id: record.id, message }
+                    },
+                }
+            },
+        };
+        anyhow::ensure!(queue.push(Outgoing::new(datagram)).await, "The client disconnected.");
+    }
+
+    let complete = Datagram::ServerResponse(ServerResponse::FetchComplete { last_id, more });
+    anyhow::ensure!(queue.push(Outgoing::new(complete)).await, "The client disconnected.");
+    Ok(())
+}
+
+/// Removes the client from the server context and announces that the user went offline
+/// if this was their last connection.
+///
+/// # Arguments
+///
+/// * `context` - The server context.
+/// * `addr` - The address of the client.
+/// * `username` - The username of the client.
+///
+/// # Returns
+///
+/// * `EmptyResult` - Returns an empty result if successful.
+async fn disconnect_client(context: &ServerContext, addr: PeerAddr, username: &str) -> EmptyResult {
+    context.remove_client(addr).await;
+
+    if context.count_connections(username).await == 0 {
+        context.broadcast_datagram(addr, Datagram::Presence { username: username.to_string(), online: false }).await?;
+    }
+    Ok(())
+}
+
+/// Handles incoming connection errors and passes control to `receive_datagrams`.
+///
+/// # Arguments
+///
+/// * `context` - The server context.
+/// * `client_info` - The result containing both halves of the connection and the address of the client.
+/// * `tls` - The acceptor doing the TLS handshake if the client connected to the TLS port.
+///
+/// # Returns
+///
+/// * `EmptyResult` - Returns an empty result if successful.
+#[tracing::instrument(name = "connection", skip_all, fields(peer = tracing::field::Empty))]
+async fn handle_client(context: ServerContext, client_info: Result<(ReadHalf, WriteHalf, PeerAddr), std::io::Error>,
+                       tls: Option<TlsAcceptor>) -> EmptyResult {
+    let (read_half, write_half, address) = client_info
+        .context("Failed to establish communication with a client.")?;
+
+    tracing::Span::current().record("peer", tracing::field::display(address));
+
+    let (read_half, write_half, certificate) = match tls {
+        Some(acceptor) => match tokio::time::timeout(server_tls::HANDSHAKE_TIMEOUT, server_tls::accept(&acceptor, read_half, write_half)).await {
+            Ok(Ok(accepted)) => accepted,
+            Ok(Err(e)) => {
+                tracing::warn!("TLS handshake failed: {e}");
+                return Ok(());
+            },
+            Err(_) => {
+                tracing::warn!("TLS handshake not completed in time.");
+                return Ok(());
+            },
+        },
+        None => (read_half, write_half, None),
+    };
+
+    // The place is held until the connection is closed
+    let _slot = match context.admit(address.ip()).await {
+        Ok(slot) => slot,
+        Err(reason) => {
+            tracing::warn!("Rejecting the connection: {reason}.");
+            reject_client(read_half, write_half, &context.config()).await;
+            return Ok(());
+        }
+    };
+
+    tracing::info!("Client task started.");
+
+    if let Err(e) = receive_datagrams(context, read_half, write_half, address, certificate).await {
+        if let Some(ServerError::BrokenStream) = e.downcast_ref::<ServerError>() {
+            tracing::warn!("Connection with client terminated.");
+            tracing::warn!("{e}");
+        } else {
+            return Err(e);
+        }
+    }
+    
+    tracing::info!("Client task terminated.");
+    Ok(())
+}
+
+/// Tells a client that the server is full and closes the connection. The login datagram is read first,
+/// so that the client gets the response instead of a reset connection.
+///
+/// # Arguments
+///
+/// * `read_half` - The readable half of the connection.
+/// * `write_half` - The writable half of the connection.
+/// * `config` - The server configuration.
+async fn reject_client(mut read_half: ReadHalf, mut write_half: WriteHalf, config: &ServerConfig) {
+    let reject = async {
+        Datagram::read_from_stream_limited(&mut read_half, config.max_message_size, &config.codec).await?;
+        send_response(&mut write_half, config, ServerResponse::ServerFull).await
+    };
+    if let Ok(Err(e)) = tokio::time::timeout(REJECT_TIMEOUT, reject).await {
+        tracing::debug!("Could not notify the rejected client: {e}");
+    }
+}
+
+/// Binds the sockets on which the server listens for clients. When several addresses are given, IPv6 sockets
+/// accept only IPv6 clients, so that e.g. `0.0.0.0` and `::` can share the port. A single IPv6 address
+/// like `::` accepts clients of both protocols.
+///
+/// # Arguments
+///
+/// * `addresses` - The addresses to bind to.
+/// * `port` - The port to bind to.
+/// * `unix_socket` - The path of a Unix socket to listen on instead of the addresses and port.
+///
+/// # Returns
+///
+/// * `Result<Vec<Listener>>` - Returns the bound listeners if successful.
+async fn bind_listeners(addresses: &[String], port: u16, unix_socket: Option<&Path>) -> Result<Vec<Listener>> {
+    if let Some(path) = unix_socket {
+        let listener = Listener::bind_unix(path)?;
+        tracing::info!("Ok: listening for connections on {}", path.display());
+        return Ok(vec![listener]);
+    }
+
+    let mut listeners = Vec::new();
+    for address in addresses {
+        let listener = Listener::bind_tcp(address, port, addresses.len() > 1).await?;
+        // The actual port differs from the requested one when binding port 0
+        let endpoint = listener.local_addr().map_or_else(|| format!("{address}:{port}"), |addr| addr.to_string());
+        tracing::info!("Ok: listening for connections on {endpoint}");
+        listeners.push(listener);
+    }
+    Ok(listeners)
+}
+
+/// Binds the TLS port on the same addresses as the plain port, if a TLS port is configured.
+/// Bots log in there with a client certificate instead of a password, other clients may use it too.
+///
+/// # Arguments
+///
+/// * `addresses` - The addresses to bind to.
+/// * `run` - The flags of the `run` command.
+/// * `file` - The configuration file.
+///
+/// # Returns
+///
+/// * `Result<Vec<Listener>>` - Returns the bound listeners, none without a TLS port.
+async fn bind_tls_listeners(addresses: &[String], run: &RunArgs, file: &FileConfig) -> Result<Vec<Listener>> {
+    let Some(port) = run.tls_port.or(file.tls_port) else {
+        return Ok(Vec::new());
+    };
+    let cert = run.tls_cert.clone().or(file.tls_cert.clone())
+        .ok_or_else(|| anyhow::anyhow!("The TLS port requires the certificate of the server, see --tls-cert."))?;
+    let key = run.tls_key.clone().or(file.tls_key.clone())
+        .ok_or_else(|| anyhow::anyhow!("The TLS port requires the private key of the server, see --tls-key."))?;
+    let acceptor = server_tls::load_acceptor(&cert, &key)?;
+
+    let listeners = bind_listeners(addresses, port, None).await?;
+    tracing::info!("Ok: connections on port {port} use TLS");
+    Ok(listeners.into_iter().map(|listener| listener.with_tls(acceptor.clone())).collect())
+}
+
+/// Main server function. Accepts connections on the listeners, each in its own task, and spawns a new task
+/// to handle each connection until `shutdown` completes. Connected clients are disconnected when the server shuts down.
+///
+/// # Arguments
+///
+/// * `listeners` - The bound listeners.
+/// * `db_file` - The path to the SQLite database file.
+/// * `config` - The server configuration.
+/// * `reloader` - Rebuilds the configuration on `SIGHUP` or from the admin console, `None` if it can't be reloaded.
+/// * `shutdown` - Completes when the server should stop.
+///
+/// # Returns
+///
+/// * `EmptyResult` - Returns an empty result if successful.
+async fn start_server(listeners: Vec<Listener>, db_file: &str, config: ServerConfig, reloader: Option<Reloader>, shutdown: impl Future<Output = ()>) -> EmptyResult {
+    let mut context = ServerContext::new(db_file, config).await?;
+    context.reloader = reloader.map(Arc::new);
+    serve(context, listeners, shutdown).await
+}
+
+/// Runs a server on an existing context, like `start_server`, e.g. after users were added to its in-memory database.
+///
+/// # Arguments
+///
+/// * `context` - The server context.
+/// * `listeners` - The bound listeners.
+/// * `shutdown` - Completes when the server should stop.
+///
+/// # Returns
+///
+/// * `EmptyResult` - Returns an empty result if successful.
+async fn serve(context: ServerContext, listeners: Vec<Listener>, shutdown: impl Future<Output = ()>) -> EmptyResult {
+    if context.database.is_in_memory() {
+        tracing::warn!("The database is kept in memory, all users and messages are lost when the server stops.");
+    }
+    // Background tasks which are stopped together with the server
+    let mut tasks = Vec::new();
+
+    #[cfg(unix)]
+    if context.reloader.is_some() {
+        tasks.push(tokio::spawn(server_reload::reload_on_hangup(context.clone())));
+    }
+
+    if let Some(retention) = context.config().retention {
+        tasks.push(tokio::spawn(prune_messages(context.database.clone(), retention)));
+    }
+    tasks.push(tokio::spawn(prune_partial_uploads(context.database.attachments.partial_dir())));
+    tasks.push(tokio::spawn(expire_messages(context.clone())));
+
+    if let Some(api_address) = &context.config().api_address {
+        let api_listener = TcpListener::bind(api_address).await
+            .with_context(|| format!("Could not bind the HTTP API to {api_address}."))?;
+        let router = server_api::router(context.clone());
+        tracing::info!("Ok: serving the HTTP API on {api_address}");
+        tasks.push(tokio::spawn(async move {
+            if let Err(e) = axum::serve(api_listener, router.into_make_service_with_connect_info::<std::net::SocketAddr>()).await {
+                tracing::error!("HTTP API error: {e}");
+            }
+        }));
+    }
+
+    let console = server_admin::bind_console(context.config().admin_address.as_deref(), context.config().admin_socket.as_deref()).await?;
+    for listener in console {
+        tasks.push(tokio::spawn(server_admin::run_console(context.clone(), listener)));
+    }
+
+    for address in &context.config().peers {
+        tasks.push(tokio::spawn(server_federation::run_peer_link(context.clone(), address.clone()).in_current_span()));
+    }
+
+    for listener in listeners {
+        tasks.push(tokio::spawn(accept_connections(context.clone(), listener)));
+    }
+
+    shutdown.await;
+    tracing::info!("Shutting down.");
+    for task in tasks {
+        task.abort();
+    }
+    context.disconnect_all().await;
+    Ok(())
+}
+
+/// Accepts connections on a listener and spawns a new task to handle each connection.
+/// Runs until the task is aborted when the server shuts down.
+///
+/// # Arguments
+///
+/// * `context` - The server context.
+/// * `listener` - The bound listener.
+async fn accept_connections(context: ServerContext, mut listener: Listener) {
+    let tls = listener.tls_acceptor();
+    loop {
+        let connection = listener.accept().await;
+        let context = context.clone();
+        let tls = tls.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_client(context, connection, tls).await {
+                tracing::error!("Client error: {e}");
+            }
+        });
+    }
+}
+
+/// Periodically deletes messages older than the retention period and attachments which are no longer referenced.
+///
+/// # Arguments
+///
+/// * `database` - The server database.
+/// * `retention` - How long messages are kept.
+async fn prune_messages(database: Arc<ServerDatabase>, retention: Duration) {
+    let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let before = chrono::Utc::now() - retention;
+        match database.prune_messages(before).await {
+            Ok((messages, files, bytes)) => {
+                tracing::info!("Pruned {messages} messages older than {before} and {files} attachments ({bytes} bytes).");
+            },
+            Err(e) => {
+                tracing::error!("Pruning of old messages failed: {e}");
+            }
+        }
+    }
+}
+
+/// Deletes the ephemeral messages when they expire and tells all clients to remove them.
+///
+/// # Arguments
+///
+/// * `context` - The server context.
+async fn expire_messages(context: ServerContext) {
+    let mut interval = tokio::time::interval(EXPIRY_INTERVAL);
+    loop {
+        interval.tick().await;
+        match context.database.delete_expired_messages(chrono::Utc::now()).await {
+            Ok(expired) => {
+                for (sender, id, seq) in expired {
+                    tracing::debug!("Message {seq} of {sender} expired.");
+                    context.deliver(Outgoing::new(Datagram::MessageExpired { sender, id, seq: Some(seq) }), Route::Everyone).await;
+                }
+            },
+            Err(e) => tracing::error!("Deleting expired messages failed: {e}"),
+        }
+    }
+}
+
+/// Periodically deletes the partial files of uploads which weren't resumed for a long time.
+///
+/// # Arguments
+///
+/// * `dir` - The directory of partial files.
+async fn prune_partial_uploads(dir: PathBuf) {
+    let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+    loop {
+        interval.tick().await;
+        match server_transfer::remove_stale_partials(&dir, PARTIAL_UPLOAD_LIFETIME).await {
+            Ok(0) => {},
+            Ok(files) => tracing::info!("Removed {files} partial files of abandoned uploads."),
+            Err(e) => tracing::error!("Pruning of partial uploads failed: {e}"),
+        }
+    }
+}
+
+/// Registers a new user in the database.
+///
+/// # Arguments
+///
+/// * `db_file` - The path to the SQLite database file.
+/// * `attachment_dir` - The directory where attachments are stored.
+/// * `username` - The username to register.
+/// * `password` - The password to register.
+/// * `admin` - Whether the user should have the admin role.
+///
+/// # Returns
+///
+/// * `EmptyResult` - Returns an empty result if successful.
+async fn register_user(db_file: &str, attachment_dir: &Path, username: &str, password: &str, admin: bool) -> EmptyResult {
+    let db = ServerDatabase::new(db_file, attachment_dir).await?;
+    db.register_user(username, password).await?;
+    if admin {
+        db.set_admin(username, true).await?;
+    }
+    db.log_event(AuditEvent::Register, Some(COMMAND_LINE_ACTOR), Some(username), None, admin.then_some("admin")).await?;
+    tracing::info!("{}", t!("user-registered", username = username));
+    Ok(())
+}
+
+/// Sets a new password of a registered user.
+///
+/// # Arguments
+///
+/// * `db_file` - The path to the SQLite database file.
+/// * `attachment_dir` - The directory where attachments are stored.
+/// * `username` - The username of the user.
+/// * `password` - The new password.
+///
+/// # Returns
+///
+/// * `EmptyResult` - Returns an empty result if successful.
+async fn change_password(db_file: &str, attachment_dir: &Path, username: &str, password: &str) -> EmptyResult {
+    let db = ServerDatabase::new(db_file, attachment_dir).await?;
+    db.change_password(username, password).await?;
+    db.log_event(AuditEvent::PasswordChange, Some(COMMAND_LINE_ACTOR), Some(username), None, None).await?;
+    tracing::info!("{}", t!("user-password-changed", username = username));
+    Ok(())
+}
+
+/// Deletes a registered user together with their message history.
+///
+/// # Arguments
+///
+/// * `db_file` - The path to the SQLite database file.
+/// * `attachment_dir` - The directory where attachments are stored.
+/// * `username` - The username of the user.
+///
+/// # Returns
+///
+/// * `EmptyResult` - Returns an empty result if successful.
+async fn delete_user(db_file: &str, attachment_dir: &Path, username: &str) -> EmptyResult {
+    let db = ServerDatabase::new(db_file, attachment_dir).await?;
+    db.delete_user(username).await?;
+    db.log_event(AuditEvent::DeleteUser, Some(COMMAND_LINE_ACTOR), Some(username), None, None).await?;
+    tracing::info!("{}", t!("user-deleted", username = username));
+    Ok(())
+}
+
+/// Registers a client certificate for a user, who can then log in with it on the TLS port without a password.
+///
+/// # Arguments
+///
+/// * `db_file` - The path to the SQLite database file.
+/// * `attachment_dir` - The directory where attachments are stored.
+/// * `username` - The username of the user.
+/// * `fingerprint` - The SHA-256 fingerprint of the certificate.
+///
+/// # Returns
+///
+/// * `EmptyResult` - Returns an empty result if successful.
+async fn add_certificate(db_file: &str, attachment_dir: &Path, username: &str, fingerprint: &str) -> EmptyResult {
+    let db = ServerDatabase::new(db_file, attachment_dir).await?;
+    db.add_certificate(username, fingerprint).await?;
+    db.log_event(AuditEvent::Certificate, Some(COMMAND_LINE_ACTOR), Some(username), None, Some(&format!("added {fingerprint}"))).await?;
+    tracing::info!("{}", t!("certificate-added", fingerprint = fingerprint, username = username));
+    Ok(())
+}
+
+/// Removes a client certificate, so it can no longer be used to log in.
+///
+/// # Arguments
+///
+/// * `db_file` - The path to the SQLite database file.
+/// * `attachment_dir` - The directory where attachments are stored.
+/// * `fingerprint` - The SHA-256 fingerprint of the certificate.
+///
+/// # Returns
+///
+/// * `EmptyResult` - Returns an empty result if successful.
+async fn remove_certificate(db_file: &str, attachment_dir: &Path, fingerprint: &str) -> EmptyResult {
+    let db = ServerDatabase::new(db_file, attachment_dir).await?;
+    let owner = db.certificate_owner(fingerprint).await?
+        .ok_or_else(|| anyhow::anyhow!(t!("certificate-not-registered", fingerprint = fingerprint)))?;
+    db.remove_certificate(fingerprint).await?;
+    db.log_event(AuditEvent::Certificate, Some(COMMAND_LINE_ACTOR), Some(&owner), None, Some(&format!("removed {fingerprint}"))).await?;
+    tracing::info!("{}", t!("certificate-removed", fingerprint = fingerprint, username = owner.as_str()));
+    Ok(())
+}
+
+/// Prints the registered client certificates with their users.
+///
+/// # Arguments
+///
+/// * `db_file` - The path to the SQLite database file.
+/// * `attachment_dir` - The directory where attachments are stored.
+///
+/// # Returns
+///
+/// * `EmptyResult` - Returns an empty result if successful.
+async fn print_certificates(db_file: &str, attachment_dir: &Path) -> EmptyResult {
+    let db = ServerDatabase::new(db_file, attachment_dir).await?;
+    for (username, fingerprint) in db.certificates().await? {
+        println!("{username} {fingerprint}");
+    }
+    Ok(())
+}
+
+/// Prints the attachment storage used by every user, with their quotas.
+///
+/// # Arguments
+///
+/// * `db_file` - The path to the SQLite database file.
+/// * `attachment_dir` - The directory where attachments are stored.
+/// * `quotas` - The storage quotas of the users.
+///
+/// # Returns
+///
+/// * `EmptyResult` - Returns an empty result if successful.
+async fn print_storage_report(db_file: &str, attachment_dir: &Path, quotas: &StorageQuotas) -> EmptyResult {
+    let db = ServerDatabase::new(db_file, attachment_dir).await?;
+    print!("{}", storage_report(&db.storage_usage().await?, quotas));
+    Ok(())
+}
 
-        for addr in to_remove {
-            clients.remove(&addr);
+/// Formats the storage used by the users as a table, followed by the totals.
+///
+/// # Arguments
+///
+/// * `usage` - The storage used by every user.
+/// * `quotas` - The storage quotas of the users.
+///
+/// # Returns
+///
+/// * `String` - Returns the table, a line per user.
+fn storage_report(usage: &[StorageUsage], quotas: &StorageQuotas) -> String {
+    let rows: Vec<[String; 5]> = usage.iter()
+        .map(|user| {
+            let quota = quotas.quota_of(&user.username);
+            [
+                user.username.clone(),
+                user.attachments.to_string(),
+                format_size(user.bytes),
+                quota.map_or_else(|| t!("storage-unlimited"), format_size),
+                quota.map(|quota| format!("{:.0} %", user.bytes as f64 * 100.0 / quota.max(1) as f64)).unwrap_or_default(),
+            ]
+        })
+        .collect();
+    let header = [t!("storage-user"), t!("storage-attachments"), t!("storage-used"), t!("storage-quota"), t!("storage-share")];
+    let mut widths = header.clone().map(|heading| heading.chars().count());
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
         }
-
-        Ok(())
     }
 
-    /// Checks user authentication by verifying the password.
-    ///
-    /// # Arguments
-    ///
-    /// * `username` - A string slice that holds the username.
-    /// * `password` - A string slice that holds the password.
-    ///
-    /// # Returns
-    ///
-    /// * `Result<bool>` - Returns a result containing a boolean indicating if authentication was successful.
-    pub async fn check_auth(&self, username: &str, password: &str) -> Result<bool> {
-        let mut db = self.database.lock().await;
-        Ok(db.check_auth(username, password).await?)
+    let mut report = String::new();
+    for row in std::iter::once(&header).chain(&rows) {
+        let line = format!("{:<w0$}  {:>w1$}  {:>w2$}  {:>w3$}  {:>w4$}", row[0], row[1], row[2], row[3], row[4],
+                           w0 = widths[0], w1 = widths[1], w2 = widths[2], w3 = widths[3], w4 = widths[4]);
+        report.push_str(line.trim_end());
+        report.push('\n');
     }
+    let attachments: u64 = usage.iter().map(|user| user.attachments).sum();
+    let bytes: u64 = usage.iter().map(|user| user.bytes).sum();
+    report.push_str(&t!("storage-total", attachments = attachments, used = format_size(bytes)));
+    report.push('\n');
+    report
 }
 
-/// Sends a server response to the client.
+/// Prints the most recent messages caught by the content filter.
 ///
 /// # Arguments
 ///
-/// * `write_half` - The writable half of the TCP stream.
-/// * `response` - The server response to be sent.
+/// * `db_file` - The path to the SQLite database file.
+/// * `attachment_dir` - The directory where attachments are stored.
+/// * `limit` - The maximum number of messages to print.
 ///
 /// # Returns
 ///
 /// * `EmptyResult` - Returns an empty result if successful.
-pub async fn send_response(write_half: &mut OwnedWriteHalf, response: ServerResponse) -> EmptyResult {
-    let datagram = Datagram::ServerResponse(response);
-    datagram.write_to_stream(write_half).await?;
+async fn print_filter_log(db_file: &str, attachment_dir: &Path, limit: u32) -> EmptyResult {
+    let db = ServerDatabase::new(db_file, attachment_dir).await?;
+    for record in db.filtered_messages(limit).await? {
+        let time = record.timestamp.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S");
+        let recipient = record.recipient.map(|recipient| format!(" -> {recipient}")).unwrap_or_default();
+        println!("{time} [{}{recipient}] {} ({}: {})", record.sender, record.text, record.action, record.rules);
+    }
     Ok(())
 }
 
-/// Receives messages from a client and broadcasts them to other clients.
+/// Prints the most recent login attempts, oldest first.
 ///
 /// # Arguments
 ///
-/// * `context` - The server context.
-/// * `stream` - The TCP stream of the client.
-/// * `addr` - The socket address of the client.
+/// * `db_file` - The path to the SQLite database file.
+/// * `attachment_dir` - The directory where attachments are stored.
+/// * `username` - Only the attempts to log in as this user are printed, all if `None`.
+/// * `limit` - The maximum number of attempts to print.
 ///
 /// # Returns
 ///
 /// * `EmptyResult` - Returns an empty result if successful.
-async fn receive_datagrams(context: ServerContext, stream: TcpStream, addr: SocketAddr) -> EmptyResult {
-    let (mut read_half, mut write_half) = stream.into_split();
-    
-    let verified_username;
-    // Expect login datagram
-    match Datagram::read_from_stream(&mut read_half).await {
-        Err(e) => return Err(e)?,
-        Ok(Datagram::Login { username, password }) => {
-            if context.check_auth(username.as_str(), password.as_str()).await? {
-                log::info!("User {username} logged in from {addr}.");
-                verified_username = username;
-                send_response(&mut write_half, ServerResponse::LoginOk).await?;
-
-            } else {
-                log::warn!("Invalid username or password received from {addr}.");
-                send_response(&mut write_half, ServerResponse::LoginFailed).await?;
-
-                return Err(ServerError::LoginError)?; 
-            }
-        },
-        Ok(_) => {
-            log::warn!("Login datagram not present, closing connection with {addr}.");
-            return Err(ServerError::LoginError)?;
-        }
+async fn print_login_audit(db_file: &str, attachment_dir: &Path, username: Option<&str>, limit: u32) -> EmptyResult {
+    let db = ServerDatabase::new(db_file, attachment_dir).await?;
+    for record in db.login_audit(username, limit).await? {
+        let time = record.timestamp.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S");
+        let address = record.address.unwrap_or_else(|| t!("unix-socket"));
+        println!("{time} {}", t!("login-attempt", username = record.username, address = address, outcome = record.outcome));
     }
-    
-    // We have authenticated the user
-    context.add_client(addr, &verified_username, write_half).await;
-    log::info!("User {verified_username} successfully authenticated.");
+    Ok(())
+}
 
-    // Read incoming datagrams in a loop
+/// Prints the most recent events of the audit log, oldest first.
+///
+/// # Arguments
+///
+/// * `db_file` - The path to the SQLite database file.
+/// * `attachment_dir` - The directory where attachments are stored.
+/// * `limit` - The maximum number of events to print.
+/// * `tail` - Whether to keep printing new events as they are recorded, until interrupted.
+///
+/// # Returns
+///
+/// * `EmptyResult` - Returns an empty result if successful.
+async fn print_audit_log(db_file: &str, attachment_dir: &Path, limit: u32, tail: bool) -> EmptyResult {
+    let db = ServerDatabase::new(db_file, attachment_dir).await?;
+    let mut last_id = None;
     loop {
-        match Datagram::read_from_stream(&mut read_half).await {
-            Ok(Datagram::Message(message)) => { 
-                context.verify_message_sender(&verified_username, &message)?;
-                try_join!(
-                    context.store_message(&message),
-                    context.broadcast_message(addr, &message)
-                )?;
-            }
-            Ok(_) => {
-                log::warn!("Received an unexpected datagram from {addr}."); 
-            },
-            Err(chat::ChatProtocolError::IOError) => { 
-                context.remove_client(addr).await;
-                Err(ServerError::BrokenStream)?
-            },
-            Err(chat::ChatProtocolError::MalformedMessage) => { 
-                log::warn!("Received a malformed datagram from {addr}."); 
-            }
+        for record in db.audit_log(last_id, limit).await? {
+            println!("{}", server_audit::format_record(&record));
+            last_id = Some(record.id);
         }
+        if !tail {
+            return Ok(());
+        }
+        // Once the log was printed, only events recorded later are
+        last_id = last_id.or(Some(0));
+        tokio::time::sleep(Duration::from_secs(1)).await;
     }
 }
 
-/// Handles incoming connection errors and passes control to `receive_datagrams`.
+/// Writes the stored messages to a file, oldest first.
 ///
 /// # Arguments
 ///
-/// * `context` - The server context.
-/// * `client_info` - The result containing the TCP stream and socket address of the client.
+/// * `db_file` - The path to the SQLite database file.
+/// * `attachment_dir` - The directory where attachments are stored.
+/// * `format` - The format of the file.
+/// * `since` - Only messages which arrived later are exported, all if `None`.
+/// * `out` - The path of the file.
 ///
 /// # Returns
 ///
 /// * `EmptyResult` - Returns an empty result if successful.
-async fn handle_client(context: ServerContext, client_info: Result<(TcpStream, SocketAddr), std::io::Error>) -> EmptyResult {
-    log::info!("Client task started.");
-
-    let (stream, address) = client_info
-        .context("Failed to establish communication with a client.")?;
+async fn export_messages(db_file: &str, attachment_dir: &Path, format: ExportFormat, since: Option<chrono::DateTime<chrono::Utc>>, out: &Path) -> EmptyResult {
+    let db = ServerDatabase::new(db_file, attachment_dir).await?;
+    let records = db.messages(since, u32::MAX).await?;
+    let file = std::fs::File::create(out)
+        .with_context(|| t!("could-not-create", path = out.display().to_string()))?;
+    server_export::write_export(&records, format, std::io::BufWriter::new(file))
+        .with_context(|| t!("could-not-write-file", path = out.display().to_string()))?;
+    tracing::info!("{}", t!("messages-exported", count = records.len(), path = out.display().to_string()));
+    Ok(())
+}
 
-    if let Err(e) = receive_datagrams(context, stream, address).await {
-        if let Some(ServerError::BrokenStream) = e.downcast_ref::<ServerError>() {
-            log::warn!("Connection with client terminated.");
-            log::warn!("{e}");
-        } else {
-            return Err(e);
+/// Registers the users listed in a CSV file. Every invalid row is reported, and then no user is registered.
+///
+/// # Arguments
+///
+/// * `db_file` - The path to the SQLite database file.
+/// * `attachment_dir` - The directory where attachments are stored.
+/// * `file` - The path of the CSV file.
+///
+/// # Returns
+///
+/// * `EmptyResult` - Returns an empty result if all users were registered.
+async fn import_users(db_file: &str, attachment_dir: &Path, file: &Path) -> EmptyResult {
+    let text = std::fs::read_to_string(file)
+        .with_context(|| t!("could-not-read-path", path = file.display().to_string()))?;
+    let report = |errors: Vec<(usize, String)>| {
+        for (line, reason) in &errors {
+            tracing::error!("{}:{line}: {reason}", file.display());
         }
+        anyhow::anyhow!(t!("import-invalid-rows", count = errors.len()))
+    };
+    let users = server_import::parse_users(&text).map_err(report)?;
+
+    let db = ServerDatabase::new(db_file, attachment_dir).await?;
+    let rows: Vec<(String, NewPassword)> = users.iter().map(|user| (user.username.clone(), user.password.clone())).collect();
+    let errors = db.import_users(&rows).await?;
+    if !errors.is_empty() {
+        Err(report(errors.into_iter().map(|(index, reason)| (users[index].line, reason)).collect()))?
     }
-    
-    log::info!("Client task terminated.");
+    let detail = format!("imported from {}", file.display());
+    for user in &users {
+        db.log_event(AuditEvent::Register, Some(COMMAND_LINE_ACTOR), Some(&user.username), None, Some(&detail)).await?;
+    }
+    tracing::info!("{}", t!("users-imported", count = users.len(), path = file.display().to_string()));
     Ok(())
 }
 
-/// Main server function. Listens for incoming connections and spawns a new task to handle each connection.
+/// Saves the database and the attachments to an archive.
 ///
 /// # Arguments
 ///
-/// * `address` - The address to bind to.
-/// * `port` - The port to bind to.
 /// * `db_file` - The path to the SQLite database file.
+/// * `attachment_dir` - The directory where attachments are stored.
+/// * `path` - The path of the archive.
 ///
 /// # Returns
 ///
 /// * `EmptyResult` - Returns an empty result if successful.
-async fn start_server(address: &str, port: u16, db_file: &str) -> EmptyResult {
-    let listener = TcpListener::bind((address, port)).await
-        .with_context(|| format!("Could not bind {address}:{port}."))?;
-
-    let context = ServerContext::new(db_file).await?;
+async fn backup(db_file: &str, attachment_dir: &Path, path: &Path) -> EmptyResult {
+    let db = ServerDatabase::new(db_file, attachment_dir).await?;
+    let count = server_backup::create_backup(&db, attachment_dir, path).await?;
+    tracing::info!("{}", t!("backup-saved", count = count, path = path.display().to_string()));
+    Ok(())
+}
 
-    log::info!("Ok: listening for connections on {address}:{port}");
-    loop {
-        let stream = listener.accept().await;
-        let context  = context.clone();
-        tokio::spawn(async move {
-            if let Err(e) = handle_client(context, stream).await {
-                log::error!("Client error: {e}");
-            }
-        });
+/// Grants or revokes the admin role of a registered user.
+///
+/// # Arguments
+///
+/// * `db_file` - The path to the SQLite database file.
+/// * `attachment_dir` - The directory where attachments are stored.
+/// * `username` - The username of the user.
+/// * `admin` - Whether the user should have the admin role.
+///
+/// # Returns
+///
+/// * `EmptyResult` - Returns an empty result if successful.
+async fn set_admin(db_file: &str, attachment_dir: &Path, username: &str, admin: bool) -> EmptyResult {
+    let db = ServerDatabase::new(db_file, attachment_dir).await?;
+    db.set_admin(username, admin).await?;
+    let detail = if admin { "granted" } else { "revoked" };
+    db.log_event(AuditEvent::SetAdmin, Some(COMMAND_LINE_ACTOR), Some(username), None, Some(detail)).await?;
+    if admin {
+        tracing::info!("{}", t!("admin-granted", username = username));
+    } else {
+        tracing::info!("{}", t!("admin-revoked", username = username));
     }
+    Ok(())
 }
 
-/// Registers a new user in the database.
+/// Enables or disables two-factor authentication of a registered user. Enabling it generates a new secret,
+/// replacing any previous one, and prints the provisioning URI to be imported into an authenticator app.
 ///
 /// # Arguments
 ///
 /// * `db_file` - The path to the SQLite database file.
-/// * `username` - The username to register.
-/// * `password` - The password to register.
+/// * `attachment_dir` - The directory where attachments are stored.
+/// * `username` - The username of the user.
+/// * `enable` - Whether the user should be asked for a TOTP code at login.
 ///
 /// # Returns
 ///
 /// * `EmptyResult` - Returns an empty result if successful.
-async fn register_user(db_file: &str, username: &str, password: &str) -> EmptyResult {
-    let mut db = ServerDatabase::new(db_file).await?;
-    db.register_user(username, password).await?;
-    log::info!("User {username} registered successfully.");
+async fn set_two_factor(db_file: &str, attachment_dir: &Path, username: &str, enable: bool) -> EmptyResult {
+    let db = ServerDatabase::new(db_file, attachment_dir).await?;
+    if enable {
+        let secret = server_totp::new_secret();
+        let uri = server_totp::provisioning_uri(&secret, username)?;
+        db.set_totp_secret(username, Some(&secret)).await?;
+        tracing::info!("{}", t!("two-factor-enabled", username = username));
+        println!("{uri}");
+    } else {
+        db.set_totp_secret(username, None).await?;
+        tracing::info!("{}", t!("two-factor-disabled", username = username));
+    }
+    let detail = if enable { "enabled" } else { "disabled" };
+    db.log_event(AuditEvent::TwoFactor, Some(COMMAND_LINE_ACTOR), Some(username), None, Some(detail)).await?;
     Ok(())
 }
 
+/// Installs the global tracing subscriber. Records of the `log` crate, e.g. from sqlx, are forwarded to it too.
+///
+/// # Arguments
+///
+/// * `format` - The output format of the log.
+/// * `level` - The most verbose level which is printed.
+///
+/// # Returns
+///
+/// * `LogHandle` - Returns the handle changing the level when the configuration is reloaded.
+fn init_logging(format: LogFormat, level: LevelFilter) -> LogHandle {
+    let (filter, handle) = tracing_subscriber::reload::Layer::new(level);
+    let layer = tracing_subscriber::fmt::layer().with_ansi(std::io::stdout().is_terminal());
+    let registry = tracing_subscriber::registry().with(filter);
+    match format {
+        LogFormat::Pretty => registry.with(layer).init(),
+        LogFormat::Json => registry.with(layer.json()).init(),
+    }
+    handle
+}
+
+/// Builds the server configuration from the flags of the `run` command and the configuration file.
+/// Flags given on the command line override the values from the file.
+///
+/// # Arguments
+///
+/// * `run` - The flags of the `run` command.
+/// * `file` - The configuration file, empty if none is used.
+/// * `attachment_dir` - The directory where attachments are stored.
+///
+/// # Returns
+///
+/// * `Result<ServerConfig>` - Returns the configuration, or an error if it's invalid.
+fn build_config(run: &RunArgs, file: &FileConfig, attachment_dir: PathBuf) -> Result<ServerConfig> {
+    let max_message_size = run.max_message_size.or(file.max_message_size).unwrap_or(chat::DEFAULT_MAX_FRAME_SIZE);
+    let codec = run.codec.or(file.codec).unwrap_or_default();
+    let idle_timeout = run.idle_timeout.or(file.idle_timeout).unwrap_or(DEFAULT_IDLE_TIMEOUT);
+    let write_timeout = run.write_timeout.or(file.write_timeout).unwrap_or(chat::DEFAULT_WRITE_TIMEOUT.as_secs());
+    let retention_days = run.retention_days.or(file.retention_days).unwrap_or(0);
+    let max_clients = run.max_clients.or(file.max_clients).unwrap_or(DEFAULT_MAX_CLIENTS);
+    let max_clients_per_ip = run.max_clients_per_ip.or(file.max_clients_per_ip).unwrap_or(0);
+    let evict_idle_after = run.evict_idle_after.or(file.evict_idle_after).unwrap_or(0);
+    let flood_max_messages = run.flood_max_messages.or(file.flood_max_messages).unwrap_or(server_flood::DEFAULT_FLOOD_MAX_MESSAGES);
+    let flood_window = run.flood_window.or(file.flood_window).unwrap_or(server_flood::DEFAULT_FLOOD_WINDOW);
+    let flood_duplicate_ratio = run.flood_duplicate_ratio.or(file.flood_duplicate_ratio).unwrap_or(server_flood::DEFAULT_FLOOD_DUPLICATE_RATIO);
+    let mute_duration = run.mute_duration.or(file.mute_duration).unwrap_or(server_flood::DEFAULT_MUTE_DURATION);
+    let lockout_attempts = run.lockout_attempts.or(file.lockout_attempts).unwrap_or(server_lockout::DEFAULT_LOCKOUT_ATTEMPTS);
+    let lockout_duration = run.lockout_duration.or(file.lockout_duration).unwrap_or(server_lockout::DEFAULT_LOCKOUT_DURATION);
+    let max_attachment_size = run.max_attachment_size.or(file.max_attachment_size).unwrap_or(server_limits::DEFAULT_MAX_ATTACHMENT_SIZE);
+    let check_mime = run.check_mime || file.check_mime.unwrap_or(false);
+    let storage_quota = run.storage_quota.or(file.storage_quota).unwrap_or(0);
+    let api_address = run.api_address.clone().or(file.api_address.clone());
+    let admin_address = run.admin_address.clone().or(file.admin_address.clone());
+    let admin_socket = run.admin_socket.clone().or(file.admin_socket.clone());
+    let compression = !run.no_compression && file.compression.unwrap_or(true);
+    let send_queue_size = run.send_queue_size.or(file.send_queue_size).unwrap_or(server_queue::DEFAULT_SEND_QUEUE_SIZE);
+    let send_queue_policy = run.send_queue_policy.or(file.send_queue_policy).unwrap_or_default();
+    let duplicate_login = run.duplicate_login.or(file.duplicate_login).unwrap_or_default();
+    let allow_guests = run.allow_guests || file.allow_guests.unwrap_or(false);
+    let allow_registration = run.allow_registration || file.allow_registration.unwrap_or(false);
+    let registration_webhook = run.registration_webhook.clone().or(file.registration_webhook.clone());
+    let server_id = run.server_id.clone().or(file.server_id.clone()).unwrap_or_else(server_federation::random_server_id);
+    let peers = if run.peers.is_empty() { file.peer.clone().unwrap_or_default() } else { run.peers.clone() };
+    let peer_secret = run.peer_secret.clone().or(file.peer_secret.clone());
+    if !peers.is_empty() && peer_secret.is_none() {
+        return Err(anyhow::anyhow!("Linking to other servers requires a peer secret."));
+    }
+    let link_previews = run.link_previews || file.link_previews.unwrap_or(false);
+    let link_preview_timeout = run.link_preview_timeout.or(file.link_preview_timeout).unwrap_or(server_preview::DEFAULT_LINK_PREVIEW_TIMEOUT);
+    let link_preview_private_hosts = run.link_preview_private_hosts || file.link_preview_private_hosts.unwrap_or(false);
+    let content_filter = match run.content_filter.as_ref().or(file.content_filter.as_ref()) {
+        Some(path) => Some(ContentFilter::load(path)?),
+        None => None,
+    };
+    let htpasswd_file = match run.auth_backend.or(file.auth_backend).unwrap_or_default() {
+        AuthBackend::Database => None,
+        AuthBackend::Htpasswd => Some(run.htpasswd_file.clone().or(file.htpasswd_file.clone())
+            .ok_or_else(|| anyhow::anyhow!("The htpasswd backend requires an htpasswd file."))?),
+    };
+    if htpasswd_file.is_some() && allow_registration {
+        return Err(anyhow::anyhow!("Registration requires the database backend, htpasswd users are added to the file."));
+    }
+
+    let idle_timeout = (idle_timeout > 0).then(|| Duration::from_secs(idle_timeout));
+    let retention = (retention_days > 0).then(|| Duration::from_secs(retention_days * 24 * 60 * 60));
+    Ok(ServerConfig {
+        max_message_size,
+        codec,
+        idle_timeout,
+        write_timeout: (write_timeout > 0).then(|| Duration::from_secs(write_timeout)),
+        attachment_dir,
+        retention,
+        max_clients: (max_clients > 0).then_some(max_clients),
+        max_clients_per_ip: (max_clients_per_ip > 0).then_some(max_clients_per_ip),
+        evict_idle_after: (evict_idle_after > 0).then(|| Duration::from_secs(evict_idle_after)),
+        flood: FloodConfig {
+            max_messages: (flood_max_messages > 0).then_some(flood_max_messages),
+            window: Duration::from_secs(flood_window),
+            duplicate_ratio: (flood_duplicate_ratio > 0.0).then_some(flood_duplicate_ratio),
+            mute_duration: Duration::from_secs(mute_duration),
+        },
+        lockout: LockoutConfig {
+            attempts: (lockout_attempts > 0).then_some(lockout_attempts),
+            duration: Duration::from_secs(lockout_duration),
+        },
+        attachments: AttachmentLimits {
+            max_size: (max_attachment_size > 0).then_some(max_attachment_size),
+            check_mime,
+            quotas: StorageQuotas {
+                default: (storage_quota > 0).then_some(storage_quota),
+                users: file.user_storage_quotas.clone().unwrap_or_default(),
+            },
+        },
+        api_address,
+        admin_address,
+        admin_socket,
+        content_filter,
+        compression,
+        send_queue_size,
+        send_queue_policy,
+        duplicate_login,
+        allow_guests,
+        registration: allow_registration.then_some(RegistrationConfig { webhook: registration_webhook }),
+        server_id,
+        peers,
+        peer_secret,
+        link_previews: link_previews.then(|| PreviewConfig {
+            timeout: Duration::from_secs(link_preview_timeout),
+            private_hosts: link_preview_private_hosts,
+        }),
+        htpasswd_file,
+    })
+}
+
 /// Simple chat server
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 struct Args {
-    /// SQLite database file
-    #[arg(short, long, default_value = "server.db")]
-    db_file: String,
+    /// TOML configuration file, flags given on the command line override its values
+    #[arg(short, long, global = true)]
+    config: Option<PathBuf>,
+    /// where the data is kept, `memory` runs a throwaway server which forgets everything when it stops [default: file]
+    #[arg(long, value_enum)]
+    db: Option<Storage>,
+    /// SQLite database file [default: server.db]
+    #[arg(short, long)]
+    db_file: Option<String>,
+    /// directory where image and file attachments are stored [default: attachments]
+    #[arg(long)]
+    attachment_dir: Option<PathBuf>,
+    /// format of the log output [default: pretty]
+    #[arg(long, global = true, value_enum)]
+    log_format: Option<LogFormat>,
+    /// most verbose log level: error, warn, info, debug or trace [default: info]
+    #[arg(long, global = true)]
+    log_level: Option<LevelFilter>,
+    /// language of the output of the commands: en or cs, the log of a running server stays in English [default: from LANG]
+    #[arg(long, global = true)]
+    lang: Option<Lang>,
     #[command(subcommand)]
     command: Commands
 }
 
+/// Flags of the `run` command, kept to rebuild the configuration when it is reloaded.
+#[derive(clap::Args, Clone)]
+struct RunArgs {
+    /// address to bind, may be repeated to listen on several addresses, e.g. -a 0.0.0.0 -a :: [default: 127.0.0.1]
+    #[arg(short, long)]
+    address: Vec<String>,
+    /// port to bind [default: 11111]
+    #[arg(short, long)]
+    port: Option<u16>,
+    /// listen on a Unix socket at this path instead of the address and port
+    #[arg(long)]
+    unix_socket: Option<PathBuf>,
+    /// maximum size of a single datagram in bytes, larger frames close the connection [default: 1048576]
+    #[arg(long)]
+    max_message_size: Option<usize>,
+    /// wire format of datagrams: cbor, json or msgpack, clients must use the same one [default: cbor]
+    #[arg(long)]
+    codec: Option<CodecKind>,
+    /// seconds of inactivity after which a client is disconnected, 0 disables the timeout [default: 60]
+    #[arg(long)]
+    idle_timeout: Option<u64>,
+    /// seconds a client or linked server may take to accept a datagram before it's disconnected, 0 waits forever [default: 30]
+    #[arg(long)]
+    write_timeout: Option<u64>,
+    /// delete messages older than this many days, 0 keeps them forever [default: 0]
+    #[arg(long)]
+    retention_days: Option<u64>,
+    /// maximum number of open connections, 0 for no limit [default: 1000]
+    #[arg(long)]
+    max_clients: Option<usize>,
+    /// maximum number of open connections from a single IP address, 0 for no limit [default: 0]
+    #[arg(long)]
+    max_clients_per_ip: Option<usize>,
+    /// when the server is full, disconnect the longest idle client if it sent nothing for this many seconds, 0 never disconnects [default: 0]
+    #[arg(long)]
+    evict_idle_after: Option<u64>,
+    /// maximum number of messages a user may send within the flood window, 0 for no limit [default: 10]
+    #[arg(long)]
+    flood_max_messages: Option<usize>,
+    /// length of the flood window in seconds [default: 10]
+    #[arg(long)]
+    flood_window: Option<u64>,
+    /// maximum share of repeated messages within the flood window, 0 for no limit [default: 0.5]
+    #[arg(long)]
+    flood_duplicate_ratio: Option<f64>,
+    /// seconds for which users exceeding the flood limits are muted [default: 60]
+    #[arg(long)]
+    mute_duration: Option<u64>,
+    /// number of failed logins of a user or from an IP address after which logins are refused for a while, 0 never refuses them [default: 5]
+    #[arg(long)]
+    lockout_attempts: Option<u32>,
+    /// seconds for which logins are refused after too many failures, failures older than this are forgotten [default: 300]
+    #[arg(long)]
+    lockout_duration: Option<u64>,
+    /// maximum size of an image or file attachment in bytes, 0 for no limit [default: 104857600]
+    #[arg(long)]
+    max_attachment_size: Option<u64>,
+    /// reject images which aren't PNG, JPEG or WebP and files whose extension doesn't match their content
+    #[arg(long)]
+    check_mime: bool,
+    /// maximum bytes of stored attachments per user, 0 for no limit [default: 0]
+    #[arg(long)]
+    storage_quota: Option<u64>,
+    /// address and port of the HTTP API, e.g. 127.0.0.1:8080, the API is disabled if not set
+    #[arg(long)]
+    api_address: Option<String>,
+    /// loopback address and port of the admin console, e.g. 127.0.0.1:9999
+    #[arg(long)]
+    admin_address: Option<String>,
+    /// Unix socket of the admin console, accessible only to the user running the server
+    #[arg(long)]
+    admin_socket: Option<PathBuf>,
+    /// TOML file with the rules of the content filter, the filter is disabled if not set
+    #[arg(long)]
+    content_filter: Option<PathBuf>,
+    /// never compress frames, even for clients offering it
+    #[arg(long)]
+    no_compression: bool,
+    /// maximum number of datagrams waiting to be written to a single client [default: 256]
+    #[arg(long)]
+    send_queue_size: Option<usize>,
+    /// what happens when a client's send queue is full [default: disconnect]
+    #[arg(long, value_enum)]
+    send_queue_policy: Option<QueuePolicy>,
+    /// what happens when a user logs in from a second connection [default: allow-multi]
+    #[arg(long, value_enum)]
+    duplicate_login: Option<DuplicateLogin>,
+    /// let clients log in as guests without an account, guests can't send attachments and may send fewer messages
+    #[arg(long)]
+    allow_guests: bool,
+    /// let clients register new users, confirmed with a verification code from the log or the registration webhook
+    #[arg(long)]
+    allow_registration: bool,
+    /// where passwords are checked, `htpasswd` checks them against the file given by --htpasswd-file [default: database]
+    #[arg(long, value_enum)]
+    auth_backend: Option<AuthBackend>,
+    /// htpasswd file with bcrypt hashes, as written by `htpasswd -B`, read again when it changes
+    #[arg(long)]
+    htpasswd_file: Option<PathBuf>,
+    /// port on which clients connect with TLS, bots may log in there with a client certificate, TLS is off if not set
+    #[arg(long)]
+    tls_port: Option<u16>,
+    /// PEM file with the certificate of the server, followed by any intermediate certificates
+    #[arg(long)]
+    tls_cert: Option<PathBuf>,
+    /// PEM file with the private key of the server
+    #[arg(long)]
+    tls_key: Option<PathBuf>,
+    /// URL the verification codes of registrations are posted to as JSON, instead of printing them to the log
+    #[arg(long)]
+    registration_webhook: Option<String>,
+    /// ID of this server among linked servers, shown after the names of its users [default: random]
+    #[arg(long)]
+    server_id: Option<String>,
+    /// address and port of another server to link to and relay messages with, may be repeated
+    #[arg(long = "peer")]
+    peers: Vec<String>,
+    /// secret shared by linked servers, links from other servers are refused if not set
+    #[arg(long)]
+    peer_secret: Option<String>,
+    /// fetch the title and description of links in public messages and send them to everyone as previews
+    #[arg(long)]
+    link_previews: bool,
+    /// seconds a linked page may take to load before its preview is given up [default: 5]
+    #[arg(long)]
+    link_preview_timeout: Option<u64>,
+    /// also preview links to private addresses like 192.168.1.1 or localhost, which are refused by default
+    #[arg(long)]
+    link_preview_private_hosts: bool,
+}
+
+// The commands are parsed only once at startup, so the size of `Run` doesn't matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand)]
 enum Commands {
     #[command(arg_required_else_help = false)]
-    Run {
-        /// address to bind
-        #[arg(short, long, default_value = "127.0.0.1")]
-        address: String,
-        /// port to bind
-        #[arg(short, long, default_value_t = 11111)]
-        port: u16,
-    },
+    Run(RunArgs),
     #[command(arg_required_else_help = true)]
     Register {
         /// username to register
@@ -362,26 +3511,383 @@ enum Commands {
         username: String,
         /// password to register
         #[arg(short, long)]
-        password: String
-    }
+        password: String,
+        /// grant the admin role to the user
+        #[arg(long)]
+        admin: bool,
+    },
+    #[command(arg_required_else_help = true)]
+    SetAdmin {
+        /// username of the user
+        #[arg(short, long)]
+        username: String,
+        /// revoke the admin role instead of granting it
+        #[arg(long)]
+        revoke: bool,
+    },
+    #[command(arg_required_else_help = true)]
+    ChangePassword {
+        /// username of the user
+        #[arg(short, long)]
+        username: String,
+        /// new password
+        #[arg(short, long)]
+        password: String,
+    },
+    #[command(arg_required_else_help = true)]
+    DeleteUser {
+        /// username of the user to delete
+        #[arg(short, long)]
+        username: String,
+    },
+    FilterLog {
+        /// number of most recent filtered messages to show
+        #[arg(short, long, default_value_t = 50)]
+        limit: u32,
+    },
+    /// require a TOTP code from an authenticator app at login and print the URI to set the app up
+    #[command(name = "enable-2fa", arg_required_else_help = true)]
+    Enable2fa {
+        /// username of the user
+        #[arg(short, long)]
+        username: String,
+    },
+    /// stop requiring a TOTP code at login, e.g. after the user lost their device
+    #[command(name = "disable-2fa", arg_required_else_help = true)]
+    Disable2fa {
+        /// username of the user
+        #[arg(short, long)]
+        username: String,
+    },
+    /// show the most recent login attempts, successful or not
+    ShowAudit {
+        /// show only the attempts to log in as this user
+        #[arg(short, long)]
+        username: Option<String>,
+        /// number of most recent login attempts to show
+        #[arg(short, long, default_value_t = 50)]
+        limit: u32,
+    },
+    /// let a user, usually a bot, log in with a client certificate on the TLS port instead of a password
+    #[command(arg_required_else_help = true)]
+    AddCertificate {
+        /// username of the user
+        #[arg(short, long)]
+        username: String,
+        /// PEM file with the certificate of the client
+        #[arg(long, required_unless_present = "fingerprint", conflicts_with = "fingerprint")]
+        cert: Option<PathBuf>,
+        /// SHA-256 fingerprint of the certificate, as printed by `openssl x509 -noout -fingerprint -sha256`
+        #[arg(long)]
+        fingerprint: Option<String>,
+    },
+    /// stop accepting a client certificate
+    #[command(arg_required_else_help = true)]
+    RemoveCertificate {
+        /// SHA-256 fingerprint of the certificate
+        #[arg(long)]
+        fingerprint: String,
+    },
+    /// show the registered client certificates and their users
+    ListCertificates,
+    /// show the attachment storage used by every user and their quota
+    StorageReport {
+        /// maximum bytes of stored attachments per user, 0 for no limit [default: the storage_quota of the configuration file]
+        #[arg(long)]
+        storage_quota: Option<u64>,
+    },
+    /// show the audit log of logins, kicks, bans, reloads, registrations and other security events
+    Audit {
+        /// number of most recent events to show
+        #[arg(short, long, default_value_t = 50)]
+        limit: u32,
+        /// keep showing new events as they are recorded, until interrupted
+        #[arg(long)]
+        tail: bool,
+    },
+    /// write the message history to a file, attachments are referenced by their name and size
+    #[command(arg_required_else_help = true)]
+    Export {
+        /// format of the file
+        #[arg(short, long, value_enum, default_value_t = ExportFormat::Json)]
+        format: ExportFormat,
+        /// export only messages which arrived after this date (2024-05-01, midnight UTC) or time (2024-05-01T12:00:00Z)
+        #[arg(short, long, value_parser = server_export::parse_since)]
+        since: Option<chrono::DateTime<chrono::Utc>>,
+        /// file the history is written to
+        #[arg(short, long)]
+        out: PathBuf,
+    },
+    /// register all users from a CSV file with the columns username,password, or none if any row is invalid
+    #[command(arg_required_else_help = true)]
+    ImportUsers {
+        /// CSV file with a user per line, the password may be an Argon2 hash in the PHC string format
+        file: PathBuf,
+    },
+    /// save the database and the attachments to a .tar.gz archive, also while the server is running
+    #[command(arg_required_else_help = true)]
+    Backup {
+        /// path of the archive
+        path: PathBuf,
+    },
+    /// replace the database with a backup and add its attachments, the server must be stopped
+    #[command(arg_required_else_help = true)]
+    Restore {
+        /// path of the archive made by the backup command
+        path: PathBuf,
+    },
+    /// start a server on a temporary database with simulated clients and measure the throughput and latency of broadcasts
+    Bench(BenchArgs),
+    /// start a throwaway server with the demo users alice and bob and print the commands starting their clients
+    Demo(DemoArgs),
+    /// print a chat:// link with the address and port of the server, which the client accepts instead of the connection flags
+    Invite {
+        /// host name or address the clients connect to [default: the address the server binds]
+        #[arg(long)]
+        host: Option<String>,
+        /// port the clients connect to [default: the port the server binds]
+        #[arg(short, long)]
+        port: Option<u16>,
+        /// name of the server shown to the invited user
+        #[arg(short, long)]
+        name: Option<String>,
+        /// username the invited user logs in with
+        #[arg(short, long)]
+        username: Option<String>,
+        /// also print the link as a QR code, to scan it with a phone
+        #[arg(long)]
+        qr: bool,
+    },
 }
 
 #[tokio::main]
 async fn main() {
-    simple_logger::init().unwrap();
     let args = Args::parse();
+    chat::i18n::set_lang(args.lang.unwrap_or_else(Lang::from_env));
+    let file = match &args.config {
+        Some(path) => FileConfig::load(path).unwrap_or_else(|e| {
+            eprintln!("{}", t!("error", error = format!("{e:#}")));
+            exit(1);
+        }),
+        None => FileConfig::default(),
+    };
+
+    // Command line flags override the values from the configuration file
+    let log_handle = init_logging(
+        args.log_format.or(file.log_format).unwrap_or(LogFormat::Pretty),
+        // The connections of the simulated clients would drown the results of the benchmark,
+        // and the upgrades of the fresh database the client commands of the demo
+        args.log_level.or(file.log_level).unwrap_or(if matches!(args.command, Commands::Bench(_) | Commands::Demo(_)) { LevelFilter::ERROR } else { LevelFilter::INFO }),
+    );
+    let db_file = match args.db.or(file.db).unwrap_or(Storage::File) {
+        Storage::File => args.db_file.or(file.db_file.clone()).unwrap_or_else(|| DEFAULT_DB_FILE.to_string()),
+        Storage::Memory if matches!(args.command, Commands::Run(_)) => MEMORY_DATABASE.to_string(),
+        Storage::Memory => {
+            tracing::error!("{}", t!("memory-database-run-only"));
+            exit(1);
+        }
+    };
+    let attachment_dir = args.attachment_dir.or(file.attachment_dir.clone()).unwrap_or_else(|| PathBuf::from(DEFAULT_ATTACHMENT_DIR));
+
     match args.command {
-        Commands::Run { address, port } => {
-            if let Err(e) = start_server(&address, port, &args.db_file).await {
-                log::error!("{e}");
+        Commands::Run(run) => {
+            let addresses = match (run.address.is_empty(), &file.address) {
+                (false, _) => run.address.clone(),
+                (true, Some(addresses)) if !addresses.is_empty() => addresses.clone(),
+                _ => vec![DEFAULT_ADDRESS.to_string()],
+            };
+            let port = run.port.or(file.port).unwrap_or(DEFAULT_PORT);
+            let unix_socket = run.unix_socket.clone().or(file.unix_socket.clone());
+            let config = match build_config(&run, &file, attachment_dir.clone()) {
+                Ok(config) => config,
+                Err(e) => {
+                    tracing::error!("{e:#}");
+                    exit(1);
+                }
+            };
+            let tls_listeners = match bind_tls_listeners(&addresses, &run, &file).await {
+                Ok(tls_listeners) => tls_listeners,
+                Err(e) => {
+                    tracing::error!("{e:#}");
+                    exit(1);
+                }
+            };
+            let reloader = Reloader::new(args.config, run, attachment_dir, args.log_level, log_handle);
+            let listeners = match bind_listeners(&addresses, port, unix_socket.as_deref()).await {
+                Ok(listeners) => listeners,
+                Err(e) => {
+                    tracing::error!("{e:#}");
+                    exit(1);
+                }
+            };
+            let listeners = listeners.into_iter().chain(tls_listeners).collect();
+            if let Err(e) = start_server(listeners, &db_file, config, Some(reloader), std::future::pending()).await {
+                tracing::error!("{e}");
+                exit(1);
+            }
+        },
+        Commands::Register { username, password, admin } => {
+            if let Err(e) = register_user(&db_file, &attachment_dir, &username, &password, admin).await {
+                tracing::error!("{e}");
+                exit(1);
+            }
+        },
+        Commands::SetAdmin { username, revoke } => {
+            if let Err(e) = set_admin(&db_file, &attachment_dir, &username, !revoke).await {
+                tracing::error!("{e}");
+                exit(1);
+            }
+        },
+        Commands::ChangePassword { username, password } => {
+            if let Err(e) = change_password(&db_file, &attachment_dir, &username, &password).await {
+                tracing::error!("{e}");
+                exit(1);
+            }
+        },
+        Commands::DeleteUser { username } => {
+            if let Err(e) = delete_user(&db_file, &attachment_dir, &username).await {
+                tracing::error!("{e}");
                 exit(1);
             }
         },
-        Commands::Register { username, password } => {
-            if let Err(e) = register_user(&args.db_file, &username, &password).await {
-                log::error!("{e}");
+        Commands::FilterLog { limit } => {
+            if let Err(e) = print_filter_log(&db_file, &attachment_dir, limit).await {
+                tracing::error!("{e}");
                 exit(1);
             }
+        },
+        Commands::Enable2fa { username } => {
+            if let Err(e) = set_two_factor(&db_file, &attachment_dir, &username, true).await {
+                tracing::error!("{e}");
+                exit(1);
+            }
+        },
+        Commands::Disable2fa { username } => {
+            if let Err(e) = set_two_factor(&db_file, &attachment_dir, &username, false).await {
+                tracing::error!("{e}");
+                exit(1);
+            }
+        },
+        Commands::ShowAudit { username, limit } => {
+            if let Err(e) = print_login_audit(&db_file, &attachment_dir, username.as_deref(), limit).await {
+                tracing::error!("{e}");
+                exit(1);
+            }
+        },
+        Commands::AddCertificate { username, cert, fingerprint } => {
+            let fingerprint = match (cert, fingerprint) {
+                (Some(path), _) => server_tls::certificate_fingerprint(&path),
+                (None, fingerprint) => server_tls::parse_fingerprint(&fingerprint.unwrap_or_default()),
+            };
+            let added = match fingerprint {
+                Ok(fingerprint) => add_certificate(&db_file, &attachment_dir, &username, &fingerprint).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = added {
+                tracing::error!("{e:#}");
+                exit(1);
+            }
+        },
+        Commands::RemoveCertificate { fingerprint } => {
+            let removed = match server_tls::parse_fingerprint(&fingerprint) {
+                Ok(fingerprint) => remove_certificate(&db_file, &attachment_dir, &fingerprint).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = removed {
+                tracing::error!("{e:#}");
+                exit(1);
+            }
+        },
+        Commands::ListCertificates => {
+            if let Err(e) = print_certificates(&db_file, &attachment_dir).await {
+                tracing::error!("{e}");
+                exit(1);
+            }
+        },
+        Commands::StorageReport { storage_quota } => {
+            let storage_quota = storage_quota.or(file.storage_quota).unwrap_or(0);
+            let quotas = StorageQuotas {
+                default: (storage_quota > 0).then_some(storage_quota),
+                users: file.user_storage_quotas.clone().unwrap_or_default(),
+            };
+            if let Err(e) = print_storage_report(&db_file, &attachment_dir, &quotas).await {
+                tracing::error!("{e:#}");
+                exit(1);
+            }
+        },
+        Commands::Audit { limit, tail } => {
+            if let Err(e) = print_audit_log(&db_file, &attachment_dir, limit, tail).await {
+                tracing::error!("{e}");
+                exit(1);
+            }
+        },
+        Commands::Export { format, since, out } => {
+            if let Err(e) = export_messages(&db_file, &attachment_dir, format, since, &out).await {
+                tracing::error!("{e:#}");
+                exit(1);
+            }
+        },
+        Commands::ImportUsers { file } => {
+            if let Err(e) = import_users(&db_file, &attachment_dir, &file).await {
+                tracing::error!("{e:#}");
+                exit(1);
+            }
+        },
+        Commands::Backup { path } => {
+            if let Err(e) = backup(&db_file, &attachment_dir, &path).await {
+                tracing::error!("{e:#}");
+                exit(1);
+            }
+        },
+        Commands::Restore { path } => {
+            match server_backup::restore_backup(&path, Path::new(&db_file), &attachment_dir).await {
+                Ok(count) => tracing::info!("Restored the database and {count} attachments from {}, the previous database was kept as {db_file}.before-restore.", path.display()),
+                Err(e) => {
+                    tracing::error!("{e:#}");
+                    exit(1);
+                }
+            }
+        },
+        Commands::Bench(bench) => {
+            if let Err(e) = server_bench::print_bench(&bench).await {
+                tracing::error!("{e:#}");
+                exit(1);
+            }
+        },
+        Commands::Demo(demo) => {
+            let listener = match Listener::bind_tcp(&demo.address, demo.port, false).await {
+                Ok(listener) => listener,
+                Err(e) => {
+                    tracing::error!("{e:#}");
+                    exit(1);
+                }
+            };
+            let shutdown = async {
+                let _ = tokio::signal::ctrl_c().await;
+            };
+            if let Err(e) = server_demo::run_demo(listener, shutdown).await {
+                tracing::error!("{e:#}");
+                exit(1);
+            }
+        },
+        Commands::Invite { host, port, name, username, qr } => {
+            let invite = match server_invite::build_invite(host, port, name, username, &file) {
+                Ok(invite) => invite.to_string(),
+                Err(e) => {
+                    tracing::error!("{e:#}");
+                    exit(1);
+                }
+            };
+            if qr {
+                match server_invite::render_qr(&invite) {
+                    Ok(code) => println!("{code}"),
+                    Err(e) => {
+                        tracing::error!("{e:#}");
+                        exit(1);
+                    }
+                }
+            }
+            println!("{invite}");
         }
     }
 }
@@ -390,25 +3896,137 @@ async fn main() {
 #[cfg(test)]
 mod tests {
     use chat::*;
-    use tempfile;
 
-    use crate::ServerContext;
+    use std::net::IpAddr;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::time::Instant;
+
+    use crate::server_transport::PeerAddr;
+    use crate::server_db::StorageUsage;
+    use crate::server_limits::StorageQuotas;
+    use crate::{storage_report, validate_avatar, validate_nickname, validate_status, ServerConfig, ServerContext};
 
     #[tokio::test]
     async fn test_verify_message_sender() {
-        let dbfile = tempfile::tempdir().unwrap().into_path().join("test.db");
+        let dbfile = tempfile::tempdir().unwrap().keep().join("test.db");
         let dbfile = dbfile.as_os_str().to_str().unwrap();
 
-        let context = ServerContext::new(dbfile).await;
+        let context = ServerContext::new(dbfile, ServerConfig::default()).await;
         assert!(context.is_ok());
         let context = context.unwrap();
 
         let verified_username = "Bob";
-        let message = ChatMessage{sender: "Bob".to_string(), content: ChatMessageContent::Text("test message".to_string())};
+        let message = ChatMessage::new(1, "Bob", ChatMessageContent::Text("test message".to_string()));
         assert!(context.verify_message_sender(verified_username, &message).is_ok());
 
         let verified_username = "Alice";
         assert!(context.verify_message_sender(verified_username, &message).is_err());
     }
+
+    #[tokio::test]
+    async fn test_connection_limits() {
+        let dbfile = tempfile::tempdir().unwrap().keep().join("test.db");
+        let dbfile = dbfile.as_os_str().to_str().unwrap();
+
+        let config = ServerConfig {
+            max_clients: Some(2),
+            max_clients_per_ip: Some(1),
+            evict_idle_after: Some(Duration::from_secs(60)),
+            ..ServerConfig::default()
+        };
+        let context = ServerContext::new(dbfile, config).await.unwrap();
+        let first: Option<IpAddr> = Some("10.0.0.1".parse().unwrap());
+        let second: Option<IpAddr> = Some("10.0.0.2".parse().unwrap());
+        let third: Option<IpAddr> = Some("10.0.0.3".parse().unwrap());
+
+        let slot = context.admit(first).await.unwrap();
+        assert!(context.admit(first).await.is_err());
+        // Unix socket connections count only towards the total
+        let unix_slot = context.admit(None).await.unwrap();
+        assert!(context.admit(second).await.is_err());
+        drop(unix_slot);
+        let _second_slot = context.admit(second).await.unwrap();
+        assert!(context.admit(third).await.is_err());
+
+        // A closed connection releases its place
+        drop(slot);
+        let _first_slot = context.admit(first).await.unwrap();
+
+        // An authenticated client idle for long enough is disconnected to make room
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (stream, addr) = listener.accept().await.unwrap();
+        let (_, write_half) = stream.into_split();
+        let last_active = Arc::new(Mutex::new(Instant::now()));
+        let disconnect = context.add_client(PeerAddr::Tcp(addr), "Bob", None, last_active.clone(), Box::new(write_half), SessionCodec::default()).await.unwrap().0;
+        assert!(context.admit(third).await.is_err());
+
+        *last_active.lock().unwrap() = Instant::now() - Duration::from_secs(120);
+        let _third_slot = context.admit(third).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(1), disconnect.notified()).await.unwrap();
+        // The evicted client isn't picked again while it's disconnecting
+        assert!(context.admit(Some("10.0.0.4".parse().unwrap())).await.is_err());
+    }
+
+    #[test]
+    fn test_validate_nickname() {
+        assert!(validate_nickname("Bobby").is_ok());
+        assert!(validate_nickname("Žofie").is_ok());
+        assert!(validate_nickname("").is_err());
+        assert!(validate_nickname("Bob Smith").is_err());
+        assert!(validate_nickname("Bob\x1b[31m").is_err());
+        assert!(validate_nickname(&"x".repeat(33)).is_err());
+    }
+
+    #[test]
+    fn test_validate_profile() {
+        assert!(validate_status("on vacation until Monday").is_ok());
+        assert!(validate_status("").is_ok());
+        assert!(validate_status("first\nsecond").is_err());
+        assert!(validate_status(&"x".repeat(81)).is_err());
+
+        let png = |size| {
+            let mut data = Vec::new();
+            image::RgbImage::new(size, size).write_to(&mut std::io::Cursor::new(&mut data), image::ImageFormat::Png).unwrap();
+            data
+        };
+        assert!(validate_avatar(&png(64)).is_ok());
+        assert!(validate_avatar(&[]).is_ok());
+        assert_eq!(validate_avatar(&png(200)), Err("The avatar is too large."));
+        assert_eq!(validate_avatar(b"GIF89a"), Err("The avatar must be a PNG image."));
+    }
+
+    #[test]
+    fn test_reloaded_config() {
+        let current = ServerConfig { server_id: "alpha".to_string(), codec: CodecKind::Json, ..ServerConfig::default() };
+        let new = ServerConfig { server_id: "beta".to_string(), max_clients: Some(5), compression: false, ..ServerConfig::default() };
+
+        let reloaded = current.reloaded(new);
+        // Limits change while the server runs, its identity and wire format only after a restart
+        assert_eq!(reloaded.max_clients, Some(5));
+        assert!(!reloaded.compression);
+        assert_eq!(reloaded.server_id, "alpha");
+        assert_eq!(reloaded.codec, CodecKind::Json);
+    }
+
+    #[test]
+    fn test_storage_report() {
+        let usage = [
+            StorageUsage { username: "alice".to_string(), attachments: 12, bytes: 1536 },
+            StorageUsage { username: "bob".to_string(), attachments: 0, bytes: 0 },
+        ];
+        let quotas = StorageQuotas { default: Some(2048), users: [("bob".to_string(), 0)].into() };
+        let report = storage_report(&usage, &quotas);
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines, vec![
+            "user   attachments    used      quota  of quota",
+            "alice           12  1.5 KB     2.0 KB      75 %",
+            "bob              0     0 B  unlimited",
+            "Total: 12 attachments, 1.5 KB",
+        ]);
+    }
     
 }
\ No newline at end of file
@@ -0,0 +1,270 @@
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use chat::{EmptyResult, ServerResponse};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+use crate::server_audit::AuditEvent;
+use crate::server_transport::{Listener, PeerAddr, ReadHalf, WriteHalf};
+use crate::ServerContext;
+
+/// Shown by the `help` command.
+const HELP: &str = "\
+list-clients      list the connected clients
+kick <user>       disconnect all connections of a user
+ban <user>        ban a user and disconnect them
+unban <user>      lift the ban of a user
+broadcast <text>  send an announcement to everyone
+stats             show the state of the server
+reload            reload the configuration file
+quit              close the console";
+
+/// Recorded as the issuer of bans made from the console.
+const CONSOLE_ISSUER: &str = "console";
+
+/// A command typed into the admin console.
+#[derive(Debug, PartialEq)]
+pub enum ConsoleCommand {
+    ListClients,
+    Kick(String),
+    Ban(String),
+    Unban(String),
+    Broadcast(String),
+    Stats,
+    Reload,
+    Help,
+    Quit,
+}
+
+impl FromStr for ConsoleCommand {
+    type Err = String;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let (name, argument) = match line.trim().split_once(char::is_whitespace) {
+            Some((name, argument)) => (name, argument.trim()),
+            None => (line.trim(), ""),
+        };
+        let required = |what: &str| match argument {
+            "" => Err(format!("{name} requires {what}, e.g. {name} Bob")),
+            argument => Ok(argument.to_string()),
+        };
+
+        match name {
+            "list-clients" => Ok(ConsoleCommand::ListClients),
+            "kick" => Ok(ConsoleCommand::Kick(required("a username")?)),
+            "ban" => Ok(ConsoleCommand::Ban(required("a username")?)),
+            "unban" => Ok(ConsoleCommand::Unban(required("a username")?)),
+            "broadcast" => match argument {
+                "" => Err("broadcast requires the text of the announcement".to_string()),
+                text => Ok(ConsoleCommand::Broadcast(text.to_string())),
+            },
+            "stats" => Ok(ConsoleCommand::Stats),
+            "reload" => Ok(ConsoleCommand::Reload),
+            "help" => Ok(ConsoleCommand::Help),
+            "quit" | "exit" => Ok(ConsoleCommand::Quit),
+            _ => Err(format!("unknown command {name}, type help for the list of commands")),
+        }
+    }
+}
+
+/// Binds the admin console. It has no authentication of its own, so a TCP console may listen only on
+/// a loopback address and a Unix socket is made accessible only to the user running the server.
+///
+/// # Arguments
+///
+/// * `address` - The loopback address and port of a TCP console.
+/// * `socket` - The path of the Unix socket of a console.
+///
+/// # Returns
+///
+/// * `Result<Vec<Listener>>` - Returns a listener for each given endpoint.
+pub async fn bind_console(address: Option<&str>, socket: Option<&Path>) -> Result<Vec<Listener>> {
+    let mut listeners = Vec::new();
+
+    if let Some(address) = address {
+        let addr: SocketAddr = address.parse()
+            .with_context(|| format!("Invalid admin console address {address}, expected an IP address and port."))?;
+        if !addr.ip().is_loopback() {
+            return Err(anyhow!("The admin console must listen on a loopback address like 127.0.0.1, not {address}."));
+        }
+        listeners.push(Listener::bind_tcp(&addr.ip().to_string(), addr.port(), false).await?);
+        tracing::info!("Ok: admin console listening on {address}");
+    }
+
+    if let Some(path) = socket {
+        let listener = Listener::bind_unix(path)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))
+                .with_context(|| format!("Could not restrict the permissions of {}.", path.display()))?;
+        }
+        listeners.push(listener);
+        tracing::info!("Ok: admin console listening on {}", path.display());
+    }
+
+    Ok(listeners)
+}
+
+/// Accepts operators on an admin console listener, each in its own task.
+/// Runs until the task is aborted when the server shuts down.
+///
+/// # Arguments
+///
+/// * `context` - The server context.
+/// * `listener` - The bound listener.
+pub async fn run_console(context: ServerContext, mut listener: Listener) {
+    loop {
+        match listener.accept().await {
+            Ok((read_half, write_half, addr)) => {
+                let context = context.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = console_session(context, read_half, write_half, addr).await {
+                        tracing::warn!("Admin console session {addr} failed: {e}");
+                    }
+                });
+            },
+            Err(e) => tracing::warn!("Could not accept an admin console connection: {e}"),
+        }
+    }
+}
+
+/// Reads commands, one per line, and writes their replies until the operator quits or closes the connection.
+///
+/// # Arguments
+///
+/// * `context` - The server context.
+/// * `read_half` - The readable half of the connection.
+/// * `write_half` - The writable half of the connection.
+/// * `addr` - The address of the operator.
+///
+/// # Returns
+///
+/// * `EmptyResult` - Returns an empty result if the session ended normally.
+async fn console_session(context: ServerContext, read_half: ReadHalf, mut write_half: WriteHalf, addr: PeerAddr) -> EmptyResult {
+    tracing::info!("Admin console opened from {addr}.");
+    let mut lines = BufReader::new(read_half).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let reply = match line.parse::<ConsoleCommand>() {
+            Ok(ConsoleCommand::Quit) => break,
+            Ok(command) => {
+                tracing::info!("Admin console command from {addr}: {}", line.trim());
+                execute(&context, addr, command).await?
+            },
+            Err(e) => format!("Error: {e}"),
+        };
+        write_half.write_all(format!("{reply}\n").as_bytes()).await?;
+    }
+
+    tracing::info!("Admin console closed from {addr}.");
+    Ok(())
+}
+
+/// Performs a console command.
+///
+/// # Arguments
+///
+/// * `context` - The server context.
+/// * `addr` - The address of the operator, recorded in the audit log.
+/// * `command` - The command to be performed.
+///
+/// # Returns
+///
+/// * `Result<String>` - Returns the reply shown to the operator.
+async fn execute(context: &ServerContext, addr: PeerAddr, command: ConsoleCommand) -> Result<String> {
+    Ok(match command {
+        ConsoleCommand::ListClients => {
+            let clients = context.connected_clients().await;
+            let mut reply = format!("{} connected clients", clients.len());
+            for client in clients {
+                write!(reply, "\n{:<24} {:<16} idle {}s, queue {}/{}, dropped {}", client.addr.to_string(), client.username,
+                       client.idle.as_secs(), client.queue.depth, client.queue.capacity, client.queue.dropped)?;
+            }
+            reply
+        },
+        ConsoleCommand::Kick(username) => match context.kick_user(&username, ServerResponse::Kicked).await {
+            0 => format!("Error: {username} is not online."),
+            count => {
+                tracing::info!("User {username} was kicked from the admin console.");
+                context.audit(AuditEvent::Kick, Some(CONSOLE_ISSUER), Some(&username), addr.ip(), None).await?;
+                format!("Kicked {count} connections of {username}.")
+            },
+        },
+        ConsoleCommand::Ban(username) => match context.database.ban_user(&username, CONSOLE_ISSUER).await {
+            Ok(()) => {
+                context.kick_user(&username, ServerResponse::Banned).await;
+                tracing::info!("User {username} was banned from the admin console.");
+                context.audit(AuditEvent::Ban, Some(CONSOLE_ISSUER), Some(&username), addr.ip(), None).await?;
+                format!("Banned {username}.")
+            },
+            Err(e) => format!("Error: could not ban {username}: {e}"),
+        },
+        ConsoleCommand::Unban(username) => match context.database.unban_user(&username).await? {
+            true => {
+                tracing::info!("User {username} was unbanned from the admin console.");
+                context.audit(AuditEvent::Unban, Some(CONSOLE_ISSUER), Some(&username), addr.ip(), None).await?;
+                format!("Unbanned {username}.")
+            },
+            false => format!("Error: {username} is not banned."),
+        },
+        ConsoleCommand::Broadcast(text) => {
+            let count = context.announce(&text).await;
+            format!("Sent to {count} clients.")
+        },
+        ConsoleCommand::Stats => {
+            let stats = context.stats().await;
+            format!(
+                "uptime {}\nconnections {} ({} authenticated)\nusers online {}\nlinked servers {}\nqueued datagrams {}, dropped {}",
+                format_duration(stats.uptime), stats.connections, stats.clients, stats.users, stats.peers, stats.queued, stats.dropped,
+            )
+        },
+        ConsoleCommand::Reload => match context.reload(CONSOLE_ISSUER, addr.ip()).await {
+            Ok(()) => "Reloaded the configuration.".to_string(),
+            Err(e) => format!("Error: could not reload the configuration: {e:#}"),
+        },
+        ConsoleCommand::Help => HELP.to_string(),
+        ConsoleCommand::Quit => String::new(),
+    })
+}
+
+/// Formats a duration like `2d 03:04:05`.
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    let time = format!("{:02}:{:02}:{:02}", seconds / 3600 % 24, seconds / 60 % 60, seconds % 60);
+    match seconds / 86400 {
+        0 => time,
+        days => format!("{days}d {time}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::server_admin::{format_duration, ConsoleCommand};
+
+    #[test]
+    fn test_parse_commands() {
+        assert_eq!("list-clients".parse(), Ok(ConsoleCommand::ListClients));
+        assert_eq!("  kick   Bob ".parse(), Ok(ConsoleCommand::Kick("Bob".to_string())));
+        assert_eq!("broadcast Restart in 5 minutes".parse(), Ok(ConsoleCommand::Broadcast("Restart in 5 minutes".to_string())));
+        assert_eq!("exit".parse(), Ok(ConsoleCommand::Quit));
+        assert!("kick".parse::<ConsoleCommand>().is_err());
+        assert!("broadcast ".parse::<ConsoleCommand>().is_err());
+        assert!("reboot".parse::<ConsoleCommand>().is_err());
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(Duration::from_secs(3725)), "01:02:05");
+        assert_eq!(format_duration(Duration::from_secs(2 * 86400 + 5)), "2d 00:00:05");
+    }
+}
@@ -0,0 +1,280 @@
+use std::net::{IpAddr, SocketAddr};
+
+use axum::extract::{ConnectInfo, Query, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::server_audit::AuditEvent;
+use crate::server_db::{MessageRecord, UserRecord};
+use crate::server_lockout::LoginOutcome;
+use crate::server_queue::QueueStats;
+use crate::server_registration;
+use crate::server_totp;
+use crate::ServerContext;
+
+/// Number of messages returned by `GET /api/messages` when no limit is given.
+const DEFAULT_MESSAGES_LIMIT: u32 = 100;
+
+/// Maximum number of messages returned by a single `GET /api/messages` request.
+const MAX_MESSAGES_LIMIT: u32 = 1000;
+
+/// Header carrying the TOTP code of users with two-factor authentication.
+const TOTP_HEADER: &str = "x-totp-code";
+
+/// Error returned by an API endpoint, sent to the client as `{"error": "..."}`.
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    message: String,
+}
+
+impl ApiError {
+    fn new(status: StatusCode, message: impl Into<String>) -> ApiError {
+        ApiError { status, message: message.into() }
+    }
+
+    fn unauthorized() -> ApiError {
+        ApiError::new(StatusCode::UNAUTHORIZED, "Invalid username or password.")
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(e: anyhow::Error) -> Self {
+        tracing::error!("API error: {e}");
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Internal server error.")
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = Json(serde_json::json!({ "error": self.message }));
+        if self.status == StatusCode::UNAUTHORIZED {
+            (self.status, [(header::WWW_AUTHENTICATE, "Basic realm=\"myrustchat\"")], body).into_response()
+        } else {
+            (self.status, body).into_response()
+        }
+    }
+}
+
+/// Query parameters of `GET /api/messages`.
+#[derive(Deserialize)]
+struct MessagesQuery {
+    /// Only messages which arrived after this time are returned
+    since: Option<DateTime<Utc>>,
+    limit: Option<u32>,
+}
+
+/// A registered user as returned by `GET /api/users`.
+#[derive(Serialize)]
+struct UserStatus {
+    #[serde(flatten)]
+    user: UserRecord,
+    /// Whether the user is connected right now
+    online: bool,
+}
+
+/// Send queue of a connected client as returned by `GET /api/queues`.
+#[derive(Serialize)]
+struct ClientQueue {
+    address: String,
+    username: String,
+    #[serde(flatten)]
+    stats: QueueStats,
+}
+
+/// Body of `POST /api/register`.
+#[derive(Deserialize)]
+struct RegisterRequest {
+    username: String,
+    password: String,
+    #[serde(default)]
+    admin: bool,
+}
+
+/// Builds the router of the HTTP API. Every endpoint requires HTTP Basic authentication
+/// with the credentials of a registered user who is not banned.
+///
+/// # Arguments
+///
+/// * `context` - The server context.
+///
+/// # Returns
+///
+/// * `Router` - Returns the router ready to be served.
+pub fn router(context: ServerContext) -> Router {
+    Router::new()
+        .route("/api/messages", get(get_messages))
+        .route("/api/users", get(get_users))
+        .route("/api/register", post(post_register))
+        .route("/api/queues", get(get_queues))
+        .with_state(context)
+}
+
+/// Extracts the username and password from an HTTP Basic `Authorization` header.
+///
+/// # Arguments
+///
+/// * `headers` - The headers of the request.
+///
+/// # Returns
+///
+/// * `Option<(String, String)>` - Returns the credentials, `None` if the header is missing or malformed.
+fn basic_credentials(headers: &HeaderMap) -> Option<(String, String)> {
+    let value = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, encoded) = value.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = base64::engine::general_purpose::STANDARD.decode(encoded.trim()).ok()?;
+    let (username, password) = std::str::from_utf8(&decoded).ok()?.split_once(':')?;
+    Some((username.to_string(), password.to_string()))
+}
+
+/// Authenticates the request. Users with two-factor authentication must send a current code in the `X-Totp-Code` header.
+/// Failed logins are recorded in the audit log and lock the user out like failed logins of chat clients.
+/// Successful ones aren't recorded, as dashboards may poll the API every few seconds.
+///
+/// # Arguments
+///
+/// * `context` - The server context.
+/// * `headers` - The headers of the request.
+/// * `ip` - The IP address of the client.
+///
+/// # Returns
+///
+/// * `Result<String, ApiError>` - Returns the username of the authenticated user.
+async fn authenticate(context: &ServerContext, headers: &HeaderMap, ip: IpAddr) -> Result<String, ApiError> {
+    let (username, password) = basic_credentials(headers).ok_or_else(ApiError::unauthorized)?;
+    let ip = Some(ip.to_canonical());
+    if let Some(until) = context.locked_until(&username, ip).await? {
+        context.audit_login(&username, ip, LoginOutcome::Locked).await?;
+        Err(ApiError::new(StatusCode::TOO_MANY_REQUESTS, format!("Too many failed logins, try again after {}.", until.to_rfc3339())))?
+    }
+    if !context.check_auth(&username, &password).await? {
+        tracing::warn!("API login of {username} failed.");
+        context.audit_login(&username, ip, LoginOutcome::WrongPassword).await?;
+        Err(ApiError::unauthorized())?
+    }
+    if let Some(secret) = context.database.totp_secret(&username).await? {
+        let code = headers.get(TOTP_HEADER).and_then(|value| value.to_str().ok()).unwrap_or_default();
+        if !server_totp::verify(&secret, &username, code, Utc::now().timestamp() as u64)? {
+            tracing::warn!("API login of {username} failed, invalid authentication code.");
+            context.audit_login(&username, ip, LoginOutcome::InvalidCode).await?;
+            Err(ApiError::new(StatusCode::UNAUTHORIZED, "A valid authentication code is required in the X-Totp-Code header."))?
+        }
+    }
+    if context.database.is_banned(&username).await? {
+        context.audit_login(&username, ip, LoginOutcome::Banned).await?;
+        Err(ApiError::new(StatusCode::FORBIDDEN, "You are banned from this server."))?
+    }
+    Ok(username)
+}
+
+/// `GET /api/messages?since=&limit=` returns stored messages, oldest first. Without `since`
+/// the most recent messages are returned. Attachments are described, not included.
+async fn get_messages(
+    State(context): State<ServerContext>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Query(query): Query<MessagesQuery>,
+) -> Result<Json<Vec<MessageRecord>>, ApiError> {
+    authenticate(&context, &headers, client.ip()).await?;
+    let limit = query.limit.unwrap_or(DEFAULT_MESSAGES_LIMIT).min(MAX_MESSAGES_LIMIT);
+    Ok(Json(context.database.messages(query.since, limit).await?))
+}
+
+/// `GET /api/users` returns all registered users and whether they are online.
+async fn get_users(
+    State(context): State<ServerContext>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<UserStatus>>, ApiError> {
+    authenticate(&context, &headers, client.ip()).await?;
+    let online = context.online_users().await;
+    let users = context.database.list_users().await?.into_iter()
+        .map(|user| UserStatus { online: online.contains(&user.username), user })
+        .collect();
+    Ok(Json(users))
+}
+
+/// `GET /api/queues` returns the depth of the send queue of every connection, fullest first,
+/// to spot clients which don't keep up. Only admins may use it.
+async fn get_queues(
+    State(context): State<ServerContext>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<ClientQueue>>, ApiError> {
+    let admin = authenticate(&context, &headers, client.ip()).await?;
+    if !context.database.is_admin(&admin).await? {
+        Err(ApiError::new(StatusCode::FORBIDDEN, "Only admins can see the send queues."))?
+    }
+    let mut queues: Vec<ClientQueue> = context.queue_stats().await.into_iter()
+        .map(|(addr, username, stats)| ClientQueue { address: addr.to_string(), username, stats })
+        .collect();
+    queues.sort_by_key(|queue| std::cmp::Reverse(queue.stats.depth));
+    Ok(Json(queues))
+}
+
+/// `POST /api/register` registers a new user. Only admins may use it.
+async fn post_register(
+    State(context): State<ServerContext>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(request): Json<RegisterRequest>,
+) -> Result<StatusCode, ApiError> {
+    let admin = authenticate(&context, &headers, client.ip()).await?;
+    if !context.database.is_admin(&admin).await? {
+        Err(ApiError::new(StatusCode::FORBIDDEN, "Only admins can register users."))?
+    }
+    if request.username.is_empty() || request.password.is_empty() {
+        Err(ApiError::new(StatusCode::BAD_REQUEST, "The username and password must not be empty."))?
+    }
+    // The same rules as for users registering themselves, other names can't be addressed in commands
+    if let Err(reason) = server_registration::validate_username(&request.username) {
+        Err(ApiError::new(StatusCode::BAD_REQUEST, format!("Invalid username: {reason}.")))?
+    }
+
+    if let Err(e) = context.database.register_user(&request.username, &request.password).await {
+        tracing::warn!("API registration of {} failed: {e}", request.username);
+        Err(ApiError::new(StatusCode::CONFLICT, "The username is already taken."))?
+    }
+    context.audit(AuditEvent::Register, Some(&admin), Some(&request.username), Some(client.ip()), None).await?;
+    if request.admin {
+        context.database.set_admin(&request.username, true).await?;
+        context.audit(AuditEvent::SetAdmin, Some(&admin), Some(&request.username), Some(client.ip()), Some("granted")).await?;
+    }
+    tracing::info!("User {} registered by {admin} through the API.", request.username);
+    Ok(StatusCode::CREATED)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{header, HeaderMap, HeaderValue};
+
+    use crate::server_api::basic_credentials;
+
+    fn headers(authorization: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, HeaderValue::from_str(authorization).unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_basic_credentials() {
+        // "Alice:open:sesame", the password may contain colons
+        let credentials = basic_credentials(&headers("Basic QWxpY2U6b3BlbjpzZXNhbWU="));
+        assert_eq!(credentials, Some(("Alice".to_string(), "open:sesame".to_string())));
+        assert!(basic_credentials(&headers("basic QWxpY2U6b3BlbjpzZXNhbWU=")).is_some());
+
+        assert_eq!(basic_credentials(&HeaderMap::new()), None);
+        assert_eq!(basic_credentials(&headers("Bearer QWxpY2U6b3BlbjpzZXNhbWU=")), None);
+        assert_eq!(basic_credentials(&headers("Basic not-base64!")), None);
+        // "Alice" without a password
+        assert_eq!(basic_credentials(&headers("Basic QWxpY2U=")), None);
+    }
+}
@@ -0,0 +1,202 @@
+use std::collections::HashSet;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use image::ImageFormat;
+use sha2::{Digest, Sha256};
+
+/// Longest side of image thumbnails in pixels.
+pub const THUMBNAIL_SIZE: u32 = 128;
+
+/// Subdirectory holding the partial files of unfinished uploads.
+const PARTIAL_DIR: &str = "partial";
+
+/// `AttachmentStore` keeps the payloads of images and files in a content-addressed directory.
+/// Every attachment is stored under the SHA-256 hash of its content, so identical files are stored only once.
+pub struct AttachmentStore {
+    root: PathBuf,
+}
+
+impl AttachmentStore {
+    /// Creates a new instance of `AttachmentStore`. The directory is created when the first attachment is stored.
+    ///
+    /// # Arguments
+    ///
+    /// * `root` - The directory where the attachments are stored.
+    ///
+    /// # Returns
+    ///
+    /// * `AttachmentStore` - Returns the attachment store.
+    pub fn new(root: &Path) -> AttachmentStore {
+        AttachmentStore { root: root.to_path_buf() }
+    }
+
+    /// Returns the path of the attachment with the given hash.
+    /// Files are spread into subdirectories by the first two characters of the hash.
+    ///
+    /// # Arguments
+    ///
+    /// * `hash` - The hex encoded SHA-256 hash of the attachment.
+    ///
+    /// # Returns
+    ///
+    /// * `PathBuf` - Returns the path of the attachment file.
+    pub fn path(&self, hash: &str) -> PathBuf {
+        self.root.join(&hash[..2]).join(hash)
+    }
+
+    /// Returns the directory of the partial files of unfinished uploads.
+    pub fn partial_dir(&self) -> PathBuf {
+        self.root.join(PARTIAL_DIR)
+    }
+
+    /// Stores the attachment data unless an attachment with the same content already exists.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The attachment data.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<String>` - Returns the hex encoded SHA-256 hash of the data.
+    pub async fn store(&self, data: &[u8]) -> Result<String> {
+        let hash = format!("{:x}", Sha256::digest(data));
+        let path = self.path(&hash);
+        if tokio::fs::try_exists(&path).await.unwrap_or(false) {
+            // Refresh the modification time so that the file isn't pruned as an orphan before the message is stored.
+            if let Ok(file) = std::fs::File::options().append(true).open(&path) {
+                let _ = file.set_modified(SystemTime::now());
+            }
+            return Ok(hash);
+        }
+
+        let dir = path.parent().context("Invalid attachment path.")?;
+        tokio::fs::create_dir_all(dir).await
+            .with_context(|| format!("Could not create directory {}.", dir.display()))?;
+
+        // Write to a temporary file first so that a crash never leaves a truncated attachment behind.
+        let temp_path = dir.join(format!("{hash}.tmp"));
+        tokio::fs::write(&temp_path, data).await
+            .with_context(|| format!("Could not write attachment {}.", temp_path.display()))?;
+        tokio::fs::rename(&temp_path, &path).await
+            .with_context(|| format!("Could not write attachment {}.", path.display()))?;
+
+        tracing::debug!("Stored attachment {hash} ({} bytes).", data.len());
+        Ok(hash)
+    }
+
+    /// Removes attachments which are not referenced by any message.
+    /// Files modified within the grace period are kept, as their message may not be stored yet.
+    ///
+    /// # Arguments
+    ///
+    /// * `referenced` - Hashes of the attachments which are still in use.
+    /// * `grace_period` - Minimum age of a file before it can be removed.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(usize, u64)>` - Returns the number of removed files and their total size in bytes.
+    pub async fn remove_unreferenced(&self, referenced: &HashSet<String>, grace_period: Duration) -> Result<(usize, u64)> {
+        let mut removed = (0, 0);
+        let mut dirs = match tokio::fs::read_dir(&self.root).await {
+            Ok(dirs) => dirs,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(removed),
+            Err(e) => Err(e).with_context(|| format!("Could not read directory {}.", self.root.display()))?,
+        };
+
+        while let Some(dir) = dirs.next_entry().await? {
+            // Partial uploads are removed only when they weren't resumed for a long time
+            if !dir.file_type().await?.is_dir() || dir.file_name() == PARTIAL_DIR {
+                continue;
+            }
+
+            let mut files = tokio::fs::read_dir(dir.path()).await?;
+            while let Some(file) = files.next_entry().await? {
+                let name = file.file_name().to_string_lossy().to_string();
+                let metadata = file.metadata().await?;
+                let age = metadata.modified()?.elapsed().unwrap_or_default();
+                if referenced.contains(&name) || age < grace_period {
+                    continue;
+                }
+
+                tokio::fs::remove_file(file.path()).await
+                    .with_context(|| format!("Could not remove attachment {}.", file.path().display()))?;
+                removed.0 += 1;
+                removed.1 += metadata.len();
+            }
+        }
+        Ok(removed)
+    }
+}
+
+/// Scales an image down to a PNG thumbnail, smaller images keep their size.
+/// Decoding and encoding take a while, so they run on a blocking thread.
+///
+/// # Arguments
+///
+/// * `data` - The encoded image.
+///
+/// # Returns
+///
+/// * `Result<Vec<u8>>` - Returns the thumbnail encoded as PNG.
+pub async fn make_thumbnail(data: Vec<u8>) -> Result<Vec<u8>> {
+    tokio::task::spawn_blocking(move || {
+        let image = image::load_from_memory(&data)
+            .context("Could not decode the image.")?;
+        let image = if image.width() > THUMBNAIL_SIZE || image.height() > THUMBNAIL_SIZE {
+            image.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE)
+        } else {
+            image
+        };
+
+        let mut thumbnail = Vec::new();
+        image.write_to(&mut Cursor::new(&mut thumbnail), ImageFormat::Png)
+            .context("Could not encode the thumbnail.")?;
+        Ok(thumbnail)
+    }).await?
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::io::Cursor;
+    use std::time::Duration;
+
+    use image::{ImageFormat, RgbImage};
+
+    use crate::server_attachments::{make_thumbnail, AttachmentStore, THUMBNAIL_SIZE};
+
+    #[tokio::test]
+    async fn test_store_attachment() {
+        let root = tempfile::tempdir().unwrap();
+        let store = AttachmentStore::new(root.path());
+
+        let hash = store.store(b"abc").await.unwrap();
+        assert_eq!(hash, "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_eq!(std::fs::read(store.path(&hash)).unwrap(), b"abc");
+        assert_eq!(store.store(b"abc").await.unwrap(), hash);
+        assert_ne!(store.store(b"abd").await.unwrap(), hash);
+
+        let referenced = HashSet::from([hash.clone()]);
+        assert_eq!(store.remove_unreferenced(&referenced, Duration::from_secs(60)).await.unwrap(), (0, 0));
+        assert_eq!(store.remove_unreferenced(&referenced, Duration::ZERO).await.unwrap(), (1, 3));
+        assert!(store.path(&hash).exists());
+    }
+
+    #[tokio::test]
+    async fn test_make_thumbnail() {
+        let mut png = Vec::new();
+        RgbImage::new(1000, 500).write_to(&mut Cursor::new(&mut png), ImageFormat::Png).unwrap();
+        let thumbnail = image::load_from_memory(&make_thumbnail(png).await.unwrap()).unwrap();
+        assert_eq!((thumbnail.width(), thumbnail.height()), (THUMBNAIL_SIZE, THUMBNAIL_SIZE / 2));
+
+        let mut png = Vec::new();
+        RgbImage::new(10, 20).write_to(&mut Cursor::new(&mut png), ImageFormat::Png).unwrap();
+        let thumbnail = image::load_from_memory(&make_thumbnail(png).await.unwrap()).unwrap();
+        assert_eq!((thumbnail.width(), thumbnail.height()), (10, 20));
+
+        assert!(make_thumbnail(b"not an image".to_vec()).await.is_err());
+    }
+}
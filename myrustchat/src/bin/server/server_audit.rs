@@ -0,0 +1,102 @@
+use std::fmt::Display;
+
+use crate::server_db::AuditRecord;
+
+/// Recorded as the actor of events caused by the commands of the server binary, like `register`.
+pub const COMMAND_LINE_ACTOR: &str = "command-line";
+
+/// Recorded as the actor of reloads triggered by `SIGHUP`.
+pub const SIGNAL_ACTOR: &str = "SIGHUP";
+
+/// Kind of a security-relevant event in the audit log.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuditEvent {
+    /// A login attempt, successful or not, the detail is its outcome
+    Login,
+    Kick,
+    Ban,
+    Unban,
+    /// A reload of the configuration, the detail tells whether it failed
+    Reload,
+    /// A new user, registered by a client, by an admin through the HTTP API or with the `register` and `import-users` commands
+    Register,
+    PasswordChange,
+    /// The admin role was granted or revoked
+    SetAdmin,
+    DeleteUser,
+    /// Two-factor authentication was enabled or disabled
+    TwoFactor,
+    /// A client certificate was added or removed, the detail holds its fingerprint
+    Certificate,
+}
+
+impl Display for AuditEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuditEvent::Login => write!(f, "login"),
+            AuditEvent::Kick => write!(f, "kick"),
+            AuditEvent::Ban => write!(f, "ban"),
+            AuditEvent::Unban => write!(f, "unban"),
+            AuditEvent::Reload => write!(f, "reload"),
+            AuditEvent::Register => write!(f, "register"),
+            AuditEvent::PasswordChange => write!(f, "password-change"),
+            AuditEvent::SetAdmin => write!(f, "set-admin"),
+            AuditEvent::DeleteUser => write!(f, "delete-user"),
+            AuditEvent::TwoFactor => write!(f, "two-factor"),
+            AuditEvent::Certificate => write!(f, "certificate"),
+        }
+    }
+}
+
+/// Formats an event of the audit log as a line like `2024-05-01 12:00:00 ban Bob by Alice from 10.0.0.1`.
+///
+/// # Arguments
+///
+/// * `record` - The event.
+///
+/// # Returns
+///
+/// * `String` - Returns the line, with the time in the local time zone.
+pub fn format_record(record: &AuditRecord) -> String {
+    let mut line = format!("{} {}", record.timestamp.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S"), record.event);
+    if let Some(target) = &record.target {
+        line += &format!(" {target}");
+    }
+    if let Some(actor) = &record.actor {
+        line += &format!(" by {actor}");
+    }
+    if let Some(address) = &record.address {
+        line += &format!(" from {address}");
+    }
+    if let Some(detail) = &record.detail {
+        line += &format!(": {detail}");
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Local, TimeZone};
+
+    use crate::server_audit::{format_record, AuditEvent};
+    use crate::server_db::AuditRecord;
+
+    #[test]
+    fn test_format_record() {
+        let timestamp = Local.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap().to_utc();
+        let mut record = AuditRecord {
+            id: 1,
+            timestamp,
+            event: AuditEvent::Ban.to_string(),
+            actor: Some("Alice".to_string()),
+            target: Some("Bob".to_string()),
+            address: Some("10.0.0.1".to_string()),
+            detail: None,
+        };
+        assert_eq!(format_record(&record), "2024-05-01 12:00:00 ban Bob by Alice from 10.0.0.1");
+
+        record.event = AuditEvent::Reload.to_string();
+        (record.actor, record.target, record.address, record.detail) = (Some("SIGHUP".to_string()), None, None, Some("ok".to_string()));
+        assert_eq!(format_record(&record), "2024-05-01 12:00:00 reload by SIGHUP: ok");
+    }
+}
@@ -0,0 +1,212 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use clap::ValueEnum;
+use serde::Deserialize;
+
+use crate::server_db::ServerDatabase;
+
+/// Where the passwords of users are checked.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthBackend {
+    /// the password hashes in the server database
+    #[default]
+    Database,
+    /// an Apache htpasswd file with bcrypt hashes
+    Htpasswd,
+}
+
+/// Checks the passwords of users logging in, over the chat protocol and the HTTP API alike.
+#[async_trait]
+pub trait Authenticator: Send + Sync {
+    /// Checks the password of a user. Unknown users fail like wrong passwords.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - A string slice that holds the username.
+    /// * `password` - A string slice that holds the password.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<bool>` - Returns `true` if the password is right.
+    async fn check_auth(&self, username: &str, password: &str) -> Result<bool>;
+
+    /// Tells whether the passwords are kept in the server database, so users can change them with `.passwd`.
+    fn stores_passwords(&self) -> bool;
+}
+
+/// Checks passwords against the Argon2 hashes in the server database.
+pub struct DatabaseAuthenticator {
+    database: Arc<ServerDatabase>,
+}
+
+impl DatabaseAuthenticator {
+    pub fn new(database: Arc<ServerDatabase>) -> DatabaseAuthenticator {
+        DatabaseAuthenticator { database }
+    }
+}
+
+#[async_trait]
+impl Authenticator for DatabaseAuthenticator {
+    async fn check_auth(&self, username: &str, password: &str) -> Result<bool> {
+        self.database.check_auth(username, password).await
+    }
+
+    fn stores_passwords(&self) -> bool {
+        true
+    }
+}
+
+/// Checks passwords against an htpasswd file, as written by `htpasswd -B`. The file is read again
+/// when it changes, so users can be added and removed while the server runs.
+pub struct HtpasswdAuthenticator {
+    path: PathBuf,
+    /// Modification time of the file when it was last read, and the hashes of its users
+    users: Mutex<(Option<SystemTime>, HashMap<String, String>)>,
+}
+
+impl HtpasswdAuthenticator {
+    /// Reads the users from an htpasswd file.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path to the htpasswd file.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<HtpasswdAuthenticator>` - Returns the authenticator, or an error if the file can't be read or is invalid.
+    pub fn load(path: &Path) -> Result<HtpasswdAuthenticator> {
+        let modified = std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok();
+        let users = read_htpasswd(path)?;
+        tracing::info!("Read {} users from {}.", users.len(), path.display());
+        Ok(HtpasswdAuthenticator { path: path.to_path_buf(), users: Mutex::new((modified, users)) })
+    }
+
+    /// Returns the hash of a user, reading the file again if it changed since it was last read.
+    /// A file which became invalid is reported, the users read before stay valid until it's fixed.
+    fn hash_of(&self, username: &str) -> Option<String> {
+        let modified = std::fs::metadata(&self.path).and_then(|metadata| metadata.modified()).ok();
+        let mut users = self.users.lock().unwrap();
+        if modified != users.0 {
+            match read_htpasswd(&self.path) {
+                Ok(read) => {
+                    tracing::info!("Read {} users from {}.", read.len(), self.path.display());
+                    *users = (modified, read);
+                },
+                Err(e) => {
+                    tracing::error!("{e:#} The users read before are kept.");
+                    users.0 = modified;
+                },
+            }
+        }
+        users.1.get(username).cloned()
+    }
+}
+
+#[async_trait]
+impl Authenticator for HtpasswdAuthenticator {
+    async fn check_auth(&self, username: &str, password: &str) -> Result<bool> {
+        let Some(hash) = self.hash_of(username) else {
+            return Ok(false);
+        };
+        // bcrypt is slow on purpose, so it doesn't hold up other tasks
+        let password = password.to_string();
+        tokio::task::spawn_blocking(move || bcrypt::verify(password, &hash)).await?
+            .map_err(|e| anyhow!("Invalid bcrypt hash of {username}: {e}"))
+    }
+
+    fn stores_passwords(&self) -> bool {
+        false
+    }
+}
+
+/// Reads and parses an htpasswd file.
+fn read_htpasswd(path: &Path) -> Result<HashMap<String, String>> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Could not read htpasswd file {}.", path.display()))?;
+    parse_htpasswd(&text).map_err(|errors| {
+        let lines: Vec<String> = errors.iter().map(|(line, reason)| format!("line {line}: {reason}")).collect();
+        anyhow!("Invalid htpasswd file {}, {}.", path.display(), lines.join(", "))
+    })
+}
+
+/// Parses the lines `username:hash` of an htpasswd file. Only bcrypt hashes are accepted, the MD5, SHA-1
+/// and crypt hashes htpasswd creates without `-B` are too weak. Empty lines and lines starting with `#` are skipped.
+///
+/// # Arguments
+///
+/// * `text` - The content of the file.
+///
+/// # Returns
+///
+/// * `Result<HashMap<String, String>, Vec<(usize, String)>>` - Returns the hashes by username, or the numbers
+///   of the invalid lines with the reasons.
+pub fn parse_htpasswd(text: &str) -> Result<HashMap<String, String>, Vec<(usize, String)>> {
+    let mut users = HashMap::new();
+    let mut errors = Vec::new();
+    for (index, row) in text.lines().enumerate() {
+        let line = index + 1;
+        let row = row.trim();
+        if row.is_empty() || row.starts_with('#') {
+            continue;
+        }
+        let Some((username, hash)) = row.split_once(':') else {
+            errors.push((line, "expected username:hash".to_string()));
+            continue;
+        };
+        if username.is_empty() || username.contains(char::is_whitespace) {
+            errors.push((line, "the username must not be empty or contain spaces".to_string()));
+        } else if chat::is_guest(username) {
+            errors.push((line, format!("usernames starting with {} are reserved for guests", chat::GUEST_PREFIX)));
+        } else if !["$2a$", "$2b$", "$2y$"].iter().any(|prefix| hash.starts_with(prefix)) {
+            errors.push((line, format!("the hash of {username} is not a bcrypt hash, create it with htpasswd -B")));
+        } else if users.insert(username.to_string(), hash.to_string()).is_some() {
+            errors.push((line, format!("user {username} is listed twice")));
+        }
+    }
+
+    match errors.is_empty() {
+        true => Ok(users),
+        false => Err(errors),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server_auth::{parse_htpasswd, Authenticator, HtpasswdAuthenticator};
+
+    #[tokio::test]
+    async fn test_htpasswd() {
+        let alice = bcrypt::hash("aaa", 4).unwrap();
+        let bob = bcrypt::hash("bbb", 4).unwrap().replacen("$2b$", "$2y$", 1);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("users.htpasswd");
+        std::fs::write(&path, format!("# staff\nalice:{alice}\n\n")).unwrap();
+
+        let authenticator = HtpasswdAuthenticator::load(&path).unwrap();
+        assert!(authenticator.check_auth("alice", "aaa").await.unwrap());
+        assert!(!authenticator.check_auth("alice", "bbb").await.unwrap());
+        assert!(!authenticator.check_auth("bob", "bbb").await.unwrap());
+        assert!(!authenticator.stores_passwords());
+
+        // A changed file is read again, an invalid one keeps the users read before
+        std::fs::write(&path, format!("alice:{alice}\nbob:{bob}\n")).unwrap();
+        let later = std::time::SystemTime::now() + std::time::Duration::from_secs(1);
+        std::fs::File::options().write(true).open(&path).unwrap().set_modified(later).unwrap();
+        assert!(authenticator.check_auth("bob", "bbb").await.unwrap());
+        std::fs::write(&path, "bob\n").unwrap();
+        let later = later + std::time::Duration::from_secs(1);
+        std::fs::File::options().write(true).open(&path).unwrap().set_modified(later).unwrap();
+        assert!(authenticator.check_auth("bob", "bbb").await.unwrap());
+
+        let errors = parse_htpasswd(&format!("carol\ndave:$apr1$abc$def\nalice:{alice}\nalice:{alice}\nguest-x:{alice}\n")).unwrap_err();
+        let lines: Vec<usize> = errors.iter().map(|(line, _)| *line).collect();
+        assert_eq!(lines, vec![1, 2, 4, 5]);
+        assert!(HtpasswdAuthenticator::load(&dir.path().join("missing")).is_err());
+    }
+}
@@ -0,0 +1,206 @@
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use crate::server_attachments::AttachmentStore;
+use crate::server_db::ServerDatabase;
+
+/// Name of the database file inside a backup archive.
+const ARCHIVE_DATABASE: &str = "chat.db";
+
+/// Directory of the attachment files inside a backup archive.
+const ARCHIVE_ATTACHMENTS: &str = "attachments";
+
+/// Lists the attachment files below the attachment directory, without the partial files of unfinished uploads
+/// and the temporary files of attachments being written.
+///
+/// # Arguments
+///
+/// * `attachment_dir` - The directory where attachments are stored.
+///
+/// # Returns
+///
+/// * `Result<Vec<PathBuf>>` - Returns the paths of the files relative to `attachment_dir`.
+fn attachment_files(attachment_dir: &Path) -> Result<Vec<PathBuf>> {
+    let partial_dir = AttachmentStore::new(attachment_dir).partial_dir();
+    let dirs = match std::fs::read_dir(attachment_dir) {
+        Ok(dirs) => dirs,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => Err(e).with_context(|| format!("Could not read directory {}.", attachment_dir.display()))?,
+    };
+
+    let mut files = Vec::new();
+    for dir in dirs {
+        let dir = dir?.path();
+        if !dir.is_dir() || dir == partial_dir {
+            continue;
+        }
+        for file in std::fs::read_dir(&dir).with_context(|| format!("Could not read directory {}.", dir.display()))? {
+            let file = file?.path();
+            if file.is_file() && file.extension().is_none_or(|extension| extension != "tmp") {
+                files.push(file.strip_prefix(attachment_dir)?.to_path_buf());
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Backs up the database and the attachments into a gzipped tar archive. The database is copied
+/// with `VACUUM INTO`, so the server may keep running. Attachments are written after the database,
+/// so every attachment referenced by the copy is in the archive.
+///
+/// # Arguments
+///
+/// * `db` - The database.
+/// * `attachment_dir` - The directory where attachments are stored.
+/// * `archive` - The path of the archive, it's replaced only once the backup is complete.
+///
+/// # Returns
+///
+/// * `Result<usize>` - Returns the number of archived attachments.
+pub async fn create_backup(db: &ServerDatabase, attachment_dir: &Path, archive: &Path) -> Result<usize> {
+    let parent = archive.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let work_dir = tempfile::tempdir_in(parent)
+        .with_context(|| format!("Could not create a temporary directory in {}.", parent.display()))?;
+    let snapshot = work_dir.path().join(ARCHIVE_DATABASE);
+    db.snapshot(&snapshot).await.context("Could not copy the database.")?;
+
+    let attachment_dir = attachment_dir.to_path_buf();
+    let archive = archive.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let files = attachment_files(&attachment_dir)?;
+        let partial = work_dir.path().join("backup.tar.gz");
+        let mut builder = tar::Builder::new(GzEncoder::new(File::create(&partial)?, Compression::default()));
+        builder.append_path_with_name(&snapshot, ARCHIVE_DATABASE)?;
+        for file in &files {
+            builder.append_path_with_name(attachment_dir.join(file), Path::new(ARCHIVE_ATTACHMENTS).join(file))
+                .with_context(|| format!("Could not archive attachment {}.", file.display()))?;
+        }
+        builder.into_inner()?.finish()?.sync_all()?;
+        std::fs::rename(&partial, &archive)
+            .with_context(|| format!("Could not write {}.", archive.display()))?;
+        Ok(files.len())
+    }).await?
+}
+
+/// Moves a database file out of the way together with its WAL files, which must never be left
+/// next to another database.
+///
+/// # Arguments
+///
+/// * `db_file` - The path of the database file.
+/// * `to` - The new path of the database file.
+///
+/// # Returns
+///
+/// * `Result<()>` - Returns an empty result if the files were moved.
+fn move_database(db_file: &Path, to: &Path) -> Result<()> {
+    for suffix in ["", "-wal", "-shm"] {
+        let from = PathBuf::from(format!("{}{suffix}", db_file.display()));
+        if from.exists() {
+            std::fs::rename(&from, format!("{}{suffix}", to.display()))
+                .with_context(|| format!("Could not move {}.", from.display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Restores a backup made by `create_backup`. The server must not be running. The current database is kept
+/// next to the restored one with the suffix `.before-restore`, attachments are added to the attachment directory.
+/// The restored database is upgraded to the current schema before it replaces the current one.
+///
+/// # Arguments
+///
+/// * `archive` - The path of the archive.
+/// * `db_file` - The path of the database file.
+/// * `attachment_dir` - The directory where attachments are stored.
+///
+/// # Returns
+///
+/// * `Result<usize>` - Returns the number of restored attachments.
+pub async fn restore_backup(archive: &Path, db_file: &Path, attachment_dir: &Path) -> Result<usize> {
+    let parent = db_file.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let work_dir = tempfile::tempdir_in(parent)
+        .with_context(|| format!("Could not create a temporary directory in {}.", parent.display()))?;
+
+    let source = archive.to_path_buf();
+    let target = work_dir.path().to_path_buf();
+    tokio::task::spawn_blocking(move || -> Result<()> {
+        let file = File::open(&source).with_context(|| format!("Could not open {}.", source.display()))?;
+        tar::Archive::new(GzDecoder::new(file)).unpack(&target)
+            .with_context(|| format!("Could not unpack {}.", source.display()))
+    }).await??;
+
+    let restored = work_dir.path().join(ARCHIVE_DATABASE);
+    if !restored.is_file() {
+        return Err(anyhow!("{} contains no database.", archive.display()));
+    }
+    let restored_attachments = work_dir.path().join(ARCHIVE_ATTACHMENTS);
+    let restored_path = restored.to_str().context("The path of the restored database is not valid UTF-8.")?;
+    ServerDatabase::new(restored_path, &restored_attachments).await
+        .context("The database in the backup can't be opened.")?
+        .close().await;
+
+    let db_file = db_file.to_path_buf();
+    let attachment_dir = attachment_dir.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let files = attachment_files(&restored_attachments)?;
+        for file in &files {
+            let target = attachment_dir.join(file);
+            // Attachments are named by the hash of their content, so an existing file is the same
+            if !target.exists() {
+                std::fs::create_dir_all(target.parent().context("Invalid attachment path.")?)?;
+                std::fs::copy(restored_attachments.join(file), &target)
+                    .with_context(|| format!("Could not restore attachment {}.", target.display()))?;
+            }
+        }
+
+        move_database(&db_file, Path::new(&format!("{}.before-restore", db_file.display())))?;
+        move_database(&restored, &db_file)?;
+        Ok(files.len())
+    }).await?
+}
+
+#[cfg(test)]
+mod tests {
+    use chat::{ChatMessage, ChatMessageContent};
+
+    use crate::server_backup::{create_backup, restore_backup};
+    use crate::server_db::ServerDatabase;
+
+    #[tokio::test]
+    async fn test_backup_and_restore() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_file = dir.path().join("chat.db");
+        let attachment_dir = dir.path().join("attachments");
+        let archive = dir.path().join("backup.tar.gz");
+
+        let db = ServerDatabase::new(db_file.to_str().unwrap(), &attachment_dir).await.unwrap();
+        db.register_user("Alice", "aaa").await.unwrap();
+        let message = ChatMessage::new(1, "Alice", ChatMessageContent::File("notes.txt".to_string(), b"hello".to_vec()));
+        let id = db.store_message(&message).await.unwrap();
+        assert_eq!(create_backup(&db, &attachment_dir, &archive).await.unwrap(), 1);
+
+        // Changes after the backup are undone by the restore, the replaced database is kept
+        db.register_user("Bob", "bbb").await.unwrap();
+        db.close().await;
+        std::fs::remove_dir_all(&attachment_dir).unwrap();
+        assert_eq!(restore_backup(&archive, &db_file, &attachment_dir).await.unwrap(), 1);
+        assert!(dir.path().join("chat.db.before-restore").exists());
+
+        let db = ServerDatabase::new(db_file.to_str().unwrap(), &attachment_dir).await.unwrap();
+        assert!(db.user_exists("Alice").await.unwrap());
+        assert!(!db.user_exists("Bob").await.unwrap());
+        let attachment = db.find_attachment(id).await.unwrap().unwrap();
+        assert_eq!(std::fs::read(attachment.path).unwrap(), b"hello");
+
+        let empty = dir.path().join("empty.tar.gz");
+        std::fs::write(&empty, b"not an archive").unwrap();
+        assert!(restore_backup(&empty, &db_file, &attachment_dir).await.is_err());
+    }
+}
@@ -0,0 +1,369 @@
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use chat::client::ChatClient;
+use chat::{t, ChatMessageContent, CodecKind, Datagram, ServerResponse};
+use tokio::sync::Notify;
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+
+use crate::server_db::{hash_password, NewPassword, ServerDatabase};
+use crate::server_flood::FloodConfig;
+use crate::server_queue::{self, QueuePolicy};
+use crate::server_transport::Listener;
+use crate::{start_server, ServerConfig};
+
+/// Width of the send time at the start of every benchmark message, in microseconds since the start.
+const TIMESTAMP_WIDTH: usize = 20;
+
+/// Password of all benchmark users.
+const BENCH_PASSWORD: &str = "bench";
+
+/// Flags of the `bench` command.
+#[derive(clap::Args, Clone, Debug)]
+pub struct BenchArgs {
+    /// number of clients receiving the messages
+    #[arg(long, default_value_t = 100)]
+    pub clients: usize,
+    /// number of clients sending the messages, they share the messages and don't count as receivers
+    #[arg(long, default_value_t = 1)]
+    pub senders: usize,
+    /// number of messages sent in total, every receiver gets each of them
+    #[arg(long, default_value_t = 1000)]
+    pub messages: usize,
+    /// size of the text of a message in bytes
+    #[arg(long, default_value_t = 64)]
+    pub size: usize,
+    /// messages per second sent by all senders together, 0 sends as fast as possible
+    #[arg(long, default_value_t = 0)]
+    pub rate: u64,
+    /// wire format of datagrams
+    #[arg(long, default_value_t = CodecKind::Cbor)]
+    pub codec: CodecKind,
+    /// maximum number of datagrams waiting to be written to a single client
+    #[arg(long, default_value_t = server_queue::DEFAULT_SEND_QUEUE_SIZE)]
+    pub send_queue_size: usize,
+    /// what happens when a client's send queue is full
+    #[arg(long, value_enum, default_value_t = QueuePolicy::default())]
+    pub send_queue_policy: QueuePolicy,
+    /// seconds to wait for the messages to arrive before the missing ones are counted as lost
+    #[arg(long, default_value_t = 60)]
+    pub timeout: u64,
+}
+
+/// Measurements of a benchmark run.
+#[derive(Debug)]
+pub struct BenchReport {
+    /// Number of messages sent
+    pub sent: usize,
+    /// Number of messages received by all receivers together
+    pub delivered: usize,
+    /// Number of deliveries which didn't arrive in time or went to a client which was disconnected
+    pub lost: usize,
+    /// Time from the first message sent to the last one received
+    pub elapsed: Duration,
+    /// Broadcast latencies of all deliveries, sorted
+    pub latencies: Vec<Duration>,
+}
+
+impl BenchReport {
+    /// Returns the number of messages per second sent through the server.
+    pub fn messages_per_second(&self) -> f64 {
+        self.sent as f64 / self.elapsed.as_secs_f64()
+    }
+
+    /// Returns the number of messages per second received by all receivers together.
+    pub fn deliveries_per_second(&self) -> f64 {
+        self.delivered as f64 / self.elapsed.as_secs_f64()
+    }
+
+    /// Returns a percentile of the broadcast latency.
+    ///
+    /// # Arguments
+    ///
+    /// * `percentile` - The percentile between 0 and 100, e.g. 99 for the latency 99 % of the deliveries stay within.
+    ///
+    /// # Returns
+    ///
+    /// * `Duration` - Returns the latency, zero if nothing was delivered.
+    pub fn latency(&self, percentile: f64) -> Duration {
+        if self.latencies.is_empty() {
+            return Duration::ZERO;
+        }
+        let rank = (percentile / 100.0 * self.latencies.len() as f64).ceil() as usize;
+        self.latencies[rank.clamp(1, self.latencies.len()) - 1]
+    }
+}
+
+/// Starts a server on a temporary database, connects the simulated clients and broadcasts the messages.
+/// The clients are registered users rather than guests, which the flood protection always limits.
+///
+/// # Arguments
+///
+/// * `args` - The flags of the benchmark.
+///
+/// # Returns
+///
+/// * `Result<BenchReport>` - Returns the measurements, or an error if the clients could not connect.
+pub async fn run_bench(args: &BenchArgs) -> Result<BenchReport> {
+    if args.clients == 0 || args.senders == 0 {
+        return Err(anyhow!("The benchmark needs at least one receiving and one sending client."));
+    }
+    let dir = tempfile::tempdir()?;
+    let db_file = dir.path().join("bench.db");
+    let db_file = db_file.to_str().ok_or_else(|| anyhow!("The temporary directory has no valid path."))?;
+    register_clients(db_file, dir.path(), args).await?;
+
+    let listener = Listener::bind_tcp("127.0.0.1", 0, false).await?;
+    let port = listener.local_addr().ok_or_else(|| anyhow!("The benchmark server has no port."))?.port();
+    let config = ServerConfig {
+        codec: args.codec,
+        max_clients: None,
+        flood: FloodConfig { max_messages: None, duplicate_ratio: None, ..FloodConfig::default() },
+        send_queue_size: args.send_queue_size,
+        send_queue_policy: args.send_queue_policy,
+        attachment_dir: dir.path().to_path_buf(),
+        ..ServerConfig::default()
+    };
+    let shutdown = Arc::new(Notify::new());
+    let server_shutdown = shutdown.clone();
+    let server_db_file = db_file.to_string();
+    let server = tokio::spawn(async move {
+        start_server(vec![listener], &server_db_file, config, None, async move { server_shutdown.notified().await }).await
+    });
+
+    let result = measure(args, port).await;
+    shutdown.notify_one();
+    server.await??;
+    result
+}
+
+/// Registers the receiving and sending users. They share a single password hash, so that hashing
+/// doesn't take longer than the benchmark itself.
+///
+/// # Arguments
+///
+/// * `db_file` - The path to the database file.
+/// * `attachment_dir` - The directory where attachments are stored.
+/// * `args` - The flags of the benchmark.
+///
+/// # Returns
+///
+/// * `EmptyResult` - Returns an empty result if all users were registered.
+async fn register_clients(db_file: &str, attachment_dir: &Path, args: &BenchArgs) -> chat::EmptyResult {
+    let database = ServerDatabase::new(db_file, attachment_dir).await?;
+    let hash = hash_password(BENCH_PASSWORD)?;
+    let users = receiver_names(args).chain(sender_names(args))
+        .map(|username| (username, NewPassword::Hashed(hash.clone())))
+        .collect::<Vec<_>>();
+    if let Some((_, error)) = database.import_users(&users).await?.into_iter().next() {
+        return Err(anyhow!("Could not register the benchmark users: {error}"));
+    }
+    Ok(())
+}
+
+fn receiver_names(args: &BenchArgs) -> impl Iterator<Item = String> {
+    (0..args.clients).map(|index| format!("receiver{index}"))
+}
+
+fn sender_names(args: &BenchArgs) -> impl Iterator<Item = String> {
+    (0..args.senders).map(|index| format!("sender{index}"))
+}
+
+/// Connects the clients to the benchmark server and measures the broadcasts.
+///
+/// # Arguments
+///
+/// * `args` - The flags of the benchmark.
+/// * `port` - The port of the server.
+///
+/// # Returns
+///
+/// * `Result<BenchReport>` - Returns the measurements.
+async fn measure(args: &BenchArgs, port: u16) -> Result<BenchReport> {
+    let mut receivers = Vec::with_capacity(args.clients);
+    for username in receiver_names(args) {
+        receivers.push(ChatClient::connect("127.0.0.1", port, &username, BENCH_PASSWORD, args.codec).await?);
+    }
+    let mut senders = Vec::with_capacity(args.senders);
+    for username in sender_names(args) {
+        senders.push(ChatClient::connect("127.0.0.1", port, &username, BENCH_PASSWORD, args.codec).await?);
+    }
+    wait_for_logins(&mut senders[0], args.clients + args.senders).await?;
+
+    let start = Instant::now();
+    let deadline = start + Duration::from_secs(args.timeout);
+    let mut receiving = JoinSet::new();
+    for client in receivers {
+        receiving.spawn(receive(client, args.messages, start, deadline));
+    }
+
+    let mut sending = JoinSet::new();
+    for (index, mut client) in senders.into_iter().enumerate() {
+        let sender = client.sender();
+        // Senders get the messages of the others, which are read and dropped so that they aren't disconnected
+        tokio::spawn(async move { while client.recv().await.is_some() {} });
+        let count = args.messages / args.senders + usize::from(index < args.messages % args.senders);
+        let interval = (args.rate > 0).then(|| Duration::from_secs_f64(args.senders as f64 / args.rate as f64));
+        let size = args.size;
+        sending.spawn(async move {
+            let mut ticks = interval.map(tokio::time::interval);
+            for _ in 0..count {
+                if let Some(ticks) = &mut ticks {
+                    ticks.tick().await;
+                }
+                let sent_at = start.elapsed().as_micros();
+                let text = format!("{sent_at:0width$}{}", "x".repeat(size.saturating_sub(TIMESTAMP_WIDTH)), width = TIMESTAMP_WIDTH);
+                sender.send_text(&text).await?;
+            }
+            Ok::<_, chat::ChatProtocolError>(())
+        });
+    }
+    while let Some(sent) = sending.join_next().await {
+        sent??;
+    }
+
+    let mut latencies = Vec::with_capacity(args.clients * args.messages);
+    let mut last_received = start;
+    while let Some(received) = receiving.join_next().await {
+        let (client_latencies, client_last) = received?;
+        latencies.extend(client_latencies);
+        last_received = last_received.max(client_last);
+    }
+    latencies.sort();
+
+    let expected = args.clients * args.messages;
+    Ok(BenchReport {
+        sent: args.messages,
+        delivered: latencies.len(),
+        lost: expected - latencies.len(),
+        elapsed: last_received.duration_since(start).max(Duration::from_micros(1)),
+        latencies,
+    })
+}
+
+/// Waits until the server has registered all clients, so that every one of them gets the first message.
+///
+/// # Arguments
+///
+/// * `client` - One of the clients, asking for the online users.
+/// * `count` - The number of clients.
+///
+/// # Returns
+///
+/// * `EmptyResult` - Returns an empty result once all clients are online.
+async fn wait_for_logins(client: &mut ChatClient, count: usize) -> chat::EmptyResult {
+    loop {
+        client.send(&Datagram::ListUsers).await?;
+        loop {
+            match client.recv().await {
+                Some(Datagram::ServerResponse(ServerResponse::UserList(users))) if users.len() >= count => return Ok(()),
+                Some(Datagram::ServerResponse(ServerResponse::UserList(_))) => break,
+                Some(_) => {},
+                None => return Err(anyhow!("The benchmark server closed the connection.")),
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+/// Receives the benchmark messages on a client until all have arrived, the client is disconnected or the deadline passes.
+///
+/// # Arguments
+///
+/// * `client` - The receiving client.
+/// * `messages` - The number of messages sent.
+/// * `start` - The start of the benchmark, the send times in the messages are relative to it.
+/// * `deadline` - When to give up waiting.
+///
+/// # Returns
+///
+/// * `(Vec<Duration>, Instant)` - Returns the latencies of the received messages and when the last one arrived.
+async fn receive(mut client: ChatClient, messages: usize, start: Instant, deadline: Instant) -> (Vec<Duration>, Instant) {
+    let mut latencies = Vec::with_capacity(messages);
+    let mut last_received = start;
+    while latencies.len() < messages {
+        let incoming = match tokio::time::timeout_at(deadline, client.recv_message()).await {
+            Ok(Some(incoming)) => incoming,
+            Ok(None) | Err(_) => break,
+        };
+        let ChatMessageContent::Text(text) = incoming.message.content else {
+            continue;
+        };
+        let Some(sent_at) = text.get(..TIMESTAMP_WIDTH).and_then(|sent_at| sent_at.parse().ok()) else {
+            continue;
+        };
+        last_received = Instant::now();
+        latencies.push(last_received.duration_since(start).saturating_sub(Duration::from_micros(sent_at)));
+    }
+    (latencies, last_received)
+}
+
+/// Runs the benchmark and prints its results.
+///
+/// # Arguments
+///
+/// * `args` - The flags of the benchmark.
+///
+/// # Returns
+///
+/// * `EmptyResult` - Returns an empty result if the benchmark ran.
+pub async fn print_bench(args: &BenchArgs) -> chat::EmptyResult {
+    println!("{}", t!("bench-start", messages = args.messages, size = args.size, senders = args.senders, clients = args.clients));
+    let report = run_bench(args).await?;
+    println!("{}", t!("bench-elapsed", seconds = format!("{:.3}", report.elapsed.as_secs_f64())));
+    println!("{}", t!("bench-throughput", messages = format!("{:.0}", report.messages_per_second()), deliveries = format!("{:.0}", report.deliveries_per_second())));
+    println!("{}", t!("bench-latency", p50 = format!("{:.2}", millis(report.latency(50.0))), p99 = format!("{:.2}", millis(report.latency(99.0))),
+                      max = format!("{:.2}", millis(report.latency(100.0)))));
+    if report.lost > 0 {
+        println!("{}", t!("bench-lost", lost = report.lost, total = report.lost + report.delivered));
+    }
+    Ok(())
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use clap::Parser;
+
+    use crate::server_bench::{run_bench, BenchArgs, BenchReport};
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        bench: BenchArgs,
+    }
+
+    #[test]
+    fn test_latency_percentiles() {
+        let report = BenchReport {
+            sent: 100,
+            delivered: 100,
+            lost: 0,
+            elapsed: Duration::from_secs(2),
+            latencies: (1..=100).map(Duration::from_millis).collect(),
+        };
+        assert_eq!(report.latency(50.0), Duration::from_millis(50));
+        assert_eq!(report.latency(99.0), Duration::from_millis(99));
+        assert_eq!(report.latency(100.0), Duration::from_millis(100));
+        assert_eq!(report.latency(0.0), Duration::from_millis(1));
+        assert_eq!(report.messages_per_second(), 50.0);
+    }
+
+    #[tokio::test]
+    async fn test_bench() {
+        let args = Cli::parse_from(["bench", "--clients", "5", "--senders", "2", "--messages", "21", "--size", "100"]).bench;
+        let report = run_bench(&args).await.unwrap();
+        assert_eq!(report.sent, 21);
+        assert_eq!(report.delivered, 5 * 21);
+        assert_eq!(report.lost, 0);
+        assert!(report.latency(99.0) <= report.elapsed);
+    }
+}
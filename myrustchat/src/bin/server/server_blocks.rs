@@ -0,0 +1,129 @@
+use std::collections::{BTreeSet, HashMap};
+
+use chat::Datagram;
+
+/// Maximum number of users a single user can block.
+pub const MAX_BLOCKED_USERS: usize = 500;
+
+/// Block lists of all users, kept in memory so that the delivery of a message doesn't need the database.
+/// The lists are persisted by `ServerDatabase` and loaded when the server starts.
+#[derive(Debug, Default)]
+pub struct BlockLists {
+    lists: HashMap<String, BTreeSet<String>>,
+}
+
+impl BlockLists {
+    /// Creates the block lists from the stored blocks.
+    ///
+    /// # Arguments
+    ///
+    /// * `blocks` - Pairs of the blocking and the blocked username.
+    ///
+    /// # Returns
+    ///
+    /// * `BlockLists` - Returns the block lists.
+    pub fn new(blocks: impl IntoIterator<Item = (String, String)>) -> BlockLists {
+        let mut lists = BlockLists::default();
+        for (blocker, blocked) in blocks {
+            lists.block(&blocker, &blocked);
+        }
+        lists
+    }
+
+    /// Adds a user to the block list of another user.
+    ///
+    /// # Arguments
+    ///
+    /// * `blocker` - The username of the blocking user.
+    /// * `blocked` - The username of the blocked user.
+    pub fn block(&mut self, blocker: &str, blocked: &str) {
+        self.lists.entry(blocker.to_string()).or_default().insert(blocked.to_string());
+    }
+
+    /// Removes a user from the block list of another user.
+    ///
+    /// # Arguments
+    ///
+    /// * `blocker` - The username of the blocking user.
+    /// * `blocked` - The username of the blocked user.
+    pub fn unblock(&mut self, blocker: &str, blocked: &str) {
+        if let Some(list) = self.lists.get_mut(blocker) {
+            list.remove(blocked);
+            if list.is_empty() {
+                self.lists.remove(blocker);
+            }
+        }
+    }
+
+    /// Returns the users blocked by a user, sorted by username.
+    ///
+    /// # Arguments
+    ///
+    /// * `blocker` - The username of the blocking user.
+    pub fn blocked_by(&self, blocker: &str) -> Vec<String> {
+        self.lists.get(blocker).map(|list| list.iter().cloned().collect()).unwrap_or_default()
+    }
+
+    /// Checks whether a user blocks another user.
+    ///
+    /// # Arguments
+    ///
+    /// * `blocker` - The username of the possibly blocking user.
+    /// * `sender` - The username of the sender of a message.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - Returns `true` if the messages of `sender` must not be delivered to `blocker`.
+    pub fn blocks(&self, blocker: &str, sender: &str) -> bool {
+        self.lists.get(blocker).is_some_and(|list| list.contains(sender))
+    }
+}
+
+/// Returns the user whose content a datagram carries, which is withheld from the users blocking them.
+/// Notices like presence and renames are delivered to everyone.
+///
+/// # Arguments
+///
+/// * `datagram` - The datagram to be delivered.
+///
+/// # Returns
+///
+/// * `Option<&str>` - Returns the username of the sender, `None` for datagrams which can't be blocked.
+pub fn blockable_sender(datagram: &Datagram) -> Option<&str> {
+    match datagram {
+        Datagram::Message(message)
+        | Datagram::DirectMessage { message, .. }
+        | Datagram::Thumbnail { message, .. } => Some(&message.sender),
+        Datagram::FileOffer { sender, .. } => Some(sender),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chat::{ChatMessage, ChatMessageContent, Datagram};
+
+    use crate::server_blocks::{blockable_sender, BlockLists};
+
+    #[test]
+    fn test_block_lists() {
+        let pair = |blocker: &str, blocked: &str| (blocker.to_string(), blocked.to_string());
+        let mut lists = BlockLists::new([pair("Alice", "Bob"), pair("Alice", "Carol"), pair("Bob", "Alice")]);
+        assert!(lists.blocks("Alice", "Bob"));
+        assert!(!lists.blocks("Carol", "Alice"));
+        assert_eq!(lists.blocked_by("Alice"), vec!["Bob", "Carol"]);
+
+        lists.unblock("Alice", "Bob");
+        lists.unblock("Alice", "Dave");
+        assert!(!lists.blocks("Alice", "Bob"));
+        lists.unblock("Bob", "Alice");
+        assert!(lists.blocked_by("Bob").is_empty());
+        lists.block("Bob", "guest-Eve");
+        assert!(lists.blocks("Bob", "guest-Eve"));
+
+        let message = ChatMessage::new(1, "Bob", ChatMessageContent::Text("hi".to_string()));
+        assert_eq!(blockable_sender(&Datagram::Message(message.clone())), Some("Bob"));
+        assert_eq!(blockable_sender(&Datagram::DirectMessage { to: "Alice".to_string(), message }), Some("Bob"));
+        assert_eq!(blockable_sender(&Datagram::Presence { username: "Bob".to_string(), online: true }), None);
+    }
+}
@@ -0,0 +1,237 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+use chat::CodecKind;
+use clap::ValueEnum;
+use serde::{Deserialize, Deserializer};
+use tracing::level_filters::LevelFilter;
+
+use crate::server_auth::AuthBackend;
+use crate::server_queue::QueuePolicy;
+use crate::DuplicateLogin;
+
+/// Output format of the server log.
+#[derive(Clone, Copy, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// human readable lines
+    Pretty,
+    /// one JSON object per line, for log collectors
+    Json,
+}
+
+/// Where the server keeps its data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Storage {
+    /// the SQLite database file and the attachment directory
+    File,
+    /// memory only, everything is lost when the server stops
+    Memory,
+}
+
+/// Settings read from a TOML configuration file. The keys match the names of the command line flags,
+/// every key is optional and flags given on the command line take precedence.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileConfig {
+    /// Addresses to bind, a single one may be given as a string
+    #[serde(deserialize_with = "one_or_many")]
+    pub address: Option<Vec<String>>,
+    /// Port to bind
+    pub port: Option<u16>,
+    /// Unix socket to listen on instead of the address and port
+    pub unix_socket: Option<PathBuf>,
+    /// Where the server keeps its data
+    pub db: Option<Storage>,
+    /// SQLite database file
+    pub db_file: Option<String>,
+    /// Directory where image and file attachments are stored
+    pub attachment_dir: Option<PathBuf>,
+    /// Maximum size of a single datagram in bytes
+    pub max_message_size: Option<usize>,
+    /// Wire format of datagrams
+    #[serde(deserialize_with = "from_str")]
+    pub codec: Option<CodecKind>,
+    /// Seconds of inactivity after which a client is disconnected
+    pub idle_timeout: Option<u64>,
+    /// Seconds a client may take to accept a datagram before it's disconnected
+    pub write_timeout: Option<u64>,
+    /// Messages older than this many days are deleted
+    pub retention_days: Option<u64>,
+    /// Maximum number of open connections
+    pub max_clients: Option<usize>,
+    /// Maximum number of open connections from a single IP address
+    pub max_clients_per_ip: Option<usize>,
+    /// Seconds of inactivity after which a client may be disconnected to make room when the server is full
+    pub evict_idle_after: Option<u64>,
+    /// Maximum number of messages a user may send within the flood window
+    pub flood_max_messages: Option<usize>,
+    /// Length of the flood window in seconds
+    pub flood_window: Option<u64>,
+    /// Maximum share of repeated messages within the flood window
+    pub flood_duplicate_ratio: Option<f64>,
+    /// Seconds for which users exceeding the flood limits are muted
+    pub mute_duration: Option<u64>,
+    /// Number of failed logins after which logins are refused for a while
+    pub lockout_attempts: Option<u32>,
+    /// Seconds for which logins are refused after too many failures
+    pub lockout_duration: Option<u64>,
+    /// Maximum size of an image or file attachment in bytes
+    pub max_attachment_size: Option<u64>,
+    /// Whether images and files are rejected if their content doesn't match their type
+    pub check_mime: Option<bool>,
+    /// Maximum bytes of stored attachments per user
+    pub storage_quota: Option<u64>,
+    /// Quotas of single users overriding `storage_quota`, the `[user_storage_quotas]` table
+    pub user_storage_quotas: Option<HashMap<String, u64>>,
+    /// Address and port of the HTTP API
+    pub api_address: Option<String>,
+    /// Loopback address and port of the admin console
+    pub admin_address: Option<String>,
+    /// Unix socket of the admin console
+    pub admin_socket: Option<PathBuf>,
+    /// TOML file with the rules of the content filter
+    pub content_filter: Option<PathBuf>,
+    /// Whether large frames are compressed for clients offering it
+    pub compression: Option<bool>,
+    /// Maximum number of datagrams waiting to be written to a single client
+    pub send_queue_size: Option<usize>,
+    /// What happens when a client's send queue is full
+    pub send_queue_policy: Option<QueuePolicy>,
+    /// What happens when a user logs in from a second connection
+    pub duplicate_login: Option<DuplicateLogin>,
+    /// Whether clients may log in as guests
+    pub allow_guests: Option<bool>,
+    /// Whether clients may register new users
+    pub allow_registration: Option<bool>,
+    /// URL the verification codes of registrations are posted to
+    pub registration_webhook: Option<String>,
+    /// Where passwords are checked
+    pub auth_backend: Option<AuthBackend>,
+    /// htpasswd file with bcrypt hashes
+    pub htpasswd_file: Option<PathBuf>,
+    /// Port on which clients connect with TLS
+    pub tls_port: Option<u16>,
+    /// PEM file with the certificate of the server
+    pub tls_cert: Option<PathBuf>,
+    /// PEM file with the private key of the server
+    pub tls_key: Option<PathBuf>,
+    /// ID of this server among linked servers
+    pub server_id: Option<String>,
+    /// Addresses of the servers to link to
+    pub peer: Option<Vec<String>>,
+    /// Secret shared by linked servers
+    pub peer_secret: Option<String>,
+    /// Whether links in public messages are previewed
+    pub link_previews: Option<bool>,
+    /// Seconds a linked page may take to load
+    pub link_preview_timeout: Option<u64>,
+    /// Whether links to private addresses are previewed too
+    pub link_preview_private_hosts: Option<bool>,
+    /// Format of the log output
+    pub log_format: Option<LogFormat>,
+    /// Most verbose log level
+    #[serde(deserialize_with = "from_str")]
+    pub log_level: Option<LevelFilter>,
+}
+
+impl FileConfig {
+    /// Reads the configuration from a TOML file.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path to the configuration file.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<FileConfig>` - Returns the parsed configuration if successful.
+    pub fn load(path: &Path) -> Result<FileConfig> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read configuration file {}.", path.display()))?;
+        toml::from_str(&text)
+            .with_context(|| format!("Invalid configuration file {}.", path.display()))
+    }
+}
+
+/// Deserializes an optional value from a string using its `FromStr` implementation.
+fn from_str<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    let value = String::deserialize(deserializer)?;
+    value.parse().map(Some).map_err(serde::de::Error::custom)
+}
+
+/// Deserializes a list of strings which may also be given as a single string.
+fn one_or_many<'de, D>(deserializer: D) -> Result<Option<Vec<String>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(Some(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use chat::CodecKind;
+    use tracing::level_filters::LevelFilter;
+
+    use crate::server_auth::AuthBackend;
+    use crate::server_config::{FileConfig, LogFormat, Storage};
+    use crate::server_queue::QueuePolicy;
+    use crate::DuplicateLogin;
+
+    #[test]
+    fn test_parse_config_file() {
+        let config: FileConfig = toml::from_str(
+            r#"
+            port = 12345
+            db = "file"
+            db_file = "chat.db"
+            codec = "json"
+            log_format = "json"
+            log_level = "debug"
+            send_queue_policy = "drop-oldest"
+            duplicate_login = "kick-old"
+            auth_backend = "htpasswd"
+            "#
+        ).unwrap();
+        assert_eq!(config.port, Some(12345));
+        assert_eq!(config.db, Some(Storage::File));
+        assert_eq!(config.db_file.as_deref(), Some("chat.db"));
+        assert_eq!(config.codec, Some(CodecKind::Json));
+        assert!(matches!(config.log_format, Some(LogFormat::Json)));
+        assert_eq!(config.log_level, Some(LevelFilter::DEBUG));
+        assert_eq!(config.send_queue_policy, Some(QueuePolicy::DropOldest));
+        assert_eq!(config.duplicate_login, Some(DuplicateLogin::KickOld));
+        assert_eq!(config.auth_backend, Some(AuthBackend::Htpasswd));
+        assert!(config.address.is_none());
+
+        let config: FileConfig = toml::from_str("storage_quota = 1000\n[user_storage_quotas]\nalice = 5000\nbot = 0").unwrap();
+        assert_eq!(config.storage_quota, Some(1000));
+        assert_eq!(config.user_storage_quotas, Some([("alice".to_string(), 5000), ("bot".to_string(), 0)].into()));
+
+        let config: FileConfig = toml::from_str("address = \"0.0.0.0\"").unwrap();
+        assert_eq!(config.address, Some(vec!["0.0.0.0".to_string()]));
+        let config: FileConfig = toml::from_str("address = [\"0.0.0.0\", \"::\"]").unwrap();
+        assert_eq!(config.address, Some(vec!["0.0.0.0".to_string(), "::".to_string()]));
+
+        assert!(toml::from_str::<FileConfig>("prot = 1").is_err());
+        assert!(toml::from_str::<FileConfig>("codec = \"xml\"").is_err());
+    }
+}
@@ -1,9 +1,19 @@
 use chat::ChatMessage;
 use chat::ChatMessageContent;
-use sqlx::Connection;
-use sqlx::SqliteConnection;
+use chat::{AttachmentId, AttachmentKind, AudioFormat, MessageId, MessageSeq, ReplyTo, UserProfile, UserStatus};
+use std::str::FromStr;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions};
+use sqlx::SqlitePool;
 use chat::EmptyResult;
+use crate::server_attachments::AttachmentStore;
+use crate::server_audit::AuditEvent;
+use crate::server_lockout::LoginOutcome;
+use crate::server_migrations::{run_migrations, MIGRATIONS};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use chrono::{DateTime, Utc};
 use anyhow::{anyhow, Result,Context};
+use serde::Serialize;
 use argon2::{
     password_hash::{
         rand_core::OsRng,
@@ -12,33 +22,171 @@ use argon2::{
     Argon2
 };
 
-/// `ServerDatabase` struct represents the server's database with a connection to an SQLite database.
+/// An image or file attachment stored on disk.
+pub struct StoredAttachment {
+    /// Username of the sender
+    pub sender: String,
+    pub kind: AttachmentKind,
+    /// Path of the file holding the data
+    pub path: PathBuf,
+    /// Size of the data in bytes
+    pub size: u64,
+}
+
+/// A stored chat message, as listed by the HTTP API.
+#[derive(Serialize)]
+pub struct MessageRecord {
+    /// Sequence number of the message, which also identifies its attachment
+    pub id: MessageSeq,
+    /// ID given by the client of the sender, which replies refer to. `None` for messages stored by old server versions
+    pub client_id: Option<MessageId>,
+    pub sender: String,
+    /// Time of arrival at the server, `None` for messages stored by old server versions
+    pub timestamp: Option<DateTime<Utc>>,
+    /// Text of a text message
+    pub text: Option<String>,
+    /// Type of the attachment, `None` for text messages
+    pub attachment: Option<AttachmentKind>,
+    /// Size of the attachment in bytes
+    pub size: Option<u64>,
+    /// The message this one replies to
+    pub reply_to: Option<ReplyTo>,
+    /// Whether the message was posted to the announcement channel
+    pub announcement: bool,
+    /// When the message expires and is deleted, `None` if it stays
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// A registered user, as listed by the HTTP API.
+#[derive(Serialize)]
+pub struct UserRecord {
+    pub username: String,
+    pub nickname: Option<String>,
+    pub admin: bool,
+    pub banned: bool,
+}
+
+/// The attachment storage filled by a user, as listed by the `storage-report` command.
+#[derive(Debug, PartialEq)]
+pub struct StorageUsage {
+    pub username: String,
+    /// Number of stored attachments sent by the user
+    pub attachments: u64,
+    /// Total size of these attachments in bytes
+    pub bytes: u64,
+}
+
+/// A message caught by the content filter, as listed by the `filter-log` command.
+pub struct FilteredRecord {
+    pub sender: String,
+    /// Recipient of a direct message, `None` for a message to everyone
+    pub recipient: Option<String>,
+    pub timestamp: DateTime<Utc>,
+    /// The original text of the message
+    pub text: String,
+    pub action: String,
+    /// Names of the matched filter rules, separated by commas
+    pub rules: String,
+}
+
+/// A login attempt, as listed by the `show-audit` command.
+pub struct LoginRecord {
+    pub username: String,
+    /// IP address of the client, `None` for Unix socket connections
+    pub address: Option<String>,
+    pub timestamp: DateTime<Utc>,
+    pub outcome: String,
+}
+
+/// An event of the audit log, as listed by the `audit` command.
+pub struct AuditRecord {
+    /// Position in the log, increasing with every event
+    pub id: i64,
+    pub timestamp: DateTime<Utc>,
+    pub event: String,
+    /// Who caused the event, a user, `console`, `command-line` or `SIGHUP`
+    pub actor: Option<String>,
+    /// The user the event is about
+    pub target: Option<String>,
+    /// IP address of the actor, `None` for Unix socket connections and local commands
+    pub address: Option<String>,
+    pub detail: Option<String>,
+}
+
+/// Attachments younger than this are never pruned, their message may still be waiting to be stored.
+const ORPHAN_GRACE_PERIOD: Duration = Duration::from_secs(60);
+
+/// `ServerDatabase` struct represents the server's database with a pool of connections to an SQLite database.
+/// All queries take `&self`, so the database can be shared between client tasks without a lock.
 pub struct ServerDatabase {
-    /// SQLite database connection
-    pub db: SqliteConnection,
-    /// Optional salt string for password hashing
-    password_salt: Option<SaltString>
+    /// SQLite connection pool
+    pub db: SqlitePool,
+    /// Storage of image and file attachments, only their hashes are kept in the database
+    pub attachments: AttachmentStore,
+    /// Holds the attachments of an in-memory database until it's dropped
+    temp_dir: Option<tempfile::TempDir>,
+}
+
+/// Name of the database kept only in memory, given instead of a file name.
+pub const MEMORY_DATABASE: &str = ":memory:";
+
+/// Password of a user created by `import_users`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum NewPassword {
+    /// A password in plain text, hashed before it's stored
+    Plain(String),
+    /// An Argon2 hash in the PHC string format, e.g. exported from another server, stored as it is
+    Hashed(String),
+}
+
+/// Hashes a password with Argon2 using a freshly generated salt.
+/// The salt is stored inside the returned PHC string, so every user gets a different one.
+///
+/// # Arguments
+///
+/// * `password` - A string slice that holds the password.
+///
+/// # Returns
+///
+/// * `Result<String>` - Returns the serialized password hash if successful.
+pub(crate) fn hash_password(password: &str) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default().hash_password(password.as_bytes(), &salt)
+        .map_err(|_| anyhow!("Failed to hash password."))?;
+    Ok(hash.serialize().as_str().to_string())
 }
 
 impl ServerDatabase {
-    /// Creates a new instance of `ServerDatabase`.
+    /// Creates a new instance of `ServerDatabase`. With `MEMORY_DATABASE` instead of a file the database
+    /// is kept in memory and the attachments in a temporary directory, both are gone once it's dropped.
     ///
     /// # Arguments
     ///
-    /// * `file` - A string slice that holds the path to the database file.
+    /// * `file` - A string slice that holds the path to the database file, or `MEMORY_DATABASE`.
+    /// * `attachment_dir` - The directory where image and file attachments are stored, unused for `MEMORY_DATABASE`.
     ///
     /// # Returns
     ///
     /// * `Result<ServerDatabase>` - Returns a result containing a `ServerDatabase` instance if successful.
-    pub async fn new(file: &str) -> Result<ServerDatabase> {
-        let connection = 
-        SqliteConnection::connect(format!("sqlite:{file}?mode=rwc").as_str())
+    pub async fn new(file: &str, attachment_dir: &Path) -> Result<ServerDatabase> {
+        if file == MEMORY_DATABASE {
+            return ServerDatabase::in_memory().await;
+        }
+
+        // WAL mode lets readers proceed while a message is being written.
+        let options = SqliteConnectOptions::from_str(format!("sqlite:{file}").as_str())?
+            .create_if_missing(true)
+            .journal_mode(SqliteJournalMode::Wal);
+
+        let pool = SqlitePoolOptions::new()
+            .connect_with(options)
             .await
-            .with_context(|| format!("Could not open database."))?;
+            .context("Could not open database.")?;
 
-        let mut db = ServerDatabase {
-            db: connection,
-            password_salt: None
+        let db = ServerDatabase {
+            db: pool,
+            attachments: AttachmentStore::new(attachment_dir),
+            temp_dir: None,
         };
 
         db.init().await?;
@@ -46,80 +194,102 @@ impl ServerDatabase {
         Ok(db)
     }
 
-    /// Initializes the database by creating necessary tables and setting up configurations.
+    /// Creates a database which lives only in memory, for tests and throwaway servers.
     ///
     /// # Returns
     ///
-    /// * `EmptyResult` - Returns an empty result if successful.
-    async fn init(&mut self) -> EmptyResult {
-        let (ver, ): (i32, ) = sqlx::query_as("PRAGMA user_version")
-            .fetch_one(&mut self.db).await?;
-        
-        if ver == 0 {
-            log::warn!("Creating a new database.");
+    /// * `Result<ServerDatabase>` - Returns a result containing a `ServerDatabase` instance if successful.
+    async fn in_memory() -> Result<ServerDatabase> {
+        // Every connection to `:memory:` opens a database of its own, so the pool keeps a single one forever.
+        let options = SqliteConnectOptions::from_str("sqlite::memory:")?;
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .connect_with(options)
+            .await
+            .context("Could not open database.")?;
 
-            let mut trans = self.db.begin().await?;
-            
-            sqlx::query(
-                "
-                CREATE TABLE IF NOT EXISTS users (
-                    username TEXT PRIMARY KEY,
-                    password TEXT
-                )
-                "
-            ).execute(&mut *trans).await
-            .context("Failed to create table: users")?;
+        let temp_dir = tempfile::tempdir().context("Could not create a directory for attachments.")?;
+        let db = ServerDatabase {
+            db: pool,
+            attachments: AttachmentStore::new(&temp_dir.path().join("attachments")),
+            temp_dir: Some(temp_dir),
+        };
 
-            sqlx::query(
-                "
-                CREATE TABLE IF NOT EXISTS messages (
-                    messages_id INTEGER PRIMARY KEY,
-                    sender TEXT,
-                    content_type INTEGER,
-                    text TEXT,
-                    filename TEXT,
-                    content BLOB,
-                    FOREIGN KEY(sender) REFERENCES users(username)
-                )
-                "
-            ).execute(&mut *trans).await
-            .context("Failed to create table: messages")?;
+        db.init().await?;
 
-            sqlx::query(
-                "
-                CREATE TABLE IF NOT EXISTS config (
-                key TEXT NOT NULL PRIMARY KEY, 
-                value TEXT NOT NULL
-                )
-                ").execute(&mut *trans).await
-                .context("Failed to create table: config")?;
+        Ok(db)
+    }
 
-            let salt = SaltString::generate(&mut OsRng);
-            log::info!("Generated password salt: {salt}");
+    /// Returns whether the database is kept only in memory.
+    pub fn is_in_memory(&self) -> bool {
+        self.temp_dir.is_some()
+    }
 
-            sqlx::query(
-                "
-                INSERT INTO config (key, value) VALUES ('password_salt', $1)
-                ",
-                
-            ).bind(salt.as_str()).execute(&mut *trans).await?;
-            
-            sqlx::query("PRAGMA user_version=1").execute(&mut *trans).await?;
-            trans.commit().await?;
-        }
+    /// Writes a consistent copy of the database to a new file with `VACUUM INTO`. Unlike copying the file,
+    /// this is safe while the server is running and writing to the database.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the copy, which must not exist.
+    ///
+    /// # Returns
+    ///
+    /// * `EmptyResult` - Returns an empty result if successful.
+    pub async fn snapshot(&self, path: &Path) -> EmptyResult {
+        let path = path.to_str().context("The path of the database copy is not valid UTF-8.")?;
+        sqlx::query("VACUUM INTO $1")
+            .bind(path)
+            .execute(&self.db).await?;
+        Ok(())
+    }
+
+    /// Closes all connections to the database, so that its file can be moved.
+    pub async fn close(self) {
+        self.db.close().await;
+    }
+
+    /// Initializes the database by applying pending schema migrations.
+    ///
+    /// # Returns
+    ///
+    /// * `EmptyResult` - Returns an empty result if successful.
+    async fn init(&self) -> EmptyResult {
+        run_migrations(&self.db, MIGRATIONS).await?;
+        self.move_attachments_to_disk().await
+    }
 
-        let (password_salt, ): (String, ) = sqlx::query_as("SELECT value FROM config WHERE key='password_salt'")
-            .fetch_one(&mut self.db).await?;
+    /// Moves attachments stored as BLOBs by older server versions to the attachment store.
+    ///
+    /// # Returns
+    ///
+    /// * `EmptyResult` - Returns an empty result if successful.
+    async fn move_attachments_to_disk(&self) -> EmptyResult {
+        let ids: Vec<i64> = sqlx::query_scalar("SELECT messages_id FROM messages WHERE content IS NOT NULL")
+            .fetch_all(&self.db).await?;
 
-        self.password_salt = Some(SaltString::from_b64(&password_salt)
-            .map_err(|e| anyhow!(e))?);
+        if ids.is_empty() {
+            return Ok(());
+        }
 
-        log::info!("Loaded password salt: {password_salt}");
+        tracing::warn!("Moving {} attachments from the database to the attachment store.", ids.len());
+        for id in ids {
+            let data: Vec<u8> = sqlx::query_scalar("SELECT content FROM messages WHERE messages_id=$1")
+                .bind(id)
+                .fetch_one(&self.db).await?;
+            let hash = self.attachments.store(&data).await?;
 
+            sqlx::query("UPDATE messages SET content=NULL, attachment_hash=$1, attachment_size=$2 WHERE messages_id=$3")
+                .bind(hash).bind(data.len() as i64).bind(id)
+                .execute(&self.db).await?;
+        }
         Ok(())
     }
 
-    /// Checks user authentication by verifying the password.
+    /// Checks user authentication by verifying the password. Unknown users fail like wrong passwords,
+    /// so that their failed logins are recorded and locked out the same way.
+    /// Passwords hashed with the legacy global salt are rehashed with a fresh salt after a successful login.
     ///
     /// # Arguments
     ///
@@ -129,24 +299,39 @@ impl ServerDatabase {
     /// # Returns
     ///
     /// * `Result<bool>` - Returns a result containing a boolean indicating if authentication was successful.
-    pub async fn check_auth(&mut self, username: &str, password: &str) -> Result<bool> {
-        let argon = Argon2::default();
-        let hash: Option<String> = sqlx::query_scalar(
+    pub async fn check_auth(&self, username: &str, password: &str) -> Result<bool> {
+        let row: Option<(String, bool)> = sqlx::query_as(
             "
-            SELECT password FROM users WHERE username=$1
+            SELECT password, needs_rehash FROM users WHERE username=$1
             "
         ).bind(username)
-        .fetch_optional(&mut self.db).await?;
+        .fetch_optional(&self.db).await?;
 
-        
-        let hash = hash.context("No such user in the database.")?;
-        let hash = PasswordHash::new(&hash).map_err(|e| anyhow!(e))?;
+        let Some((hash, needs_rehash)) = row else {
+            return Ok(false);
+        };
+        // Argon2 is slow on purpose, so it runs on a blocking thread and doesn't hold up other tasks
+        let password = password.to_string();
+        let verified = tokio::task::spawn_blocking(move || -> Result<Option<Option<String>>> {
+            let hash = PasswordHash::new(&hash).map_err(|e| anyhow!(e))?;
+            if Argon2::default().verify_password(password.as_bytes(), &hash).is_err() {
+                return Ok(None);
+            }
+            let rehashed = needs_rehash.then(|| hash_password(&password)).transpose()?;
+            Ok(Some(rehashed))
+        }).await??;
 
-        if let Ok(_) = argon.verify_password(password.as_bytes(), &hash) {
-            return Ok(true);
-        } else {
+        let Some(rehashed) = verified else {
             return Ok(false);
+        };
+        if let Some(rehashed) = rehashed {
+            tracing::info!("Rehashing the password of {username} with a per-user salt.");
+            sqlx::query("UPDATE users SET password=$1, needs_rehash=0 WHERE username=$2")
+                .bind(rehashed).bind(username)
+                .execute(&self.db).await?;
         }
+
+        Ok(true)
     }
 
     /// Registers a new user with a username and password.
@@ -159,96 +344,1609 @@ impl ServerDatabase {
     /// # Returns
     ///
     /// * `EmptyResult` - Returns an empty result if successful.
-    pub async fn register_user(&mut self, username: &str, password: &str) -> EmptyResult {
-        let argon = Argon2::default();
-        let password_salt = self.password_salt.as_ref()
-            .context("Password salt must be defined")?;
-
-        let hash = argon.hash_password(password.as_bytes(), password_salt.as_salt());
-        if let Ok(hash) = hash {
-            let hash = hash.serialize();
-            let hash = hash.as_str();
-            log::debug!("Hashed password {hash}");
-            sqlx::query(
+    pub async fn register_user(&self, username: &str, password: &str) -> EmptyResult {
+        if chat::is_guest(username) {
+            return Err(anyhow!("Usernames starting with {} are reserved for guests.", chat::GUEST_PREFIX));
+        }
+
+        let nickname_taken: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM users WHERE nickname=$1 COLLATE NOCASE")
+            .bind(username)
+            .fetch_one(&self.db).await?;
+        if nickname_taken > 0 {
+            return Err(anyhow!("The username is used as a nickname by another user."));
+        }
+
+        // Hashed on a blocking thread like the passwords checked by check_auth
+        let password = password.to_string();
+        let hash = tokio::task::spawn_blocking(move || hash_password(&password)).await??;
+        tracing::debug!("Hashed password {hash}");
+        sqlx::query(
+            "
+            INSERT INTO users(username, password) VALUES ($1,$2)
+            "
+        ).bind(username).bind(hash)
+        .execute(&self.db).await?;
+        Ok(())
+    }
+
+    /// Adds a user whose password is checked by another authentication backend, unless the user exists.
+    /// The stored password is random, so the user can't log in when the server goes back to the database backend.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - A string slice that holds the username.
+    ///
+    /// # Returns
+    ///
+    /// * `EmptyResult` - Returns an empty result if the user exists or was added.
+    pub async fn add_external_user(&self, username: &str) -> EmptyResult {
+        if self.user_exists(username).await? {
+            return Ok(());
+        }
+        tracing::info!("Adding {username}, who was authenticated by another backend, to the database.");
+        self.register_user(username, &format!("{:032x}", rand::random::<u128>())).await
+    }
+
+    /// Registers many users at once in a single transaction. Either all users are created or,
+    /// if any of them can't be, none.
+    ///
+    /// # Arguments
+    ///
+    /// * `users` - The usernames and passwords of the new users.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Vec<(usize, String)>>` - Returns the indexes of the users which could not be created with the reasons,
+    ///   empty if all were created.
+    pub async fn import_users(&self, users: &[(String, NewPassword)]) -> Result<Vec<(usize, String)>> {
+        let mut trans = self.db.begin().await?;
+        let mut errors = Vec::new();
+
+        for (index, (username, password)) in users.iter().enumerate() {
+            if chat::is_guest(username) {
+                errors.push((index, format!("usernames starting with {} are reserved for guests", chat::GUEST_PREFIX)));
+                continue;
+            }
+            let hash = match password {
+                NewPassword::Plain(password) => hash_password(password)?,
+                NewPassword::Hashed(hash) => match PasswordHash::new(hash) {
+                    Ok(parsed) if parsed.algorithm.as_str().starts_with("argon2") => hash.clone(),
+                    Ok(parsed) => {
+                        errors.push((index, format!("unsupported password hash algorithm {}", parsed.algorithm)));
+                        continue;
+                    },
+                    Err(e) => {
+                        errors.push((index, format!("invalid password hash: {e}")));
+                        continue;
+                    },
+                },
+            };
+
+            let result = sqlx::query(
                 "
-                INSERT INTO users(username, password) VALUES ($1,$2)
+                INSERT INTO users(username, password)
+                SELECT $1, $2 WHERE NOT EXISTS (SELECT 1 FROM users WHERE nickname=$1 COLLATE NOCASE)
                 "
             ).bind(username).bind(hash)
-            .execute(&mut self.db).await?;
-            Ok(())
-        } else {
-            Err(anyhow!("Failed to hash password."))
+            .execute(&mut *trans).await;
+            match result {
+                Ok(result) if result.rows_affected() == 0 => errors.push((index, "the username is used as a nickname by another user".to_string())),
+                Ok(_) => {},
+                Err(sqlx::Error::Database(e)) if e.is_unique_violation() => errors.push((index, format!("user {username} already exists"))),
+                Err(e) => Err(e)?,
+            }
+        }
+
+        if errors.is_empty() {
+            trans.commit().await?;
         }
+        Ok(errors)
     }
 
-    /// Stores a chat message in the database.
+    /// Replaces the password of a user.
     ///
     /// # Arguments
     ///
-    /// * `message` - A reference to a `ChatMessage` containing the message details.
+    /// * `username` - A string slice that holds the username.
+    /// * `password` - A string slice that holds the new password.
     ///
     /// # Returns
     ///
     /// * `EmptyResult` - Returns an empty result if successful.
-    pub async fn store_message(&mut self, message: &ChatMessage) -> EmptyResult {
+    pub async fn change_password(&self, username: &str, password: &str) -> EmptyResult {
+        let result = sqlx::query("UPDATE users SET password=$1, needs_rehash=0 WHERE username=$2")
+            .bind(hash_password(password)?).bind(username)
+            .execute(&self.db).await?;
 
-        match &message.content {
-            ChatMessageContent::Text(txt) => {
-                sqlx::query(
-                    "
-                    INSERT INTO messages (sender, text, content_type)
-                    VALUES ($1, $2, 1)
-                    "
-                )
-                .bind(&message.sender).bind(txt)
-                .execute(&mut self.db).await?;
-                
-            },
-            ChatMessageContent::Image(data) => {
-                sqlx::query(
-                    "
-                    INSERT INTO messages (sender, content, content_type)
-                    VALUES ($1, $2, 2)
-                    ",
+        if result.rows_affected() == 0 {
+            return Err(anyhow!("No such user in the database."));
+        }
+        Ok(())
+    }
 
-                )
-                .bind(&message.sender).bind(data)
-                .execute(&mut self.db).await?;
+    /// Deletes a user account together with its message history, ban, block list and profile.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - A string slice that holds the username.
+    ///
+    /// # Returns
+    ///
+    /// * `EmptyResult` - Returns an empty result if successful.
+    pub async fn delete_user(&self, username: &str) -> EmptyResult {
+        let mut trans = self.db.begin().await?;
 
-            },
-            ChatMessageContent::File(filename, data) => {
-                sqlx::query(
-                    "
-                    INSERT INTO messages (sender, filename, content, content_type)
-                    VALUES ($1, $2, $3, 3)
-                    ",
-                ).bind(&message.sender).bind(filename).bind(data)
-                .execute(&mut self.db).await?;
-            },
+        sqlx::query("DELETE FROM messages WHERE sender=$1")
+            .bind(username)
+            .execute(&mut *trans).await?;
+        sqlx::query("DELETE FROM bans WHERE username=$1")
+            .bind(username)
+            .execute(&mut *trans).await?;
+        sqlx::query("DELETE FROM read_receipts WHERE username=$1")
+            .bind(username)
+            .execute(&mut *trans).await?;
+        sqlx::query("DELETE FROM blocks WHERE blocker=$1 OR blocked=$1")
+            .bind(username)
+            .execute(&mut *trans).await?;
+        sqlx::query("DELETE FROM client_certificates WHERE username=$1")
+            .bind(username)
+            .execute(&mut *trans).await?;
+        sqlx::query("DELETE FROM profiles WHERE username=$1")
+            .bind(username)
+            .execute(&mut *trans).await?;
+        let result = sqlx::query("DELETE FROM users WHERE username=$1")
+            .bind(username)
+            .execute(&mut *trans).await?;
+
+        if result.rows_affected() == 0 {
+            return Err(anyhow!("No such user in the database."));
         }
+        trans.commit().await?;
         Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use crate::ServerDatabase;
+    /// Grants or revokes the admin role of a user.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - A string slice that holds the username.
+    /// * `admin` - Whether the user should be an admin.
+    ///
+    /// # Returns
+    ///
+    /// * `EmptyResult` - Returns an empty result if successful.
+    pub async fn set_admin(&self, username: &str, admin: bool) -> EmptyResult {
+        let result = sqlx::query("UPDATE users SET admin=$1 WHERE username=$2")
+            .bind(admin).bind(username)
+            .execute(&self.db).await?;
 
-    #[tokio::test]
-    async fn test_registration_and_login() {
-        let dbfile = tempfile::tempdir().unwrap().into_path().join("test.db");
-        let dbfile = dbfile.as_os_str().to_str().unwrap();
-        
-        let server_database = ServerDatabase::new(dbfile).await;
-        assert!(server_database.is_ok());
-        let mut server_database = server_database.unwrap();
-        assert!(server_database.register_user("Alice", "aaa").await.is_ok());
-        assert!(server_database.register_user("Bob", "bbb").await.is_ok());
+        if result.rows_affected() == 0 {
+            return Err(anyhow!("No such user in the database."));
+        }
+        Ok(())
+    }
+
+    /// Sets or removes the TOTP secret of a user, which enables or disables their two-factor authentication.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - A string slice that holds the username.
+    /// * `secret` - The base32 encoded secret, `None` disables two-factor authentication.
+    ///
+    /// # Returns
+    ///
+    /// * `EmptyResult` - Returns an empty result if successful.
+    pub async fn set_totp_secret(&self, username: &str, secret: Option<&str>) -> EmptyResult {
+        let result = sqlx::query("UPDATE users SET totp_secret=$1 WHERE username=$2")
+            .bind(secret).bind(username)
+            .execute(&self.db).await?;
 
+        if result.rows_affected() == 0 {
+            return Err(anyhow!("No such user in the database."));
+        }
+        Ok(())
+    }
 
-        assert!(matches!(server_database.check_auth("Alice", "aaa").await, Ok(true)));
-        assert!(matches!(server_database.check_auth("Alice", "bbb").await, Ok(false)));
-        assert!(matches!(server_database.check_auth("Catie", "aaa").await, Err(_)));
+    /// Returns the TOTP secret of a user.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - A string slice that holds the username.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<String>>` - Returns the base32 encoded secret, `None` if the user doesn't use two-factor authentication.
+    pub async fn totp_secret(&self, username: &str) -> Result<Option<String>> {
+        let secret: Option<Option<String>> = sqlx::query_scalar("SELECT totp_secret FROM users WHERE username=$1")
+            .bind(username)
+            .fetch_optional(&self.db).await?;
+        Ok(secret.flatten())
+    }
+
+    /// Checks whether a user has the admin role.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - A string slice that holds the username.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<bool>` - Returns a result containing `true` if the user is an admin.
+    pub async fn is_admin(&self, username: &str) -> Result<bool> {
+        let admin: Option<bool> = sqlx::query_scalar("SELECT admin FROM users WHERE username=$1")
+            .bind(username)
+            .fetch_optional(&self.db).await?;
+        Ok(admin.unwrap_or(false))
+    }
+
+    /// Checks whether a user is registered.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - A string slice that holds the username.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<bool>` - Returns a result containing `true` if the user exists.
+    pub async fn user_exists(&self, username: &str) -> Result<bool> {
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM users WHERE username=$1")
+            .bind(username)
+            .fetch_one(&self.db).await?;
+        Ok(count > 0)
+    }
+
+    /// Sets or removes the nickname of a user. A nickname must differ from the usernames
+    /// and nicknames of all other users, ignoring case.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - A string slice that holds the username.
+    /// * `nickname` - The new nickname, `None` removes it.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<bool>` - Returns a result containing `false` if the nickname is taken.
+    pub async fn set_nickname(&self, username: &str, nickname: Option<&str>) -> Result<bool> {
+        let mut trans = self.db.begin().await?;
+
+        if let Some(nickname) = nickname {
+            let taken: i64 = sqlx::query_scalar(
+                "
+                SELECT COUNT(*) FROM users
+                WHERE (username=$1 COLLATE NOCASE OR nickname=$1 COLLATE NOCASE) AND username<>$2
+                "
+            ).bind(nickname).bind(username)
+            .fetch_one(&mut *trans).await?;
+            if taken > 0 {
+                return Ok(false);
+            }
+        }
+
+        let result = sqlx::query("UPDATE users SET nickname=$1 WHERE username=$2")
+            .bind(nickname).bind(username)
+            .execute(&mut *trans).await?;
+        if result.rows_affected() == 0 {
+            return Err(anyhow!("No such user in the database."));
+        }
+
+        trans.commit().await?;
+        Ok(true)
+    }
+
+    /// Returns the nickname of a user.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - A string slice that holds the username.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<String>>` - Returns a result containing the nickname, `None` if the user has none.
+    pub async fn nickname(&self, username: &str) -> Result<Option<String>> {
+        let nickname: Option<Option<String>> = sqlx::query_scalar("SELECT nickname FROM users WHERE username=$1")
+            .bind(username)
+            .fetch_optional(&self.db).await?;
+        Ok(nickname.flatten())
+    }
+
+    /// Lists all registered users.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Vec<UserRecord>>` - Returns the users sorted by their username.
+    pub async fn list_users(&self) -> Result<Vec<UserRecord>> {
+        let rows: Vec<(String, Option<String>, bool, bool)> = sqlx::query_as(
+            "
+            SELECT users.username, nickname, admin, bans.username IS NOT NULL FROM users
+            LEFT JOIN bans ON bans.username=users.username
+            ORDER BY users.username
+            "
+        ).fetch_all(&self.db).await?;
+
+        Ok(rows.into_iter()
+            .map(|(username, nickname, admin, banned)| UserRecord { username, nickname, admin, banned })
+            .collect())
+    }
+
+    /// Permanently bans a user.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - A string slice that holds the username of the banned user.
+    /// * `banned_by` - A string slice that holds the username of the admin issuing the ban.
+    ///
+    /// # Returns
+    ///
+    /// * `EmptyResult` - Returns an empty result if successful.
+    pub async fn ban_user(&self, username: &str, banned_by: &str) -> EmptyResult {
+        let exists: Option<String> = sqlx::query_scalar("SELECT username FROM users WHERE username=$1")
+            .bind(username)
+            .fetch_optional(&self.db).await?;
+        exists.context("No such user in the database.")?;
+
+        sqlx::query("INSERT OR REPLACE INTO bans (username, banned_by) VALUES ($1, $2)")
+            .bind(username).bind(banned_by)
+            .execute(&self.db).await?;
+        Ok(())
+    }
+
+    /// Lifts the ban of a user.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - A string slice that holds the username.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<bool>` - Returns a result containing `false` if the user was not banned.
+    pub async fn unban_user(&self, username: &str) -> Result<bool> {
+        let result = sqlx::query("DELETE FROM bans WHERE username=$1")
+            .bind(username)
+            .execute(&self.db).await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Checks whether a user is banned.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - A string slice that holds the username.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<bool>` - Returns a result containing `true` if the user is banned.
+    pub async fn is_banned(&self, username: &str) -> Result<bool> {
+        let banned: Option<String> = sqlx::query_scalar("SELECT username FROM bans WHERE username=$1")
+            .bind(username)
+            .fetch_optional(&self.db).await?;
+        Ok(banned.is_some())
+    }
+
+    /// Adds a user to the block list of another user.
+    ///
+    /// # Arguments
+    ///
+    /// * `blocker` - A string slice that holds the username of the blocking user.
+    /// * `blocked` - A string slice that holds the username of the blocked user.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<bool>` - Returns a result containing `false` if the user was already blocked.
+    pub async fn block_user(&self, blocker: &str, blocked: &str) -> Result<bool> {
+        let result = sqlx::query("INSERT OR IGNORE INTO blocks (blocker, blocked) VALUES ($1, $2)")
+            .bind(blocker).bind(blocked)
+            .execute(&self.db).await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Removes a user from the block list of another user.
+    ///
+    /// # Arguments
+    ///
+    /// * `blocker` - A string slice that holds the username of the blocking user.
+    /// * `blocked` - A string slice that holds the username of the blocked user.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<bool>` - Returns a result containing `false` if the user was not blocked.
+    pub async fn unblock_user(&self, blocker: &str, blocked: &str) -> Result<bool> {
+        let result = sqlx::query("DELETE FROM blocks WHERE blocker=$1 AND blocked=$2")
+            .bind(blocker).bind(blocked)
+            .execute(&self.db).await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Registers a client certificate, so the user can log in with it on the TLS port without a password.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - A string slice that holds the username.
+    /// * `fingerprint` - The SHA-256 fingerprint of the certificate.
+    ///
+    /// # Returns
+    ///
+    /// * `EmptyResult` - Returns an empty result if successful, or an error if the user doesn't exist
+    ///   or the certificate is already registered.
+    pub async fn add_certificate(&self, username: &str, fingerprint: &str) -> EmptyResult {
+        if !self.user_exists(username).await? {
+            return Err(anyhow!("No such user in the database."));
+        }
+        sqlx::query("INSERT INTO client_certificates (fingerprint, username) VALUES ($1, $2)")
+            .bind(fingerprint).bind(username)
+            .execute(&self.db).await
+            .map_err(|_| anyhow!("The certificate {fingerprint} is already registered."))?;
+        Ok(())
+    }
+
+    /// Removes a client certificate.
+    ///
+    /// # Arguments
+    ///
+    /// * `fingerprint` - The SHA-256 fingerprint of the certificate.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<bool>` - Returns a result containing `false` if the certificate wasn't registered.
+    pub async fn remove_certificate(&self, fingerprint: &str) -> Result<bool> {
+        let result = sqlx::query("DELETE FROM client_certificates WHERE fingerprint=$1")
+            .bind(fingerprint)
+            .execute(&self.db).await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Finds the user a client certificate is registered for.
+    ///
+    /// # Arguments
+    ///
+    /// * `fingerprint` - The SHA-256 fingerprint of the certificate.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<String>>` - Returns the username, `None` if the certificate isn't registered.
+    pub async fn certificate_owner(&self, fingerprint: &str) -> Result<Option<String>> {
+        let owner = sqlx::query_scalar("SELECT username FROM client_certificates WHERE fingerprint=$1")
+            .bind(fingerprint)
+            .fetch_optional(&self.db).await?;
+        Ok(owner)
+    }
+
+    /// Lists the registered client certificates.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Vec<(String, String)>>` - Returns pairs of the username and the fingerprint, ordered by username.
+    pub async fn certificates(&self) -> Result<Vec<(String, String)>> {
+        let certificates = sqlx::query_as("SELECT username, fingerprint FROM client_certificates ORDER BY username, added_at")
+            .fetch_all(&self.db).await?;
+        Ok(certificates)
+    }
+
+    /// Lists the block lists of all users, loaded once when the server starts.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Vec<(String, String)>>` - Returns pairs of the blocking and the blocked username.
+    pub async fn blocks(&self) -> Result<Vec<(String, String)>> {
+        let blocks = sqlx::query_as("SELECT blocker, blocked FROM blocks ORDER BY blocker, blocked")
+            .fetch_all(&self.db).await?;
+        Ok(blocks)
+    }
+
+    /// Changes the profile of a user. `None` leaves a field as it is, an empty status or avatar removes it.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - The username of the user.
+    /// * `status` - The new status line.
+    /// * `avatar` - The new avatar encoded as PNG.
+    ///
+    /// # Returns
+    ///
+    /// * `EmptyResult` - Returns an empty result if successful.
+    pub async fn set_profile(&self, username: &str, status: Option<&str>, avatar: Option<&[u8]>) -> EmptyResult {
+        let mut trans = self.db.begin().await?;
+        sqlx::query("INSERT INTO profiles (username) VALUES ($1) ON CONFLICT(username) DO NOTHING")
+            .bind(username)
+            .execute(&mut *trans).await?;
+        if let Some(status) = status {
+            sqlx::query("UPDATE profiles SET status=$1, updated_at=CURRENT_TIMESTAMP WHERE username=$2")
+                .bind((!status.is_empty()).then_some(status)).bind(username)
+                .execute(&mut *trans).await?;
+        }
+        if let Some(avatar) = avatar {
+            sqlx::query("UPDATE profiles SET avatar=$1, updated_at=CURRENT_TIMESTAMP WHERE username=$2")
+                .bind((!avatar.is_empty()).then_some(avatar)).bind(username)
+                .execute(&mut *trans).await?;
+        }
+        // An empty profile isn't kept
+        sqlx::query("DELETE FROM profiles WHERE username=$1 AND status IS NULL AND avatar IS NULL")
+            .bind(username)
+            .execute(&mut *trans).await?;
+        trans.commit().await?;
+        Ok(())
+    }
+
+    /// Loads the profile of a user.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - The username of the user.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<UserProfile>` - Returns the profile, empty if the user has none or doesn't exist.
+    pub async fn profile(&self, username: &str) -> Result<UserProfile> {
+        let profile: Option<(Option<String>, Option<Vec<u8>>)> = sqlx::query_as("SELECT status, avatar FROM profiles WHERE username=$1")
+            .bind(username)
+            .fetch_optional(&self.db).await?;
+        let (status, avatar_png) = profile.unwrap_or_default();
+        Ok(UserProfile { username: username.to_string(), status, avatar_png })
+    }
+
+    /// Loads the status lines of some users, without their avatars.
+    ///
+    /// # Arguments
+    ///
+    /// * `usernames` - The usernames of the users.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Vec<UserStatus>>` - Returns the users in the given order with their status lines.
+    pub async fn statuses(&self, usernames: Vec<String>) -> Result<Vec<UserStatus>> {
+        let mut statuses: std::collections::HashMap<String, String> = sqlx::query_as("SELECT username, status FROM profiles WHERE status IS NOT NULL")
+            .fetch_all(&self.db).await?
+            .into_iter().collect();
+        Ok(usernames.into_iter().map(|username| UserStatus { status: statuses.remove(&username), username }).collect())
+    }
+
+    /// Deletes messages older than the given time, together with the attachments which are no longer referenced.
+    /// Messages stored by old server versions without a timestamp are kept.
+    ///
+    /// # Arguments
+    ///
+    /// * `before` - Messages with an older timestamp are deleted.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(u64, usize, u64)>` - Returns the number of deleted messages and attachments and the size of the attachments in bytes.
+    pub async fn prune_messages(&self, before: DateTime<Utc>) -> Result<(u64, usize, u64)> {
+        let result = sqlx::query("DELETE FROM messages WHERE timestamp < $1")
+            .bind(before)
+            .execute(&self.db).await?;
+
+        let referenced: Vec<String> = sqlx::query_scalar("SELECT DISTINCT attachment_hash FROM messages WHERE attachment_hash IS NOT NULL")
+            .fetch_all(&self.db).await?;
+        let (files, bytes) = self.attachments
+            .remove_unreferenced(&referenced.into_iter().collect(), ORPHAN_GRACE_PERIOD).await?;
+
+        Ok((result.rows_affected(), files, bytes))
+    }
+
+    /// Deletes the ephemeral messages which expired.
+    ///
+    /// # Arguments
+    ///
+    /// * `now` - Messages expiring at this time or earlier are deleted.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Vec<(String, MessageId, MessageSeq)>>` - Returns the sender, the ID given by the client and the sequence
+    ///   number of every deleted message.
+    pub async fn delete_expired_messages(&self, now: DateTime<Utc>) -> Result<Vec<(String, MessageId, MessageSeq)>> {
+        let deleted: Vec<(String, Option<i64>, i64)> = sqlx::query_as("DELETE FROM messages WHERE expires_at <= $1 RETURNING sender, client_id, messages_id")
+            .bind(now)
+            .fetch_all(&self.db).await?;
+        Ok(deleted.into_iter().filter_map(|(sender, client_id, seq)| Some((sender, client_id? as MessageId, seq as MessageSeq))).collect())
+    }
+
+    /// Stores a chat message in the database.
+    ///
+    /// # Arguments
+    ///
+    /// * `message` - A reference to a `ChatMessage` containing the message details.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<MessageSeq>` - Returns the sequence number of the stored message, which also identifies its attachment.
+    pub async fn store_message(&self, message: &ChatMessage) -> Result<MessageSeq> {
+        let reply_sender = message.reply_to.as_ref().map(|reply_to| reply_to.sender.as_str());
+        let reply_id = message.reply_to.as_ref().map(|reply_to| reply_to.id as i64);
+
+        let result = match &message.content {
+            ChatMessageContent::Text(txt) => {
+                sqlx::query(
+                    "
+                    INSERT INTO messages (sender, timestamp, text, content_type, reply_sender, reply_id, client_id, announcement, expires_at)
+                    VALUES ($1, $2, $3, 1, $4, $5, $6, $7, $8)
+                    "
+                )
+                .bind(&message.sender).bind(message.timestamp).bind(txt).bind(reply_sender).bind(reply_id).bind(message.id as i64).bind(message.announcement)
+                .bind(message.ttl.map(|ttl| message.timestamp + chrono::Duration::seconds(ttl.into())))
+                .execute(&self.db).await?
+            },
+            ChatMessageContent::Image(data) => {
+                let hash = self.attachments.store(data).await?;
+                sqlx::query(
+                    "
+                    INSERT INTO messages (sender, timestamp, attachment_hash, attachment_size, content_type, reply_sender, reply_id, client_id)
+                    VALUES ($1, $2, $3, $4, 2, $5, $6, $7)
+                    ",
+
+                )
+                .bind(&message.sender).bind(message.timestamp).bind(hash).bind(data.len() as i64).bind(reply_sender).bind(reply_id).bind(message.id as i64)
+                .execute(&self.db).await?
+            },
+            ChatMessageContent::File(filename, data) => {
+                let hash = self.attachments.store(data).await?;
+                sqlx::query(
+                    "
+                    INSERT INTO messages (sender, timestamp, filename, attachment_hash, attachment_size, content_type, reply_sender, reply_id, client_id)
+                    VALUES ($1, $2, $3, $4, $5, 3, $6, $7, $8)
+                    ",
+                ).bind(&message.sender).bind(message.timestamp).bind(filename).bind(hash).bind(data.len() as i64).bind(reply_sender).bind(reply_id).bind(message.id as i64)
+                .execute(&self.db).await?
+            },
+            // The format is kept in the filename column
+            ChatMessageContent::Audio { format, data } => {
+                let hash = self.attachments.store(data).await?;
+                sqlx::query(
+                    "
+                    INSERT INTO messages (sender, timestamp, filename, attachment_hash, attachment_size, content_type, reply_sender, reply_id, client_id)
+                    VALUES ($1, $2, $3, $4, $5, 4, $6, $7, $8)
+                    ",
+                ).bind(&message.sender).bind(message.timestamp).bind(format.extension()).bind(hash).bind(data.len() as i64).bind(reply_sender).bind(reply_id).bind(message.id as i64)
+                .execute(&self.db).await?
+            },
+            // Previews follow stored messages, they're made again when needed
+            ChatMessageContent::LinkPreview { .. } => return Err(anyhow!("Link previews aren't stored.")),
+        };
+        Ok(result.last_insert_rowid() as MessageSeq)
+    }
+
+    /// Loads stored messages, oldest first.
+    ///
+    /// # Arguments
+    ///
+    /// * `since` - Only messages which arrived after this time are loaded, `None` loads the most recent ones.
+    /// * `limit` - The maximum number of messages.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Vec<MessageRecord>>` - Returns a result containing the messages.
+    pub async fn messages(&self, since: Option<DateTime<Utc>>, limit: u32) -> Result<Vec<MessageRecord>> {
+        let rows: Vec<MessageRow> = match since {
+            Some(since) => sqlx::query_as(
+                "
+                SELECT messages_id, sender, timestamp, content_type, text, filename, attachment_size, reply_sender, reply_id, client_id, announcement, expires_at FROM messages
+                WHERE timestamp > $1 ORDER BY messages_id LIMIT $2
+                "
+            ).bind(since).bind(limit)
+            .fetch_all(&self.db).await?,
+            None => sqlx::query_as(
+                "
+                SELECT * FROM (
+                    SELECT messages_id, sender, timestamp, content_type, text, filename, attachment_size, reply_sender, reply_id, client_id, announcement, expires_at FROM messages
+                    ORDER BY messages_id DESC LIMIT $1
+                ) ORDER BY messages_id
+                "
+            ).bind(limit)
+            .fetch_all(&self.db).await?,
+        };
+        Ok(rows.into_iter().map(message_record).collect())
+    }
+
+    /// Loads the messages stored after a message, oldest first. Message IDs are never reused,
+    /// so these are exactly the messages a client missed after it received the message `last_id`.
+    ///
+    /// # Arguments
+    ///
+    /// * `last_id` - The ID of the last known message, 0 loads the oldest messages.
+    /// * `limit` - The maximum number of messages.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Vec<MessageRecord>>` - Returns a result containing the messages.
+    pub async fn messages_after(&self, last_id: MessageSeq, limit: u32) -> Result<Vec<MessageRecord>> {
+        let rows: Vec<MessageRow> = sqlx::query_as(
+            "
+            SELECT messages_id, sender, timestamp, content_type, text, filename, attachment_size, reply_sender, reply_id, client_id, announcement, expires_at FROM messages
+            WHERE messages_id > $1 ORDER BY messages_id LIMIT $2
+            "
+        ).bind(last_id as i64).bind(limit)
+        .fetch_all(&self.db).await?;
+        Ok(rows.into_iter().map(message_record).collect())
+    }
+
+    /// Counts the stored chat messages.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<u64>` - Returns the number of messages.
+    pub async fn message_count(&self) -> Result<u64> {
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM messages")
+            .fetch_one(&self.db).await?;
+        Ok(count as u64)
+    }
+
+    /// Returns the size of the database, without the attachment files stored next to it.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<u64>` - Returns the size in bytes.
+    pub async fn size(&self) -> Result<u64> {
+        let size: i64 = sqlx::query_scalar("SELECT page_count * page_size FROM pragma_page_count(), pragma_page_size()")
+            .fetch_one(&self.db).await?;
+        Ok(size as u64)
+    }
+
+    /// Returns the size of the stored attachments sent by a user. An attachment sent twice counts twice,
+    /// even though its data is stored once.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - The username of the sender.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<u64>` - Returns the size in bytes.
+    pub async fn storage_used(&self, username: &str) -> Result<u64> {
+        let bytes: i64 = sqlx::query_scalar("SELECT COALESCE(SUM(attachment_size), 0) FROM messages WHERE sender=$1 AND attachment_hash IS NOT NULL")
+            .bind(username)
+            .fetch_one(&self.db).await?;
+        Ok(bytes as u64)
+    }
+
+    /// Lists the attachment storage used by every registered user.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Vec<StorageUsage>>` - Returns the users, those using the most storage first.
+    pub async fn storage_usage(&self) -> Result<Vec<StorageUsage>> {
+        let rows: Vec<(String, i64, i64)> = sqlx::query_as(
+            "
+            SELECT users.username, COUNT(messages.attachment_hash), COALESCE(SUM(messages.attachment_size), 0) FROM users
+            LEFT JOIN messages ON messages.sender=users.username AND messages.attachment_hash IS NOT NULL
+            GROUP BY users.username
+            ORDER BY 3 DESC, users.username
+            "
+        ).fetch_all(&self.db).await?;
+
+        Ok(rows.into_iter()
+            .map(|(username, attachments, bytes)| StorageUsage { username, attachments: attachments as u64, bytes: bytes as u64 })
+            .collect())
+    }
+
+    /// Records that a user has read the messages which arrived up to the given time.
+    /// The position only moves forward, reports of older messages are ignored.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - The username of the reader.
+    /// * `up_to` - Arrival time of the newest message the user has read.
+    /// * `now` - The time of the report.
+    ///
+    /// # Returns
+    ///
+    /// * `EmptyResult` - Returns an empty result if successful.
+    pub async fn mark_read(&self, username: &str, up_to: DateTime<Utc>, now: DateTime<Utc>) -> EmptyResult {
+        let last_read_id: Option<i64> = sqlx::query_scalar("SELECT MAX(messages_id) FROM messages WHERE timestamp <= $1")
+            .bind(up_to)
+            .fetch_one(&self.db).await?;
+        let Some(last_read_id) = last_read_id else {
+            return Ok(());
+        };
+
+        sqlx::query(
+            "
+            INSERT INTO read_receipts(username, last_read_id, read_at) VALUES ($1, $2, $3)
+            ON CONFLICT(username) DO UPDATE SET last_read_id=excluded.last_read_id, read_at=excluded.read_at
+            WHERE excluded.last_read_id > read_receipts.last_read_id
+            "
+        ).bind(username).bind(last_read_id).bind(now)
+        .execute(&self.db).await?;
+        Ok(())
+    }
+
+    /// Looks up when a user last read new messages.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - The username of the reader.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<(MessageSeq, DateTime<Utc>)>>` - Returns the sequence number of the last read message and the time
+    ///   when it was read, `None` if the user never read anything.
+    pub async fn last_read(&self, username: &str) -> Result<Option<(MessageSeq, DateTime<Utc>)>> {
+        let row: Option<(i64, DateTime<Utc>)> = sqlx::query_as("SELECT last_read_id, read_at FROM read_receipts WHERE username=$1")
+            .bind(username)
+            .fetch_optional(&self.db).await?;
+        Ok(row.map(|(id, read_at)| (id as MessageSeq, read_at)))
+    }
+
+    /// Records a message caught by the content filter.
+    ///
+    /// # Arguments
+    ///
+    /// * `record` - The filtered message and what was done with it.
+    ///
+    /// # Returns
+    ///
+    /// * `EmptyResult` - Returns an empty result if successful.
+    pub async fn log_filtered(&self, record: &FilteredRecord) -> EmptyResult {
+        sqlx::query("INSERT INTO filtered_messages(sender, recipient, timestamp, text, action, rules) VALUES ($1, $2, $3, $4, $5, $6)")
+            .bind(&record.sender)
+            .bind(&record.recipient)
+            .bind(record.timestamp)
+            .bind(&record.text)
+            .bind(&record.action)
+            .bind(&record.rules)
+            .execute(&self.db).await?;
+        Ok(())
+    }
+
+    /// Lists the most recent messages caught by the content filter, oldest first.
+    ///
+    /// # Arguments
+    ///
+    /// * `limit` - The maximum number of records to return.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Vec<FilteredRecord>>` - Returns the filtered messages.
+    pub async fn filtered_messages(&self, limit: u32) -> Result<Vec<FilteredRecord>> {
+        type Row = (String, Option<String>, DateTime<Utc>, String, String, String);
+        let rows: Vec<Row> = sqlx::query_as(
+            "
+            SELECT sender, recipient, timestamp, text, action, rules FROM filtered_messages
+            ORDER BY filtered_id DESC LIMIT $1
+            "
+        ).bind(limit).fetch_all(&self.db).await?;
+
+        Ok(rows.into_iter().rev()
+            .map(|(sender, recipient, timestamp, text, action, rules)| FilteredRecord { sender, recipient, timestamp, text, action, rules })
+            .collect())
+    }
+
+    /// Records a login attempt in the audit log.
+    ///
+    /// # Arguments
+    ///
+    /// * `record` - The login attempt and its outcome.
+    ///
+    /// # Returns
+    ///
+    /// * `EmptyResult` - Returns an empty result if successful.
+    pub async fn log_login(&self, record: &LoginRecord) -> EmptyResult {
+        sqlx::query("INSERT INTO login_audit(username, address, timestamp, outcome) VALUES ($1, $2, $3, $4)")
+            .bind(&record.username)
+            .bind(&record.address)
+            .bind(record.timestamp)
+            .bind(&record.outcome)
+            .execute(&self.db).await?;
+        self.log_event(AuditEvent::Login, Some(&record.username), None, record.address.as_deref(), Some(&record.outcome)).await
+    }
+
+    /// Appends a security-relevant event to the audit log.
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The kind of the event.
+    /// * `actor` - Who caused the event.
+    /// * `target` - The user the event is about.
+    /// * `address` - The IP address of the actor.
+    /// * `detail` - More about the event, e.g. the outcome of a login.
+    ///
+    /// # Returns
+    ///
+    /// * `EmptyResult` - Returns an empty result if successful.
+    pub async fn log_event(&self, event: AuditEvent, actor: Option<&str>, target: Option<&str>, address: Option<&str>, detail: Option<&str>) -> EmptyResult {
+        sqlx::query("INSERT INTO audit_log(timestamp, event, actor, target, address, detail) VALUES ($1, $2, $3, $4, $5, $6)")
+            .bind(Utc::now())
+            .bind(event.to_string())
+            .bind(actor)
+            .bind(target)
+            .bind(address)
+            .bind(detail)
+            .execute(&self.db).await?;
+        Ok(())
+    }
+
+    /// Lists events of the audit log, oldest first.
+    ///
+    /// # Arguments
+    ///
+    /// * `after` - Only events after the one with this ID are listed, the most recent ones if `None`.
+    /// * `limit` - The maximum number of events to return.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Vec<AuditRecord>>` - Returns the events.
+    pub async fn audit_log(&self, after: Option<i64>, limit: u32) -> Result<Vec<AuditRecord>> {
+        type AuditRow = (i64, DateTime<Utc>, String, Option<String>, Option<String>, Option<String>, Option<String>);
+        let columns = "audit_id, timestamp, event, actor, target, address, detail";
+        let rows: Vec<AuditRow> = match after {
+            Some(after) => sqlx::query_as(&format!("SELECT {columns} FROM audit_log WHERE audit_id > $1 ORDER BY audit_id LIMIT $2"))
+                .bind(after).bind(limit).fetch_all(&self.db).await?,
+            None => {
+                let mut rows: Vec<AuditRow> = sqlx::query_as(&format!("SELECT {columns} FROM audit_log ORDER BY audit_id DESC LIMIT $1"))
+                    .bind(limit).fetch_all(&self.db).await?;
+                rows.reverse();
+                rows
+            },
+        };
+
+        Ok(rows.into_iter()
+            .map(|(id, timestamp, event, actor, target, address, detail)| AuditRecord { id, timestamp, event, actor, target, address, detail })
+            .collect())
+    }
+
+    /// Lists the most recent login attempts, oldest first.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - Only the attempts to log in as this user are listed, all if `None`.
+    /// * `limit` - The maximum number of records to return.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Vec<LoginRecord>>` - Returns the login attempts.
+    pub async fn login_audit(&self, username: Option<&str>, limit: u32) -> Result<Vec<LoginRecord>> {
+        let rows: Vec<(String, Option<String>, DateTime<Utc>, String)> = sqlx::query_as(
+            "
+            SELECT username, address, timestamp, outcome FROM login_audit
+            WHERE $1 IS NULL OR username=$1
+            ORDER BY audit_id DESC LIMIT $2
+            "
+        ).bind(username).bind(limit).fetch_all(&self.db).await?;
+
+        Ok(rows.into_iter().rev()
+            .map(|(username, address, timestamp, outcome)| LoginRecord { username, address, timestamp, outcome })
+            .collect())
+    }
+
+    /// Returns the times of the failed logins of a user after `since` and after their last successful login.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - A string slice that holds the username.
+    /// * `since` - Older failures are ignored.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Vec<DateTime<Utc>>>` - Returns the times of the failures.
+    pub async fn failed_logins_of(&self, username: &str, since: DateTime<Utc>) -> Result<Vec<DateTime<Utc>>> {
+        self.failed_logins("username", username, since).await
+    }
+
+    /// Returns the times of the failed logins from an IP address after `since` and after the last successful login from it.
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - The IP address of the clients.
+    /// * `since` - Older failures are ignored.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Vec<DateTime<Utc>>>` - Returns the times of the failures.
+    pub async fn failed_logins_from(&self, address: &str, since: DateTime<Utc>) -> Result<Vec<DateTime<Utc>>> {
+        self.failed_logins("address", address, since).await
+    }
+
+    async fn failed_logins(&self, column: &str, value: &str, since: DateTime<Utc>) -> Result<Vec<DateTime<Utc>>> {
+        let failures = LoginOutcome::FAILURES.map(|outcome| format!("'{outcome}'")).join(", ");
+        Ok(sqlx::query_scalar(&format!(
+            "
+            SELECT timestamp FROM login_audit
+            WHERE {column}=$1 AND outcome IN ({failures}) AND timestamp > $2
+            AND timestamp > COALESCE((SELECT MAX(timestamp) FROM login_audit WHERE {column}=$1 AND outcome='{}'), $2)
+            ", LoginOutcome::Success
+        )).bind(value).bind(since).fetch_all(&self.db).await?)
+    }
+
+    /// Looks up a stored image or file attachment.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The ID of the message carrying the attachment.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<StoredAttachment>>` - Returns a result containing the attachment, `None` if there is no such attachment.
+    pub async fn find_attachment(&self, id: AttachmentId) -> Result<Option<StoredAttachment>> {
+        let row: Option<(String, i64, Option<String>, String, i64)> = sqlx::query_as(
+            "
+            SELECT sender, content_type, filename, attachment_hash, attachment_size FROM messages
+            WHERE messages_id=$1 AND attachment_hash IS NOT NULL
+            "
+        ).bind(id as i64)
+        .fetch_optional(&self.db).await?;
+
+        Ok(row.and_then(|(sender, content_type, filename, hash, size)| Some(StoredAttachment {
+            sender,
+            kind: attachment_kind(content_type, filename)?,
+            path: self.attachments.path(&hash),
+            size: size as u64,
+        })))
+    }
+}
+
+/// Columns of the messages table loaded into a `MessageRecord`.
+type MessageRow = (i64, String, Option<DateTime<Utc>>, i64, Option<String>, Option<String>, Option<i64>, Option<String>, Option<i64>, Option<i64>, bool, Option<DateTime<Utc>>);
+
+/// Converts a row of the messages table into a `MessageRecord`.
+fn message_record((id, sender, timestamp, content_type, text, filename, size, reply_sender, reply_id, client_id, announcement, expires_at): MessageRow) -> MessageRecord {
+    MessageRecord {
+        id: id as AttachmentId,
+        client_id: client_id.map(|client_id| client_id as MessageId),
+        sender,
+        timestamp,
+        text,
+        attachment: attachment_kind(content_type, filename),
+        size: size.map(|size| size as u64),
+        reply_to: reply_sender.zip(reply_id).map(|(sender, id)| ReplyTo { sender, id: id as MessageId }),
+        announcement,
+        expires_at,
+    }
+}
+
+/// Maps the content type of a stored message to the type of its attachment.
+///
+/// # Arguments
+///
+/// * `content_type` - The content type of the row, 1 for text, 2 for images, 3 for files and 4 for voice notes.
+/// * `filename` - The filename column, the name of a file or the extension of a voice note.
+///
+/// # Returns
+///
+/// * `Option<AttachmentKind>` - Returns the type of the attachment, `None` for text messages.
+fn attachment_kind(content_type: i64, filename: Option<String>) -> Option<AttachmentKind> {
+    match content_type {
+        2 => Some(AttachmentKind::Image),
+        3 => Some(AttachmentKind::File(filename.unwrap_or_default())),
+        4 => filename.as_deref().and_then(AudioFormat::from_extension).map(AttachmentKind::Audio),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chat::{AttachmentKind, AudioFormat, ChatMessage, ChatMessageContent, ReplyTo, UserProfile, UserStatus};
+
+    use crate::server_audit::AuditEvent;
+    use crate::server_db::{hash_password, FilteredRecord, LoginRecord, NewPassword, MEMORY_DATABASE};
+    use crate::ServerDatabase;
+
+    #[tokio::test]
+    async fn test_prune_messages() {
+        let dir = tempfile::tempdir().unwrap().keep();
+        let dbfile = dir.join("test.db");
+        let dbfile = dbfile.as_os_str().to_str().unwrap();
+
+        let server_database = ServerDatabase::new(dbfile, &dir.join("attachments")).await.unwrap();
+        assert!(server_database.register_user("Bob", "bbb").await.is_ok());
+
+        let now = chrono::Utc::now();
+        let old_message = ChatMessage {
+            timestamp: now - chrono::Duration::days(10),
+            ..ChatMessage::new(1, "Bob", ChatMessageContent::Text("old".to_string()))
+        };
+        let new_message = ChatMessage {
+            timestamp: now,
+            ..ChatMessage::new(2, "Bob", ChatMessageContent::Text("new".to_string()))
+        };
+        assert!(server_database.store_message(&old_message).await.is_ok());
+        assert!(server_database.store_message(&new_message).await.is_ok());
+
+        let (messages, _, _) = server_database.prune_messages(now - chrono::Duration::days(1)).await.unwrap();
+        assert_eq!(messages, 1);
+        let texts: Vec<String> = sqlx::query_scalar("SELECT text FROM messages")
+            .fetch_all(&server_database.db).await.unwrap();
+        assert_eq!(texts, vec!["new".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_expired_messages() {
+        let unused = tempfile::tempdir().unwrap();
+        let server_database = ServerDatabase::new(MEMORY_DATABASE, unused.path()).await.unwrap();
+        server_database.register_user("Bob", "bbb").await.unwrap();
+        let now = chrono::Utc::now();
+        for (id, ttl) in [(1, Some(60)), (2, None), (3, Some(3600))] {
+            let message = ChatMessage {
+                timestamp: now,
+                ttl,
+                ..ChatMessage::new(id, "Bob", ChatMessageContent::Text(format!("message {id}")))
+            };
+            server_database.store_message(&message).await.unwrap();
+        }
+        assert_eq!(server_database.messages(None, 10).await.unwrap()[0].expires_at, Some(now + chrono::Duration::seconds(60)));
+
+        assert!(server_database.delete_expired_messages(now + chrono::Duration::seconds(59)).await.unwrap().is_empty());
+        let expired = server_database.delete_expired_messages(now + chrono::Duration::seconds(60)).await.unwrap();
+        assert_eq!(expired, vec![("Bob".to_string(), 1, 1)]);
+        // The sequence number identifies the message, the ID of the client may be reused
+        let reused = ChatMessage { timestamp: now, ttl: Some(60), ..ChatMessage::new(3, "Bob", ChatMessageContent::Text("reused".to_string())) };
+        let seq = server_database.store_message(&reused).await.unwrap();
+        assert_eq!(server_database.delete_expired_messages(now + chrono::Duration::seconds(60)).await.unwrap(), vec![("Bob".to_string(), 3, seq)]);
+        let ids: Vec<_> = server_database.messages(None, 10).await.unwrap().iter().map(|record| record.client_id).collect();
+        assert_eq!(ids, vec![Some(2), Some(3)]);
+    }
+
+    #[tokio::test]
+    async fn test_profiles() {
+        let unused = tempfile::tempdir().unwrap();
+        let server_database = ServerDatabase::new(MEMORY_DATABASE, unused.path()).await.unwrap();
+        server_database.register_user("Bob", "bbb").await.unwrap();
+        assert_eq!(server_database.profile("Bob").await.unwrap(), UserProfile { username: "Bob".to_string(), ..UserProfile::default() });
+
+        server_database.set_profile("Bob", Some("at lunch"), None).await.unwrap();
+        server_database.set_profile("Bob", None, Some(&[1, 2, 3])).await.unwrap();
+        let profile = server_database.profile("Bob").await.unwrap();
+        assert_eq!(profile.status.as_deref(), Some("at lunch"));
+        assert_eq!(profile.avatar_png, Some(vec![1, 2, 3]));
+        let statuses = server_database.statuses(vec!["Alice".to_string(), "Bob".to_string()]).await.unwrap();
+        assert_eq!(statuses, vec![
+            UserStatus { username: "Alice".to_string(), status: None },
+            UserStatus { username: "Bob".to_string(), status: Some("at lunch".to_string()) },
+        ]);
+
+        // Empty fields are removed, and so is the profile once it's empty
+        server_database.set_profile("Bob", Some(""), None).await.unwrap();
+        assert_eq!(server_database.profile("Bob").await.unwrap().status, None);
+        server_database.set_profile("Bob", None, Some(&[])).await.unwrap();
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM profiles").fetch_one(&server_database.db).await.unwrap();
+        assert_eq!(count, 0);
+
+        server_database.set_profile("Bob", Some("away"), None).await.unwrap();
+        server_database.delete_user("Bob").await.unwrap();
+        assert_eq!(server_database.profile("Bob").await.unwrap().status, None);
+        assert!(server_database.set_profile("Nobody", Some("hi"), None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_attachments_on_disk() {
+        let dir = tempfile::tempdir().unwrap().keep();
+        let dbfile = dir.join("test.db");
+        let dbfile = dbfile.as_os_str().to_str().unwrap();
+
+        let server_database = ServerDatabase::new(dbfile, &dir.join("attachments")).await.unwrap();
+        assert!(server_database.register_user("Bob", "bbb").await.is_ok());
+        let message = ChatMessage::new(1, "Bob", ChatMessageContent::File("test.txt".to_string(), b"abc".to_vec()));
+        let id = server_database.store_message(&message).await.unwrap();
+
+        let attachment = server_database.find_attachment(id).await.unwrap().unwrap();
+        assert_eq!(attachment.kind, AttachmentKind::File("test.txt".to_string()));
+        assert_eq!(std::fs::read(&attachment.path).unwrap(), b"abc");
+        assert!(server_database.find_attachment(id + 1).await.unwrap().is_none());
+
+        let (hash, size): (String, i64) = sqlx::query_as("SELECT attachment_hash, attachment_size FROM messages")
+            .fetch_one(&server_database.db).await.unwrap();
+        assert_eq!(size, 3);
+        assert_eq!(std::fs::read(server_database.attachments.path(&hash)).unwrap(), b"abc");
+
+        // Attachments stored in the database by older versions are moved to disk when the database is opened
+        sqlx::query("UPDATE messages SET content=x'646566', attachment_hash=NULL")
+            .execute(&server_database.db).await.unwrap();
+        drop(server_database);
+        let server_database = ServerDatabase::new(dbfile, &dir.join("attachments")).await.unwrap();
+        let (hash, size): (String, i64) = sqlx::query_as("SELECT attachment_hash, attachment_size FROM messages WHERE content IS NULL")
+            .fetch_one(&server_database.db).await.unwrap();
+        assert_eq!(size, 3);
+        assert_eq!(std::fs::read(server_database.attachments.path(&hash)).unwrap(), b"def");
+
+        // The format of a voice note is kept with it
+        let voice = ChatMessage { id: 2, content: ChatMessageContent::Audio { format: AudioFormat::Opus, data: b"OggS".to_vec() }, ..message };
+        let id = server_database.store_message(&voice).await.unwrap();
+        let attachment = server_database.find_attachment(id).await.unwrap().unwrap();
+        assert_eq!(attachment.kind, AttachmentKind::Audio(AudioFormat::Opus));
+        assert_eq!(attachment.size, 4);
+    }
+
+    #[tokio::test]
+    async fn test_storage_usage() {
+        let dir = tempfile::tempdir().unwrap();
+        let server_database = ServerDatabase::new(MEMORY_DATABASE, dir.path()).await.unwrap();
+        assert!(server_database.register_user("Bob", "bbb").await.is_ok());
+        assert!(server_database.register_user("Alice", "aaa").await.is_ok());
+        assert!(server_database.register_user("Carol", "ccc").await.is_ok());
+
+        let message = ChatMessage::new(1, "Bob", ChatMessageContent::File("test.txt".to_string(), b"abc".to_vec()));
+        server_database.store_message(&message).await.unwrap();
+        // The same file sent again counts again
+        server_database.store_message(&ChatMessage { id: 2, ..message.clone() }).await.unwrap();
+        server_database.store_message(&ChatMessage { id: 3, content: ChatMessageContent::Text("hello".to_string()), ..message.clone() }).await.unwrap();
+        server_database.store_message(&ChatMessage { sender: "Alice".to_string(), content: ChatMessageContent::Image(b"image".to_vec()), ..message }).await.unwrap();
+
+        assert_eq!(server_database.storage_used("Bob").await.unwrap(), 6);
+        assert_eq!(server_database.storage_used("Carol").await.unwrap(), 0);
+        let usage: Vec<(String, u64, u64)> = server_database.storage_usage().await.unwrap().into_iter()
+            .map(|user| (user.username, user.attachments, user.bytes))
+            .collect();
+        assert_eq!(usage, vec![("Bob".to_string(), 2, 6), ("Alice".to_string(), 1, 5), ("Carol".to_string(), 0, 0)]);
+    }
+
+    #[tokio::test]
+    async fn test_registration_and_login() {
+        let dir = tempfile::tempdir().unwrap().keep();
+        let dbfile = dir.join("test.db");
+        let dbfile = dbfile.as_os_str().to_str().unwrap();
+        
+        let server_database = ServerDatabase::new(dbfile, &dir.join("attachments")).await;
+        assert!(server_database.is_ok());
+        let server_database = server_database.unwrap();
+        assert!(server_database.register_user("Alice", "aaa").await.is_ok());
+        assert!(server_database.register_user("Bob", "bbb").await.is_ok());
+        assert!(server_database.register_user("guest-Eve", "eee").await.is_err());
+        assert!(server_database.user_exists("Bob").await.unwrap());
+        assert!(!server_database.user_exists("Catie").await.unwrap());
+
+
+        assert!(matches!(server_database.check_auth("Alice", "aaa").await, Ok(true)));
+        assert!(matches!(server_database.check_auth("Alice", "bbb").await, Ok(false)));
+        assert!(matches!(server_database.check_auth("Catie", "aaa").await, Ok(false)));
+    }
+
+    #[tokio::test]
+    async fn test_memory_database() {
+        let unused = tempfile::tempdir().unwrap();
+        let server_database = ServerDatabase::new(MEMORY_DATABASE, unused.path()).await.unwrap();
+        assert!(server_database.is_in_memory());
+        assert!(server_database.register_user("Alice", "aaa").await.is_ok());
+        assert!(server_database.check_auth("Alice", "aaa").await.unwrap());
+
+        // Concurrent queries never open a second, empty in-memory database
+        let messages: Vec<ChatMessage> = (0..5).map(|i| ChatMessage::new(i, "Alice", ChatMessageContent::File("notes.txt".to_string(), format!("note {i}").into_bytes()))).collect();
+        let ids = futures::future::join_all(messages.iter().map(|message| server_database.store_message(message))).await;
+        assert_eq!(server_database.message_count().await.unwrap(), 5);
+
+        let attachment = server_database.find_attachment(*ids[0].as_ref().unwrap()).await.unwrap().unwrap();
+        assert_eq!(std::fs::read(&attachment.path).unwrap(), b"note 0");
+        assert!(std::fs::read_dir(unused.path()).unwrap().next().is_none());
+        drop(server_database);
+        assert!(!attachment.path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_import_users() {
+        let dir = tempfile::tempdir().unwrap().keep();
+        let dbfile = dir.join("test.db");
+        let dbfile = dbfile.as_os_str().to_str().unwrap();
+
+        let server_database = ServerDatabase::new(dbfile, &dir.join("attachments")).await.unwrap();
+        assert!(server_database.register_user("Alice", "aaa").await.is_ok());
+        let user = |username: &str, password: NewPassword| (username.to_string(), password);
+
+        // A single invalid user rolls back the whole import
+        let users = [
+            user("Bob", NewPassword::Plain("bbb".to_string())),
+            user("Alice", NewPassword::Plain("aaa".to_string())),
+            user("Carol", NewPassword::Hashed("$scrypt$ln=15,r=8,p=1$c2FsdA$aGFzaA".to_string())),
+            user("Dave", NewPassword::Hashed("not a hash".to_string())),
+            user("guest-Eve", NewPassword::Plain("eee".to_string())),
+        ];
+        let errors = server_database.import_users(&users).await.unwrap();
+        assert_eq!(errors.iter().map(|(index, _)| *index).collect::<Vec<_>>(), vec![1, 2, 3, 4]);
+        assert_eq!(errors[0].1, "user Alice already exists");
+        assert!(!server_database.user_exists("Bob").await.unwrap());
+
+        let users = [
+            user("Bob", NewPassword::Plain("bbb".to_string())),
+            user("Carol", NewPassword::Hashed(hash_password("ccc").unwrap())),
+        ];
+        assert!(server_database.import_users(&users).await.unwrap().is_empty());
+        assert!(server_database.check_auth("Bob", "bbb").await.unwrap());
+        assert!(server_database.check_auth("Carol", "ccc").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_per_user_salts() {
+        let dir = tempfile::tempdir().unwrap().keep();
+        let dbfile = dir.join("test.db");
+        let dbfile = dbfile.as_os_str().to_str().unwrap();
+
+        let server_database = ServerDatabase::new(dbfile, &dir.join("attachments")).await.unwrap();
+        assert!(server_database.register_user("Alice", "same").await.is_ok());
+        assert!(server_database.register_user("Bob", "same").await.is_ok());
+
+        let hashes: Vec<String> = sqlx::query_scalar("SELECT password FROM users")
+            .fetch_all(&server_database.db).await.unwrap();
+        assert_ne!(hashes[0], hashes[1]);
+
+        sqlx::query("UPDATE users SET needs_rehash=1 WHERE username='Alice'")
+            .execute(&server_database.db).await.unwrap();
+        assert!(matches!(server_database.check_auth("Alice", "same").await, Ok(true)));
+        let (hash, needs_rehash): (String, bool) = sqlx::query_as("SELECT password, needs_rehash FROM users WHERE username='Alice'")
+            .fetch_one(&server_database.db).await.unwrap();
+        assert!(!needs_rehash);
+        assert_ne!(hash, hashes[0]);
+        assert!(matches!(server_database.check_auth("Alice", "same").await, Ok(true)));
+    }
+
+    #[tokio::test]
+    async fn test_admins_and_bans() {
+        let dir = tempfile::tempdir().unwrap().keep();
+        let dbfile = dir.join("test.db");
+        let dbfile = dbfile.as_os_str().to_str().unwrap();
+
+        let server_database = ServerDatabase::new(dbfile, &dir.join("attachments")).await.unwrap();
+        assert!(server_database.register_user("Alice", "aaa").await.is_ok());
+        assert!(server_database.register_user("Bob", "bbb").await.is_ok());
+
+        assert!(matches!(server_database.is_admin("Alice").await, Ok(false)));
+        assert!(server_database.set_admin("Alice", true).await.is_ok());
+        assert!(matches!(server_database.is_admin("Alice").await, Ok(true)));
+        assert!(server_database.set_admin("Catie", true).await.is_err());
+
+        assert!(matches!(server_database.is_banned("Bob").await, Ok(false)));
+        assert!(server_database.ban_user("Bob", "Alice").await.is_ok());
+        assert!(matches!(server_database.is_banned("Bob").await, Ok(true)));
+        assert!(server_database.ban_user("Catie", "Alice").await.is_err());
+        assert!(matches!(server_database.unban_user("Bob").await, Ok(true)));
+        assert!(matches!(server_database.unban_user("Bob").await, Ok(false)));
+        assert!(matches!(server_database.is_banned("Bob").await, Ok(false)));
+    }
+
+    #[tokio::test]
+    async fn test_change_password_and_delete_user() {
+        let dir = tempfile::tempdir().unwrap().keep();
+        let dbfile = dir.join("test.db");
+        let dbfile = dbfile.as_os_str().to_str().unwrap();
+
+        let server_database = ServerDatabase::new(dbfile, &dir.join("attachments")).await.unwrap();
+        assert!(server_database.register_user("Alice", "aaa").await.is_ok());
+        assert!(server_database.register_user("Bob", "bbb").await.is_ok());
+
+        assert!(server_database.change_password("Alice", "new").await.is_ok());
+        assert!(matches!(server_database.check_auth("Alice", "aaa").await, Ok(false)));
+        assert!(matches!(server_database.check_auth("Alice", "new").await, Ok(true)));
+        assert!(server_database.change_password("Catie", "new").await.is_err());
+
+        let message = ChatMessage::new(1, "Bob", ChatMessageContent::Text("hello".to_string()));
+        assert!(server_database.store_message(&message).await.is_ok());
+        assert!(server_database.ban_user("Bob", "Alice").await.is_ok());
+        assert!(server_database.delete_user("Bob").await.is_ok());
+        assert!(matches!(server_database.check_auth("Bob", "bbb").await, Ok(false)));
+        assert!(matches!(server_database.is_banned("Bob").await, Ok(false)));
+        assert!(server_database.delete_user("Bob").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_nicknames() {
+        let dir = tempfile::tempdir().unwrap().keep();
+        let dbfile = dir.join("test.db");
+        let dbfile = dbfile.as_os_str().to_str().unwrap();
+
+        let server_database = ServerDatabase::new(dbfile, &dir.join("attachments")).await.unwrap();
+        assert!(server_database.register_user("Alice", "aaa").await.is_ok());
+        assert!(server_database.register_user("Bob", "bbb").await.is_ok());
+        assert!(matches!(server_database.nickname("Alice").await, Ok(None)));
+
+        assert!(matches!(server_database.set_nickname("Alice", Some("Ally")).await, Ok(true)));
+        assert_eq!(server_database.nickname("Alice").await.unwrap().as_deref(), Some("Ally"));
+        // Setting the same nickname again is fine
+        assert!(matches!(server_database.set_nickname("Alice", Some("Ally")).await, Ok(true)));
+
+        // Names of other users can't be taken, regardless of case
+        assert!(matches!(server_database.set_nickname("Bob", Some("ally")).await, Ok(false)));
+        assert!(matches!(server_database.set_nickname("Bob", Some("alice")).await, Ok(false)));
+        assert!(server_database.register_user("ALLY", "ccc").await.is_err());
+
+        assert!(matches!(server_database.set_nickname("Alice", None).await, Ok(true)));
+        assert!(matches!(server_database.nickname("Alice").await, Ok(None)));
+        assert!(matches!(server_database.set_nickname("Bob", Some("ally")).await, Ok(true)));
+        assert!(server_database.set_nickname("Catie", Some("Cat")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_list_messages_and_users() {
+        let dir = tempfile::tempdir().unwrap().keep();
+        let dbfile = dir.join("test.db");
+        let dbfile = dbfile.as_os_str().to_str().unwrap();
+
+        let server_database = ServerDatabase::new(dbfile, &dir.join("attachments")).await.unwrap();
+        assert!(server_database.register_user("Bob", "bbb").await.is_ok());
+        assert!(server_database.register_user("Alice", "aaa").await.is_ok());
+        assert!(server_database.set_admin("Alice", true).await.is_ok());
+        assert!(server_database.ban_user("Bob", "Alice").await.is_ok());
+
+        let users = server_database.list_users().await.unwrap();
+        let users: Vec<(&str, bool, bool)> = users.iter().map(|user| (user.username.as_str(), user.admin, user.banned)).collect();
+        assert_eq!(users, vec![("Alice", true, false), ("Bob", false, true)]);
+
+        let start = chrono::Utc::now() - chrono::Duration::hours(1);
+        let contents = [
+            ChatMessageContent::Text("one".to_string()),
+            ChatMessageContent::File("notes.txt".to_string(), b"hello".to_vec()),
+            ChatMessageContent::Text("three".to_string()),
+        ];
+        for (i, content) in contents.into_iter().enumerate() {
+            let message = ChatMessage {
+                timestamp: start + chrono::Duration::minutes(i as i64),
+                // The last message replies to the first one
+                reply_to: (i == 2).then(|| ReplyTo { sender: "Alice".to_string(), id: 0 }),
+                ..ChatMessage::new(i as u64, "Alice", content)
+            };
+            assert!(server_database.store_message(&message).await.is_ok());
+        }
+
+        // Without `since` the most recent messages are returned, oldest first
+        let messages = server_database.messages(None, 2).await.unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].attachment, Some(AttachmentKind::File("notes.txt".to_string())));
+        assert_eq!(messages[0].size, Some(5));
+        assert_eq!(messages[1].text.as_deref(), Some("three"));
+        assert_eq!(messages[0].reply_to, None);
+        assert_eq!(messages[1].reply_to, Some(ReplyTo { sender: "Alice".to_string(), id: 0 }));
+
+        let messages = server_database.messages(Some(start), 10).await.unwrap();
+        let ids: Vec<u64> = messages.iter().map(|message| message.id).collect();
+        assert_eq!(ids.len(), 2);
+        assert!(ids[0] < ids[1]);
+        assert_eq!(messages[1].text.as_deref(), Some("three"));
+        assert!(server_database.messages(Some(chrono::Utc::now()), 10).await.unwrap().is_empty());
+        assert_eq!(server_database.message_count().await.unwrap(), 3);
+        assert!(server_database.size().await.unwrap() > 0);
+    }
+
+    #[tokio::test]
+    async fn test_messages_after() {
+        let dir = tempfile::tempdir().unwrap().keep();
+        let dbfile = dir.join("test.db");
+        let dbfile = dbfile.as_os_str().to_str().unwrap();
+
+        let server_database = ServerDatabase::new(dbfile, &dir.join("attachments")).await.unwrap();
+        assert!(server_database.register_user("Alice", "aaa").await.is_ok());
+        let message = |text: &str| ChatMessage::new(1, "Alice", ChatMessageContent::Text(text.to_string()));
+        let first = server_database.store_message(&message("one")).await.unwrap();
+        let second = server_database.store_message(&message("two")).await.unwrap();
+        let third = server_database.store_message(&message("three")).await.unwrap();
+
+        let texts = |messages: Vec<crate::server_db::MessageRecord>| -> Vec<String> {
+            messages.into_iter().filter_map(|message| message.text).collect()
+        };
+        assert_eq!(texts(server_database.messages_after(first, 10).await.unwrap()), vec!["two", "three"]);
+        assert_eq!(texts(server_database.messages_after(0, 1).await.unwrap()), vec!["one"]);
+        assert_eq!(server_database.messages_after(0, 1).await.unwrap()[0].client_id, Some(1));
+        assert!(server_database.messages_after(third, 10).await.unwrap().is_empty());
+
+        // The IDs of pruned messages are never given out again
+        server_database.prune_messages(chrono::Utc::now()).await.unwrap();
+        let fourth = server_database.store_message(&message("four")).await.unwrap();
+        assert!(first < second && second < third && third < fourth);
+        assert_eq!(texts(server_database.messages_after(first, 10).await.unwrap()), vec!["four"]);
+    }
+
+    #[tokio::test]
+    async fn test_filter_log() {
+        let dir = tempfile::tempdir().unwrap().keep();
+        let dbfile = dir.join("test.db");
+        let dbfile = dbfile.as_os_str().to_str().unwrap();
+
+        let server_database = ServerDatabase::new(dbfile, &dir.join("attachments")).await.unwrap();
+        for (text, recipient) in [("darn", None), ("buy now", Some("Bob".to_string())), ("heck", None)] {
+            let record = FilteredRecord {
+                sender: "Alice".to_string(),
+                recipient,
+                timestamp: chrono::Utc::now(),
+                text: text.to_string(),
+                action: "redact".to_string(),
+                rules: "swearing".to_string(),
+            };
+            assert!(server_database.log_filtered(&record).await.is_ok());
+        }
+
+        let records = server_database.filtered_messages(2).await.unwrap();
+        assert_eq!(records.iter().map(|record| record.text.as_str()).collect::<Vec<_>>(), vec!["buy now", "heck"]);
+        assert_eq!(records[0].recipient.as_deref(), Some("Bob"));
+    }
+
+    #[tokio::test]
+    async fn test_read_receipts() {
+        let dir = tempfile::tempdir().unwrap().keep();
+        let dbfile = dir.join("test.db");
+        let dbfile = dbfile.as_os_str().to_str().unwrap();
+
+        let server_database = ServerDatabase::new(dbfile, &dir.join("attachments")).await.unwrap();
+        assert!(server_database.register_user("Alice", "aaa").await.is_ok());
+        assert!(server_database.register_user("Bob", "bbb").await.is_ok());
+
+        let start = chrono::Utc::now() - chrono::Duration::hours(1);
+        // Nothing to read yet
+        assert!(server_database.mark_read("Bob", start, start).await.is_ok());
+        assert!(matches!(server_database.last_read("Bob").await, Ok(None)));
+
+        let mut ids = Vec::new();
+        for i in 0..3 {
+            let message = ChatMessage {
+                timestamp: start + chrono::Duration::minutes(i as i64),
+                ..ChatMessage::new(i, "Alice", ChatMessageContent::Text(i.to_string()))
+            };
+            ids.push(server_database.store_message(&message).await.unwrap());
+        }
+
+        let read_at = start + chrono::Duration::minutes(10);
+        assert!(server_database.mark_read("Bob", start + chrono::Duration::minutes(1), read_at).await.is_ok());
+        assert_eq!(server_database.last_read("Bob").await.unwrap(), Some((ids[1], read_at)));
+
+        // Reading an older message doesn't move the position back
+        let later = read_at + chrono::Duration::minutes(1);
+        assert!(server_database.mark_read("Bob", start, later).await.is_ok());
+        assert_eq!(server_database.last_read("Bob").await.unwrap(), Some((ids[1], read_at)));
+        assert!(server_database.mark_read("Bob", later, later).await.is_ok());
+        assert_eq!(server_database.last_read("Bob").await.unwrap(), Some((ids[2], later)));
+
+        assert!(server_database.delete_user("Bob").await.is_ok());
+        assert!(matches!(server_database.last_read("Bob").await, Ok(None)));
+    }
+
+    #[tokio::test]
+    async fn test_blocks() {
+        let dir = tempfile::tempdir().unwrap().keep();
+        let dbfile = dir.join("test.db");
+        let dbfile = dbfile.as_os_str().to_str().unwrap();
+
+        let server_database = ServerDatabase::new(dbfile, &dir.join("attachments")).await.unwrap();
+        assert!(server_database.register_user("Alice", "aaa").await.is_ok());
+        assert!(server_database.register_user("Bob", "bbb").await.is_ok());
+
+        assert!(server_database.block_user("Alice", "Bob").await.unwrap());
+        assert!(!server_database.block_user("Alice", "Bob").await.unwrap());
+        assert!(server_database.block_user("Alice", "guest-Eve").await.unwrap());
+        assert!(server_database.block_user("Bob", "Alice").await.unwrap());
+        // Only registered users have a block list
+        assert!(server_database.block_user("guest-Eve", "Alice").await.is_err());
+
+        let pair = |blocker: &str, blocked: &str| (blocker.to_string(), blocked.to_string());
+        assert_eq!(server_database.blocks().await.unwrap(), vec![pair("Alice", "Bob"), pair("Alice", "guest-Eve"), pair("Bob", "Alice")]);
+
+        assert!(server_database.unblock_user("Alice", "guest-Eve").await.unwrap());
+        assert!(!server_database.unblock_user("Alice", "guest-Eve").await.unwrap());
+
+        // Deleting a user removes their block list and their entries in other lists
+        assert!(server_database.delete_user("Bob").await.is_ok());
+        assert!(server_database.blocks().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_certificates() {
+        let unused = tempfile::tempdir().unwrap();
+        let server_database = ServerDatabase::new(MEMORY_DATABASE, unused.path()).await.unwrap();
+        assert!(server_database.register_user("bot", "secret").await.is_ok());
+
+        assert!(server_database.add_certificate("bot", "AA:01").await.is_ok());
+        assert!(server_database.add_certificate("bot", "AA:02").await.is_ok());
+        assert!(server_database.add_certificate("bot", "AA:01").await.is_err());
+        assert!(server_database.add_certificate("nobody", "AA:03").await.is_err());
+        assert_eq!(server_database.certificate_owner("AA:01").await.unwrap().as_deref(), Some("bot"));
+        assert_eq!(server_database.certificate_owner("AA:03").await.unwrap(), None);
+        assert_eq!(server_database.certificates().await.unwrap().len(), 2);
+
+        assert!(server_database.remove_certificate("AA:01").await.unwrap());
+        assert!(!server_database.remove_certificate("AA:01").await.unwrap());
+        // Deleting a user removes their certificates
+        assert!(server_database.delete_user("bot").await.is_ok());
+        assert_eq!(server_database.certificate_owner("AA:02").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_audit_log() {
+        let unused = tempfile::tempdir().unwrap();
+        let server_database = ServerDatabase::new(MEMORY_DATABASE, unused.path()).await.unwrap();
+        let record = LoginRecord { username: "Bob".to_string(), address: Some("10.0.0.2".to_string()), timestamp: chrono::Utc::now(), outcome: "success".to_string() };
+        assert!(server_database.log_login(&record).await.is_ok());
+        assert!(server_database.log_event(AuditEvent::Ban, Some("Alice"), Some("Bob"), Some("10.0.0.1"), None).await.is_ok());
+        assert!(server_database.log_event(AuditEvent::Reload, Some("SIGHUP"), None, None, Some("ok")).await.is_ok());
+
+        let events = server_database.audit_log(None, 2).await.unwrap();
+        assert_eq!(events.iter().map(|record| record.event.as_str()).collect::<Vec<_>>(), vec!["ban", "reload"]);
+        assert_eq!((events[0].actor.as_deref(), events[0].target.as_deref()), (Some("Alice"), Some("Bob")));
+        let first = server_database.audit_log(None, 10).await.unwrap().remove(0);
+        assert_eq!((first.event.as_str(), first.address.as_deref(), first.detail.as_deref()), ("login", Some("10.0.0.2"), Some("success")));
+        assert_eq!(server_database.audit_log(Some(events[0].id), 10).await.unwrap().len(), 1);
+
+        // Events can't be changed or removed
+        assert!(sqlx::query("UPDATE audit_log SET actor='Mallory'").execute(&server_database.db).await.is_err());
+        assert!(sqlx::query("DELETE FROM audit_log").execute(&server_database.db).await.is_err());
+        assert_eq!(server_database.audit_log(None, 10).await.unwrap().len(), 3);
     }
-    
 }
\ No newline at end of file
@@ -0,0 +1,207 @@
+use std::collections::HashSet;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+
+use anyhow::anyhow;
+use chat::client::ChatClient;
+use chat::{t, ChatMessage, ChatMessageContent, Datagram, EmptyResult};
+
+use crate::server_db::MEMORY_DATABASE;
+use crate::server_transport::{Listener, PeerAddr};
+use crate::{serve, ServerConfig, ServerContext};
+
+/// The demo users, logging in with their username as the password.
+pub const DEMO_USERS: [&str; 2] = ["alice", "bob"];
+
+/// Username of the account which posted the seed messages.
+const HOST: &str = "demo";
+
+/// Flags of the `demo` command.
+#[derive(clap::Args, Clone, Debug)]
+pub struct DemoArgs {
+    /// address the demo server listens on
+    #[arg(short, long, default_value = "127.0.0.1")]
+    pub address: String,
+    /// port the demo server listens on, 0 picks a free one
+    #[arg(short, long, default_value_t = 0)]
+    pub port: u16,
+}
+
+/// Runs a throwaway server with the demo users until `shutdown` completes. It prints the commands
+/// starting a client for each of them. The history starts with a few messages of the host, which every
+/// demo user gets when they log in.
+///
+/// # Arguments
+///
+/// * `listener` - The bound listener.
+/// * `shutdown` - Completes when the demo should stop.
+///
+/// # Returns
+///
+/// * `EmptyResult` - Returns an empty result once the demo stopped.
+pub async fn run_demo(listener: Listener, shutdown: impl Future<Output = ()> + Send + 'static) -> EmptyResult {
+    let address = listener.local_addr().ok_or_else(|| anyhow!("The demo server has no port."))?;
+    let config = ServerConfig::default();
+    let codec = config.codec;
+    let context = ServerContext::new(MEMORY_DATABASE, config).await?;
+    // The host gets a random password, nobody else logs in with its account
+    let host_password = format!("{:016x}", rand::random::<u64>());
+    context.database.register_user(HOST, &host_password).await?;
+    for username in DEMO_USERS {
+        context.database.register_user(username, username).await?;
+    }
+    for text in seed_messages() {
+        context.store_message(&ChatMessage::new(0, HOST, ChatMessageContent::Text(text))).await?;
+    }
+    let server = tokio::spawn(serve(context.clone(), vec![listener], shutdown));
+
+    let host = ChatClient::connect(&address.ip().to_string(), address.port(), HOST, &host_password, codec).await?;
+    // Every demo user gets their own history file and download directory, so both clients can run in the same directory
+    let dir = tempfile::tempdir()?;
+    println!("{}", t!("demo-started", address = address.to_string()));
+    for username in DEMO_USERS {
+        println!();
+        println!("  {}", client_command(&client_path(), &address.ip().to_string(), address.port(), username, dir.path()));
+    }
+    println!();
+    println!("{}", t!("demo-stop"));
+
+    let greeter = tokio::spawn(async move {
+        if let Err(e) = greet(host, context).await {
+            tracing::warn!("The demo host stopped greeting: {e}");
+        }
+    });
+    let result = server.await?;
+    greeter.abort();
+    result
+}
+
+/// Returns the path of the client binary, which is built next to the server.
+fn client_path() -> PathBuf {
+    let name = format!("client{}", std::env::consts::EXE_SUFFIX);
+    std::env::current_exe().ok()
+        .and_then(|server| server.parent().map(|dir| dir.join(&name)))
+        .filter(|client| client.exists())
+        .unwrap_or_else(|| PathBuf::from(name))
+}
+
+/// Builds the command line starting the client of a demo user.
+///
+/// # Arguments
+///
+/// * `client` - The path of the client binary.
+/// * `address` - The address of the demo server.
+/// * `port` - The port of the demo server.
+/// * `username` - The demo user, whose password is their username.
+/// * `dir` - The directory for the history files and downloads of the demo users.
+///
+/// # Returns
+///
+/// * `String` - Returns the command line.
+fn client_command(client: &Path, address: &str, port: u16, username: &str, dir: &Path) -> String {
+    format!("{} -a {address} -P {port} -u {username} -p {username} --history-file {} --download-dir {}",
+            client.display(), dir.join(format!("{username}.db")).display(), dir.join(username).display())
+}
+
+/// Sends the stored history to every new connection of a demo user, as the client asks only for the messages
+/// it missed while it was logged in before. The host learns about the logins from the presence notices.
+///
+/// # Arguments
+///
+/// * `host` - The connection of the host.
+/// * `context` - The context of the demo server.
+///
+/// # Returns
+///
+/// * `EmptyResult` - Returns an empty result once the server closes the connection.
+async fn greet(mut host: ChatClient, context: ServerContext) -> EmptyResult {
+    let mut greeted = HashSet::<PeerAddr>::new();
+    while let Some(datagram) = host.recv().await {
+        let Datagram::Presence { username, online: true } = datagram else {
+            continue;
+        };
+        if !DEMO_USERS.contains(&username.as_str()) {
+            continue;
+        }
+        for client in context.connected_clients().await {
+            if client.username == username && greeted.insert(client.addr) {
+                context.fetch_since(client.addr, &username, 0).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns the messages the history of the demo starts with.
+fn seed_messages() -> [String; 3] {
+    let [first, second] = DEMO_USERS;
+    [
+        t!("demo-welcome"),
+        t!("demo-chat", first = first, second = second),
+        t!("demo-commands", username = second),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use chat::client::ChatClient;
+    use chat::{ChatMessageContent, CodecKind, Datagram};
+    use tokio::sync::Notify;
+
+    use crate::server_demo::{client_command, run_demo};
+    use crate::server_transport::Listener;
+
+    /// Collects the texts of the messages of the host until nothing arrives for a while.
+    async fn receive_history(client: &mut ChatClient) -> Vec<String> {
+        let mut texts = Vec::new();
+        while let Ok(Some(datagram)) = tokio::time::timeout(Duration::from_millis(500), client.recv()).await {
+            if let Datagram::Message(message) = datagram {
+                assert_eq!(message.sender, "demo");
+                if let ChatMessageContent::Text(text) = message.content {
+                    texts.push(text);
+                }
+            }
+        }
+        texts
+    }
+
+    #[test]
+    fn test_client_command() {
+        let command = client_command(Path::new("client"), "127.0.0.1", 4321, "alice", Path::new("/tmp/demo"));
+        assert_eq!(command, "client -a 127.0.0.1 -P 4321 -u alice -p alice --history-file /tmp/demo/alice.db --download-dir /tmp/demo/alice");
+    }
+
+    #[tokio::test]
+    async fn test_demo() {
+        let listener = Listener::bind_tcp("127.0.0.1", 0, false).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let shutdown = Arc::new(Notify::new());
+        let demo_shutdown = shutdown.clone();
+        let demo = tokio::spawn(run_demo(listener, async move { demo_shutdown.notified().await }));
+
+        // The host may still be logging in, so alice logs in again until greeted
+        let greeted = async {
+            loop {
+                let mut alice = ChatClient::connect("127.0.0.1", port, "alice", "alice", CodecKind::Cbor).await.unwrap();
+                let texts = receive_history(&mut alice).await;
+                if !texts.is_empty() {
+                    return texts;
+                }
+            }
+        };
+        let texts = tokio::time::timeout(Duration::from_secs(10), greeted).await.expect("alice wasn't greeted");
+        assert_eq!(texts.len(), 3);
+        assert!(texts[1].contains("alice") && texts[1].contains("bob"));
+
+        // The messages are stored, so every login gets them again
+        let mut bob = ChatClient::connect("127.0.0.1", port, "bob", "bob", CodecKind::Cbor).await.unwrap();
+        assert_eq!(receive_history(&mut bob).await, texts);
+
+        shutdown.notify_one();
+        demo.await.unwrap().unwrap();
+    }
+}
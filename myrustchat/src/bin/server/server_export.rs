@@ -0,0 +1,152 @@
+use std::io::Write;
+
+use anyhow::Result;
+use chat::AttachmentKind;
+use chrono::{DateTime, NaiveDate, Utc};
+use clap::ValueEnum;
+
+use crate::server_db::MessageRecord;
+
+/// Header of the CSV export, one column per field of `MessageRecord` except the ID given by the client.
+const CSV_HEADER: &str = "id,sender,timestamp,text,attachment,filename,size,reply_sender,reply_id,announcement,expires_at";
+
+/// Format of the exported message history.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ExportFormat {
+    /// a JSON array of messages, like the HTTP API returns
+    Json,
+    /// a CSV table with a header row
+    Csv,
+}
+
+/// Parses the `--since` argument of the export, a date like `2024-05-01` meaning midnight UTC,
+/// or an RFC 3339 time like `2024-05-01T12:00:00+02:00`.
+///
+/// # Arguments
+///
+/// * `value` - The argument.
+///
+/// # Returns
+///
+/// * `Result<DateTime<Utc>, String>` - Returns the time, or the reason why it could not be parsed.
+pub fn parse_since(value: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        return Ok(date.and_hms_opt(0, 0, 0).unwrap_or_default().and_utc());
+    }
+    DateTime::parse_from_rfc3339(value)
+        .map(|time| time.with_timezone(&Utc))
+        .map_err(|_| format!("expected a date like 2024-05-01 or a time like 2024-05-01T12:00:00Z, got {value}"))
+}
+
+/// Writes the message history in the chosen format. Attachments are referenced by their type, name and size,
+/// the data stays in the attachment directory.
+///
+/// # Arguments
+///
+/// * `records` - The exported messages, oldest first.
+/// * `format` - The format of the export.
+/// * `out` - Where the export is written to.
+///
+/// # Returns
+///
+/// * `Result<()>` - Returns an empty result if the export was written.
+pub fn write_export(records: &[MessageRecord], format: ExportFormat, mut out: impl Write) -> Result<()> {
+    match format {
+        ExportFormat::Json => {
+            serde_json::to_writer_pretty(&mut out, records)?;
+            writeln!(out)?;
+        },
+        ExportFormat::Csv => {
+            writeln!(out, "{CSV_HEADER}")?;
+            for record in records {
+                let (attachment, filename) = match &record.attachment {
+                    None => ("", None),
+                    Some(AttachmentKind::Image) => ("image", None),
+                    Some(AttachmentKind::File(filename)) => ("file", Some(filename.clone())),
+                    Some(AttachmentKind::Audio(format)) => ("audio", Some(format.extension().to_string())),
+                };
+                let fields = [
+                    record.id.to_string(),
+                    csv_field(&record.sender),
+                    record.timestamp.map(|timestamp| timestamp.to_rfc3339()).unwrap_or_default(),
+                    csv_field(record.text.as_deref().unwrap_or_default()),
+                    attachment.to_string(),
+                    csv_field(filename.as_deref().unwrap_or_default()),
+                    record.size.map(|size| size.to_string()).unwrap_or_default(),
+                    csv_field(record.reply_to.as_ref().map(|reply| reply.sender.as_str()).unwrap_or_default()),
+                    record.reply_to.as_ref().map(|reply| reply.id.to_string()).unwrap_or_default(),
+                    record.announcement.to_string(),
+                    record.expires_at.map(|expires_at| expires_at.to_rfc3339()).unwrap_or_default(),
+                ];
+                writeln!(out, "{}", fields.join(","))?;
+            }
+        },
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Quotes a CSV field if it contains a separator, a quote or a line break, doubling the quotes inside it.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chat::{AttachmentKind, ReplyTo};
+
+    use crate::server_db::MessageRecord;
+    use crate::server_export::{parse_since, write_export, ExportFormat};
+
+    #[test]
+    fn test_export() {
+        let timestamp = parse_since("2024-05-01T12:00:00+02:00").unwrap();
+        assert_eq!(timestamp.to_rfc3339(), "2024-05-01T10:00:00+00:00");
+        assert_eq!(parse_since("2024-05-01").unwrap().to_rfc3339(), "2024-05-01T00:00:00+00:00");
+        assert!(parse_since("yesterday").is_err());
+
+        let records = [
+            MessageRecord {
+                id: 1,
+                client_id: Some(3),
+                sender: "Alice".to_string(),
+                timestamp: Some(timestamp),
+                text: Some("hello, \"world\"".to_string()),
+                attachment: None,
+                size: None,
+                reply_to: None,
+                announcement: true,
+                expires_at: Some(timestamp + chrono::Duration::seconds(60)),
+            },
+            MessageRecord {
+                id: 2,
+                client_id: None,
+                sender: "Bob".to_string(),
+                timestamp: None,
+                text: None,
+                attachment: Some(AttachmentKind::File("notes.txt".to_string())),
+                size: Some(5),
+                reply_to: Some(ReplyTo { sender: "Alice".to_string(), id: 7 }),
+                announcement: false,
+                expires_at: None,
+            },
+        ];
+
+        let mut csv = Vec::new();
+        write_export(&records, ExportFormat::Csv, &mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[1], "1,Alice,2024-05-01T10:00:00+00:00,\"hello, \"\"world\"\"\",,,,,,true,2024-05-01T10:01:00+00:00");
+        assert_eq!(lines[2], "2,Bob,,,file,notes.txt,5,Alice,7,false,");
+
+        let mut json = Vec::new();
+        write_export(&records, ExportFormat::Json, &mut json).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(json[1]["attachment"]["File"], "notes.txt");
+        assert_eq!(json[0]["text"], "hello, \"world\"");
+    }
+}
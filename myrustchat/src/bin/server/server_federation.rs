@@ -0,0 +1,339 @@
+use std::collections::{HashSet, VecDeque};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use chat::{ChatMessage, ChatMessageContent, CodecKind, Datagram, EmptyResult, MessageId, ReplyTo, ServerResponse};
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+use tokio::net::TcpStream;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+use crate::server_queue::{Outgoing, SendQueue};
+use crate::server_transport::{PeerAddr, ReadHalf, WriteHalf};
+use crate::{send_response, ServerContext, ServerError};
+
+/// How long a server waits before linking again to a peer it lost or could not reach.
+const PEER_RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// How long a peer may take to answer `PeerHello`.
+const PEER_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Quiet links are pinged this often, a link silent for three intervals is considered lost.
+const PEER_PING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Number of relayed messages remembered to drop those arriving again over another link.
+const RELAY_CACHE_SIZE: usize = 4096;
+
+/// Identifies a message across the servers: its origin, sender, ID and the time it was posted.
+type RelayKey = (String, String, MessageId, DateTime<Utc>);
+
+/// Remembers the most recently relayed messages. In a network with loops, a message reaches
+/// a server over several links and must be delivered to its users only once.
+#[derive(Debug, Default)]
+pub struct RelayCache {
+    seen: HashSet<RelayKey>,
+    order: VecDeque<RelayKey>,
+}
+
+impl RelayCache {
+    /// Records a relayed message.
+    ///
+    /// # Arguments
+    ///
+    /// * `origin` - The ID of the server where the message was posted.
+    /// * `message` - The relayed message.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - Returns `true` if the message is seen for the first time.
+    pub fn insert(&mut self, origin: &str, message: &ChatMessage) -> bool {
+        let key = (origin.to_string(), message.sender.clone(), message.id, message.timestamp);
+        if !self.seen.insert(key.clone()) {
+            return false;
+        }
+        self.order.push_back(key);
+        if self.order.len() > RELAY_CACHE_SIZE {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+/// Generates a server ID for servers which aren't given one.
+pub fn random_server_id() -> String {
+    format!("{:08x}", rand::random::<u32>())
+}
+
+/// Tells whether a message may be relayed between linked servers. Only public text messages which stay are relayed,
+/// attachments would bypass the limits and quotas of the other servers and ephemeral messages couldn't be deleted
+/// there, as relayed messages aren't stored. Both sides of a link check it, the peer may be older or misbehaving.
+///
+/// # Arguments
+///
+/// * `message` - The message to be relayed.
+///
+/// # Returns
+///
+/// * `bool` - Returns `true` if the message may be relayed.
+pub fn relayable(message: &ChatMessage) -> bool {
+    matches!(message.content, ChatMessageContent::Text(_)) && message.ttl.is_none()
+}
+
+/// Makes the copy of a relayed message shown to local users, whose sender is qualified by the origin server,
+/// so that `Bob` of another server can't be mistaken for the local `Bob`. The sender of the message it replies to
+/// is qualified the same way, unless it's already qualified by another server. Announcements arrive as ordinary
+/// messages, whether the sender is an admin is only known to the origin server.
+///
+/// # Arguments
+///
+/// * `message` - The relayed message.
+/// * `origin` - The ID of the server where the message was posted.
+///
+/// # Returns
+///
+/// * `ChatMessage` - Returns the local copy of the message.
+pub fn local_copy(message: &ChatMessage, origin: &str) -> ChatMessage {
+    ChatMessage {
+        sender: format!("{}@{origin}", message.sender),
+        origin: Some(origin.to_string()),
+        reply_to: message.reply_to.as_ref().map(|reply_to| ReplyTo {
+            sender: match reply_to.sender.contains('@') {
+                true => reply_to.sender.clone(),
+                false => format!("{}@{origin}", reply_to.sender),
+            },
+            id: reply_to.id,
+        }),
+        // IDs are given by every server on its own
+        seq: None,
+        announcement: false,
+        ..message.clone()
+    }
+}
+
+/// Keeps a link to the peer server at `address`, linking again whenever the link is lost.
+/// Runs until the task is aborted when the server shuts down.
+///
+/// # Arguments
+///
+/// * `context` - The server context.
+/// * `address` - The address and port of the peer.
+pub async fn run_peer_link(context: ServerContext, address: String) {
+    loop {
+        match dial_peer(&context, &address).await {
+            Ok((read_half, write_half, addr, server_id)) => {
+                tracing::info!("Linked to server {server_id} at {address}.");
+                if let Err(e) = peer_session(context.clone(), read_half, write_half, addr, server_id).await {
+                    tracing::warn!("Link to {address} failed: {e}");
+                }
+                tracing::warn!("Link to {address} lost.");
+            },
+            Err(e) => tracing::warn!("Could not link to {address}: {e:#}"),
+        }
+        tokio::time::sleep(PEER_RECONNECT_DELAY).await;
+    }
+}
+
+/// Connects to a peer server and introduces this server with `PeerHello`.
+///
+/// # Arguments
+///
+/// * `context` - The server context.
+/// * `address` - The address and port of the peer.
+///
+/// # Returns
+///
+/// * `Result<(ReadHalf, WriteHalf, PeerAddr, String)>` - Returns both halves of the link, the address and the ID of the peer.
+async fn dial_peer(context: &ServerContext, address: &str) -> Result<(ReadHalf, WriteHalf, PeerAddr, String)> {
+    let codec = context.config().codec;
+    let stream = TcpStream::connect(address).await?;
+    let addr = PeerAddr::Tcp(stream.peer_addr()?);
+    let (mut read_half, mut write_half) = stream.into_split();
+
+    let hello = Datagram::PeerHello {
+        server_id: context.config().server_id.clone(),
+        secret: context.config().peer_secret.clone().unwrap_or_default(),
+    };
+    hello.write_to_stream_timeout(&mut write_half, &codec, context.config().write_timeout).await?;
+    let response = Datagram::read_from_stream_limited(&mut read_half, context.config().max_message_size, &codec);
+    let response = tokio::time::timeout(PEER_HANDSHAKE_TIMEOUT, response).await
+        .context("The peer did not answer in time.")??;
+
+    match response {
+        Datagram::ServerResponse(ServerResponse::PeerOk { server_id }) => Ok((Box::new(read_half), Box::new(write_half), addr, server_id)),
+        Datagram::ServerResponse(ServerResponse::LoginFailed) => Err(anyhow::anyhow!("The peer refused the link, check the peer secret and server IDs.")),
+        _ => Err(anyhow::anyhow!("Unexpected answer from the peer.")),
+    }
+}
+
+/// Accepts a link opened by a peer server with `PeerHello`. The peer must know the peer secret of this server
+/// and have another server ID.
+///
+/// # Arguments
+///
+/// * `context` - The server context.
+/// * `read_half` - The readable half of the connection.
+/// * `write_half` - The writable half of the connection.
+/// * `addr` - The address of the peer.
+/// * `server_id` - The ID of the peer.
+/// * `secret` - The secret sent by the peer.
+///
+/// # Returns
+///
+/// * `EmptyResult` - Returns an empty result if the link ended normally.
+pub async fn accept_peer(context: ServerContext, read_half: ReadHalf, mut write_half: WriteHalf, addr: PeerAddr, server_id: String, secret: String) -> EmptyResult {
+    let config = context.config();
+    let known = config.peer_secret.as_deref().is_some_and(|expected| secret_matches(expected, &secret));
+    if !known || server_id == config.server_id {
+        tracing::warn!("Refused a link from server {server_id} at {addr}.");
+        send_response(&mut write_half, &config, ServerResponse::LoginFailed).await?;
+        return Err(ServerError::LoginError)?;
+    }
+
+    send_response(&mut write_half, &config, ServerResponse::PeerOk { server_id: config.server_id.clone() }).await?;
+    tracing::info!("Server {server_id} linked from {addr}.");
+    let result = peer_session(context, read_half, write_half, addr, server_id).await;
+    tracing::info!("Link from {addr} closed.");
+    result
+}
+
+/// Compares the secret sent by a peer with the peer secret in constant time. The digests of both are compared,
+/// so the time taken doesn't tell how much of the secret was guessed.
+///
+/// # Arguments
+///
+/// * `expected` - The peer secret of this server.
+/// * `secret` - The secret sent by the peer.
+///
+/// # Returns
+///
+/// * `bool` - Returns `true` if the secrets are equal.
+fn secret_matches(expected: &str, secret: &str) -> bool {
+    Sha256::digest(expected) == Sha256::digest(secret)
+}
+
+/// Relays messages over an established link until it's closed.
+///
+/// # Arguments
+///
+/// * `context` - The server context.
+/// * `read_half` - The readable half of the link.
+/// * `write_half` - The writable half of the link.
+/// * `addr` - The address of the peer.
+/// * `server_id` - The ID of the peer.
+///
+/// # Returns
+///
+/// * `EmptyResult` - Returns an empty result if the link was closed by this server.
+#[tracing::instrument(name = "peer", skip_all, fields(server_id = %server_id))]
+async fn peer_session(context: ServerContext, mut read_half: ReadHalf, write_half: WriteHalf, addr: PeerAddr, server_id: String) -> EmptyResult {
+    let (queue, disconnect) = context.add_peer(addr, &server_id, write_half).await;
+    let result = relay_loop(&context, &mut read_half, addr, &queue, &disconnect).await;
+    context.remove_peer(addr).await;
+    result
+}
+
+/// Reads the datagrams of a peer, answering pings and pinging the peer when the link is quiet.
+///
+/// # Arguments
+///
+/// * `context` - The server context.
+/// * `read_half` - The readable half of the link.
+/// * `addr` - The address of the peer.
+/// * `queue` - The send queue of the link.
+/// * `disconnect` - Notified when the link should be closed.
+///
+/// # Returns
+///
+/// * `EmptyResult` - Returns an empty result if the link was closed by this server.
+async fn relay_loop(context: &ServerContext, read_half: &mut ReadHalf, addr: PeerAddr, queue: &SendQueue, disconnect: &Notify) -> EmptyResult {
+    let codec: CodecKind = context.config().codec;
+    let mut last_received = Instant::now();
+
+    loop {
+        let read = Datagram::read_from_stream_limited(read_half, context.config().max_message_size, &codec);
+        tokio::pin!(read);
+
+        let datagram = loop {
+            tokio::select! {
+                datagram = &mut read => break datagram?,
+                _ = disconnect.notified() => return Ok(()),
+                _ = tokio::time::sleep(PEER_PING_INTERVAL) => {
+                    if last_received.elapsed() >= 3 * PEER_PING_INTERVAL {
+                        Err(ServerError::BrokenStream)?
+                    }
+                    queue.try_offer(Outgoing::new(Datagram::Ping));
+                }
+            }
+        };
+        last_received = Instant::now();
+
+        match datagram {
+            Datagram::Message(message) => context.receive_relayed(addr, message).await?,
+            Datagram::Ping => {
+                queue.try_offer(Outgoing::new(Datagram::Pong));
+            },
+            Datagram::Pong => {},
+            _ => tracing::debug!("Ignoring an unexpected datagram from the peer."),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chat::{ChatMessage, ChatMessageContent, ReplyTo};
+
+    use crate::server_federation::{local_copy, relayable, secret_matches, RelayCache};
+
+    fn message(id: u64) -> ChatMessage {
+        ChatMessage {
+            origin: Some("b".to_string()),
+            ..ChatMessage::new(id, "Bob", ChatMessageContent::Text("hello".to_string()))
+        }
+    }
+
+    #[test]
+    fn test_relay_cache() {
+        let mut cache = RelayCache::default();
+        let first = message(1);
+        assert!(cache.insert("b", &first));
+        // The same message arriving over another link is dropped
+        assert!(!cache.insert("b", &first));
+        // Messages differing in ID or origin are distinct
+        assert!(cache.insert("b", &message(2)));
+        assert!(cache.insert("c", &first));
+    }
+
+    #[test]
+    fn test_local_copy() {
+        let copy = local_copy(&message(1), "b");
+        assert_eq!(copy.sender, "Bob@b");
+        assert_eq!(copy.origin.as_deref(), Some("b"));
+        assert!(!local_copy(&ChatMessage { announcement: true, ..message(1) }, "b").announcement);
+
+        let reply = ChatMessage { reply_to: Some(ReplyTo { sender: "Alice".to_string(), id: 7 }), ..message(2) };
+        assert_eq!(local_copy(&reply, "b").reply_to, Some(ReplyTo { sender: "Alice@b".to_string(), id: 7 }));
+        let reply = ChatMessage { reply_to: Some(ReplyTo { sender: "Carol@c".to_string(), id: 7 }), ..message(2) };
+        assert_eq!(local_copy(&reply, "b").reply_to.unwrap().sender, "Carol@c");
+    }
+
+    #[test]
+    fn test_secret_matches() {
+        assert!(secret_matches("s3cret", "s3cret"));
+        assert!(!secret_matches("s3cret", "s3cre"));
+        assert!(!secret_matches("s3cret", ""));
+    }
+
+    #[test]
+    fn test_relayable() {
+        assert!(relayable(&message(1)));
+        // Attachments and ephemeral messages are dropped, whichever server sent them
+        assert!(!relayable(&ChatMessage { ttl: Some(60), ..message(1) }));
+        let image = ChatMessage { content: ChatMessageContent::Image(vec![0; 16]), ..message(1) };
+        assert!(!relayable(&image));
+        let file = ChatMessage { content: ChatMessageContent::File("notes.txt".to_string(), vec![0; 16]), ..message(1) };
+        assert!(!relayable(&file));
+    }
+}
@@ -0,0 +1,205 @@
+use std::fmt::Display;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use regex::{Regex, RegexBuilder};
+use serde::Deserialize;
+
+/// What happens to a text message matching a filter rule. The variants are ordered by severity.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FilterAction {
+    /// The message is delivered unchanged, only logged
+    Flag,
+    /// The matching parts are masked with asterisks before delivery
+    Redact,
+    /// The message is not delivered
+    Reject,
+}
+
+impl Display for FilterAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FilterAction::Flag => write!(f, "flag"),
+            FilterAction::Redact => write!(f, "redact"),
+            FilterAction::Reject => write!(f, "reject"),
+        }
+    }
+}
+
+/// A rule as written in the filter file, matching either a list of words or a regular expression.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleConfig {
+    /// Name shown in the log, `rule N` by default
+    name: Option<String>,
+    /// Whole words matched regardless of case
+    #[serde(default)]
+    words: Vec<String>,
+    /// Regular expression matched anywhere in the text
+    regex: Option<String>,
+    action: FilterAction,
+}
+
+/// Content of the filter file.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FilterFile {
+    #[serde(default)]
+    rules: Vec<RuleConfig>,
+}
+
+/// A compiled filter rule.
+#[derive(Clone, Debug)]
+struct FilterRule {
+    name: String,
+    pattern: Regex,
+    action: FilterAction,
+}
+
+/// Result of filtering a message which matched at least one rule.
+#[derive(Debug, PartialEq, Eq)]
+pub struct FilterVerdict {
+    /// The most severe action of the matched rules
+    pub action: FilterAction,
+    /// The text with the matches of the redacting rules masked
+    pub text: String,
+    /// Names of the matched rules
+    pub rules: Vec<String>,
+}
+
+/// Filter checking text messages against word lists and regular expressions.
+#[derive(Clone, Debug, Default)]
+pub struct ContentFilter {
+    rules: Vec<FilterRule>,
+}
+
+impl ContentFilter {
+    /// Reads the filter rules from a TOML file.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path to the filter file.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<ContentFilter>` - Returns the compiled filter if successful.
+    pub fn load(path: &Path) -> Result<ContentFilter> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read filter file {}.", path.display()))?;
+        ContentFilter::parse(&text)
+            .with_context(|| format!("Invalid filter file {}.", path.display()))
+    }
+
+    /// Compiles the filter rules from the content of a filter file.
+    ///
+    /// # Arguments
+    ///
+    /// * `text` - The TOML content of the filter file.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<ContentFilter>` - Returns the compiled filter if successful.
+    pub fn parse(text: &str) -> Result<ContentFilter> {
+        let file: FilterFile = toml::from_str(text)?;
+        let mut rules = Vec::new();
+        for (index, rule) in file.rules.into_iter().enumerate() {
+            let name = rule.name.unwrap_or_else(|| format!("rule {}", index + 1));
+            let pattern = match (rule.words.is_empty(), rule.regex) {
+                (false, None) => {
+                    let words: Vec<String> = rule.words.iter().map(|word| regex::escape(word.trim())).collect();
+                    RegexBuilder::new(&format!(r"\b(?:{})\b", words.join("|")))
+                        .case_insensitive(true)
+                        .build()?
+                },
+                (true, Some(regex)) => Regex::new(&regex)
+                    .with_context(|| format!("Invalid regular expression in {name}."))?,
+                _ => Err(anyhow::anyhow!("Filter {name} must have either words or a regex."))?,
+            };
+            rules.push(FilterRule { name, pattern, action: rule.action });
+        }
+        Ok(ContentFilter { rules })
+    }
+
+    /// Checks a text message against all rules.
+    ///
+    /// # Arguments
+    ///
+    /// * `text` - The text of the message.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<FilterVerdict>` - Returns what to do with the message, `None` if no rule matched.
+    pub fn check(&self, text: &str) -> Option<FilterVerdict> {
+        let mut verdict: Option<FilterVerdict> = None;
+        let mut filtered = text.to_string();
+        for rule in &self.rules {
+            if !rule.pattern.is_match(text) {
+                continue;
+            }
+            if rule.action == FilterAction::Redact {
+                filtered = rule.pattern
+                    .replace_all(&filtered, |captures: &regex::Captures| "*".repeat(captures[0].chars().count()))
+                    .into_owned();
+            }
+            match &mut verdict {
+                Some(verdict) => {
+                    verdict.action = verdict.action.max(rule.action);
+                    verdict.rules.push(rule.name.clone());
+                },
+                None => verdict = Some(FilterVerdict { action: rule.action, text: String::new(), rules: vec![rule.name.clone()] }),
+            }
+        }
+        verdict.map(|verdict| FilterVerdict { text: filtered, ..verdict })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server_filter::{ContentFilter, FilterAction};
+
+    const RULES: &str = r#"
+        [[rules]]
+        name = "swearing"
+        words = ["darn", "heck"]
+        action = "redact"
+
+        [[rules]]
+        regex = "buy (cheap|now)"
+        action = "reject"
+
+        [[rules]]
+        words = ["password"]
+        action = "flag"
+    "#;
+
+    #[test]
+    fn test_content_filter() {
+        let filter = ContentFilter::parse(RULES).unwrap();
+        assert_eq!(filter.check("hello world"), None);
+        // Words must match whole, but regardless of case
+        assert_eq!(filter.check("Checkered darning"), None);
+
+        let verdict = filter.check("What the HECK, darn it").unwrap();
+        assert_eq!(verdict.action, FilterAction::Redact);
+        assert_eq!(verdict.text, "What the ****, **** it");
+        assert_eq!(verdict.rules, vec!["swearing"]);
+
+        let verdict = filter.check("darn, buy now").unwrap();
+        assert_eq!(verdict.action, FilterAction::Reject);
+        assert_eq!(verdict.rules, vec!["swearing", "rule 2"]);
+
+        let verdict = filter.check("my password is secret").unwrap();
+        assert_eq!(verdict.action, FilterAction::Flag);
+        assert_eq!(verdict.text, "my password is secret");
+    }
+
+    #[test]
+    fn test_invalid_filter() {
+        assert!(ContentFilter::parse("[[rules]]\naction = \"flag\"").is_err());
+        assert!(ContentFilter::parse("[[rules]]\nwords = [\"a\"]\nregex = \"b\"\naction = \"flag\"").is_err());
+        assert!(ContentFilter::parse("[[rules]]\nregex = \"(\"\naction = \"flag\"").is_err());
+        assert!(ContentFilter::parse("[[rules]]\nwords = [\"a\"]\naction = \"delete\"").is_err());
+        assert!(ContentFilter::parse("").unwrap().check("anything").is_none());
+    }
+}
@@ -0,0 +1,228 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant};
+
+use chat::ChatMessageContent;
+
+/// Default number of messages a user may send within the flood window.
+pub const DEFAULT_FLOOD_MAX_MESSAGES: usize = 10;
+
+/// Default length of the flood window in seconds.
+pub const DEFAULT_FLOOD_WINDOW: u64 = 10;
+
+/// Default share of repeated messages within the flood window which gets a user muted.
+pub const DEFAULT_FLOOD_DUPLICATE_RATIO: f64 = 0.5;
+
+/// Default number of seconds a flooding user is muted for.
+pub const DEFAULT_MUTE_DURATION: u64 = 60;
+
+/// Guests may send only this many times fewer messages within the flood window than registered users.
+const GUEST_FLOOD_DIVISOR: usize = 3;
+
+/// The duplicate ratio is checked only once the window holds at least this many messages,
+/// so that saying "ok" twice doesn't get anyone muted.
+const DUPLICATE_MIN_MESSAGES: usize = 4;
+
+/// Struct holding the thresholds of the flood protection.
+#[derive(Clone, Debug)]
+pub struct FloodConfig {
+    /// Maximum number of messages within `window`, `None` for no limit
+    pub max_messages: Option<usize>,
+    /// Period over which the messages of a user are counted
+    pub window: Duration,
+    /// Maximum share of repeated messages within `window`, `None` for no limit
+    pub duplicate_ratio: Option<f64>,
+    /// How long a user exceeding a threshold is muted
+    pub mute_duration: Duration,
+}
+
+impl Default for FloodConfig {
+    fn default() -> Self {
+        FloodConfig {
+            max_messages: Some(DEFAULT_FLOOD_MAX_MESSAGES),
+            window: Duration::from_secs(DEFAULT_FLOOD_WINDOW),
+            duplicate_ratio: Some(DEFAULT_FLOOD_DUPLICATE_RATIO),
+            mute_duration: Duration::from_secs(DEFAULT_MUTE_DURATION),
+        }
+    }
+}
+
+/// Result of checking a message against the flood protection.
+#[derive(Debug, PartialEq)]
+pub enum FloodVerdict {
+    /// The message may be delivered.
+    Allowed,
+    /// The message exceeded a threshold and the user was muted until the given time.
+    Muted(Instant),
+    /// The user was already muted until the given time, the message must be dropped.
+    StillMuted(Instant),
+}
+
+/// Recent activity of a single user.
+#[derive(Default)]
+struct UserActivity {
+    /// Arrival times and fingerprints of the messages within the window, oldest first
+    recent: VecDeque<(Instant, u64)>,
+    muted_until: Option<Instant>,
+}
+
+/// `FloodGuard` tracks how often and how repetitively users send messages and mutes those
+/// exceeding the thresholds. The activity is tracked per user, so opening several connections doesn't help.
+pub struct FloodGuard {
+    config: FloodConfig,
+    users: HashMap<String, UserActivity>,
+}
+
+impl FloodGuard {
+    /// Creates a new instance of `FloodGuard`.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The thresholds of the flood protection.
+    ///
+    /// # Returns
+    ///
+    /// * `FloodGuard` - Returns a guard with no recorded activity.
+    pub fn new(config: FloodConfig) -> FloodGuard {
+        FloodGuard { config, users: HashMap::new() }
+    }
+
+    /// Replaces the thresholds, e.g. when the configuration is reloaded. Current mutes are kept.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The new thresholds.
+    pub fn set_config(&mut self, config: FloodConfig) {
+        self.config = config;
+    }
+
+    /// Records a message of a user and decides whether it may be delivered. Guests are limited more strictly,
+    /// even if the number of messages of registered users isn't limited.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - The username of the sender.
+    /// * `content` - The content of the message.
+    /// * `now` - The time when the message arrived.
+    ///
+    /// # Returns
+    ///
+    /// * `FloodVerdict` - Returns whether the message is allowed or the user is muted.
+    pub fn check(&mut self, username: &str, content: &ChatMessageContent, now: Instant) -> FloodVerdict {
+        let activity = self.users.entry(username.to_string()).or_default();
+
+        if let Some(until) = activity.muted_until {
+            if now < until {
+                return FloodVerdict::StillMuted(until);
+            }
+            activity.muted_until = None;
+        }
+
+        while activity.recent.front().is_some_and(|(time, _)| now.duration_since(*time) >= self.config.window) {
+            activity.recent.pop_front();
+        }
+        activity.recent.push_back((now, fingerprint(content)));
+
+        let count = activity.recent.len();
+        let max_messages = match chat::is_guest(username) {
+            true => Some(self.config.max_messages.unwrap_or(DEFAULT_FLOOD_MAX_MESSAGES).div_ceil(GUEST_FLOOD_DIVISOR)),
+            false => self.config.max_messages,
+        };
+        let too_many = max_messages.is_some_and(|max| count > max);
+        let too_repetitive = self.config.duplicate_ratio.is_some_and(|ratio| {
+            let distinct = activity.recent.iter().map(|(_, hash)| hash).collect::<HashSet<_>>().len();
+            count >= DUPLICATE_MIN_MESSAGES && (count - distinct) as f64 / count as f64 > ratio
+        });
+
+        if too_many || too_repetitive {
+            let until = now + self.config.mute_duration;
+            activity.muted_until = Some(until);
+            activity.recent.clear();
+            return FloodVerdict::Muted(until);
+        }
+
+        FloodVerdict::Allowed
+    }
+}
+
+/// Computes a fingerprint of a message content, equal contents have equal fingerprints.
+///
+/// # Arguments
+///
+/// * `content` - The content of the message.
+///
+/// # Returns
+///
+/// * `u64` - Returns the fingerprint.
+fn fingerprint(content: &ChatMessageContent) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    match content {
+        ChatMessageContent::Text(text) => (0u8, text).hash(&mut hasher),
+        ChatMessageContent::Image(data) => (1u8, data).hash(&mut hasher),
+        ChatMessageContent::File(filename, data) => (2u8, filename, data).hash(&mut hasher),
+        ChatMessageContent::LinkPreview { url, .. } => (3u8, url).hash(&mut hasher),
+        ChatMessageContent::Audio { format, data } => (4u8, format.extension(), data).hash(&mut hasher),
+    }
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use chat::ChatMessageContent;
+
+    use crate::server_flood::{FloodConfig, FloodGuard, FloodVerdict};
+
+    fn text(text: &str) -> ChatMessageContent {
+        ChatMessageContent::Text(text.to_string())
+    }
+
+    #[test]
+    fn test_flood_guard() {
+        let config = FloodConfig {
+            max_messages: Some(5),
+            window: Duration::from_secs(10),
+            duplicate_ratio: Some(0.5),
+            mute_duration: Duration::from_secs(60),
+        };
+        let start = Instant::now();
+
+        // Too many messages within the window
+        let mut guard = FloodGuard::new(config.clone());
+        for i in 0..5 {
+            assert_eq!(guard.check("Bob", &text(&i.to_string()), start), FloodVerdict::Allowed);
+        }
+        assert_eq!(guard.check("Alice", &text("hi"), start), FloodVerdict::Allowed);
+        let until = start + Duration::from_secs(60);
+        assert_eq!(guard.check("Bob", &text("5"), start), FloodVerdict::Muted(until));
+        assert_eq!(guard.check("Bob", &text("6"), start + Duration::from_secs(30)), FloodVerdict::StillMuted(until));
+        assert_eq!(guard.check("Bob", &text("7"), until), FloodVerdict::Allowed);
+
+        // Messages outside of the window don't count
+        let mut guard = FloodGuard::new(config.clone());
+        for i in 0..20 {
+            let now = start + Duration::from_secs(i * 3);
+            assert_eq!(guard.check("Bob", &text(&i.to_string()), now), FloodVerdict::Allowed);
+        }
+
+        // Repeated messages
+        let mut guard = FloodGuard::new(config.clone());
+        assert_eq!(guard.check("Bob", &text("spam"), start), FloodVerdict::Allowed);
+        assert_eq!(guard.check("Bob", &text("spam"), start), FloodVerdict::Allowed);
+        assert_eq!(guard.check("Bob", &text("eggs"), start), FloodVerdict::Allowed);
+        assert_eq!(guard.check("Bob", &text("spam"), start), FloodVerdict::Allowed);
+        assert!(matches!(guard.check("Bob", &text("spam"), start), FloodVerdict::Muted(_)));
+
+        // Guests may send a third of the messages, even without a limit for registered users
+        let mut guard = FloodGuard::new(FloodConfig { max_messages: None, ..config });
+        for i in 0..4 {
+            assert_eq!(guard.check("guest-Eve", &text(&i.to_string()), start), FloodVerdict::Allowed);
+        }
+        assert!(matches!(guard.check("guest-Eve", &text("4"), start), FloodVerdict::Muted(_)));
+        for i in 0..20 {
+            assert_eq!(guard.check("Bob", &text(&i.to_string()), start), FloodVerdict::Allowed);
+        }
+    }
+}
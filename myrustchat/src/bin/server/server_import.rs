@@ -0,0 +1,142 @@
+use crate::server_db::NewPassword;
+
+/// A user read from a row of the import file.
+#[derive(Debug, PartialEq)]
+pub struct ImportedUser {
+    /// Number of the line in the file, starting at 1
+    pub line: usize,
+    pub username: String,
+    pub password: NewPassword,
+}
+
+/// Reads the users to be imported from a CSV file with the columns `username,password`. A password starting
+/// with `$` is taken for an Argon2 hash in the PHC string format. The password is the rest of the row,
+/// so the commas of a PHC string need no quotes, but a field may be quoted like `"pass""word"`.
+/// Empty lines, lines starting with `#` and a header row are skipped.
+///
+/// # Arguments
+///
+/// * `text` - The content of the file.
+///
+/// # Returns
+///
+/// * `Result<Vec<ImportedUser>, Vec<(usize, String)>>` - Returns the users, or the numbers of the invalid lines with the reasons.
+pub fn parse_users(text: &str) -> Result<Vec<ImportedUser>, Vec<(usize, String)>> {
+    let mut users: Vec<ImportedUser> = Vec::new();
+    let mut errors = Vec::new();
+    let mut first_row = true;
+
+    for (index, row) in text.lines().enumerate() {
+        let line = index + 1;
+        if row.trim().is_empty() || row.starts_with('#') {
+            continue;
+        }
+        let header = std::mem::replace(&mut first_row, false);
+        let (username, password) = match split_row(row) {
+            Ok(fields) => fields,
+            Err(reason) => {
+                errors.push((line, reason.to_string()));
+                continue;
+            }
+        };
+        if header && username.eq_ignore_ascii_case("username") && password.eq_ignore_ascii_case("password") {
+            continue;
+        }
+
+        let username = username.trim();
+        if username.is_empty() || username.contains(char::is_whitespace) {
+            errors.push((line, "the username must not be empty or contain spaces".to_string()));
+        } else if password.is_empty() {
+            errors.push((line, "the password must not be empty".to_string()));
+        } else if let Some(first) = users.iter().find(|user| user.username == username) {
+            errors.push((line, format!("user {username} is already on line {}", first.line)));
+        } else {
+            let password = match password.starts_with('$') {
+                true => NewPassword::Hashed(password),
+                false => NewPassword::Plain(password),
+            };
+            users.push(ImportedUser { line, username: username.to_string(), password });
+        }
+    }
+
+    match errors.is_empty() {
+        true => Ok(users),
+        false => Err(errors),
+    }
+}
+
+/// Splits a row into the username and the password.
+fn split_row(row: &str) -> Result<(String, String), &'static str> {
+    let (username, rest) = take_field(row)?;
+    let rest = rest.ok_or("expected 2 fields, username and password")?;
+    if rest.starts_with('"') {
+        match take_field(rest)? {
+            (password, None) => Ok((username, password)),
+            (_, Some(_)) => Err("unexpected field after the password"),
+        }
+    } else {
+        Ok((username, rest.to_string()))
+    }
+}
+
+/// Takes the first field of a row, removing the quotes of a quoted field.
+///
+/// # Arguments
+///
+/// * `row` - The row.
+///
+/// # Returns
+///
+/// * `Result<(String, Option<&str>), &'static str>` - Returns the field and the rest of the row after the comma,
+///   `None` if it was the last field.
+fn take_field(row: &str) -> Result<(String, Option<&str>), &'static str> {
+    let Some(quoted) = row.strip_prefix('"') else {
+        return Ok(match row.split_once(',') {
+            Some((field, rest)) => (field.to_string(), Some(rest)),
+            None => (row.to_string(), None),
+        });
+    };
+
+    let mut field = String::new();
+    let mut chars = quoted.char_indices();
+    while let Some((i, c)) = chars.next() {
+        if c != '"' {
+            field.push(c);
+            continue;
+        }
+        let rest = &quoted[i + 1..];
+        if rest.starts_with('"') {
+            field.push('"');
+            chars.next();
+        } else if rest.is_empty() {
+            return Ok((field, None));
+        } else if let Some(rest) = rest.strip_prefix(',') {
+            return Ok((field, Some(rest)));
+        } else {
+            return Err("unexpected character after a closing quote");
+        }
+    }
+    Err("unterminated quoted field")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server_db::NewPassword;
+    use crate::server_import::{parse_users, ImportedUser};
+
+    #[test]
+    fn test_parse_users() {
+        let hash = "$argon2id$v=19$m=19456,t=2,p=1$c2FsdA$aGFzaA";
+        let text = format!("username,password\n\n# teachers\nalice,secret\n\"bob\",\"pass,\"\"word\"\"\"\ncarol,{hash}\n");
+        assert_eq!(parse_users(&text).unwrap(), vec![
+            ImportedUser { line: 4, username: "alice".to_string(), password: NewPassword::Plain("secret".to_string()) },
+            ImportedUser { line: 5, username: "bob".to_string(), password: NewPassword::Plain("pass,\"word\"".to_string()) },
+            ImportedUser { line: 6, username: "carol".to_string(), password: NewPassword::Hashed(hash.to_string()) },
+        ]);
+
+        let errors = parse_users("alice,secret\nbob\nalice,again\ndave,\"open\neve,\n\"frank\"x,y\n").unwrap_err();
+        let lines: Vec<usize> = errors.iter().map(|(line, _)| *line).collect();
+        assert_eq!(lines, vec![2, 3, 4, 5, 6]);
+        assert_eq!(errors[1].1, "user alice is already on line 1");
+    }
+}
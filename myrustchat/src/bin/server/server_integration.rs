@@ -0,0 +1,1239 @@
+//! End-to-end tests running the server in-process on an ephemeral port and talking to it
+//! through `ChatClient`, the same way bots do.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chat::client::{self, ChatClient, IncomingMessage, LoginError, TlsOptions};
+use chat::{AttachmentKind, AudioFormat, ChatMessage, ChatMessageContent, CodecKind, Datagram, ReplyTo, ServerResponse, UserProfile, UserStatus, FILE_CHUNK_SIZE};
+use tempfile::TempDir;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+use crate::server_db::{ServerDatabase, MEMORY_DATABASE};
+use crate::server_filter::ContentFilter;
+use crate::server_flood::FloodConfig;
+use crate::server_limits::{AttachmentLimits, StorageQuotas};
+use crate::server_lockout::LockoutConfig;
+use crate::server_preview::PreviewConfig;
+use crate::server_queue::QueuePolicy;
+use crate::server_registration::RegistrationConfig;
+use crate::server_tls;
+use crate::server_totp;
+use crate::server_transport::Listener;
+use crate::{start_server, DuplicateLogin, ServerConfig};
+
+/// How long a test waits for a datagram which should arrive.
+const RECV_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a test waits to make sure that a datagram doesn't arrive.
+const SILENCE_TIMEOUT: Duration = Duration::from_millis(300);
+
+/// A server running in the background of a test, with the users Alice, Bob and Carol
+/// whose passwords are their lowercase names.
+struct TestServer {
+    port: u16,
+    shutdown: Arc<Notify>,
+    task: JoinHandle<chat::EmptyResult>,
+    /// Holds the database and attachments until the server is dropped
+    dir: TempDir,
+}
+
+impl TestServer {
+    /// Starts a server with the given configuration, its attachment directory is replaced by a temporary one.
+    async fn start(config: ServerConfig) -> TestServer {
+        let dir = tempfile::tempdir().unwrap();
+        let db_file = dir.path().join("test.db").to_str().unwrap().to_string();
+        let attachment_dir: PathBuf = dir.path().join("attachments");
+
+        let database = ServerDatabase::new(&db_file, &attachment_dir).await.unwrap();
+        for username in ["Alice", "Bob", "Carol"] {
+            database.register_user(username, &username.to_lowercase()).await.unwrap();
+        }
+
+        let listener = Listener::bind_tcp("127.0.0.1", 0, false).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let config = ServerConfig { attachment_dir, ..config };
+        let shutdown = Arc::new(Notify::new());
+        let server_shutdown = shutdown.clone();
+        let task = tokio::spawn(async move {
+            start_server(vec![listener], &db_file, config, None, async move { server_shutdown.notified().await }).await
+        });
+
+        TestServer { port, shutdown, task, dir }
+    }
+
+    /// Logs in as one of the users. The server answers the login before it registers the client,
+    /// so this waits for the answer to a request, which comes only once the client gets messages.
+    async fn connect(&self, username: &str) -> ChatClient {
+        let mut client = self.try_connect(username, &username.to_lowercase()).await.unwrap();
+        online_users(&mut client).await;
+        client
+    }
+
+    async fn try_connect(&self, username: &str, password: &str) -> Result<ChatClient, LoginError> {
+        ChatClient::connect("127.0.0.1", self.port, username, password, CodecKind::Cbor).await
+    }
+
+    /// Tries to log in as a guest.
+    async fn try_connect_guest(&self, nickname: &str) -> Result<ChatClient, LoginError> {
+        ChatClient::connect_guest("127.0.0.1", self.port, nickname, CodecKind::Cbor).await
+    }
+
+    /// Opens the database of the running server, e.g. to change the users.
+    async fn database(&self) -> ServerDatabase {
+        let db_file = self.dir.path().join("test.db");
+        ServerDatabase::new(db_file.to_str().unwrap(), &self.dir.path().join("attachments")).await.unwrap()
+    }
+
+    /// Stops the server and waits until it has shut down.
+    async fn stop(self) {
+        self.shutdown.notify_one();
+        tokio::time::timeout(RECV_TIMEOUT, self.task).await.unwrap().unwrap().unwrap();
+    }
+}
+
+/// Waits for the next datagram matching `filter`, skipping the others.
+async fn expect<T>(client: &mut ChatClient, mut filter: impl FnMut(Datagram) -> Option<T>) -> T {
+    let wait = async {
+        loop {
+            let datagram = client.recv().await.expect("the connection was closed");
+            if let Some(found) = filter(datagram) {
+                return found;
+            }
+        }
+    };
+    tokio::time::timeout(RECV_TIMEOUT, wait).await.expect("the datagram didn't arrive in time")
+}
+
+/// Waits for the next chat message.
+async fn expect_message(client: &mut ChatClient) -> IncomingMessage {
+    tokio::time::timeout(RECV_TIMEOUT, client.recv_message()).await
+        .expect("the message didn't arrive in time")
+        .expect("the connection was closed")
+}
+
+/// Waits until the server acknowledges the message `id`.
+async fn expect_ack(client: &mut ChatClient, id: u64) {
+    expect(client, |datagram| match datagram {
+        Datagram::ServerResponse(ServerResponse::MessageAck(acked)) if acked == id => Some(()),
+        _ => None,
+    }).await
+}
+
+/// Waits until the server accepts the upload `transfer_id` and returns the chunk it continues with.
+async fn expect_accepted(client: &mut ChatClient, id: u64) -> u64 {
+    expect(client, |datagram| match datagram {
+        Datagram::ServerResponse(ServerResponse::TransferAccepted { transfer_id, next_seq }) if transfer_id == id => Some(next_seq),
+        _ => None,
+    }).await
+}
+
+/// Waits until the server closes the connection.
+async fn expect_closed(client: &mut ChatClient) {
+    let wait = async { while client.recv().await.is_some() {} };
+    tokio::time::timeout(RECV_TIMEOUT, wait).await.expect("the connection wasn't closed in time");
+}
+
+/// Waits until the server announces that a user has come online or gone offline.
+async fn expect_presence(client: &mut ChatClient, user: &str, online: bool) {
+    expect(client, |datagram| match datagram {
+        Datagram::Presence { username, online: is_online } if username == user && is_online == online => Some(()),
+        _ => None,
+    }).await
+}
+
+/// Asks the server for the online users.
+async fn online_users(client: &mut ChatClient) -> Vec<String> {
+    client.send(&Datagram::ListUsers).await.unwrap();
+    expect(client, |datagram| match datagram {
+        Datagram::ServerResponse(ServerResponse::UserList(users)) => Some(users),
+        _ => None,
+    }).await
+}
+
+#[tokio::test]
+async fn test_login() {
+    let server = TestServer::start(ServerConfig::default()).await;
+
+    let alice = server.connect("Alice").await;
+    assert_eq!(alice.username(), "Alice");
+    assert!(matches!(server.try_connect("Alice", "wrong").await, Err(LoginError::LoginFailed)));
+    assert!(server.try_connect("Mallory", "mallory").await.is_err());
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_broadcast_and_direct_messages() {
+    let server = TestServer::start(ServerConfig::default()).await;
+    let mut alice = server.connect("Alice").await;
+    let mut bob = server.connect("Bob").await;
+    let mut carol = server.connect("Carol").await;
+    assert_eq!(online_users(&mut alice).await, vec!["Alice", "Bob", "Carol"]);
+
+    let id = alice.send_text("hello everyone").await.unwrap();
+    expect_ack(&mut alice, id).await;
+    let mut received = Vec::new();
+    for client in [&mut bob, &mut carol] {
+        let incoming = expect_message(client).await;
+        assert_eq!(incoming.message.sender, "Alice");
+        assert!(!incoming.direct);
+        assert!(matches!(incoming.message.content, ChatMessageContent::Text(ref text) if text == "hello everyone"));
+        received.push(incoming.message);
+    }
+
+    // A reply refers to the original message by its sender and ID
+    let reply_id = bob.send_reply(&received[0], "hi Alice").await.unwrap();
+    expect_ack(&mut bob, reply_id).await;
+    let reply = expect_message(&mut alice).await.message;
+    assert_eq!(reply.reply_to, Some(ReplyTo { sender: "Alice".to_string(), id }));
+    assert_eq!(expect_message(&mut carol).await.message.reply_to, reply.reply_to);
+
+    // A direct message reaches only its recipient
+    let id = bob.send_direct("Carol", "just for you").await.unwrap();
+    expect_ack(&mut bob, id).await;
+    let incoming = expect_message(&mut carol).await;
+    assert!(incoming.direct);
+    assert!(matches!(incoming.message.content, ChatMessageContent::Text(ref text) if text == "just for you"));
+    assert!(tokio::time::timeout(SILENCE_TIMEOUT, alice.recv_message()).await.is_err());
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_announcements() {
+    let server = TestServer::start(ServerConfig::default()).await;
+    server.database().await.set_admin("Alice", true).await.unwrap();
+    let mut alice = server.connect("Alice").await;
+    let mut bob = server.connect("Bob").await;
+
+    let id = alice.send_announcement("maintenance at 18:00").await.unwrap();
+    expect_ack(&mut alice, id).await;
+    let incoming = expect_message(&mut bob).await;
+    assert!(incoming.message.announcement);
+    assert!(matches!(incoming.message.content, ChatMessageContent::Text(ref text) if text == "maintenance at 18:00"));
+
+    // Everyone else may only read the channel
+    let id = bob.send_announcement("me too").await.unwrap();
+    let reason = expect(&mut bob, |datagram| match datagram {
+        Datagram::ServerResponse(ServerResponse::MessageRejected { reason }) => Some(reason),
+        _ => None,
+    }).await;
+    assert_eq!(reason, "only admins can post announcements");
+    expect_ack(&mut bob, id).await;
+    assert!(tokio::time::timeout(SILENCE_TIMEOUT, alice.recv_message()).await.is_err());
+
+    // Only text can be announced
+    let image = ChatMessage {
+        announcement: true,
+        ..ChatMessage::new(100, "Alice", ChatMessageContent::Image(vec![0; 16]))
+    };
+    alice.send(&Datagram::Message(image)).await.unwrap();
+    let reason = expect(&mut alice, |datagram| match datagram {
+        Datagram::ServerResponse(ServerResponse::MessageRejected { reason }) => Some(reason),
+        _ => None,
+    }).await;
+    assert_eq!(reason, "announcements can only be text");
+    expect_ack(&mut alice, 100).await;
+    assert!(tokio::time::timeout(SILENCE_TIMEOUT, bob.recv_message()).await.is_err());
+
+    // Announcements are kept in the history
+    let stored = server.database().await.messages(None, 10).await.unwrap();
+    assert_eq!(stored.iter().map(|record| record.announcement).collect::<Vec<_>>(), vec![true]);
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_ephemeral_messages() {
+    let server = TestServer::start(ServerConfig::default()).await;
+    let mut alice = server.connect("Alice").await;
+    let mut bob = server.connect("Bob").await;
+
+    let id = alice.send_ephemeral("the door code is 1234", 1).await.unwrap();
+    expect_ack(&mut alice, id).await;
+    let incoming = expect_message(&mut bob).await;
+    assert_eq!(incoming.message.ttl, Some(1));
+    let seq = incoming.message.seq.unwrap();
+    assert_eq!(server.database().await.messages(None, 10).await.unwrap().len(), 1);
+
+    // Everyone is told when it expires, and it's gone from the history
+    for client in [&mut alice, &mut bob] {
+        let expired = expect(client, |datagram| match datagram {
+            Datagram::MessageExpired { sender, id, seq } => Some((sender, id, seq)),
+            _ => None,
+        }).await;
+        assert_eq!(expired, ("Alice".to_string(), id, Some(seq)));
+    }
+    assert!(server.database().await.messages(None, 10).await.unwrap().is_empty());
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_profiles() {
+    let server = TestServer::start(ServerConfig::default()).await;
+    let mut alice = server.connect("Alice").await;
+    let mut bob = server.connect("Bob").await;
+
+    let mut avatar = Vec::new();
+    image::RgbImage::new(16, 16).write_to(&mut std::io::Cursor::new(&mut avatar), image::ImageFormat::Png).unwrap();
+    alice.send(&Datagram::SetProfile { status: Some(" at lunch ".to_string()), avatar_png: Some(avatar.clone()) }).await.unwrap();
+    expect(&mut alice, |datagram| matches!(datagram, Datagram::ServerResponse(ServerResponse::ProfileSet)).then_some(())).await;
+    let updated = expect(&mut bob, |datagram| match datagram {
+        Datagram::ProfileUpdated { username } => Some(username),
+        _ => None,
+    }).await;
+    assert_eq!(updated, "Alice");
+
+    bob.send(&Datagram::FetchProfile("Alice".to_string())).await.unwrap();
+    let profile = expect(&mut bob, |datagram| match datagram {
+        Datagram::ServerResponse(ServerResponse::Profile(profile)) => Some(profile),
+        _ => None,
+    }).await;
+    assert_eq!(profile, UserProfile { username: "Alice".to_string(), status: Some("at lunch".to_string()), avatar_png: Some(avatar) });
+
+    // The online users are listed with their statuses, without the avatars
+    bob.send(&Datagram::ListStatuses).await.unwrap();
+    let users = expect(&mut bob, |datagram| match datagram {
+        Datagram::ServerResponse(ServerResponse::UserStatuses(users)) => Some(users),
+        _ => None,
+    }).await;
+    assert_eq!(users, vec![
+        UserStatus { username: "Alice".to_string(), status: Some("at lunch".to_string()) },
+        UserStatus { username: "Bob".to_string(), status: None },
+    ]);
+
+    // Something which isn't a PNG is rejected and the profile stays as it was
+    alice.send(&Datagram::SetProfile { status: None, avatar_png: Some(b"not a picture".to_vec()) }).await.unwrap();
+    expect(&mut alice, |datagram| matches!(datagram, Datagram::ServerResponse(ServerResponse::ProfileRejected(_))).then_some(())).await;
+    // An empty status removes it, the avatar is kept
+    alice.send(&Datagram::SetProfile { status: Some(String::new()), avatar_png: None }).await.unwrap();
+    expect(&mut alice, |datagram| matches!(datagram, Datagram::ServerResponse(ServerResponse::ProfileSet)).then_some(())).await;
+    bob.send(&Datagram::FetchProfile("Alice".to_string())).await.unwrap();
+    let profile = expect(&mut bob, |datagram| match datagram {
+        Datagram::ServerResponse(ServerResponse::Profile(profile)) => Some(profile),
+        _ => None,
+    }).await;
+    assert_eq!(profile.status, None);
+    assert!(profile.avatar_png.is_some());
+
+    // Users without a profile get an empty one
+    alice.send(&Datagram::FetchProfile("Carol".to_string())).await.unwrap();
+    let profile = expect(&mut alice, |datagram| match datagram {
+        Datagram::ServerResponse(ServerResponse::Profile(profile)) => Some(profile),
+        _ => None,
+    }).await;
+    assert_eq!(profile, UserProfile { username: "Carol".to_string(), ..UserProfile::default() });
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_file_offers() {
+    let server = TestServer::start(ServerConfig::default()).await;
+    let mut alice = server.connect("Alice").await;
+    let mut bob = server.connect("Bob").await;
+
+    // The other clients get only the name and size of the file, not its chunks
+    let kind = AttachmentKind::File("notes.txt".to_string());
+    alice.send(&Datagram::FileBegin { transfer_id: 1, id: 5, sender: "Alice".to_string(), kind, size: 6 }).await.unwrap();
+    expect_accepted(&mut alice, 1).await;
+    alice.send(&Datagram::FileChunk { transfer_id: 1, seq: 0, data: b"abcdef".to_vec() }).await.unwrap();
+    alice.send(&Datagram::FileEnd { transfer_id: 1 }).await.unwrap();
+    let id = expect(&mut bob, |datagram| match datagram {
+        Datagram::FileOffer { id, sender, filename, size, .. } if sender == "Alice" && filename == "notes.txt" && size == 6 => Some(id),
+        Datagram::FileBegin { .. } | Datagram::FileChunk { .. } => panic!("a file was sent without being asked for"),
+        _ => None,
+    }).await;
+
+    bob.send(&Datagram::FetchAttachment { id, from_seq: 0 }).await.unwrap();
+    let transfer_id = expect(&mut bob, |datagram| match datagram {
+        Datagram::FileBegin { transfer_id, kind: AttachmentKind::File(filename), size: 6, .. } if filename == "notes.txt" => Some(transfer_id),
+        _ => None,
+    }).await;
+    let mut data = Vec::new();
+    loop {
+        match expect(&mut bob, Some).await {
+            Datagram::FileChunk { transfer_id: chunk_id, data: chunk, .. } if chunk_id == transfer_id => data.extend(chunk),
+            Datagram::FileEnd { transfer_id: end_id } if end_id == transfer_id => break,
+            datagram => panic!("unexpected datagram {datagram:?}"),
+        }
+    }
+    assert_eq!(data, b"abcdef");
+
+    // A file sent in a single message is announced the same way
+    let message = ChatMessage::new(6, "Alice", ChatMessageContent::File("small.txt".to_string(), b"hi".to_vec()));
+    alice.send(&Datagram::Message(message)).await.unwrap();
+    expect(&mut bob, |datagram| match datagram {
+        Datagram::FileOffer { filename, size: 2, .. } if filename == "small.txt" => Some(()),
+        Datagram::Message(_) => panic!("the file was sent inline"),
+        _ => None,
+    }).await;
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_storage_quotas() {
+    let quotas = StorageQuotas { default: Some(10), users: [("Bob".to_string(), 0)].into() };
+    let config = ServerConfig { attachments: AttachmentLimits { quotas, ..AttachmentLimits::default() }, ..ServerConfig::default() };
+    let server = TestServer::start(config).await;
+    let mut alice = server.connect("Alice").await;
+    let mut bob = server.connect("Bob").await;
+    let file = |id, sender: &str, data: &[u8]| ChatMessage::new(id, sender, ChatMessageContent::File("notes.txt".to_string(), data.to_vec()));
+
+    alice.send(&Datagram::Message(file(1, "Alice", b"abcdef"))).await.unwrap();
+    expect_ack(&mut alice, 1).await;
+
+    // The second file would exceed the quota of Alice
+    alice.send(&Datagram::Message(file(2, "Alice", b"ghijkl"))).await.unwrap();
+    let reason = expect(&mut alice, |datagram| match datagram {
+        Datagram::ServerResponse(ServerResponse::AttachmentRejected { reason }) => Some(reason),
+        _ => None,
+    }).await;
+    assert!(reason.contains("4 B of your storage quota of 10 B"), "{reason}");
+    expect_ack(&mut alice, 2).await;
+
+    // A chunked upload is refused before any chunk is sent
+    let kind = AttachmentKind::File("big.txt".to_string());
+    alice.send(&Datagram::FileBegin { transfer_id: 3, id: 3, sender: "Alice".to_string(), kind, size: 5 }).await.unwrap();
+    expect(&mut alice, |datagram| match datagram {
+        Datagram::FileAbort { transfer_id: 3 } => Some(()),
+        _ => None,
+    }).await;
+    expect(&mut alice, |datagram| match datagram {
+        Datagram::ServerResponse(ServerResponse::AttachmentRejected { .. }) => Some(()),
+        _ => None,
+    }).await;
+
+    // Bob has no limit
+    bob.send(&Datagram::Message(file(4, "Bob", &[0; 20]))).await.unwrap();
+    expect_ack(&mut bob, 4).await;
+
+    // Uploads in progress count against the quota until they're stored or aborted
+    let mut carol = server.connect("Carol").await;
+    let begin = |transfer_id| Datagram::FileBegin {
+        transfer_id, id: transfer_id, sender: "Carol".to_string(), kind: AttachmentKind::File("half.txt".to_string()), size: 6,
+    };
+    carol.send(&begin(5)).await.unwrap();
+    expect_accepted(&mut carol, 5).await;
+    carol.send(&begin(6)).await.unwrap();
+    expect(&mut carol, |datagram| match datagram {
+        Datagram::FileAbort { transfer_id: 6 } => Some(()),
+        _ => None,
+    }).await;
+    carol.send(&Datagram::FileAbort { transfer_id: 5 }).await.unwrap();
+    carol.send(&begin(7)).await.unwrap();
+    expect_accepted(&mut carol, 7).await;
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_voice_notes() {
+    let server = TestServer::start(ServerConfig::default()).await;
+    let mut alice = server.connect("Alice").await;
+    let mut bob = server.connect("Bob").await;
+
+    let opus = b"OggS\0\x02\0\0\0\0\0\0\0\0\x01\0\0\0\0\0\0\0\0\0\0\0\x01\x13OpusHead\x01\x01".to_vec();
+    let voice_note = |id, format| ChatMessage::new(id, "Alice", ChatMessageContent::Audio { format, data: opus.clone() });
+
+    // Voice notes are delivered inline
+    alice.send(&Datagram::Message(voice_note(1, AudioFormat::Opus))).await.unwrap();
+    expect_ack(&mut alice, 1).await;
+    let message = expect_message(&mut bob).await.message;
+    assert!(matches!(message.content, ChatMessageContent::Audio { format: AudioFormat::Opus, ref data } if *data == opus));
+
+    // The content has to match the format
+    alice.send(&Datagram::Message(voice_note(2, AudioFormat::Mp3))).await.unwrap();
+    expect(&mut alice, |datagram| match datagram {
+        Datagram::ServerResponse(ServerResponse::AttachmentRejected { reason }) => Some(reason),
+        _ => None,
+    }).await;
+    expect_ack(&mut alice, 2).await;
+    assert!(tokio::time::timeout(SILENCE_TIMEOUT, bob.recv_message()).await.is_err());
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_resumed_transfers() {
+    let server = TestServer::start(ServerConfig::default()).await;
+    let mut bob = server.connect("Bob").await;
+    let chunk = vec![7u8; FILE_CHUNK_SIZE];
+    let size = FILE_CHUNK_SIZE as u64 + 3;
+    let begin = Datagram::FileBegin { transfer_id: 42, id: 1, sender: "Alice".to_string(), kind: AttachmentKind::File("big.bin".to_string()), size };
+
+    // The connection drops after the first chunk
+    let mut alice = server.connect("Alice").await;
+    alice.send(&begin).await.unwrap();
+    assert_eq!(expect_accepted(&mut alice, 42).await, 0);
+    alice.send(&Datagram::FileChunk { transfer_id: 42, seq: 0, data: chunk.clone() }).await.unwrap();
+    online_users(&mut alice).await;
+    drop(alice);
+
+    // The same upload continues with the second chunk
+    let mut alice = server.connect("Alice").await;
+    alice.send(&begin).await.unwrap();
+    assert_eq!(expect_accepted(&mut alice, 42).await, 1);
+    alice.send(&Datagram::FileChunk { transfer_id: 42, seq: 1, data: b"end".to_vec() }).await.unwrap();
+    alice.send(&Datagram::FileEnd { transfer_id: 42 }).await.unwrap();
+    expect_ack(&mut alice, 1).await;
+    let id = expect(&mut bob, |datagram| match datagram {
+        Datagram::FileOffer { id, size: offered, .. } if offered == size => Some(id),
+        _ => None,
+    }).await;
+
+    // A download resumed at the second chunk gets only the rest of the file
+    bob.send(&Datagram::FetchAttachment { id, from_seq: 1 }).await.unwrap();
+    expect(&mut bob, |datagram| match datagram {
+        Datagram::FileBegin { size: announced, .. } if announced == size => Some(()),
+        _ => None,
+    }).await;
+    match expect(&mut bob, Some).await {
+        Datagram::FileChunk { seq, data, .. } => assert_eq!((seq, data.as_slice()), (1, b"end".as_slice())),
+        datagram => panic!("unexpected datagram {datagram:?}"),
+    }
+    assert!(matches!(expect(&mut bob, Some).await, Datagram::FileEnd { .. }));
+
+    // A finished upload starts over
+    alice.send(&begin).await.unwrap();
+    assert_eq!(expect_accepted(&mut alice, 42).await, 0);
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_spoofed_sender_is_disconnected() {
+    let server = TestServer::start(ServerConfig::default()).await;
+    let mut alice = server.connect("Alice").await;
+    let mut bob = server.connect("Bob").await;
+
+    let spoofed = ChatMessage::new(1, "Alice", ChatMessageContent::Text("I am Alice".to_string()));
+    bob.send(&Datagram::Message(spoofed)).await.unwrap();
+    expect_closed(&mut bob).await;
+
+    // The message isn't delivered and Bob is gone
+    assert!(tokio::time::timeout(SILENCE_TIMEOUT, alice.recv_message()).await.is_err());
+    assert_eq!(online_users(&mut alice).await, vec!["Alice"]);
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_disconnect_cleanup() {
+    let server = TestServer::start(ServerConfig::default()).await;
+    let mut alice = server.connect("Alice").await;
+    let bob = server.connect("Bob").await;
+    expect_presence(&mut alice, "Bob", true).await;
+
+    drop(bob);
+    expect_presence(&mut alice, "Bob", false).await;
+    assert_eq!(online_users(&mut alice).await, vec!["Alice"]);
+
+    // Shutting the server down disconnects the remaining clients
+    server.stop().await;
+    expect_closed(&mut alice).await;
+}
+
+#[tokio::test]
+async fn test_federation() {
+    let secret = Some("secret".to_string());
+    let server_b = TestServer::start(ServerConfig { server_id: "b".to_string(), peer_secret: secret.clone(), ..ServerConfig::default() }).await;
+    let server_a = TestServer::start(ServerConfig {
+        server_id: "a".to_string(),
+        peers: vec![format!("127.0.0.1:{}", server_b.port)],
+        peer_secret: secret,
+        ..ServerConfig::default()
+    }).await;
+    let mut alice = server_a.connect("Alice").await;
+    let mut bob = server_b.connect("Bob").await;
+
+    // The link is set up in the background, messages sent before it's up aren't relayed
+    let mut relayed = None;
+    for _ in 0..10 {
+        let id = alice.send_text("hello from a").await.unwrap();
+        expect_ack(&mut alice, id).await;
+        if let Ok(incoming) = tokio::time::timeout(SILENCE_TIMEOUT, bob.recv_message()).await {
+            relayed = incoming;
+            break;
+        }
+    }
+    let incoming = relayed.expect("the message wasn't relayed");
+    assert_eq!(incoming.message.sender, "Alice@a");
+    assert!(matches!(incoming.message.content, ChatMessageContent::Text(ref text) if text == "hello from a"));
+
+    // The link works both ways and messages don't come back to their origin
+    bob.send_text("hello from b").await.unwrap();
+    let incoming = expect_message(&mut alice).await;
+    assert_eq!(incoming.message.sender, "Bob@b");
+    assert!(tokio::time::timeout(SILENCE_TIMEOUT, bob.recv_message()).await.is_err());
+
+    // Announcements reach linked servers as ordinary messages, their admins aren't known there
+    server_a.database().await.set_admin("Alice", true).await.unwrap();
+    let id = alice.send_announcement("maintenance at 18:00").await.unwrap();
+    expect_ack(&mut alice, id).await;
+    let incoming = expect_message(&mut bob).await;
+    assert_eq!(incoming.message.sender, "Alice@a");
+    assert!(!incoming.message.announcement);
+
+    server_a.stop().await;
+    server_b.stop().await;
+}
+
+#[tokio::test]
+async fn test_two_factor_login() {
+    let server = TestServer::start(ServerConfig::default()).await;
+    let secret = server_totp::new_secret();
+    server.database().await.set_totp_secret("Bob", Some(&secret)).await.unwrap();
+
+    let login = |code: Option<String>| async move {
+        let (mut read_half, mut write_half) = client::open_tcp("127.0.0.1", server.port).await.unwrap();
+        client::login(&mut read_half, &mut write_half, "Bob", "bob", || code, CodecKind::Cbor, false, None).await
+    };
+    let code = server_totp::totp(&secret, "Bob").unwrap().generate_current().unwrap();
+    assert!(login(Some(code)).await.is_ok());
+    assert!(matches!(login(Some("000000".to_string())).await, Err(LoginError::InvalidCode)));
+    assert!(matches!(login(None).await, Err(LoginError::TotpRequired)));
+    // Users without two-factor authentication aren't asked for a code
+    assert_eq!(server.connect("Alice").await.username(), "Alice");
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_login_lockout() {
+    let lockout = LockoutConfig { attempts: Some(3), duration: Duration::from_secs(60) };
+    let server = TestServer::start(ServerConfig { lockout, ..ServerConfig::default() }).await;
+
+    // A successful login doesn't count, and unknown users fail like wrong passwords
+    server.connect("Bob").await;
+    assert!(matches!(server.try_connect("Bob", "wrong").await, Err(LoginError::LoginFailed)));
+    assert!(matches!(server.try_connect("Bob", "guess").await, Err(LoginError::LoginFailed)));
+    assert!(matches!(server.try_connect("Mallory", "mallory").await, Err(LoginError::LoginFailed)));
+
+    // Three failures from the same address lock out everyone, even with the right password
+    assert!(matches!(server.try_connect("Bob", "bob").await, Err(LoginError::Locked(_))));
+    assert!(matches!(server.try_connect("Alice", "alice").await, Err(LoginError::Locked(_))));
+
+    let outcomes: Vec<_> = server.database().await.login_audit(None, 10).await.unwrap().into_iter()
+        .map(|record| format!("{} {}", record.username, record.outcome))
+        .collect();
+    assert_eq!(outcomes, [
+        "Bob success", "Bob wrong-password", "Bob wrong-password", "Mallory wrong-password", "Bob locked", "Alice locked",
+    ]);
+    let bob = server.database().await.login_audit(Some("Bob"), 2).await.unwrap();
+    assert_eq!(bob.len(), 2);
+    assert_eq!(bob[0].address.as_deref(), Some("127.0.0.1"));
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_htpasswd_backend() {
+    let dir = tempfile::tempdir().unwrap();
+    let htpasswd_file = dir.path().join("users.htpasswd");
+    let alice = bcrypt::hash("secret", 4).unwrap();
+    let dave = bcrypt::hash("dave", 4).unwrap();
+    let eve = bcrypt::hash("eve", 4).unwrap();
+    std::fs::write(&htpasswd_file, format!("Alice:{alice}\nDave:{dave}\neve+smith:{eve}\n")).unwrap();
+    let server = TestServer::start(ServerConfig { htpasswd_file: Some(htpasswd_file), ..ServerConfig::default() }).await;
+
+    // The passwords in the database no longer count, users only in the file are added on their first login
+    assert!(matches!(server.try_connect("Alice", "alice").await, Err(LoginError::LoginFailed)));
+    assert!(matches!(server.try_connect("Bob", "bob").await, Err(LoginError::LoginFailed)));
+    let mut alice = server.try_connect("Alice", "secret").await.unwrap();
+    assert!(!server.database().await.user_exists("Dave").await.unwrap());
+    let dave = server.connect("Dave").await;
+    assert!(server.database().await.user_exists("Dave").await.unwrap());
+    // Names which couldn't be registered are refused, even if the file knows them
+    assert!(matches!(server.try_connect("eve+smith", "eve").await, Err(LoginError::LoginFailed)));
+    assert!(!server.database().await.user_exists("eve+smith").await.unwrap());
+
+    dave.send_text("hi from the directory").await.unwrap();
+    assert_eq!(expect_message(&mut alice).await.message.sender, "Dave");
+
+    // The file is the only place to change passwords
+    alice.send(&Datagram::ChangePassword { old_password: "secret".to_string(), new_password: "other".to_string() }).await.unwrap();
+    expect(&mut alice, |datagram| match datagram {
+        Datagram::ServerResponse(ServerResponse::PasswordChangeFailed(_)) => Some(()),
+        _ => None,
+    }).await;
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_write_timeout() {
+    // Under the block policy, a client which doesn't read would hold up everyone without the timeout
+    let server = TestServer::start(ServerConfig {
+        write_timeout: Some(Duration::from_millis(500)),
+        send_queue_size: 1,
+        send_queue_policy: QueuePolicy::Block,
+        flood: FloodConfig { max_messages: None, duplicate_ratio: None, ..FloodConfig::default() },
+        ..ServerConfig::default()
+    }).await;
+    let mut alice = server.connect("Alice").await;
+    let (mut read_half, mut write_half) = client::open_tcp("127.0.0.1", server.port).await.unwrap();
+    client::login(&mut read_half, &mut write_half, "Bob", "bob", || None, CodecKind::Cbor, false, None).await.unwrap();
+    expect_presence(&mut alice, "Bob", true).await;
+
+    // Bob never reads, so the messages fill up the socket buffers until a write to him times out
+    let text = "x".repeat(512 * 1024);
+    for _ in 0..40 {
+        alice.send_text(&text).await.unwrap();
+    }
+    expect_presence(&mut alice, "Bob", false).await;
+    assert_eq!(online_users(&mut alice).await, ["Alice"]);
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_duplicate_login() {
+    // By default, all connections of a user get the messages sent to the user
+    let server = TestServer::start(ServerConfig::default()).await;
+    let alice = server.connect("Alice").await;
+    let mut bob = server.connect("Bob").await;
+    let mut bob_phone = server.connect("Bob").await;
+    alice.send_direct("Bob", "hi both").await.unwrap();
+    assert!(expect_message(&mut bob).await.direct);
+    assert!(expect_message(&mut bob_phone).await.direct);
+    server.stop().await;
+
+    let server = TestServer::start(ServerConfig { duplicate_login: DuplicateLogin::RejectNew, ..ServerConfig::default() }).await;
+    let mut bob = server.connect("Bob").await;
+    assert!(matches!(server.try_connect("Bob", "bob").await, Err(LoginError::AlreadyLoggedIn)));
+    assert_eq!(online_users(&mut bob).await, ["Bob"]);
+    drop(bob);
+    // The user can log in again once the first connection is gone
+    let wait = async {
+        while server.try_connect("Bob", "bob").await.is_err() {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    };
+    tokio::time::timeout(RECV_TIMEOUT, wait).await.unwrap();
+    let outcomes: Vec<_> = server.database().await.login_audit(Some("Bob"), 10).await.unwrap().into_iter()
+        .map(|record| record.outcome)
+        .collect();
+    assert!(outcomes.contains(&"already-logged-in".to_string()));
+    server.stop().await;
+
+    let server = TestServer::start(ServerConfig { duplicate_login: DuplicateLogin::KickOld, ..ServerConfig::default() }).await;
+    let mut alice = server.connect("Alice").await;
+    let mut bob = server.connect("Bob").await;
+    expect_presence(&mut alice, "Bob", true).await;
+    let mut bob_phone = server.connect("Bob").await;
+    expect(&mut bob, |datagram| matches!(datagram, Datagram::ServerResponse(ServerResponse::AlreadyLoggedIn)).then_some(())).await;
+    expect_closed(&mut bob).await;
+    // The user stayed online the whole time
+    let presence = expect(&mut alice, |datagram| matches!(datagram, Datagram::Presence { .. }).then_some(()));
+    assert!(tokio::time::timeout(SILENCE_TIMEOUT, presence).await.is_err());
+    assert_eq!(online_users(&mut alice).await, ["Alice", "Bob"]);
+    alice.send_direct("Bob", "only the phone").await.unwrap();
+    assert!(matches!(expect_message(&mut bob_phone).await.message.content, ChatMessageContent::Text(text) if text == "only the phone"));
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_guests() {
+    let server = TestServer::start(ServerConfig::default()).await;
+    assert!(matches!(server.try_connect_guest("Eve").await, Err(LoginError::LoginFailed)));
+    server.stop().await;
+
+    let server = TestServer::start(ServerConfig { allow_guests: true, ..ServerConfig::default() }).await;
+    let mut alice = server.connect("Alice").await;
+    let mut eve = server.try_connect_guest("Eve").await.unwrap();
+    assert_eq!(eve.username(), "guest-Eve");
+    expect_presence(&mut alice, "guest-Eve", true).await;
+    assert!(matches!(server.try_connect_guest("Eve").await, Err(LoginError::AlreadyLoggedIn)));
+    assert!(matches!(server.try_connect_guest("two words").await, Err(LoginError::LoginFailed)));
+
+    // Guests chat like everyone else, but their messages aren't kept
+    let id = eve.send_text("hello").await.unwrap();
+    expect_ack(&mut eve, id).await;
+    assert_eq!(expect_message(&mut alice).await.message.sender, "guest-Eve");
+    alice.send_direct("guest-Eve", "welcome").await.unwrap();
+    assert!(expect_message(&mut eve).await.direct);
+    assert!(server.database().await.messages(None, 10).await.unwrap().is_empty());
+
+    // They can't send attachments
+    let kind = AttachmentKind::File("notes.txt".to_string());
+    eve.send(&Datagram::FileBegin { transfer_id: 1, id: 7, sender: "guest-Eve".to_string(), kind, size: 6 }).await.unwrap();
+    expect(&mut eve, |datagram| matches!(datagram, Datagram::FileAbort { transfer_id: 1 }).then_some(())).await;
+    let reason = expect(&mut eve, |datagram| match datagram {
+        Datagram::ServerResponse(ServerResponse::AttachmentRejected { reason }) => Some(reason),
+        _ => None,
+    }).await;
+    assert_eq!(reason, "guests can't send attachments");
+    eve.send(&Datagram::SetNickname(Some("Evelyn".to_string()))).await.unwrap();
+    expect(&mut eve, |datagram| matches!(datagram, Datagram::ServerResponse(ServerResponse::NicknameRejected(_))).then_some(())).await;
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_blocks() {
+    let server = TestServer::start(ServerConfig::default()).await;
+    let mut alice = server.connect("Alice").await;
+    let mut bob = server.connect("Bob").await;
+    let mut carol = server.connect("Carol").await;
+    let block_list = |datagram| match datagram {
+        Datagram::ServerResponse(ServerResponse::BlockList(users)) => Some(users),
+        _ => None,
+    };
+    let block_failed = |datagram| match datagram {
+        Datagram::ServerResponse(ServerResponse::BlockFailed(reason)) => Some(reason),
+        _ => None,
+    };
+
+    alice.send(&Datagram::Block("Bob".to_string())).await.unwrap();
+    assert_eq!(expect(&mut alice, block_list).await, vec!["Bob"]);
+    alice.send(&Datagram::Block("Dave".to_string())).await.unwrap();
+    assert_eq!(expect(&mut alice, block_failed).await, "There is no user Dave.");
+    alice.send(&Datagram::Block("Alice".to_string())).await.unwrap();
+    expect(&mut alice, block_failed).await;
+
+    // Alice no longer gets the messages of Bob, the others do
+    let id = bob.send_text("anyone there?").await.unwrap();
+    expect_ack(&mut bob, id).await;
+    assert_eq!(expect_message(&mut carol).await.message.sender, "Bob");
+    bob.send_direct("Alice", "hello?").await.unwrap();
+    assert!(tokio::time::timeout(SILENCE_TIMEOUT, alice.recv_message()).await.is_err());
+
+    // Blocks are one-way
+    alice.send_text("hi Carol").await.unwrap();
+    assert_eq!(expect_message(&mut bob).await.message.sender, "Alice");
+
+    alice.send(&Datagram::Unblock("Bob".to_string())).await.unwrap();
+    assert!(expect(&mut alice, block_list).await.is_empty());
+    alice.send(&Datagram::Unblock("Bob".to_string())).await.unwrap();
+    assert_eq!(expect(&mut alice, block_failed).await, "Bob is not blocked.");
+    bob.send_text("back again").await.unwrap();
+    assert_eq!(expect_message(&mut alice).await.message.sender, "Bob");
+
+    // The block list is kept in the database
+    carol.send(&Datagram::Block("Alice".to_string())).await.unwrap();
+    expect(&mut carol, block_list).await;
+    carol.send(&Datagram::ListBlocks).await.unwrap();
+    assert_eq!(expect(&mut carol, block_list).await, vec!["Alice"]);
+    assert_eq!(server.database().await.blocks().await.unwrap(), vec![("Carol".to_string(), "Alice".to_string())]);
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_memory_database() {
+    let listener = Listener::bind_tcp("127.0.0.1", 0, false).await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let shutdown = Arc::new(Notify::new());
+    let server_shutdown = shutdown.clone();
+    let config = ServerConfig { allow_guests: true, ..ServerConfig::default() };
+    let task = tokio::spawn(async move {
+        start_server(vec![listener], MEMORY_DATABASE, config, None, async move { server_shutdown.notified().await }).await
+    });
+
+    // A throwaway server has no users, but guests can chat and send images
+    let mut eve = ChatClient::connect_guest("127.0.0.1", port, "Eve", CodecKind::Cbor).await.unwrap();
+    let mut mallory = ChatClient::connect_guest("127.0.0.1", port, "Mallory", CodecKind::Cbor).await.unwrap();
+    online_users(&mut mallory).await;
+    let id = eve.send_text("hello").await.unwrap();
+    expect_ack(&mut eve, id).await;
+    assert_eq!(expect_message(&mut mallory).await.message.sender, "guest-Eve");
+    eve.send(&Datagram::Stats).await.unwrap();
+    let stats = expect(&mut eve, |datagram| match datagram {
+        Datagram::ServerResponse(ServerResponse::Stats(stats)) => Some(stats),
+        _ => None,
+    }).await;
+    assert_eq!(stats.users_online, 2);
+
+    shutdown.notify_one();
+    tokio::time::timeout(RECV_TIMEOUT, task).await.unwrap().unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn test_certificate_login() {
+    let tls_file = |name: &str| PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("testdata").join("tls").join(name);
+    let dir = tempfile::tempdir().unwrap();
+    let db_file = dir.path().join("test.db").to_str().unwrap().to_string();
+    let database = ServerDatabase::new(&db_file, &dir.path().join("attachments")).await.unwrap();
+    database.register_user("bot", "secret").await.unwrap();
+    database.register_user("Alice", "alice").await.unwrap();
+    let fingerprint = server_tls::certificate_fingerprint(&tls_file("bot.pem")).unwrap();
+    database.add_certificate("bot", &fingerprint).await.unwrap();
+
+    let plain = Listener::bind_tcp("127.0.0.1", 0, false).await.unwrap();
+    let port = plain.local_addr().unwrap().port();
+    let acceptor = server_tls::load_acceptor(&tls_file("server.pem"), &tls_file("server.key")).unwrap();
+    let tls = Listener::bind_tcp("127.0.0.1", 0, false).await.unwrap().with_tls(acceptor);
+    let tls_port = tls.local_addr().unwrap().port();
+    let shutdown = Arc::new(Notify::new());
+    let server_shutdown = shutdown.clone();
+    let config = ServerConfig { attachment_dir: dir.path().join("attachments"), ..ServerConfig::default() };
+    let task = tokio::spawn(async move {
+        start_server(vec![plain, tls], &db_file, config, None, async move { server_shutdown.notified().await }).await
+    });
+
+    let identity = |name: &str| TlsOptions {
+        ca_file: Some(tls_file("ca.pem")),
+        identity: Some((tls_file(&format!("{name}.pem")), tls_file(&format!("{name}.key")))),
+    };
+    let mut bot = ChatClient::connect_with_certificate("127.0.0.1", tls_port, &identity("bot"), "bot", CodecKind::Cbor).await.unwrap();
+    online_users(&mut bot).await;
+
+    // The certificate must be registered for the user, and there must be one
+    let as_alice = ChatClient::connect_with_certificate("127.0.0.1", tls_port, &identity("bot"), "Alice", CodecKind::Cbor).await;
+    assert!(matches!(as_alice, Err(LoginError::LoginFailed)));
+    let stranger = ChatClient::connect_with_certificate("127.0.0.1", tls_port, &identity("stranger"), "bot", CodecKind::Cbor).await;
+    assert!(matches!(stranger, Err(LoginError::LoginFailed)));
+    let (mut read_half, mut write_half) = client::open_tls("127.0.0.1", tls_port, &TlsOptions { ca_file: Some(tls_file("ca.pem")), identity: None }).await.unwrap();
+    let anonymous = client::certificate_login(&mut read_half, &mut write_half, "bot", CodecKind::Cbor, false, None).await;
+    assert!(matches!(anonymous, Err(LoginError::LoginFailed)));
+    let (mut read_half, mut write_half) = client::open_tcp("127.0.0.1", port).await.unwrap();
+    let plain_text = client::certificate_login(&mut read_half, &mut write_half, "bot", CodecKind::Cbor, false, None).await;
+    assert!(matches!(plain_text, Err(LoginError::LoginFailed)));
+
+    // Passwords still work on both ports, and the server certificate must be trusted
+    let (read_half, write_half) = client::open_tls("127.0.0.1", tls_port, &TlsOptions { ca_file: Some(tls_file("ca.pem")), identity: None }).await.unwrap();
+    let mut alice = ChatClient::login(read_half, write_half, "Alice", "alice", CodecKind::Cbor).await.unwrap();
+    online_users(&mut alice).await;
+    let id = bot.send_text("beep").await.unwrap();
+    expect_ack(&mut bot, id).await;
+    assert_eq!(expect_message(&mut alice).await.message.sender, "bot");
+    assert!(matches!(client::open_tls("127.0.0.1", tls_port, &TlsOptions::default()).await, Err(LoginError::Connect(..))));
+
+    let outcomes: Vec<_> = database.login_audit(Some("bot"), 10).await.unwrap().into_iter().map(|record| record.outcome).collect();
+    assert_eq!(outcomes, ["success", "unknown-certificate", "unknown-certificate", "unknown-certificate"]);
+
+    shutdown.notify_one();
+    tokio::time::timeout(RECV_TIMEOUT, task).await.unwrap().unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn test_stats() {
+    let server = TestServer::start(ServerConfig::default()).await;
+    let mut alice = server.connect("Alice").await;
+    let _bob = server.connect("Bob").await;
+    let id = alice.send_text("hello").await.unwrap();
+    expect_ack(&mut alice, id).await;
+
+    alice.send(&Datagram::Stats).await.unwrap();
+    let stats = expect(&mut alice, |datagram| match datagram {
+        Datagram::ServerResponse(ServerResponse::Stats(stats)) => Some(stats),
+        _ => None,
+    }).await;
+    assert_eq!(stats.users_online, 2);
+    assert_eq!(stats.messages, 1);
+    assert!(stats.database_size > 0);
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_fetch_since() {
+    let server = TestServer::start(ServerConfig::default()).await;
+    let mut alice = server.connect("Alice").await;
+    let mut bob = server.connect("Bob").await;
+    let received = |datagram| match datagram {
+        Datagram::Message(message) => Some(message),
+        _ => None,
+    };
+
+    let mut seqs = Vec::new();
+    for text in ["one", "two", "three"] {
+        let id = alice.send_text(text).await.unwrap();
+        expect_ack(&mut alice, id).await;
+        seqs.push(expect(&mut bob, received).await.seq.unwrap());
+    }
+    assert!(seqs[0] < seqs[1] && seqs[1] < seqs[2]);
+
+    // The ID sent by the client is replaced
+    let spoofed = ChatMessage {
+        seq: Some(1),
+        ..ChatMessage::new(9, "Alice", ChatMessageContent::Text("four".to_string()))
+    };
+    alice.send(&Datagram::Message(spoofed)).await.unwrap();
+    expect_ack(&mut alice, 9).await;
+    let four = expect(&mut bob, received).await.seq.unwrap();
+    assert!(four > seqs[2]);
+
+    // Carol missed the messages and fetches those after the first one
+    let mut carol = server.connect("Carol").await;
+    carol.send(&Datagram::FetchSince { last_id: seqs[0] }).await.unwrap();
+    let mut fetched = Vec::new();
+    let (last_id, more) = expect(&mut carol, |datagram| match datagram {
+        Datagram::Message(ChatMessage { seq, content: ChatMessageContent::Text(text), .. }) => {
+            fetched.push((seq.unwrap(), text));
+            None
+        },
+        Datagram::ServerResponse(ServerResponse::FetchComplete { last_id, more }) => Some((last_id, more)),
+        _ => None,
+    }).await;
+    assert_eq!(fetched, vec![
+        (seqs[1], "two".to_string()),
+        (seqs[2], "three".to_string()),
+        (four, "four".to_string()),
+    ]);
+    assert_eq!((last_id, more), (four, false));
+
+    carol.send(&Datagram::FetchSince { last_id: four }).await.unwrap();
+    let complete = expect(&mut carol, |datagram| match datagram {
+        Datagram::Message(_) => panic!("no message is newer"),
+        Datagram::ServerResponse(ServerResponse::FetchComplete { last_id, more }) => Some((last_id, more)),
+        _ => None,
+    }).await;
+    assert_eq!(complete, (four, false));
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_resume_after_reconnect() {
+    let server = TestServer::start(ServerConfig::default()).await;
+    let mut alice = server.connect("Alice").await;
+    let mut bob = server.connect("Bob").await;
+
+    let id = alice.send_text("one").await.unwrap();
+    expect_ack(&mut alice, id).await;
+    let last_id = expect_message(&mut bob).await.message.seq.unwrap();
+
+    // Bob's connection breaks and the chat goes on without him
+    drop(bob);
+    expect_presence(&mut alice, "Bob", false).await;
+    for text in ["two", "three"] {
+        let id = alice.send_text(text).await.unwrap();
+        expect_ack(&mut alice, id).await;
+    }
+
+    let (mut read_half, mut write_half) = client::open_tcp("127.0.0.1", server.port).await.unwrap();
+    let codec = client::login(&mut read_half, &mut write_half, "Bob", "bob", || None, CodecKind::Cbor, false, Some(last_id)).await.unwrap();
+    let id = alice.send_text("four").await.unwrap();
+    expect_ack(&mut alice, id).await;
+
+    // The missed messages come first, then the new one
+    let mut received = Vec::new();
+    while received.len() < 4 {
+        let datagram = tokio::time::timeout(RECV_TIMEOUT, Datagram::read_from_stream(&mut read_half, &codec)).await.unwrap().unwrap();
+        match datagram {
+            Datagram::Message(ChatMessage { content: ChatMessageContent::Text(text), .. }) => received.push(text),
+            Datagram::ServerResponse(ServerResponse::FetchComplete { more: false, .. }) => received.push("complete".to_string()),
+            _ => {},
+        }
+    }
+    assert_eq!(received, vec!["two", "three", "complete", "four"]);
+
+    server.stop().await;
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_admin_console() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let console_dir = tempfile::tempdir().unwrap();
+    let admin_socket = console_dir.path().join("admin.sock");
+    let server = TestServer::start(ServerConfig { admin_socket: Some(admin_socket.clone()), ..ServerConfig::default() }).await;
+    let mut alice = server.connect("Alice").await;
+    let mut bob = server.connect("Bob").await;
+
+    let mut console = tokio::net::UnixStream::connect(&admin_socket).await.unwrap();
+    console.write_all(b"list-clients\nkick Carol\nbroadcast Restart at noon\nkick Bob\nban Carol\nquit\n").await.unwrap();
+    let mut reply = String::new();
+    tokio::time::timeout(RECV_TIMEOUT, console.read_to_string(&mut reply)).await.unwrap().unwrap();
+    let lines: Vec<&str> = reply.lines().collect();
+    assert_eq!(lines[0], "2 connected clients");
+    assert!(lines[1].contains("Alice") && lines[2].contains("Bob"));
+    assert_eq!(&lines[3..], ["Error: Carol is not online.", "Sent to 2 clients.", "Kicked 1 connections of Bob.", "Banned Carol."]);
+
+    for client in [&mut alice, &mut bob] {
+        let text = expect(client, |datagram| match datagram {
+            Datagram::Announcement(text) => Some(text),
+            _ => None,
+        }).await;
+        assert_eq!(text, "Restart at noon");
+    }
+    expect_closed(&mut bob).await;
+
+    let events: Vec<_> = server.database().await.audit_log(None, 10).await.unwrap().into_iter()
+        .map(|record| format!("{} {:?} {:?} {:?}", record.event, record.actor, record.target, record.detail))
+        .collect();
+    assert_eq!(events, [
+        r#"login Some("Alice") None Some("success")"#, r#"login Some("Bob") None Some("success")"#,
+        r#"kick Some("console") Some("Bob") None"#, r#"ban Some("console") Some("Carol") None"#,
+    ]);
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_registration() {
+    let server = TestServer::start(ServerConfig::default()).await;
+    let register = |port: u16, username: &'static str, code: Option<&'static str>| async move {
+        let (mut read_half, mut write_half) = client::open_tcp("127.0.0.1", port).await.unwrap();
+        client::register(&mut read_half, &mut write_half, username, "secret", || code.map(str::to_string), CodecKind::Cbor).await
+    };
+    assert!(matches!(register(server.port, "carol", Some("000000")).await, Err(LoginError::RegistrationFailed(reason)) if reason.contains("disabled")));
+    server.stop().await;
+
+    // The webhook gets the verification codes
+    let (codes, received) = std::sync::mpsc::channel::<serde_json::Value>();
+    let web = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let webhook = format!("http://{}/codes", web.local_addr().unwrap());
+    let router = axum::Router::new().route("/codes", axum::routing::post(move |axum::Json(body): axum::Json<serde_json::Value>| async move {
+        codes.send(body).unwrap();
+    }));
+    tokio::spawn(async move { axum::serve(web, router).await });
+
+    let registration = RegistrationConfig { webhook: Some(webhook) };
+    let server = TestServer::start(ServerConfig { registration: Some(registration), ..ServerConfig::default() }).await;
+    let (mut read_half, mut write_half) = client::open_tcp("127.0.0.1", server.port).await.unwrap();
+    let code = || {
+        let body = received.try_recv().unwrap();
+        assert_eq!(body["username"], "carol");
+        body["code"].as_str().map(str::to_string)
+    };
+    client::register(&mut read_half, &mut write_half, "carol", "secret", code, CodecKind::Cbor).await.unwrap();
+    server.try_connect("carol", "secret").await.unwrap();
+
+    assert!(matches!(register(server.port, "carol", None).await, Err(LoginError::RegistrationFailed(reason)) if reason == "the username is taken"));
+    assert!(matches!(register(server.port, "Bob", None).await, Err(LoginError::RegistrationFailed(reason)) if reason == "the username is taken"));
+    assert!(matches!(register(server.port, "no way", None).await, Err(LoginError::RegistrationFailed(_))));
+    // A wrong code doesn't create the user
+    let result = register(server.port, "dave", Some("not a code")).await;
+    assert!(matches!(result, Err(LoginError::RegistrationFailed(reason)) if reason == "wrong verification code"));
+    assert!(matches!(server.try_connect("dave", "secret").await, Err(LoginError::LoginFailed)));
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_api_registration() {
+    let api_address = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+    let server = TestServer::start(ServerConfig { api_address: Some(api_address.clone()), ..ServerConfig::default() }).await;
+    server.database().await.set_admin("Alice", true).await.unwrap();
+    let register = |username: &str| {
+        reqwest::Client::new().post(format!("http://{api_address}/api/register"))
+            .basic_auth("Alice", Some("alice"))
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(serde_json::json!({ "username": username, "password": "secret" }).to_string())
+            .send()
+    };
+    // The API is served in the background, it may not listen yet
+    let mut response = register("dave").await;
+    for _ in 0..50 {
+        if response.is_ok() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        response = register("dave").await;
+    }
+    assert_eq!(response.unwrap().status(), reqwest::StatusCode::CREATED);
+    let response = reqwest::Client::new().post(format!("http://{api_address}/api/register"))
+        .basic_auth("Alice", Some("alice"))
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(serde_json::json!({ "username": "erin", "password": "secret", "admin": true }).to_string())
+        .send().await.unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::CREATED);
+    assert!(server.database().await.is_admin("erin").await.unwrap());
+
+    // The registrations are audited like those made with the commands
+    let events: Vec<_> = server.database().await.audit_log(None, 10).await.unwrap().into_iter()
+        .filter(|record| record.event != "login")
+        .map(|record| format!("{} {:?} {:?} {:?}", record.event, record.actor, record.target, record.detail))
+        .collect();
+    assert_eq!(events, [
+        r#"register Some("Alice") Some("dave") None"#, r#"register Some("Alice") Some("erin") None"#,
+        r#"set-admin Some("Alice") Some("erin") Some("granted")"#,
+    ]);
+
+    // Names which registering over the chat protocol refuses are refused here too
+    for username in ["x", "a b", "-dash"] {
+        assert_eq!(register(username).await.unwrap().status(), reqwest::StatusCode::BAD_REQUEST, "{username}");
+    }
+    assert!(server.try_connect("a b", "secret").await.is_err());
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_link_previews() {
+    use axum::response::Html;
+
+    let page = "<html><head><title>Test page</title><meta name=\"description\" content=\"Served by the test\"></head></html>";
+    let web = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/page", web.local_addr().unwrap());
+    let router = axum::Router::new().route("/page", axum::routing::get(move || async move { Html(page) }));
+    tokio::spawn(async move { axum::serve(web, router).await });
+
+    // The test page is on a private address, which is previewed only when allowed
+    let previews = PreviewConfig { private_hosts: true, ..PreviewConfig::default() };
+    let server = TestServer::start(ServerConfig { link_previews: Some(previews), ..ServerConfig::default() }).await;
+    let mut alice = server.connect("Alice").await;
+    let mut bob = server.connect("Bob").await;
+
+    // Messages which expire aren't previewed, the next message is the text of the second one
+    alice.send_ephemeral(&format!("gone soon {url}"), 60).await.unwrap();
+    assert!(expect_message(&mut bob).await.message.ttl.is_some());
+    let id = alice.send_text(&format!("look at {url}.")).await.unwrap();
+    assert!(matches!(expect_message(&mut bob).await.message.content, ChatMessageContent::Text(_)));
+    // The author gets the preview too
+    for client in [&mut alice, &mut bob] {
+        let preview = expect_message(client).await.message;
+        assert_eq!((preview.sender.as_str(), preview.id), ("Alice", id));
+        assert!(matches!(preview.content, ChatMessageContent::LinkPreview { url: ref previewed, ref title, ref description }
+            if *previewed == url && title == "Test page" && description.as_deref() == Some("Served by the test")));
+    }
+
+    // Clients can't send previews themselves
+    let spoofed = ChatMessage::new(2, "Bob", ChatMessageContent::LinkPreview { url, title: "Fake".to_string(), description: None });
+    bob.send(&Datagram::Message(spoofed)).await.unwrap();
+    expect_closed(&mut bob).await;
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_federation_content_filter() {
+    let secret = Some("secret".to_string());
+    let rules = "[[rules]]\nwords = [\"darn\"]\naction = \"redact\"\n\n[[rules]]\nregex = \"buy (cheap|now)\"\naction = \"reject\"";
+    let server_b = TestServer::start(ServerConfig {
+        server_id: "b".to_string(),
+        peer_secret: secret.clone(),
+        content_filter: Some(ContentFilter::parse(rules).unwrap()),
+        ..ServerConfig::default()
+    }).await;
+    let server_a = TestServer::start(ServerConfig {
+        server_id: "a".to_string(),
+        peers: vec![format!("127.0.0.1:{}", server_b.port)],
+        peer_secret: secret,
+        ..ServerConfig::default()
+    }).await;
+    let mut alice = server_a.connect("Alice").await;
+    let mut bob = server_b.connect("Bob").await;
+
+    // The filter of the receiving server applies, as to its own users
+    let mut relayed = None;
+    for _ in 0..10 {
+        let id = alice.send_text("darn it").await.unwrap();
+        expect_ack(&mut alice, id).await;
+        if let Ok(incoming) = tokio::time::timeout(SILENCE_TIMEOUT, bob.recv_message()).await {
+            relayed = incoming;
+            break;
+        }
+    }
+    let incoming = relayed.expect("the message wasn't relayed");
+    assert!(matches!(incoming.message.content, ChatMessageContent::Text(ref text) if text == "**** it"));
+
+    let id = alice.send_text("buy now").await.unwrap();
+    expect_ack(&mut alice, id).await;
+    let id = alice.send_text("see you").await.unwrap();
+    expect_ack(&mut alice, id).await;
+    let incoming = expect_message(&mut bob).await;
+    assert!(matches!(incoming.message.content, ChatMessageContent::Text(ref text) if text == "see you"));
+
+    server_a.stop().await;
+    server_b.stop().await;
+}
@@ -0,0 +1,96 @@
+use std::net::IpAddr;
+
+use anyhow::{bail, Result};
+use chat::invite::Invite;
+use chat::{t, CodecKind};
+use qrcode::render::unicode::Dense1x2;
+use qrcode::QrCode;
+
+use crate::server_config::FileConfig;
+use crate::{DEFAULT_ADDRESS, DEFAULT_PORT};
+
+/// Builds the invite link of the server. The address and port the server binds are used unless others are given,
+/// e.g. when the server is behind a router.
+///
+/// # Arguments
+///
+/// * `host` - The host name or address the clients connect to.
+/// * `port` - The port the clients connect to.
+/// * `name` - The name of the server shown to the invited user.
+/// * `username` - The username the invited user logs in with.
+/// * `file` - The configuration file of the server.
+///
+/// # Returns
+///
+/// * `Result<Invite>` - Returns the invite, or an error if the server listens on all addresses and no host is given.
+pub fn build_invite(host: Option<String>, port: Option<u16>, name: Option<String>, username: Option<String>, file: &FileConfig) -> Result<Invite> {
+    let address = match host {
+        Some(host) => host,
+        None => {
+            let address = file.address.iter().flatten().next().cloned().unwrap_or_else(|| DEFAULT_ADDRESS.to_string());
+            if address.parse::<IpAddr>().is_ok_and(|ip| ip.is_unspecified()) {
+                bail!(t!("invite-needs-host"));
+            }
+            address
+        },
+    };
+    Ok(Invite {
+        address,
+        port: Some(port.or(file.port).unwrap_or(DEFAULT_PORT)),
+        name,
+        username,
+        // The clients use the default codec unless the link says otherwise
+        codec: file.codec.filter(|codec| *codec != CodecKind::default()),
+    })
+}
+
+/// Renders a text as a QR code for the terminal, two rows of modules per line of half blocks.
+///
+/// # Arguments
+///
+/// * `text` - The text encoded in the QR code.
+///
+/// # Returns
+///
+/// * `Result<String>` - Returns the lines of the QR code, or an error if the text is too long for one.
+pub fn render_qr(text: &str) -> Result<String> {
+    let code = QrCode::new(text.as_bytes())?;
+    // The colors are inverted, as terminals are usually dark and scanners expect dark modules on a light background
+    Ok(code.render::<Dense1x2>()
+        .dark_color(Dense1x2::Light)
+        .light_color(Dense1x2::Dark)
+        .quiet_zone(true)
+        .build())
+}
+
+#[cfg(test)]
+mod tests {
+    use chat::CodecKind;
+
+    use crate::server_config::FileConfig;
+    use crate::server_invite::{build_invite, render_qr};
+
+    #[test]
+    fn test_build_invite() {
+        let file = FileConfig { address: Some(vec!["192.0.2.7".into()]), port: Some(2000), codec: Some(CodecKind::Json), ..FileConfig::default() };
+        let invite = build_invite(None, None, Some("Team".into()), None, &file).unwrap();
+        assert_eq!(invite.to_string(), "chat://192.0.2.7:2000?name=Team&codec=json");
+
+        let invite = build_invite(Some("chat.example.com".into()), Some(443), None, Some("bob".into()), &FileConfig::default()).unwrap();
+        assert_eq!(invite.to_string(), "chat://chat.example.com:443?user=bob");
+        assert_eq!(build_invite(None, None, None, None, &FileConfig::default()).unwrap().to_string(), "chat://127.0.0.1:11111");
+
+        // The clients can't connect to the unspecified address
+        let file = FileConfig { address: Some(vec!["0.0.0.0".into()]), ..FileConfig::default() };
+        assert!(build_invite(None, None, None, None, &file).is_err());
+        assert!(build_invite(Some("192.0.2.7".into()), None, None, None, &file).is_ok());
+    }
+
+    #[test]
+    fn test_render_qr() {
+        let qr = render_qr("chat://127.0.0.1:11111").unwrap();
+        let lines: Vec<_> = qr.lines().collect();
+        assert!(lines.len() > 10);
+        assert!(lines.iter().all(|line| line.chars().count() == lines[0].chars().count()));
+    }
+}
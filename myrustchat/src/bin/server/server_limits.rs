@@ -0,0 +1,356 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use chat::{format_size, AttachmentKind, AudioFormat, ChatMessageContent, MAX_VOICE_NOTE_SIZE};
+
+/// Default maximum size of an attachment in bytes.
+pub const DEFAULT_MAX_ATTACHMENT_SIZE: u64 = 100 * 1024 * 1024;
+
+/// Types of images accepted when the content is sniffed. Clients convert other formats to PNG.
+const IMAGE_TYPES: &[&str] = &["image/png", "image/jpeg", "image/webp"];
+
+/// Struct holding the limits of image and file attachments.
+#[derive(Clone, Debug)]
+pub struct AttachmentLimits {
+    /// Maximum size of an attachment in bytes, `None` for no limit
+    pub max_size: Option<u64>,
+    /// Whether the content is sniffed to reject images which aren't PNG, JPEG or WebP and files whose
+    /// extension doesn't match their content
+    pub check_mime: bool,
+    /// How much attachment storage each user may fill
+    pub quotas: StorageQuotas,
+}
+
+impl Default for AttachmentLimits {
+    fn default() -> Self {
+        AttachmentLimits { max_size: Some(DEFAULT_MAX_ATTACHMENT_SIZE), check_mime: false, quotas: StorageQuotas::default() }
+    }
+}
+
+/// Struct holding how many bytes of stored attachments each user may have. Only the attachments
+/// of messages to everyone are stored, so direct messages don't count.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StorageQuotas {
+    /// Quota of every user without one of their own, `None` for no limit
+    pub default: Option<u64>,
+    /// Quotas of single users, 0 for no limit
+    pub users: HashMap<String, u64>,
+}
+
+impl StorageQuotas {
+    /// Returns the quota of a user.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - The username of the user.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<u64>` - Returns the quota in bytes, `None` if the user has no limit.
+    pub fn quota_of(&self, username: &str) -> Option<u64> {
+        match self.users.get(username) {
+            Some(0) => None,
+            Some(quota) => Some(*quota),
+            None => self.default,
+        }
+    }
+
+    /// Checks whether a new attachment fits into the quota of a user.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - The username of the sender.
+    /// * `used` - The bytes of stored attachments the user already has.
+    /// * `size` - The size of the new attachment in bytes.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), String>` - Returns the reason if the attachment would exceed the quota.
+    pub fn check(&self, username: &str, used: u64, size: u64) -> Result<(), String> {
+        match self.quota_of(username) {
+            Some(quota) if used.saturating_add(size) > quota => Err(format!(
+                "the attachment has {}, but only {} of your storage quota of {} are left",
+                format_size(size), format_size(quota.saturating_sub(used)), format_size(quota))),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Struct holding the sizes of attachments which fit into the quota of their sender but aren't stored
+/// yet, like uploads in progress. They count as used, so parallel uploads can't exceed a quota together.
+#[derive(Clone, Default)]
+pub struct PendingStorage {
+    /// Reserved bytes of each user with an attachment pending
+    users: Arc<Mutex<HashMap<String, u64>>>,
+    /// Held while a quota is checked, so two checks don't both take the last free bytes
+    checking: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl PendingStorage {
+    /// Waits until no other quota is being checked. The returned guard must be held until the
+    /// checked size is reserved.
+    pub async fn checking(&self) -> tokio::sync::MutexGuard<'_, ()> {
+        self.checking.lock().await
+    }
+
+    /// Returns the bytes reserved for the pending attachments of a user.
+    pub fn of(&self, username: &str) -> u64 {
+        self.users.lock().unwrap().get(username).copied().unwrap_or(0)
+    }
+
+    /// Reserves the size of an attachment until it's stored.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - The username of the sender.
+    /// * `size` - The size of the attachment in bytes.
+    ///
+    /// # Returns
+    ///
+    /// * `Reservation` - Returns the reservation, the size is released when it's dropped.
+    pub fn reserve(&self, username: &str, size: u64) -> Reservation {
+        *self.users.lock().unwrap().entry(username.to_string()).or_default() += size;
+        Reservation { pending: Some(self.clone()), username: username.to_string(), size }
+    }
+}
+
+/// Reserved storage of a pending attachment, released when dropped. It should be dropped only
+/// after the attachment is stored or given up.
+#[derive(Default)]
+pub struct Reservation {
+    /// `None` for attachments of users without a quota, nothing is reserved for them
+    pending: Option<PendingStorage>,
+    username: String,
+    size: u64,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        let Some(pending) = &self.pending else {
+            return;
+        };
+        let mut users = pending.users.lock().unwrap();
+        if let Some(reserved) = users.get_mut(&self.username) {
+            *reserved = reserved.saturating_sub(self.size);
+            if *reserved == 0 {
+                users.remove(&self.username);
+            }
+        }
+    }
+}
+
+/// Returns the size of the attachment of a chat message, 0 for text messages.
+///
+/// # Arguments
+///
+/// * `content` - The content of the chat message.
+///
+/// # Returns
+///
+/// * `u64` - Returns the size in bytes.
+pub fn attachment_size(content: &ChatMessageContent) -> u64 {
+    match content {
+        ChatMessageContent::Text(_) | ChatMessageContent::LinkPreview { .. } => 0,
+        ChatMessageContent::Image(data) | ChatMessageContent::File(_, data) | ChatMessageContent::Audio { data, .. } => data.len() as u64,
+    }
+}
+
+impl AttachmentLimits {
+    /// Checks the size of an attachment. Voice notes are also limited to `MAX_VOICE_NOTE_SIZE`.
+    ///
+    /// # Arguments
+    ///
+    /// * `kind` - The announced type of the attachment.
+    /// * `size` - The size of the attachment in bytes.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), String>` - Returns the reason if the attachment is too large.
+    pub fn check_size(&self, kind: &AttachmentKind, size: u64) -> Result<(), String> {
+        match self.max_size {
+            Some(max_size) if size > max_size => Err(format!("the attachment has {size} bytes, the limit is {max_size} bytes")),
+            _ if matches!(kind, AttachmentKind::Audio(_)) && size > MAX_VOICE_NOTE_SIZE as u64 =>
+                Err(format!("the voice note has {size} bytes, the limit is {MAX_VOICE_NOTE_SIZE} bytes")),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks that the content of an attachment matches its type. Only the beginning of the data is needed.
+    /// Voice notes are played by the clients, so their format is checked even if sniffing is off.
+    ///
+    /// # Arguments
+    ///
+    /// * `kind` - The announced type of the attachment.
+    /// * `head` - The first bytes of the attachment.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), String>` - Returns the reason if the content is mislabeled.
+    pub fn check_content(&self, kind: &AttachmentKind, head: &[u8]) -> Result<(), String> {
+        let sniffed = infer::get(head);
+        match kind {
+            AttachmentKind::Audio(format) => match sniffed {
+                Some(sniffed) if audio_types(*format).contains(&sniffed.mime_type()) => Ok(()),
+                _ => Err(format!("the voice note is not in the .{} format", format.extension())),
+            },
+            _ if !self.check_mime => Ok(()),
+            AttachmentKind::Image => match sniffed {
+                Some(sniffed) if IMAGE_TYPES.contains(&sniffed.mime_type()) => Ok(()),
+                _ => Err("the image is not a PNG, JPEG or WebP".to_string()),
+            },
+            AttachmentKind::File(filename) => {
+                let extension = Path::new(filename).extension()
+                    .map(|extension| extension.to_string_lossy().to_lowercase());
+                let (Some(extension), Some(sniffed)) = (extension, sniffed) else {
+                    // Files without an extension or with an unrecognized content, like plain text, are fine
+                    return Ok(());
+                };
+
+                // Extensions unknown to the sniffer, like `.whl` archives, may hold any content
+                if infer::is_supported(&extension) && canonical_extension(&extension) != canonical_extension(sniffed.extension()) {
+                    return Err(format!("the content of {filename} looks like {}, not .{extension}", sniffed.mime_type()));
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Checks an attachment sent inline in a chat message. Text messages always pass.
+    ///
+    /// # Arguments
+    ///
+    /// * `content` - The content of the chat message.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), String>` - Returns the reason if the attachment is rejected.
+    pub fn check_message(&self, content: &ChatMessageContent) -> Result<(), String> {
+        match content {
+            ChatMessageContent::Text(_) | ChatMessageContent::LinkPreview { .. } => Ok(()),
+            ChatMessageContent::Image(data) => self.check_attachment(&AttachmentKind::Image, data),
+            ChatMessageContent::File(filename, data) => self.check_attachment(&AttachmentKind::File(filename.clone()), data),
+            ChatMessageContent::Audio { format, data } => self.check_attachment(&AttachmentKind::Audio(*format), data),
+        }
+    }
+
+    /// Checks the size and the content of an attachment sent inline.
+    fn check_attachment(&self, kind: &AttachmentKind, data: &[u8]) -> Result<(), String> {
+        self.check_size(kind, data.len() as u64)?;
+        self.check_content(kind, data)
+    }
+}
+
+/// Returns the types reported by the sniffer for audio in the given format.
+fn audio_types(format: AudioFormat) -> &'static [&'static str] {
+    match format {
+        AudioFormat::Opus => &["audio/opus"],
+        // Opus is a codec of the Ogg container, too
+        AudioFormat::Ogg => &["audio/ogg", "audio/opus"],
+        AudioFormat::Mp3 => &["audio/mpeg"],
+        // Recordings of some phones are tagged as generic MPEG-4
+        AudioFormat::M4a => &["audio/m4a", "video/mp4"],
+        AudioFormat::Wav => &["audio/x-wav"],
+    }
+}
+
+/// Maps alternative spellings of an extension to the one reported by the sniffer.
+fn canonical_extension(extension: &str) -> &str {
+    match extension {
+        "jpeg" | "jpe" => "jpg",
+        "tiff" => "tif",
+        "htm" => "html",
+        "tgz" => "gz",
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chat::{AttachmentKind, AudioFormat, ChatMessageContent, MAX_VOICE_NOTE_SIZE};
+
+    use crate::server_limits::{attachment_size, AttachmentLimits, PendingStorage, StorageQuotas};
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+    const JPEG: &[u8] = b"\xff\xd8\xff\xe0\0\x10JFIF\0";
+    const OPUS: &[u8] = b"OggS\0\x02\0\0\0\0\0\0\0\0\x01\0\0\0\0\0\0\0\0\0\0\0\x01\x13OpusHead\x01\x01";
+    const MP3: &[u8] = b"ID3\x04\0\0\0\0\0\0";
+
+    fn file(filename: &str) -> AttachmentKind {
+        AttachmentKind::File(filename.to_string())
+    }
+
+    #[test]
+    fn test_attachment_limits() {
+        let limits = AttachmentLimits { max_size: Some(10), check_mime: true, ..AttachmentLimits::default() };
+        assert!(limits.check_size(&AttachmentKind::Image, 10).is_ok());
+        assert!(limits.check_size(&AttachmentKind::Image, 11).is_err());
+        let unchecked = AttachmentLimits { max_size: None, ..AttachmentLimits::default() };
+        assert!(unchecked.check_size(&AttachmentKind::Image, u64::MAX).is_ok());
+        assert!(unchecked.check_size(&AttachmentKind::Audio(AudioFormat::Opus), MAX_VOICE_NOTE_SIZE as u64).is_ok());
+        assert!(unchecked.check_size(&AttachmentKind::Audio(AudioFormat::Opus), MAX_VOICE_NOTE_SIZE as u64 + 1).is_err());
+
+        assert!(limits.check_content(&AttachmentKind::Image, PNG).is_ok());
+        assert!(limits.check_content(&AttachmentKind::Image, JPEG).is_ok());
+        assert!(limits.check_content(&AttachmentKind::Image, b"GIF89a\x01\0\x01\0").is_err());
+        assert!(limits.check_content(&AttachmentKind::Image, b"hello").is_err());
+
+        assert!(limits.check_content(&file("photo.jpg"), JPEG).is_ok());
+        assert!(limits.check_content(&file("photo.JPEG"), JPEG).is_ok());
+        assert!(limits.check_content(&file("photo.png"), JPEG).is_err());
+        assert!(limits.check_content(&file("notes.txt"), b"hello").is_ok());
+        assert!(limits.check_content(&file("README"), JPEG).is_ok());
+        assert!(limits.check_content(&file("bundle.whl"), b"PK\x03\x04").is_ok());
+
+        assert!(unchecked.check_content(&file("photo.png"), JPEG).is_ok());
+
+        // Voice notes are checked even without sniffing
+        let audio = |format, data: &[u8]| ChatMessageContent::Audio { format, data: data.to_vec() };
+        assert!(unchecked.check_message(&audio(AudioFormat::Opus, OPUS)).is_ok());
+        assert!(unchecked.check_message(&audio(AudioFormat::Ogg, OPUS)).is_ok());
+        assert!(unchecked.check_message(&audio(AudioFormat::Mp3, MP3)).is_ok());
+        assert!(unchecked.check_message(&audio(AudioFormat::Mp3, OPUS)).is_err());
+        assert!(unchecked.check_message(&audio(AudioFormat::Wav, b"hello")).is_err());
+
+        assert!(limits.check_message(&ChatMessageContent::Text("a long text message".to_string())).is_ok());
+        assert!(limits.check_message(&ChatMessageContent::Image(PNG.to_vec())).is_err());
+        assert!(limits.check_message(&ChatMessageContent::File("a.png".to_string(), JPEG.to_vec())).is_err());
+    }
+
+    #[test]
+    fn test_storage_quotas() {
+        let quotas = StorageQuotas {
+            default: Some(1000),
+            users: [("alice".to_string(), 5000), ("bot".to_string(), 0)].into(),
+        };
+        assert_eq!(quotas.quota_of("bob"), Some(1000));
+        assert_eq!(quotas.quota_of("alice"), Some(5000));
+        assert_eq!(quotas.quota_of("bot"), None);
+
+        assert!(quotas.check("bob", 600, 400).is_ok());
+        let reason = quotas.check("bob", 600, 401).unwrap_err();
+        assert_eq!(reason, "the attachment has 401 B, but only 400 B of your storage quota of 1000 B are left");
+        // Over the quota, e.g. after it was lowered
+        assert!(quotas.check("bob", 2000, 1).is_err());
+        assert!(quotas.check("alice", 2000, 3000).is_ok());
+        assert!(quotas.check("bot", u64::MAX, u64::MAX).is_ok());
+        assert!(StorageQuotas::default().check("bob", u64::MAX, 1).is_ok());
+
+        assert_eq!(attachment_size(&ChatMessageContent::Text("hello".to_string())), 0);
+        assert_eq!(attachment_size(&ChatMessageContent::File("a.txt".to_string(), b"abc".to_vec())), 3);
+    }
+
+    #[test]
+    fn test_pending_storage() {
+        let pending = PendingStorage::default();
+        let first = pending.reserve("bob", 300);
+        let second = pending.reserve("bob", 200);
+        assert_eq!(pending.of("bob"), 500);
+        assert_eq!(pending.of("alice"), 0);
+        drop(first);
+        assert_eq!(pending.of("bob"), 200);
+        drop(second);
+        assert_eq!(pending.of("bob"), 0);
+        assert!(pending.users.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,110 @@
+use std::fmt::Display;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+
+/// Default number of failed logins after which further logins are refused for a while.
+pub const DEFAULT_LOCKOUT_ATTEMPTS: u32 = 5;
+
+/// Default number of seconds for which logins are refused after too many failures.
+pub const DEFAULT_LOCKOUT_DURATION: u64 = 300;
+
+/// Struct holding the thresholds of the account lockout.
+#[derive(Clone, Debug)]
+pub struct LockoutConfig {
+    /// Number of failed logins of a user or from an address which locks further logins, `None` never locks
+    pub attempts: Option<u32>,
+    /// Failures older than this are forgotten and the lock lasts this long after the last failure
+    pub duration: Duration,
+}
+
+impl Default for LockoutConfig {
+    fn default() -> Self {
+        LockoutConfig {
+            attempts: Some(DEFAULT_LOCKOUT_ATTEMPTS),
+            duration: Duration::from_secs(DEFAULT_LOCKOUT_DURATION),
+        }
+    }
+}
+
+impl LockoutConfig {
+    /// Decides whether logins are locked.
+    ///
+    /// # Arguments
+    ///
+    /// * `failures` - The times of the failed logins within `duration`, after the last successful login.
+    /// * `now` - The current time.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<DateTime<Utc>>` - Returns the time until which logins are refused, `None` if they are allowed.
+    pub fn locked_until(&self, failures: &[DateTime<Utc>], now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        if failures.len() < self.attempts? as usize {
+            return None;
+        }
+        let until = *failures.iter().max()? + chrono::Duration::from_std(self.duration).ok()?;
+        (until > now).then_some(until)
+    }
+}
+
+/// Outcome of a login attempt, as recorded in the audit log.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoginOutcome {
+    /// The user logged in
+    Success,
+    /// The username or the password was wrong
+    WrongPassword,
+    /// The password was right but the TOTP code wasn't
+    InvalidCode,
+    /// The user is banned
+    Banned,
+    /// The login was refused without checking the password because of earlier failures
+    Locked,
+    /// The login was refused because the user was already logged in from another connection
+    AlreadyLoggedIn,
+    /// The client certificate was missing or isn't registered for the user
+    UnknownCertificate,
+}
+
+impl LoginOutcome {
+    /// Outcomes counted towards the lockout. Refused attempts of a locked user don't count,
+    /// so that an attacker can't keep the real user locked out forever.
+    pub const FAILURES: [LoginOutcome; 2] = [LoginOutcome::WrongPassword, LoginOutcome::InvalidCode];
+}
+
+impl Display for LoginOutcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoginOutcome::Success => write!(f, "success"),
+            LoginOutcome::WrongPassword => write!(f, "wrong-password"),
+            LoginOutcome::InvalidCode => write!(f, "invalid-code"),
+            LoginOutcome::Banned => write!(f, "banned"),
+            LoginOutcome::Locked => write!(f, "locked"),
+            LoginOutcome::AlreadyLoggedIn => write!(f, "already-logged-in"),
+            LoginOutcome::UnknownCertificate => write!(f, "unknown-certificate"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::server_lockout::LockoutConfig;
+
+    #[test]
+    fn test_locked_until() {
+        let config = LockoutConfig { attempts: Some(3), duration: Duration::from_secs(60) };
+        let now = chrono::Utc::now();
+        let seconds_ago = |seconds| now - chrono::Duration::seconds(seconds);
+
+        assert_eq!(config.locked_until(&[seconds_ago(10), seconds_ago(5)], now), None);
+        // The lock lasts from the last failure
+        let failures = [seconds_ago(30), seconds_ago(20), seconds_ago(10)];
+        assert_eq!(config.locked_until(&failures, now), Some(seconds_ago(10) + chrono::Duration::seconds(60)));
+        assert_eq!(config.locked_until(&failures, now + chrono::Duration::seconds(50)), None);
+
+        let disabled = LockoutConfig { attempts: None, ..config };
+        assert_eq!(disabled.locked_until(&failures, now), None);
+    }
+}
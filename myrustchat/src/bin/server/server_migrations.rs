@@ -0,0 +1,361 @@
+use anyhow::{anyhow, Context};
+use chat::EmptyResult;
+use sqlx::SqlitePool;
+
+/// A single step in the evolution of the database schema.
+pub struct Migration {
+    /// Schema version reached after the migration is applied
+    pub version: i32,
+    /// Short human readable description printed when the migration is applied
+    pub description: &'static str,
+    /// SQL statements executed in order inside a single transaction
+    pub statements: &'static [&'static str],
+}
+
+/// All migrations of the server database, ordered by version.
+/// New schema changes must be appended here with the next version number, never edited in place.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "create users, messages and config tables",
+        statements: &[
+            "
+            CREATE TABLE IF NOT EXISTS users (
+                username TEXT PRIMARY KEY,
+                password TEXT
+            )
+            ",
+            "
+            CREATE TABLE IF NOT EXISTS messages (
+                messages_id INTEGER PRIMARY KEY,
+                sender TEXT,
+                content_type INTEGER,
+                text TEXT,
+                filename TEXT,
+                content BLOB,
+                FOREIGN KEY(sender) REFERENCES users(username)
+            )
+            ",
+            "
+            CREATE TABLE IF NOT EXISTS config (
+                key TEXT NOT NULL PRIMARY KEY,
+                value TEXT NOT NULL
+            )
+            ",
+        ],
+    },
+    Migration {
+        version: 2,
+        description: "add admin role and bans",
+        statements: &[
+            "ALTER TABLE users ADD COLUMN admin INTEGER NOT NULL DEFAULT 0",
+            "
+            CREATE TABLE IF NOT EXISTS bans (
+                username TEXT PRIMARY KEY,
+                banned_by TEXT,
+                banned_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+                FOREIGN KEY(username) REFERENCES users(username)
+            )
+            ",
+        ],
+    },
+    Migration {
+        version: 3,
+        description: "add message timestamps",
+        statements: &[
+            "ALTER TABLE messages ADD COLUMN timestamp TEXT",
+        ],
+    },
+    Migration {
+        version: 4,
+        description: "replace the global password salt with per-user salts",
+        statements: &[
+            // Existing hashes embed the old global salt and stay valid; they are rehashed on the next login.
+            "ALTER TABLE users ADD COLUMN needs_rehash INTEGER NOT NULL DEFAULT 0",
+            "UPDATE users SET needs_rehash=1",
+            "DELETE FROM config WHERE key='password_salt'",
+        ],
+    },
+    Migration {
+        version: 5,
+        description: "store attachments on disk",
+        statements: &[
+            // Existing BLOBs are moved out of the `content` column by `ServerDatabase::move_attachments_to_disk`.
+            "ALTER TABLE messages ADD COLUMN attachment_hash TEXT",
+            "ALTER TABLE messages ADD COLUMN attachment_size INTEGER",
+        ],
+    },
+    Migration {
+        version: 6,
+        description: "add nicknames",
+        statements: &[
+            "ALTER TABLE users ADD COLUMN nickname TEXT",
+            "CREATE UNIQUE INDEX users_nickname ON users(nickname COLLATE NOCASE)",
+        ],
+    },
+    Migration {
+        version: 7,
+        description: "add read receipts",
+        statements: &[
+            "
+            CREATE TABLE IF NOT EXISTS read_receipts (
+                username TEXT PRIMARY KEY,
+                last_read_id INTEGER NOT NULL,
+                read_at TEXT NOT NULL,
+                FOREIGN KEY(username) REFERENCES users(username)
+            )
+            ",
+        ],
+    },
+    Migration {
+        version: 8,
+        description: "add filter log",
+        statements: &[
+            "
+            CREATE TABLE IF NOT EXISTS filtered_messages (
+                filtered_id INTEGER PRIMARY KEY,
+                sender TEXT NOT NULL,
+                recipient TEXT,
+                timestamp TEXT NOT NULL,
+                text TEXT NOT NULL,
+                action TEXT NOT NULL,
+                rules TEXT NOT NULL
+            )
+            ",
+        ],
+    },
+    Migration {
+        version: 9,
+        description: "add two-factor authentication",
+        statements: &[
+            "ALTER TABLE users ADD COLUMN totp_secret TEXT",
+        ],
+    },
+    Migration {
+        version: 10,
+        description: "add login audit",
+        statements: &[
+            // Failed logins of unknown users are recorded too, so there is no foreign key
+            "
+            CREATE TABLE IF NOT EXISTS login_audit (
+                audit_id INTEGER PRIMARY KEY,
+                username TEXT NOT NULL,
+                address TEXT,
+                timestamp TEXT NOT NULL,
+                outcome TEXT NOT NULL
+            )
+            ",
+            "CREATE INDEX login_audit_username ON login_audit(username, timestamp)",
+            "CREATE INDEX login_audit_address ON login_audit(address, timestamp)",
+        ],
+    },
+    Migration {
+        version: 11,
+        description: "add replies",
+        statements: &[
+            "ALTER TABLE messages ADD COLUMN reply_sender TEXT",
+            "ALTER TABLE messages ADD COLUMN reply_id INTEGER",
+        ],
+    },
+    Migration {
+        version: 12,
+        description: "add block lists",
+        statements: &[
+            // Guests can be blocked too, so only the blocker references the users
+            "
+            CREATE TABLE IF NOT EXISTS blocks (
+                blocker TEXT NOT NULL,
+                blocked TEXT NOT NULL,
+                blocked_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+                PRIMARY KEY(blocker, blocked),
+                FOREIGN KEY(blocker) REFERENCES users(username)
+            )
+            ",
+        ],
+    },
+    Migration {
+        version: 13,
+        description: "never reuse message IDs and keep the IDs given by clients",
+        statements: &[
+            // Without AUTOINCREMENT the IDs of pruned messages would be given out again, so clients
+            // could not rely on them to detect missed messages. SQLite can only add it by rebuilding the table.
+            // The IDs given by clients are kept, as replies to messages fetched again refer to them.
+            "
+            CREATE TABLE messages_new (
+                messages_id INTEGER PRIMARY KEY AUTOINCREMENT,
+                sender TEXT,
+                content_type INTEGER,
+                text TEXT,
+                filename TEXT,
+                content BLOB,
+                timestamp TEXT,
+                attachment_hash TEXT,
+                attachment_size INTEGER,
+                reply_sender TEXT,
+                reply_id INTEGER,
+                client_id INTEGER,
+                FOREIGN KEY(sender) REFERENCES users(username)
+            )
+            ",
+            "INSERT INTO messages_new SELECT messages_id, sender, content_type, text, filename, content, timestamp, attachment_hash, attachment_size, reply_sender, reply_id, NULL FROM messages",
+            "DROP TABLE messages",
+            "ALTER TABLE messages_new RENAME TO messages",
+        ],
+    },
+    Migration {
+        version: 14,
+        description: "add the audit log",
+        statements: &[
+            "
+            CREATE TABLE audit_log (
+                audit_id INTEGER PRIMARY KEY AUTOINCREMENT,
+                timestamp TEXT NOT NULL,
+                event TEXT NOT NULL,
+                actor TEXT,
+                target TEXT,
+                address TEXT,
+                detail TEXT
+            )
+            ",
+            // Events can only be added, not changed or removed
+            "
+            CREATE TRIGGER audit_log_no_update BEFORE UPDATE ON audit_log
+            BEGIN SELECT RAISE(ABORT, 'the audit log is append-only'); END
+            ",
+            "
+            CREATE TRIGGER audit_log_no_delete BEFORE DELETE ON audit_log
+            BEGIN SELECT RAISE(ABORT, 'the audit log is append-only'); END
+            ",
+            // Login attempts recorded before are kept in the new log
+            "
+            INSERT INTO audit_log(timestamp, event, actor, address, detail)
+            SELECT timestamp, 'login', username, address, outcome FROM login_audit ORDER BY audit_id
+            ",
+        ],
+    },
+    Migration {
+        version: 15,
+        description: "add client certificates of bots",
+        statements: &[
+            "
+            CREATE TABLE client_certificates (
+                fingerprint TEXT PRIMARY KEY,
+                username TEXT NOT NULL,
+                added_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+                FOREIGN KEY(username) REFERENCES users(username)
+            )
+            ",
+        ],
+    },
+    Migration {
+        version: 16,
+        description: "index messages by sender for the storage quotas",
+        statements: &[
+            "CREATE INDEX messages_sender ON messages(sender)",
+        ],
+    },
+    Migration {
+        version: 17,
+        description: "mark messages of the announcement channel",
+        statements: &[
+            "ALTER TABLE messages ADD COLUMN announcement INTEGER NOT NULL DEFAULT 0",
+        ],
+    },
+    Migration {
+        version: 18,
+        description: "add the expiry of ephemeral messages",
+        statements: &[
+            "ALTER TABLE messages ADD COLUMN expires_at TEXT",
+            "CREATE INDEX messages_expires_at ON messages(expires_at) WHERE expires_at IS NOT NULL",
+        ],
+    },
+    Migration {
+        version: 19,
+        description: "add user profiles",
+        statements: &[
+            "
+            CREATE TABLE profiles (
+                username TEXT PRIMARY KEY,
+                status TEXT,
+                avatar BLOB,
+                updated_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+                FOREIGN KEY(username) REFERENCES users(username)
+            )
+            ",
+        ],
+    },
+];
+
+/// Brings the database schema up to date by applying all migrations newer than the stored `user_version`.
+/// Every migration runs in its own transaction, so a failing migration is rolled back
+/// and leaves the database at the last successfully applied version.
+///
+/// # Arguments
+///
+/// * `db` - The database connection pool.
+/// * `migrations` - The list of migrations ordered by version.
+///
+/// # Returns
+///
+/// * `EmptyResult` - Returns an empty result if the database is up to date.
+pub async fn run_migrations(db: &SqlitePool, migrations: &[Migration]) -> EmptyResult {
+    let (ver, ): (i32, ) = sqlx::query_as("PRAGMA user_version")
+        .fetch_one(db).await?;
+
+    let latest = migrations.last().map(|m| m.version).unwrap_or(0);
+    if ver > latest {
+        return Err(anyhow!("Database version {ver} is newer than the supported version {latest}."));
+    }
+
+    let mut current = ver;
+    for migration in migrations.iter().filter(|m| m.version > ver) {
+        if migration.version != current + 1 {
+            return Err(anyhow!("Missing database migration to version {}.", current + 1));
+        }
+
+        tracing::warn!("Upgrading the database to version {}: {}.", migration.version, migration.description);
+
+        let mut trans = db.begin().await?;
+        for statement in migration.statements {
+            sqlx::query(statement).execute(&mut *trans).await
+                .with_context(|| format!("Database migration to version {} failed.", migration.version))?;
+        }
+
+        // PRAGMA doesn't accept bound parameters.
+        sqlx::query(&format!("PRAGMA user_version={}", migration.version))
+            .execute(&mut *trans).await?;
+        trans.commit().await?;
+        current = migration.version;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+
+    use crate::server_migrations::{run_migrations, Migration};
+
+    #[tokio::test]
+    async fn test_migration_rollback() {
+        let options = SqliteConnectOptions::from_str("sqlite::memory:").unwrap();
+        let db = SqlitePoolOptions::new().max_connections(1).connect_with(options).await.unwrap();
+
+        let migrations = [
+            Migration { version: 1, description: "first", statements: &["CREATE TABLE a (x INTEGER)"] },
+            Migration { version: 2, description: "broken", statements: &["CREATE TABLE b (x INTEGER)", "NOT SQL"] },
+        ];
+        assert!(run_migrations(&db, &migrations).await.is_err());
+
+        let (ver, ): (i32, ) = sqlx::query_as("PRAGMA user_version").fetch_one(&db).await.unwrap();
+        assert_eq!(ver, 1);
+        assert!(sqlx::query("SELECT * FROM a").execute(&db).await.is_ok());
+        assert!(sqlx::query("SELECT * FROM b").execute(&db).await.is_err());
+
+        assert!(run_migrations(&db, &migrations[..1]).await.is_ok());
+        assert!(run_migrations(&db, &[]).await.is_err());
+    }
+}
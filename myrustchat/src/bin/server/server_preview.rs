@@ -0,0 +1,442 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use regex::Regex;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::header::{ACCEPT, CONTENT_TYPE};
+use reqwest::redirect::Policy;
+use reqwest::{Client, Url};
+
+/// Default number of seconds a page may take to load before its preview is given up.
+pub const DEFAULT_LINK_PREVIEW_TIMEOUT: u64 = 5;
+
+/// Maximum number of links previewed in a single message.
+const MAX_PREVIEWS_PER_MESSAGE: usize = 2;
+
+/// Only the beginning of a page is read, the title and the description are in its head.
+const MAX_PAGE_SIZE: usize = 256 * 1024;
+
+/// Maximum number of redirects followed to get to a page.
+const MAX_REDIRECTS: usize = 3;
+
+/// Number of links whose previews are remembered.
+const CACHE_CAPACITY: usize = 500;
+
+/// How long a preview, or the failure to make one, is remembered.
+const CACHE_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Maximum number of characters of a title and a description.
+const MAX_TITLE_LENGTH: usize = 200;
+const MAX_DESCRIPTION_LENGTH: usize = 300;
+
+/// Settings of the link previews.
+#[derive(Clone, Debug)]
+pub struct PreviewConfig {
+    /// How long a page may take to load
+    pub timeout: Duration,
+    /// Whether links to private addresses, like 192.168.1.1 or localhost, are previewed
+    pub private_hosts: bool,
+}
+
+impl Default for PreviewConfig {
+    fn default() -> Self {
+        PreviewConfig {
+            timeout: Duration::from_secs(DEFAULT_LINK_PREVIEW_TIMEOUT),
+            private_hosts: false,
+        }
+    }
+}
+
+/// The title and the description of a linked page.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Preview {
+    pub title: String,
+    pub description: Option<String>,
+}
+
+/// A remembered preview, `None` if the page couldn't be previewed.
+struct CachedPreview {
+    preview: Option<Preview>,
+    fetched: Instant,
+}
+
+/// `LinkPreviewer` fetches linked pages and remembers their previews. The server makes the requests, so links
+/// to private addresses aren't followed by default, to keep users from probing the network of the server.
+pub struct LinkPreviewer {
+    client: Client,
+    /// Shared with the resolver and the redirect policy of the client
+    private_hosts: Arc<AtomicBool>,
+    cache: Mutex<HashMap<String, CachedPreview>>,
+}
+
+impl LinkPreviewer {
+    /// Creates a new instance of `LinkPreviewer`.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The settings of the link previews.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<LinkPreviewer>` - Returns the previewer, or an error if the HTTP client can't be created.
+    pub fn new(config: &PreviewConfig) -> Result<LinkPreviewer> {
+        let private_hosts = Arc::new(AtomicBool::new(config.private_hosts));
+        let redirect_private_hosts = private_hosts.clone();
+        let client = Client::builder()
+            .user_agent(concat!("myrustchat/", env!("CARGO_PKG_VERSION")))
+            .dns_resolver(Arc::new(PublicResolver { private_hosts: private_hosts.clone() }))
+            .redirect(Policy::custom(move |attempt| {
+                let allowed = allowed_host(attempt.url(), redirect_private_hosts.load(Ordering::Relaxed));
+                if attempt.previous().len() > MAX_REDIRECTS || !allowed {
+                    attempt.stop()
+                } else {
+                    attempt.follow()
+                }
+            }))
+            .build()?;
+        Ok(LinkPreviewer { client, private_hosts, cache: Mutex::new(HashMap::new()) })
+    }
+
+    /// Changes the settings, used when the configuration is reloaded.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The new settings.
+    pub fn set_config(&self, config: &PreviewConfig) {
+        self.private_hosts.store(config.private_hosts, Ordering::Relaxed);
+    }
+
+    /// Makes the preview of a linked page, from the cache if the page was fetched recently.
+    ///
+    /// # Arguments
+    ///
+    /// * `url` - The link.
+    /// * `timeout` - How long the page may take to load.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<Preview>` - Returns the preview, `None` if the page has no title or couldn't be loaded.
+    pub async fn preview(&self, url: &str, timeout: Duration) -> Option<Preview> {
+        if let Some(cached) = self.cache.lock().unwrap().get(url).filter(|cached| cached.fetched.elapsed() < CACHE_TTL) {
+            return cached.preview.clone();
+        }
+
+        let preview = match self.fetch(url, timeout).await {
+            Ok(preview) => preview,
+            Err(e) => {
+                tracing::debug!("Could not preview {url}: {e:#}");
+                None
+            },
+        };
+
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= CACHE_CAPACITY {
+            cache.retain(|_, cached| cached.fetched.elapsed() < CACHE_TTL);
+            let oldest = cache.iter().min_by_key(|(_, cached)| cached.fetched).map(|(url, _)| url.clone());
+            if let Some(oldest) = oldest.filter(|_| cache.len() >= CACHE_CAPACITY) {
+                cache.remove(&oldest);
+            }
+        }
+        cache.insert(url.to_string(), CachedPreview { preview: preview.clone(), fetched: Instant::now() });
+        preview
+    }
+
+    /// Loads the beginning of a page and reads its title and description.
+    ///
+    /// # Arguments
+    ///
+    /// * `url` - The link.
+    /// * `timeout` - How long the page may take to load.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<Preview>>` - Returns the preview, `None` if the link isn't an HTML page with a title.
+    async fn fetch(&self, url: &str, timeout: Duration) -> Result<Option<Preview>> {
+        let url = Url::parse(url)?;
+        if !allowed_host(&url, self.private_hosts.load(Ordering::Relaxed)) {
+            return Ok(None);
+        }
+
+        let mut response = self.client.get(url)
+            .header(ACCEPT, "text/html")
+            .timeout(timeout)
+            .send().await?
+            .error_for_status()?;
+        let html = response.headers().get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with("text/html") || value.starts_with("application/xhtml+xml"));
+        if !html {
+            return Ok(None);
+        }
+
+        let mut page = Vec::new();
+        while let Some(chunk) = response.chunk().await? {
+            page.extend_from_slice(&chunk);
+            if page.len() >= MAX_PAGE_SIZE {
+                break;
+            }
+        }
+        Ok(parse_page(&String::from_utf8_lossy(&page)))
+    }
+}
+
+/// Resolves host names to their public addresses only, unless private hosts are allowed.
+/// Resolving in the HTTP client itself also covers redirects and hosts changing their addresses.
+struct PublicResolver {
+    private_hosts: Arc<AtomicBool>,
+}
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let private_hosts = self.private_hosts.load(Ordering::Relaxed);
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0)).await?
+                .filter(|addr| private_hosts || is_public(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(format!("{} has no public address", name.as_str()).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Checks the host of a link given as an IP address, which isn't resolved. Host names are checked by the resolver.
+///
+/// # Arguments
+///
+/// * `url` - The link.
+/// * `private_hosts` - Whether private addresses are allowed.
+///
+/// # Returns
+///
+/// * `bool` - Returns `true` if the link may be fetched.
+fn allowed_host(url: &Url, private_hosts: bool) -> bool {
+    if !matches!(url.scheme(), "http" | "https") {
+        return false;
+    }
+    // IPv6 addresses are in brackets, like http://[::1]/
+    match url.host_str().map(|host| host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>()) {
+        Some(Ok(ip)) => private_hosts || is_public(ip),
+        Some(Err(_)) => true,
+        None => false,
+    }
+}
+
+/// Tells whether an address is reachable on the internet, as opposed to loopback, private and link-local ones.
+///
+/// # Arguments
+///
+/// * `ip` - The IP address.
+///
+/// # Returns
+///
+/// * `bool` - Returns `true` if the address is public.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            // 100.64.0.0/10 is shared by carrier-grade NATs
+            let shared = ip.octets()[0] == 100 && ip.octets()[1] & 0xc0 == 64;
+            !(ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_unspecified()
+                || ip.is_broadcast() || ip.is_documentation() || shared)
+        },
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                let unique_local = ip.segments()[0] & 0xfe00 == 0xfc00;
+                let link_local = ip.segments()[0] & 0xffc0 == 0xfe80;
+                !(ip.is_loopback() || ip.is_unspecified() || unique_local || link_local)
+            },
+        },
+    }
+}
+
+/// Finds the links in a text message, without repeats and at most `MAX_PREVIEWS_PER_MESSAGE`.
+///
+/// # Arguments
+///
+/// * `text` - The text of the message.
+///
+/// # Returns
+///
+/// * `Vec<&str>` - Returns the links in the order they appear.
+pub fn find_urls(text: &str) -> Vec<&str> {
+    let mut urls = Vec::new();
+    for word in text.split_whitespace() {
+        // Links are often wrapped like <https://...>, (https://...) or `https://...`, or end a sentence
+        let url = word.trim_start_matches(['<', '(', '[', '`', '"', '\''])
+            .trim_end_matches(['>', ')', ']', '`', '"', '\'', '.', ',', ';', ':', '!', '?']);
+        let has_host = url.strip_prefix("https://").or_else(|| url.strip_prefix("http://")).is_some_and(|rest| !rest.is_empty());
+        if has_host && !urls.contains(&url) {
+            urls.push(url);
+            if urls.len() == MAX_PREVIEWS_PER_MESSAGE {
+                break;
+            }
+        }
+    }
+    urls
+}
+
+/// Reads the title and the description of a page, preferring the Open Graph ones shown by other chat apps too.
+///
+/// # Arguments
+///
+/// * `html` - The beginning of the page.
+///
+/// # Returns
+///
+/// * `Option<Preview>` - Returns the preview, `None` if the page has no title.
+fn parse_page(html: &str) -> Option<Preview> {
+    static META: OnceLock<Regex> = OnceLock::new();
+    static ATTRIBUTE: OnceLock<Regex> = OnceLock::new();
+    static TITLE: OnceLock<Regex> = OnceLock::new();
+
+    let meta_tag = META.get_or_init(|| Regex::new(r"(?is)<meta\s[^>]*>").unwrap());
+    let attribute = ATTRIBUTE.get_or_init(|| Regex::new(r#"(?is)([a-z:-]+)\s*=\s*(?:"([^"]*)"|'([^']*)')"#).unwrap());
+
+    let head = html.split_once("</head>").map_or(html, |(head, _)| head);
+    let mut meta = HashMap::new();
+    for tag in meta_tag.find_iter(head) {
+        let attributes: HashMap<String, &str> = attribute.captures_iter(tag.as_str())
+            .filter_map(|captures| Some((captures[1].to_lowercase(), captures.get(2).or(captures.get(3))?.as_str())))
+            .collect();
+        if let (Some(key), Some(content)) = (attributes.get("property").or(attributes.get("name")), attributes.get("content")) {
+            meta.entry(key.to_lowercase()).or_insert(*content);
+        }
+    }
+
+    let title_tag = TITLE.get_or_init(|| Regex::new(r"(?is)<title[^>]*>(.*?)</title>").unwrap())
+        .captures(head)
+        .map(|captures| captures.get(1).unwrap().as_str());
+    let title = meta.get("og:title").copied().or(title_tag)
+        .map(|title| clean_text(title, MAX_TITLE_LENGTH))
+        .filter(|title| !title.is_empty())?;
+    let description = meta.get("og:description").or(meta.get("description"))
+        .map(|description| clean_text(description, MAX_DESCRIPTION_LENGTH))
+        .filter(|description| !description.is_empty());
+    Some(Preview { title, description })
+}
+
+/// Decodes the HTML entities of a text, collapses its whitespace and shortens it.
+///
+/// # Arguments
+///
+/// * `text` - The text from the page.
+/// * `max_length` - The maximum number of characters.
+///
+/// # Returns
+///
+/// * `String` - Returns the cleaned text, ending with an ellipsis if it was shortened.
+fn clean_text(text: &str, max_length: usize) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let entity = rest[1..].find(';').filter(|&end| end <= 10).map(|end| &rest[1..end + 1]);
+        let character = entity.and_then(|entity| match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some(' '),
+            _ => entity.strip_prefix('#')
+                .and_then(|code| match code.strip_prefix(['x', 'X']) {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                    None => code.parse().ok(),
+                })
+                .and_then(char::from_u32),
+        });
+        match (entity, character) {
+            (Some(entity), Some(character)) => {
+                decoded.push(character);
+                rest = &rest[entity.len() + 2..];
+            },
+            _ => {
+                decoded.push('&');
+                rest = &rest[1..];
+            },
+        }
+    }
+    decoded.push_str(rest);
+
+    let collapsed = decoded.split_whitespace().collect::<Vec<_>>().join(" ");
+    if collapsed.chars().count() <= max_length {
+        return collapsed;
+    }
+    let shortened: String = collapsed.chars().take(max_length).collect();
+    format!("{}…", shortened.trim_end())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use reqwest::Url;
+
+    use crate::server_preview::{allowed_host, clean_text, find_urls, is_public, parse_page, Preview};
+
+    #[test]
+    fn test_find_urls() {
+        assert_eq!(find_urls("see https://example.com/a?b=c."), vec!["https://example.com/a?b=c"]);
+        assert_eq!(find_urls("(http://a.example) and <https://b.example>, again http://a.example"),
+                   vec!["http://a.example", "https://b.example"]);
+        assert_eq!(find_urls("https://1.example https://2.example https://3.example").len(), 2);
+        assert!(find_urls("no links, ftp://example.com or https:// here").is_empty());
+    }
+
+    #[test]
+    fn test_parse_page() {
+        let page = r#"<html><head>
+            <title>Plain  title</title>
+            <meta name="description" content="A &quot;short&quot; page &amp; more">
+            </head><body><title>Not this</title></body></html>"#;
+        assert_eq!(parse_page(page), Some(Preview {
+            title: "Plain title".to_string(),
+            description: Some("A \"short\" page & more".to_string()),
+        }));
+
+        let page = r#"<head><title>Plain</title>
+            <meta property='og:title' content='Open Graph title'>
+            <meta content="Open Graph description" property="og:description"/>
+            <meta name="description" content="Plain description">"#;
+        assert_eq!(parse_page(page), Some(Preview {
+            title: "Open Graph title".to_string(),
+            description: Some("Open Graph description".to_string()),
+        }));
+
+        assert_eq!(parse_page("<head><meta name=\"description\" content=\"no title\"></head>"), None);
+        assert_eq!(parse_page("<title>   </title>"), None);
+    }
+
+    #[test]
+    fn test_clean_text() {
+        assert_eq!(clean_text("Tom &amp; Jerry&#39;s &#x1F600; &bogus; & co", 100), "Tom & Jerry's 😀 &bogus; & co");
+        assert_eq!(clean_text(" a\n\tb ", 100), "a b");
+        assert_eq!(clean_text("abcdef ghi", 7), "abcdef…");
+    }
+
+    #[test]
+    fn test_allowed_hosts() {
+        let allowed = |url: &str, private_hosts: bool| allowed_host(&Url::parse(url).unwrap(), private_hosts);
+        assert!(allowed("https://example.com/", false));
+        assert!(allowed("http://93.184.216.34/", false));
+        assert!(!allowed("http://127.0.0.1:8080/", false));
+        assert!(!allowed("http://[::1]/", false));
+        assert!(!allowed("http://169.254.169.254/latest/meta-data", false));
+        assert!(!allowed("file:///etc/passwd", true));
+        assert!(allowed("http://192.168.1.1/", true));
+
+        let public = |ip: &str| is_public(ip.parse::<IpAddr>().unwrap());
+        assert!(!public("10.1.2.3"));
+        assert!(!public("100.100.0.1"));
+        assert!(!public("fd00::1"));
+        assert!(!public("::ffff:127.0.0.1"));
+        assert!(public("2001:4860:4860::8888"));
+    }
+}
@@ -0,0 +1,360 @@
+use std::collections::VecDeque;
+use std::pin::pin;
+use std::sync::{Arc, Mutex, OnceLock};
+
+use bytes::Bytes;
+use chat::{ChatProtocolError, CodecKind, Datagram, SessionCodec};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+
+/// Default number of datagrams waiting to be written to a single client.
+pub const DEFAULT_SEND_QUEUE_SIZE: usize = 256;
+
+/// Number of session codecs a datagram can be encoded with, every codec kind with and without compression.
+const SESSION_CODECS: usize = 6;
+
+/// What happens to a datagram for a client whose send queue is full.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum QueuePolicy {
+    /// the oldest queued datagram is dropped to make room
+    DropOldest,
+    /// the client is disconnected
+    #[default]
+    Disconnect,
+    /// the sender waits until there is room, slowing everyone down to the slowest client
+    Block,
+}
+
+/// Result of offering a datagram to a send queue.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Offer {
+    /// The datagram was queued
+    Queued,
+    /// The datagram was queued in place of the oldest one
+    DroppedOldest,
+    /// The queue is full and the client should be disconnected
+    Full,
+    /// The client is being disconnected and doesn't take any more datagrams
+    Closed,
+}
+
+/// Snapshot of the state of a send queue.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct QueueStats {
+    /// Number of datagrams waiting to be written
+    pub depth: usize,
+    /// Maximum number of waiting datagrams
+    pub capacity: usize,
+    /// Highest depth since the client connected
+    pub peak: usize,
+    /// Number of datagrams dropped because the queue was full
+    pub dropped: u64,
+}
+
+/// A datagram queued for one or more clients. It's encoded only once for every codec the clients use,
+/// the recipients with the same codec share the encoded frame.
+pub struct Outgoing {
+    datagram: Datagram,
+    /// Encoded frames, indexed by `codec_index`
+    frames: [OnceLock<Result<Bytes, ChatProtocolError>>; SESSION_CODECS],
+}
+
+impl Outgoing {
+    /// Creates a new instance of `Outgoing`.
+    ///
+    /// # Arguments
+    ///
+    /// * `datagram` - The datagram to be sent.
+    ///
+    /// # Returns
+    ///
+    /// * `Arc<Outgoing>` - Returns the datagram, not encoded yet, to be shared by the send queues of its recipients.
+    pub fn new(datagram: Datagram) -> Arc<Outgoing> {
+        Arc::new(Outgoing { datagram, frames: Default::default() })
+    }
+
+    /// Returns the datagram.
+    pub fn datagram(&self) -> &Datagram {
+        &self.datagram
+    }
+
+    /// Returns the datagram encoded as a frame, encoding it only for the first recipient with the codec.
+    ///
+    /// # Arguments
+    ///
+    /// * `codec` - The codec of the recipient.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Bytes, ChatProtocolError>` - Returns the frame, or why the datagram could not be encoded.
+    pub fn frame(&self, codec: &SessionCodec) -> Result<Bytes, ChatProtocolError> {
+        self.frames[codec_index(codec)].get_or_init(|| self.datagram.encode_frame(codec)).clone()
+    }
+}
+
+/// Returns the position of a session codec in `Outgoing::frames`.
+fn codec_index(codec: &SessionCodec) -> usize {
+    let kind = match codec.kind {
+        CodecKind::Cbor => 0,
+        CodecKind::Json => 1,
+        CodecKind::MessagePack => 2,
+    };
+    kind * 2 + usize::from(codec.compression)
+}
+
+#[derive(Default)]
+struct QueueState {
+    datagrams: VecDeque<Arc<Outgoing>>,
+    closed: bool,
+    peak: usize,
+    dropped: u64,
+}
+
+/// Bounded queue of datagrams waiting to be written to a client by its writer task.
+/// Unlike a channel, it can drop its oldest datagrams and be inspected by the server.
+pub struct SendQueue {
+    state: Mutex<QueueState>,
+    capacity: usize,
+    policy: QueuePolicy,
+    /// Notified when a datagram is pushed or the queue is closed
+    pushed: Notify,
+    /// Notified when a datagram is popped or the queue is closed
+    popped: Notify,
+}
+
+impl SendQueue {
+    /// Creates an empty queue.
+    ///
+    /// # Arguments
+    ///
+    /// * `capacity` - The maximum number of waiting datagrams, at least 1.
+    /// * `policy` - What `offer` does when the queue is full.
+    ///
+    /// # Returns
+    ///
+    /// * `Arc<SendQueue>` - Returns the queue, shared by the server and the writer task.
+    pub fn new(capacity: usize, policy: QueuePolicy) -> Arc<SendQueue> {
+        Arc::new(SendQueue {
+            state: Mutex::new(QueueState::default()),
+            capacity: capacity.max(1),
+            policy,
+            pushed: Notify::new(),
+            popped: Notify::new(),
+        })
+    }
+
+    /// Queues a datagram according to the policy of the queue. Only the `Block` policy waits.
+    ///
+    /// # Arguments
+    ///
+    /// * `datagram` - The datagram to be queued.
+    ///
+    /// # Returns
+    ///
+    /// * `Offer` - Returns whether the datagram was queued.
+    pub async fn offer(&self, datagram: Arc<Outgoing>) -> Offer {
+        match self.policy {
+            QueuePolicy::Block => match self.push(datagram).await {
+                true => Offer::Queued,
+                false => Offer::Closed,
+            },
+            _ => self.try_offer(datagram),
+        }
+    }
+
+    /// Queues a datagram without waiting. A full queue drops its oldest datagram under the `DropOldest` policy,
+    /// otherwise the datagram is refused.
+    ///
+    /// # Arguments
+    ///
+    /// * `datagram` - The datagram to be queued.
+    ///
+    /// # Returns
+    ///
+    /// * `Offer` - Returns whether the datagram was queued.
+    pub fn try_offer(&self, datagram: Arc<Outgoing>) -> Offer {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Offer::Closed;
+        }
+        let mut offer = Offer::Queued;
+        if state.datagrams.len() >= self.capacity {
+            if self.policy != QueuePolicy::DropOldest {
+                return Offer::Full;
+            }
+            state.datagrams.pop_front();
+            state.dropped += 1;
+            offer = Offer::DroppedOldest;
+        }
+        state.datagrams.push_back(datagram);
+        state.peak = state.peak.max(state.datagrams.len());
+        drop(state);
+        self.pushed.notify_one();
+        offer
+    }
+
+    /// Queues a datagram, waiting for room regardless of the policy. Used for file transfers,
+    /// which must not lose chunks and may go as slowly as the client reads.
+    ///
+    /// # Arguments
+    ///
+    /// * `datagram` - The datagram to be queued.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - Returns `false` if the queue was closed.
+    pub async fn push(&self, datagram: Arc<Outgoing>) -> bool {
+        loop {
+            let mut popped = pin!(self.popped.notified());
+            // Registers the waiter before checking, so a pop right after the check isn't missed
+            popped.as_mut().enable();
+            {
+                let mut state = self.state.lock().unwrap();
+                if state.closed {
+                    return false;
+                }
+                if state.datagrams.len() < self.capacity {
+                    state.datagrams.push_back(datagram);
+                    state.peak = state.peak.max(state.datagrams.len());
+                    drop(state);
+                    self.pushed.notify_one();
+                    return true;
+                }
+            }
+            popped.await;
+        }
+    }
+
+    /// Takes the oldest datagram, waiting until there is one.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<Arc<Outgoing>>` - Returns the datagram, `None` once the queue is closed and empty.
+    pub async fn pop(&self) -> Option<Arc<Outgoing>> {
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                if let Some(datagram) = state.datagrams.pop_front() {
+                    drop(state);
+                    self.popped.notify_waiters();
+                    return Some(datagram);
+                }
+                if state.closed {
+                    return None;
+                }
+            }
+            // There is a single reader, a push between the check and here leaves a permit
+            self.pushed.notified().await;
+        }
+    }
+
+    /// Closes the queue. The datagrams already queued can still be popped, new ones are refused.
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.pushed.notify_one();
+        self.popped.notify_waiters();
+    }
+
+    /// Returns the current state of the queue.
+    pub fn stats(&self) -> QueueStats {
+        let state = self.state.lock().unwrap();
+        QueueStats { depth: state.datagrams.len(), capacity: self.capacity, peak: state.peak, dropped: state.dropped }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use chat::{CodecKind, Datagram, SessionCodec};
+
+    use crate::server_queue::{Offer, Outgoing, QueuePolicy, QueueStats, SendQueue};
+
+    /// Makes a datagram distinguishable by its number.
+    fn numbered(n: u64) -> Arc<Outgoing> {
+        Outgoing::new(Datagram::FileEnd { transfer_id: n })
+    }
+
+    async fn popped(queue: &SendQueue) -> Option<u64> {
+        match queue.pop().await.as_deref().map(Outgoing::datagram) {
+            Some(Datagram::FileEnd { transfer_id }) => Some(*transfer_id),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_policy() {
+        let queue = SendQueue::new(2, QueuePolicy::Disconnect);
+        assert_eq!(queue.offer(numbered(1)).await, Offer::Queued);
+        assert_eq!(queue.offer(numbered(2)).await, Offer::Queued);
+        assert_eq!(queue.offer(numbered(3)).await, Offer::Full);
+        assert_eq!(queue.stats(), QueueStats { depth: 2, capacity: 2, peak: 2, dropped: 0 });
+
+        // Closing keeps the queued datagrams for the writer
+        queue.close();
+        assert_eq!(queue.offer(numbered(4)).await, Offer::Closed);
+        assert_eq!(popped(&queue).await, Some(1));
+        assert_eq!(popped(&queue).await, Some(2));
+        assert_eq!(popped(&queue).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_drop_oldest_policy() {
+        let queue = SendQueue::new(2, QueuePolicy::DropOldest);
+        for n in 1..=4 {
+            queue.offer(numbered(n)).await;
+        }
+        assert_eq!(queue.stats(), QueueStats { depth: 2, capacity: 2, peak: 2, dropped: 2 });
+        assert_eq!(popped(&queue).await, Some(3));
+        assert_eq!(popped(&queue).await, Some(4));
+    }
+
+    #[tokio::test]
+    async fn test_block_policy() {
+        let queue = SendQueue::new(1, QueuePolicy::Block);
+        assert_eq!(queue.offer(numbered(1)).await, Offer::Queued);
+        assert_eq!(queue.try_offer(numbered(2)), Offer::Full);
+
+        // The sender waits until the writer makes room
+        let sender = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.offer(numbered(2)).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!sender.is_finished());
+        assert_eq!(popped(&queue).await, Some(1));
+        assert_eq!(sender.await.unwrap(), Offer::Queued);
+        assert_eq!(popped(&queue).await, Some(2));
+
+        // A waiting sender gives up when the client is disconnected
+        queue.offer(numbered(3)).await;
+        let sender = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.offer(numbered(4)).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        queue.close();
+        assert_eq!(sender.await.unwrap(), Offer::Closed);
+    }
+
+    #[tokio::test]
+    async fn test_shared_frames() {
+        let outgoing = Outgoing::new(Datagram::Announcement("hello ".repeat(1000)));
+        let cbor = SessionCodec::from(CodecKind::Cbor);
+        let compressed = SessionCodec { kind: CodecKind::Cbor, compression: true };
+
+        // Recipients with the same codec get the same buffer
+        let frame = outgoing.frame(&cbor).unwrap();
+        assert_eq!(outgoing.frame(&cbor).unwrap().as_ptr(), frame.as_ptr());
+        assert!(outgoing.frame(&compressed).unwrap().len() < frame.len());
+
+        for codec in [cbor, compressed, SessionCodec::from(CodecKind::Json), SessionCodec::from(CodecKind::MessagePack)] {
+            let frame = outgoing.frame(&codec).unwrap();
+            let datagram = Datagram::read_from_stream(&mut &frame[..], &codec).await.unwrap();
+            assert!(matches!(datagram, Datagram::Announcement(text) if text.len() == 6000));
+        }
+    }
+}
@@ -0,0 +1,182 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use chat::{Datagram, EmptyResult, ServerResponse};
+use rand::Rng;
+use reqwest::header::CONTENT_TYPE;
+
+use crate::server_audit::AuditEvent;
+use crate::server_transport::{PeerAddr, ReadHalf, WriteHalf};
+use crate::{send_response, ServerContext, ServerError};
+
+/// Maximum number of characters of a username chosen by a registering user.
+const MAX_USERNAME_LENGTH: usize = 32;
+
+/// How long the server waits for the verification code of a registration.
+const VERIFICATION_CODE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// How long the webhook may take to accept a verification code.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Settings of the registration of new users by clients.
+#[derive(Clone, Debug, Default)]
+pub struct RegistrationConfig {
+    /// URL the verification codes are posted to, `None` prints them to the log
+    pub webhook: Option<String>,
+}
+
+/// Checks that a username chosen by a registering user is 3 to 32 letters, digits, dots, dashes or underscores,
+/// starting with a letter or digit. Usernames of guests are reserved.
+///
+/// # Arguments
+///
+/// * `username` - The requested username.
+///
+/// # Returns
+///
+/// * `Result<(), &'static str>` - Returns the reason if the username is not allowed.
+pub fn validate_username(username: &str) -> Result<(), &'static str> {
+    if username.len() < 3 || username.len() > MAX_USERNAME_LENGTH {
+        return Err("the username must have 3 to 32 characters");
+    }
+    if !username.starts_with(|c: char| c.is_ascii_alphanumeric()) {
+        return Err("the username must start with a letter or a digit");
+    }
+    if !username.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_')) {
+        return Err("the username may contain only letters, digits, dots, dashes and underscores");
+    }
+    if chat::is_guest(username) {
+        return Err("the username is reserved for guests");
+    }
+    Ok(())
+}
+
+/// Generates a one-time verification code of 6 digits.
+fn generate_code() -> String {
+    format!("{:06}", rand::thread_rng().gen_range(0..1_000_000))
+}
+
+/// Hands the verification code of a registration to the operator of the server, who passes it on
+/// to the user like an email would.
+///
+/// # Arguments
+///
+/// * `config` - The registration settings.
+/// * `username` - The username being registered.
+/// * `code` - The verification code.
+///
+/// # Returns
+///
+/// * `EmptyResult` - Returns an empty result if the code was posted or logged.
+async fn deliver_code(config: &RegistrationConfig, username: &str, code: &str) -> EmptyResult {
+    let Some(webhook) = &config.webhook else {
+        tracing::info!("Verification code for the registration of {username}: {code}");
+        return Ok(());
+    };
+
+    let body = serde_json::json!({ "username": username, "code": code });
+    reqwest::Client::new()
+        .post(webhook)
+        .header(CONTENT_TYPE, "application/json")
+        .body(body.to_string())
+        .timeout(WEBHOOK_TIMEOUT)
+        .send().await
+        .and_then(|response| response.error_for_status())
+        .with_context(|| format!("Could not post the verification code to {webhook}."))?;
+    Ok(())
+}
+
+/// Refuses a registration, telling the client why.
+///
+/// # Arguments
+///
+/// * `context` - The server context.
+/// * `write_half` - The writable half of the connection.
+/// * `username` - The requested username.
+/// * `reason` - Why the registration was refused.
+///
+/// # Returns
+///
+/// * `EmptyResult` - Returns the login error closing the connection.
+async fn refuse(context: &ServerContext, write_half: &mut WriteHalf, username: &str, reason: &str) -> EmptyResult {
+    tracing::warn!("Refused the registration of {username}: {reason}.");
+    send_response(write_half, &context.config(), ServerResponse::RegistrationFailed { reason: reason.to_string() }).await?;
+    Err(ServerError::LoginError)?
+}
+
+/// Registers a new user on behalf of a client which sent `Datagram::Register`. The client gets a verification
+/// code out of band, from the log or the webhook, and has to send it back before the user is created.
+/// The connection is closed afterwards, the new user logs in on a new one.
+///
+/// # Arguments
+///
+/// * `context` - The server context.
+/// * `read_half` - The readable half of the connection.
+/// * `write_half` - The writable half of the connection.
+/// * `addr` - The address of the client.
+/// * `username` - The requested username.
+/// * `password` - The password of the new user.
+///
+/// # Returns
+///
+/// * `EmptyResult` - Returns an empty result if the user was registered.
+pub async fn register_client(context: ServerContext, mut read_half: ReadHalf, mut write_half: WriteHalf, addr: PeerAddr,
+                             username: String, password: String) -> EmptyResult {
+    let config = context.config();
+    let Some(registration) = &config.registration else {
+        return refuse(&context, &mut write_half, &username, "registration is disabled on this server").await;
+    };
+    if let Err(reason) = validate_username(&username) {
+        return refuse(&context, &mut write_half, &username, reason).await;
+    }
+    if password.is_empty() {
+        return refuse(&context, &mut write_half, &username, "the password must not be empty").await;
+    }
+    if context.database.user_exists(&username).await? {
+        return refuse(&context, &mut write_half, &username, "the username is taken").await;
+    }
+
+    let code = generate_code();
+    if let Err(e) = deliver_code(registration, &username, &code).await {
+        tracing::error!("{e:#}");
+        return refuse(&context, &mut write_half, &username, "the verification code could not be sent").await;
+    }
+    tracing::info!("Client {addr} is registering {username}, waiting for the verification code.");
+    send_response(&mut write_half, &config, ServerResponse::VerificationRequired).await?;
+
+    let answer = Datagram::read_from_stream_limited(&mut read_half, config.max_message_size, &config.codec);
+    let answer = tokio::time::timeout(VERIFICATION_CODE_TIMEOUT, answer).await.map_err(|_| ServerError::LoginError)??;
+    if !matches!(answer, Datagram::VerifyRegistration { code: answer } if answer.trim() == code) {
+        return refuse(&context, &mut write_half, &username, "wrong verification code").await;
+    }
+
+    // Someone may have taken the username in the meantime
+    if let Err(e) = context.database.register_user(&username, &password).await {
+        tracing::debug!("Could not register {username}: {e}");
+        return refuse(&context, &mut write_half, &username, "the username is taken").await;
+    }
+    tracing::info!("User {username} registered from {addr}.");
+    context.audit(AuditEvent::Register, Some(&username), Some(&username), addr.ip(), None).await?;
+    send_response(&mut write_half, &config, ServerResponse::Registered).await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server_registration::{generate_code, validate_username};
+
+    #[test]
+    fn test_validate_username() {
+        assert!(validate_username("alice").is_ok());
+        assert!(validate_username("bob_2.0-x").is_ok());
+        assert!(validate_username("al").is_err());
+        assert!(validate_username(&"a".repeat(33)).is_err());
+        assert!(validate_username("_alice").is_err());
+        assert!(validate_username("alice smith").is_err());
+        assert!(validate_username("Žofie").is_err());
+        assert!(validate_username("guest-Eve").is_err());
+
+        let code = generate_code();
+        assert_eq!(code.len(), 6);
+        assert!(code.chars().all(|c| c.is_ascii_digit()));
+    }
+}
@@ -0,0 +1,95 @@
+use std::path::PathBuf;
+
+use anyhow::Context;
+use chat::EmptyResult;
+use tracing::level_filters::LevelFilter;
+
+use crate::server_config::FileConfig;
+use crate::{build_config, RunArgs, ServerContext};
+
+/// Handle changing the level of the global tracing subscriber.
+pub type LogHandle = tracing_subscriber::reload::Handle<LevelFilter, tracing_subscriber::Registry>;
+
+/// Rebuilds the configuration of a running server from its configuration file, keeping the flags
+/// given on the command line, which take precedence over the file like at startup.
+pub struct Reloader {
+    /// Configuration file, without one only the content filter is read again
+    config_file: Option<PathBuf>,
+    /// Flags of the `run` command
+    run: RunArgs,
+    /// Directory where attachments are stored, which doesn't change until a restart
+    attachment_dir: PathBuf,
+    /// Log level given on the command line
+    log_level: Option<LevelFilter>,
+    log_handle: LogHandle,
+}
+
+impl Reloader {
+    /// Creates a new instance of `Reloader`.
+    ///
+    /// # Arguments
+    ///
+    /// * `config_file` - The configuration file the server was started with.
+    /// * `run` - The flags of the `run` command.
+    /// * `attachment_dir` - The directory where attachments are stored.
+    /// * `log_level` - The log level given on the command line.
+    /// * `log_handle` - The handle changing the log level.
+    ///
+    /// # Returns
+    ///
+    /// * `Reloader` - Returns the reloader.
+    pub fn new(config_file: Option<PathBuf>, run: RunArgs, attachment_dir: PathBuf, log_level: Option<LevelFilter>, log_handle: LogHandle) -> Reloader {
+        Reloader { config_file, run, attachment_dir, log_level, log_handle }
+    }
+
+    /// Reads the configuration file and applies the settings which can change while the server runs.
+    /// An invalid file changes nothing.
+    ///
+    /// # Arguments
+    ///
+    /// * `context` - The server context.
+    ///
+    /// # Returns
+    ///
+    /// * `EmptyResult` - Returns an empty result if the configuration was reloaded.
+    pub fn reload(&self, context: &ServerContext) -> EmptyResult {
+        let file = match &self.config_file {
+            Some(path) => FileConfig::load(path)?,
+            None => FileConfig::default(),
+        };
+        let level = self.log_level.or(file.log_level).unwrap_or(LevelFilter::INFO);
+        let config = build_config(&self.run, &file, self.attachment_dir.clone())?;
+
+        context.apply_config(config);
+        self.log_handle.modify(|filter| *filter = level).context("Could not change the log level.")?;
+        tracing::info!("Reloaded the configuration, log level {level}.");
+        Ok(())
+    }
+}
+
+/// Reloads the configuration whenever the server receives `SIGHUP`.
+/// Runs until the task is aborted when the server shuts down.
+///
+/// # Arguments
+///
+/// * `context` - The server context.
+#[cfg(unix)]
+pub async fn reload_on_hangup(context: ServerContext) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    use crate::server_audit::SIGNAL_ACTOR;
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            tracing::warn!("Could not listen for SIGHUP, the configuration can only be reloaded from the admin console: {e}");
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        tracing::info!("Received SIGHUP, reloading the configuration.");
+        if let Err(e) = context.reload(SIGNAL_ACTOR, None).await {
+            tracing::error!("Could not reload the configuration: {e:#}");
+        }
+    }
+}
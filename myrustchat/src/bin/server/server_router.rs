@@ -0,0 +1,70 @@
+use crate::server_transport::PeerAddr;
+
+/// Recipients of a datagram.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Route<'a> {
+    /// All connected clients except the author
+    Broadcast { author: PeerAddr },
+    /// All connected clients, for datagrams of the server itself
+    Everyone,
+    /// All connections of a user
+    User(&'a str),
+    /// A single connection
+    Connection(PeerAddr),
+}
+
+/// Decides which connections a datagram is delivered to. The router doesn't touch the connections,
+/// it only picks their addresses, so the policies can be tested without any sockets.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MessageRouter;
+
+impl MessageRouter {
+    /// Selects the connections on a route.
+    ///
+    /// # Arguments
+    ///
+    /// * `route` - The recipients of the datagram.
+    /// * `connections` - The addresses and usernames of all authenticated connections.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<PeerAddr>` - Returns the addresses of the target connections, in the order of `connections`.
+    pub fn targets<'a>(&self, route: Route, connections: impl IntoIterator<Item = (PeerAddr, &'a str)>) -> Vec<PeerAddr> {
+        connections.into_iter()
+            .filter(|(addr, username)| match route {
+                Route::Broadcast { author } => *addr != author,
+                Route::Everyone => true,
+                Route::User(to) => *username == to,
+                Route::Connection(target) => *addr == target,
+            })
+            .map(|(addr, _)| addr)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server_router::{MessageRouter, Route};
+    use crate::server_transport::PeerAddr;
+
+    #[test]
+    fn test_routes() {
+        let alice = PeerAddr::Tcp("10.0.0.1:4000".parse().unwrap());
+        let bob_desktop = PeerAddr::Tcp("10.0.0.2:4000".parse().unwrap());
+        let bob_phone = PeerAddr::Unix(1);
+        let connections = [(alice, "Alice"), (bob_desktop, "Bob"), (bob_phone, "Bob")];
+        let router = MessageRouter;
+
+        // The author of a broadcast doesn't get it back, their other connections do
+        assert_eq!(router.targets(Route::Broadcast { author: bob_desktop }, connections), vec![alice, bob_phone]);
+        assert_eq!(router.targets(Route::Broadcast { author: alice }, connections), vec![bob_desktop, bob_phone]);
+        assert_eq!(router.targets(Route::Everyone, connections), vec![alice, bob_desktop, bob_phone]);
+
+        // A direct message reaches every connection of the recipient
+        assert_eq!(router.targets(Route::User("Bob"), connections), vec![bob_desktop, bob_phone]);
+        assert_eq!(router.targets(Route::User("Carol"), connections), vec![]);
+
+        assert_eq!(router.targets(Route::Connection(bob_phone), connections), vec![bob_phone]);
+        assert_eq!(router.targets(Route::Connection(PeerAddr::Unix(2)), connections), vec![]);
+    }
+}
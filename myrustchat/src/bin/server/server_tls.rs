@@ -0,0 +1,174 @@
+use std::fmt::Write as _;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use rustls::client::danger::HandshakeSignatureValid;
+use rustls::crypto::WebPkiSupportedAlgorithms;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, UnixTime};
+use rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use rustls::{DigitallySignedStruct, DistinguishedName, SignatureScheme};
+use sha2::{Digest, Sha256};
+use tokio_rustls::TlsAcceptor;
+
+use crate::server_transport::{ReadHalf, WriteHalf};
+
+/// How long a client connecting to the TLS port may take to complete the handshake.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Asks clients for a certificate without requiring one, and accepts any certificate whose private key the client
+/// holds. Certificates aren't checked against an authority, the fingerprints registered with `add-certificate`
+/// decide which user a certificate belongs to.
+#[derive(Debug)]
+struct PinnedClientCertificates {
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ClientCertVerifier for PinnedClientCertificates {
+    fn client_auth_mandatory(&self) -> bool {
+        // Users logging in with a password have no certificate
+        false
+    }
+
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(&self, _end_entity: &CertificateDer<'_>, _intermediates: &[CertificateDer<'_>],
+                          _now: UnixTime) -> Result<ClientCertVerified, rustls::Error> {
+        Ok(ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>,
+                              dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>,
+                              dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+/// Reads the certificate chain and the private key of the server and prepares accepting TLS connections.
+///
+/// # Arguments
+///
+/// * `cert_file` - The PEM file with the certificate of the server, followed by any intermediate certificates.
+/// * `key_file` - The PEM file with the private key of the server.
+///
+/// # Returns
+///
+/// * `Result<TlsAcceptor>` - Returns the acceptor, or an error if the files can't be read or don't match.
+pub fn load_acceptor(cert_file: &Path, key_file: &Path) -> Result<TlsAcceptor> {
+    let certs = CertificateDer::pem_file_iter(cert_file)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("Could not read the TLS certificate {}.", cert_file.display()))?;
+    if certs.is_empty() {
+        return Err(anyhow!("No certificate found in {}.", cert_file.display()));
+    }
+    let key = PrivateKeyDer::from_pem_file(key_file)
+        .with_context(|| format!("Could not read the TLS private key {}.", key_file.display()))?;
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let verifier = Arc::new(PinnedClientCertificates { algorithms: provider.signature_verification_algorithms });
+    let config = rustls::ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(certs, key)
+        .with_context(|| format!("The TLS private key {} doesn't match the certificate {}.", key_file.display(), cert_file.display()))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Performs the TLS handshake on an accepted connection.
+///
+/// # Arguments
+///
+/// * `acceptor` - The acceptor of the TLS port.
+/// * `read_half` - The readable half of the connection.
+/// * `write_half` - The writable half of the connection.
+///
+/// # Returns
+///
+/// * `std::io::Result<(ReadHalf, WriteHalf, Option<String>)>` - Returns both halves of the encrypted connection
+///   and the fingerprint of the client certificate, `None` if the client presented none.
+pub async fn accept(acceptor: &TlsAcceptor, read_half: ReadHalf, write_half: WriteHalf) -> std::io::Result<(ReadHalf, WriteHalf, Option<String>)> {
+    let stream = acceptor.accept(tokio::io::join(read_half, write_half)).await?;
+    let certificate = stream.get_ref().1.peer_certificates()
+        .and_then(|certs| certs.first())
+        .map(|cert| fingerprint(cert));
+    let (read_half, write_half) = tokio::io::split(stream);
+    Ok((Box::new(read_half), Box::new(write_half), certificate))
+}
+
+/// Computes the SHA-256 fingerprint of a certificate, in the format `openssl x509 -fingerprint -sha256` prints.
+///
+/// # Arguments
+///
+/// * `der` - The DER encoding of the certificate.
+///
+/// # Returns
+///
+/// * `String` - Returns the fingerprint like `AB:CD:...`, 32 bytes in uppercase hex separated by colons.
+pub fn fingerprint(der: &[u8]) -> String {
+    let mut fingerprint = String::new();
+    for (index, byte) in Sha256::digest(der).iter().enumerate() {
+        let separator = if index == 0 { "" } else { ":" };
+        let _ = write!(fingerprint, "{separator}{byte:02X}");
+    }
+    fingerprint
+}
+
+/// Computes the fingerprint of the first certificate of a PEM file.
+///
+/// # Arguments
+///
+/// * `path` - The path to the PEM file.
+///
+/// # Returns
+///
+/// * `Result<String>` - Returns the fingerprint, or an error if the file holds no certificate.
+pub fn certificate_fingerprint(path: &Path) -> Result<String> {
+    let cert = CertificateDer::from_pem_file(path)
+        .with_context(|| format!("Could not read a certificate from {}.", path.display()))?;
+    Ok(fingerprint(&cert))
+}
+
+/// Brings a fingerprint typed by the operator into the format of `fingerprint`. Colons and case are optional.
+///
+/// # Arguments
+///
+/// * `text` - The fingerprint, e.g. copied from the output of openssl.
+///
+/// # Returns
+///
+/// * `Result<String>` - Returns the fingerprint, or an error if it's not 64 hex digits.
+pub fn parse_fingerprint(text: &str) -> Result<String> {
+    let digits: String = text.trim().chars().filter(|c| *c != ':').collect();
+    if digits.len() != 64 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(anyhow!("Invalid SHA-256 fingerprint {text}, expected 64 hex digits."));
+    }
+    let bytes: Vec<&str> = (0..64).step_by(2).map(|index| &digits[index..index + 2]).collect();
+    Ok(bytes.join(":").to_ascii_uppercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server_tls::{fingerprint, parse_fingerprint};
+
+    #[test]
+    fn test_fingerprint() {
+        let printed = fingerprint(b"certificate");
+        assert_eq!(printed, "03:D6:6D:D0:88:35:C1:CA:3F:12:8C:CE:AC:D1:F3:1A:C9:41:63:09:6B:20:F4:45:AE:84:28:5B:C0:83:2D:72");
+        assert_eq!(parse_fingerprint(&printed).unwrap(), printed);
+        assert_eq!(parse_fingerprint(&printed.replace(':', "").to_lowercase()).unwrap(), printed);
+        assert!(parse_fingerprint("AB:CD").is_err());
+        assert!(parse_fingerprint(&"G".repeat(64)).is_err());
+    }
+}
@@ -0,0 +1,87 @@
+use anyhow::{anyhow, Result};
+use totp_rs::{Algorithm, Secret, TOTP};
+
+/// Issuer shown next to the account in authenticator apps.
+const TOTP_ISSUER: &str = "myrustchat";
+
+/// Number of 30 second steps by which the clock of the user's device may differ from the server's.
+const TOTP_SKEW: u8 = 1;
+
+/// Generates a new random TOTP secret of 160 bits.
+///
+/// # Returns
+///
+/// * `String` - Returns the secret encoded in base32, as stored in the database and shown by authenticator apps.
+pub fn new_secret() -> String {
+    Secret::Raw(rand::random::<[u8; 20]>().to_vec()).to_encoded().to_string()
+}
+
+/// Creates the TOTP generator of a user, with the parameters understood by common authenticator apps:
+/// SHA-1, 6 digits and 30 second steps.
+///
+/// # Arguments
+///
+/// * `secret` - The base32 encoded secret of the user.
+/// * `username` - The username of the user.
+///
+/// # Returns
+///
+/// * `Result<TOTP>` - Returns the generator, or an error if the secret is invalid.
+pub fn totp(secret: &str, username: &str) -> Result<TOTP> {
+    let secret = Secret::Encoded(secret.to_string()).to_bytes().map_err(|e| anyhow!("Invalid TOTP secret: {e}"))?;
+    Ok(TOTP::new(Algorithm::SHA1, 6, TOTP_SKEW, 30, secret, Some(TOTP_ISSUER.to_string()), username.to_string())?)
+}
+
+/// Makes the `otpauth://` URI which authenticator apps import, usually from a QR code.
+///
+/// # Arguments
+///
+/// * `secret` - The base32 encoded secret of the user.
+/// * `username` - The username of the user.
+///
+/// # Returns
+///
+/// * `Result<String>` - Returns the provisioning URI.
+pub fn provisioning_uri(secret: &str, username: &str) -> Result<String> {
+    Ok(totp(secret, username)?.get_url())
+}
+
+/// Checks a code entered by the user, accepting the codes of the neighbouring steps too.
+///
+/// # Arguments
+///
+/// * `secret` - The base32 encoded secret of the user.
+/// * `username` - The username of the user.
+/// * `code` - The code entered by the user.
+/// * `time` - The current UNIX time in seconds.
+///
+/// # Returns
+///
+/// * `Result<bool>` - Returns `true` if the code is valid.
+pub fn verify(secret: &str, username: &str, code: &str, time: u64) -> Result<bool> {
+    Ok(totp(secret, username)?.check(code.trim(), time))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server_totp::{new_secret, provisioning_uri, totp, verify};
+
+    #[test]
+    fn test_totp() {
+        let secret = new_secret();
+        assert_eq!(secret.len(), 32);
+        assert_ne!(secret, new_secret());
+
+        let time = 1_700_000_000;
+        let code = totp(&secret, "Bob").unwrap().generate(time);
+        assert!(verify(&secret, "Bob", &code, time).unwrap());
+        // Clocks may be off by one step, but not more
+        assert!(verify(&secret, "Bob", &code, time + 30).unwrap());
+        assert!(!verify(&secret, "Bob", &code, time + 90).unwrap());
+        assert!(!verify(&new_secret(), "Bob", &code, time).unwrap());
+
+        let uri = provisioning_uri(&secret, "Bob").unwrap();
+        assert!(uri.starts_with("otpauth://totp/myrustchat:Bob?"));
+        assert!(uri.contains(&format!("secret={secret}")));
+    }
+}
@@ -0,0 +1,212 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use chat::{AttachmentKind, ChatMessage, ChatMessageContent, EmptyResult, MessageId, TransferId, FILE_CHUNK_SIZE};
+use sha2::{Digest, Sha256};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+use crate::ServerError;
+
+/// `IncomingTransfer` holds the state of a chunked file transfer received from a client.
+/// The data is written to a partial file in the attachment directory, so that large files don't need to be kept
+/// in memory and an upload interrupted by a dropped connection can be resumed later.
+pub struct IncomingTransfer {
+    /// ID of the chat message assigned by the sender
+    pub message_id: MessageId,
+    /// Username of the sender
+    pub sender: String,
+    /// Type of the transferred file
+    pub kind: AttachmentKind,
+    /// Announced size of the file
+    pub size: u64,
+    next_seq: u64,
+    received: u64,
+    path: PathBuf,
+    file: File,
+}
+
+impl IncomingTransfer {
+    /// Starts a transfer, or resumes it if its partial file exists. The partial file is found by the sender,
+    /// the transfer ID and the type and size of the file, so a transfer is resumed only by the same user
+    /// sending the same file again. Only complete chunks of the partial file are kept.
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - The directory of partial files.
+    /// * `transfer_id` - ID of the transfer chosen by the sender, the same when the transfer is resumed.
+    /// * `message_id` - ID of the chat message assigned by the sender.
+    /// * `sender` - Username of the sender.
+    /// * `kind` - Type of the transferred file.
+    /// * `size` - Announced size of the file.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<IncomingTransfer>` - Returns a result containing an `IncomingTransfer` instance if successful.
+    pub async fn open(dir: &Path, transfer_id: TransferId, message_id: MessageId, sender: String, kind: AttachmentKind, size: u64) -> Result<IncomingTransfer> {
+        tokio::fs::create_dir_all(dir).await
+            .with_context(|| format!("Could not create directory {}.", dir.display()))?;
+        let key = Sha256::new()
+            .chain_update(format!("{sender}\n{transfer_id}\n{kind:?}\n{size}"))
+            .finalize();
+        let path = dir.join(format!("{key:x}"));
+        let mut file = File::options().create(true).truncate(false).read(true).write(true).open(&path).await
+            .with_context(|| format!("Could not open partial file {}.", path.display()))?;
+
+        let chunk_size = FILE_CHUNK_SIZE as u64;
+        let length = file.metadata().await?.len();
+        let next_seq = length.min(size) / chunk_size;
+        let received = next_seq * chunk_size;
+        file.set_len(received).await
+            .with_context(|| format!("Could not truncate partial file {}.", path.display()))?;
+        file.seek(std::io::SeekFrom::End(0)).await?;
+
+        Ok(IncomingTransfer { message_id, sender, kind, size, next_seq, received, path, file })
+    }
+
+    /// Returns the sequence number of the next expected chunk, greater than zero for a resumed transfer.
+    pub fn next_seq(&self) -> u64 {
+        self.next_seq
+    }
+
+    /// Appends a chunk of data to the transfer. All chunks except the last one must be full,
+    /// so that a resumed transfer continues at a whole chunk.
+    ///
+    /// # Arguments
+    ///
+    /// * `seq` - The sequence number of the chunk.
+    /// * `data` - The chunk data.
+    ///
+    /// # Returns
+    ///
+    /// * `EmptyResult` - Returns an empty result if the chunk was accepted.
+    pub async fn write_chunk(&mut self, seq: u64, data: &[u8]) -> EmptyResult {
+        if seq != self.next_seq {
+            Err(ServerError::TransferFailed(format!("expected chunk {}, got {seq}", self.next_seq)))?
+        }
+
+        if data.len() > FILE_CHUNK_SIZE {
+            Err(ServerError::TransferFailed(format!("chunk of {} bytes is too large", data.len())))?
+        }
+
+        if self.received + data.len() as u64 > self.size {
+            Err(ServerError::TransferFailed(format!("more than the announced {} bytes received", self.size)))?
+        }
+
+        if data.len() < FILE_CHUNK_SIZE && self.received + (data.len() as u64) < self.size {
+            Err(ServerError::TransferFailed(format!("chunk {seq} of {} bytes isn't full", data.len())))?
+        }
+
+        self.file.write_all(data).await
+            .context("Could not write to a partial file.")?;
+        self.next_seq += 1;
+        self.received += data.len() as u64;
+        Ok(())
+    }
+
+    /// Completes the transfer and assembles the chat message containing the file. The partial file is removed.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<ChatMessage>` - Returns the received chat message if the whole file was received.
+    pub async fn finish(mut self) -> Result<ChatMessage> {
+        if self.received != self.size {
+            self.discard().await;
+            Err(ServerError::TransferFailed(format!("received {} of {} bytes", self.received, self.size)))?
+        }
+
+        let mut data = Vec::<u8>::with_capacity(self.size as usize);
+        self.file.rewind().await
+            .context("Could not read a partial file.")?;
+        self.file.read_to_end(&mut data).await
+            .context("Could not read a partial file.")?;
+        self.discard().await;
+
+        let content = match self.kind {
+            AttachmentKind::Image => ChatMessageContent::Image(data),
+            AttachmentKind::File(filename) => ChatMessageContent::File(filename, data),
+            AttachmentKind::Audio(format) => ChatMessageContent::Audio { format, data },
+        };
+
+        Ok(ChatMessage::new(self.message_id, &self.sender, content))
+    }
+
+    /// Removes the partial file of a transfer which was cancelled or failed, so it can't be resumed.
+    pub async fn discard(&self) {
+        if let Err(e) = tokio::fs::remove_file(&self.path).await {
+            tracing::warn!("Could not remove partial file {}: {e}", self.path.display());
+        }
+    }
+}
+
+/// Removes partial files of transfers which weren't resumed for a long time.
+///
+/// # Arguments
+///
+/// * `dir` - The directory of partial files.
+/// * `max_age` - How long an untouched partial file is kept.
+///
+/// # Returns
+///
+/// * `Result<usize>` - Returns the number of removed files.
+pub async fn remove_stale_partials(dir: &Path, max_age: std::time::Duration) -> Result<usize> {
+    let mut files = match tokio::fs::read_dir(dir).await {
+        Ok(files) => files,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => Err(e).with_context(|| format!("Could not read directory {}.", dir.display()))?,
+    };
+
+    let mut removed = 0;
+    while let Some(file) = files.next_entry().await? {
+        let age = file.metadata().await?.modified()?.elapsed().unwrap_or_default();
+        if age >= max_age {
+            tokio::fs::remove_file(file.path()).await
+                .with_context(|| format!("Could not remove partial file {}.", file.path().display()))?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chat::{AttachmentKind, ChatMessageContent, FILE_CHUNK_SIZE};
+
+    use crate::server_transfer::{remove_stale_partials, IncomingTransfer};
+
+    #[tokio::test]
+    async fn test_incoming_transfer() {
+        let dir = tempfile::tempdir().unwrap();
+        let kind = AttachmentKind::File("test.txt".to_string());
+        let size = FILE_CHUNK_SIZE as u64 + 3;
+        let open = |kind: AttachmentKind, size| IncomingTransfer::open(dir.path(), 1, 2, "Bob".to_string(), kind, size);
+
+        let mut transfer = open(kind.clone(), size).await.unwrap();
+        let chunk = vec![7u8; FILE_CHUNK_SIZE];
+        assert!(transfer.write_chunk(0, b"abc").await.is_err());
+        assert!(transfer.write_chunk(1, &chunk).await.is_err());
+        assert!(transfer.write_chunk(0, &chunk).await.is_ok());
+        drop(transfer);
+
+        // The same file sent again continues after the received chunk, another file starts over
+        assert_eq!(open(AttachmentKind::Image, size).await.unwrap().next_seq(), 0);
+        let mut transfer = open(kind.clone(), size).await.unwrap();
+        assert_eq!(transfer.next_seq(), 1);
+        assert!(transfer.write_chunk(1, b"defg").await.is_err());
+        assert!(transfer.write_chunk(1, b"def").await.is_ok());
+
+        let message = transfer.finish().await.unwrap();
+        assert_eq!(message.id, 2);
+        assert!(matches!(message.content, ChatMessageContent::File(filename, data) if filename == "test.txt"
+            && data[..FILE_CHUNK_SIZE] == chunk[..] && &data[FILE_CHUNK_SIZE..] == b"def"));
+        assert_eq!(open(kind.clone(), size).await.unwrap().next_seq(), 0);
+
+        let transfer = open(kind, 6).await.unwrap();
+        assert!(transfer.finish().await.is_err());
+
+        assert_eq!(remove_stale_partials(dir.path(), Duration::from_secs(60)).await.unwrap(), 0);
+        assert_eq!(remove_stale_partials(dir.path(), Duration::ZERO).await.unwrap(), 2);
+    }
+}
@@ -0,0 +1,246 @@
+use std::fmt::Display;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+
+/// Readable half of a client connection, TCP or Unix socket.
+pub type ReadHalf = Box<dyn AsyncRead + Send + Unpin>;
+
+/// Writable half of a client connection, TCP or Unix socket.
+pub type WriteHalf = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// Address of a connected client. Clients connected through a Unix socket are unnamed,
+/// so they are numbered in the order they connected instead.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PeerAddr {
+    Tcp(SocketAddr),
+    Unix(u64),
+}
+
+impl PeerAddr {
+    /// Returns the IP address of a TCP client, `None` for Unix socket clients. IPv4 clients of a dual-stack
+    /// socket have their plain IPv4 address, so that they count the same towards the limits per address.
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            PeerAddr::Tcp(addr) => Some(addr.ip().to_canonical()),
+            PeerAddr::Unix(_) => None,
+        }
+    }
+}
+
+impl Display for PeerAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PeerAddr::Tcp(addr) => write!(f, "{addr}"),
+            PeerAddr::Unix(number) => write!(f, "unix:{number}"),
+        }
+    }
+}
+
+/// Socket accepting client connections.
+pub enum Listener {
+    Tcp(TcpListener),
+    /// TCP socket whose clients start with a TLS handshake, which is done by the task of the connection
+    Tls {
+        listener: TcpListener,
+        acceptor: TlsAcceptor,
+    },
+    #[cfg(unix)]
+    Unix {
+        listener: tokio::net::UnixListener,
+        /// Number given to the next accepted client
+        next_peer: u64,
+    },
+}
+
+impl Listener {
+    /// Binds a TCP socket. An IPv6 address may be enclosed in brackets, e.g. `[::1]`.
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - The address to bind to.
+    /// * `port` - The port to bind to.
+    /// * `only_v6` - Whether an IPv6 socket refuses IPv4 clients, so that an IPv4 socket can be bound
+    ///   to the same port. Otherwise binding `::` accepts clients of both protocols.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Listener>` - Returns the listener if successful.
+    pub async fn bind_tcp(address: &str, port: u16, only_v6: bool) -> Result<Listener> {
+        let host = address.strip_prefix('[').and_then(|host| host.strip_suffix(']')).unwrap_or(address);
+        let addr = tokio::net::lookup_host((host, port)).await
+            .with_context(|| format!("Could not resolve {address}."))?
+            .next()
+            .with_context(|| format!("Could not resolve {address}."))?;
+        let listener = bind_socket(addr, only_v6)
+            .with_context(|| format!("Could not bind {address}:{port}."))?;
+        Ok(Listener::Tcp(listener))
+    }
+
+    /// Binds a Unix socket. A socket file left behind by a previous run is replaced.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the socket file.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Listener>` - Returns the listener if successful.
+    #[cfg(unix)]
+    pub fn bind_unix(path: &Path) -> Result<Listener> {
+        use std::os::unix::fs::FileTypeExt;
+
+        if std::fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+            std::fs::remove_file(path)
+                .with_context(|| format!("Could not remove the stale socket {}.", path.display()))?;
+        }
+        let listener = tokio::net::UnixListener::bind(path)
+            .with_context(|| format!("Could not bind the Unix socket {}.", path.display()))?;
+        Ok(Listener::Unix { listener, next_peer: 1 })
+    }
+
+    /// Binds a Unix socket, which is not available on this platform.
+    #[cfg(not(unix))]
+    pub fn bind_unix(path: &Path) -> Result<Listener> {
+        Err(anyhow::anyhow!("Could not bind {}: Unix sockets are not supported on this platform.", path.display()))
+    }
+
+    /// Makes the clients of a TCP listener start with a TLS handshake.
+    ///
+    /// # Arguments
+    ///
+    /// * `acceptor` - The acceptor holding the certificate of the server.
+    ///
+    /// # Returns
+    ///
+    /// * `Listener` - Returns the TLS listener, other listeners are returned as they are.
+    pub fn with_tls(self, acceptor: TlsAcceptor) -> Listener {
+        match self {
+            Listener::Tcp(listener) => Listener::Tls { listener, acceptor },
+            listener => listener,
+        }
+    }
+
+    /// Returns the acceptor doing the TLS handshake with the clients of a TLS listener, `None` for other listeners.
+    pub fn tls_acceptor(&self) -> Option<TlsAcceptor> {
+        match self {
+            Listener::Tls { acceptor, .. } => Some(acceptor.clone()),
+            _ => None,
+        }
+    }
+
+    /// Returns the address of a TCP listener, e.g. to find the port chosen by the system when binding port 0.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<SocketAddr>` - Returns the bound address, `None` for a Unix socket.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Listener::Tcp(listener) | Listener::Tls { listener, .. } => listener.local_addr().ok(),
+            #[cfg(unix)]
+            Listener::Unix { .. } => None,
+        }
+    }
+
+    /// Waits for a client to connect.
+    ///
+    /// # Returns
+    ///
+    /// * `std::io::Result<(ReadHalf, WriteHalf, PeerAddr)>` - Returns both halves of the connection and the address of the client.
+    pub async fn accept(&mut self) -> std::io::Result<(ReadHalf, WriteHalf, PeerAddr)> {
+        match self {
+            Listener::Tcp(listener) | Listener::Tls { listener, .. } => {
+                let (stream, addr) = listener.accept().await?;
+                let (read_half, write_half) = stream.into_split();
+                Ok((Box::new(read_half), Box::new(write_half), PeerAddr::Tcp(addr)))
+            },
+            #[cfg(unix)]
+            Listener::Unix { listener, next_peer } => {
+                let (stream, _) = listener.accept().await?;
+                let (read_half, write_half) = stream.into_split();
+                let addr = PeerAddr::Unix(*next_peer);
+                *next_peer += 1;
+                Ok((Box::new(read_half), Box::new(write_half), addr))
+            },
+        }
+    }
+}
+
+/// Binds a listening TCP socket, setting the options `TcpListener::bind` doesn't expose.
+///
+/// # Arguments
+///
+/// * `addr` - The address to bind to.
+/// * `only_v6` - Whether an IPv6 socket refuses IPv4 clients.
+///
+/// # Returns
+///
+/// * `std::io::Result<TcpListener>` - Returns the listener if successful.
+fn bind_socket(addr: SocketAddr, only_v6: bool) -> std::io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(only_v6)?;
+    }
+    // Like `TcpListener::bind`, so that a restarted server doesn't wait for the old connections to time out
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server_transport::{Listener, PeerAddr};
+
+    #[test]
+    fn test_peer_addr() {
+        let tcp = PeerAddr::Tcp("10.0.0.1:4000".parse().unwrap());
+        assert_eq!(tcp.to_string(), "10.0.0.1:4000");
+        assert_eq!(tcp.ip(), Some("10.0.0.1".parse().unwrap()));
+        assert_eq!(PeerAddr::Unix(3).to_string(), "unix:3");
+        assert_eq!(PeerAddr::Unix(3).ip(), None);
+        let mapped = PeerAddr::Tcp("[::ffff:10.0.0.1]:4000".parse().unwrap());
+        assert_eq!(mapped.ip(), Some("10.0.0.1".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_bind_tcp() {
+        let first = Listener::bind_tcp("127.0.0.1", 0, false).await.unwrap();
+        let port = first.local_addr().unwrap().port();
+        assert!(Listener::bind_tcp("127.0.0.1", port, false).await.is_err());
+
+        // An IPv6-only socket shares the port with an IPv4 one, where IPv6 is available
+        if let Ok(second) = Listener::bind_tcp("[::1]", port, true).await {
+            assert_eq!(second.local_addr().unwrap(), format!("[::1]:{port}").parse().unwrap());
+        }
+        assert!(Listener::bind_tcp("no such host.invalid", 0, false).await.is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unix_listener() {
+        let path = tempfile::tempdir().unwrap().keep().join("chat.sock");
+        // A stale socket from a previous run is replaced
+        drop(Listener::bind_unix(&path).unwrap());
+        let mut listener = Listener::bind_unix(&path).unwrap();
+
+        let _first = tokio::net::UnixStream::connect(&path).await.unwrap();
+        let _second = tokio::net::UnixStream::connect(&path).await.unwrap();
+        let (_, _, first) = listener.accept().await.unwrap();
+        let (_, _, second) = listener.accept().await.unwrap();
+        assert_eq!((first, second), (PeerAddr::Unix(1), PeerAddr::Unix(2)));
+
+        // Other files are never removed
+        let file = path.with_file_name("data.txt");
+        std::fs::write(&file, "data").unwrap();
+        assert!(Listener::bind_unix(&file).is_err());
+        assert!(file.exists());
+    }
+}
@@ -0,0 +1,815 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, Notify};
+
+use crate::batch::BatchWriter;
+use crate::{ChatMessage, ChatMessageContent, ChatProtocolError, CodecKind, Datagram, MessageId, MessageSeq, ReplyTo, ServerResponse, SessionCodec, DEFAULT_WRITE_TIMEOUT, GUEST_PREFIX};
+
+/// Readable half of the connection to the server, TCP or Unix socket.
+pub type ReadHalf = Box<dyn AsyncRead + Send + Unpin>;
+
+/// Writable half of the connection to the server, TCP or Unix socket.
+pub type WriteHalf = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// Number of received datagrams buffered until they are picked up by `ChatClient::recv`.
+const INCOMING_QUEUE_SIZE: usize = 256;
+
+/// Enum representing the reasons why connecting to the server failed.
+#[derive(Debug, thiserror::Error)]
+pub enum LoginError {
+    #[error("Could not connect to {0}")]
+    Connect(String, #[source] std::io::Error),
+    #[error("Login failed")]
+    LoginFailed,
+    #[error("You are banned from this server")]
+    Banned,
+    #[error("The server is full, try again later")]
+    ServerFull,
+    #[error("The account requires a two-factor authentication code")]
+    TotpRequired,
+    #[error("Invalid two-factor authentication code")]
+    InvalidCode,
+    #[error("Too many failed logins, try again after {}", .0.with_timezone(&chrono::Local).format("%H:%M:%S"))]
+    Locked(DateTime<Utc>),
+    #[error("You are already logged in from another connection")]
+    AlreadyLoggedIn,
+    #[error("Registration failed: {0}")]
+    RegistrationFailed(String),
+    #[error("Invalid TLS settings: {0}")]
+    Tls(String),
+    #[error(transparent)]
+    Protocol(#[from] ChatProtocolError),
+}
+
+/// Opens a TCP connection to the server.
+///
+/// # Arguments
+///
+/// * `address` - The address of the server.
+/// * `port` - The port of the server.
+///
+/// # Returns
+///
+/// * `Result<(ReadHalf, WriteHalf), LoginError>` - Returns both halves of the connection if successful.
+pub async fn open_tcp(address: &str, port: u16) -> Result<(ReadHalf, WriteHalf), LoginError> {
+    let stream = TcpStream::connect((address, port)).await
+        .map_err(|e| LoginError::Connect(format!("{address}:{port}"), e))?;
+    let (read_half, write_half) = stream.into_split();
+    Ok((Box::new(read_half), Box::new(write_half)))
+}
+
+/// Opens a connection to the Unix socket of the server.
+///
+/// # Arguments
+///
+/// * `path` - The path of the socket.
+///
+/// # Returns
+///
+/// * `Result<(ReadHalf, WriteHalf), LoginError>` - Returns both halves of the connection if successful.
+#[cfg(unix)]
+pub async fn open_unix(path: &Path) -> Result<(ReadHalf, WriteHalf), LoginError> {
+    let stream = tokio::net::UnixStream::connect(path).await
+        .map_err(|e| LoginError::Connect(path.display().to_string(), e))?;
+    let (read_half, write_half) = stream.into_split();
+    Ok((Box::new(read_half), Box::new(write_half)))
+}
+
+/// Opens a connection to the Unix socket of the server, which is not available on this platform.
+#[cfg(not(unix))]
+pub async fn open_unix(path: &Path) -> Result<(ReadHalf, WriteHalf), LoginError> {
+    let e = std::io::Error::new(std::io::ErrorKind::Unsupported, "Unix sockets are not supported on this platform");
+    Err(LoginError::Connect(path.display().to_string(), e))
+}
+
+/// Certificates used to connect to the TLS port of the server.
+#[derive(Clone, Debug, Default)]
+pub struct TlsOptions {
+    /// PEM file with the certificate of the server or of the authority which signed it, the usual web authorities if `None`
+    pub ca_file: Option<PathBuf>,
+    /// PEM files with the certificate and the private key of the client, for bots logging in with `certificate_login`
+    pub identity: Option<(PathBuf, PathBuf)>,
+}
+
+impl TlsOptions {
+    /// Builds the rustls configuration from the PEM files.
+    fn client_config(&self) -> Result<rustls::ClientConfig, LoginError> {
+        let unreadable = |path: &Path, e: &dyn std::fmt::Display| LoginError::Tls(format!("could not read {}: {e}", path.display()));
+
+        let mut roots = rustls::RootCertStore::empty();
+        match &self.ca_file {
+            Some(path) => {
+                for cert in CertificateDer::pem_file_iter(path).map_err(|e| unreadable(path, &e))? {
+                    roots.add(cert.map_err(|e| unreadable(path, &e))?).map_err(|e| unreadable(path, &e))?;
+                }
+            },
+            None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+        }
+
+        let builder = rustls::ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(|e| LoginError::Tls(e.to_string()))?
+            .with_root_certificates(roots);
+        match &self.identity {
+            Some((cert_file, key_file)) => {
+                let certs = CertificateDer::pem_file_iter(cert_file)
+                    .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                    .map_err(|e| unreadable(cert_file, &e))?;
+                let key = PrivateKeyDer::from_pem_file(key_file).map_err(|e| unreadable(key_file, &e))?;
+                builder.with_client_auth_cert(certs, key).map_err(|e| LoginError::Tls(e.to_string()))
+            },
+            None => Ok(builder.with_no_client_auth()),
+        }
+    }
+}
+
+/// Opens a TLS connection to the TLS port of the server. The certificate of the server must be valid for `address`.
+///
+/// # Arguments
+///
+/// * `address` - The host name or IP address of the server.
+/// * `port` - The TLS port of the server.
+/// * `options` - The certificates trusted and presented by the client.
+///
+/// # Returns
+///
+/// * `Result<(ReadHalf, WriteHalf), LoginError>` - Returns both halves of the connection if successful.
+pub async fn open_tls(address: &str, port: u16, options: &TlsOptions) -> Result<(ReadHalf, WriteHalf), LoginError> {
+    let config = options.client_config()?;
+    let name = ServerName::try_from(address.to_string())
+        .map_err(|e| LoginError::Tls(format!("invalid server name {address}: {e}")))?;
+    let stream = TcpStream::connect((address, port)).await
+        .map_err(|e| LoginError::Connect(format!("{address}:{port}"), e))?;
+    let stream = tokio_rustls::TlsConnector::from(Arc::new(config)).connect(name, stream).await
+        .map_err(|e| LoginError::Connect(format!("{address}:{port}"), e))?;
+    let (read_half, write_half) = tokio::io::split(stream);
+    Ok((Box::new(read_half), Box::new(write_half)))
+}
+
+/// Logs in on an open connection.
+///
+/// # Arguments
+///
+/// * `read_half` - The readable half of the connection.
+/// * `write_half` - The writable half of the connection.
+/// * `username` - The username of the user.
+/// * `password` - The password of the user.
+/// * `totp_code` - Called for the TOTP code if the user enabled two-factor authentication, `None` gives up the login.
+/// * `codec` - The codec used by the server.
+/// * `compression` - Whether to offer the server to compress large frames.
+/// * `last_id` - The ID of the last stored message received before the connection broke, the server sends
+///   the newer ones first. `None` for a new session.
+///
+/// # Returns
+///
+/// * `Result<SessionCodec, LoginError>` - Returns the codec of the session if the server accepted the login.
+#[allow(clippy::too_many_arguments)]
+pub async fn login(read_half: &mut ReadHalf, write_half: &mut WriteHalf, username: &str, password: &str, totp_code: impl FnOnce() -> Option<String>,
+                   codec: CodecKind, compression: bool, last_id: Option<MessageSeq>) -> Result<SessionCodec, LoginError> {
+    let login_datagram = Datagram::Login { username: username.to_string(), password: password.to_string(), compression, last_id };
+    login_datagram.write_to_stream_timeout(write_half, &codec, Some(DEFAULT_WRITE_TIMEOUT)).await?;
+
+    let mut response = Datagram::read_from_stream(read_half, &codec).await?;
+    if let Datagram::ServerResponse(ServerResponse::TotpRequired) = response {
+        let code = totp_code().ok_or(LoginError::TotpRequired)?;
+        Datagram::TotpCode(code).write_to_stream_timeout(write_half, &codec, Some(DEFAULT_WRITE_TIMEOUT)).await?;
+        response = match Datagram::read_from_stream(read_half, &codec).await? {
+            // The password was already accepted, so only the code can be wrong
+            Datagram::ServerResponse(ServerResponse::LoginFailed) => return Err(LoginError::InvalidCode),
+            response => response,
+        };
+    }
+    login_result(response, codec)
+}
+
+/// Logs in with the client certificate of a TLS connection opened by `open_tls`, without a password.
+/// The server must have the fingerprint of the certificate registered for the user.
+///
+/// # Arguments
+///
+/// * `read_half` - The readable half of the connection.
+/// * `write_half` - The writable half of the connection.
+/// * `username` - The username the certificate is registered for.
+/// * `codec` - The codec used by the server.
+/// * `compression` - Whether to offer the server to compress large frames.
+/// * `last_id` - The ID of the last stored message received before the connection broke, `None` for a new session.
+///
+/// # Returns
+///
+/// * `Result<SessionCodec, LoginError>` - Returns the codec of the session if the server accepted the login.
+pub async fn certificate_login(read_half: &mut ReadHalf, write_half: &mut WriteHalf, username: &str, codec: CodecKind, compression: bool,
+                               last_id: Option<MessageSeq>) -> Result<SessionCodec, LoginError> {
+    let login_datagram = Datagram::CertificateLogin { username: username.to_string(), compression, last_id };
+    login_datagram.write_to_stream_timeout(write_half, &codec, Some(DEFAULT_WRITE_TIMEOUT)).await?;
+    let response = Datagram::read_from_stream(read_half, &codec).await?;
+    login_result(response, codec)
+}
+
+/// Registers a new user on an open connection. The server asks for a verification code, which the operator
+/// of the server passes on to the user. The server closes the connection afterwards, the new user logs in on a new one.
+///
+/// # Arguments
+///
+/// * `read_half` - The readable half of the connection.
+/// * `write_half` - The writable half of the connection.
+/// * `username` - The username of the new user.
+/// * `password` - The password of the new user.
+/// * `code` - Called for the verification code, `None` gives up the registration.
+/// * `codec` - The codec used by the server.
+///
+/// # Returns
+///
+/// * `Result<(), LoginError>` - Returns an empty result if the user was registered.
+pub async fn register(read_half: &mut ReadHalf, write_half: &mut WriteHalf, username: &str, password: &str, code: impl FnOnce() -> Option<String>,
+                      codec: CodecKind) -> Result<(), LoginError> {
+    Datagram::Register { username: username.to_string(), password: password.to_string() }.write_to_stream_timeout(write_half, &codec, Some(DEFAULT_WRITE_TIMEOUT)).await?;
+
+    let mut response = Datagram::read_from_stream(read_half, &codec).await?;
+    if let Datagram::ServerResponse(ServerResponse::VerificationRequired) = response {
+        let code = code().ok_or_else(|| LoginError::RegistrationFailed("no verification code was given".to_string()))?;
+        Datagram::VerifyRegistration { code }.write_to_stream_timeout(write_half, &codec, Some(DEFAULT_WRITE_TIMEOUT)).await?;
+        response = Datagram::read_from_stream(read_half, &codec).await?;
+    }
+
+    match response {
+        Datagram::ServerResponse(ServerResponse::Registered) => Ok(()),
+        Datagram::ServerResponse(ServerResponse::RegistrationFailed { reason }) => Err(LoginError::RegistrationFailed(reason)),
+        Datagram::ServerResponse(ServerResponse::ServerFull) => Err(LoginError::ServerFull),
+        _ => Err(LoginError::RegistrationFailed("unexpected response of the server".to_string())),
+    }
+}
+
+/// Logs in as a guest on an open connection. The server must allow guests.
+///
+/// # Arguments
+///
+/// * `read_half` - The readable half of the connection.
+/// * `write_half` - The writable half of the connection.
+/// * `nickname` - The nickname of the guest, the username is the nickname with `GUEST_PREFIX` in front of it.
+/// * `codec` - The codec used by the server.
+/// * `last_id` - The ID of the last stored message received before the connection broke, like for `login`.
+///
+/// # Returns
+///
+/// * `Result<SessionCodec, LoginError>` - Returns the codec of the session if the server accepted the login.
+pub async fn guest_login(read_half: &mut ReadHalf, write_half: &mut WriteHalf, nickname: &str, codec: CodecKind,
+                         last_id: Option<MessageSeq>) -> Result<SessionCodec, LoginError> {
+    Datagram::GuestLogin { nickname: nickname.to_string(), last_id }.write_to_stream_timeout(write_half, &codec, Some(DEFAULT_WRITE_TIMEOUT)).await?;
+    let response = Datagram::read_from_stream(read_half, &codec).await?;
+    login_result(response, codec)
+}
+
+/// Interprets the response of the server to a login.
+///
+/// # Arguments
+///
+/// * `response` - The datagram received after the login.
+/// * `codec` - The codec used by the server.
+///
+/// # Returns
+///
+/// * `Result<SessionCodec, LoginError>` - Returns the codec of the session if the server accepted the login.
+fn login_result(response: Datagram, codec: CodecKind) -> Result<SessionCodec, LoginError> {
+    match response {
+        Datagram::ServerResponse(ServerResponse::LoginOk) => Ok(SessionCodec::from(codec)),
+        Datagram::ServerResponse(ServerResponse::LoginOkCompressed) => Ok(SessionCodec { kind: codec, compression: true }),
+        Datagram::ServerResponse(ServerResponse::Banned) => Err(LoginError::Banned),
+        Datagram::ServerResponse(ServerResponse::ServerFull) => Err(LoginError::ServerFull),
+        Datagram::ServerResponse(ServerResponse::LoginLocked { until }) => Err(LoginError::Locked(until)),
+        Datagram::ServerResponse(ServerResponse::AlreadyLoggedIn) => Err(LoginError::AlreadyLoggedIn),
+        _ => Err(LoginError::LoginFailed),
+    }
+}
+
+/// A chat message received by a `ChatClient`.
+#[derive(Debug, Clone)]
+pub struct IncomingMessage {
+    pub message: ChatMessage,
+    /// Whether the message was sent only to this user
+    pub direct: bool,
+}
+
+/// Sending side of a `ChatClient`. It's cheap to clone and can be moved to other tasks.
+#[derive(Clone)]
+pub struct ChatSender {
+    username: String,
+    codec: SessionCodec,
+    writer: Arc<BatchWriter>,
+    /// Notified when a write fails, so that the client stops receiving as well
+    disconnected: Arc<Notify>,
+    next_message_id: Arc<AtomicU64>,
+}
+
+impl ChatSender {
+    /// Sends a text message to everyone.
+    ///
+    /// # Arguments
+    ///
+    /// * `text` - The text of the message.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<MessageId, ChatProtocolError>` - Returns the ID of the message, acknowledged later by the server.
+    pub async fn send_text(&self, text: &str) -> Result<MessageId, ChatProtocolError> {
+        let message = self.message(text);
+        let id = message.id;
+        self.send(&Datagram::Message(message)).await?;
+        Ok(id)
+    }
+
+    /// Sends a text message to everyone, replying to a received message.
+    ///
+    /// # Arguments
+    ///
+    /// * `original` - The message being replied to.
+    /// * `text` - The text of the reply.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<MessageId, ChatProtocolError>` - Returns the ID of the reply, acknowledged later by the server.
+    pub async fn send_reply(&self, original: &ChatMessage, text: &str) -> Result<MessageId, ChatProtocolError> {
+        let message = ChatMessage {
+            reply_to: Some(ReplyTo { sender: original.sender.clone(), id: original.id }),
+            ..self.message(text)
+        };
+        let id = message.id;
+        self.send(&Datagram::Message(message)).await?;
+        Ok(id)
+    }
+
+    /// Posts a text message to the announcement channel, which only admins may do.
+    /// The server answers other users with `ServerResponse::MessageRejected`.
+    ///
+    /// # Arguments
+    ///
+    /// * `text` - The text of the announcement.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<MessageId, ChatProtocolError>` - Returns the ID of the message, acknowledged later by the server.
+    pub async fn send_announcement(&self, text: &str) -> Result<MessageId, ChatProtocolError> {
+        let message = ChatMessage { announcement: true, ..self.message(text) };
+        let id = message.id;
+        self.send(&Datagram::Message(message)).await?;
+        Ok(id)
+    }
+
+    /// Sends a text message which the server deletes after `ttl` seconds, telling everyone with `Datagram::MessageExpired`.
+    ///
+    /// # Arguments
+    ///
+    /// * `text` - The text of the message.
+    /// * `ttl` - The number of seconds after the arrival at the server when the message expires.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<MessageId, ChatProtocolError>` - Returns the ID of the message, acknowledged later by the server.
+    pub async fn send_ephemeral(&self, text: &str, ttl: u32) -> Result<MessageId, ChatProtocolError> {
+        let message = ChatMessage { ttl: Some(ttl), ..self.message(text) };
+        let id = message.id;
+        self.send(&Datagram::Message(message)).await?;
+        Ok(id)
+    }
+
+    /// Sends a text message to a single user.
+    ///
+    /// # Arguments
+    ///
+    /// * `to` - The username of the recipient.
+    /// * `text` - The text of the message.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<MessageId, ChatProtocolError>` - Returns the ID of the message, acknowledged later by the server.
+    pub async fn send_direct(&self, to: &str, text: &str) -> Result<MessageId, ChatProtocolError> {
+        let message = self.message(text);
+        let id = message.id;
+        self.send(&Datagram::DirectMessage { to: to.to_string(), message }).await?;
+        Ok(id)
+    }
+
+    /// Sends any datagram, for the features without a dedicated method. A failed or timed out write closes
+    /// the connection, as the server may have received part of the datagram, and later sends fail right away.
+    ///
+    /// # Arguments
+    ///
+    /// * `datagram` - The datagram to be sent.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), ChatProtocolError>` - Returns an empty result if successful.
+    pub async fn send(&self, datagram: &Datagram) -> Result<(), ChatProtocolError> {
+        let result = self.writer.write(datagram, &self.codec).await;
+        self.check_disconnected(&result);
+        result
+    }
+
+    /// Sends several datagrams in order, written together instead of one at a time, e.g. the lines of a long answer.
+    /// If one of them can't be encoded, none are sent.
+    ///
+    /// # Arguments
+    ///
+    /// * `datagrams` - The datagrams to be sent.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), ChatProtocolError>` - Returns an empty result if all were sent.
+    pub async fn send_all(&self, datagrams: &[Datagram]) -> Result<(), ChatProtocolError> {
+        let queued = self.writer.queue_all(datagrams, &self.codec)?;
+        let result = self.writer.flush(queued).await;
+        self.check_disconnected(&result);
+        result
+    }
+
+    /// Stops the receiving side as well once a write closed the connection.
+    fn check_disconnected(&self, result: &Result<(), ChatProtocolError>) {
+        if matches!(result, Err(ChatProtocolError::IOError | ChatProtocolError::Timeout)) {
+            self.disconnected.notify_one();
+        }
+    }
+
+    /// Sets how long the server may take to accept a datagram before the connection is closed.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - The timeout, `None` waits forever. It's `DEFAULT_WRITE_TIMEOUT` unless changed.
+    pub async fn set_write_timeout(&self, timeout: Option<Duration>) {
+        self.writer.set_timeout(timeout).await;
+    }
+
+    /// Creates a text message from this user with a fresh ID.
+    fn message(&self, text: &str) -> ChatMessage {
+        let id = self.next_message_id.fetch_add(1, Ordering::Relaxed);
+        ChatMessage::new(id, &self.username, ChatMessageContent::Text(text.to_string()))
+    }
+}
+
+/// `ChatClient` is a connection to the chat server for bots and other automation. It logs in,
+/// answers the pings of the server in the background and hands over everything else it receives.
+///
+/// ```no_run
+/// # async fn echo() -> anyhow::Result<()> {
+/// use chat::client::ChatClient;
+/// use chat::CodecKind;
+///
+/// let mut client = ChatClient::connect("127.0.0.1", 11111, "echo", "secret", CodecKind::Cbor).await?;
+/// while let Some(incoming) = client.recv_message().await {
+///     if let chat::ChatMessageContent::Text(text) = incoming.message.content {
+///         client.send_text(&text).await?;
+///     }
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct ChatClient {
+    sender: ChatSender,
+    incoming: mpsc::Receiver<Datagram>,
+}
+
+impl ChatClient {
+    /// Connects to the server over TCP and logs in.
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - The address of the server.
+    /// * `port` - The port of the server.
+    /// * `username` - The username of the user.
+    /// * `password` - The password of the user.
+    /// * `codec` - The codec used by the server.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<ChatClient, LoginError>` - Returns the logged in client if successful.
+    pub async fn connect(address: &str, port: u16, username: &str, password: &str, codec: CodecKind) -> Result<ChatClient, LoginError> {
+        let (read_half, write_half) = open_tcp(address, port).await?;
+        ChatClient::login(read_half, write_half, username, password, codec).await
+    }
+
+    /// Connects to the Unix socket of the server and logs in.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the socket.
+    /// * `username` - The username of the user.
+    /// * `password` - The password of the user.
+    /// * `codec` - The codec used by the server.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<ChatClient, LoginError>` - Returns the logged in client if successful.
+    pub async fn connect_unix(path: &Path, username: &str, password: &str, codec: CodecKind) -> Result<ChatClient, LoginError> {
+        let (read_half, write_half) = open_unix(path).await?;
+        ChatClient::login(read_half, write_half, username, password, codec).await
+    }
+
+    /// Logs in on an open connection and starts receiving. Users with two-factor authentication
+    /// can't log in this way, the login fails with `LoginError::TotpRequired`.
+    ///
+    /// # Arguments
+    ///
+    /// * `read_half` - The readable half of the connection.
+    /// * `write_half` - The writable half of the connection.
+    /// * `username` - The username of the user.
+    /// * `password` - The password of the user.
+    /// * `codec` - The codec used by the server.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<ChatClient, LoginError>` - Returns the logged in client if successful.
+    pub async fn login(mut read_half: ReadHalf, mut write_half: WriteHalf, username: &str, password: &str, codec: CodecKind) -> Result<ChatClient, LoginError> {
+        let codec = login(&mut read_half, &mut write_half, username, password, || None, codec, true, None).await?;
+        Ok(ChatClient::start(read_half, write_half, username.to_string(), codec))
+    }
+
+    /// Connects to the TLS port of the server and logs in with a client certificate, see `certificate_login`.
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - The host name or IP address of the server.
+    /// * `port` - The TLS port of the server.
+    /// * `options` - The certificates trusted and presented by the client, `identity` must be set.
+    /// * `username` - The username the certificate is registered for.
+    /// * `codec` - The codec used by the server.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<ChatClient, LoginError>` - Returns the logged in client if successful.
+    pub async fn connect_with_certificate(address: &str, port: u16, options: &TlsOptions, username: &str, codec: CodecKind) -> Result<ChatClient, LoginError> {
+        if options.identity.is_none() {
+            return Err(LoginError::Tls("a client certificate is needed to log in without a password".to_string()));
+        }
+        let (mut read_half, mut write_half) = open_tls(address, port, options).await?;
+        let codec = certificate_login(&mut read_half, &mut write_half, username, codec, true, None).await?;
+        Ok(ChatClient::start(read_half, write_half, username.to_string(), codec))
+    }
+
+    /// Connects to the server over TCP and logs in as a guest, see `guest_login`.
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - The address of the server.
+    /// * `port` - The port of the server.
+    /// * `nickname` - The nickname of the guest.
+    /// * `codec` - The codec used by the server.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<ChatClient, LoginError>` - Returns the logged in client if successful.
+    pub async fn connect_guest(address: &str, port: u16, nickname: &str, codec: CodecKind) -> Result<ChatClient, LoginError> {
+        let (mut read_half, mut write_half) = open_tcp(address, port).await?;
+        let codec = guest_login(&mut read_half, &mut write_half, nickname, codec, None).await?;
+        Ok(ChatClient::start(read_half, write_half, format!("{GUEST_PREFIX}{nickname}"), codec))
+    }
+
+    /// Starts receiving on a logged in connection.
+    fn start(read_half: ReadHalf, write_half: WriteHalf, username: String, codec: SessionCodec) -> ChatClient {
+        let sender = ChatSender {
+            username,
+            codec,
+            writer: Arc::new(BatchWriter::new(write_half, Some(DEFAULT_WRITE_TIMEOUT))),
+            disconnected: Arc::new(Notify::new()),
+            next_message_id: Arc::new(AtomicU64::new(1)),
+        };
+        let (queue, incoming) = mpsc::channel(INCOMING_QUEUE_SIZE);
+        tokio::spawn(receive_datagrams(read_half, sender.clone(), queue));
+        ChatClient { sender, incoming }
+    }
+
+    /// Returns the username the client is logged in as.
+    pub fn username(&self) -> &str {
+        &self.sender.username
+    }
+
+    /// Returns a sender which can be used while the client is receiving, e.g. from another task.
+    pub fn sender(&self) -> ChatSender {
+        self.sender.clone()
+    }
+
+    /// Sends a text message to everyone, see `ChatSender::send_text`.
+    pub async fn send_text(&self, text: &str) -> Result<MessageId, ChatProtocolError> {
+        self.sender.send_text(text).await
+    }
+
+    /// Sends a reply to a received message to everyone, see `ChatSender::send_reply`.
+    pub async fn send_reply(&self, original: &ChatMessage, text: &str) -> Result<MessageId, ChatProtocolError> {
+        self.sender.send_reply(original, text).await
+    }
+
+    /// Posts an announcement, see `ChatSender::send_announcement`.
+    pub async fn send_announcement(&self, text: &str) -> Result<MessageId, ChatProtocolError> {
+        self.sender.send_announcement(text).await
+    }
+
+    /// Sends a text message which expires, see `ChatSender::send_ephemeral`.
+    pub async fn send_ephemeral(&self, text: &str, ttl: u32) -> Result<MessageId, ChatProtocolError> {
+        self.sender.send_ephemeral(text, ttl).await
+    }
+
+    /// Sends a text message to a single user, see `ChatSender::send_direct`.
+    pub async fn send_direct(&self, to: &str, text: &str) -> Result<MessageId, ChatProtocolError> {
+        self.sender.send_direct(to, text).await
+    }
+
+    /// Sends any datagram, see `ChatSender::send`.
+    pub async fn send(&self, datagram: &Datagram) -> Result<(), ChatProtocolError> {
+        self.sender.send(datagram).await
+    }
+
+    /// Sends several datagrams written together, see `ChatSender::send_all`.
+    pub async fn send_all(&self, datagrams: &[Datagram]) -> Result<(), ChatProtocolError> {
+        self.sender.send_all(datagrams).await
+    }
+
+    /// Sets how long the server may take to accept a datagram, see `ChatSender::set_write_timeout`.
+    pub async fn set_write_timeout(&self, timeout: Option<Duration>) {
+        self.sender.set_write_timeout(timeout).await
+    }
+
+    /// Waits for the next datagram from the server. Pings are answered automatically and not returned.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<Datagram>` - Returns the datagram, `None` once the connection is closed.
+    pub async fn recv(&mut self) -> Option<Datagram> {
+        self.incoming.recv().await
+    }
+
+    /// Waits for the next chat message sent to everyone or directly to this user, skipping other datagrams.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<IncomingMessage>` - Returns the message, `None` once the connection is closed.
+    pub async fn recv_message(&mut self) -> Option<IncomingMessage> {
+        loop {
+            match self.recv().await? {
+                Datagram::Message(message) => return Some(IncomingMessage { message, direct: false }),
+                Datagram::DirectMessage { message, .. } => return Some(IncomingMessage { message, direct: true }),
+                _ => {},
+            }
+        }
+    }
+
+    /// Turns the received chat messages into a stream, which ends when the connection is closed.
+    /// Use `sender` to reply while the stream is borrowed.
+    ///
+    /// # Returns
+    ///
+    /// * `BoxStream<IncomingMessage>` - Returns the stream of messages.
+    pub fn messages(&mut self) -> BoxStream<'_, IncomingMessage> {
+        futures::stream::unfold(self, |client| async move {
+            client.recv_message().await.map(|message| (message, client))
+        }).boxed()
+    }
+}
+
+/// Reads datagrams until the connection is closed, answers pings and queues everything else.
+///
+/// # Arguments
+///
+/// * `read_half` - The readable half of the connection.
+/// * `sender` - Used to answer pings.
+/// * `queue` - Where the received datagrams are queued, the task ends when the client is dropped.
+async fn receive_datagrams(mut read_half: ReadHalf, sender: ChatSender, queue: mpsc::Sender<Datagram>) {
+    loop {
+        // A dropped client closes the queue, which also closes the connection unless a `ChatSender` is still alive
+        let datagram = tokio::select! {
+            datagram = Datagram::read_from_stream(&mut read_half, &sender.codec) => datagram,
+            _ = queue.closed() => break,
+            _ = sender.disconnected.notified() => break,
+        };
+        let Ok(datagram) = datagram else {
+            break;
+        };
+        match datagram {
+            Datagram::Ping => {
+                if sender.send(&Datagram::Pong).await.is_err() {
+                    break;
+                }
+            },
+            datagram => {
+                if queue.send(datagram).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+    use tokio::io::{AsyncRead, AsyncWrite};
+
+    use crate::client::{login, ChatClient, LoginError};
+    use crate::{ChatMessageContent, ChatProtocolError, CodecKind, Datagram, ServerResponse};
+
+    /// Pretends to be a server on the other end of an in-memory pipe.
+    fn pipe() -> (Box<dyn AsyncRead + Send + Unpin>, Box<dyn AsyncWrite + Send + Unpin>, tokio::io::DuplexStream) {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let (read_half, write_half) = tokio::io::split(client);
+        (Box::new(read_half), Box::new(write_half), server)
+    }
+
+    #[tokio::test]
+    async fn test_chat_client() {
+        let codec = CodecKind::Cbor;
+        let (read_half, write_half, mut server) = pipe();
+        Datagram::ServerResponse(ServerResponse::LoginOk).write_to_stream(&mut server, &codec).await.unwrap();
+        let mut client = ChatClient::login(read_half, write_half, "bot", "secret", codec).await.unwrap();
+        assert_eq!(client.username(), "bot");
+        assert!(matches!(
+            Datagram::read_from_stream(&mut server, &codec).await.unwrap(),
+            Datagram::Login { username, password, compression: true, last_id: None } if username == "bot" && password == "secret"
+        ));
+
+        // Pings are answered without involving the user
+        Datagram::Ping.write_to_stream(&mut server, &codec).await.unwrap();
+        assert!(matches!(Datagram::read_from_stream(&mut server, &codec).await.unwrap(), Datagram::Pong));
+
+        let first = client.send_text("hello").await.unwrap();
+        let second = client.send_direct("Alice", "psst").await.unwrap();
+        assert_ne!(first, second);
+        assert!(matches!(
+            Datagram::read_from_stream(&mut server, &codec).await.unwrap(),
+            Datagram::Message(message) if message.id == first && message.sender == "bot"
+        ));
+        assert!(matches!(
+            Datagram::read_from_stream(&mut server, &codec).await.unwrap(),
+            Datagram::DirectMessage { to, .. } if to == "Alice"
+        ));
+
+        // Other datagrams are skipped by the message stream
+        Datagram::ServerResponse(ServerResponse::MessageAck(first)).write_to_stream(&mut server, &codec).await.unwrap();
+        let mut reply = client.sender().message("hi bot");
+        reply.sender = "Alice".to_string();
+        Datagram::DirectMessage { to: "bot".to_string(), message: reply }.write_to_stream(&mut server, &codec).await.unwrap();
+        drop(server);
+
+        let messages: Vec<_> = client.messages().collect().await;
+        assert_eq!(messages.len(), 1);
+        assert!(messages[0].direct);
+        assert!(matches!(&messages[0].message.content, ChatMessageContent::Text(text) if text == "hi bot"));
+    }
+
+    #[tokio::test]
+    async fn test_login_rejected() {
+        let codec = CodecKind::Json;
+        let (read_half, write_half, mut server) = pipe();
+        Datagram::ServerResponse(ServerResponse::Banned).write_to_stream(&mut server, &codec).await.unwrap();
+        let result = ChatClient::login(read_half, write_half, "bot", "secret", codec).await;
+        assert!(matches!(result, Err(LoginError::Banned)));
+
+        let (read_half, write_half, mut server) = pipe();
+        Datagram::ServerResponse(ServerResponse::LoginFailed).write_to_stream(&mut server, &codec).await.unwrap();
+        let result = ChatClient::login(read_half, write_half, "bot", "wrong", codec).await;
+        assert!(matches!(result, Err(LoginError::LoginFailed)));
+
+        let (read_half, write_half, mut server) = pipe();
+        Datagram::ServerResponse(ServerResponse::TotpRequired).write_to_stream(&mut server, &codec).await.unwrap();
+        let result = ChatClient::login(read_half, write_half, "bot", "secret", codec).await;
+        assert!(matches!(result, Err(LoginError::TotpRequired)));
+    }
+
+    #[tokio::test]
+    async fn test_totp_login() {
+        let codec = CodecKind::Cbor;
+        let (mut read_half, mut write_half, mut server) = pipe();
+        Datagram::ServerResponse(ServerResponse::TotpRequired).write_to_stream(&mut server, &codec).await.unwrap();
+        Datagram::ServerResponse(ServerResponse::LoginOk).write_to_stream(&mut server, &codec).await.unwrap();
+        let session = login(&mut read_half, &mut write_half, "bot", "secret", || Some("123456".to_string()), codec, false, None).await.unwrap();
+        assert!(!session.compression);
+        assert!(matches!(Datagram::read_from_stream(&mut server, &codec).await.unwrap(), Datagram::Login { .. }));
+        assert!(matches!(Datagram::read_from_stream(&mut server, &codec).await.unwrap(), Datagram::TotpCode(code) if code == "123456"));
+
+        let (mut read_half, mut write_half, mut server) = pipe();
+        Datagram::ServerResponse(ServerResponse::TotpRequired).write_to_stream(&mut server, &codec).await.unwrap();
+        Datagram::ServerResponse(ServerResponse::LoginFailed).write_to_stream(&mut server, &codec).await.unwrap();
+        let result = login(&mut read_half, &mut write_half, "bot", "secret", || Some("000000".to_string()), codec, false, None).await;
+        assert!(matches!(result, Err(LoginError::InvalidCode)));
+    }
+
+    #[tokio::test]
+    async fn test_write_timeout() {
+        let codec = CodecKind::Cbor;
+        let (read_half, write_half, mut server) = pipe();
+        Datagram::ServerResponse(ServerResponse::LoginOk).write_to_stream(&mut server, &codec).await.unwrap();
+        let mut client = ChatClient::login(read_half, write_half, "bot", "secret", codec).await.unwrap();
+        client.set_write_timeout(Some(std::time::Duration::from_millis(100))).await;
+
+        // The server stops reading, so the pipe fills up
+        let result = client.send_text(&"hello ".repeat(20_000)).await;
+        assert!(matches!(result, Err(ChatProtocolError::Timeout)));
+        assert!(matches!(client.send_text("hello").await, Err(ChatProtocolError::IOError)));
+        assert!(client.recv().await.is_none());
+    }
+}
@@ -0,0 +1,234 @@
+use std::fmt::Display;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+use fluent_bundle::concurrent::FluentBundle;
+use fluent_bundle::FluentResource;
+pub use fluent_bundle::{FluentArgs, FluentValue};
+use unic_langid::LanguageIdentifier;
+
+/// Messages of the client and the server in English, also used for messages missing in other languages.
+const ENGLISH: &str = include_str!("../locales/en.ftl");
+
+/// Messages of the client and the server in Czech.
+const CZECH: &str = include_str!("../locales/cs.ftl");
+
+/// Language of the messages shown to the user, chosen with `--lang` or taken from the environment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Lang {
+    #[default]
+    En,
+    Cs,
+}
+
+impl Lang {
+    /// Every supported language.
+    pub const ALL: &'static [Lang] = &[Lang::En, Lang::Cs];
+
+    /// Picks the language from the `LC_ALL`, `LC_MESSAGES` and `LANG` environment variables, like `C` programs do.
+    ///
+    /// # Returns
+    ///
+    /// * `Lang` - Returns the language of the first variable which is set, or English if it's not supported.
+    pub fn from_env() -> Lang {
+        ["LC_ALL", "LC_MESSAGES", "LANG"].iter()
+            .filter_map(|name| std::env::var(name).ok())
+            .find(|value| !value.is_empty())
+            .and_then(|value| Lang::from_locale(&value))
+            .unwrap_or_default()
+    }
+
+    /// Picks the language of a POSIX locale like `cs_CZ.UTF-8`.
+    ///
+    /// # Arguments
+    ///
+    /// * `locale` - The locale.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<Lang>` - Returns the language, or `None` if it's not supported.
+    pub fn from_locale(locale: &str) -> Option<Lang> {
+        let language = locale.split(['_', '-', '.', '@']).next().unwrap_or_default();
+        language.parse().ok()
+    }
+
+    /// Returns the fluent messages of the language.
+    fn resource(&self) -> &'static str {
+        match self {
+            Lang::En => ENGLISH,
+            Lang::Cs => CZECH,
+        }
+    }
+}
+
+impl FromStr for Lang {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "en" => Ok(Lang::En),
+            "cs" => Ok(Lang::Cs),
+            _ => Err(format!("unknown language {s}, expected one of: en, cs")),
+        }
+    }
+}
+
+impl Display for Lang {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Lang::En => write!(f, "en"),
+            Lang::Cs => write!(f, "cs"),
+        }
+    }
+}
+
+/// Looks up the messages of one language, falling back to English for the ones it lacks.
+pub struct Localizer {
+    bundle: FluentBundle<FluentResource>,
+    fallback: Option<FluentBundle<FluentResource>>,
+}
+
+impl Localizer {
+    /// Loads the messages of a language.
+    ///
+    /// # Arguments
+    ///
+    /// * `lang` - The language.
+    ///
+    /// # Returns
+    ///
+    /// * `Localizer` - Returns the localizer.
+    pub fn new(lang: Lang) -> Localizer {
+        Localizer {
+            bundle: bundle(lang),
+            fallback: (lang != Lang::En).then(|| bundle(Lang::En)),
+        }
+    }
+
+    /// Formats a message with its arguments.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The ID of the message in the `.ftl` files.
+    /// * `args` - The values of the variables in the message.
+    ///
+    /// # Returns
+    ///
+    /// * `String` - Returns the message, or its ID if no language has it.
+    pub fn format(&self, id: &str, args: Option<&FluentArgs>) -> String {
+        for bundle in std::iter::once(&self.bundle).chain(&self.fallback) {
+            if let Some(pattern) = bundle.get_message(id).and_then(|message| message.value()) {
+                // A wrong argument only leaves a placeholder in the text, which is better than no message at all
+                let mut errors = Vec::new();
+                return bundle.format_pattern(pattern, args, &mut errors).into_owned();
+            }
+        }
+        id.to_string()
+    }
+}
+
+/// Builds the fluent bundle of a language.
+fn bundle(lang: Lang) -> FluentBundle<FluentResource> {
+    let id: LanguageIdentifier = lang.to_string().parse().unwrap_or_default();
+    let mut bundle = FluentBundle::new_concurrent(vec![id]);
+    // The isolation marks around the arguments would show up in terminals and log files
+    bundle.set_use_isolating(false);
+    let resource = FluentResource::try_new(lang.resource().to_string()).unwrap_or_else(|(resource, _)| resource);
+    let _ = bundle.add_resource(resource);
+    bundle
+}
+
+/// Language of the process, English until `set_lang` chooses another.
+static LOCALIZER: OnceLock<Localizer> = OnceLock::new();
+
+/// Chooses the language of the messages, once at startup.
+///
+/// # Arguments
+///
+/// * `lang` - The language.
+pub fn set_lang(lang: Lang) {
+    let _ = LOCALIZER.set(Localizer::new(lang));
+}
+
+/// Formats a message in the chosen language, usually through the `t!` macro.
+///
+/// # Arguments
+///
+/// * `id` - The ID of the message in the `.ftl` files.
+/// * `args` - The values of the variables in the message.
+///
+/// # Returns
+///
+/// * `String` - Returns the message.
+pub fn tr(id: &str, args: Option<&FluentArgs>) -> String {
+    LOCALIZER.get_or_init(|| Localizer::new(Lang::En)).format(id, args)
+}
+
+/// Formats a message in the chosen language: `t!("login-successful")` or `t!("user-joined", username = name)`.
+/// Numbers and strings are passed as they are, other values with `to_string()`.
+#[macro_export]
+macro_rules! t {
+    ($id:literal) => {
+        $crate::i18n::tr($id, None)
+    };
+    ($id:literal, $($name:ident = $value:expr),+ $(,)?) => {{
+        let mut args = $crate::i18n::FluentArgs::new();
+        $(args.set(stringify!($name), $value);)+
+        $crate::i18n::tr($id, Some(&args))
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use fluent_bundle::{FluentArgs, FluentResource};
+    use fluent_syntax::ast::Entry;
+
+    use crate::i18n::{Lang, Localizer};
+
+    /// Returns the IDs of the messages of a language.
+    fn message_ids(lang: Lang) -> BTreeSet<String> {
+        let resource = FluentResource::try_new(lang.resource().to_string()).expect("The messages don't parse.");
+        resource.entries().filter_map(|entry| match entry {
+            Entry::Message(message) => Some(message.id.name.to_string()),
+            _ => None,
+        }).collect()
+    }
+
+    #[test]
+    fn test_languages_complete() {
+        let english = message_ids(Lang::En);
+        for lang in Lang::ALL {
+            assert_eq!(message_ids(*lang), english, "The messages of {lang} differ from the English ones.");
+        }
+    }
+
+    #[test]
+    fn test_format() {
+        let mut args = FluentArgs::new();
+        args.set("username", "Bob");
+        assert_eq!(Localizer::new(Lang::En).format("user-joined", Some(&args)), "*** Bob joined the chat.");
+        assert_eq!(Localizer::new(Lang::Cs).format("user-joined", Some(&args)), "*** Bob se připojuje do chatu.");
+        assert_eq!(Localizer::new(Lang::Cs).format("no-such-message", None), "no-such-message");
+
+        // Czech has three plural forms
+        let unacknowledged = |count: u64| {
+            let mut args = FluentArgs::new();
+            args.set("count", count);
+            args.set("seconds", 5);
+            Localizer::new(Lang::Cs).format("messages-not-acknowledged", Some(&args))
+        };
+        assert!(unacknowledged(1).starts_with("1 zpráva nebyla"));
+        assert!(unacknowledged(3).starts_with("3 zprávy nebyly"));
+        assert!(unacknowledged(12).starts_with("12 zpráv nebylo"));
+    }
+
+    #[test]
+    fn test_lang_from_locale() {
+        assert_eq!(Lang::from_locale("cs_CZ.UTF-8"), Some(Lang::Cs));
+        assert_eq!(Lang::from_locale("en"), Some(Lang::En));
+        assert_eq!(Lang::from_locale("C"), None);
+        assert_eq!("CS".parse::<Lang>(), Ok(Lang::Cs));
+    }
+}
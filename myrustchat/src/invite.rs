@@ -0,0 +1,122 @@
+use std::fmt::Display;
+use std::str::FromStr;
+
+use url::{Host, Url};
+
+use crate::CodecKind;
+
+/// Scheme of the invite links.
+pub const INVITE_SCHEME: &str = "chat";
+
+/// Connection details of a server packed into a link like `chat://example.com:11111?name=Team%20chat`,
+/// printed by `server invite` and accepted by the client instead of the connection flags.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Invite {
+    /// Host name or IP address of the server
+    pub address: String,
+    /// Port of the server, the default one if the link has none
+    pub port: Option<u16>,
+    /// Name of the server shown to the invited user
+    pub name: Option<String>,
+    /// Username the invited user logs in with
+    pub username: Option<String>,
+    /// Wire format of the server, if it's not the default one
+    pub codec: Option<CodecKind>,
+}
+
+impl Display for Invite {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // IPv6 addresses are put in brackets, so that their colons aren't taken for the port
+        let host = match self.address.contains(':') {
+            true => format!("[{}]", self.address),
+            false => self.address.clone(),
+        };
+        let authority = match self.port {
+            Some(port) => format!("{INVITE_SCHEME}://{host}:{port}"),
+            None => format!("{INVITE_SCHEME}://{host}"),
+        };
+        let mut url = Url::parse(&authority).map_err(|_| std::fmt::Error)?;
+        let parameters = [
+            ("name", self.name.clone()),
+            ("user", self.username.clone()),
+            ("codec", self.codec.map(|codec| codec.to_string())),
+        ];
+        if parameters.iter().any(|(_, value)| value.is_some()) {
+            let mut query = url.query_pairs_mut();
+            for (key, value) in parameters {
+                if let Some(value) = value {
+                    query.append_pair(key, &value);
+                }
+            }
+        }
+        write!(f, "{url}")
+    }
+}
+
+impl FromStr for Invite {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let url = Url::parse(s).map_err(|e| format!("invalid invite link {s}: {e}"))?;
+        if url.scheme() != INVITE_SCHEME {
+            return Err(format!("invalid invite link {s}: expected a {INVITE_SCHEME}:// link"));
+        }
+        let address = match url.host() {
+            Some(Host::Domain(domain)) if !domain.is_empty() => domain.to_string(),
+            Some(Host::Ipv4(address)) => address.to_string(),
+            Some(Host::Ipv6(address)) => address.to_string(),
+            _ => return Err(format!("invalid invite link {s}: the server address is missing")),
+        };
+        if !matches!(url.path(), "" | "/") {
+            return Err(format!("invalid invite link {s}: unexpected path {}", url.path()));
+        }
+
+        let mut invite = Invite { address, port: url.port(), name: None, username: None, codec: None };
+        // Unknown parameters are ignored, so that links of newer servers still work
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                "name" => invite.name = Some(value.into_owned()),
+                "user" => invite.username = Some(value.into_owned()),
+                "codec" => invite.codec = Some(value.parse().map_err(|e| format!("invalid invite link {s}: {e}"))?),
+                _ => {},
+            }
+        }
+        Ok(invite)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::invite::Invite;
+    use crate::CodecKind;
+
+    #[test]
+    fn test_invite_roundtrip() {
+        let invite = Invite {
+            address: "chat.example.com".into(),
+            port: Some(11111),
+            name: Some("Team chat & friends".into()),
+            username: Some("alice".into()),
+            codec: Some(CodecKind::Json),
+        };
+        let link = invite.to_string();
+        assert_eq!(link, "chat://chat.example.com:11111?name=Team+chat+%26+friends&user=alice&codec=json");
+        assert_eq!(link.parse::<Invite>().unwrap(), invite);
+
+        let invite = Invite { address: "::1".into(), port: Some(2000), name: None, username: None, codec: None };
+        assert_eq!(invite.to_string(), "chat://[::1]:2000");
+        assert_eq!("chat://[::1]:2000/".parse::<Invite>().unwrap(), invite);
+    }
+
+    #[test]
+    fn test_parse_invite() {
+        let invite: Invite = "chat://127.0.0.1?name=Home&theme=dark".parse().unwrap();
+        assert_eq!(invite, Invite { address: "127.0.0.1".into(), port: None, name: Some("Home".into()), username: None, codec: None });
+
+        assert!("http://example.com:11111".parse::<Invite>().is_err());
+        assert!("chat://:11111".parse::<Invite>().is_err());
+        assert!("chat://example.com:11111/room".parse::<Invite>().is_err());
+        assert!("chat://example.com:11111?codec=xml".parse::<Invite>().is_err());
+        assert!("example.com:11111".parse::<Invite>().is_err());
+    }
+}
@@ -1,4 +1,69 @@
-pub mod datagram;
-pub use datagram::*;
+pub use chat_protocol::*;
+pub mod client;
+pub mod batch;
+pub mod i18n;
+pub mod invite;
 
 pub type EmptyResult = anyhow::Result<()>;
+
+/// Formats a number of bytes for humans.
+///
+/// # Arguments
+///
+/// * `bytes` - The number of bytes.
+///
+/// # Returns
+///
+/// * `String` - Returns the size, e.g. `1.5 MB`.
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
+}
+
+/// Returns the usual file extension of an image by its content.
+///
+/// # Arguments
+///
+/// * `data` - The image data, its start is enough.
+///
+/// # Returns
+///
+/// * `&'static str` - Returns the extension, `png` if the format isn't recognized.
+pub fn image_extension(data: &[u8]) -> &'static str {
+    match image::guess_format(data) {
+        Ok(image::ImageFormat::Jpeg) => "jpg",
+        Ok(image::ImageFormat::Gif) => "gif",
+        Ok(image::ImageFormat::WebP) => "webp",
+        _ => "png",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{format_size, image_extension};
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!(format_size(10), "10 B");
+        assert_eq!(format_size(1536), "1.5 KB");
+        assert_eq!(format_size(5 * 1024 * 1024), "5.0 MB");
+    }
+
+    #[test]
+    fn test_image_extension() {
+        assert_eq!(image_extension(b"\xFF\xD8\xFF\xE0"), "jpg");
+        assert_eq!(image_extension(b"GIF89a"), "gif");
+        assert_eq!(image_extension(b"RIFF\0\0\0\0WEBP"), "webp");
+        assert_eq!(image_extension(b"\x89PNG\r\n\x1a\n"), "png");
+        assert_eq!(image_extension(b"plain text"), "png");
+    }
+}